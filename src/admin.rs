@@ -0,0 +1,189 @@
+use crate::{
+    amqp::amqp_connect,
+    config::{BrokerKind, Config},
+};
+use anyhow::{ensure, Context, Result};
+use lapin::{
+    options::{
+        BasicAckOptions, BasicGetOptions, BasicPublishOptions, ConfirmSelectOptions,
+        ExchangeDeclareOptions, QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, ExchangeKind,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+use tracing::info;
+
+const TASK_EXCHANGE: &str = "waterwheel.tasks";
+const TASK_QUEUE: &str = "waterwheel.tasks";
+
+const PERSISTENT: u8 = 2;
+
+/// how many messages to write out before syncing the file and acking them
+const DRAIN_BATCH: usize = 100;
+
+/// a task request taken off the queue, one per line in the drain file
+#[derive(Serialize, Deserialize)]
+struct DrainedTask {
+    priority: Option<u8>,
+    expiration: Option<String>,
+    body: JsonValue,
+}
+
+/// Move every message on the task queue into a file, so the broker can be
+/// replaced or execution paused without losing queued work. Messages are only
+/// acked once they have been synced to disk. The file is appended to, so a
+/// drain that was interrupted can be run again.
+pub async fn drain_queue(config: &Config, path: &Path) -> Result<u64> {
+    ensure_amqp(config)?;
+    let conn = amqp_connect(config).await?;
+    let chan = conn.create_channel().await?;
+
+    // passive, so the queue is not created with the wrong arguments if it's missing
+    chan.queue_declare(
+        TASK_QUEUE,
+        QueueDeclareOptions {
+            passive: true,
+            ..QueueDeclareOptions::default()
+        },
+        FieldTable::default(),
+    )
+    .await?;
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+
+    let mut drained = 0;
+    loop {
+        let mut last = None;
+        let mut batch = 0;
+
+        while batch < DRAIN_BATCH {
+            let msg = match chan
+                .basic_get(TASK_QUEUE, BasicGetOptions::default())
+                .await?
+            {
+                Some(msg) => msg,
+                None => break,
+            };
+
+            let task = DrainedTask {
+                priority: *msg.delivery.properties.priority(),
+                expiration: msg
+                    .delivery
+                    .properties
+                    .expiration()
+                    .as_ref()
+                    .map(|e| e.to_string()),
+                body: serde_json::from_slice(&msg.delivery.data)?,
+            };
+
+            serde_json::to_writer(&mut out, &task)?;
+            out.write_all(b"\n")?;
+
+            last = Some(msg.delivery);
+            batch += 1;
+        }
+
+        let last = match last {
+            Some(last) => last,
+            None => break,
+        };
+
+        out.flush()?;
+        out.get_ref().sync_data()?;
+
+        last.ack(BasicAckOptions { multiple: true }).await?;
+
+        drained += batch as u64;
+        info!(drained, "drained tasks from the queue");
+    }
+
+    info!(drained, path=%path.display(), "task queue is empty");
+    Ok(drained)
+}
+
+/// Publish every task in a file written by `drain_queue` back onto the task
+/// queue, with the priority and expiry they had when they were drained.
+pub async fn replay_queue(config: &Config, path: &Path) -> Result<u64> {
+    ensure_amqp(config)?;
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+
+    let conn = amqp_connect(config).await?;
+    let chan = conn.create_channel().await?;
+    chan.confirm_select(ConfirmSelectOptions::default()).await?;
+
+    chan.exchange_declare(
+        TASK_EXCHANGE,
+        ExchangeKind::Direct,
+        ExchangeDeclareOptions {
+            durable: true,
+            ..ExchangeDeclareOptions::default()
+        },
+        FieldTable::default(),
+    )
+    .await?;
+
+    let mut replayed = 0;
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let task: DrainedTask = serde_json::from_str(&line)
+            .with_context(|| format!("parsing line {} of {}", line_no + 1, path.display()))?;
+
+        let mut props = BasicProperties::default().with_delivery_mode(PERSISTENT);
+        if let Some(priority) = task.priority {
+            props = props.with_priority(priority);
+        }
+        if let Some(expiration) = task.expiration {
+            props = props.with_expiration(expiration.into());
+        }
+
+        let confirm = chan
+            .basic_publish(
+                TASK_EXCHANGE,
+                "",
+                BasicPublishOptions::default(),
+                &serde_json::to_vec(&task.body)?,
+                props,
+            )
+            .await?
+            .await?;
+
+        if !confirm.is_ack() {
+            anyhow::bail!(
+                "broker did not accept line {} of {}, {} tasks were replayed before it",
+                line_no + 1,
+                path.display(),
+                replayed
+            );
+        }
+
+        replayed += 1;
+    }
+
+    info!(replayed, path=%path.display(), "replayed tasks onto the queue");
+    Ok(replayed)
+}
+
+/// the drain file keeps RabbitMQ's message properties, so only it is supported
+fn ensure_amqp(config: &Config) -> Result<()> {
+    ensure!(
+        config.broker == BrokerKind::Amqp,
+        "draining and replaying the task queue needs WATERWHEEL_BROKER=amqp"
+    );
+    Ok(())
+}
@@ -1,6 +1,6 @@
-use crate::config::Config;
+use crate::{config::Config, messages::TaskPriority};
 use anyhow::Result;
-use lapin::{Connection, ConnectionProperties};
+use lapin::{types::FieldTable, Connection, ConnectionProperties};
 use tracing::info;
 
 pub async fn amqp_connect(config: &Config) -> Result<Connection> {
@@ -15,3 +15,19 @@ pub async fn amqp_connect(config: &Config) -> Result<Connection> {
 
     Ok(conn)
 }
+
+/// The arguments every task queue is declared with. Both the server and the
+/// workers declare them, and the broker refuses a declaration whose arguments
+/// differ from the existing queue's, so they must be built the same way.
+pub fn task_queue_args(config: &Config) -> FieldTable {
+    let mut args = FieldTable::default();
+
+    // tasks are published with `TaskPriority as u8`, so a high priority task is
+    // delivered ahead of a backlog of backfill
+    args.insert("x-max-priority".into(), (TaskPriority::High as i8).into());
+
+    let timeout_ms = config.amqp_consumer_timeout * 1000;
+    args.insert("x-consumer-timeout".into(), timeout_ms.into());
+
+    args
+}
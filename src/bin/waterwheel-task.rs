@@ -0,0 +1,88 @@
+//! A small helper injected into task containers, so tasks written in any
+//! language (including shell scripts) can write their result file.
+//!
+//!     waterwheel-task result <success|failure|error>
+//!     waterwheel-task output <name> <value>
+//!     waterwheel-task artifact <name> <uri>
+//!     waterwheel-task install <dir>
+//!
+//! Output values are stored as JSON if they parse as JSON, otherwise as strings.
+
+use anyhow::{format_err, Context, Result};
+use serde_json::Value as JsonValue;
+use std::path::{Path, PathBuf};
+use waterwheel::task_contract::{Artifact, ResultFile, ResultKind, HELPER_NAME, RESULT_FILE_ENV};
+
+const USAGE: &str = "usage:
+    waterwheel-task result <success|failure|error>
+    waterwheel-task output <name> <value>
+    waterwheel-task artifact <name> <uri>
+    waterwheel-task install <dir>";
+
+fn result_file_path() -> Result<PathBuf> {
+    std::env::var_os(RESULT_FILE_ENV)
+        .map(PathBuf::from)
+        .ok_or_else(|| format_err!("{RESULT_FILE_ENV} is not set, is this running in a task?"))
+}
+
+/// read the result file, change it and write it back
+fn update(change: impl FnOnce(&mut ResultFile)) -> Result<()> {
+    let path = result_file_path()?;
+
+    let mut file = match std::fs::read(&path) {
+        Ok(data) => ResultFile::parse(&data)?.unwrap_or_default(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => ResultFile::default(),
+        Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+    };
+
+    change(&mut file);
+
+    // written in place, since the file may be a mount (eg. a Kubernetes termination log)
+    std::fs::write(&path, serde_json::to_vec(&file)?)
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// copy this binary into a directory, eg. from a Kubernetes init container
+fn install(dir: &Path) -> Result<()> {
+    let target = dir.join(HELPER_NAME);
+    std::fs::copy(std::env::current_exe()?, &target)
+        .with_context(|| format!("copying to {}", target.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["result", kind] => {
+            let kind: ResultKind = serde_json::from_value(JsonValue::String(kind.to_string()))
+                .map_err(|_| format_err!("result must be one of: success, failure, error"))?;
+            update(|file| file.result = Some(kind))
+        }
+        ["output", name, value] => {
+            let value = serde_json::from_str(value)
+                .unwrap_or_else(|_| JsonValue::String(value.to_string()));
+            update(|file| {
+                file.outputs.insert(name.to_string(), value);
+            })
+        }
+        ["artifact", name, uri] => update(|file| {
+            file.artifacts.retain(|artifact| artifact.name != *name);
+            file.artifacts.push(Artifact {
+                name: name.to_string(),
+                uri: uri.to_string(),
+            });
+        }),
+        ["install", dir] => install(Path::new(dir)),
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    }
+}
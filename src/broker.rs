@@ -0,0 +1,129 @@
+//! The message broker the scheduler, API and workers talk through. Queues hand
+//! each message to one consumer, which acks it once it's been handled, and
+//! topics fan each message out to everyone subscribed at the time. RabbitMQ is
+//! the default, Redis Streams saves a small deployment running a second broker
+//! alongside the Redis it already has for logs.
+
+use crate::{
+    config::{BrokerKind, Config},
+    messages::TaskPriority,
+};
+use anyhow::Result;
+use futures::{
+    future::BoxFuture,
+    stream::{BoxStream, TryStreamExt},
+};
+use std::sync::Arc;
+
+mod kafka;
+mod nats;
+mod rabbitmq;
+mod redis_streams;
+
+/// where messages are sent, each one is taken by a single consumer
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Queue<'a> {
+    /// a task queue by name, taken from by the workers
+    Tasks(&'a str),
+    /// task progress from the workers and sensors, for the scheduler
+    Results,
+    /// tokens activated through the API, for the scheduler
+    TokenUpdates,
+}
+
+/// where messages are published, each one goes to every subscriber
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Topic {
+    /// changed project config and task definitions, for the workers' caches
+    Config,
+    /// commands for all workers, or one of them
+    WorkerControl,
+    /// changed triggers, for every scheduler
+    TriggerUpdates,
+    /// live updates, for every API process to pass on to its websockets
+    Live,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    pub priority: Option<TaskPriority>,
+    /// milliseconds until the message is dropped, if it hasn't been taken
+    pub expiration_ms: Option<u64>,
+}
+
+#[async_trait::async_trait]
+pub trait Broker: Send + Sync {
+    /// Make sure a queue exists, so messages sent to it are kept until they're
+    /// taken. Sending and consuming declare their queues too.
+    async fn declare(&self, queue: Queue<'_>) -> Result<()>;
+
+    async fn send(&self, queue: Queue<'_>, data: &[u8], options: SendOptions) -> Result<()>;
+
+    /// take the next message from the queue, if there is one
+    async fn get(&self, queue: Queue<'_>) -> Result<Option<Delivery>>;
+
+    /// Take messages from the queues as they arrive, higher priorities first.
+    /// No more than `prefetch` are held that haven't been acked.
+    async fn consume(&self, queues: &[Queue<'_>], prefetch: u16) -> Result<Consumer>;
+
+    async fn publish(&self, topic: Topic, data: &[u8]) -> Result<()>;
+
+    /// the messages published to the topic from now on
+    async fn subscribe(&self, topic: Topic) -> Result<BoxStream<'static, Result<Vec<u8>>>>;
+
+    /// whether the broker can be reached, for the readiness check
+    async fn check(&self) -> Result<()>;
+}
+
+/// connect to the broker chosen by `WATERWHEEL_BROKER`
+pub async fn connect(config: &Config) -> Result<Arc<dyn Broker>> {
+    Ok(match config.broker {
+        BrokerKind::Amqp => Arc::new(rabbitmq::RabbitMqBroker::new(config).await?),
+        BrokerKind::Redis => Arc::new(redis_streams::RedisStreamsBroker::new(config).await?),
+        BrokerKind::Nats => Arc::new(nats::NatsBroker::new(config).await?),
+        BrokerKind::Kafka => Arc::new(kafka::KafkaBroker::new(config).await?),
+    })
+}
+
+/// a message taken from a queue
+pub struct Delivery {
+    pub data: Vec<u8>,
+    /// the priority it was sent with, if any
+    pub priority: Option<TaskPriority>,
+    acker: Box<dyn Acker>,
+}
+
+impl Delivery {
+    /// the message has been handled, so is removed from the queue
+    pub async fn ack(self) -> Result<()> {
+        self.acker.ack().await
+    }
+
+    /// give the message back, to be taken again
+    pub async fn requeue(self) -> Result<()> {
+        self.acker.requeue().await
+    }
+}
+
+#[async_trait::async_trait]
+trait Acker: Send + Sync {
+    async fn ack(&self) -> Result<()>;
+    async fn requeue(&self) -> Result<()>;
+}
+
+/// messages being taken from queues by `Broker::consume`
+pub struct Consumer {
+    deliveries: BoxStream<'static, Result<Delivery>>,
+    cancel: BoxFuture<'static, Result<()>>,
+}
+
+impl Consumer {
+    pub async fn next(&mut self) -> Result<Option<Delivery>> {
+        self.deliveries.try_next().await
+    }
+
+    /// stop taking messages, any that were taken but not acked are given back
+    pub async fn cancel(self) -> Result<()> {
+        self.cancel.await
+    }
+}
@@ -0,0 +1,542 @@
+use super::{Acker, Broker, Consumer, Delivery, Queue, SendOptions, Topic};
+use crate::{config::Config, messages::TaskPriority};
+use anyhow::Result;
+use chrono::Utc;
+use futures::{
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
+use rdkafka::{
+    admin::{AdminClient, AdminOptions, NewTopic, TopicReplication},
+    client::{ClientContext, DefaultClientContext},
+    consumer::{BaseConsumer, Consumer as _, ConsumerContext, Rebalance, StreamConsumer},
+    message::{Header, Headers, Message, OwnedHeaders, OwnedMessage},
+    producer::{FutureProducer, FutureRecord},
+    types::RDKafkaErrorCode,
+    util::Timeout,
+    ClientConfig, Offset, TopicPartitionList,
+};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::warn;
+
+const RESULT_TOPIC: &str = "waterwheel.results";
+const TOKEN_UPDATES_TOPIC: &str = "waterwheel.updates.tokens";
+
+/// Kafka has no per-message expiry, so it's checked when the message is taken
+const EXPIRES_HEADER: &str = "waterwheel-expires";
+
+/// how often a consumer with nothing to do looks for messages again
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// how long to wait for the brokers to acknowledge a message, or answer a check
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the longest librdkafka allows `max.poll.interval.ms` to be
+const MAX_POLL_INTERVAL_MS: u64 = 86_400_000;
+
+/// task queues have a topic for each priority, taken from highest first
+const PRIORITIES: [TaskPriority; 4] = [
+    TaskPriority::High,
+    TaskPriority::Normal,
+    TaskPriority::Low,
+    TaskPriority::BackFill,
+];
+
+/// Queues are topics read by a consumer group named after the topic, with one
+/// member per process, so each partition is taken from by a single process at
+/// a time. Offsets are only committed past messages that have all been acked,
+/// so anything taken but not acked when a process stops, such as task results
+/// `process_progress` hadn't committed to the database yet, is delivered again
+/// from the last committed offset. Kafka doesn't have priorities, so each task
+/// queue has a topic per priority. Topics are single partition topics that
+/// each subscriber reads on its own, from the latest message on.
+pub struct KafkaBroker {
+    client_config: ClientConfig,
+    producer: FutureProducer,
+    admin: AdminClient<DefaultClientContext>,
+    partitions: i32,
+    /// a worker only polls for a task when it has a free slot, so this is how
+    /// long every slot can be busy before the group gives its partitions to
+    /// another worker
+    max_poll_interval_ms: u64,
+    /// topics are only created the first time they're used
+    created: Mutex<HashSet<String>>,
+    /// consumers, by topic, are shared by everything in the process taking from
+    /// the queue, and only created the first time they're used
+    consumers: Mutex<HashMap<String, Arc<TopicConsumer>>>,
+}
+
+impl KafkaBroker {
+    pub async fn new(config: &Config) -> Result<Self> {
+        let mut client_config = ClientConfig::new();
+        client_config.set("bootstrap.servers", &config.kafka_brokers);
+
+        let producer: FutureProducer = client_config
+            .clone()
+            .set("enable.idempotence", "true")
+            .create()?;
+        let admin: AdminClient<DefaultClientContext> = client_config.create()?;
+
+        Ok(KafkaBroker {
+            client_config,
+            producer,
+            admin,
+            partitions: config.kafka_partitions,
+            max_poll_interval_ms: (config.amqp_consumer_timeout * 1000).min(MAX_POLL_INTERVAL_MS),
+            created: Mutex::default(),
+            consumers: Mutex::default(),
+        })
+    }
+
+    /// create a topic, if it doesn't exist already
+    async fn create_topic(&self, topic: &str, partitions: i32) -> Result<()> {
+        let created = self
+            .created
+            .lock()
+            .expect("created mutex poisoned")
+            .contains(topic);
+        if created {
+            return Ok(());
+        }
+
+        // replicated as many times as the brokers' default
+        let new_topic = NewTopic::new(topic, partitions, TopicReplication::Fixed(-1));
+        let results = self
+            .admin
+            .create_topics([&new_topic], &AdminOptions::new())
+            .await?;
+        for result in results {
+            match result {
+                Ok(_) | Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+                Err((topic, code)) => anyhow::bail!("failed to create topic {topic}: {code}"),
+            }
+        }
+
+        self.created
+            .lock()
+            .expect("created mutex poisoned")
+            .insert(topic.to_owned());
+        Ok(())
+    }
+
+    /// the consumer for one of a queue's topics, joining its group the first time
+    fn consumer(&self, topic: &str) -> Result<Arc<TopicConsumer>> {
+        let cached = self
+            .consumers
+            .lock()
+            .expect("consumers mutex poisoned")
+            .get(topic)
+            .cloned();
+        if let Some(consumer) = cached {
+            return Ok(consumer);
+        }
+
+        let offsets = Arc::<Mutex<_>>::default();
+        let context = RebalanceContext {
+            offsets: offsets.clone(),
+        };
+
+        let consumer: BaseConsumer<RebalanceContext> = self
+            .client_config
+            .clone()
+            .set("group.id", topic)
+            // offsets are stored as messages are acked, and committed in the
+            // background
+            .set("enable.auto.offset.store", "false")
+            .set("enable.auto.commit", "true")
+            // from the start of the topic, so nothing sent before now is missed
+            .set("auto.offset.reset", "earliest")
+            .set(
+                "max.poll.interval.ms",
+                self.max_poll_interval_ms.to_string(),
+            )
+            .create_with_context(context)?;
+        consumer.subscribe(&[topic])?;
+
+        let consumer = Arc::new(TopicConsumer {
+            topic: topic.to_owned(),
+            consumer,
+            offsets,
+        });
+
+        Ok(self
+            .consumers
+            .lock()
+            .expect("consumers mutex poisoned")
+            .entry(topic.to_owned())
+            .or_insert(consumer)
+            .clone())
+    }
+
+    /// the consumers a queue is read from, highest priority first
+    fn queue_consumers(&self, queue: Queue<'_>) -> Result<Vec<QueueConsumer>> {
+        queue_topics(queue)
+            .into_iter()
+            .map(|(topic, priority)| {
+                Ok(QueueConsumer {
+                    consumer: self.consumer(&topic)?,
+                    priority,
+                })
+            })
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl Broker for KafkaBroker {
+    /// Creates the queue's topics. Consumers aren't created until something
+    /// takes from the queue, since a member of the group is given partitions.
+    async fn declare(&self, queue: Queue<'_>) -> Result<()> {
+        for (topic, _) in queue_topics(queue) {
+            self.create_topic(&topic, self.partitions).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, queue: Queue<'_>, data: &[u8], options: SendOptions) -> Result<()> {
+        self.declare(queue).await?;
+
+        let topic = match queue {
+            Queue::Tasks(task_queue) => {
+                task_topic(task_queue, options.priority.unwrap_or_default())
+            }
+            Queue::Results => RESULT_TOPIC.to_owned(),
+            Queue::TokenUpdates => TOKEN_UPDATES_TOPIC.to_owned(),
+        };
+
+        let mut record = FutureRecord::<(), [u8]>::to(&topic).payload(data);
+        if let Some(expiration_ms) = options.expiration_ms {
+            let expires = Utc::now().timestamp_millis() as u64 + expiration_ms;
+            record = record.headers(expires_header(&expires.to_string()));
+        }
+
+        // waiting for the delivery report means the message has been stored
+        self.producer
+            .send(record, Timeout::After(REQUEST_TIMEOUT))
+            .await
+            .map_err(|(err, _)| err)?;
+
+        Ok(())
+    }
+
+    async fn get(&self, queue: Queue<'_>) -> Result<Option<Delivery>> {
+        self.declare(queue).await?;
+
+        let consumers = self.queue_consumers(queue)?;
+        take_next(&self.producer, &consumers)
+    }
+
+    /// Consumers take from the process's shared consumer for each topic, which
+    /// fetches ahead on its own, so the prefetch doesn't apply. Messages are
+    /// taken one at a time, polling when there's nothing to take.
+    async fn consume(&self, queues: &[Queue<'_>], _prefetch: u16) -> Result<Consumer> {
+        let mut consumers = Vec::new();
+        for queue in queues {
+            self.declare(*queue).await?;
+            consumers.extend(self.queue_consumers(*queue)?);
+        }
+        // across all the queues, since a consumer takes from them together
+        consumers.sort_by_key(|consumer| std::cmp::Reverse(consumer.priority));
+
+        let state = (self.producer.clone(), consumers);
+        let deliveries = stream::unfold(state, |(producer, consumers)| async move {
+            loop {
+                match take_next(&producer, &consumers) {
+                    Ok(Some(delivery)) => return Some((Ok(delivery), (producer, consumers))),
+                    Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+                    Err(err) => return Some((Err(err), (producer, consumers))),
+                }
+            }
+        })
+        .boxed();
+
+        // anything taken but not acked holds back the committed offset, so is
+        // delivered again after a restart
+        let cancel = async { Ok(()) }.boxed();
+
+        Ok(Consumer { deliveries, cancel })
+    }
+
+    async fn publish(&self, topic: Topic, data: &[u8]) -> Result<()> {
+        let topic = topic_name(topic);
+        // a single partition, so subscribers see messages in the order they're sent
+        self.create_topic(topic, 1).await?;
+
+        self.producer
+            .send(
+                FutureRecord::<(), [u8]>::to(topic).payload(data),
+                Timeout::After(REQUEST_TIMEOUT),
+            )
+            .await
+            .map_err(|(err, _)| err)?;
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: Topic) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        let topic = topic_name(topic);
+        self.create_topic(topic, 1).await?;
+
+        // Assigned the partition rather than joining a group, so it's given
+        // every message. It starts from the end as it is now, since joining
+        // a group and starting from the latest offset would miss anything
+        // published before the group had assigned it the partition.
+        let consumer: StreamConsumer = self
+            .client_config
+            .clone()
+            .set("enable.auto.commit", "false")
+            .create()?;
+
+        let end = {
+            let producer = self.producer.clone();
+            let topic = topic.to_owned();
+            tokio::task::spawn_blocking(move || {
+                producer
+                    .client()
+                    .fetch_watermarks(&topic, 0, Timeout::After(REQUEST_TIMEOUT))
+            })
+            .await??
+            .1
+        };
+
+        let mut list = TopicPartitionList::new();
+        list.add_partition_offset(topic, 0, Offset::Offset(end))?;
+        consumer.assign(&list)?;
+
+        let messages = stream::unfold(consumer, |consumer| async move {
+            let data = match consumer.recv().await {
+                Ok(message) => Ok(message.payload().unwrap_or_default().to_vec()),
+                Err(err) => Err(err.into()),
+            };
+            Some((data, consumer))
+        });
+
+        Ok(messages.boxed())
+    }
+
+    /// fetching the cluster's metadata is a round trip to the brokers
+    async fn check(&self) -> Result<()> {
+        let producer = self.producer.clone();
+        tokio::task::spawn_blocking(move || {
+            producer
+                .client()
+                .fetch_metadata(None, Timeout::After(REQUEST_TIMEOUT))
+                .map(|_| ())
+        })
+        .await??;
+
+        Ok(())
+    }
+}
+
+/// the topics a queue is read from, highest priority first
+fn queue_topics(queue: Queue<'_>) -> Vec<(String, Option<TaskPriority>)> {
+    match queue {
+        Queue::Tasks(task_queue) => PRIORITIES
+            .into_iter()
+            .map(|priority| (task_topic(task_queue, priority), Some(priority)))
+            .collect(),
+        Queue::Results => vec![(RESULT_TOPIC.to_owned(), None)],
+        Queue::TokenUpdates => vec![(TOKEN_UPDATES_TOPIC.to_owned(), None)],
+    }
+}
+
+fn task_topic(task_queue: &str, priority: TaskPriority) -> String {
+    format!("waterwheel.tasks.{task_queue}.{}", priority.as_str())
+}
+
+fn topic_name(topic: Topic) -> &'static str {
+    match topic {
+        Topic::Config => "waterwheel.config",
+        Topic::WorkerControl => "waterwheel.worker_control",
+        Topic::TriggerUpdates => "waterwheel.updates.triggers",
+        Topic::Live => "waterwheel.live",
+    }
+}
+
+fn expires_header(expires: &str) -> OwnedHeaders {
+    OwnedHeaders::new().insert(Header {
+        key: EXPIRES_HEADER,
+        value: Some(expires.as_bytes()),
+    })
+}
+
+struct QueueConsumer {
+    consumer: Arc<TopicConsumer>,
+    priority: Option<TaskPriority>,
+}
+
+/// the next message from the highest priority consumer that has one
+fn take_next(producer: &FutureProducer, consumers: &[QueueConsumer]) -> Result<Option<Delivery>> {
+    for queue_consumer in consumers {
+        while let Some(message) = queue_consumer.consumer.poll()? {
+            let expires = message
+                .headers()
+                .and_then(|headers| headers.iter().find(|header| header.key == EXPIRES_HEADER))
+                .and_then(|header| header.value)
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .map(str::to_owned);
+
+            // an expired message is acked and dropped rather than delivered
+            let expired = expires
+                .as_deref()
+                .and_then(|expires| expires.parse::<i64>().ok())
+                .map_or(false, |expires| expires < Utc::now().timestamp_millis());
+            if expired {
+                queue_consumer
+                    .consumer
+                    .ack(message.partition(), message.offset());
+                continue;
+            }
+
+            let data = message.payload().unwrap_or_default().to_vec();
+            let acker = KafkaAcker {
+                consumer: queue_consumer.consumer.clone(),
+                producer: producer.clone(),
+                partition: message.partition(),
+                offset: message.offset(),
+                data: data.clone(),
+                expires,
+            };
+
+            return Ok(Some(Delivery {
+                data,
+                priority: queue_consumer.priority,
+                acker: Box::new(acker),
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// a process's member of the consumer group for one topic
+struct TopicConsumer {
+    topic: String,
+    consumer: BaseConsumer<RebalanceContext>,
+    /// what's been taken from each partition assigned to this member
+    offsets: Arc<Mutex<HashMap<i32, PartitionOffsets>>>,
+}
+
+/// Forgets what was taken from partitions when they're revoked or assigned,
+/// so offsets are never stored for partitions another member owns, or stored
+/// from a previous assignment.
+struct RebalanceContext {
+    offsets: Arc<Mutex<HashMap<i32, PartitionOffsets>>>,
+}
+
+impl RebalanceContext {
+    fn forget(&self, list: &TopicPartitionList) {
+        let mut offsets = self.offsets.lock().expect("offsets mutex poisoned");
+        for element in list.elements() {
+            offsets.remove(&element.partition());
+        }
+    }
+}
+
+impl ClientContext for RebalanceContext {}
+
+impl ConsumerContext for RebalanceContext {
+    fn pre_rebalance(&self, _: &BaseConsumer<Self>, rebalance: &Rebalance<'_>) {
+        if let Rebalance::Revoke(list) = rebalance {
+            self.forget(list);
+        }
+    }
+
+    fn post_rebalance(&self, _: &BaseConsumer<Self>, rebalance: &Rebalance<'_>) {
+        if let Rebalance::Assign(list) = rebalance {
+            self.forget(list);
+        }
+    }
+}
+
+#[derive(Default)]
+struct PartitionOffsets {
+    /// messages that have been taken but not acked yet
+    taken: BTreeSet<i64>,
+    /// the offset after the last message taken
+    next: i64,
+}
+
+impl TopicConsumer {
+    /// the next message, if one has been fetched already
+    fn poll(&self) -> Result<Option<OwnedMessage>> {
+        let message = match self.consumer.poll(Duration::ZERO) {
+            Some(message) => message?.detach(),
+            None => return Ok(None),
+        };
+
+        let mut offsets = self.offsets.lock().expect("offsets mutex poisoned");
+        let partition = offsets.entry(message.partition()).or_default();
+        partition.taken.insert(message.offset());
+        partition.next = partition.next.max(message.offset() + 1);
+
+        Ok(Some(message))
+    }
+
+    /// Acks can come out of order, since several slots share the consumer, so
+    /// the offset stored to be committed is the earliest message still taken.
+    /// Messages taken before the partition was revoked are ignored, the member
+    /// that has it now delivers them again.
+    fn ack(&self, partition: i32, offset: i64) {
+        let commit = {
+            let mut offsets = self.offsets.lock().expect("offsets mutex poisoned");
+            let offsets = match offsets.get_mut(&partition) {
+                Some(offsets) => offsets,
+                None => return,
+            };
+            if !offsets.taken.remove(&offset) {
+                return;
+            }
+            offsets.taken.iter().next().copied().unwrap_or(offsets.next)
+        };
+
+        let mut list = TopicPartitionList::new();
+        let stored = list
+            .add_partition_offset(&self.topic, partition, Offset::Offset(commit))
+            .and_then(|_| self.consumer.store_offsets(&list));
+        if let Err(err) = stored {
+            // the partition has been given to another process, which takes
+            // from the last committed offset, so the message will be delivered
+            // again
+            warn!(topic=%self.topic, partition, offset, "failed to store offset: {}", err);
+        }
+    }
+}
+
+struct KafkaAcker {
+    consumer: Arc<TopicConsumer>,
+    producer: FutureProducer,
+    partition: i32,
+    offset: i64,
+    data: Vec<u8>,
+    expires: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl Acker for KafkaAcker {
+    async fn ack(&self) -> Result<()> {
+        self.consumer.ack(self.partition, self.offset);
+        Ok(())
+    }
+
+    /// sent to the end of the topic again, since a partition can't be reordered
+    async fn requeue(&self) -> Result<()> {
+        let mut record = FutureRecord::<(), [u8]>::to(&self.consumer.topic).payload(&self.data);
+        if let Some(expires) = &self.expires {
+            record = record.headers(expires_header(expires));
+        }
+
+        self.producer
+            .send(record, Timeout::After(REQUEST_TIMEOUT))
+            .await
+            .map_err(|(err, _)| err)?;
+
+        self.ack().await
+    }
+}
@@ -0,0 +1,340 @@
+use super::{Acker, Broker, Consumer, Delivery, Queue, SendOptions, Topic};
+use crate::{config::Config, messages::TaskPriority};
+use anyhow::Result;
+use async_nats::{
+    jetstream::{
+        self,
+        consumer::{pull, AckPolicy, PullConsumer},
+        message::{AckKind, Acker as MessageAcker},
+        stream::{self as jetstream_stream, RetentionPolicy},
+        Message,
+    },
+    Client, HeaderMap,
+};
+use chrono::Utc;
+use futures::{
+    stream::{self, BoxStream},
+    FutureExt, StreamExt, TryStreamExt,
+};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+const TASKS_STREAM: &str = "WATERWHEEL_TASKS";
+const RESULTS_STREAM: &str = "WATERWHEEL_RESULTS";
+const TOKEN_UPDATES_STREAM: &str = "WATERWHEEL_TOKEN_UPDATES";
+
+const RESULT_SUBJECT: &str = "waterwheel.results";
+const TOKEN_UPDATES_SUBJECT: &str = "waterwheel.updates.tokens";
+
+/// JetStream has no per-message expiry, so it's checked when the message is taken
+const EXPIRES_HEADER: &str = "Waterwheel-Expires";
+
+/// how often a consumer with nothing to do looks for messages again
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// messages on the scheduler's queues are handled quickly, so ones that haven't
+/// been acked after this long are delivered again
+const SCHEDULER_ACK_WAIT: Duration = Duration::from_secs(60);
+
+/// task queues have a subject for each priority, taken from highest first
+const PRIORITIES: [TaskPriority; 4] = [
+    TaskPriority::High,
+    TaskPriority::Normal,
+    TaskPriority::Low,
+    TaskPriority::BackFill,
+];
+
+/// Queues are subjects in work queue streams, each read by a durable pull
+/// consumer that every process taking from the queue shares, so a message is
+/// removed once it's acked. Messages that aren't acked within
+/// `amqp_consumer_timeout` (for tasks) or a minute (for everything else) are
+/// delivered again, as RabbitMQ does when a consumer goes away. JetStream
+/// doesn't have priorities, so each task queue has a subject per priority.
+/// Topics are plain NATS subjects, so like RabbitMQ's fanout exchanges they
+/// only reach whoever is subscribed at the time.
+pub struct NatsBroker {
+    client: Client,
+    jetstream: jetstream::Context,
+    task_ack_wait: Duration,
+    /// consumers, by subject, are only created the first time they're used
+    consumers: Mutex<HashMap<String, PullConsumer>>,
+}
+
+impl NatsBroker {
+    pub async fn new(config: &Config) -> Result<Self> {
+        let client = async_nats::connect(config.nats_url.as_str()).await?;
+        let jetstream = jetstream::new(client.clone());
+
+        Ok(NatsBroker {
+            client,
+            jetstream,
+            task_ack_wait: Duration::from_secs(config.amqp_consumer_timeout),
+            consumers: Mutex::default(),
+        })
+    }
+
+    /// the consumers a queue is read from, highest priority first
+    async fn queue_consumers(&self, queue: Queue<'_>) -> Result<Vec<QueueConsumer>> {
+        let subjects = match queue {
+            Queue::Tasks(task_queue) => PRIORITIES
+                .into_iter()
+                .map(|priority| (task_subject(task_queue, priority), Some(priority)))
+                .collect(),
+            Queue::Results => vec![(RESULT_SUBJECT.to_owned(), None)],
+            Queue::TokenUpdates => vec![(TOKEN_UPDATES_SUBJECT.to_owned(), None)],
+        };
+
+        let mut consumers = Vec::new();
+        for (subject, priority) in subjects {
+            consumers.push(QueueConsumer {
+                consumer: self.consumer(queue, &subject).await?,
+                priority,
+            });
+        }
+
+        Ok(consumers)
+    }
+
+    /// the consumer for one of a queue's subjects, creating it and its stream
+    /// the first time
+    async fn consumer(&self, queue: Queue<'_>, subject: &str) -> Result<PullConsumer> {
+        let cached = self
+            .consumers
+            .lock()
+            .expect("consumers mutex poisoned")
+            .get(subject)
+            .cloned();
+        if let Some(consumer) = cached {
+            return Ok(consumer);
+        }
+
+        let (stream_name, stream_subject, ack_wait) = match queue {
+            Queue::Tasks(_) => (TASKS_STREAM, "waterwheel.tasks.>", self.task_ack_wait),
+            Queue::Results => (RESULTS_STREAM, RESULT_SUBJECT, SCHEDULER_ACK_WAIT),
+            Queue::TokenUpdates => (
+                TOKEN_UPDATES_STREAM,
+                TOKEN_UPDATES_SUBJECT,
+                SCHEDULER_ACK_WAIT,
+            ),
+        };
+
+        let stream = self
+            .jetstream
+            .get_or_create_stream(jetstream_stream::Config {
+                name: stream_name.to_owned(),
+                subjects: vec![stream_subject.to_owned()],
+                retention: RetentionPolicy::WorkQueue,
+                ..jetstream_stream::Config::default()
+            })
+            .await?;
+
+        // consumer names can't contain dots
+        let name = subject.replace('.', "-");
+        let consumer: PullConsumer = stream
+            .get_or_create_consumer(
+                &name,
+                pull::Config {
+                    durable_name: Some(name.clone()),
+                    filter_subject: subject.to_owned(),
+                    ack_policy: AckPolicy::Explicit,
+                    ack_wait,
+                    ..pull::Config::default()
+                },
+            )
+            .await?;
+
+        self.consumers
+            .lock()
+            .expect("consumers mutex poisoned")
+            .insert(subject.to_owned(), consumer.clone());
+        Ok(consumer)
+    }
+}
+
+#[async_trait::async_trait]
+impl Broker for NatsBroker {
+    async fn declare(&self, queue: Queue<'_>) -> Result<()> {
+        self.queue_consumers(queue).await?;
+        Ok(())
+    }
+
+    async fn send(&self, queue: Queue<'_>, data: &[u8], options: SendOptions) -> Result<()> {
+        // a work queue stream keeps messages no consumer is interested in yet,
+        // but the stream itself must exist
+        self.declare(queue).await?;
+
+        let subject = match queue {
+            Queue::Tasks(task_queue) => {
+                task_subject(task_queue, options.priority.unwrap_or_default())
+            }
+            Queue::Results => RESULT_SUBJECT.to_owned(),
+            Queue::TokenUpdates => TOKEN_UPDATES_SUBJECT.to_owned(),
+        };
+
+        let mut headers = HeaderMap::new();
+        if let Some(expiration_ms) = options.expiration_ms {
+            let expires = Utc::now().timestamp_millis() as u64 + expiration_ms;
+            headers.insert(EXPIRES_HEADER, expires.to_string().as_str());
+        }
+
+        // waiting for the ack means the message has been stored
+        self.jetstream
+            .publish_with_headers(subject, headers, data.to_vec().into())
+            .await?
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, queue: Queue<'_>) -> Result<Option<Delivery>> {
+        let consumers = self.queue_consumers(queue).await?;
+        take_next(&consumers).await
+    }
+
+    /// A consumer of a single subject is sent up to `prefetch` messages at a
+    /// time. A consumer of several takes one at a time, highest priority
+    /// first, polling when there's nothing to take.
+    async fn consume(&self, queues: &[Queue<'_>], prefetch: u16) -> Result<Consumer> {
+        let mut consumers = Vec::new();
+        for queue in queues {
+            consumers.extend(self.queue_consumers(*queue).await?);
+        }
+        // across all the queues, since a consumer takes from them together
+        consumers.sort_by_key(|consumer| std::cmp::Reverse(consumer.priority));
+
+        let deliveries = if let [single] = consumers.as_slice() {
+            let priority = single.priority;
+            single
+                .consumer
+                .stream()
+                .max_messages_per_batch(prefetch.into())
+                .messages()
+                .await?
+                .map_err(anyhow::Error::msg)
+                .try_filter_map(move |message| delivery(message, priority))
+                .boxed()
+        } else {
+            stream::unfold(consumers, |consumers| async move {
+                loop {
+                    match take_next(&consumers).await {
+                        Ok(Some(delivery)) => return Some((Ok(delivery), consumers)),
+                        Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+                        Err(err) => return Some((Err(err), consumers)),
+                    }
+                }
+            })
+            .boxed()
+        };
+
+        // anything taken but not acked is delivered again after the ack wait
+        let cancel = async { Ok(()) }.boxed();
+
+        Ok(Consumer { deliveries, cancel })
+    }
+
+    async fn publish(&self, topic: Topic, data: &[u8]) -> Result<()> {
+        self.client
+            .publish(topic_subject(topic).to_owned(), data.to_vec().into())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: Topic) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        let subscriber = self
+            .client
+            .subscribe(topic_subject(topic).to_owned())
+            .await?;
+
+        Ok(subscriber
+            .map(|message| Ok(message.payload.to_vec()))
+            .boxed())
+    }
+
+    /// a round trip to the server
+    async fn check(&self) -> Result<()> {
+        self.client.flush().await?;
+        Ok(())
+    }
+}
+
+fn task_subject(task_queue: &str, priority: TaskPriority) -> String {
+    format!("waterwheel.tasks.{task_queue}.{}", priority.as_str())
+}
+
+fn topic_subject(topic: Topic) -> &'static str {
+    match topic {
+        Topic::Config => "waterwheel.config",
+        Topic::WorkerControl => "waterwheel.worker_control",
+        Topic::TriggerUpdates => "waterwheel.updates.triggers",
+        Topic::Live => "waterwheel.live",
+    }
+}
+
+struct QueueConsumer {
+    consumer: PullConsumer,
+    priority: Option<TaskPriority>,
+}
+
+/// the next message from the highest priority consumer that has one
+async fn take_next(consumers: &[QueueConsumer]) -> Result<Option<Delivery>> {
+    for queue_consumer in consumers {
+        loop {
+            let mut batch = queue_consumer
+                .consumer
+                .fetch()
+                .max_messages(1)
+                .messages()
+                .await
+                .map_err(anyhow::Error::msg)?;
+
+            let message = match batch.try_next().await.map_err(anyhow::Error::msg)? {
+                Some(message) => message,
+                None => break,
+            };
+
+            if let Some(delivery) = delivery(message, queue_consumer.priority).await? {
+                return Ok(Some(delivery));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// an expired message is acked and dropped rather than delivered
+async fn delivery(message: Message, priority: Option<TaskPriority>) -> Result<Option<Delivery>> {
+    let (message, acker) = message.split();
+
+    let expires = message
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(EXPIRES_HEADER))
+        .and_then(|expires| expires.as_str().parse::<i64>().ok());
+    if expires.map_or(false, |expires| expires < Utc::now().timestamp_millis()) {
+        acker.ack().await.map_err(anyhow::Error::msg)?;
+        return Ok(None);
+    }
+
+    Ok(Some(Delivery {
+        data: message.payload.to_vec(),
+        priority,
+        acker: Box::new(NatsAcker(acker)),
+    }))
+}
+
+struct NatsAcker(MessageAcker);
+
+#[async_trait::async_trait]
+impl Acker for NatsAcker {
+    async fn ack(&self) -> Result<()> {
+        self.0.ack().await.map_err(anyhow::Error::msg)
+    }
+
+    /// delivered again straight away, keeping its place in the stream
+    async fn requeue(&self) -> Result<()> {
+        self.0
+            .ack_with(AckKind::Nak(None))
+            .await
+            .map_err(anyhow::Error::msg)
+    }
+}
@@ -0,0 +1,369 @@
+use super::{Acker, Broker, Consumer, Delivery, Queue, SendOptions, Topic};
+use crate::{
+    amqp::{amqp_connect, task_queue_args},
+    config::Config,
+    messages::{queue_amqp_name, queue_routing_key, TaskPriority},
+};
+use anyhow::Result;
+use futures::{
+    stream::{self, BoxStream},
+    FutureExt, StreamExt, TryStreamExt,
+};
+use lapin::{
+    acker::Acker as DeliveryAcker,
+    message::Delivery as AmqpDelivery,
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicGetOptions, BasicNackOptions,
+        BasicPublishOptions, BasicQosOptions, ExchangeDeclareOptions, QueueBindOptions,
+        QueueDeclareOptions,
+    },
+    types::FieldTable,
+    BasicProperties, Channel, Connection, ExchangeKind,
+};
+use std::{collections::HashSet, sync::Mutex};
+
+const TASK_EXCHANGE: &str = "waterwheel.tasks";
+const RESULT_QUEUE: &str = "waterwheel.results";
+const TOKEN_UPDATES_EXCHANGE: &str = "waterwheel.updates.tokens";
+const TOKEN_UPDATES_QUEUE: &str = "waterwheel.updates.tokens";
+
+const PERSISTENT: u8 = 2;
+
+/// Queues are AMQP queues and topics are fanout exchanges, with the same names
+/// as before the broker could be changed, so `waterwheel admin drain-queue`
+/// and older workers still find them.
+pub struct RabbitMqBroker {
+    conn: Connection,
+    /// For publishing and taking single messages, consumers get their own. A
+    /// channel error closes the channel, so it's reopened the next time it's used.
+    chan: tokio::sync::Mutex<Channel>,
+    task_queue_args: FieldTable,
+    /// queues and exchanges are only declared the first time they're used
+    declared: Mutex<HashSet<String>>,
+}
+
+impl RabbitMqBroker {
+    pub async fn new(config: &Config) -> Result<Self> {
+        let conn = amqp_connect(config).await?;
+        let chan = conn.create_channel().await?;
+
+        Ok(RabbitMqBroker {
+            conn,
+            chan: tokio::sync::Mutex::new(chan),
+            task_queue_args: task_queue_args(config),
+            declared: Mutex::default(),
+        })
+    }
+
+    /// the shared channel, reopened if an error closed it
+    async fn channel(&self) -> Result<Channel> {
+        let mut chan = self.chan.lock().await;
+        if !chan.status().connected() {
+            *chan = self.conn.create_channel().await?;
+        }
+
+        Ok(chan.clone())
+    }
+
+    fn is_declared(&self, name: &str) -> bool {
+        self.declared
+            .lock()
+            .expect("declared mutex poisoned")
+            .contains(name)
+    }
+
+    fn set_declared(&self, name: String) {
+        self.declared
+            .lock()
+            .expect("declared mutex poisoned")
+            .insert(name);
+    }
+
+    async fn declare_exchange(&self, topic: Topic) -> Result<()> {
+        let exchange = topic_exchange(topic);
+        if self.is_declared(exchange) {
+            return Ok(());
+        }
+
+        self.channel()
+            .await?
+            .exchange_declare(
+                exchange,
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions {
+                    // nothing is lost if no one is watching the live updates
+                    durable: topic != Topic::Live,
+                    ..ExchangeDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        self.set_declared(exchange.to_owned());
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Broker for RabbitMqBroker {
+    async fn declare(&self, queue: Queue<'_>) -> Result<()> {
+        let name = queue_name(queue);
+        if self.is_declared(&name) {
+            return Ok(());
+        }
+
+        let durable = QueueDeclareOptions {
+            durable: true,
+            ..QueueDeclareOptions::default()
+        };
+        let durable_exchange = ExchangeDeclareOptions {
+            durable: true,
+            ..ExchangeDeclareOptions::default()
+        };
+
+        let chan = self.channel().await?;
+        match queue {
+            Queue::Tasks(task_queue) => {
+                chan.exchange_declare(
+                    TASK_EXCHANGE,
+                    ExchangeKind::Direct,
+                    durable_exchange,
+                    FieldTable::default(),
+                )
+                .await?;
+                chan.queue_declare(&name, durable, self.task_queue_args.clone())
+                    .await?;
+                chan.queue_bind(
+                    &name,
+                    TASK_EXCHANGE,
+                    queue_routing_key(task_queue),
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+            }
+            Queue::Results => {
+                chan.queue_declare(&name, durable, FieldTable::default())
+                    .await?;
+            }
+            Queue::TokenUpdates => {
+                chan.exchange_declare(
+                    TOKEN_UPDATES_EXCHANGE,
+                    ExchangeKind::Direct,
+                    durable_exchange,
+                    FieldTable::default(),
+                )
+                .await?;
+                chan.queue_declare(&name, durable, FieldTable::default())
+                    .await?;
+                chan.queue_bind(
+                    &name,
+                    TOKEN_UPDATES_EXCHANGE,
+                    "",
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+            }
+        }
+
+        self.set_declared(name);
+        Ok(())
+    }
+
+    async fn send(&self, queue: Queue<'_>, data: &[u8], options: SendOptions) -> Result<()> {
+        self.declare(queue).await?;
+
+        let mut props = BasicProperties::default().with_delivery_mode(PERSISTENT);
+        if let Some(priority) = options.priority {
+            props = props.with_priority(priority as u8);
+        }
+        if let Some(expiration_ms) = options.expiration_ms {
+            props = props.with_expiration(expiration_ms.to_string().into());
+        }
+
+        let (exchange, routing_key) = match queue {
+            Queue::Tasks(task_queue) => (TASK_EXCHANGE, queue_routing_key(task_queue)),
+            Queue::Results => ("", RESULT_QUEUE),
+            Queue::TokenUpdates => (TOKEN_UPDATES_EXCHANGE, ""),
+        };
+
+        self.channel()
+            .await?
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions::default(),
+                data,
+                props,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, queue: Queue<'_>) -> Result<Option<Delivery>> {
+        self.declare(queue).await?;
+
+        let message = self
+            .channel()
+            .await?
+            .basic_get(&queue_name(queue), BasicGetOptions::default())
+            .await?;
+
+        Ok(message.map(|message| delivery(message.delivery)))
+    }
+
+    async fn consume(&self, queues: &[Queue<'_>], prefetch: u16) -> Result<Consumer> {
+        let chan = self.conn.create_channel().await?;
+
+        // the prefetch is shared by every consumer on the channel
+        chan.basic_qos(prefetch, BasicQosOptions { global: true })
+            .await?;
+
+        let mut consumers = Vec::new();
+        for queue in queues {
+            self.declare(*queue).await?;
+
+            let name = queue_name(*queue);
+            let consumer = chan
+                .basic_consume(
+                    &name,
+                    // tags must be unique on the channel
+                    &format!("waterwheel-{name}"),
+                    BasicConsumeOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+            consumers.push(consumer);
+        }
+
+        let deliveries = stream::select_all(consumers)
+            .map_ok(delivery)
+            .map_err(anyhow::Error::from)
+            .boxed();
+
+        // closing the channel gives back anything taken but not acked
+        let cancel = async move {
+            chan.close(200, "consumer cancelled").await?;
+            Ok(())
+        }
+        .boxed();
+
+        Ok(Consumer { deliveries, cancel })
+    }
+
+    async fn publish(&self, topic: Topic, data: &[u8]) -> Result<()> {
+        self.declare_exchange(topic).await?;
+
+        self.channel()
+            .await?
+            .basic_publish(
+                topic_exchange(topic),
+                "",
+                BasicPublishOptions::default(),
+                data,
+                BasicProperties::default(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: Topic) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        self.declare_exchange(topic).await?;
+
+        // each subscriber gets its own queue, deleted when it disconnects
+        let chan = self.conn.create_channel().await?;
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        chan.queue_bind(
+            queue.name().as_str(),
+            topic_exchange(topic),
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+        let consumer = chan
+            .basic_consume(
+                queue.name().as_str(),
+                "subscriber",
+                BasicConsumeOptions {
+                    no_ack: true,
+                    ..BasicConsumeOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        Ok(consumer
+            .map_ok(|delivery| delivery.data)
+            .map_err(anyhow::Error::from)
+            .boxed())
+    }
+
+    async fn check(&self) -> Result<()> {
+        if self.conn.status().connected() {
+            Ok(())
+        } else {
+            Err(anyhow::format_err!("the connection isn't connected"))
+        }
+    }
+}
+
+fn queue_name(queue: Queue<'_>) -> String {
+    match queue {
+        Queue::Tasks(task_queue) => queue_amqp_name(task_queue),
+        Queue::Results => RESULT_QUEUE.to_owned(),
+        Queue::TokenUpdates => TOKEN_UPDATES_QUEUE.to_owned(),
+    }
+}
+
+fn topic_exchange(topic: Topic) -> &'static str {
+    match topic {
+        Topic::Config => "waterwheel.config",
+        Topic::WorkerControl => "waterwheel.worker_control",
+        Topic::TriggerUpdates => "waterwheel.updates.triggers",
+        Topic::Live => "waterwheel.live",
+    }
+}
+
+fn delivery(delivery: AmqpDelivery) -> Delivery {
+    Delivery {
+        priority: delivery.properties.priority().map(TaskPriority::from_amqp),
+        data: delivery.data,
+        acker: Box::new(RabbitMqAcker(delivery.acker)),
+    }
+}
+
+struct RabbitMqAcker(DeliveryAcker);
+
+#[async_trait::async_trait]
+impl Acker for RabbitMqAcker {
+    async fn ack(&self) -> Result<()> {
+        self.0.ack(BasicAckOptions::default()).await?;
+        Ok(())
+    }
+
+    async fn requeue(&self) -> Result<()> {
+        self.0
+            .nack(BasicNackOptions {
+                requeue: true,
+                ..BasicNackOptions::default()
+            })
+            .await?;
+        Ok(())
+    }
+}
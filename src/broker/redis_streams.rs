@@ -0,0 +1,374 @@
+use super::{Acker, Broker, Consumer, Delivery, Queue, SendOptions, Topic};
+use crate::{config::Config, messages::TaskPriority};
+use anyhow::Result;
+use chrono::Utc;
+use futures::{
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
+use redis::{
+    aio::MultiplexedConnection,
+    streams::{
+        StreamClaimReply, StreamId, StreamPendingCountReply, StreamRangeReply, StreamReadOptions,
+        StreamReadReply,
+    },
+    AsyncCommands, Client,
+};
+use std::{collections::VecDeque, time::Duration};
+use uuid::Uuid;
+
+/// the consumer group every queue's streams are read by
+const GROUP: &str = "waterwheel";
+
+/// how often a consumer with nothing to do looks for messages again
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// messages on the scheduler's queues are handled quickly, so ones that haven't
+/// been acked after this long are taken by another consumer
+const SCHEDULER_CLAIM_AFTER: Duration = Duration::from_secs(60);
+
+/// how long a subscriber waits for a message before asking again
+const SUBSCRIBE_BLOCK_MS: usize = 5000;
+
+/// topics only keep messages for as long as it takes subscribers to read them
+const TOPIC_MAX_LEN: usize = 10_000;
+
+/// task queues have a stream for each priority, taken from highest first
+const PRIORITIES: [TaskPriority; 4] = [
+    TaskPriority::High,
+    TaskPriority::Normal,
+    TaskPriority::Low,
+    TaskPriority::BackFill,
+];
+
+/// Queues are streams read by a consumer group, and topics are streams each
+/// subscriber reads on its own. Streams don't have priorities, so each task
+/// queue has one per priority. Messages taken by a consumer that went away are
+/// taken again once they've been pending for `amqp_consumer_timeout` (for
+/// tasks) or a minute (for everything else).
+pub struct RedisStreamsBroker {
+    client: Client,
+    conn: MultiplexedConnection,
+    task_claim_after: Duration,
+    /// the consumer `get` takes messages as
+    consumer_name: String,
+}
+
+impl RedisStreamsBroker {
+    pub async fn new(config: &Config) -> Result<Self> {
+        let client = Client::open(config.redis_url.as_ref())?;
+        let conn = client.get_multiplexed_tokio_connection().await?;
+
+        Ok(RedisStreamsBroker {
+            client,
+            conn,
+            task_claim_after: Duration::from_secs(config.amqp_consumer_timeout),
+            consumer_name: Uuid::new_v4().to_string(),
+        })
+    }
+
+    /// the streams a queue is read from, highest priority first
+    fn streams(&self, queue: Queue<'_>) -> Vec<QueueStream> {
+        match queue {
+            Queue::Tasks(task_queue) => PRIORITIES
+                .into_iter()
+                .map(|priority| QueueStream {
+                    key: task_stream(task_queue, priority),
+                    priority: Some(priority),
+                    claim_after: self.task_claim_after,
+                })
+                .collect(),
+            Queue::Results => vec![QueueStream {
+                key: "waterwheel:results".to_owned(),
+                priority: None,
+                claim_after: SCHEDULER_CLAIM_AFTER,
+            }],
+            Queue::TokenUpdates => vec![QueueStream {
+                key: "waterwheel:updates:tokens".to_owned(),
+                priority: None,
+                claim_after: SCHEDULER_CLAIM_AFTER,
+            }],
+        }
+    }
+
+    fn reader(&self, queues: &[Queue<'_>], consumer_name: String) -> Reader {
+        let mut streams = queues
+            .iter()
+            .flat_map(|queue| self.streams(*queue))
+            .collect::<Vec<_>>();
+        // across all the queues, since a consumer takes from them together
+        streams.sort_by_key(|stream| std::cmp::Reverse(stream.priority));
+
+        Reader {
+            conn: self.conn.clone(),
+            streams,
+            consumer_name,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Broker for RedisStreamsBroker {
+    async fn declare(&self, queue: Queue<'_>) -> Result<()> {
+        let mut conn = self.conn.clone();
+
+        for stream in self.streams(queue) {
+            // from the start of the stream, so nothing sent before now is missed
+            let created: redis::RedisResult<()> =
+                conn.xgroup_create_mkstream(&stream.key, GROUP, "0").await;
+            match created {
+                Err(err) if err.code() != Some("BUSYGROUP") => return Err(err.into()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, queue: Queue<'_>, data: &[u8], options: SendOptions) -> Result<()> {
+        self.declare(queue).await?;
+
+        let key = match queue {
+            Queue::Tasks(task_queue) => {
+                task_stream(task_queue, options.priority.unwrap_or_default())
+            }
+            _ => self.streams(queue).remove(0).key,
+        };
+
+        let mut cmd = redis::cmd("XADD");
+        cmd.arg(&key).arg("*").arg("data").arg(data);
+        if let Some(expiration_ms) = options.expiration_ms {
+            let expires = Utc::now().timestamp_millis() as u64 + expiration_ms;
+            cmd.arg("expires").arg(expires);
+        }
+
+        let _: String = cmd.query_async(&mut self.conn.clone()).await?;
+        Ok(())
+    }
+
+    async fn get(&self, queue: Queue<'_>) -> Result<Option<Delivery>> {
+        self.declare(queue).await?;
+
+        self.reader(&[queue], self.consumer_name.clone())
+            .take_next()
+            .await
+    }
+
+    /// Messages are taken one at a time, so the prefetch is always one. Consumers
+    /// poll rather than block, since a blocking read across several streams can
+    /// return a message from each of them.
+    async fn consume(&self, queues: &[Queue<'_>], _prefetch: u16) -> Result<Consumer> {
+        for queue in queues {
+            self.declare(*queue).await?;
+        }
+
+        let reader = self.reader(queues, Uuid::new_v4().to_string());
+
+        let deliveries = stream::unfold(reader, |mut reader| async move {
+            loop {
+                match reader.take_next().await {
+                    Ok(Some(delivery)) => return Some((Ok(delivery), reader)),
+                    Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+                    Err(err) => return Some((Err(err), reader)),
+                }
+            }
+        })
+        .boxed();
+
+        // nothing is held that hasn't been handed out
+        let cancel = async { Ok(()) }.boxed();
+
+        Ok(Consumer { deliveries, cancel })
+    }
+
+    async fn publish(&self, topic: Topic, data: &[u8]) -> Result<()> {
+        let _: String = redis::cmd("XADD")
+            .arg(topic_stream(topic))
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(TOPIC_MAX_LEN)
+            .arg("*")
+            .arg("data")
+            .arg(data)
+            .query_async(&mut self.conn.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: Topic) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        // blocking reads need a connection of their own
+        let mut conn = self.client.get_tokio_connection().await?;
+        let key = topic_stream(topic);
+
+        // start after the latest message, rather than at `$` each time, so
+        // nothing published between reads is missed
+        let latest: StreamRangeReply = conn.xrevrange_count(key, "+", "-", 1).await?;
+        let last_id = latest
+            .ids
+            .first()
+            .map_or_else(|| "0-0".to_owned(), |entry| entry.id.clone());
+
+        let state = (conn, last_id, VecDeque::new());
+
+        let messages = stream::unfold(
+            state,
+            move |(mut conn, mut last_id, mut pending)| async move {
+                let opts = StreamReadOptions::default()
+                    .block(SUBSCRIBE_BLOCK_MS)
+                    .count(100);
+                while pending.is_empty() {
+                    let reply: StreamReadReply =
+                        match conn.xread_options(&[key], &[last_id.as_str()], &opts).await {
+                            Ok(reply) => reply,
+                            Err(err) => return Some((Err(err.into()), (conn, last_id, pending))),
+                        };
+
+                    for entry in reply.keys.into_iter().flat_map(|key| key.ids) {
+                        last_id = entry.id.clone();
+                        pending.push_back(entry.get::<Vec<u8>>("data").unwrap_or_default());
+                    }
+                }
+
+                let data = pending.pop_front().expect("pending messages aren't empty");
+                Some((Ok(data), (conn, last_id, pending)))
+            },
+        );
+
+        Ok(messages.boxed())
+    }
+
+    async fn check(&self) -> Result<()> {
+        let _: String = redis::cmd("PING")
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+}
+
+fn task_stream(task_queue: &str, priority: TaskPriority) -> String {
+    format!("waterwheel:tasks:{task_queue}:{}", priority.as_str())
+}
+
+fn topic_stream(topic: Topic) -> &'static str {
+    match topic {
+        Topic::Config => "waterwheel:config",
+        Topic::WorkerControl => "waterwheel:worker_control",
+        Topic::TriggerUpdates => "waterwheel:updates:triggers",
+        Topic::Live => "waterwheel:live",
+    }
+}
+
+struct QueueStream {
+    key: String,
+    priority: Option<TaskPriority>,
+    claim_after: Duration,
+}
+
+/// takes messages from a consumer's streams as one member of the group
+struct Reader {
+    conn: MultiplexedConnection,
+    streams: Vec<QueueStream>,
+    consumer_name: String,
+}
+
+impl Reader {
+    /// the next message from the highest priority stream that has one
+    async fn take_next(&mut self) -> Result<Option<Delivery>> {
+        for i in 0..self.streams.len() {
+            while let Some(entry) = self.read(i).await? {
+                let stream = &self.streams[i];
+                let acker = RedisAcker {
+                    conn: self.conn.clone(),
+                    key: stream.key.clone(),
+                    entry: entry.clone(),
+                };
+
+                // an expired message is dropped rather than delivered
+                let expires = entry.get::<i64>("expires");
+                if expires.map_or(false, |expires| expires < Utc::now().timestamp_millis()) {
+                    acker.ack().await?;
+                    continue;
+                }
+
+                return Ok(Some(Delivery {
+                    data: entry.get("data").unwrap_or_default(),
+                    priority: stream.priority,
+                    acker: Box::new(acker),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// one message from a stream, taking over the oldest one taken by another
+    /// consumer first, if it's been pending too long
+    async fn read(&mut self, i: usize) -> Result<Option<StreamId>> {
+        let stream = &self.streams[i];
+        let claim_after = stream.claim_after.as_millis() as usize;
+
+        let pending: StreamPendingCountReply = self
+            .conn
+            .xpending_count(&stream.key, GROUP, "-", "+", 1)
+            .await?;
+        if let Some(oldest) = pending.ids.first() {
+            if oldest.last_delivered_ms >= claim_after {
+                let claimed: StreamClaimReply = self
+                    .conn
+                    .xclaim(
+                        &stream.key,
+                        GROUP,
+                        &self.consumer_name,
+                        claim_after,
+                        &[&oldest.id],
+                    )
+                    .await?;
+                if let Some(entry) = claimed.ids.into_iter().next() {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+
+        let opts = StreamReadOptions::default()
+            .group(GROUP, &self.consumer_name)
+            .count(1);
+        let reply: StreamReadReply = self
+            .conn
+            .xread_options(&[stream.key.as_str()], &[">"], &opts)
+            .await?;
+
+        Ok(reply.keys.into_iter().flat_map(|key| key.ids).next())
+    }
+}
+
+struct RedisAcker {
+    conn: MultiplexedConnection,
+    key: String,
+    entry: StreamId,
+}
+
+#[async_trait::async_trait]
+impl Acker for RedisAcker {
+    /// acked messages are deleted too, so streams don't grow forever
+    async fn ack(&self) -> Result<()> {
+        let mut conn = self.conn.clone();
+        let _: i64 = conn.xack(&self.key, GROUP, &[&self.entry.id]).await?;
+        let _: i64 = conn.xdel(&self.key, &[&self.entry.id]).await?;
+        Ok(())
+    }
+
+    /// added to the back of the stream again, since a stream can't be reordered
+    async fn requeue(&self) -> Result<()> {
+        let mut cmd = redis::cmd("XADD");
+        cmd.arg(&self.key).arg("*");
+        for field in self.entry.map.keys() {
+            if let Some(value) = self.entry.get::<Vec<u8>>(field) {
+                cmd.arg(field).arg(value);
+            }
+        }
+
+        let _: String = cmd.query_async(&mut self.conn.clone()).await?;
+        self.ack().await
+    }
+}
@@ -1,9 +1,13 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Formatter;
-use crate::worker::engine::TaskEngine;
-use anyhow::{Context, Result};
+use crate::{
+    messages::{is_valid_queue_name, ContainerOs, TaskPriority},
+    worker::engine::TaskEngine,
+};
+use anyhow::{format_err, Context, Result};
 use config::{builder::DefaultState, ConfigBuilder, Environment, File, FileFormat};
 use reqwest::Url;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Deserializer, de};
 
 struct DurationError(humantime::DurationError);
@@ -23,43 +27,236 @@ fn serde_human_time<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<u64,
     Ok(secs)
 }
 
+/// a named set of worker settings, selected with the `--profile` flag
+/// so one image can be deployed as several differently configured worker pools
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct WorkerProfile {
+    pub task_engine: Option<TaskEngine>,
+    pub max_tasks: Option<u32>,
+    pub worker_tags: Option<Vec<String>>,
+    pub kube_namespace: Option<String>,
+}
+
+/// what the scheduler does with a task result that no downstream edge is waiting for
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StrictResults {
+    Off,
+    /// record an `unmapped_result` event in the token's history
+    Record,
+    /// record the event and fail the rest of the job run
+    Fail,
+}
+
+/// which message broker the scheduler, API and workers talk through, see broker.rs
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BrokerKind {
+    /// RabbitMQ, at `amqp_addr`
+    Amqp,
+    /// Redis Streams, at `redis_url`
+    Redis,
+    /// NATS JetStream, at `nats_url`
+    Nats,
+    /// Kafka, at `kafka_brokers`
+    Kafka,
+}
+
+/// where task logs are kept once they've been captured, see log_store.rs
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStore {
+    /// only kept in Redis for `log_retention`, while they can be tailed
+    None,
+    /// sent to the server, which keeps them in the database
+    Server,
+    S3,
+    Elasticsearch,
+}
+
 /// config for Waterwheel
 /// note that the default values are loaded from default_config.toml,
 /// mandatory values are not Option *and* not present in that file
 #[derive(serde::Deserialize, Clone)]
 pub struct Config {
     pub db_url: String, // mandatory
+    pub broker: BrokerKind,
     pub amqp_addr: String,
     pub redis_url: String,
+    pub nats_url: String,
+    pub kafka_brokers: String,
+    pub kafka_partitions: i32,
     pub server_addr: String, // mandatory
     pub server_bind: String,
+    /// serve the `/int-api` endpoints on their own listener
+    pub internal_bind: Option<String>,
+    /// the URL workers and tasks use for the `/int-api` endpoints
+    pub internal_addr: Option<String>,
+    /// PEM CA certificates workers trust for the `/int-api` endpoints, eg. a private CA,
+    /// and which sign the certificates workers must present when the server serves TLS
+    pub internal_ca_cert: Option<String>,
+    /// PEM certificate and (PKCS #8) key the server serves `internal_bind` with
+    pub internal_tls_cert: Option<String>,
+    pub internal_tls_key: Option<String>,
+    /// PEM certificate and (PKCS #8) key workers present to the `/int-api` endpoints
+    pub internal_client_cert: Option<String>,
+    pub internal_client_key: Option<String>,
+    pub max_api_requests: Option<usize>,
+    pub max_internal_requests: Option<usize>,
     pub worker_bind: String,
     pub max_tasks: u32,
+    /// slots kept free for tasks of a priority or higher, as `<priority>=<slots>`
+    pub worker_reserved_slots: Vec<String>,
+    /// `worker_reserved_slots` parsed when the config is loaded
+    #[serde(skip)]
+    pub reserved_slots: BTreeMap<TaskPriority, u32>,
     pub task_engine: TaskEngine,
+    /// run each task in a child executor process
+    pub worker_supervisor: bool,
+    /// the most memory an executor process may map, in bytes
+    pub executor_memory_limit: Option<u64>,
     pub hmac_secret: Option<String>,
     pub public_key: Option<String>,
     pub private_key: Option<String>,
+    /// HMAC secrets accepted as well as the signing one, for rotating it
+    pub verify_hmac_secrets: Vec<String>,
+    /// RSA public keys accepted as well as the signing one, for rotating it
+    pub verify_public_keys: Vec<String>,
     pub opa_sidecar_addr: Option<Url>,
+    /// OpenID Connect provider that people log in with, see server/api/oidc.rs
+    pub oidc_issuer: Option<Url>,
+    pub oidc_client_id: Option<String>,
+    pub oidc_client_secret: Option<String>,
+    pub oidc_scopes: String,
+    /// the ID token claim used as the principal
+    pub oidc_principal_claim: String,
+    /// the ID token claim listing the groups someone is in
+    pub oidc_groups_claim: String,
+    /// roles given to groups, as `<group>=<role>` or `<group>=<project>:<role>`
+    pub oidc_group_roles: Vec<String>,
     pub no_authz: bool,
+    /// enforce the roles bound to principals, see server/api/roles.rs
+    pub rbac: bool,
+    /// take the principal roles are resolved for from the author header, when
+    /// something in front of the API sets it
+    pub trust_author_header: bool,
+    /// reject every request that could change something
+    pub read_only: bool,
     pub statsd_server: Option<String>,
     pub json_log: bool,
+    /// log each line of task output as a record of its own on the worker
+    pub log_task_output: bool,
     pub log: String,
     pub cluster_id: Option<String>,
     pub cluster_gossip_bind: String,
     pub cluster_gossip_addr: String,
     pub cluster_seed_nodes: Vec<String>,
+    pub worker_tags: Vec<String>,
+    /// the availability zone (or rack, region...) the worker runs in
+    pub worker_zone: Option<String>,
+    /// how many GPUs the worker has, any at all means it only takes GPU tasks
+    pub worker_gpus: u32,
+    /// the task queues the worker takes from, if not the default (or `gpu`) one
+    pub worker_queues: Vec<String>,
+    /// the OS of the containers the worker's runtime runs, Windows workers take
+    /// tasks from the `windows` queue
+    pub worker_os: ContainerOs,
+    /// images the docker engine pulls when the worker starts
+    pub worker_prepull_images: Vec<String>,
+    /// how many times an infrastructure error running a task is retried
+    pub worker_infra_retries: u32,
+    pub kube_namespace: Option<String>,
+    /// how many times Kubernetes retries a task's pod before its job fails
+    pub kube_backoff_limit: u32,
+    /// the ECS cluster the `ecs` engine runs tasks in
+    pub ecs_cluster: Option<String>,
+    pub ecs_subnets: Vec<String>,
+    pub ecs_security_groups: Vec<String>,
+    pub ecs_assign_public_ip: bool,
+    pub ecs_execution_role_arn: Option<String>,
+    pub ecs_task_role_arn: Option<String>,
+    /// the CloudWatch log group ECS tasks log to
+    pub ecs_log_group: String,
+    /// the `waterwheel-task` binary to mount into docker tasks
+    pub task_helper_path: Option<String>,
+    /// an image with `waterwheel-task` on its path, to copy into kubernetes tasks
+    pub task_helper_image: Option<String>,
+    /// where docker tasks' result files are kept, defaults to the temp dir
+    pub task_result_dir: Option<String>,
+    pub docker_registry_username: Option<String>,
+    pub docker_registry_password: Option<String>,
+    /// the Docker API socket of the container runtime, eg. `unix:///run/podman/podman.sock`
+    pub docker_host: Option<String>,
+    pub vault_addr: Option<String>,
+    pub vault_token: Option<String>,
+    /// the Vault paths a job can reference, `{project}` is replaced by the job's project
+    pub vault_path_prefix: String,
+    /// the AWS Secrets Manager names a job can reference, as for `vault_path_prefix`
+    pub aws_secret_prefix: String,
+    pub default_project_config: Option<String>,
+    pub default_project_max_jobs: Option<i32>,
+    pub default_project_max_concurrent_tasks: Option<i32>,
+    pub default_project_max_stash_bytes: Option<i64>,
+    pub default_project_min_trigger_period_secs: Option<i64>,
+    pub default_project_max_fires_per_hour: Option<i32>,
+    pub default_project_max_triggers: Option<i32>,
+    /// URL that job change events are posted to
+    pub job_events_url: Option<String>,
+    /// whether to record results that activate no downstream edges
+    pub strict_results: StrictResults,
+    pub log_store: LogStore,
+    /// the bucket task logs are written to with the `s3` log store
+    pub log_store_bucket: Option<String>,
+    pub log_store_prefix: String,
+    /// the index task logs are written to with the `elasticsearch` log store
+    pub log_store_url: Option<Url>,
+    /// the bucket each task run's whole output is uploaded to when it finishes
+    pub log_archive_bucket: Option<String>,
+    pub log_archive_prefix: String,
+    /// an S3 compatible endpoint for the archive, eg. Google Cloud Storage's
+    pub log_archive_endpoint: Option<String>,
+    /// the largest value that can be written to the stash
+    pub max_stash_item_bytes: u64,
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, WorkerProfile>,
+
+    /// the file this config was loaded from, used when reloading
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
 
     #[serde(deserialize_with="serde_human_time")]
     pub requeue_interval: u64,
 
     pub requeue_missed_heartbeats: u32,
 
+    #[serde(deserialize_with="serde_human_time")]
+    pub requeue_unstarted_after: u64,
+
+    pub max_queued_tasks: u64,
+
+    pub max_concurrent_tasks: u64,
+
     #[serde(deserialize_with="serde_human_time")]
     pub default_task_timeout: u64,
 
     #[serde(deserialize_with="serde_human_time")]
     pub default_task_retry_delay: u64,
 
+    #[serde(deserialize_with="serde_human_time")]
+    pub preempted_retry_delay: u64,
+
+    pub max_preempted_retries: u32,
+
+    #[serde(deserialize_with="serde_human_time")]
+    pub token_expiry_interval: u64,
+
+    #[serde(deserialize_with="serde_human_time")]
+    pub backfill_escalation_delay: u64,
+
+    #[serde(deserialize_with="serde_human_time")]
+    pub max_trigger_override: u64,
+
     #[serde(deserialize_with="serde_human_time")]
     pub task_heartbeat: u64,
 
@@ -68,6 +265,91 @@ pub struct Config {
 
     #[serde(deserialize_with="serde_human_time")]
     pub amqp_consumer_timeout: u64,
+
+    /// how long after its last heartbeat a worker is deleted
+    #[serde(deserialize_with="serde_human_time")]
+    pub worker_expiry: u64,
+
+    /// how long a worker told to shut down waits for its running tasks to finish
+    #[serde(deserialize_with="serde_human_time")]
+    pub worker_shutdown_grace_period: u64,
+
+    /// how often `kubernetes` workers look for jobs left behind by dead workers
+    #[serde(deserialize_with="serde_human_time")]
+    pub kube_reaper_interval: u64,
+
+    /// how long before the first retry of an infrastructure error, doubling each time
+    #[serde(deserialize_with="serde_human_time")]
+    pub worker_infra_retry_delay: u64,
+
+    /// how long workers cache project configs and task definitions
+    #[serde(deserialize_with="serde_human_time")]
+    pub worker_config_cache_ttl: u64,
+
+    /// how long an OIDC login lasts
+    #[serde(deserialize_with="serde_human_time")]
+    pub session_lifetime: u64,
+}
+
+impl Config {
+    /// the URL of the internal endpoints, which is the server's unless they're served separately
+    pub fn internal_addr(&self) -> &str {
+        self.internal_addr.as_deref().unwrap_or(&self.server_addr)
+    }
+
+    /// A client for the internal endpoints, which trusts `internal_ca_cert` and
+    /// presents `internal_client_cert` when they're set. The files are read each
+    /// time, so the worker builds one and shares it.
+    pub fn internal_client(&self) -> Result<reqwest::ClientBuilder> {
+        let read =
+            |path: &str| std::fs::read(path).with_context(|| format!("failed to read '{path}'"));
+
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(path) = &self.internal_ca_cert {
+            let cert =
+                reqwest::Certificate::from_pem(&read(path)?).context("invalid internal_ca_cert")?;
+            builder = builder.add_root_certificate(cert);
+        }
+
+        match (&self.internal_client_cert, &self.internal_client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = reqwest::Identity::from_pkcs8_pem(&read(cert)?, &read(key)?)
+                    .context("invalid internal_client_cert or internal_client_key")?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("internal_client_cert and internal_client_key must be set together"),
+        }
+
+        Ok(builder)
+    }
+
+    /// override settings with those from the named profile
+    pub fn apply_profile(&mut self, name: &str) -> Result<()> {
+        let profile = self
+            .profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format_err!("worker profile '{name}' is not defined"))?;
+
+        if let Some(task_engine) = profile.task_engine {
+            self.task_engine = task_engine;
+        }
+        if let Some(max_tasks) = profile.max_tasks {
+            self.max_tasks = max_tasks;
+        }
+        if let Some(worker_tags) = profile.worker_tags {
+            self.worker_tags = worker_tags;
+        }
+        if let Some(kube_namespace) = profile.kube_namespace {
+            self.kube_namespace = Some(kube_namespace);
+        }
+
+        self.profile = Some(name.to_owned());
+
+        Ok(())
+    }
 }
 
 pub fn loader(file: Option<&Path>) -> ConfigBuilder<DefaultState> {
@@ -86,15 +368,63 @@ pub fn loader(file: Option<&Path>) -> ConfigBuilder<DefaultState> {
         Environment::with_prefix("WATERWHEEL")
             .list_separator(",")
             .try_parsing(true)
-            .with_list_parse_key("cluster_seed_nodes"),
+            .with_list_parse_key("cluster_seed_nodes")
+            .with_list_parse_key("worker_tags")
+            .with_list_parse_key("worker_queues")
+            .with_list_parse_key("worker_reserved_slots")
+            .with_list_parse_key("worker_prepull_images")
+            .with_list_parse_key("ecs_subnets")
+            .with_list_parse_key("ecs_security_groups")
+            .with_list_parse_key("verify_hmac_secrets")
+            .with_list_parse_key("verify_public_keys")
+            .with_list_parse_key("oidc_group_roles"),
     )
 }
 
-pub fn load(file: Option<&Path>) -> Result<Config> {
-    let config = loader(file)
+/// parse reservations such as `high=2`, repeating a priority adds to its slots
+fn parse_reserved_slots(reservations: &[String]) -> Result<BTreeMap<TaskPriority, u32>> {
+    let mut reserved = BTreeMap::new();
+
+    for reservation in reservations {
+        let (priority, slots) = reservation.split_once('=').ok_or_else(|| {
+            format_err!("reserved slots '{reservation}' must be <priority>=<slots>")
+        })?;
+
+        let priority: TaskPriority =
+            serde_json::from_value(serde_json::Value::String(priority.trim().to_owned()))
+                .with_context(|| format!("invalid priority in reserved slots '{reservation}'"))?;
+        let slots: u32 = slots
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid number of reserved slots '{reservation}'"))?;
+
+        *reserved.entry(priority).or_default() += slots;
+    }
+
+    Ok(reserved)
+}
+
+pub fn load(file: Option<&Path>, profile: Option<&str>) -> Result<Config> {
+    let mut config: Config = loader(file)
         .build()?
         .try_deserialize()
         .context("mandatory configuration value not set")?;
 
+    config.config_file = file.map(Path::to_owned);
+    config.reserved_slots = parse_reserved_slots(&config.worker_reserved_slots)?;
+
+    if let Some(queue) = config
+        .worker_queues
+        .iter()
+        .find(|queue| !is_valid_queue_name(queue))
+    {
+        return Err(format_err!("invalid worker queue '{queue}'"));
+    }
+
+    // the flag takes precedence over WATERWHEEL_PROFILE
+    if let Some(name) = profile.map(str::to_owned).or_else(|| config.profile.clone()) {
+        config.apply_profile(&name)?;
+    }
+
     Ok(config)
 }
@@ -1,8 +1,12 @@
 use crate::config::Config;
 use sqlx::{Executor, PgPool};
-use tracing::{debug, info, trace};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info, trace, warn};
 
 const SCHEMA: &str = include_str!("schema.sql");
+const TRIGRAM_SCHEMA: &str = include_str!("trigram.sql");
+
+static HAS_TRIGRAM: AtomicBool = AtomicBool::new(false);
 
 pub async fn create_pool(config: &Config) -> anyhow::Result<PgPool> {
     info!("connecting to database...");
@@ -14,7 +18,19 @@ pub async fn create_pool(config: &Config) -> anyhow::Result<PgPool> {
     let done = conn.execute(SCHEMA).await?;
     trace!("schema created: {} rows modified", done.rows_affected());
 
+    match conn.execute(TRIGRAM_SCHEMA).await {
+        Ok(_) => HAS_TRIGRAM.store(true, Ordering::Relaxed),
+        Err(err) => {
+            warn!("couldn't create the pg_trgm extension, search won't rank by similarity: {err}")
+        }
+    }
+
     info!("connected to database");
 
     Ok(pool)
 }
+
+/// whether the `pg_trgm` extension and its indexes could be created
+pub fn has_trigram() -> bool {
+    HAS_TRIGRAM.load(Ordering::Relaxed)
+}
@@ -1,17 +1,22 @@
 #![feature(never_type)]
 #![feature(assert_matches)]
 
+pub mod admin;
 mod amqp;
+pub mod broker;
 pub mod circuit_breaker;
 pub mod config;
 pub mod counter;
 mod db;
+mod log_store;
 pub mod logging;
 pub mod messages;
 mod metrics;
 pub mod postoffice;
 pub mod rendezvous;
+mod secrets;
 pub mod server;
+pub mod task_contract;
 pub mod util;
 pub mod worker;
 
@@ -0,0 +1,94 @@
+//! Where task logs are kept once they've been captured. Workers write each task
+//! run's lines as it runs, and the server reads them back for
+//! `GET /api/tasks/:id/logs/:trigger_datetime`. Redis only keeps logs for a few
+//! hours, for tailing a running task.
+
+use crate::{
+    config::{Config, LogStore},
+    server::api::jwt::JwtKeys,
+};
+use anyhow::{format_err, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+mod archive;
+mod elasticsearch;
+mod postgres;
+mod s3;
+mod server;
+
+pub use self::{archive::LogArchive, postgres::PostgresLogStore};
+
+/// one line of a task's output, numbered from 0
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct LogLine {
+    pub seq: i64,
+    pub line: String,
+}
+
+impl LogLine {
+    /// Postgres can't store NUL characters, and nobody wants to read them
+    pub fn new(seq: i64, line: &[u8]) -> Self {
+        let line = String::from_utf8_lossy(line).replace('\0', "");
+        LogLine { seq, line }
+    }
+}
+
+/// some of a task run's lines, and how many lines it has altogether
+#[derive(Debug, Default)]
+pub struct LogPage {
+    pub lines: Vec<LogLine>,
+    pub total: i64,
+}
+
+#[async_trait::async_trait]
+pub trait LogWriter: Send + Sync {
+    /// Store the next lines of a task run's logs. Writing the same lines again
+    /// must not duplicate them, so a failed write can be retried.
+    async fn write(&self, task_run_id: Uuid, lines: &[LogLine]) -> Result<()>;
+}
+
+#[async_trait::async_trait]
+pub trait LogReader: Send + Sync {
+    async fn read(&self, task_run_id: Uuid, offset: i64, limit: i64) -> Result<LogPage>;
+}
+
+/// what workers write logs to, or `None` if they aren't kept
+pub async fn writer(
+    config: &Config,
+    jwt_keys: &JwtKeys,
+    internal_client: &reqwest::Client,
+) -> Result<Option<Arc<dyn LogWriter>>> {
+    Ok(match config.log_store {
+        LogStore::None => None,
+        LogStore::Server => Some(Arc::new(server::ServerLogWriter::new(
+            config,
+            jwt_keys,
+            internal_client,
+        )?)),
+        LogStore::S3 => Some(Arc::new(s3::S3LogStore::new(config).await?)),
+        LogStore::Elasticsearch => {
+            Some(Arc::new(elasticsearch::ElasticsearchLogStore::new(config)?))
+        }
+    })
+}
+
+/// what the server reads logs from, or `None` if they aren't kept
+pub async fn reader(config: &Config, pool: &PgPool) -> Result<Option<Arc<dyn LogReader>>> {
+    Ok(match config.log_store {
+        LogStore::None => None,
+        LogStore::Server => Some(Arc::new(PostgresLogStore::new(pool.clone()))),
+        LogStore::S3 => Some(Arc::new(s3::S3LogStore::new(config).await?)),
+        LogStore::Elasticsearch => {
+            Some(Arc::new(elasticsearch::ElasticsearchLogStore::new(config)?))
+        }
+    })
+}
+
+fn required<'a, T>(value: &'a Option<T>, name: &str) -> Result<&'a T> {
+    value
+        .as_ref()
+        .ok_or_else(|| format_err!("WATERWHEEL_{name} must be set for this log store"))
+}
@@ -0,0 +1,132 @@
+use crate::{
+    config::Config,
+    messages::{TaskDef, TaskRequest},
+};
+use anyhow::{format_err, Context, Result};
+use aws_sdk_s3::{presigning::config::PresigningConfig, types::ByteStream, Client, Endpoint};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::{path::Path, time::Duration};
+
+/// Uploads each task run's whole output as one object once it finishes, at a
+/// key that can be found without Waterwheel, eg.
+/// `waterwheel-archive/<project>/<job>/<task>/<trigger datetime>/<attempt>.log`.
+/// This is as well as the log store, which the API pages through.
+pub struct LogArchive {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl LogArchive {
+    /// the archive, if `log_archive_bucket` is set
+    pub async fn new(config: &Config) -> Result<Option<Self>> {
+        let bucket = match &config.log_archive_bucket {
+            Some(bucket) => bucket,
+            None => return Ok(None),
+        };
+
+        let aws_config = aws_config::load_from_env().await;
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&aws_config);
+        if let Some(endpoint) = &config.log_archive_endpoint {
+            let uri = endpoint
+                .parse()
+                .with_context(|| format!("invalid log archive endpoint '{endpoint}'"))?;
+            s3_config = s3_config.endpoint_resolver(Endpoint::immutable(uri));
+        }
+
+        Ok(Some(LogArchive {
+            client: Client::from_conf(s3_config.build()),
+            bucket: bucket.clone(),
+            prefix: config.log_archive_prefix.clone(),
+        }))
+    }
+
+    pub fn key(&self, task_def: &TaskDef, task_req: &TaskRequest) -> String {
+        archive_key(
+            &self.prefix,
+            [
+                &task_def.project_name,
+                &task_def.job_name,
+                &task_def.task_name,
+            ],
+            task_req.trigger_datetime,
+            task_req.attempt,
+        )
+    }
+
+    /// upload a task run's output, and return where it's kept
+    pub async fn upload(&self, key: &str, path: &Path) -> Result<String> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_type("text/plain; charset=utf-8")
+            .body(ByteStream::from_path(path).await?)
+            .send()
+            .await?;
+
+        Ok(format!("s3://{}/{key}", self.bucket))
+    }
+
+    /// a link to download an archived log from, which works for `expires_in`
+    pub async fn presign(&self, location: &str, expires_in: Duration) -> Result<String> {
+        let (bucket, key) = parse_location(location)
+            .ok_or_else(|| format_err!("invalid log archive location '{location}'"))?;
+
+        let presigned = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .presigned(PresigningConfig::expires_in(expires_in)?)
+            .await?;
+
+        Ok(presigned.uri().to_string())
+    }
+}
+
+/// names are used as path segments, so can't add any of their own
+fn archive_key(
+    prefix: &str,
+    names: [&str; 3],
+    trigger_datetime: DateTime<Utc>,
+    attempt: u32,
+) -> String {
+    let names = names.map(|name| name.replace('/', "_"));
+
+    format!(
+        "{prefix}{}/{}/{}/{}/{attempt}.log",
+        names[0],
+        names[1],
+        names[2],
+        trigger_datetime.to_rfc3339_opts(SecondsFormat::Secs, true),
+    )
+}
+
+fn parse_location(location: &str) -> Option<(&str, &str)> {
+    location.strip_prefix("s3://")?.split_once('/')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_archive_key() {
+        let trigger_datetime = Utc.ymd(2022, 3, 1).and_hms(12, 0, 0);
+        assert_eq!(
+            archive_key("logs/", ["proj", "etl/daily", "load"], trigger_datetime, 2),
+            "logs/proj/etl_daily/load/2022-03-01T12:00:00Z/2.log"
+        );
+    }
+
+    #[test]
+    fn test_parse_location() {
+        assert_eq!(
+            parse_location("s3://bucket/logs/a/b.log"),
+            Some(("bucket", "logs/a/b.log"))
+        );
+        assert_eq!(parse_location("gs://bucket/key"), None);
+    }
+}
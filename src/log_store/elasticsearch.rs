@@ -0,0 +1,117 @@
+use super::{required, LogLine, LogPage, LogReader, LogWriter};
+use crate::config::Config;
+use anyhow::{format_err, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::{fmt::Write, time::Duration};
+use uuid::Uuid;
+
+/// Indexes each line as a document, with an ID made from its task run and
+/// number so writing it again replaces it.
+pub struct ElasticsearchLogStore {
+    client: reqwest::Client,
+    /// the index's URL, without a trailing slash
+    index_url: String,
+}
+
+impl ElasticsearchLogStore {
+    pub fn new(config: &Config) -> Result<Self> {
+        let url = required(&config.log_store_url, "LOG_STORE_URL")?;
+
+        Ok(ElasticsearchLogStore {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()?,
+            index_url: url.as_str().trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct BulkReply {
+    errors: bool,
+}
+
+#[derive(Deserialize)]
+struct SearchReply {
+    hits: Hits,
+}
+
+#[derive(Deserialize)]
+struct Hits {
+    total: Total,
+    hits: Vec<Hit>,
+}
+
+#[derive(Deserialize)]
+struct Total {
+    value: i64,
+}
+
+#[derive(Deserialize)]
+struct Hit {
+    #[serde(rename = "_source")]
+    source: LogLine,
+}
+
+#[async_trait::async_trait]
+impl LogWriter for ElasticsearchLogStore {
+    async fn write(&self, task_run_id: Uuid, lines: &[LogLine]) -> Result<()> {
+        let mut body = String::new();
+        for line in lines {
+            let action = json!({ "index": { "_id": format!("{task_run_id}-{}", line.seq) } });
+            let doc = json!({
+                "task_run_id": task_run_id,
+                "seq": line.seq,
+                "line": line.line,
+            });
+            writeln!(body, "{action}\n{doc}")?;
+        }
+
+        let reply: BulkReply = self
+            .client
+            .post(format!("{}/_bulk", self.index_url))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if reply.errors {
+            return Err(format_err!("elasticsearch failed to index some log lines"));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl LogReader for ElasticsearchLogStore {
+    async fn read(&self, task_run_id: Uuid, offset: i64, limit: i64) -> Result<LogPage> {
+        // `match_phrase` matches the ID whether it's mapped as a keyword or text
+        let query = json!({
+            "query": { "match_phrase": { "task_run_id": task_run_id } },
+            "sort": [{ "seq": "asc" }],
+            "from": offset,
+            "size": limit,
+            "track_total_hits": true,
+        });
+
+        let reply: SearchReply = self
+            .client
+            .post(format!("{}/_search", self.index_url))
+            .json(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(LogPage {
+            lines: reply.hits.hits.into_iter().map(|hit| hit.source).collect(),
+            total: reply.hits.total.value,
+        })
+    }
+}
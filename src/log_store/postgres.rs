@@ -0,0 +1,68 @@
+use super::{LogLine, LogPage, LogReader, LogWriter};
+use anyhow::Result;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Keeps logs in the `task_log` table. Workers send them to the server, which
+/// writes them here.
+pub struct PostgresLogStore {
+    pool: PgPool,
+}
+
+impl PostgresLogStore {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresLogStore { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl LogWriter for PostgresLogStore {
+    async fn write(&self, task_run_id: Uuid, lines: &[LogLine]) -> Result<()> {
+        let seqs: Vec<i64> = lines.iter().map(|line| line.seq).collect();
+        let text: Vec<&str> = lines.iter().map(|line| line.line.as_str()).collect();
+
+        sqlx::query(
+            "INSERT INTO task_log(task_run_id, seq, line)
+            SELECT $1, seq, line
+            FROM UNNEST($2::BIGINT[], $3::VARCHAR[]) AS l(seq, line)
+            ON CONFLICT DO NOTHING",
+        )
+        .bind(task_run_id)
+        .bind(&seqs)
+        .bind(&text)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl LogReader for PostgresLogStore {
+    async fn read(&self, task_run_id: Uuid, offset: i64, limit: i64) -> Result<LogPage> {
+        let (total,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(1)
+            FROM task_log
+            WHERE task_run_id = $1",
+        )
+        .bind(task_run_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let lines: Vec<LogLine> = sqlx::query_as(
+            "SELECT seq, line
+            FROM task_log
+            WHERE task_run_id = $1
+            ORDER BY seq
+            LIMIT $2
+            OFFSET $3",
+        )
+        .bind(task_run_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(LogPage { lines, total })
+    }
+}
@@ -0,0 +1,202 @@
+use super::{required, LogLine, LogPage, LogReader, LogWriter};
+use crate::config::Config;
+use anyhow::Result;
+use aws_sdk_s3::{types::ByteStream, Client};
+use uuid::Uuid;
+
+/// Writes each batch of lines as an object named after the number of its first
+/// line, eg. `waterwheel-logs/<task run id>/000000000500.log`, so a page can be
+/// read without downloading every batch before it.
+pub struct S3LogStore {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+/// a batch of lines, and the number of its first line
+struct Chunk {
+    first_seq: i64,
+    key: String,
+}
+
+impl S3LogStore {
+    pub async fn new(config: &Config) -> Result<Self> {
+        let bucket = required(&config.log_store_bucket, "LOG_STORE_BUCKET")?;
+        let aws_config = aws_config::load_from_env().await;
+
+        Ok(S3LogStore {
+            client: Client::new(&aws_config),
+            bucket: bucket.clone(),
+            prefix: config.log_store_prefix.clone(),
+        })
+    }
+
+    fn run_prefix(&self, task_run_id: Uuid) -> String {
+        format!("{}{task_run_id}/", self.prefix)
+    }
+
+    /// every chunk of a task run's logs, in order
+    async fn list_chunks(&self, task_run_id: Uuid) -> Result<Vec<Chunk>> {
+        let prefix = self.run_prefix(task_run_id);
+        let mut chunks = Vec::new();
+        let mut continuation = None;
+
+        loop {
+            let reply = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&prefix)
+                .set_continuation_token(continuation)
+                .send()
+                .await?;
+
+            for object in reply.contents().unwrap_or_default() {
+                let key = match object.key() {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if let Some(first_seq) = chunk_first_seq(&prefix, key) {
+                    chunks.push(Chunk {
+                        first_seq,
+                        key: key.to_owned(),
+                    });
+                }
+            }
+
+            continuation = reply.next_continuation_token().map(str::to_owned);
+            if continuation.is_none() {
+                break;
+            }
+        }
+
+        chunks.sort_by_key(|chunk| chunk.first_seq);
+        Ok(chunks)
+    }
+
+    async fn read_chunk(&self, chunk: &Chunk) -> Result<Vec<LogLine>> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&chunk.key)
+            .send()
+            .await?;
+        let data = object.body.collect().await?.into_bytes();
+
+        Ok(split_chunk(chunk.first_seq, &data))
+    }
+}
+
+fn chunk_key(prefix: &str, first_seq: i64) -> String {
+    format!("{prefix}{first_seq:012}.log")
+}
+
+fn chunk_first_seq(prefix: &str, key: &str) -> Option<i64> {
+    key.strip_prefix(prefix)?.strip_suffix(".log")?.parse().ok()
+}
+
+/// every line in a chunk ends with a newline
+fn split_chunk(first_seq: i64, data: &[u8]) -> Vec<LogLine> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    data.split(|&b| b == b'\n')
+        .enumerate()
+        .map(|(i, line)| LogLine::new(first_seq + i as i64, line))
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl LogWriter for S3LogStore {
+    async fn write(&self, task_run_id: Uuid, lines: &[LogLine]) -> Result<()> {
+        let first_seq = match lines.first() {
+            Some(line) => line.seq,
+            None => return Ok(()),
+        };
+
+        let mut body = String::new();
+        for line in lines {
+            body.push_str(&line.line);
+            body.push('\n');
+        }
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(chunk_key(&self.run_prefix(task_run_id), first_seq))
+            .content_type("text/plain; charset=utf-8")
+            .body(ByteStream::from(body.into_bytes()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl LogReader for S3LogStore {
+    async fn read(&self, task_run_id: Uuid, offset: i64, limit: i64) -> Result<LogPage> {
+        let chunks = self.list_chunks(task_run_id).await?;
+
+        let last = match chunks.last() {
+            Some(last) => last,
+            None => return Ok(LogPage::default()),
+        };
+        let last_lines = self.read_chunk(last).await?;
+        let total = last.first_seq + last_lines.len() as i64;
+
+        let end = offset.saturating_add(limit);
+        let mut lines = Vec::new();
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next_seq = chunks.get(i + 1).map_or(total, |next| next.first_seq);
+            if next_seq <= offset || chunk.first_seq >= end {
+                continue;
+            }
+
+            let chunk_lines = if i == chunks.len() - 1 {
+                last_lines.clone()
+            } else {
+                self.read_chunk(chunk).await?
+            };
+            lines.extend(
+                chunk_lines
+                    .into_iter()
+                    .filter(|line| line.seq >= offset && line.seq < end),
+            );
+        }
+
+        Ok(LogPage { lines, total })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chunk_keys() {
+        let key = chunk_key("logs/run/", 500);
+        assert_eq!(key, "logs/run/000000000500.log");
+        assert_eq!(chunk_first_seq("logs/run/", &key), Some(500));
+        assert_eq!(chunk_first_seq("logs/run/", "logs/run/other"), None);
+    }
+
+    #[test]
+    fn test_split_chunk() {
+        let lines = split_chunk(10, b"one\n\ntwo\n");
+        assert_eq!(
+            lines,
+            vec![
+                LogLine::new(10, b"one"),
+                LogLine::new(11, b""),
+                LogLine::new(12, b"two")
+            ]
+        );
+        assert_eq!(split_chunk(0, b"\n"), vec![LogLine::new(0, b"")]);
+        assert!(split_chunk(0, b"").is_empty());
+    }
+}
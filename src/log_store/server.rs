@@ -0,0 +1,45 @@
+use super::{LogLine, LogWriter};
+use crate::{
+    config::Config,
+    server::api::{jwt, jwt::JwtKeys},
+};
+use anyhow::Result;
+use reqwest::Url;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Sends logs to the server's internal API, which keeps them in the database.
+pub struct ServerLogWriter {
+    client: reqwest::Client,
+    base: Url,
+    jwt_keys: JwtKeys,
+}
+
+impl ServerLogWriter {
+    pub fn new(config: &Config, jwt_keys: &JwtKeys, client: &reqwest::Client) -> Result<Self> {
+        Ok(ServerLogWriter {
+            client: client.clone(),
+            base: Url::parse(config.internal_addr())?.join("int-api/")?,
+            jwt_keys: jwt_keys.clone(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl LogWriter for ServerLogWriter {
+    async fn write(&self, task_run_id: Uuid, lines: &[LogLine]) -> Result<()> {
+        let url = self.base.join(&format!("task_runs/{task_run_id}/logs"))?;
+        let token = "Bearer ".to_owned() + &jwt::generate_logs_jwt(&self.jwt_keys, task_run_id)?;
+
+        self.client
+            .post(url)
+            .timeout(Duration::from_secs(30))
+            .header(reqwest::header::AUTHORIZATION, token)
+            .json(lines)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
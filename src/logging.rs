@@ -2,6 +2,7 @@ use crate::config::Config;
 use anyhow::Result;
 use chrono::SecondsFormat;
 use colored::Colorize;
+use once_cell::sync::OnceCell;
 use std::fmt::{Debug, Result as FmtResult};
 use tracing::{
     field::{Field, Visit},
@@ -10,12 +11,18 @@ use tracing::{
 use tracing_log::NormalizeEvent;
 use tracing_subscriber::{
     field::RecordFields,
-    fmt::{self, format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    fmt::{
+        self, format::Writer, writer::BoxMakeWriter, FmtContext, FormatEvent, FormatFields,
+        FormattedFields,
+    },
     prelude::*,
     registry::LookupSpan,
-    EnvFilter,
+    reload, EnvFilter, Registry,
 };
 
+/// allows the log filter to be changed at runtime (eg. when a worker reloads its config)
+static FILTER_HANDLE: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
 fn level_color(level: Level, msg: String) -> impl std::fmt::Display {
     match level {
         Level::ERROR => msg.bright_red(),
@@ -99,18 +106,35 @@ pub fn setup(config: &Config) -> Result<()> {
     setup_raw(config.json_log, &config.log)
 }
 
+/// log to stderr, for processes that use stdout for something else (eg. task executors)
+pub fn setup_stderr(config: &Config) -> Result<()> {
+    setup_with_writer(config.json_log, &config.log, BoxMakeWriter::new(std::io::stderr))
+}
+
 pub fn setup_raw(use_json: bool, filter: &str) -> Result<()> {
-    let filter_layer = EnvFilter::new(filter);
+    setup_with_writer(use_json, filter, BoxMakeWriter::new(std::io::stdout))
+}
+
+fn setup_with_writer(use_json: bool, filter: &str, writer: BoxMakeWriter) -> Result<()> {
+    let (filter_layer, handle) = reload::Layer::new(EnvFilter::new(filter));
+    let _ = FILTER_HANDLE.set(handle);
 
     if use_json {
         tracing_subscriber::registry()
             .with(filter_layer)
-            .with(fmt::layer().json().with_file(true).with_line_number(true))
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_file(true)
+                    .with_line_number(true)
+                    .with_writer(writer),
+            )
             .init();
     } else {
         let fmt_layer = fmt::layer()
             .event_format(SemiCompact)
-            .fmt_fields(SemiCompact);
+            .fmt_fields(SemiCompact)
+            .with_writer(writer);
 
         tracing_subscriber::registry()
             .with(filter_layer)
@@ -120,3 +144,11 @@ pub fn setup_raw(use_json: bool, filter: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// replace the log filter, if logging has been set up
+pub fn set_filter(filter: &str) -> Result<()> {
+    if let Some(handle) = FILTER_HANDLE.get() {
+        handle.reload(EnvFilter::new(filter))?;
+    }
+    Ok(())
+}
@@ -1,8 +1,9 @@
 use anyhow::Result;
+use std::path::Path;
 use waterwheel::{
-    config, logging,
+    admin, config, logging,
     server::{api, Server},
-    worker::Worker,
+    worker::{executor, Worker},
 };
 
 #[tokio::main]
@@ -21,6 +22,13 @@ async fn main() -> Result<()> {
                 .takes_value(true)
                 .help("Provide a specific config file"),
         )
+        .arg(
+            clap::Arg::new("profile")
+                .long("profile")
+                .short('p')
+                .takes_value(true)
+                .help("Use a named worker profile from the config file"),
+        )
         .subcommand(
             clap::Command::new("scheduler")
                 .alias("server")
@@ -32,14 +40,58 @@ async fn main() -> Result<()> {
                 .about("launch the API server process")
                 .after_help("The API server may be launched many times for load balancing and HA"),
         )
-        .subcommand(clap::Command::new("worker").about("launch the worker process"));
+        .subcommand(clap::Command::new("worker").about("launch the worker process"))
+        .subcommand(
+            clap::Command::new(executor::EXECUTOR_COMMAND)
+                .about("run a single task for a worker in supervisor mode")
+                .after_help("Reads the task from stdin and writes the result to stdout.")
+                .hide(true),
+        )
+        .subcommand(
+            clap::Command::new("admin")
+                .about("maintenance commands")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("drain-queue")
+                        .about("move all queued tasks off the broker into a file")
+                        .after_help(
+                            "Stop the schedulers first or tasks may be queued while draining.",
+                        )
+                        .arg(
+                            clap::Arg::new("to_file")
+                                .long("to-file")
+                                .takes_value(true)
+                                .required(true)
+                                .help("File to append the drained tasks to"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("replay")
+                        .about("put tasks drained with drain-queue back on the broker")
+                        .arg(
+                            clap::Arg::new("from_file")
+                                .long("from-file")
+                                .takes_value(true)
+                                .required(true)
+                                .help("File written by drain-queue"),
+                        ),
+                ),
+        );
 
     let args = app.get_matches();
 
     let config_path = args.value_of("config_path").map(AsRef::as_ref);
 
-    let config = config::load(config_path)?;
-    logging::setup(&config)?;
+    let profile = args.value_of("profile");
+
+    let config = config::load(config_path, profile)?;
+
+    // an executor's stdout is for its result
+    if args.subcommand_name() == Some(executor::EXECUTOR_COMMAND) {
+        logging::setup_stderr(&config)?;
+    } else {
+        logging::setup(&config)?;
+    }
 
     match args.subcommand().expect("subcommand is required") {
         ("scheduler", _args) => {
@@ -53,6 +105,24 @@ async fn main() -> Result<()> {
             let worker = Worker::new(config).await?;
             worker.run_worker().await?;
         }
+        (executor::EXECUTOR_COMMAND, _args) => {
+            executor::run_executor(config).await?;
+            return Ok(());
+        }
+        ("admin", args) => {
+            match args.subcommand().expect("subcommand is required") {
+                ("drain-queue", args) => {
+                    let path = args.value_of("to_file").expect("to-file is required");
+                    admin::drain_queue(&config, Path::new(path)).await?;
+                }
+                ("replay", args) => {
+                    let path = args.value_of("from_file").expect("from-file is required");
+                    admin::replay_queue(&config, Path::new(path)).await?;
+                }
+                _ => unreachable!("clap should have already checked the subcommands"),
+            }
+            return Ok(());
+        }
         _ => unreachable!("clap should have already checked the subcommands"),
     }
 
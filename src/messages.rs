@@ -1,6 +1,7 @@
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::time::Duration;
 use tracing::error;
@@ -31,13 +32,30 @@ pub enum TokenState {
     Error,
     /// task failed but is going to be retried
     Retry,
+    /// task was interrupted by the infrastructure (eg. node eviction) and will be retried
+    Preempted,
+    /// the token passed the task's `expires_after` deadline before it could be dispatched
+    Expired,
+    /// an upstream task failed so this task can never be activated
+    #[serde(rename = "upstream_failed")]
+    #[sqlx(rename = "upstream_failed")]
+    UpstreamFailed,
+    /// the worker couldn't run the task, even after retrying (eg. docker daemon unreachable)
+    #[serde(rename = "infra_failure")]
+    #[sqlx(rename = "infra_failure")]
+    InfraFailure,
 }
 
 impl TokenState {
     pub fn is_final(&self) -> bool {
         matches!(
             self,
-            TokenState::Success | TokenState::Failure | TokenState::Error | TokenState::Timeout
+            TokenState::Success
+                | TokenState::Failure
+                | TokenState::Error
+                | TokenState::Timeout
+                | TokenState::Preempted
+                | TokenState::InfraFailure
         )
     }
 
@@ -72,6 +90,10 @@ impl AsRef<str> for TokenState {
             TokenState::Error => "error",
             TokenState::Cancelled => "cancelled",
             TokenState::Retry => "retry",
+            TokenState::Preempted => "preempted",
+            TokenState::Expired => "expired",
+            TokenState::UpstreamFailed => "upstream_failed",
+            TokenState::InfraFailure => "infra_failure",
         }
     }
 }
@@ -92,6 +114,10 @@ impl FromStr for TokenState {
             "error" => Ok(TokenState::Error),
             "cancelled" => Ok(TokenState::Cancelled),
             "retry" => Ok(TokenState::Retry),
+            "preempted" => Ok(TokenState::Preempted),
+            "expired" => Ok(TokenState::Expired),
+            "upstream_failed" => Ok(TokenState::UpstreamFailed),
+            "infra_failure" => Ok(TokenState::InfraFailure),
             _ => Err(TokenStateParseError(format!(
                 "invalid token state: '{s}'"
             ))),
@@ -99,6 +125,22 @@ impl FromStr for TokenState {
     }
 }
 
+/// overall state of a job for one trigger time, summarised from its tasks' tokens
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+#[sqlx(type_name = "VARCHAR")]
+pub enum JobRunState {
+    /// some tasks are still queued, running, waiting to retry or waiting for more upstreams
+    Running,
+    /// every task that ran succeeded
+    Success,
+    /// every task that ran failed
+    Failed,
+    /// some tasks succeeded and some failed
+    Partial,
+}
+
 #[derive(PartialEq, Hash, Eq, Clone, Debug, Serialize, Deserialize)]
 pub struct Token {
     pub task_id: Uuid,
@@ -110,9 +152,30 @@ pub struct TaskRequest {
     pub task_run_id: Uuid,
     pub task_id: Uuid,
     pub trigger_datetime: DateTime<Utc>,
+    /// which attempt at the task this run is, from 1
+    #[serde(default)]
+    pub attempt: u32,
+    /// set when this task is the job's `on_failure` callback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<TaskFailure>,
+    /// extra environment variables added by the scheduler's dispatch hooks
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// the outputs of the upstream tasks' runs, by task name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub upstream_outputs: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// describes the failed task that caused an `on_failure` callback to run
+#[derive(Serialize, Deserialize, Debug, Clone, sqlx::FromRow)]
+pub struct TaskFailure {
+    pub task_id: Uuid,
+    pub task_name: String,
+    pub state: TokenState,
+    pub error_details: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct TaskDef {
     pub task_id: Uuid,
     pub task_name: String,
@@ -121,10 +184,327 @@ pub struct TaskDef {
     pub project_id: Uuid,
     pub project_name: String,
     pub image: Option<String>,
+    /// URL (or worker-local path) of a WASI module to run instead of an image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wasm_module: Option<String>,
+    /// a command to run as a process on the worker's host instead of an image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
     pub args: Vec<String>,
     pub env: Option<Vec<String>>,
+    /// environment variables whose values are read from a secret store when the task runs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secret_env: Vec<SecretEnv>,
     pub paused: bool,
     pub timeout: Option<Duration>,
+    /// limits on what the task's container may use
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<TaskResources>,
+    /// when to pull the image, if not the project's default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_pull_policy: Option<ImagePullPolicy>,
+    /// an AWS Lambda function to invoke instead of an image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lambda: Option<LambdaInvoke>,
+    /// the network, volumes, working dir and user of the task's container
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub docker_options: Option<DockerOptions>,
+    /// init containers and sidecars for the task's pod
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pod_containers: Option<PodContainers>,
+    /// files downloaded before the task starts and uploaded after it succeeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staging: Option<Staging>,
+}
+
+/// An AWS Lambda function a task invokes, waiting for its result
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LambdaInvoke {
+    /// the function's name or ARN
+    pub function: String,
+    /// a version or alias of the function, otherwise `$LATEST`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qualifier: Option<String>,
+    /// the event the function is invoked with, `{}` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+}
+
+/// when a worker pulls a task's image, the same as in Kubernetes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(rename_all = "kebab-case")]
+#[sqlx(type_name = "VARCHAR")]
+pub enum ImagePullPolicy {
+    /// pull before every run, eg. for mutable tags such as `latest`
+    Always,
+    IfNotPresent,
+    /// only use an image that's already on the worker
+    Never,
+}
+
+impl ImagePullPolicy {
+    /// the name of the policy in a pod spec
+    pub fn kube_name(self) -> &'static str {
+        match self {
+            ImagePullPolicy::Always => "Always",
+            ImagePullPolicy::IfNotPresent => "IfNotPresent",
+            ImagePullPolicy::Never => "Never",
+        }
+    }
+}
+
+/// Limits on a task's container. Kubernetes gets them as both the requests and
+/// the limits of the pod, ulimits are only applied by the docker engine.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TaskResources {
+    /// CPU cores, eg. `0.5`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<f64>,
+    /// memory in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<u64>,
+    /// GPUs, which also means it's only sent to workers that have them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpus: Option<u32>,
+    /// ulimits by name, eg. `nofile`, with the same soft and hard limit
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ulimits: BTreeMap<String, i64>,
+}
+
+/// How the docker engine runs a task's container, beyond its image and args
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DockerOptions {
+    /// eg. `host`, `none` or the name of a network
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_mode: Option<String>,
+    /// `<host path or volume>:<container path>[:<options>]`, as for `docker run -v`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    /// `<user>[:<group>]`, by name or id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// the OS the task's image is built for, when it needs a worker that isn't Linux
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<ContainerOs>,
+}
+
+/// the OS a container runs, which a worker's container runtime must match
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerOs {
+    #[default]
+    Linux,
+    Windows,
+}
+
+/// Containers a Kubernetes task's pod runs as well as the task, each in the pod
+/// spec's own form, eg. `{"name": "proxy", "image": "...", "args": [...]}`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PodContainers {
+    /// run one after another before the task starts, eg. to download its data
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub init_containers: Vec<serde_json::Value>,
+    /// started before the task and stopped once it exits, eg. a database proxy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sidecars: Vec<serde_json::Value>,
+}
+
+/// Files the worker moves in and out of a task's working directory, so tasks
+/// don't each have to fetch their inputs and push their outputs themselves
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Staging {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<StagedInput>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<StagedOutput>,
+}
+
+/// a file downloaded into the working directory before the task starts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StagedInput {
+    /// `s3://<bucket>/<key>`, `https://...` or `stash://<global|project|job>/<key>`
+    pub uri: String,
+    /// relative to the working directory, the last segment of the URI if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+impl StagedInput {
+    pub fn path(&self) -> &str {
+        match &self.path {
+            Some(path) => path,
+            None => self.uri.rsplit('/').next().unwrap_or_default(),
+        }
+    }
+}
+
+/// files in the working directory uploaded after the task succeeds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StagedOutput {
+    /// relative to the working directory, `*` and `?` match within a directory
+    /// and `**` matches any number of them, eg. `out/**/*.csv`
+    pub glob: String,
+    /// `s3://<bucket>/<prefix>`, each file is uploaded by its path in the working directory
+    pub uri: String,
+}
+
+/// staged files can't be put outside the task's working directory
+pub fn is_valid_staged_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.starts_with('/')
+        && path.split('/').all(|part| !part.is_empty() && part != "..")
+}
+
+/// an environment variable set from a secret, rather than a literal value
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "SecretEnvSpec")]
+pub struct SecretEnv {
+    pub name: String,
+    #[serde(rename = "secretRef")]
+    pub secret_ref: SecretRef,
+}
+
+/// a secret env as it's written, either with a `secretRef` or a `secret` URI
+#[derive(Deserialize)]
+struct SecretEnvSpec {
+    name: String,
+    #[serde(default, rename = "secretRef", alias = "secret_ref")]
+    secret_ref: Option<SecretRef>,
+    #[serde(default)]
+    secret: Option<String>,
+}
+
+impl TryFrom<SecretEnvSpec> for SecretEnv {
+    type Error = String;
+
+    fn try_from(spec: SecretEnvSpec) -> Result<Self, Self::Error> {
+        let secret_ref = match (spec.secret_ref, spec.secret) {
+            (Some(secret_ref), None) => secret_ref,
+            (None, Some(uri)) => uri.parse()?,
+            _ => {
+                return Err(format!(
+                    "env '{}' needs exactly one of secretRef or secret",
+                    spec.name
+                ))
+            }
+        };
+
+        Ok(SecretEnv {
+            name: spec.name,
+            secret_ref,
+        })
+    }
+}
+
+/// where to read a secret from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "scope", rename_all = "lowercase")]
+pub enum SecretRef {
+    Global { key: String },
+    Project { key: String },
+    /// job stash is written by upstream tasks, so it can only be checked at run time
+    Job { key: String },
+    Vault { path: String, field: String },
+    Aws {
+        secret_id: String,
+        /// read one field of a JSON secret instead of the whole string
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        field: Option<String>,
+    },
+    /// a key of a secret in the worker's Kubernetes namespace
+    Kubernetes { name: String, key: String },
+}
+
+/// Parses the `secret` shorthand, `<scope>:<location>#<field>`, eg.
+/// `vault:secret/data/app#api_key`, `aws:prod/app` or `kubernetes:app-secrets#token`
+impl FromStr for SecretRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scope, location) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid secret '{s}', expected <scope>:<location>"))?;
+        let (location, field) = match location.split_once('#') {
+            Some((location, field)) => (location.to_owned(), Some(field.to_owned())),
+            None => (location.to_owned(), None),
+        };
+        let required = |field: Option<String>, what: &str| {
+            field.ok_or_else(|| format!("invalid secret '{s}', expected #<{what}>"))
+        };
+
+        match scope {
+            "global" if field.is_none() => Ok(SecretRef::Global { key: location }),
+            "project" if field.is_none() => Ok(SecretRef::Project { key: location }),
+            "job" if field.is_none() => Ok(SecretRef::Job { key: location }),
+            "vault" => Ok(SecretRef::Vault {
+                path: location,
+                field: required(field, "field")?,
+            }),
+            "aws" => Ok(SecretRef::Aws {
+                secret_id: location,
+                field,
+            }),
+            "kubernetes" => Ok(SecretRef::Kubernetes {
+                name: location,
+                key: required(field, "key")?,
+            }),
+            "global" | "project" | "job" => {
+                Err(format!("invalid secret '{s}', stash keys have no field"))
+            }
+            _ => Err(format!(
+                "invalid secret '{s}', the scope must be one of \
+                global, project, job, vault, aws or kubernetes"
+            )),
+        }
+    }
+}
+
+/// A condition a sensor task waits for. The scheduler checks it itself on the
+/// task's poke interval, so waiting doesn't occupy a worker.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "check", rename_all = "lowercase")]
+pub enum SensorCheck {
+    /// passes when a GET of the URL returns `status`, or any 2xx status if unset
+    Http {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<u16>,
+    },
+    /// passes when the query returns a row whose first column is true,
+    /// `database` is a secret containing a Postgres connection URL
+    Sql { database: SecretRef, query: String },
+    /// passes when the stash item exists
+    Stash { scope: StashScope, key: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StashScope {
+    Global,
+    Project,
+    Job,
+}
+
+impl std::fmt::Display for SecretRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretRef::Global { key } => write!(f, "global stash '{key}'"),
+            SecretRef::Project { key } => write!(f, "project stash '{key}'"),
+            SecretRef::Job { key } => write!(f, "job stash '{key}'"),
+            SecretRef::Vault { path, field } => write!(f, "vault secret '{path}' field '{field}'"),
+            SecretRef::Aws { secret_id, field: None } => write!(f, "aws secret '{secret_id}'"),
+            SecretRef::Aws {
+                secret_id,
+                field: Some(field),
+            } => write!(f, "aws secret '{secret_id}' field '{field}'"),
+            SecretRef::Kubernetes { name, key } => {
+                write!(f, "kubernetes secret '{name}' key '{key}'")
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -135,7 +515,39 @@ pub struct TaskProgress {
     pub started_datetime: DateTime<Utc>,
     pub finished_datetime: Option<DateTime<Utc>>,
     pub result: TokenState,
-    pub worker_id: Uuid,
+    pub worker_id: Option<Uuid>, // None when an operator set the state manually
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_details: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub operator_override: bool,
+    /// outputs and artifacts the task wrote to its result file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<serde_json::Value>,
+    /// the task's exit code, when its engine reports one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+    /// why the task failed, when the worker can tell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_class: Option<ErrorClass>,
+}
+
+/// The kind of problem a failed task run had, so it's clear whether to look at
+/// the task itself or at the infrastructure it ran on
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+#[sqlx(type_name = "VARCHAR")]
+pub enum ErrorClass {
+    /// the task's image couldn't be pulled
+    ImagePull,
+    /// the task was killed for going over its memory limit
+    OomKilled,
+    /// the task exited with a non-zero exit code
+    NonZeroExit,
+    /// the task's inputs couldn't be downloaded, or its outputs uploaded
+    Staging,
+    /// the worker or task engine failed, rather than the task
+    Infra,
 }
 
 // impl TaskProgress {
@@ -169,6 +581,16 @@ impl TaskPriority {
             TaskPriority::High => "high",
         }
     }
+
+    /// the priority of a task message, which is published as `priority as u8`
+    pub fn from_amqp(priority: u8) -> Self {
+        match priority {
+            0 => TaskPriority::BackFill,
+            1 => TaskPriority::Low,
+            2 => TaskPriority::Normal,
+            _ => TaskPriority::High,
+        }
+    }
 }
 
 impl Default for TaskPriority {
@@ -177,6 +599,36 @@ impl Default for TaskPriority {
     }
 }
 
+/// the queue of tasks that don't name one
+pub const DEFAULT_QUEUE: &str = "default";
+
+/// The AMQP routing key of a task queue. Tasks in the default queue are
+/// published without one.
+pub fn queue_routing_key(queue: &str) -> &str {
+    if queue == DEFAULT_QUEUE {
+        ""
+    } else {
+        queue
+    }
+}
+
+/// the AMQP queue workers take a task queue's tasks from
+pub fn queue_amqp_name(queue: &str) -> String {
+    match queue_routing_key(queue) {
+        "" => "waterwheel.tasks".to_owned(),
+        routing_key => format!("waterwheel.tasks.{routing_key}"),
+    }
+}
+
+/// queue names are used in routing keys and AMQP queue names, so are kept simple
+pub fn is_valid_queue_name(queue: &str) -> bool {
+    !queue.is_empty()
+        && queue.len() <= 64
+        && queue
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct WorkerHeartbeat {
     pub uuid: Uuid,
@@ -185,6 +637,109 @@ pub struct WorkerHeartbeat {
     pub running_tasks: i32,
     pub total_tasks: i32,
     pub version: String,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub labels: WorkerLabels,
+    #[serde(default)]
+    pub status: WorkerStatus,
+}
+
+/// whether a worker is taking new tasks, reported in its heartbeat
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerStatus {
+    #[default]
+    Active,
+    /// finishing the tasks it has, but not taking any more
+    Draining,
+    /// still in the fleet, but not taking tasks while its host is looked at
+    Maintenance,
+}
+
+/// where a worker runs and what it can take, reported in its heartbeat
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WorkerLabels {
+    pub hostname: Option<String>,
+    pub zone: Option<String>,
+    /// how many tasks it runs at once
+    pub capacity: Option<u32>,
+    /// the queues it takes tasks from
+    #[serde(default)]
+    pub queues: Vec<String>,
+    /// how many GPUs it has for tasks
+    #[serde(default)]
+    pub gpus: u32,
+    /// the task engines it runs tasks with
+    #[serde(default)]
+    pub engines: Vec<String>,
+    /// the OS of the containers it runs
+    #[serde(default)]
+    pub os: ContainerOs,
+}
+
+/// Change pushed to anyone watching `/api/updates`, eg. the UI.
+/// These are best effort, a client that misses one will catch up on its next fetch.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveUpdate {
+    /// a task reported progress
+    TokenState {
+        job_id: Uuid,
+        task_id: Uuid,
+        trigger_datetime: DateTime<Utc>,
+        state: TokenState,
+    },
+    /// nothing is left to do in a job run
+    RunFinished {
+        job_id: Uuid,
+        trigger_datetime: DateTime<Utc>,
+        state: JobRunState,
+    },
+    WorkerHeartbeat {
+        worker_id: Uuid,
+        running_tasks: i32,
+        total_tasks: i32,
+        last_seen_datetime: DateTime<Utc>,
+    },
+    /// a job was applied for the first time
+    JobCreated {
+        job_id: Uuid,
+        project: String,
+        name: String,
+    },
+    /// a trigger fired on schedule, catchups aren't sent
+    TriggerFired {
+        job_id: Uuid,
+        trigger_id: Uuid,
+        name: String,
+        trigger_datetime: DateTime<Utc>,
+    },
+    /// a worker sent its first heartbeat, or its first since it was gone
+    WorkerJoined { worker_id: Uuid, addr: String },
+    /// a worker has shut down, or hasn't sent a heartbeat for long enough that it's
+    /// considered gone
+    WorkerDied {
+        worker_id: Uuid,
+        last_seen_datetime: DateTime<Utc>,
+    },
+}
+
+impl LiveUpdate {
+    /// the job this update is about, if it's about a job
+    pub fn job_id(&self) -> Option<Uuid> {
+        match self {
+            LiveUpdate::TokenState { job_id, .. }
+            | LiveUpdate::RunFinished { job_id, .. }
+            | LiveUpdate::JobCreated { job_id, .. }
+            | LiveUpdate::TriggerFired { job_id, .. } => Some(*job_id),
+            LiveUpdate::WorkerHeartbeat { .. }
+            | LiveUpdate::WorkerJoined { .. }
+            | LiveUpdate::WorkerDied { .. } => None,
+        }
+    }
 }
 
 /// Message sent from API to scheduler to notify of a trigger being updated.
@@ -211,4 +766,67 @@ pub enum ProcessToken {
 pub enum ConfigUpdate {
     Project(Uuid),
     TaskDef(Uuid),
+    /// drop every cached project config and task definition
+    Flush,
+}
+
+/// message sent from the API to the workers to control them at runtime
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkerControl {
+    /// the worker to control, or all workers if not set
+    pub worker_id: Option<Uuid>,
+    pub command: WorkerCommand,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WorkerCommand {
+    /// reload the hot-reloadable parts of the worker's config
+    Reload,
+    /// stop taking new tasks, finish the running ones, then retire and exit
+    Drain,
+    /// stop (or start again) taking new tasks, without leaving the fleet
+    Maintenance { enabled: bool },
+    /// stop a running task and report it cancelled
+    Kill { task_run_id: Uuid },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_secret_ref() {
+        assert_eq!(
+            "vault:kv/data/foo#api_key".parse(),
+            Ok(SecretRef::Vault {
+                path: "kv/data/foo".to_owned(),
+                field: "api_key".to_owned(),
+            })
+        );
+        assert_eq!(
+            "aws:prod/app".parse(),
+            Ok(SecretRef::Aws {
+                secret_id: "prod/app".to_owned(),
+                field: None,
+            })
+        );
+        assert_eq!(
+            "kubernetes:app-secrets#token".parse(),
+            Ok(SecretRef::Kubernetes {
+                name: "app-secrets".to_owned(),
+                key: "token".to_owned(),
+            })
+        );
+        assert_eq!(
+            "project:password".parse(),
+            Ok(SecretRef::Project {
+                key: "password".to_owned()
+            })
+        );
+
+        assert!("vault:kv/data/foo".parse::<SecretRef>().is_err());
+        assert!("project:password#field".parse::<SecretRef>().is_err());
+        assert!("gcp:secret".parse::<SecretRef>().is_err());
+        assert!("no-scope".parse::<SecretRef>().is_err());
+    }
 }
@@ -126,3 +126,328 @@ CREATE TABLE IF NOT EXISTS job_stash (
     data BYTEA,
     UNIQUE(job_id, trigger_datetime, name)
 );
+
+-- token increments waiting for the token processor, see server/outbox.rs
+CREATE TABLE IF NOT EXISTS token_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    task_id UUID NOT NULL REFERENCES task(id),
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    priority VARCHAR NOT NULL
+);
+
+-- daily task run totals per job for the reporting endpoints, see server/rollup.rs
+CREATE TABLE IF NOT EXISTS job_run_daily (
+    job_id UUID NOT NULL REFERENCES job(id),
+    day DATE NOT NULL,
+    total_runs BIGINT NOT NULL,
+    success_runs BIGINT NOT NULL,
+    failure_runs BIGINT NOT NULL,
+    total_duration_secs DOUBLE PRECISION,
+    max_duration_secs DOUBLE PRECISION,
+    median_queue_secs DOUBLE PRECISION,
+    median_duration_secs DOUBLE PRECISION,
+    PRIMARY KEY(job_id, day)
+);
+
+CREATE INDEX IF NOT EXISTS task_run_by_finish
+    ON task_run(finish_datetime);
+
+CREATE INDEX IF NOT EXISTS task_run_by_worker
+    ON task_run(worker_id);
+
+-- summary of a job's tasks for each trigger time, see server/job_run.rs
+CREATE TABLE IF NOT EXISTS job_run (
+    job_id UUID NOT NULL REFERENCES job(id),
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    state VARCHAR NOT NULL,
+    running_tasks INT NOT NULL,
+    success_tasks INT NOT NULL,
+    failed_tasks INT NOT NULL,
+    started_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    finish_datetime TIMESTAMP WITH TIME ZONE,
+    PRIMARY KEY(job_id, trigger_datetime)
+);
+
+-- per-project limits, see server/api/quota.rs
+CREATE TABLE IF NOT EXISTS project_quota (
+    project_id UUID PRIMARY KEY REFERENCES project(id),
+    max_jobs INT,
+    max_concurrent_tasks INT,
+    max_stash_bytes BIGINT
+);
+
+-- principals that own a project, passed to OPA with each authz request
+CREATE TABLE IF NOT EXISTS project_owner (
+    project_id UUID NOT NULL REFERENCES project(id),
+    owner VARCHAR NOT NULL,
+    PRIMARY KEY(project_id, owner)
+);
+
+-- time-boxed operator changes to a trigger, see server/trigger_override.rs
+CREATE TABLE IF NOT EXISTS trigger_override (
+    trigger_id UUID PRIMARY KEY REFERENCES trigger(id),
+    paused BOOLEAN NOT NULL,
+    shift BIGINT,
+    catchup VARCHAR,
+    reason VARCHAR NOT NULL,
+    owner VARCHAR,
+    created_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_datetime TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- sensor tasks being checked by the scheduler, see server/sensors.rs
+CREATE TABLE IF NOT EXISTS sensor_poke (
+    task_run_id UUID PRIMARY KEY,
+    task_id UUID NOT NULL REFERENCES task(id),
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    started_datetime TIMESTAMP WITH TIME ZONE,
+    next_poke_datetime TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- append-only log of changes to tokens, see server/token_history.rs
+CREATE TABLE IF NOT EXISTS token_history (
+    id BIGSERIAL PRIMARY KEY,
+    task_id UUID NOT NULL REFERENCES task(id),
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    event VARCHAR NOT NULL,
+    state VARCHAR,
+    count INT,
+    actor VARCHAR NOT NULL,
+    task_run_id UUID,
+    details VARCHAR,
+    created_datetime TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS token_history_by_token
+    ON token_history(task_id, trigger_datetime);
+
+-- for listing unmapped results without scanning the whole history, see api/status.rs
+CREATE INDEX IF NOT EXISTS token_history_unmapped_result
+    ON token_history(created_datetime)
+    WHERE event = 'unmapped_result';
+
+-- final task results already processed, so redelivered results are ignored, see server/progress.rs
+CREATE TABLE IF NOT EXISTS task_result_processed (
+    task_run_id UUID PRIMARY KEY,
+    task_id UUID NOT NULL,
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    attempt BIGINT,
+    result VARCHAR NOT NULL,
+    processed_datetime TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS task_result_processed_datetime
+    ON task_result_processed(processed_datetime);
+
+-- triggers that have fired into each job run, see server/job_run.rs
+CREATE TABLE IF NOT EXISTS job_run_trigger (
+    job_id UUID NOT NULL REFERENCES job(id),
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    trigger_id UUID NOT NULL REFERENCES trigger(id),
+    fired_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY(job_id, trigger_datetime, trigger_id)
+);
+
+-- how many of each job run's tokens are in each state, for the tokens overview,
+-- see server/job_run.rs
+CREATE TABLE IF NOT EXISTS job_run_state_count (
+    job_id UUID NOT NULL REFERENCES job(id),
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    state VARCHAR NOT NULL,
+    tokens INT NOT NULL,
+    PRIMARY KEY(job_id, trigger_datetime, state)
+);
+
+-- count the tokens from before the counts were kept, only while there are none
+INSERT INTO job_run_state_count(job_id, trigger_datetime, state, tokens)
+SELECT t.job_id, k.trigger_datetime, k.state, COUNT(1)
+FROM token k
+JOIN task t ON t.id = k.task_id
+WHERE NOT EXISTS (SELECT 1 FROM job_run_state_count)
+GROUP BY t.job_id, k.trigger_datetime, k.state;
+
+-- for counting recent fires against the trigger quotas, see server/triggers.rs
+CREATE INDEX IF NOT EXISTS job_run_trigger_by_fired
+    ON job_run_trigger(fired_datetime);
+CREATE INDEX IF NOT EXISTS job_run_trigger_by_trigger
+    ON job_run_trigger(trigger_id, fired_datetime);
+
+-- every definition a job has had, see server/api/job/versions.rs
+CREATE TABLE IF NOT EXISTS job_version (
+    job_id UUID NOT NULL REFERENCES job(id),
+    version INT NOT NULL,
+    definition_hash VARCHAR NOT NULL,
+    definition_zstd BYTEA NOT NULL,
+    definition_size INT NOT NULL,
+    author VARCHAR,
+    rollback_of INT,
+    created_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY(job_id, version)
+);
+
+-- who changed what through the API, see server/api/audit.rs
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    created_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    principal VARCHAR,
+    method VARCHAR NOT NULL,
+    path VARCHAR NOT NULL,
+    action VARCHAR NOT NULL,
+    kind VARCHAR NOT NULL,
+    object VARCHAR,
+    project_id UUID,
+    job_id UUID,
+    task_id UUID,
+    diff JSONB,
+    details JSONB
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_by_created
+    ON audit_log(created_datetime);
+CREATE INDEX IF NOT EXISTS audit_log_by_job
+    ON audit_log(job_id, created_datetime)
+    WHERE job_id IS NOT NULL;
+
+-- roles bound to principals in a project, or globally when project_id is NULL,
+-- see server/api/roles.rs
+CREATE TABLE IF NOT EXISTS role_binding (
+    project_id UUID REFERENCES project(id),
+    principal VARCHAR NOT NULL,
+    role VARCHAR NOT NULL,
+    created_datetime TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS role_binding_by_project
+    ON role_binding(COALESCE(project_id, '00000000-0000-0000-0000-000000000000'::UUID), principal);
+CREATE INDEX IF NOT EXISTS role_binding_by_principal
+    ON role_binding(principal);
+
+-- API keys scoped to a project or global, see server/api/service_accounts.rs
+CREATE TABLE IF NOT EXISTS service_account (
+    id UUID PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    description VARCHAR NOT NULL,
+    project_id UUID REFERENCES project(id),
+    scopes VARCHAR[] NOT NULL,
+    key_prefix VARCHAR NOT NULL,
+    key_hash VARCHAR NOT NULL,
+    created_by VARCHAR,
+    created_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_datetime TIMESTAMP WITH TIME ZONE,
+    last_used_datetime TIMESTAMP WITH TIME ZONE,
+    revoked_datetime TIMESTAMP WITH TIME ZONE
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS service_account_by_key
+    ON service_account(key_hash);
+CREATE INDEX IF NOT EXISTS service_account_by_project
+    ON service_account(project_id);
+
+-- task run logs when they're kept in the database, see log_store/postgres.rs
+CREATE TABLE IF NOT EXISTS task_log (
+    task_run_id UUID NOT NULL REFERENCES task_run(id),
+    seq BIGINT NOT NULL,
+    line VARCHAR NOT NULL,
+    PRIMARY KEY(task_run_id, seq)
+);
+
+-- every value written to the global and project stash, so an overwrite can be undone
+CREATE TABLE IF NOT EXISTS global_stash_version (
+    name VARCHAR NOT NULL,
+    version INT NOT NULL,
+    data BYTEA,
+    author VARCHAR,
+    created_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY(name, version)
+);
+
+CREATE TABLE IF NOT EXISTS project_stash_version (
+    project_id UUID NOT NULL REFERENCES project(id),
+    name VARCHAR NOT NULL,
+    version INT NOT NULL,
+    data BYTEA,
+    author VARCHAR,
+    created_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY(project_id, name, version)
+);
+
+-- job stash values too big to keep in one row, see server/api/stash/job.rs
+CREATE TABLE IF NOT EXISTS job_stash_chunk (
+    job_id UUID NOT NULL REFERENCES job(id),
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    name VARCHAR NOT NULL,
+    seq INT NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY(job_id, trigger_datetime, name, seq)
+);
+
+-- runtime settings changed through the API, see server/settings.rs
+CREATE TABLE IF NOT EXISTS setting (
+    name VARCHAR PRIMARY KEY,
+    value JSONB NOT NULL,
+    updated_datetime TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- columns added after the initial release
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS error_details VARCHAR;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS on_failure_task_id UUID REFERENCES task(id);
+ALTER TABLE worker ADD COLUMN IF NOT EXISTS profile VARCHAR;
+ALTER TABLE worker ADD COLUMN IF NOT EXISTS tags VARCHAR[];
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS operator_override BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS expires_after_secs BIGINT;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS wasm_module VARCHAR;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS secret_env JSONB NOT NULL DEFAULT '[]';
+ALTER TABLE job ADD COLUMN IF NOT EXISTS slo_start_secs BIGINT;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS slo_finish_secs BIGINT;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS slo_objective DOUBLE PRECISION;
+ALTER TABLE job_run ADD COLUMN IF NOT EXISTS first_started_datetime TIMESTAMP WITH TIME ZONE;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS sensor JSONB;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS poke_interval_secs BIGINT;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS source_hash VARCHAR;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS applied_definition_hash VARCHAR;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS definition_zstd BYTEA;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS definition_size INT;
+ALTER TABLE project_quota ADD COLUMN IF NOT EXISTS min_trigger_period_secs BIGINT;
+ALTER TABLE project_quota ADD COLUMN IF NOT EXISTS max_fires_per_hour INT;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS outputs JSONB;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS definition_version INT;
+ALTER TABLE job_run ADD COLUMN IF NOT EXISTS definition_version INT;
+ALTER TABLE worker ADD COLUMN IF NOT EXISTS gone_datetime TIMESTAMP WITH TIME ZONE;
+ALTER TABLE worker ADD COLUMN IF NOT EXISTS draining_datetime TIMESTAMP WITH TIME ZONE;
+ALTER TABLE worker ADD COLUMN IF NOT EXISTS retired_datetime TIMESTAMP WITH TIME ZONE;
+ALTER TABLE worker ADD COLUMN IF NOT EXISTS labels JSONB;
+ALTER TABLE project_quota ADD COLUMN IF NOT EXISTS max_triggers INT;
+ALTER TABLE global_stash ADD COLUMN IF NOT EXISTS version INT;
+ALTER TABLE global_stash ADD COLUMN IF NOT EXISTS author VARCHAR;
+ALTER TABLE global_stash ADD COLUMN IF NOT EXISTS updated_datetime TIMESTAMP WITH TIME ZONE;
+ALTER TABLE project_stash ADD COLUMN IF NOT EXISTS version INT;
+ALTER TABLE project_stash ADD COLUMN IF NOT EXISTS author VARCHAR;
+ALTER TABLE project_stash ADD COLUMN IF NOT EXISTS updated_datetime TIMESTAMP WITH TIME ZONE;
+ALTER TABLE job_stash ADD COLUMN IF NOT EXISTS bytes BIGINT;
+ALTER TABLE job_stash ADD COLUMN IF NOT EXISTS content_type VARCHAR;
+ALTER TABLE job_stash ADD COLUMN IF NOT EXISTS chunks INT;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS exit_code BIGINT;
+ALTER TABLE project ADD COLUMN IF NOT EXISTS paused BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS paused_by_project BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS command VARCHAR;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS resources JSONB;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS image_pull_policy VARCHAR;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS lambda JSONB;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS queue VARCHAR;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS log_location VARCHAR;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS killed_datetime TIMESTAMP WITH TIME ZONE;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS error_class VARCHAR;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS docker_options JSONB;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS pod_containers JSONB;
+ALTER TABLE worker ADD COLUMN IF NOT EXISTS maintenance BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS staging JSONB;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS escalate_datetime TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS task_run_to_escalate
+    ON task_run(escalate_datetime)
+    WHERE state = 'active' AND priority = 'backfill';
+
+CREATE INDEX IF NOT EXISTS token_waiting
+    ON token(task_id, trigger_datetime)
+    WHERE state = 'waiting';
@@ -0,0 +1,185 @@
+use crate::{config::Config, messages::SecretRef};
+use anyhow::{bail, format_err, Result};
+use k8s_openapi::api::core::v1::Secret;
+use kube::Api;
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+/// built on first use, loading the AWS config and credentials is slow
+static AWS_CLIENT: OnceCell<aws_sdk_secretsmanager::Client> = OnceCell::const_new();
+
+/// Check that a Vault or AWS reference is under its project's prefix, from
+/// `vault_path_prefix` or `aws_secret_prefix`, so a job can't read another
+/// project's secrets with the server's or worker's credentials. Other
+/// references are always in scope.
+pub fn check_scope(config: &Config, project: &str, secret_ref: &SecretRef) -> Result<()> {
+    match secret_ref {
+        SecretRef::Vault { path, .. } => check_vault_path(&config.vault_path_prefix, project, path),
+        SecretRef::Aws { secret_id, .. } => {
+            check_aws_secret(&config.aws_secret_prefix, project, secret_id)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_vault_path(prefix: &str, project: &str, path: &str) -> Result<()> {
+    let path = path.trim_start_matches('/');
+
+    // the path becomes part of a URL, where these could step outside the prefix
+    if path
+        .split('/')
+        .any(|segment| matches!(segment, "" | "." | ".."))
+        || path.contains(|c| matches!(c, '%' | '?' | '#' | '\\'))
+    {
+        bail!("vault path '{path}' is invalid");
+    }
+
+    check_prefix(prefix, project, path, "vault path")
+}
+
+fn check_aws_secret(prefix: &str, project: &str, secret_id: &str) -> Result<()> {
+    // a secret can be given by ARN, which ends with `secret:<name>`
+    let name = match secret_id.strip_prefix("arn:") {
+        Some(arn) => arn
+            .split_once(":secret:")
+            .map(|(_, name)| name)
+            .ok_or_else(|| format_err!("aws secret '{secret_id}' is not a secret ARN"))?,
+        None => secret_id,
+    };
+
+    check_prefix(prefix, project, name, "aws secret")
+}
+
+fn check_prefix(prefix: &str, project: &str, name: &str, what: &str) -> Result<()> {
+    let prefix = prefix.replace("{project}", project);
+    if !name.starts_with(&prefix) {
+        bail!("{what} '{name}' is not under '{prefix}'");
+    }
+    Ok(())
+}
+
+/// Read one field of a Vault secret. Both KV v1 and v2 engines are supported,
+/// for v2 the path must include the `data/` segment, eg. `secret/data/my-app`.
+pub async fn read_vault(
+    addr: Option<&str>,
+    token: Option<&str>,
+    path: &str,
+    field: &str,
+) -> Result<String> {
+    let addr = addr.ok_or_else(|| format_err!("vault_addr is not configured"))?;
+    let url = format!("{}/v1/{}", addr.trim_end_matches('/'), path.trim_start_matches('/'));
+
+    let mut req = reqwest::Client::new().get(&url);
+    if let Some(token) = token {
+        req = req.header("X-Vault-Token", token);
+    }
+
+    let body: Value = req.send().await?.error_for_status()?.json().await?;
+
+    let data = &body["data"];
+    let data = if data["data"].is_object() {
+        &data["data"]
+    } else {
+        data
+    };
+
+    json_field(data, field).ok_or_else(|| format_err!("vault secret '{path}' has no field '{field}'"))
+}
+
+/// Read an AWS Secrets Manager secret using the default credential chain.
+/// If `field` is given the secret must be a JSON object.
+pub async fn read_aws(secret_id: &str, field: Option<&str>) -> Result<String> {
+    let client = AWS_CLIENT
+        .get_or_init(|| async {
+            aws_sdk_secretsmanager::Client::new(&aws_config::load_from_env().await)
+        })
+        .await;
+
+    let resp = client.get_secret_value().secret_id(secret_id).send().await?;
+    let secret = resp
+        .secret_string()
+        .ok_or_else(|| format_err!("aws secret '{secret_id}' is not a string"))?;
+
+    match field {
+        None => Ok(secret.to_owned()),
+        Some(field) => {
+            let value: Value = serde_json::from_str(secret)?;
+            json_field(&value, field)
+                .ok_or_else(|| format_err!("aws secret '{secret_id}' has no field '{field}'"))
+        }
+    }
+}
+
+/// Read one key of a Kubernetes secret, in `namespace` or the kubeconfig's default
+pub async fn read_kube(namespace: Option<&str>, name: &str, key: &str) -> Result<String> {
+    let client = kube::Client::try_default().await?;
+    let secrets: Api<Secret> = match namespace {
+        Some(namespace) => Api::namespaced(client, namespace),
+        None => Api::default_namespaced(client),
+    };
+
+    let secret = secrets.get(name).await?;
+    let data = secret
+        .data
+        .and_then(|mut data| data.remove(key))
+        .ok_or_else(|| format_err!("kubernetes secret '{name}' has no key '{key}'"))?;
+
+    String::from_utf8(data.0)
+        .map_err(|_| format_err!("kubernetes secret '{name}' key '{key}' is not valid UTF-8"))
+}
+
+fn json_field(value: &Value, field: &str) -> Option<String> {
+    match value.get(field)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_aws_secret, check_vault_path, json_field};
+    use serde_json::json;
+
+    #[test]
+    fn test_json_field() {
+        let value = json!({"password": "hunter2", "port": 5432, "unset": null});
+
+        assert_eq!(json_field(&value, "password").as_deref(), Some("hunter2"));
+        assert_eq!(json_field(&value, "port").as_deref(), Some("5432"));
+        assert_eq!(json_field(&value, "unset"), None);
+        assert_eq!(json_field(&value, "missing"), None);
+    }
+
+    #[test]
+    fn test_check_vault_path() {
+        let prefix = "secret/data/waterwheel/{project}/";
+
+        assert!(check_vault_path(prefix, "etl", "secret/data/waterwheel/etl/db").is_ok());
+        assert!(check_vault_path(prefix, "etl", "/secret/data/waterwheel/etl/db").is_ok());
+        assert!(check_vault_path(prefix, "etl", "secret/data/waterwheel/ml/db").is_err());
+        assert!(check_vault_path(prefix, "etl", "secret/data/waterwheel/etl/../ml/db").is_err());
+        assert!(check_vault_path(prefix, "etl", "secret/data/waterwheel/etl/%2e%2e/db").is_err());
+        assert!(check_vault_path("", "etl", "anything/at/all").is_ok());
+    }
+
+    #[test]
+    fn test_check_aws_secret() {
+        let prefix = "waterwheel/{project}/";
+
+        assert!(check_aws_secret(prefix, "etl", "waterwheel/etl/s3").is_ok());
+        assert!(check_aws_secret(prefix, "etl", "waterwheel/ml/s3").is_err());
+        assert!(check_aws_secret(
+            prefix,
+            "etl",
+            "arn:aws:secretsmanager:us-east-1:123456789012:secret:waterwheel/etl/s3-AbCdEf"
+        )
+        .is_ok());
+        assert!(check_aws_secret(
+            prefix,
+            "etl",
+            "arn:aws:secretsmanager:us-east-1:123456789012:secret:prod/s3-AbCdEf"
+        )
+        .is_err());
+    }
+}
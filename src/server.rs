@@ -1,11 +1,15 @@
 use crate::{
-    amqp::amqp_connect, config::Config, db, metrics, postoffice::PostOffice, util::spawn_or_crash,
+    broker::{self, Broker},
+    config::Config,
+    db, metrics,
+    postoffice::PostOffice,
+    util::spawn_or_crash,
 };
 use anyhow::Result;
 use api::{jwt, jwt::JwtKeys};
+use hooks::Hooks;
 use cadence::StatsdClient;
 use chitchat::{Chitchat, ChitchatHandle};
-use lapin::Connection;
 use sqlx::PgPool;
 use std::sync::{atomic::AtomicUsize, Arc};
 use tokio::sync::Mutex;
@@ -17,21 +21,35 @@ use crate::server::triggers::trigger_cluster_changes;
 pub mod api;
 pub mod body_parser;
 mod cluster;
+mod escalate;
 mod execute;
+mod expiry;
+mod fair_queue;
 mod heartbeat;
+pub mod hooks;
+mod job_run;
+mod live_updates;
+mod outbox;
 mod progress;
 mod requeue;
 pub mod tokens;
+mod token_history;
+mod trigger_override;
 mod trigger_time;
 pub mod triggers;
 mod updates;
 mod retries;
+mod rollup;
+mod sensors;
+pub mod settings;
+mod slo;
+mod workers;
 
 pub struct Server {
     pub scheduler_id: Uuid,
     pub node_id: String,
     pub db_pool: PgPool,
-    pub amqp_conn: Connection,
+    pub broker: Arc<dyn Broker>,
     pub post_office: PostOffice,
     pub statsd: Arc<StatsdClient>,
     pub config: Config,
@@ -40,12 +58,18 @@ pub struct Server {
     pub on_cluster_membership_change: tokio::sync::watch::Sender<Rendezvous<String>>,
     pub queued_triggers: AtomicUsize,
     pub waiting_for_trigger_id: Mutex<Option<Uuid>>,
+    pub hooks: Hooks,
 }
 
 impl Server {
     pub async fn new(config: Config) -> Result<Arc<Self>> {
+        Self::with_hooks(config, Hooks::default()).await
+    }
+
+    /// create a scheduler with task hooks registered
+    pub async fn with_hooks(config: Config, hooks: Hooks) -> Result<Arc<Self>> {
         let db_pool = db::create_pool(&config).await?;
-        let amqp_conn = amqp_connect(&config).await?;
+        let broker = broker::connect(&config).await?;
         let statsd = metrics::new_client(&config)?;
         let jwt_keys = jwt::load_keys(&config)?;
         let node_id = cluster::get_node_id()?;
@@ -56,7 +80,7 @@ impl Server {
             scheduler_id: Uuid::new_v4(),
             node_id,
             db_pool,
-            amqp_conn,
+            broker,
             post_office: PostOffice::open(),
             statsd,
             config,
@@ -65,6 +89,7 @@ impl Server {
             on_cluster_membership_change: tx,
             queued_triggers: AtomicUsize::new(0),
             waiting_for_trigger_id: Mutex::default(),
+            hooks,
         }))
     }
 
@@ -96,6 +121,17 @@ impl Server {
             retry_cluster_changes
         );
         spawn_or_crash("process_retries", self.clone(), retries::process_retries);
+        spawn_or_crash("process_expiry", self.clone(), expiry::process_expiry);
+        spawn_or_crash("process_escalation", self.clone(), escalate::process_escalation);
+        spawn_or_crash("process_rollup", self.clone(), rollup::process_rollup);
+        spawn_or_crash("process_slo", self.clone(), slo::process_slo);
+        spawn_or_crash("process_sensors", self.clone(), sensors::process_sensors);
+        spawn_or_crash("process_gone_workers", self.clone(), workers::process_gone_workers);
+        spawn_or_crash(
+            "process_override_expiry",
+            self.clone(),
+            trigger_override::process_override_expiry,
+        );
 
         // this much be launched last - otherwise other tasks can miss the initial cluster
         // membership change event
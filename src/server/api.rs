@@ -1,36 +1,66 @@
-use crate::{amqp, config::Config, db, metrics, server::api::jwt::JwtKeys};
+use crate::{
+    broker::{self, Broker},
+    config::Config,
+    db,
+    log_store::{self, LogArchive, LogReader},
+    messages::LiveUpdate,
+    metrics,
+    server::api::jwt::JwtKeys,
+    util::spawn_retry,
+};
 use anyhow::Result;
 use cadence::StatsdClient;
-use lapin::Channel;
 use sqlx::PgPool;
 use std::sync::Arc;
+use tokio::sync::broadcast;
 use tracing::{debug, warn};
 
+mod audit;
 pub mod auth;
+mod conditional;
 mod config_cache;
+mod events;
+mod health;
 mod heartbeat;
 mod job;
 pub mod jwt;
+mod limit;
+mod live;
+mod oidc;
+mod paging;
 mod project;
+mod quota;
+mod read_only;
 mod request_ext;
+mod roles;
 mod schedulers;
+mod search;
+mod service_accounts;
+mod settings;
 mod stash;
 mod status;
 mod task;
 mod task_logs;
+mod tls;
 pub mod types;
 mod updates;
+mod worker_control;
 mod workers;
 
+#[derive(Clone)]
 pub struct State {
     db_pool: PgPool,
-    //amqp_conn: Connection,
-    amqp_channel: Channel,
+    broker: Arc<dyn Broker>,
     //pub post_office: PostOffice,
     statsd: Arc<StatsdClient>,
     redis_client: redis::Client,
+    live_tx: broadcast::Sender<LiveUpdate>,
     pub config: Config,
     pub jwt_keys: JwtKeys,
+    /// where stored task logs are read from, if they're kept
+    log_reader: Option<Arc<dyn LogReader>>,
+    /// where task runs' whole output is archived, if it is
+    log_archive: Option<Arc<LogArchive>>,
 }
 
 impl highnoon::State for State {
@@ -53,38 +83,125 @@ macro_rules! get_file {
     };
 }
 
-pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
-    let amqp_conn = amqp::amqp_connect(&config).await?;
+async fn make_state(config: Config) -> Result<State> {
+    let broker = broker::connect(&config).await?;
     let db_pool = db::create_pool(&config).await?;
     let statsd = metrics::new_client(&config)?;
     let jwt_keys = jwt::load_keys(&config)?;
+    let log_reader = log_store::reader(&config, &db_pool).await?;
+    let log_archive = LogArchive::new(&config).await?.map(Arc::new);
 
-    let amqp_channel = amqp_conn.create_channel().await?;
     let redis_client = redis::Client::open(config.redis_url.as_ref())?;
 
+    let (live_tx, _) = broadcast::channel(live::LIVE_BUFFER);
+    spawn_retry(
+        "live_updates",
+        (broker.clone(), live_tx.clone()),
+        live::consume,
+    );
+    spawn_retry(
+        "settings",
+        (db_pool.clone(), config.clone()),
+        crate::server::settings::refresh,
+    );
+
     let state = State {
         config,
         db_pool,
-        //amqp_conn,
-        amqp_channel,
+        broker,
         statsd,
         jwt_keys,
         redis_client,
+        live_tx,
+        log_reader,
+        log_archive,
     };
 
-    updates::setup(&state.amqp_channel).await?;
-    config_cache::setup(&state.amqp_channel).await?;
+    Ok(state)
+}
 
+fn new_app(state: State, max_requests: Option<usize>) -> highnoon::App<State> {
     let mut app = highnoon::App::new(state);
     app.with(highnoon::filter::Log);
 
-    // basic healthcheck to see if waterwheel is up
-    app.at("/healthcheck").get(|_req| async { Ok("OK") });
+    if let Some(max_requests) = max_requests {
+        app.with(limit::ConcurrencyLimit::new(max_requests));
+    }
 
-    app.at("/api/status").get(status::status);
+    if app.state().config.read_only {
+        app.with(read_only::ReadOnly);
+    }
+
+    // basic healthcheck to see if waterwheel is up, the same as /livez
+    app.at("/healthcheck").get(health::livez);
+    app.at("/livez").get(health::livez);
+    // whether waterwheel can reach what it depends on
+    app.at("/readyz").get(health::readyz);
+
+    app
+}
 
+/// an app serving both the public and internal endpoints
+pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
+    let max_requests = config.max_api_requests;
+    let mut app = new_app(make_state(config).await?, max_requests);
+
+    add_api_routes(&mut app);
+    add_internal_routes(&mut app);
+
+    Ok(app)
+}
+
+/// endpoints used by workers and tasks, which keep execution moving
+fn add_internal_routes(app: &mut highnoon::App<State>) {
     // worker heartbeats
     app.at("/int-api/heartbeat").post(heartbeat::post);
+    app.at("/int-api/workers/:id/retire").post(heartbeat::retire);
+    app.at("/int-api/workers/:id/gone").post(heartbeat::gone);
+    app.at("/int-api/task_runs/orphaned")
+        .post(heartbeat::orphaned_task_runs);
+
+    // workers send task logs here with the `server` log store
+    app.at("/int-api/task_runs/:id/logs").post(task_logs::store);
+    app.at("/int-api/task_runs/:id/logs/archive")
+        .put(task_logs::store_archive);
+
+    // tasks running past their stash token's expiry get a new one here
+    app.at("/int-api/tokens/refresh").post(jwt::refresh);
+
+    // task and project definitions
+    app.at("/int-api/tasks/:id")
+        .get(task::internal_get_task_def);
+    app.at("/int-api/projects/:id/config")
+        .get(project::get_config);
+    app.at("/int-api/config-cache/flush")
+        .post(workers::flush_config_cache);
+
+    // stash
+    app.at("/int-api/stash/:key").get(stash::global::get);
+    app.at("/int-api/projects/:id/stash/:key")
+        .get(stash::project::get);
+    app.at("/int-api/jobs/:id/stash/:trigger_datetime/")
+        .get(stash::job::list);
+    app.at("/int-api/jobs/:id/stash/:trigger_datetime/:key")
+        .put(stash::job::create)
+        .get(stash::job::get)
+        .delete(stash::job::delete);
+}
+
+/// endpoints used by people, the UI and the CLI
+fn add_api_routes(app: &mut highnoon::App<State>) {
+    app.at("/api/status").get(status::status);
+    app.at("/api/status/summary").get(status::summary);
+    app.at("/api/status/unmapped-results")
+        .get(status::unmapped_results);
+    app.at("/api/status/definitions")
+        .get(job::get_definition_stats);
+    app.at("/api/metrics/queue-depths")
+        .get(status::queue_depths);
+
+    app.at("/api/search").get(search::search);
+    app.at("/api/audit").get(audit::list);
 
     // project
     app.at("/api/projects")
@@ -95,26 +212,50 @@ pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
         .get(project::get_by_id)
         .delete(project::delete);
     app.at("/api/projects/:id/jobs").get(project::list_jobs);
-
-    app.at("/int-api/projects/:id/config")
-        .get(project::get_config);
+    app.at("/api/projects/:id/paused")
+        .get(project::get_paused)
+        .put(project::set_paused);
+    app.at("/api/projects/:id/quotas")
+        .get(quota::get_project_quotas);
+    app.at("/api/projects/:id/quota")
+        .get(quota::get_project_quotas);
+    app.at("/api/projects/:id/roles")
+        .get(roles::list_project)
+        .put(roles::set_project)
+        .delete(roles::delete_project);
+
+    // roles bound in every project
+    app.at("/api/roles")
+        .get(roles::list_global)
+        .put(roles::set_global)
+        .delete(roles::delete_global);
+
+    // API keys for CI and other automation
+    app.at("/api/service-accounts")
+        .get(service_accounts::list)
+        .post(service_accounts::create);
+    app.at("/api/service-accounts/:id")
+        .delete(service_accounts::revoke);
 
     // project stash
     app.at("/api/projects/:id/stash").get(stash::project::list);
     app.at("/api/projects/:id/stash/:key")
         .put(stash::project::create)
         .delete(stash::project::delete);
-
-    app.at("/int-api/projects/:id/stash/:key")
-        .get(stash::project::get);
+    app.at("/api/projects/:id/stash/:key/versions")
+        .get(stash::project::list_versions);
+    app.at("/api/projects/:id/stash/:key/versions/:version/restore")
+        .post(stash::project::restore);
 
     // job
     app.at("/api/jobs")
         .get(job::get_by_name)
         .post(job::create)
         .put(job::create);
+    app.at("/api/jobs/batch").post(job::create_batch);
     app.at("/api/jobs/:id")
         .get(job::get_by_id)
+        .patch(job::patch_job)
         .delete(job::delete);
     app.at("/api/jobs/:id/tasks").get(job::list_tasks);
     app.at("/api/jobs/:id/paused")
@@ -122,6 +263,17 @@ pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
         .put(job::set_paused);
     app.at("/api/jobs/:id/graph").get(job::get_graph);
     app.at("/api/jobs/:id/duration").get(job::get_duration);
+    app.at("/api/jobs/:id/stats").get(job::get_stats);
+    app.at("/api/jobs/:id/slo").get(job::get_slo);
+    app.at("/api/jobs/:id/schedule.ics")
+        .get(job::get_schedule_ics);
+
+    // job versions
+    app.at("/api/jobs/:id/versions").get(job::list_versions);
+    app.at("/api/jobs/:id/versions/:version")
+        .get(job::get_version);
+    app.at("/api/jobs/:id/rollback/:version")
+        .post(job::rollback);
 
     // job tokens
     app.at("/api/jobs/:id/tokens").get(job::get_tokens);
@@ -132,54 +284,96 @@ pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
         .delete(job::clear_tokens_trigger_datetime);
 
     // job runs
+    app.at("/api/jobs/:id/drift").get(job::get_drift);
+    app.at("/api/jobs/:id/runs").get(job::list_job_runs);
     app.at("/api/jobs/:id/runs/:trigger_datetime")
         .get(job::list_job_all_task_runs);
+    app.at("/api/jobs/:id/runs/:trigger_datetime/summary")
+        .get(job::get_job_run);
+    app.at("/api/jobs/:id/runs/:trigger_datetime/rerun")
+        .post(job::rerun_job_run);
 
     // job triggers
     app.at("/api/jobs/:id/triggers")
         .get(job::get_triggers_by_job);
 
-    // job stash
-    app.at("/int-api/jobs/:id/stash/:trigger_datetime/")
-        .get(stash::job::list);
-    app.at("/int-api/jobs/:id/stash/:trigger_datetime/:key")
-        .put(stash::job::create)
-        .get(stash::job::get)
-        .delete(stash::job::delete);
-
     // tasks
     app.at("/api/tasks/:id").get(task::get_task_def);
     app.at("/api/tasks/:id/tokens")
         .post(task::activate_multiple_tokens);
     app.at("/api/tasks/:id/tokens/:trigger_datetime")
         .put(task::activate_token);
-    app.at("/int-api/tasks/:id")
-        .get(task::internal_get_task_def);
+    app.at("/api/tasks/:id/tokens/:trigger_datetime/rerun")
+        .post(task::rerun_token);
 
     // task runs
     app.at("/api/tasks/:id/runs/:trigger_datetime")
         .get(job::list_task_runs);
-
-    // task logs - TODO unimplemented
+    app.at("/api/tasks/:id/runs/:trigger_datetime/history")
+        .get(job::get_token_history);
+    app.at("/api/tasks/:id/runs/:trigger_datetime/attempts")
+        .get(job::list_task_attempts);
+    app.at("/api/tasks/:id/runs/:trigger_datetime/state")
+        .put(task::set_task_run_state);
+    app.at("/api/tasks/:id/runs/:trigger_datetime/kill")
+        .post(task::kill_task_run);
+
+    // task logs, stored or tailed while the task runs
+    app.at("/api/tasks/:id/logs/:trigger_datetime").get(task_logs::list);
+    app.at("/api/tasks/:id/logs/:trigger_datetime/archive")
+        .get(task_logs::archive);
     app.at("/api/task_runs/:id/logs").ws(task_logs::logs);
 
+    // live updates for the UI
+    app.at("/api/updates").ws(live::updates);
+
+    // lifecycle events for other systems, as server-sent events
+    app.at("/api/events").get(events::events);
+
     // trigger times
     app.at("/api/triggers/:id").get(job::get_trigger);
+    app.at("/api/triggers/:id/override")
+        .put(job::set_trigger_override)
+        .delete(job::clear_trigger_override);
+    app.at("/api/triggers/:id/effective")
+        .get(job::get_effective_trigger);
+    app.at("/api/triggers/:id/simulate")
+        .post(job::simulate_trigger);
 
     // workers
     app.at("/api/workers").get(workers::list);
-    app.at("/api/workers/:id").get(workers::tasks);
+    app.at("/api/workers/reload").post(workers::reload_all);
+    app.at("/api/workers/:id")
+        .get(workers::tasks)
+        .delete(workers::delete);
+    app.at("/api/workers/:id/reload").post(workers::reload);
+    app.at("/api/workers/:id/drain").post(workers::drain);
+    app.at("/api/workers/:id/maintenance")
+        .put(workers::start_maintenance)
+        .delete(workers::stop_maintenance);
 
     // schedulers
     app.at("/api/schedulers").get(schedulers::list);
 
+    // runtime settings
+    app.at("/api/settings")
+        .get(settings::get)
+        .put(settings::update);
+
     // stash
     app.at("/api/stash").get(stash::global::list);
     app.at("/api/stash/:key")
         .put(stash::global::create)
         .delete(stash::global::delete);
+    app.at("/api/stash/:key/versions").get(stash::global::list_versions);
+    app.at("/api/stash/:key/versions/:version/restore")
+        .post(stash::global::restore);
 
-    app.at("/int-api/stash/:key").get(stash::global::get);
+    // logging in with OIDC
+    app.at("/auth/login").get(oidc::login);
+    app.at("/auth/callback").get(oidc::callback);
+    app.at("/auth/logout").get(oidc::logout);
+    app.at("/auth/session").get(oidc::get_session);
 
     // web UI
 
@@ -205,8 +399,6 @@ pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
         app.at("/**")
             .get(get_file!(HTML; "text/html;charset=utf-8"));
     }
-
-    Ok(app)
 }
 
 pub async fn serve(config: Config) -> Result<()> {
@@ -214,11 +406,59 @@ pub async fn serve(config: Config) -> Result<()> {
         warn!("authorization is disabled, this is not recommended in production");
     }
 
-    let app = make_app(config).await?;
+    let internal_bind = match config.internal_bind.clone() {
+        Some(internal_bind) => internal_bind,
+        None => {
+            if config.internal_tls_cert.is_some() {
+                anyhow::bail!("internal_tls_cert requires internal_bind to be set");
+            }
+
+            let app = make_app(config).await?;
 
-    let server_bind = &app.state().config.server_bind.clone();
-    debug!("server binding to {}", server_bind);
-    app.listen(&server_bind).await?;
+            let server_bind = &app.state().config.server_bind.clone();
+            debug!("server binding to {}", server_bind);
+            app.listen(&server_bind).await?;
+
+            return Ok(());
+        }
+    };
+
+    let internal_tls = tls::acceptor(&config)?;
+
+    // the internal endpoints get their own listener and database pool,
+    // so heavy use of the public API can't starve workers
+    let state = make_state(config.clone()).await?;
+    let internal_state = State {
+        db_pool: db::create_pool(&config).await?,
+        ..state.clone()
+    };
+
+    let mut app = new_app(state, config.max_api_requests);
+    add_api_routes(&mut app);
+
+    let mut internal_app = new_app(internal_state, config.max_internal_requests);
+    add_internal_routes(&mut internal_app);
+
+    debug!("server binding to {}", config.server_bind);
+    match internal_tls {
+        Some(acceptor) => {
+            // the internal app only sees connections that passed the TLS handshake
+            let upstream = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+            debug!("internal server binding to {} with TLS", internal_bind);
+            tokio::try_join!(
+                app.listen(&config.server_bind),
+                internal_app.listen(&upstream.to_string()),
+                tls::serve(&internal_bind, acceptor, upstream),
+            )?;
+        }
+        None => {
+            debug!("internal server binding to {}", internal_bind);
+            tokio::try_join!(
+                app.listen(&config.server_bind),
+                internal_app.listen(&internal_bind),
+            )?;
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,349 @@
+use crate::server::api::{
+    auth,
+    paging::{list_response, Paging},
+    request_ext::RequestExt,
+    service_accounts, State,
+};
+use chrono::{DateTime, Utc};
+use highnoon::{
+    headers::{authorization::Bearer, Authorization},
+    Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value as JsonValue};
+use sqlx::PgExecutor;
+use tracing::debug;
+use uuid::Uuid;
+
+/// A change made through the API, recorded in the `audit_log` table. Built up
+/// the same way as an `auth::Check`, eg.
+/// `audit::update("job").job(id, project_id).diff(&before, &after).record(&req, &mut txn)`.
+/// Record it in the same transaction as the change where there is one, so one
+/// can't be committed without the other.
+pub struct Entry {
+    action: String,
+    kind: String,
+    object: Option<String>,
+    project_id: Option<Uuid>,
+    job_id: Option<Uuid>,
+    task_id: Option<Uuid>,
+    diff: Option<JsonValue>,
+    details: Option<JsonValue>,
+}
+
+fn entry(action: impl Into<String>, kind: &str) -> Entry {
+    Entry {
+        action: action.into(),
+        kind: kind.to_owned(),
+        object: None,
+        project_id: None,
+        job_id: None,
+        task_id: None,
+        diff: None,
+        details: None,
+    }
+}
+
+pub fn create(kind: &str) -> Entry {
+    entry("create", kind)
+}
+
+pub fn update(kind: &str) -> Entry {
+    entry("update", kind)
+}
+
+pub fn delete(kind: &str) -> Entry {
+    entry("delete", kind)
+}
+
+/// anything that isn't a plain create, update or delete, eg. `pause` or `rerun`
+pub fn action(action: &str, kind: &str) -> Entry {
+    entry(action, kind)
+}
+
+impl Entry {
+    /// the name or ID of the thing that was changed, if it isn't the project or job
+    pub fn object(mut self, object: impl ToString) -> Self {
+        self.object = Some(object.to_string());
+        self
+    }
+
+    pub fn project(mut self, project_id: impl Into<Option<Uuid>>) -> Self {
+        self.project_id = project_id.into();
+        self
+    }
+
+    pub fn job(mut self, job_id: Uuid, project_id: impl Into<Option<Uuid>>) -> Self {
+        self.job_id = Some(job_id);
+        self.project_id = project_id.into();
+        self
+    }
+
+    /// the task that was changed, its job and project are filled in from it if unset
+    pub fn task(mut self, task_id: Uuid) -> Self {
+        self.task_id = Some(task_id);
+        self
+    }
+
+    /// what changed, `before` is null for something new
+    pub fn diff(mut self, before: &JsonValue, after: &JsonValue) -> Self {
+        self.diff = Some(json_diff(before, after));
+        self
+    }
+
+    /// the parameters of an action, eg. the priority a token was activated with
+    pub fn details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
+    }
+
+    pub async fn record(
+        self,
+        req: &Request<State>,
+        executor: impl PgExecutor<'_>,
+    ) -> highnoon::Result<()> {
+        let principal = principal(req);
+
+        debug!(action=%self.action, kind=%self.kind, object=?self.object,
+            job_id=?self.job_id, project_id=?self.project_id, ?principal,
+            "recording audit log entry");
+
+        sqlx::query(
+            "WITH owner AS (
+                SELECT COALESCE($8, (SELECT job_id FROM task WHERE id = $9)) AS job_id
+            )
+            INSERT INTO audit_log(
+                created_datetime, principal, method, path,
+                action, kind, object, project_id, job_id, task_id, diff, details
+            )
+            SELECT
+                CURRENT_TIMESTAMP, $1, $2, $3,
+                $4, $5, $6,
+                COALESCE($7, (SELECT project_id FROM job WHERE id = owner.job_id)),
+                owner.job_id, $9, $10, $11
+            FROM owner",
+        )
+        .bind(principal)
+        .bind(req.method().as_str())
+        .bind(req.uri().path())
+        .bind(&self.action)
+        .bind(&self.kind)
+        .bind(&self.object)
+        .bind(self.project_id)
+        .bind(self.job_id)
+        .bind(self.task_id)
+        .bind(&self.diff)
+        .bind(&self.details)
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Who made a request: the author header if a proxy has set it, the start of
+/// the API key for a service account, otherwise a fingerprint of the bearer
+/// token, so calls can be attributed without storing it.
+pub fn principal(req: &Request<State>) -> Option<String> {
+    auth::author(req).or_else(|| {
+        req.header::<Authorization<Bearer>>().map(|header| {
+            let token = header.0.token();
+            if service_accounts::is_api_key(token) {
+                format!("service-account:{}", service_accounts::key_display(token))
+            } else {
+                let hash = xxhash_rust::xxh3::xxh3_64(token.as_bytes());
+                format!("bearer:{hash:016x}")
+            }
+        })
+    })
+}
+
+/// Every value that differs between two JSON documents, keyed by its JSON
+/// pointer, eg. `{"/paused": {"from": false, "to": true}}`. Objects and arrays
+/// are compared element by element so a change deep in a job doesn't repeat the
+/// whole job.
+fn json_diff(before: &JsonValue, after: &JsonValue) -> JsonValue {
+    let mut changes = Map::new();
+    diff_into(&mut changes, String::new(), before, after);
+    JsonValue::Object(changes)
+}
+
+fn diff_into(
+    changes: &mut Map<String, JsonValue>,
+    path: String,
+    before: &JsonValue,
+    after: &JsonValue,
+) {
+    match (before, after) {
+        (JsonValue::Object(before), JsonValue::Object(after)) => {
+            for (key, value) in before {
+                let path = format!("{path}/{}", escape_pointer(key));
+                diff_into(
+                    changes,
+                    path,
+                    value,
+                    after.get(key).unwrap_or(&JsonValue::Null),
+                );
+            }
+            for (key, value) in after {
+                if !before.contains_key(key) {
+                    let path = format!("{path}/{}", escape_pointer(key));
+                    diff_into(changes, path, &JsonValue::Null, value);
+                }
+            }
+        }
+        (JsonValue::Array(before), JsonValue::Array(after)) => {
+            for i in 0..before.len().max(after.len()) {
+                diff_into(
+                    changes,
+                    format!("{path}/{i}"),
+                    before.get(i).unwrap_or(&JsonValue::Null),
+                    after.get(i).unwrap_or(&JsonValue::Null),
+                );
+            }
+        }
+        (before, after) if before != after => {
+            changes.insert(path, json!({ "from": before, "to": after }));
+        }
+        _ => {}
+    }
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    principal: Option<String>,
+    action: Option<String>,
+    kind: Option<String>,
+    project_id: Option<Uuid>,
+    job_id: Option<Uuid>,
+    task_id: Option<Uuid>,
+    after: Option<DateTime<Utc>>,
+    before: Option<DateTime<Utc>>,
+}
+
+const AUDIT_SORT: &[(&str, &str)] = &[("created", "created_datetime"), ("id", "id")];
+
+#[derive(Serialize, sqlx::FromRow)]
+struct AuditLogEntry {
+    id: i64,
+    created_datetime: DateTime<Utc>,
+    principal: Option<String>,
+    method: String,
+    path: String,
+    action: String,
+    kind: String,
+    object: Option<String>,
+    project_id: Option<Uuid>,
+    job_id: Option<Uuid>,
+    task_id: Option<Uuid>,
+    diff: Option<JsonValue>,
+    details: Option<JsonValue>,
+}
+
+/// the audit log, newest first, filtered by who, what and when
+pub async fn list(req: Request<State>) -> highnoon::Result<Response> {
+    let query: AuditQuery = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(AUDIT_SORT, "-created")?;
+
+    auth::list()
+        .project(query.project_id)
+        .kind("audit")
+        .check(&req)
+        .await?;
+
+    let pool = req.get_pool();
+
+    let filter = "FROM audit_log
+        WHERE ($1::VARCHAR IS NULL OR principal = $1)
+        AND ($2::VARCHAR IS NULL OR action = $2)
+        AND ($3::VARCHAR IS NULL OR kind = $3)
+        AND ($4::UUID IS NULL OR project_id = $4)
+        AND ($5::UUID IS NULL OR job_id = $5)
+        AND ($6::UUID IS NULL OR task_id = $6)
+        AND ($7::TIMESTAMPTZ IS NULL OR created_datetime >= $7)
+        AND ($8::TIMESTAMPTZ IS NULL OR created_datetime < $8)";
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(&query.principal)
+        .bind(&query.action)
+        .bind(&query.kind)
+        .bind(query.project_id)
+        .bind(query.job_id)
+        .bind(query.task_id)
+        .bind(query.after)
+        .bind(query.before)
+        .fetch_one(&pool)
+        .await?;
+
+    let entries: Vec<AuditLogEntry> = sqlx::query_as(&format!(
+        "SELECT
+            id,
+            created_datetime,
+            principal,
+            method,
+            path,
+            action,
+            kind,
+            object,
+            project_id,
+            job_id,
+            task_id,
+            diff,
+            details
+        {filter}
+        ORDER BY {order_by}, id DESC
+        LIMIT $9
+        OFFSET $10"
+    ))
+    .bind(&query.principal)
+    .bind(&query.action)
+    .bind(&query.kind)
+    .bind(query.project_id)
+    .bind(query.job_id)
+    .bind(query.task_id)
+    .bind(query.after)
+    .bind(query.before)
+    .bind(paging.limit(100))
+    .bind(paging.offset())
+    .fetch_all(&pool)
+    .await?;
+
+    list_response(entries, total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json_diff() {
+        let before = json!({"name": "a", "paused": false, "tasks": [{"name": "t1"}], "gone": 1});
+        let after = json!({"name": "a", "paused": true, "tasks": [{"name": "t2"}, {"name": "t3"}], "a/b": 2});
+
+        assert_eq!(
+            json_diff(&before, &after),
+            json!({
+                "/paused": {"from": false, "to": true},
+                "/tasks/0/name": {"from": "t1", "to": "t2"},
+                "/tasks/1": {"from": null, "to": {"name": "t3"}},
+                "/gone": {"from": 1, "to": null},
+                "/a~1b": {"from": null, "to": 2},
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_diff_new() {
+        let after = json!({"name": "a"});
+        assert_eq!(
+            json_diff(&JsonValue::Null, &after),
+            json!({"": {"from": null, "to": {"name": "a"}}})
+        );
+        assert_eq!(json_diff(&after, &after), json!({}));
+    }
+}
@@ -1,6 +1,12 @@
 use crate::{
     config::Config,
-    server::api::{job::get_job_project_id, request_ext::RequestExt, State},
+    server::api::{
+        job::get_job_project_id,
+        oidc::{self, Session},
+        request_ext::RequestExt,
+        roles::{self, Role},
+        service_accounts, State,
+    },
 };
 use anyhow::Result;
 use highnoon::{
@@ -17,11 +23,24 @@ struct Object {
     project_id: Option<Uuid>,
     job_id: Option<Uuid>,
     kind: String,
+    /// principals bound as owners of the project
+    owners: Vec<String>,
+    /// the principal's roles in the project (or globally), if role bindings are enabled
+    roles: Vec<Role>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct Principal {
     bearer: Option<String>, // bearer token if present
+    /// whoever has logged in, if the request has a session
+    session: Option<SessionPrincipal>,
+}
+
+#[derive(Serialize, Debug)]
+struct SessionPrincipal {
+    principal: String,
+    #[serde(flatten)]
+    session: Session,
 }
 
 #[derive(Serialize, Debug, Copy, Clone)]
@@ -56,12 +75,15 @@ struct OPAResponse {
     result: Option<bool>,
 }
 
-fn derive_principal<S: highnoon::State>(req: &highnoon::Request<S>) -> Result<Principal> {
+fn derive_principal(req: &highnoon::Request<State>) -> Result<Principal> {
     let bearer = req
         .header::<Authorization<Bearer>>()
         .map(|header| header.0.token().to_owned());
 
-    Ok(Principal { bearer })
+    let session =
+        oidc::session(req).map(|(principal, session)| SessionPrincipal { principal, session });
+
+    Ok(Principal { bearer, session })
 }
 
 fn derive_http<S: highnoon::State>(req: &highnoon::Request<S>) -> Result<Http> {
@@ -166,6 +188,47 @@ impl Check {
             }
         }
 
+        // API keys are checked against their service account's scopes, not passed to OPA
+        if let Some(key) = principal
+            .bearer
+            .as_deref()
+            .filter(|token| service_accounts::is_api_key(token))
+        {
+            let account = service_accounts::authenticate(&req.get_pool(), key).await?;
+
+            return if account.allows(self.action, &object.kind, object.project_id) {
+                debug!(?account, action=?self.action, ?object, "authorized service account");
+                Ok(())
+            } else {
+                warn!(?account, action=?self.action, ?object, "unauthorized service account");
+                Err(highnoon::Error::http(StatusCode::FORBIDDEN))
+            };
+        }
+
+        if let Some(project_id) = object.project_id {
+            object.owners = get_project_owners(&req.get_pool(), project_id).await?;
+        }
+
+        if config.rbac {
+            let roles = caller_roles(req, object.project_id).await?;
+
+            let allowed = roles
+                .iter()
+                .any(|&(role, global)| roles::role_allows(role, global, self.action, &object.kind));
+            if !allowed {
+                warn!(?principal, action=?self.action, ?object, ?roles, "unauthorized by role");
+                return Err(highnoon::Error::http(StatusCode::FORBIDDEN));
+            }
+
+            // OPA can refine the decision, but isn't needed when roles are enabled
+            if config.opa_sidecar_addr.is_none() {
+                debug!(?principal, action=?self.action, ?object, ?roles, "authorized by role");
+                return Ok(());
+            }
+
+            object.roles = roles.into_iter().map(|(role, _)| role).collect();
+        }
+
         let http = derive_http(req)?;
         // NOTE - this potentially logs credentials so don't leave it uncommented
         //debug!("http context", { http: Value::from_debug(&http) });
@@ -178,6 +241,51 @@ impl Check {
     }
 }
 
+async fn get_project_owners(pool: &sqlx::PgPool, project_id: Uuid) -> highnoon::Result<Vec<String>> {
+    let owners: Vec<(String,)> = sqlx::query_as(
+        "SELECT owner
+        FROM project_owner
+        WHERE project_id = $1
+        ORDER BY owner",
+    )
+    .bind(project_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(owners.into_iter().map(|(owner,)| owner).collect())
+}
+
+/// The caller's roles that apply to a project, from their role bindings and
+/// their login, with whether each is global.
+pub async fn caller_roles(
+    req: &highnoon::Request<State>,
+    project_id: Option<Uuid>,
+) -> highnoon::Result<Vec<(Role, bool)>> {
+    let mut roles = match authenticated_principal(req) {
+        Some(name) => roles::principal_roles(&req.get_pool(), &name, project_id).await?,
+        None => Vec::new(),
+    };
+    if let Some((_, session)) = oidc::session(req) {
+        roles.extend(session.roles_in(project_id));
+    }
+
+    Ok(roles)
+}
+
+/// the API key the request was made with, if any
+pub fn api_key(req: &highnoon::Request<State>) -> Option<String> {
+    req.header::<Authorization<Bearer>>()
+        .map(|header| header.0.token().to_owned())
+        .filter(|token| service_accounts::is_api_key(token))
+}
+
+pub fn action(action: Action) -> Check {
+    Check {
+        action,
+        object: Default::default(),
+    }
+}
+
 pub fn get() -> Check {
     Check {
         action: Action::Get,
@@ -205,3 +313,31 @@ pub fn delete() -> Check {
         object: Default::default(),
     }
 }
+
+/// header naming who made a request, for recording who changed what. Without an
+/// OIDC login Waterwheel doesn't know who its users are, so this should be set by
+/// a proxy in front of it.
+pub const AUTHOR_HEADER: &str = "x-waterwheel-author";
+
+/// Who made a request, when that can be relied on for authorization: whoever
+/// is logged in, otherwise the author header only if it's trusted. Anyone can
+/// send the header, so it's only trusted when a proxy in front of the API sets it.
+fn authenticated_principal(req: &highnoon::Request<State>) -> Option<String> {
+    match oidc::session(req) {
+        Some((principal, _)) => Some(principal),
+        None if req.state().config.trust_author_header => author(req),
+        None => None,
+    }
+}
+
+/// who made a request: whoever is logged in, otherwise the author header
+pub fn author(req: &highnoon::Request<State>) -> Option<String> {
+    if let Some((principal, _)) = oidc::session(req) {
+        return Some(principal);
+    }
+
+    req.headers()
+        .get(AUTHOR_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
@@ -0,0 +1,218 @@
+use crate::server::api::State;
+use highnoon::{
+    headers::{self, ETag, Header, HeaderName, HeaderValue, IfNoneMatch},
+    Request, Response, StatusCode,
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::io::Write;
+
+/// bodies smaller than this aren't worth compressing
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// brotli's levels go up to 11, but above 5 it gets much slower for little gain
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+static CONTENT_ENCODING: Lazy<HeaderName> =
+    Lazy::new(|| HeaderName::from_static("content-encoding"));
+static VARY: Lazy<HeaderName> = Lazy::new(|| HeaderName::from_static("vary"));
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                writer.write_all(body)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// the `Content-Encoding` of a compressed response
+struct ContentEncoding(Encoding);
+
+impl Header for ContentEncoding {
+    fn name() -> &'static HeaderName {
+        &CONTENT_ENCODING
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(
+        values: &mut I,
+    ) -> Result<Self, headers::Error> {
+        match values.next().and_then(|value| value.to_str().ok()) {
+            Some("br") => Ok(ContentEncoding(Encoding::Brotli)),
+            Some("gzip") => Ok(ContentEncoding(Encoding::Gzip)),
+            _ => Err(headers::Error::invalid()),
+        }
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(std::iter::once(HeaderValue::from_static(self.0.name())));
+    }
+}
+
+/// `Vary: Accept-Encoding`, so caches keep the compressed and plain bodies apart
+struct VaryAcceptEncoding;
+
+impl Header for VaryAcceptEncoding {
+    fn name() -> &'static HeaderName {
+        &VARY
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(
+        values: &mut I,
+    ) -> Result<Self, headers::Error> {
+        values
+            .next()
+            .map(|_| VaryAcceptEncoding)
+            .ok_or_else(headers::Error::invalid)
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(std::iter::once(HeaderValue::from_static("accept-encoding")));
+    }
+}
+
+/// Pick the best encoding the client accepts, preferring brotli when they're
+/// weighted the same. Encodings with `q=0` are refused.
+fn choose_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        let candidates: &[Encoding] = match name {
+            "br" => &[Encoding::Brotli],
+            "gzip" | "x-gzip" => &[Encoding::Gzip],
+            "*" => &[Encoding::Brotli, Encoding::Gzip],
+            _ => &[],
+        };
+
+        for &encoding in candidates {
+            let better = best.map_or(true, |(_, best_q)| {
+                q > best_q || (q == best_q && encoding == Encoding::Brotli)
+            });
+            if q > 0.0 && better {
+                best = Some((encoding, q));
+            }
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+/// The parts of a request that decide how a large JSON response is sent. Take
+/// these before the request is used up by a handler.
+pub struct Conditional {
+    encoding: Option<Encoding>,
+    if_none_match: Option<IfNoneMatch>,
+}
+
+impl Conditional {
+    pub fn from_request(req: &Request<State>) -> Self {
+        let encoding = req
+            .headers()
+            .get("accept-encoding")
+            .and_then(|value| value.to_str().ok())
+            .and_then(choose_encoding);
+
+        Conditional {
+            encoding,
+            if_none_match: req.header::<IfNoneMatch>(),
+        }
+    }
+
+    /// Respond with JSON and a (weak) ETag of it. If the client already has
+    /// this body it gets a 304 with no body, otherwise the body is compressed if
+    /// the client accepts it.
+    pub fn json(&self, value: impl Serialize) -> highnoon::Result<Response> {
+        let body = serde_json::to_vec(&value)?;
+
+        let hash = xxhash_rust::xxh3::xxh3_64(&body);
+        let etag: ETag = format!("W/\"{hash:016x}\"")
+            .parse()
+            .expect("a hex hash is a valid ETag");
+
+        if let Some(if_none_match) = &self.if_none_match {
+            if !if_none_match.precondition_passes(&etag) {
+                return Ok(Response::status(StatusCode::NOT_MODIFIED)
+                    .header(etag)
+                    .header(VaryAcceptEncoding));
+            }
+        }
+
+        let response = Response::ok()
+            .header(headers::ContentType::json())
+            .header(etag)
+            .header(VaryAcceptEncoding);
+
+        match self.encoding {
+            Some(encoding) if body.len() >= MIN_COMPRESS_SIZE => Ok(response
+                .header(ContentEncoding(encoding))
+                .body(encoding.compress(&body)?)),
+            _ => Ok(response.body(body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_choose_encoding() {
+        assert_eq!(choose_encoding("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(choose_encoding("gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            choose_encoding("br;q=0.5, gzip;q=0.8"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(choose_encoding("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(choose_encoding("*"), Some(Encoding::Brotli));
+        assert_eq!(choose_encoding("identity"), None);
+        assert_eq!(choose_encoding(""), None);
+    }
+
+    #[test]
+    fn test_compress() {
+        let body = br#"{"tokens": []}"#.repeat(100);
+
+        let gzipped = Encoding::Gzip.compress(&body).unwrap();
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(
+            &mut flate2::read::GzDecoder::new(&gzipped[..]),
+            &mut decoded,
+        )
+        .unwrap();
+        assert_eq!(decoded, body);
+
+        assert!(Encoding::Brotli.compress(&body).unwrap().len() < body.len());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    messages::{LiveUpdate, TokenState},
+    server::api::{auth, State},
+};
+use futures::stream;
+use highnoon::{
+    headers::{CacheControl, ContentType},
+    Request, Response,
+};
+use serde::Deserialize;
+use std::{convert::Infallible, time::Duration};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, trace};
+use uuid::Uuid;
+
+/// Proxies close connections that have been quiet for a while, so a comment is
+/// sent this often when there are no events.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// only send events about this job
+    job_id: Option<Uuid>,
+    /// only send these kinds of event, comma separated
+    events: Option<String>,
+}
+
+/// The name of the event a live update is sent as, if it is one. Progress that
+/// isn't a task starting or finishing, and heartbeats, aren't events.
+fn event_name(update: &LiveUpdate) -> Option<&'static str> {
+    Some(match update {
+        LiveUpdate::JobCreated { .. } => "job_created",
+        LiveUpdate::TriggerFired { .. } => "trigger_fired",
+        LiveUpdate::TokenState { state, .. } => match state {
+            TokenState::Running => "task_started",
+            TokenState::Success => "task_succeeded",
+            TokenState::Failure
+            | TokenState::Error
+            | TokenState::Timeout
+            | TokenState::InfraFailure => "task_failed",
+            _ => return None,
+        },
+        LiveUpdate::RunFinished { .. } => "run_finished",
+        LiveUpdate::WorkerJoined { .. } => "worker_joined",
+        LiveUpdate::WorkerDied { .. } => "worker_died",
+        LiveUpdate::WorkerHeartbeat { .. } => return None,
+    })
+}
+
+/// format an event for the stream, the data is always a single line of JSON
+fn format_event(name: &str, update: &LiveUpdate) -> serde_json::Result<String> {
+    let data = serde_json::to_string(update)?;
+    Ok(format!("event: {name}\ndata: {data}\n\n"))
+}
+
+/// Stream lifecycle events as server-sent events, so other systems can react to
+/// them without polling. Events are best effort: any sent while a client is
+/// disconnected, or too slow to keep up, are missed.
+pub async fn events(req: Request<State>) -> highnoon::Result<Response> {
+    let q: EventsQuery = req.query()?;
+
+    match q.job_id {
+        Some(job_id) => auth::get().job(job_id, None).check(&req).await?,
+        None => auth::list().kind("events").check(&req).await?,
+    }
+
+    let wanted: Option<Vec<String>> = q.events.map(|events| {
+        events
+            .split(',')
+            .map(|event| event.trim().to_owned())
+            .filter(|event| !event.is_empty())
+            .collect()
+    });
+
+    let live_rx = req.state().live_tx.subscribe();
+    let job_id = q.job_id;
+
+    debug!(?job_id, ?wanted, "client streaming events");
+
+    let events = stream::unfold(live_rx, move |mut live_rx| {
+        let wanted = wanted.clone();
+        async move {
+            loop {
+                let update = match tokio::time::timeout(KEEP_ALIVE, live_rx.recv()).await {
+                    Err(_) => return Some((Ok(": keep-alive\n\n".to_owned()), live_rx)),
+                    Ok(Ok(update)) => update,
+                    Ok(Err(RecvError::Lagged(missed))) => {
+                        trace!(missed, "client fell behind on events");
+                        continue;
+                    }
+                    Ok(Err(RecvError::Closed)) => return None,
+                };
+
+                if job_id.is_some() && update.job_id() != job_id {
+                    continue;
+                }
+
+                let name = match event_name(&update) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                if let Some(wanted) = &wanted {
+                    if !wanted.iter().any(|event| event == name) {
+                        continue;
+                    }
+                }
+
+                match format_event(name, &update) {
+                    Ok(event) => return Some((Ok::<_, Infallible>(event), live_rx)),
+                    Err(err) => trace!("error formatting event: {}", err),
+                }
+            }
+        }
+    });
+
+    Ok(Response::ok()
+        .header(ContentType::from(
+            "text/event-stream".parse::<mime::Mime>().unwrap(),
+        ))
+        .header(CacheControl::new().with_no_cache())
+        .body(hyper::Body::wrap_stream(events)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_event_name() {
+        let token = |state| LiveUpdate::TokenState {
+            job_id: Uuid::nil(),
+            task_id: Uuid::nil(),
+            trigger_datetime: Utc.ymd(2022, 1, 1).and_hms(0, 0, 0),
+            state,
+        };
+
+        assert_eq!(
+            event_name(&token(TokenState::Running)),
+            Some("task_started")
+        );
+        assert_eq!(event_name(&token(TokenState::Timeout)), Some("task_failed"));
+        assert_eq!(event_name(&token(TokenState::Waiting)), None);
+        assert_eq!(
+            event_name(&LiveUpdate::WorkerJoined {
+                worker_id: Uuid::nil(),
+                addr: "worker-1".to_owned(),
+            }),
+            Some("worker_joined")
+        );
+    }
+
+    #[test]
+    fn test_format_event() {
+        let update = LiveUpdate::WorkerJoined {
+            worker_id: Uuid::nil(),
+            addr: "worker-1".to_owned(),
+        };
+
+        assert_eq!(
+            format_event("worker_joined", &update).unwrap(),
+            "event: worker_joined\n\
+            data: {\"type\":\"worker_joined\",\"worker_id\":\"00000000-0000-0000-0000-000000000000\",\
+            \"addr\":\"worker-1\"}\n\n"
+        );
+    }
+}
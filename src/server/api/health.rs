@@ -0,0 +1,94 @@
+use crate::server::api::{request_ext::RequestExt, State};
+use highnoon::{Request, Response, StatusCode};
+use serde::Serialize;
+use std::{collections::BTreeMap, future::Future, time::Duration};
+
+/// how long each dependency has to answer before it counts as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    async fn run<F: Future<Output = anyhow::Result<()>>>(check: F) -> Check {
+        let error = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err.to_string()),
+            Err(_) => Some(format!("no answer within {}s", CHECK_TIMEOUT.as_secs())),
+        };
+
+        Check {
+            ok: error.is_none(),
+            error,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+/// The process is up and serving requests. This doesn't touch any dependencies,
+/// so an orchestrator won't restart it just because Postgres is down.
+pub async fn livez(_req: Request<State>) -> highnoon::Result<&'static str> {
+    Ok("OK")
+}
+
+/// Whether this process can do useful work: Postgres answers a query, the
+/// message broker can be reached and at least one scheduler has sent a heartbeat
+/// recently. Responds 503 if any check fails, with the status of each.
+pub async fn readyz(req: Request<State>) -> highnoon::Result<Response> {
+    let pool = req.get_pool();
+
+    let database = Check::run(async {
+        sqlx::query("SELECT 1").execute(&pool).await?;
+        Ok(())
+    })
+    .await;
+
+    let broker = Check::run(req.get_broker().check()).await;
+
+    // schedulers heartbeat every 20 seconds, and the schedulers API calls
+    // them gone after a minute
+    let scheduler = Check::run(async {
+        let (up,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS (
+                SELECT 1
+                FROM scheduler
+                WHERE CURRENT_TIMESTAMP - last_seen_datetime < INTERVAL '1 minute'
+            )",
+        )
+        .fetch_one(&pool)
+        .await?;
+
+        if up {
+            Ok(())
+        } else {
+            Err(anyhow::format_err!(
+                "no scheduler has sent a heartbeat in the last minute"
+            ))
+        }
+    })
+    .await;
+
+    let checks = BTreeMap::from([
+        ("database", database),
+        ("broker", broker),
+        ("scheduler", scheduler),
+    ]);
+    let ready = checks.values().all(|check| check.ok);
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Response::status(status).json(Readiness { ready, checks })
+}
@@ -1,34 +1,64 @@
 use crate::{
-    messages::WorkerHeartbeat,
-    server::api::{request_ext::RequestExt, State},
+    messages::{LiveUpdate, WorkerHeartbeat, WorkerStatus},
+    server::{
+        api::{jwt, request_ext::RequestExt, State},
+        live_updates,
+    },
 };
-use highnoon::{Request, Responder, StatusCode};
+use chrono::{DateTime, Utc};
+use highnoon::{Json, Request, Responder, StatusCode};
 use tracing::trace;
+use uuid::Uuid;
 
 pub async fn post(mut req: Request<State>) -> highnoon::Result<impl Responder> {
     let beat: WorkerHeartbeat = req.body_json().await?;
 
-    // TODO - should heartbeats be JWT protected?
+    jwt::validate_worker_jwt(&req, Some(beat.uuid))?;
 
     trace!(uuid=?beat.uuid, "received heartbeat");
 
-    sqlx::query(
-        "INSERT INTO worker(
+    // a worker that's new, or was marked gone, has joined.
+    // One that's draining itself (eg. it was sent SIGTERM) is marked the same
+    // as one told to drain through the API
+    let (joined,): (bool,) = sqlx::query_as(
+        "WITH previous AS (
+            SELECT gone_datetime
+            FROM worker
+            WHERE id = $1
+        )
+        INSERT INTO worker(
             id,
             addr,
             last_seen_datetime,
             running_tasks,
             total_tasks,
-            version
+            version,
+            profile,
+            tags,
+            labels,
+            draining_datetime,
+            maintenance
         )
-        VALUES($1, $2, $3, $4, $5, $6)
+        VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, CASE WHEN $10 THEN $3 END, $11)
         ON CONFLICT(id)
         DO UPDATE
         SET addr = $2,
             last_seen_datetime = $3,
             running_tasks = $4,
             total_tasks = $5,
-            version = $6",
+            version = $6,
+            profile = $7,
+            tags = $8,
+            labels = $9,
+            draining_datetime = CASE
+                WHEN $10 THEN COALESCE(worker.draining_datetime, $3)
+                ELSE worker.draining_datetime
+            END,
+            maintenance = $11,
+            gone_datetime = NULL
+        RETURNING NOT EXISTS (
+            SELECT 1 FROM previous WHERE gone_datetime IS NULL
+        )",
     )
     .bind(beat.uuid)
     .bind(&beat.addr)
@@ -36,6 +66,122 @@ pub async fn post(mut req: Request<State>) -> highnoon::Result<impl Responder> {
     .bind(beat.running_tasks)
     .bind(beat.total_tasks)
     .bind(&beat.version)
+    .bind(&beat.profile)
+    .bind(&beat.tags)
+    .bind(sqlx::types::Json(&beat.labels))
+    .bind(beat.status == WorkerStatus::Draining)
+    .bind(beat.status == WorkerStatus::Maintenance)
+    .fetch_one(&req.get_pool())
+    .await?;
+
+    if joined {
+        live_updates::send(
+            req.get_broker(),
+            &LiveUpdate::WorkerJoined {
+                worker_id: beat.uuid,
+                addr: beat.addr.clone(),
+            },
+        )
+        .await;
+    }
+
+    live_updates::send(
+        req.get_broker(),
+        &LiveUpdate::WorkerHeartbeat {
+            worker_id: beat.uuid,
+            running_tasks: beat.running_tasks,
+            total_tasks: beat.total_tasks,
+            last_seen_datetime: beat.last_seen_datetime,
+        },
+    )
+    .await;
+
+    Ok(StatusCode::OK)
+}
+
+/// A worker that was told to shut down has finished (or stopped) its tasks and
+/// is about to exit. It's gone straight away, rather than once it has missed
+/// enough heartbeats.
+pub async fn gone(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+
+    jwt::validate_worker_jwt(&req, Some(id))?;
+
+    trace!(uuid=?id, "worker gone");
+
+    let gone: Option<(DateTime<Utc>,)> = sqlx::query_as(
+        "UPDATE worker
+        SET gone_datetime = CURRENT_TIMESTAMP,
+            running_tasks = 0
+        WHERE id = $1
+        AND gone_datetime IS NULL
+        RETURNING last_seen_datetime",
+    )
+    .bind(id)
+    .fetch_optional(&req.get_pool())
+    .await?;
+
+    if let Some((last_seen_datetime,)) = gone {
+        live_updates::send(
+            req.get_broker(),
+            &LiveUpdate::WorkerDied {
+                worker_id: id,
+                last_seen_datetime,
+            },
+        )
+        .await;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Of the task runs a worker found Kubernetes jobs for, the ones that aren't
+/// running. Their jobs were left behind by a worker that died, and the task
+/// runs have since been requeued or finished.
+pub async fn orphaned_task_runs(mut req: Request<State>) -> highnoon::Result<impl Responder> {
+    jwt::validate_worker_jwt(&req, None)?;
+
+    let task_run_ids: Vec<Uuid> = req.body_json().await?;
+
+    let orphaned: Vec<(Uuid,)> = sqlx::query_as(
+        "SELECT ids.id
+        FROM UNNEST($1::UUID[]) AS ids(id)
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM task_run tr
+            WHERE tr.id = ids.id
+            AND tr.state IN ('active', 'running')
+        )",
+    )
+    .bind(&task_run_ids)
+    .fetch_all(&req.get_pool())
+    .await?;
+
+    trace!(
+        "{} of {} task runs are orphaned",
+        orphaned.len(),
+        task_run_ids.len()
+    );
+
+    let orphaned: Vec<Uuid> = orphaned.into_iter().map(|(id,)| id).collect();
+    Ok(Json(orphaned))
+}
+
+/// a drained worker has finished its tasks and is about to exit
+pub async fn retire(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+
+    jwt::validate_worker_jwt(&req, Some(id))?;
+
+    trace!(uuid=?id, "worker retired");
+
+    sqlx::query(
+        "UPDATE worker
+        SET retired_datetime = CURRENT_TIMESTAMP,
+            running_tasks = 0
+        WHERE id = $1",
+    )
+    .bind(id)
     .execute(&req.get_pool())
     .await?;
 
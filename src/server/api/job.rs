@@ -1,39 +1,73 @@
 use crate::{
-    messages::ConfigUpdate,
+    messages::{ConfigUpdate, LiveUpdate},
     server::{
-        api::{auth, config_cache, request_ext::RequestExt, types::Job, updates, State},
+        api::{
+            audit, auth, config_cache,
+            paging::{list_response, Paging},
+            quota::{check_job_quota, check_trigger_quota},
+            request_ext::RequestExt,
+            types::Job,
+            updates, State,
+        },
         body_parser::read_from_body,
+        live_updates,
     },
     util::{is_pg_integrity_error, pg_error},
 };
-use highnoon::{Json, Request, Responder, Response, StatusCode};
+use highnoon::{headers::IfMatch, Json, Request, Responder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
-use tracing::{info, warn};
+use serde_json::{json, Value as JsonValue};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashSet;
+use tracing::{info, trace, warn};
 use uuid::Uuid;
 
+mod definition;
+mod drift;
 mod duration;
 mod graph;
+pub mod groups;
+mod overrides;
+mod patch;
 pub mod reference;
+mod runs;
+mod schedule;
+mod secrets;
+mod simulate;
+mod slo;
+mod stats;
 mod task_runs;
 mod tasks;
 mod tokens;
 mod triggers;
+mod versions;
 
 pub use self::{
+    definition::get_definition_stats,
+    drift::get_drift,
     duration::get_duration,
     graph::get_graph,
+    overrides::{clear_trigger_override, get_effective_trigger, set_trigger_override},
+    patch::patch_job,
+    runs::{get_job_run, list_job_runs, rerun_job_run},
+    schedule::get_schedule_ics,
+    simulate::simulate_trigger,
+    slo::get_slo,
+    stats::get_stats,
     tasks::list_tasks,
     tokens::{
         clear_tokens_trigger_datetime, get_tokens, get_tokens_overview, get_tokens_trigger_datetime,
     },
     triggers::{get_trigger, get_triggers_by_job},
+    versions::{get_version, list_versions, rollback},
 };
 use crate::{
     messages::{ProcessToken, TriggerUpdate},
     util::first,
 };
-pub use task_runs::{list_job_all_task_runs, list_task_runs};
+pub use task_runs::{
+    get_token_history, list_job_all_task_runs, list_task_attempts, list_task_runs,
+};
 
 pub async fn get_job_project_id(pool: &PgPool, job_id: Uuid) -> highnoon::Result<Uuid> {
     let row: Option<(Uuid,)> = sqlx::query_as(
@@ -64,23 +98,214 @@ pub async fn get_project_id(pool: &PgPool, name: &str) -> highnoon::Result<Uuid>
     }
 }
 
-pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
-    let pool = req.get_pool();
+/// the most jobs that can be applied in one batch
+const MAX_BATCH_JOBS: usize = 500;
+
+/// a job that has been written, with what's left to do once the transaction commits
+struct AppliedJob {
+    job_id: Uuid,
+    project: String,
+    name: String,
+    triggers: Vec<Uuid>,
+    tasks: Vec<Uuid>,
+    drift: drift::JobDrift,
+    from_source: bool,
+    version: i32,
+    /// whether the job didn't exist before
+    created: bool,
+}
 
+/// Create or replace a job. With `If-Match` it's only replaced if it hasn't
+/// changed since its ETag was read.
+pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
     let job: Job = read_from_body(&mut req).await?;
+    let if_match = req.header::<IfMatch>();
+
+    check_before_apply(&req, &job).await?;
+
+    let mut txn = req.get_pool().begin().await?;
+
+    let applied = match apply_job(&req, &mut txn, job, None, if_match.as_ref()).await? {
+        Some(applied) => applied,
+        None => return StatusCode::CONFLICT.into_response(),
+    };
+
+    txn.commit().await?;
+
+    let etag = patch::definition_etag(&applied.drift.definition_hash);
+    send_job_updates(&req, applied).await?;
+
+    Ok(Response::status(StatusCode::CREATED).header(etag))
+}
+
+#[derive(Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum BatchStatus {
+    Created,
+    /// the job's name or id clashes with another job, so nothing was applied
+    Conflict,
+    /// the job couldn't be applied, see its error, so nothing was applied
+    Rejected,
+    /// another job in the batch failed, so this one was rolled back
+    NotApplied,
+}
+
+#[derive(Serialize)]
+struct BatchResult {
+    project: String,
+    name: String,
+    id: Uuid,
+    status: BatchStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Create or update several jobs in one transaction, so either all of them are
+/// applied or none are. If a job is rejected, nothing is applied and its error
+/// is reported in its result.
+pub async fn create_batch(mut req: Request<State>) -> highnoon::Result<Response> {
+    let jobs: Vec<Job> = read_from_body(&mut req).await?;
+
+    if jobs.is_empty() {
+        return Err(highnoon::Error::bad_request("the batch has no jobs"));
+    }
+    if jobs.len() > MAX_BATCH_JOBS {
+        return Err(highnoon::Error::bad_request(format!(
+            "the batch has {} jobs, at most {MAX_BATCH_JOBS} can be applied at once",
+            jobs.len()
+        )));
+    }
+
+    let mut seen_ids = HashSet::new();
+    let mut seen_names = HashSet::new();
+    for job in &jobs {
+        if !seen_ids.insert(job.uuid) || !seen_names.insert((&job.project, &job.name)) {
+            return Err(highnoon::Error::bad_request(format!(
+                "job '{}' in project '{}' is in the batch more than once",
+                job.name, job.project
+            )));
+        }
+    }
+
+    let mut results: Vec<BatchResult> = jobs
+        .iter()
+        .map(|job| BatchResult {
+            project: job.project.clone(),
+            name: job.name.clone(),
+            id: job.uuid,
+            status: BatchStatus::NotApplied,
+            error: None,
+        })
+        .collect();
+
+    for (i, job) in jobs.iter().enumerate() {
+        if let Err(err) = check_before_apply(&req, job).await {
+            return rejected(results, i, err);
+        }
+    }
+
+    let mut txn = req.get_pool().begin().await?;
+    let mut applied_jobs = Vec::new();
+
+    for (i, job) in jobs.into_iter().enumerate() {
+        match apply_job(&req, &mut txn, job, None, None).await {
+            Ok(Some(applied)) => applied_jobs.push(applied),
+            Ok(None) => {
+                // the transaction is dropped without committing, so nothing is applied
+                results[i].status = BatchStatus::Conflict;
+                return Response::status(StatusCode::CONFLICT).json(results);
+            }
+            Err(err) => return rejected(results, i, err),
+        }
+    }
+
+    txn.commit().await?;
+
+    info!("applied batch of {} jobs", applied_jobs.len());
+
+    for applied in applied_jobs {
+        send_job_updates(&req, applied).await?;
+    }
+
+    for result in &mut results {
+        result.status = BatchStatus::Created;
+    }
+
+    Response::status(StatusCode::CREATED).json(results)
+}
+
+/// the response to a batch whose `i`th job was rejected
+fn rejected(
+    mut results: Vec<BatchResult>,
+    i: usize,
+    err: highnoon::Error,
+) -> highnoon::Result<Response> {
+    warn!(project=%results[i].project, job=%results[i].name,
+        "job in batch was rejected, rolling back the batch: {:?}", err);
+    results[i].status = BatchStatus::Rejected;
+    results[i].error = Some(format!("{err:?}"));
+    Response::status(StatusCode::UNPROCESSABLE_ENTITY).json(results)
+}
+
+/// Check what can't be checked inside the job's transaction without holding it
+/// open: that the caller can update the job, then its Vault and AWS secrets,
+/// which are read over the network.
+async fn check_before_apply(req: &Request<State>, job: &Job) -> highnoon::Result<()> {
+    let project_id = get_project_id(&req.get_pool(), &job.project).await?;
+    auth::update().job(job.uuid, project_id).check(req).await?;
+
+    secrets::check_external_secrets(&req.state().config, job).await
+}
+
+/// Write a job, its triggers and its tasks in the transaction, and store its
+/// definition as a new version if it changed. `rollback_of` is the version being
+/// restored, if this is a rollback. With `if_match` the job is locked and
+/// rejected if its current definition doesn't match. Returns `None` if the job
+/// clashes with another one, in which case the transaction can't be used.
+async fn apply_job(
+    req: &Request<State>,
+    txn: &mut Transaction<'_, Postgres>,
+    mut job: Job,
+    rollback_of: Option<i32>,
+    if_match: Option<&IfMatch>,
+) -> highnoon::Result<Option<AppliedJob>> {
+    let pool = req.get_pool();
 
     let project_id = get_project_id(&pool, &job.project).await?;
     auth::update().job(job.uuid, project_id).check(&req).await?;
 
-    let mut txn = pool.begin().await?;
+    // the source hash is stored on its own so it doesn't change the definition's hash
+    let source_hash = job.source_hash.take();
+
+    // store the definition as submitted, before groups are expanded into plain dependencies
+    let raw_definition = serde_json::to_string(&job)?;
+    let definition_hash = drift::definition_hash(&raw_definition);
+    let definition_zstd = definition::compress(&raw_definition)?;
+    groups::expand_groups(&mut job)?;
+
+    let previous_definition = if if_match.is_some() {
+        patch::load_locked(txn, job.uuid).await?
+    } else {
+        definition::load(&mut *txn, job.uuid).await?
+    };
+    patch::check_if_match(if_match, previous_definition.as_deref())?;
+
+    check_job_quota(txn, project_id, job.uuid).await?;
 
-    let query = sqlx::query(
+    let slo = job.slo.as_ref().map(slo::parse_slo).transpose()?;
+
+    let query = sqlx::query_as::<_, (Option<String>, Option<String>)>(
         "INSERT INTO job(
-            id, name, project_id, description, paused, raw_definition
+            id, name, project_id, description, paused,
+            raw_definition, definition_zstd, definition_size,
+            slo_start_secs, slo_finish_secs, slo_objective,
+            source_hash, applied_definition_hash
         ) VALUES (
             $1, $2, $3, $4,
             COALESCE($5, FALSE),
-            $6
+            NULL, $6, $12,
+            $7, $8, $9,
+            $10, CASE WHEN $10 IS NULL THEN NULL ELSE $11 END
         )
         ON CONFLICT(id)
         DO UPDATE
@@ -88,7 +313,17 @@ pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
             project_id = $3,
             description = $4,
             paused = COALESCE($5, job.paused),
-            raw_definition = $6",
+            raw_definition = NULL,
+            definition_zstd = $6,
+            definition_size = $12,
+            slo_start_secs = $7,
+            slo_finish_secs = $8,
+            slo_objective = $9,
+            source_hash = COALESCE($10, job.source_hash),
+            applied_definition_hash = CASE WHEN $10 IS NULL
+                THEN job.applied_definition_hash
+                ELSE $11 END
+        RETURNING source_hash, applied_definition_hash",
     );
 
     let res = query
@@ -97,57 +332,209 @@ pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
         .bind(project_id)
         .bind(&job.description)
         .bind(job.paused)
-        .bind(serde_json::to_string(&job)?)
-        .execute(&mut txn)
+        .bind(&definition_zstd)
+        .bind(slo.and_then(|slo| slo.start_secs))
+        .bind(slo.and_then(|slo| slo.finish_secs))
+        .bind(slo.map(|slo| slo.objective))
+        .bind(&source_hash)
+        .bind(&definition_hash)
+        .bind(raw_definition.len() as i32)
+        .fetch_one(&mut *txn)
         .await;
 
-    match pg_error(res)? {
-        Ok(_done) => {
+    let drift = match pg_error(res)? {
+        Ok((source_hash, applied_definition_hash)) => {
             info!("created job {} -> {}", job.name, job.uuid);
+            drift::JobDrift {
+                job_id: job.uuid,
+                project: job.project.clone(),
+                name: job.name.clone(),
+                source_hash,
+                applied_definition_hash,
+                definition_hash,
+            }
         }
         Err(err) => {
             warn!("error creating job: {}", err);
             return if is_pg_integrity_error(&err) {
-                StatusCode::CONFLICT.into_response()
+                Ok(None)
             } else {
                 Err(err.into())
             };
         }
     };
 
+    let before = match previous_definition {
+        Some(previous) => serde_json::from_str(&previous)?,
+        None => JsonValue::Null,
+    };
+    let created = before.is_null();
+    let audit = match (rollback_of, created) {
+        (Some(version), _) => {
+            audit::action("rollback", "job").details(json!({ "version": version }))
+        }
+        (None, true) => audit::create("job"),
+        (None, false) => audit::update("job"),
+    };
+    audit
+        .job(job.uuid, project_id)
+        .diff(&before, &serde_json::from_str(&raw_definition)?)
+        .record(req, &mut *txn)
+        .await?;
+
+    let version = versions::record_version(
+        txn,
+        job.uuid,
+        versions::NewVersion {
+            definition_hash: &drift.definition_hash,
+            definition_zstd: &definition_zstd,
+            definition_size: raw_definition.len() as i32,
+            author: auth::author(req),
+            rollback_of,
+        },
+    )
+    .await?;
+
     let mut triggers_to_tx = Vec::new();
     let mut tasks_to_tx = Vec::new();
 
     // insert the triggers
     for trigger in &job.triggers {
-        let id = triggers::create_trigger(&mut txn, &job, trigger).await?;
+        let id = triggers::create_trigger(txn, &job, trigger).await?;
         triggers_to_tx.push(id);
     }
 
+    check_trigger_quota(txn, project_id).await?;
+
     for task in &job.tasks {
-        let id = tasks::create_task(&mut txn, task, &job).await?;
+        let id = tasks::create_task(txn, task, &job).await?;
         tasks_to_tx.push(id);
     }
 
     for task in &job.tasks {
-        tasks::create_task_edges(&mut txn, task, &job).await?;
+        tasks::create_task_edges(txn, task, &job).await?;
     }
 
-    txn.commit().await?;
+    tasks::set_on_failure_task(txn, &job).await?;
+
+    Ok(Some(AppliedJob {
+        job_id: job.uuid,
+        project: job.project,
+        name: job.name,
+        triggers: triggers_to_tx,
+        tasks: tasks_to_tx,
+        drift,
+        from_source: source_hash.is_some(),
+        version,
+        created,
+    }))
+}
+
+/// tell the schedulers and workers about a job once it's committed
+async fn send_job_updates(req: &Request<State>, applied: AppliedJob) -> highnoon::Result<()> {
+    trace!(job_id=?applied.job_id, project=%applied.project, job=%applied.name,
+        "sending job updates");
+
+    updates::send_trigger_update(req.get_broker(), TriggerUpdate(applied.triggers)).await?;
+
+    if applied.created {
+        live_updates::send(
+            req.get_broker(),
+            &LiveUpdate::JobCreated {
+                job_id: applied.job_id,
+                project: applied.project.clone(),
+                name: applied.name.clone(),
+            },
+        )
+        .await;
+    }
 
-    updates::send_trigger_update(req.get_channel(), TriggerUpdate(triggers_to_tx)).await?;
+    for id in applied.tasks {
+        config_cache::send(req.get_broker(), ConfigUpdate::TaskDef(id)).await?;
+    }
 
-    for id in tasks_to_tx {
-        config_cache::send(req.get_channel(), ConfigUpdate::TaskDef(id)).await?;
+    // a job applied from source can't have drifted, anything else is an out of band edit
+    if !applied.from_source && applied.drift.drifted() {
+        drift::send_drift_event(req, applied.drift);
     }
 
-    StatusCode::CREATED.into_response()
+    Ok(())
 }
 
 #[derive(Deserialize)]
 struct QueryJob {
+    pub project: Option<String>,
+    pub name: Option<String>,
+    /// only jobs with names containing this
+    pub q: Option<String>,
+    pub paused: Option<bool>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ListJob {
+    pub id: Uuid,
     pub project: String,
+    pub project_id: Uuid,
     pub name: String,
+    pub description: String,
+    pub paused: bool,
+}
+
+const LIST_JOB_SORT: &[(&str, &str)] = &[
+    ("name", "j.name"),
+    ("project", "p.name"),
+    ("paused", "j.paused"),
+];
+
+/// jobs in every project, or just the named one
+async fn list(req: Request<State>, q: QueryJob) -> highnoon::Result<Response> {
+    let pool = req.get_pool();
+
+    let project_id = match &q.project {
+        Some(project) => Some(get_project_id(&pool, project).await?),
+        None => None,
+    };
+
+    auth::list().job(None, project_id).check(&req).await?;
+
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(LIST_JOB_SORT, "name")?;
+
+    let filter = "FROM job j
+        JOIN project p ON p.id = j.project_id
+        WHERE ($1::UUID IS NULL OR j.project_id = $1)
+        AND ($2::VARCHAR IS NULL OR STRPOS(LOWER(j.name), LOWER($2)) > 0)
+        AND ($3::BOOLEAN IS NULL OR j.paused = $3)";
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(project_id)
+        .bind(&q.q)
+        .bind(q.paused)
+        .fetch_one(&pool)
+        .await?;
+
+    let jobs: Vec<ListJob> = sqlx::query_as(&format!(
+        "SELECT
+            j.id AS id,
+            p.name AS project,
+            p.id AS project_id,
+            j.name AS name,
+            j.description AS description,
+            j.paused AS paused
+        {filter}
+        ORDER BY {order_by}, j.id
+        LIMIT $4
+        OFFSET $5"
+    ))
+    .bind(project_id)
+    .bind(&q.q)
+    .bind(q.paused)
+    .bind(paging.limit(100))
+    .bind(paging.offset())
+    .fetch_all(&pool)
+    .await?;
+
+    list_response(jobs, total)
 }
 
 #[derive(Serialize, sqlx::FromRow)]
@@ -159,9 +546,14 @@ struct GetJob {
     pub paused: bool,
 }
 
-pub async fn get_by_name(req: Request<State>) -> highnoon::Result<impl Responder> {
+pub async fn get_by_name(req: Request<State>) -> highnoon::Result<Response> {
     let q = req.query::<QueryJob>()?;
 
+    let (project, name) = match (&q.project, &q.name) {
+        (Some(project), Some(name)) => (project, name),
+        _ => return list(req, q).await,
+    };
+
     let maybe_job: Option<GetJob> = sqlx::query_as(
         "SELECT
             j.id AS id,
@@ -174,8 +566,8 @@ pub async fn get_by_name(req: Request<State>) -> highnoon::Result<impl Responder
         WHERE j.name = $1
         AND p.name = $2",
     )
-    .bind(&q.name)
-    .bind(&q.project)
+    .bind(name)
+    .bind(project)
     .fetch_optional(&req.get_pool())
     .await?;
 
@@ -195,7 +587,10 @@ struct GetJobExtra {
     pub name: String,
     pub description: String,
     pub paused: bool,
-    pub raw_definition: String,
+    pub raw_definition: Option<String>,
+    #[serde(skip)]
+    pub definition_zstd: Option<Vec<u8>>,
+    pub definition_version: Option<i32>,
     pub active_tasks: i64,
     pub waiting_tasks: i64,
     pub failed_tasks_last_hour: i64,
@@ -225,6 +620,8 @@ pub async fn get_by_id(req: Request<State>) -> highnoon::Result<impl Responder>
             j.description AS description,
             j.paused AS paused,
             j.raw_definition AS raw_definition,
+            j.definition_zstd AS definition_zstd,
+            j.definition_version AS definition_version,
             (
                 SELECT COUNT(1)
                 FROM these_tasks t
@@ -258,9 +655,17 @@ pub async fn get_by_id(req: Request<State>) -> highnoon::Result<impl Responder>
     .fetch_optional(&req.get_pool())
     .await?;
 
-    if let Some(job) = maybe_job {
+    if let Some(mut job) = maybe_job {
         auth::get().job(job.id, job.project_id).check(&req).await?;
-        Json(job).into_response()
+        job.raw_definition =
+            definition::decompress(job.raw_definition.take(), job.definition_zstd.as_deref())?;
+        let mut response = Response::ok();
+        if let Some(raw_definition) = &job.raw_definition {
+            response = response.header(patch::definition_etag(&drift::definition_hash(
+                raw_definition,
+            )));
+        }
+        response.json(job)
     } else {
         StatusCode::NOT_FOUND.into_response()
     }
@@ -285,6 +690,10 @@ pub async fn delete(req: Request<State>) -> highnoon::Result<StatusCode> {
         Ok(done) => {
             if done.rows_affected() == 1 {
                 info!("deleted job {}", id);
+                audit::delete("job")
+                    .job(id, None)
+                    .record(&req, &req.get_pool())
+                    .await?;
                 Ok(StatusCode::NO_CONTENT)
             } else {
                 info!("no job with id {}", id);
@@ -320,39 +729,55 @@ pub async fn get_paused(req: Request<State>) -> highnoon::Result<impl Responder>
 }
 
 #[derive(Deserialize)]
-struct Paused {
-    paused: bool,
+pub struct Paused {
+    pub paused: bool,
 }
 
 pub async fn set_paused(mut req: Request<State>) -> impl Responder {
     let job_id = req.param("id")?.parse::<Uuid>()?;
 
-    auth::update().job(job_id, None).check(&req).await?;
+    auth::update()
+        .job(job_id, None)
+        .kind("pause")
+        .check(&req)
+        .await?;
 
     let Paused { paused } = req.body_json().await?;
 
-    let row = sqlx::query(
-        "UPDATE job
-        SET paused = $2
-        WHERE id = $1",
+    let mut txn = req.get_pool().begin().await?;
+
+    // the old value is read from the locked row, so the audit log has what it really was
+    let row: sqlx::Result<Option<(bool, Uuid)>> = sqlx::query_as(
+        "UPDATE job j
+        SET paused = $2,
+            paused_by_project = FALSE
+        FROM (SELECT paused, project_id FROM job WHERE id = $1 FOR UPDATE) old
+        WHERE j.id = $1
+        RETURNING old.paused, old.project_id",
     )
     .bind(job_id)
     .bind(paused)
-    .execute(&req.get_pool())
+    .fetch_optional(&mut txn)
     .await;
 
     match row {
-        Ok(done) => {
-            if done.rows_affected() == 1 {
-                if paused {
-                    info!("paused job {}", job_id);
-                } else {
-                    info!("unpaused job {}", job_id);
-                }
+        Ok(Some((was_paused, project_id))) => {
+            if paused {
+                info!("paused job {}", job_id);
             } else {
-                info!("no job with id {}", job_id);
-                return Ok(StatusCode::NOT_FOUND);
+                info!("unpaused job {}", job_id);
             }
+
+            audit::action(if paused { "pause" } else { "unpause" }, "job")
+                .job(job_id, project_id)
+                .diff(&json!({ "paused": was_paused }), &json!({ "paused": paused }))
+                .record(&req, &mut txn)
+                .await?;
+            txn.commit().await?;
+        }
+        Ok(None) => {
+            info!("no job with id {}", job_id);
+            return Ok(StatusCode::NOT_FOUND);
         }
         Err(err) => {
             warn!("error pausing job: {:?}", err);
@@ -360,37 +785,55 @@ pub async fn set_paused(mut req: Request<State>) -> impl Responder {
         }
     }
 
+    send_pause_updates(&req, &[job_id], paused).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Tell the scheduler and workers that jobs have been paused or unpaused, after
+/// the change is committed.
+pub async fn send_pause_updates(
+    req: &Request<State>,
+    job_ids: &[Uuid],
+    paused: bool,
+) -> highnoon::Result<()> {
+    if job_ids.is_empty() {
+        return Ok(());
+    }
+
     // send trigger updates for the whole job to notify the scheduler
     let triggers_to_tx: Vec<(Uuid,)> = sqlx::query_as(
         "SELECT id
         FROM trigger
-        WHERE job_id = $1",
+        WHERE job_id = ANY($1)",
     )
-    .bind(job_id)
+    .bind(job_ids)
     .fetch_all(&req.get_pool())
     .await?;
 
     let triggers_to_tx = triggers_to_tx.into_iter().map(first).collect();
-    updates::send_trigger_update(req.get_channel(), TriggerUpdate(triggers_to_tx)).await?;
+    updates::send_trigger_update(req.get_broker(), TriggerUpdate(triggers_to_tx)).await?;
 
     // send taskdef updates for the whole job to notify the workers
     let tasks_to_tx: Vec<(Uuid,)> = sqlx::query_as(
         "SELECT id
         FROM task
-        WHERE job_id = $1",
+        WHERE job_id = ANY($1)",
     )
-    .bind(job_id)
+    .bind(job_ids)
     .fetch_all(&req.get_pool())
     .await?;
 
     for (id,) in tasks_to_tx {
-        config_cache::send(req.get_channel(), ConfigUpdate::TaskDef(id)).await?;
+        config_cache::send(req.get_broker(), ConfigUpdate::TaskDef(id)).await?;
     }
 
     // if job is being unpaused notify the token processor to trigger any pending tasks
     if !paused {
-        updates::send_token_update(req.get_channel(), ProcessToken::UnpauseJob(job_id)).await?;
+        for &job_id in job_ids {
+            updates::send_token_update(req.get_broker(), ProcessToken::UnpauseJob(job_id)).await?;
+        }
     }
 
-    Ok(StatusCode::NO_CONTENT)
+    Ok(())
 }
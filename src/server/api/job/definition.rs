@@ -0,0 +1,139 @@
+use crate::server::api::{auth, request_ext::RequestExt, State};
+use anyhow::Result;
+use highnoon::{Json, Request, Responder};
+use serde::Serialize;
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+/// zstd's default level, generated definitions are very repetitive so higher levels gain little
+const ZSTD_LEVEL: i32 = 3;
+
+/// how many of the largest definitions the stats endpoint lists
+const LARGEST_JOBS: i64 = 10;
+
+pub fn compress(raw_definition: &str) -> Result<Vec<u8>> {
+    Ok(zstd::encode_all(raw_definition.as_bytes(), ZSTD_LEVEL)?)
+}
+
+/// Get a job's definition from whichever column it was stored in.
+/// Jobs created before definitions were compressed only have `raw_definition`.
+pub fn decompress(
+    raw_definition: Option<String>,
+    definition_zstd: Option<&[u8]>,
+) -> Result<Option<String>> {
+    match definition_zstd {
+        Some(compressed) => Ok(Some(String::from_utf8(zstd::decode_all(compressed)?)?)),
+        None => Ok(raw_definition),
+    }
+}
+
+/// load a job's definition as it was submitted
+pub async fn load(executor: impl PgExecutor<'_>, job_id: Uuid) -> Result<Option<String>> {
+    let row: Option<(Option<String>, Option<Vec<u8>>)> = sqlx::query_as(
+        "SELECT raw_definition, definition_zstd
+        FROM job
+        WHERE id = $1",
+    )
+    .bind(job_id)
+    .fetch_optional(executor)
+    .await?;
+
+    match row {
+        Some((raw_definition, definition_zstd)) => {
+            decompress(raw_definition, definition_zstd.as_deref())
+        }
+        None => Ok(None),
+    }
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct DefinitionTotals {
+    num_jobs: i64,
+    /// jobs stored before compression was added, they're compressed when next updated
+    num_uncompressed: i64,
+    definition_bytes: i64,
+    stored_bytes: i64,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct LargestJob {
+    job_id: Uuid,
+    project: String,
+    name: String,
+    definition_bytes: i64,
+    stored_bytes: i64,
+}
+
+#[derive(Serialize)]
+struct DefinitionStats {
+    #[serde(flatten)]
+    totals: DefinitionTotals,
+    compression_ratio: Option<f64>,
+    largest: Vec<LargestJob>,
+}
+
+/// how much space job definitions take, before and after compression
+pub async fn get_definition_stats(req: Request<State>) -> highnoon::Result<impl Responder> {
+    auth::get().kind("status").check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let totals: DefinitionTotals = sqlx::query_as(
+        "SELECT
+            COUNT(1) AS num_jobs,
+            COUNT(1) FILTER (WHERE definition_zstd IS NULL) AS num_uncompressed,
+            COALESCE(SUM(
+                COALESCE(definition_size, OCTET_LENGTH(raw_definition), 0)
+            ), 0)::BIGINT AS definition_bytes,
+            COALESCE(SUM(
+                COALESCE(OCTET_LENGTH(definition_zstd), OCTET_LENGTH(raw_definition), 0)
+            ), 0)::BIGINT AS stored_bytes
+        FROM job",
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    let largest: Vec<LargestJob> = sqlx::query_as(
+        "SELECT
+            j.id AS job_id,
+            p.name AS project,
+            j.name AS name,
+            COALESCE(j.definition_size, OCTET_LENGTH(j.raw_definition), 0)::BIGINT
+                AS definition_bytes,
+            COALESCE(OCTET_LENGTH(j.definition_zstd), OCTET_LENGTH(j.raw_definition), 0)::BIGINT
+                AS stored_bytes
+        FROM job j
+        JOIN project p ON p.id = j.project_id
+        ORDER BY definition_bytes DESC
+        LIMIT $1",
+    )
+    .bind(LARGEST_JOBS)
+    .fetch_all(&pool)
+    .await?;
+
+    let compression_ratio = (totals.stored_bytes > 0)
+        .then(|| totals.definition_bytes as f64 / totals.stored_bytes as f64);
+
+    Ok(Json(DefinitionStats {
+        totals,
+        compression_ratio,
+        largest,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let raw = r#"{"name":"example","tasks":[]}"#.repeat(100);
+        let compressed = compress(&raw)?;
+        assert!(compressed.len() < raw.len());
+
+        assert_eq!(decompress(None, Some(&compressed))?, Some(raw.clone()));
+        assert_eq!(decompress(Some(raw.clone()), None)?, Some(raw));
+        assert_eq!(decompress(None, None)?, None);
+        Ok(())
+    }
+}
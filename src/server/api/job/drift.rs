@@ -0,0 +1,153 @@
+use super::definition;
+use crate::server::api::{auth, request_ext::RequestExt, State};
+use cadence::CountedExt;
+use chrono::{DateTime, Utc};
+use highnoon::{Request, Response, StatusCode};
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// how long to wait for the job events webhook to accept an event
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// hash of a job's stored definition, used to tell when it has changed since it was applied
+pub fn definition_hash(raw_definition: &str) -> String {
+    format!("{:016x}", xxhash_rust::xxh3::xxh3_64(raw_definition.as_bytes()))
+}
+
+/// whether a job's definition has been changed since it was last applied from source
+#[derive(Serialize, Clone, Debug)]
+pub struct JobDrift {
+    pub job_id: Uuid,
+    pub project: String,
+    pub name: String,
+    /// the hash given by the tool that last applied the job from source
+    pub source_hash: Option<String>,
+    /// the definition hash when the job was last applied from source
+    pub applied_definition_hash: Option<String>,
+    pub definition_hash: String,
+}
+
+impl JobDrift {
+    /// a job that has never been applied from source can't drift
+    pub fn drifted(&self) -> bool {
+        matches!(&self.applied_definition_hash, Some(applied) if *applied != self.definition_hash)
+    }
+}
+
+#[derive(Serialize)]
+struct JobEvent<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    drift: &'a JobDrift,
+    datetime: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct GetDrift {
+    #[serde(flatten)]
+    drift: JobDrift,
+    drifted: bool,
+}
+
+/// Tell the job events webhook, if there is one, that a job was changed out of band.
+/// The event is sent in the background so a slow webhook doesn't hold up the API.
+pub fn send_drift_event(req: &Request<State>, drift: JobDrift) {
+    warn!(job_id=?drift.job_id,
+        source_hash=?drift.source_hash,
+        "job definition no longer matches its source");
+
+    req.get_statsd()
+        .incr_with_tags("jobs.drifted")
+        .with_tag("project", &drift.project)
+        .send();
+
+    let url = match &req.state().config.job_events_url {
+        Some(url) => url.clone(),
+        None => return,
+    };
+
+    tokio::spawn(async move {
+        let event = JobEvent {
+            event: "job_drifted",
+            drift: &drift,
+            datetime: Utc::now(),
+        };
+
+        let res = async {
+            reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?
+                .post(&url)
+                .json(&event)
+                .send()
+                .await?
+                .error_for_status()
+        }
+        .await;
+
+        match res {
+            Ok(_) => info!(job_id=?drift.job_id, "sent job drift event"),
+            Err(err) => warn!(job_id=?drift.job_id, "failed to send job drift event: {}", err),
+        }
+    });
+}
+
+#[derive(sqlx::FromRow)]
+struct StoredJob {
+    job_id: Uuid,
+    project_id: Uuid,
+    project: String,
+    name: String,
+    raw_definition: Option<String>,
+    definition_zstd: Option<Vec<u8>>,
+    source_hash: Option<String>,
+    applied_definition_hash: Option<String>,
+}
+
+/// compare a job's stored definition with the one last applied from source
+pub async fn get_drift(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+
+    let maybe_job: Option<StoredJob> = sqlx::query_as(
+        "SELECT
+            j.id AS job_id,
+            p.id AS project_id,
+            p.name AS project,
+            j.name AS name,
+            j.raw_definition AS raw_definition,
+            j.definition_zstd AS definition_zstd,
+            j.source_hash AS source_hash,
+            j.applied_definition_hash AS applied_definition_hash
+        FROM job j
+        JOIN project p ON p.id = j.project_id
+        WHERE j.id = $1",
+    )
+    .bind(job_id)
+    .fetch_optional(&req.get_pool())
+    .await?;
+
+    let job = match maybe_job {
+        Some(job) => job,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    auth::get().job(job.job_id, job.project_id).check(&req).await?;
+
+    let raw_definition = definition::decompress(job.raw_definition, job.definition_zstd.as_deref())?;
+
+    let drift = JobDrift {
+        job_id: job.job_id,
+        project: job.project,
+        name: job.name,
+        source_hash: job.source_hash,
+        applied_definition_hash: job.applied_definition_hash,
+        definition_hash: definition_hash(raw_definition.as_deref().unwrap_or_default()),
+    };
+
+    Response::ok().json(GetDrift {
+        drifted: drift.drifted(),
+        drift,
+    })
+}
@@ -1,6 +1,13 @@
-use crate::server::api::{auth, request_ext::RequestExt, State};
+use crate::server::api::{
+    auth,
+    conditional::Conditional,
+    job::{definition, groups::group_parents},
+    request_ext::RequestExt,
+    types::Job,
+    State,
+};
 use chrono::{DateTime, Utc};
-use highnoon::{Json, Request, Responder};
+use highnoon::{Request, Response};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -20,10 +27,21 @@ struct Edge {
     kind: String,
 }
 
-#[derive(Serialize, sqlx::FromRow)]
+/// a task group, so the UI can collapse sub-graphs
+#[derive(Serialize)]
+struct Group {
+    name: String,
+    /// the enclosing group, if this is a nested group
+    parent: Option<String>,
+    /// ids of the tasks directly in this group
+    tasks: Vec<Uuid>,
+}
+
+#[derive(Serialize)]
 struct Graph {
     nodes: Vec<Node>,
     edges: Vec<Edge>,
+    groups: Vec<Group>,
 }
 
 #[derive(Deserialize)]
@@ -31,8 +49,12 @@ struct QueryGraph {
     trigger_datetime: Option<DateTime<Utc>>,
 }
 
-pub async fn get_graph(req: Request<State>) -> highnoon::Result<impl Responder> {
+/// A job's tasks and triggers, the edges between them and any upstream tasks in
+/// other jobs. The UI polls this, so it's compressed and can be revalidated
+/// with its ETag.
+pub async fn get_graph(req: Request<State>) -> highnoon::Result<Response> {
     let job_id = req.param("id")?.parse::<Uuid>()?;
+    let conditional = Conditional::from_request(&req);
 
     auth::get().job(job_id, None).check(&req).await?;
 
@@ -125,5 +147,49 @@ pub async fn get_graph(req: Request<State>) -> highnoon::Result<impl Responder>
 
     nodes.extend(extra_nodes);
 
-    Ok(Json(Graph { nodes, edges }))
+    let groups = get_groups(&req, job_id, &nodes).await?;
+
+    conditional.json(Graph {
+        nodes,
+        edges,
+        groups,
+    })
+}
+
+/// load the group hierarchy from the job's definition
+async fn get_groups(
+    req: &Request<State>,
+    job_id: Uuid,
+    nodes: &[Node],
+) -> highnoon::Result<Vec<Group>> {
+    let raw = definition::load(&req.get_pool(), job_id).await?;
+
+    let job: Job = match raw {
+        Some(raw) => serde_json::from_str(&raw)?,
+        None => return Ok(Vec::new()),
+    };
+
+    let parents = group_parents(&job)?;
+
+    let groups = job
+        .groups
+        .iter()
+        .map(|group| Group {
+            name: group.name.clone(),
+            parent: parents.get(&group.name).cloned(),
+            tasks: group
+                .tasks
+                .iter()
+                .flatten()
+                .filter_map(|name| {
+                    nodes
+                        .iter()
+                        .find(|n| n.kind == "task" && n.job_id == job_id && &n.name == name)
+                        .map(|n| n.id)
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(groups)
 }
@@ -0,0 +1,383 @@
+use crate::server::api::{
+    job::reference::{parse_reference, Reference, ReferenceKind},
+    types::{Job, Task},
+};
+use std::collections::{BTreeSet, HashMap};
+
+type Members = HashMap<String, BTreeSet<String>>;
+
+/// Expand task groups into plain task dependencies.
+///
+/// A `group/<name>` reference becomes a reference to each of the group's exit
+/// tasks (members that no other member depends on), and a group's own `depends`
+/// are added to each of its entry tasks (members that don't depend on another member).
+pub fn expand_groups(job: &mut Job) -> highnoon::Result<()> {
+    if job.groups.is_empty() {
+        return Ok(());
+    }
+
+    let parents = group_parents(job)?;
+    let members = group_members(job, &parents)?;
+
+    let mut entries = HashMap::new();
+    let mut exits = HashMap::new();
+    for group in &job.groups {
+        entries.insert(group.name.clone(), group_entries(job, &parents, &members, &group.name)?);
+        exits.insert(group.name.clone(), group_exits(job, &parents, &members, &group.name)?);
+    }
+
+    let mut group_depends: HashMap<String, Vec<String>> = HashMap::new();
+    for group in &job.groups {
+        let depends = expand_refs(job, &exits, group.depends.as_deref().unwrap_or_default())?;
+        for entry in &entries[&group.name] {
+            group_depends
+                .entry(entry.clone())
+                .or_default()
+                .extend(depends.iter().cloned());
+        }
+    }
+
+    let mut tasks = std::mem::take(&mut job.tasks);
+    for task in &mut tasks {
+        if let Some(depends) = &task.depends {
+            task.depends = Some(expand_refs(job, &exits, depends)?);
+        }
+        if let Some(depends) = &task.depends_failure {
+            task.depends_failure = Some(expand_refs(job, &exits, depends)?);
+        }
+        if let Some(extra) = group_depends.remove(&task.name) {
+            task.depends.get_or_insert_with(Vec::new).extend(extra);
+        }
+    }
+    job.tasks = tasks;
+
+    Ok(())
+}
+
+/// map each task and group name to the group that directly contains it
+pub fn group_parents(job: &Job) -> highnoon::Result<HashMap<String, String>> {
+    let mut parents = HashMap::new();
+
+    for group in &job.groups {
+        let children = group
+            .tasks
+            .iter()
+            .flatten()
+            .chain(group.groups.iter().flatten());
+
+        for child in children {
+            if let Some(other) = parents.insert(child.clone(), group.name.clone()) {
+                return Err(highnoon::Error::bad_request(format!(
+                    "'{child}' is in both group '{other}' and group '{}'",
+                    group.name
+                )));
+            }
+        }
+    }
+
+    Ok(parents)
+}
+
+/// resolve every task in each group, including tasks in nested groups
+fn group_members(job: &Job, parents: &HashMap<String, String>) -> highnoon::Result<Members> {
+    let mut members = Members::new();
+
+    for group in &job.groups {
+        if members.insert(group.name.clone(), BTreeSet::new()).is_some() {
+            return Err(highnoon::Error::bad_request(format!(
+                "duplicate group name '{}'",
+                group.name
+            )));
+        }
+        for sub in group.groups.iter().flatten() {
+            if !job.groups.iter().any(|g| &g.name == sub) {
+                return Err(highnoon::Error::bad_request(format!(
+                    "group '{}' contains unknown group '{sub}'",
+                    group.name
+                )));
+            }
+        }
+    }
+
+    for group in &job.groups {
+        let mut parent = parents.get(&group.name);
+        let mut depth = 0;
+        while let Some(name) = parent {
+            depth += 1;
+            if depth > job.groups.len() {
+                return Err(highnoon::Error::bad_request(format!(
+                    "group '{name}' contains itself"
+                )));
+            }
+            parent = parents.get(name);
+        }
+    }
+
+    for task in &job.tasks {
+        let mut group = parents.get(&task.name);
+        while let Some(name) = group {
+            members
+                .get_mut(name)
+                .expect("parent group exists")
+                .insert(task.name.clone());
+            group = parents.get(name);
+        }
+    }
+
+    for group in &job.groups {
+        for task in group.tasks.iter().flatten() {
+            if !job.tasks.iter().any(|t| &t.name == task) {
+                return Err(highnoon::Error::bad_request(format!(
+                    "group '{}' contains unknown task '{task}'",
+                    group.name
+                )));
+            }
+        }
+    }
+
+    Ok(members)
+}
+
+/// check that the reference is to something in this job
+fn is_local(reference: &Reference, job: &Job) -> bool {
+    reference.proj.as_ref().map_or(true, |p| p == &job.project)
+        && reference.job.as_ref().map_or(true, |j| j == &job.name)
+}
+
+/// the task names that a (local) task or group reference depends on
+fn referenced_tasks<'a>(
+    reference: &'a Reference,
+    job: &Job,
+    members: &'a Members,
+) -> highnoon::Result<Vec<&'a String>> {
+    match reference.kind {
+        ReferenceKind::Task if is_local(reference, job) => Ok(vec![&reference.name]),
+        ReferenceKind::Group => {
+            if !is_local(reference, job) {
+                return Err(highnoon::Error::bad_request(format!(
+                    "group references must be within the same job: {reference}"
+                )));
+            }
+            let tasks = members.get(&reference.name).ok_or_else(|| {
+                highnoon::Error::bad_request(format!("unknown group: {reference}"))
+            })?;
+            Ok(tasks.iter().collect())
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn get_task<'a>(job: &'a Job, name: &str) -> &'a Task {
+    job.tasks
+        .iter()
+        .find(|t| t.name == name)
+        .expect("group members are validated")
+}
+
+/// a member's own dependencies, plus those of any groups it's in that are nested inside `group`
+fn member_depends(
+    job: &Job,
+    parents: &HashMap<String, String>,
+    member: &str,
+    group: &str,
+) -> Vec<String> {
+    let mut depends = get_task(job, member).depends.clone().unwrap_or_default();
+
+    let mut parent = parents.get(member);
+    while let Some(name) = parent {
+        if name == group {
+            break;
+        }
+        let nested = job.groups.iter().find(|g| &g.name == name);
+        depends.extend(nested.and_then(|g| g.depends.clone()).unwrap_or_default());
+        parent = parents.get(name);
+    }
+
+    depends
+}
+
+/// members of the group that don't depend on any other member
+fn group_entries(
+    job: &Job,
+    parents: &HashMap<String, String>,
+    members: &Members,
+    group: &str,
+) -> highnoon::Result<BTreeSet<String>> {
+    let mut entries = BTreeSet::new();
+
+    for member in &members[group] {
+        let mut internal = false;
+        for dep in &member_depends(job, parents, member, group) {
+            let reference = parse_reference(dep)?;
+            if referenced_tasks(&reference, job, members)?
+                .into_iter()
+                .any(|t| members[group].contains(t))
+            {
+                internal = true;
+            }
+        }
+
+        if !internal {
+            entries.insert(member.clone());
+        }
+    }
+
+    Ok(entries)
+}
+
+/// members of the group that no other member depends on
+fn group_exits(
+    job: &Job,
+    parents: &HashMap<String, String>,
+    members: &Members,
+    group: &str,
+) -> highnoon::Result<BTreeSet<String>> {
+    let mut exits = members[group].clone();
+
+    for member in &members[group] {
+        for dep in &member_depends(job, parents, member, group) {
+            let reference = parse_reference(dep)?;
+            for task in referenced_tasks(&reference, job, members)? {
+                if task != member {
+                    exits.remove(task);
+                }
+            }
+        }
+    }
+
+    Ok(exits)
+}
+
+/// replace group references with references to the group's exit tasks
+fn expand_refs(
+    job: &Job,
+    exits: &HashMap<String, BTreeSet<String>>,
+    depends: &[String],
+) -> highnoon::Result<Vec<String>> {
+    let mut expanded = Vec::new();
+
+    for dep in depends {
+        let reference = parse_reference(dep)?;
+        if reference.kind != ReferenceKind::Group {
+            expanded.push(dep.clone());
+            continue;
+        }
+
+        if !is_local(&reference, job) {
+            return Err(highnoon::Error::bad_request(format!(
+                "group references must be within the same job: {reference}"
+            )));
+        }
+        let tasks = exits.get(&reference.name).ok_or_else(|| {
+            highnoon::Error::bad_request(format!("unknown group: {reference}"))
+        })?;
+
+        for task in tasks {
+            let task_ref = Reference {
+                proj: None,
+                job: None,
+                kind: ReferenceKind::Task,
+                name: task.clone(),
+                offset: reference.offset,
+            };
+            expanded.push(task_ref.to_string());
+        }
+    }
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn job(value: serde_json::Value) -> Job {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn depends(job: &Job, task: &str) -> Vec<String> {
+        get_task(job, task).depends.clone().unwrap_or_default()
+    }
+
+    #[test]
+    fn test_expand_groups() {
+        let mut job = job(serde_json::json!({
+            "uuid": "00000000-0000-0000-0000-000000000000",
+            "project": "p",
+            "name": "j",
+            "description": "",
+            "triggers": [],
+            "tasks": [
+                { "name": "a" },
+                { "name": "b", "depends": ["task/a"] },
+                { "name": "c", "depends": ["task/a"] },
+                { "name": "d", "depends": ["group/etl"] },
+            ],
+            "groups": [
+                { "name": "etl", "tasks": ["a", "b", "c"], "depends": ["trigger/daily"] },
+            ],
+        }));
+
+        expand_groups(&mut job).unwrap();
+
+        assert_eq!(depends(&job, "a"), vec!["trigger/daily"]);
+        assert_eq!(depends(&job, "b"), vec!["task/a"]);
+        assert_eq!(depends(&job, "d"), vec!["task/b", "task/c"]);
+    }
+
+    #[test]
+    fn test_expand_nested_groups() {
+        let mut job = job(serde_json::json!({
+            "uuid": "00000000-0000-0000-0000-000000000000",
+            "project": "p",
+            "name": "j",
+            "description": "",
+            "triggers": [],
+            "tasks": [
+                { "name": "a" },
+                { "name": "b" },
+                { "name": "c" },
+                { "name": "d", "depends": ["group/outer@1h"] },
+            ],
+            "groups": [
+                { "name": "outer", "tasks": ["a"], "groups": ["inner"], "depends": ["trigger/daily"] },
+                { "name": "inner", "tasks": ["b", "c"], "depends": ["task/a"] },
+            ],
+        }));
+
+        expand_groups(&mut job).unwrap();
+
+        assert_eq!(depends(&job, "a"), vec!["trigger/daily"]);
+        assert_eq!(depends(&job, "b"), vec!["task/a"]);
+        assert_eq!(depends(&job, "d"), vec!["task/b@1h", "task/c@1h"]);
+    }
+
+    #[test]
+    fn test_group_errors() {
+        let mut cycle = job(serde_json::json!({
+            "uuid": "00000000-0000-0000-0000-000000000000",
+            "project": "p",
+            "name": "j",
+            "description": "",
+            "triggers": [],
+            "tasks": [{ "name": "a" }],
+            "groups": [
+                { "name": "x", "tasks": ["a"], "groups": ["y"] },
+                { "name": "y", "groups": ["x"] },
+            ],
+        }));
+        assert!(expand_groups(&mut cycle).is_err());
+
+        let mut unknown = job(serde_json::json!({
+            "uuid": "00000000-0000-0000-0000-000000000000",
+            "project": "p",
+            "name": "j",
+            "description": "",
+            "triggers": [],
+            "tasks": [{ "name": "a", "depends": ["group/nope"] }],
+            "groups": [{ "name": "x", "tasks": ["a"] }],
+        }));
+        assert!(expand_groups(&mut unknown).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{
+    messages::TriggerUpdate,
+    server::api::{
+        audit, auth,
+        request_ext::RequestExt,
+        types::{duration_from_string, Catchup},
+        updates, State,
+    },
+};
+use chrono::{DateTime, Duration, Utc};
+use highnoon::{Json, Request, Responder, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// A time-boxed change an operator makes to a trigger, eg. during an incident.
+/// The scheduler ignores it once `expires` has passed, so the trigger always
+/// reverts to the schedule in the job definition.
+#[derive(Deserialize)]
+struct SetOverride {
+    #[serde(default)]
+    paused: bool,
+    /// duration to shift the trigger's offset by, may be negative
+    shift: Option<String>,
+    catchup: Option<Catchup>,
+    reason: String,
+    /// who to ask about the override, eg. the on-call engineer
+    owner: Option<String>,
+    expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct TriggerOverride {
+    paused: bool,
+    shift: Option<i64>, // seconds
+    catchup: Option<Catchup>,
+    reason: String,
+    owner: Option<String>,
+    created_datetime: DateTime<Utc>,
+    expires_datetime: DateTime<Utc>,
+}
+
+pub(super) async fn get_trigger_job(pool: &PgPool, trigger_id: Uuid) -> highnoon::Result<(Uuid, Uuid)> {
+    let row: Option<(Uuid, Uuid)> = sqlx::query_as(
+        "SELECT
+            j.id,
+            j.project_id
+        FROM trigger g
+        JOIN job j ON j.id = g.job_id
+        WHERE g.id = $1",
+    )
+    .bind(trigger_id)
+    .fetch_optional(pool)
+    .await?;
+
+    row.ok_or_else(|| highnoon::Error::http((StatusCode::NOT_FOUND, "trigger not found")))
+}
+
+/// an override as it's shown in the audit log, without when it was set
+async fn override_for_audit(
+    txn: &mut Transaction<'_, Postgres>,
+    trigger_id: Uuid,
+) -> highnoon::Result<JsonValue> {
+    let row: Option<TriggerOverride> = sqlx::query_as(
+        "SELECT
+            paused,
+            shift,
+            catchup,
+            reason,
+            owner,
+            created_datetime,
+            expires_datetime
+        FROM trigger_override
+        WHERE trigger_id = $1
+        FOR UPDATE",
+    )
+    .bind(trigger_id)
+    .fetch_optional(&mut *txn)
+    .await?;
+
+    let mut value = serde_json::to_value(row)?;
+    if let Some(obj) = value.as_object_mut() {
+        obj.remove("created_datetime");
+    }
+    Ok(value)
+}
+
+pub async fn set_trigger_override(mut req: Request<State>) -> highnoon::Result<impl Responder> {
+    let trigger_id = req.param("id")?.parse::<Uuid>()?;
+    let body: SetOverride = req.body_json().await?;
+
+    let pool = req.get_pool();
+    let (job_id, project_id) = get_trigger_job(&pool, trigger_id).await?;
+
+    auth::update()
+        .job(job_id, project_id)
+        .kind("trigger")
+        .check(&req)
+        .await?;
+
+    let now = Utc::now();
+    if body.expires <= now {
+        return Err(highnoon::Error::bad_request("override expiry must be in the future"));
+    }
+
+    let max_secs = req.state().config.max_trigger_override;
+    if body.expires - now > Duration::seconds(max_secs as i64) {
+        return Err(highnoon::Error::bad_request(format!(
+            "override must expire within {}",
+            humantime::format_duration(std::time::Duration::from_secs(max_secs))
+        )));
+    }
+
+    let shift = duration_from_string(body.shift.as_deref())
+        .map_err(|err| highnoon::Error::bad_request(format!("shift is not valid: {err}")))?;
+
+    let mut txn = pool.begin().await?;
+    let before = override_for_audit(&mut txn, trigger_id).await?;
+
+    sqlx::query(
+        "INSERT INTO trigger_override(trigger_id, paused, shift, catchup,
+            reason, owner, created_datetime, expires_datetime)
+        VALUES ($1, $2, $3, $4,
+            $5, $6, CURRENT_TIMESTAMP, $7)
+        ON CONFLICT(trigger_id)
+        DO UPDATE
+        SET paused = $2,
+            shift = $3,
+            catchup = $4,
+            reason = $5,
+            owner = $6,
+            created_datetime = CURRENT_TIMESTAMP,
+            expires_datetime = $7",
+    )
+    .bind(trigger_id)
+    .bind(body.paused)
+    .bind(shift.map(i64::from))
+    .bind(body.catchup)
+    .bind(&body.reason)
+    .bind(&body.owner)
+    .bind(body.expires)
+    .execute(&mut txn)
+    .await?;
+
+    let after = override_for_audit(&mut txn, trigger_id).await?;
+    audit::update("trigger_override")
+        .job(job_id, project_id)
+        .object(trigger_id)
+        .diff(&before, &after)
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    // notify the scheduler to requeue the trigger with the override applied
+    updates::send_trigger_update(req.get_broker(), TriggerUpdate(vec![trigger_id])).await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn clear_trigger_override(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let trigger_id = req.param("id")?.parse::<Uuid>()?;
+
+    let pool = req.get_pool();
+    let (job_id, project_id) = get_trigger_job(&pool, trigger_id).await?;
+
+    auth::update()
+        .job(job_id, project_id)
+        .kind("trigger")
+        .check(&req)
+        .await?;
+
+    let mut txn = pool.begin().await?;
+    let before = override_for_audit(&mut txn, trigger_id).await?;
+
+    let res = sqlx::query(
+        "DELETE FROM trigger_override
+        WHERE trigger_id = $1",
+    )
+    .bind(trigger_id)
+    .execute(&mut txn)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+
+    audit::delete("trigger_override")
+        .job(job_id, project_id)
+        .object(trigger_id)
+        .diff(&before, &JsonValue::Null)
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    updates::send_trigger_update(req.get_broker(), TriggerUpdate(vec![trigger_id])).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct TriggerBase {
+    trigger_name: String,
+    job_paused: bool,
+    trigger_offset: Option<i64>, // seconds
+    catchup: Catchup,
+}
+
+#[derive(Serialize)]
+struct EffectiveState {
+    paused: bool,
+    trigger_offset: i64, // seconds
+    catchup: Catchup,
+}
+
+#[derive(Serialize)]
+struct GetEffectiveTrigger {
+    trigger_id: Uuid,
+    base: TriggerBase,
+    #[serde(rename = "override")]
+    trigger_override: Option<TriggerOverride>,
+    effective: EffectiveState,
+}
+
+/// the trigger as the scheduler sees it, with any active override applied
+pub async fn get_effective_trigger(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let trigger_id = req.param("id")?.parse::<Uuid>()?;
+
+    let pool = req.get_pool();
+    let (job_id, project_id) = get_trigger_job(&pool, trigger_id).await?;
+
+    auth::get()
+        .job(job_id, project_id)
+        .kind("trigger")
+        .check(&req)
+        .await?;
+
+    let base: TriggerBase = sqlx::query_as(
+        "SELECT
+            g.name AS trigger_name,
+            j.paused AS job_paused,
+            g.trigger_offset,
+            g.catchup
+        FROM trigger g
+        JOIN job j ON j.id = g.job_id
+        WHERE g.id = $1",
+    )
+    .bind(trigger_id)
+    .fetch_one(&pool)
+    .await?;
+
+    // an expired override may not have been swept up yet, but it no longer applies
+    let trigger_override: Option<TriggerOverride> = sqlx::query_as(
+        "SELECT
+            paused,
+            shift,
+            catchup,
+            reason,
+            owner,
+            created_datetime,
+            expires_datetime
+        FROM trigger_override
+        WHERE trigger_id = $1
+        AND expires_datetime > CURRENT_TIMESTAMP",
+    )
+    .bind(trigger_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let effective = EffectiveState {
+        paused: base.job_paused || trigger_override.as_ref().map_or(false, |o| o.paused),
+        trigger_offset: base.trigger_offset.unwrap_or(0)
+            + trigger_override.as_ref().and_then(|o| o.shift).unwrap_or(0),
+        catchup: trigger_override
+            .as_ref()
+            .and_then(|o| o.catchup)
+            .unwrap_or(base.catchup),
+    };
+
+    Ok(Json(GetEffectiveTrigger {
+        trigger_id,
+        base,
+        trigger_override,
+        effective,
+    }))
+}
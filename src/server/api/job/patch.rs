@@ -0,0 +1,184 @@
+use super::{apply_job, definition, drift, secrets::check_external_secrets, send_job_updates};
+use crate::server::api::{auth, request_ext::RequestExt, types::Job, State};
+use highnoon::{
+    headers::{ContentType, ETag, IfMatch},
+    Request, Response, StatusCode,
+};
+use serde_json::Value as JsonValue;
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+const JSON_PATCH_MIME: &str = "application/json-patch+json";
+
+/// a job's ETag is the hash of its definition as it was submitted
+pub fn definition_etag(definition_hash: &str) -> ETag {
+    format!("\"{definition_hash}\"")
+        .parse()
+        .expect("a hex hash is a valid ETag")
+}
+
+/// Load a job's definition and lock it until the transaction ends, so it can't
+/// change between checking `If-Match` and writing the update.
+pub async fn load_locked(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+) -> highnoon::Result<Option<String>> {
+    sqlx::query("SELECT 1 FROM job WHERE id = $1 FOR UPDATE")
+        .bind(job_id)
+        .execute(&mut *txn)
+        .await?;
+
+    Ok(definition::load(&mut *txn, job_id).await?)
+}
+
+/// Whether an update sent with `If-Match` was made to the current definition.
+/// If it wasn't, someone else has changed the job since it was read.
+pub fn check_if_match(
+    if_match: Option<&IfMatch>,
+    current_definition: Option<&str>,
+) -> highnoon::Result<()> {
+    let if_match = match if_match {
+        Some(if_match) => if_match,
+        None => return Ok(()),
+    };
+
+    let passes = current_definition
+        .map(|current| definition_etag(&drift::definition_hash(current)))
+        .map_or(false, |etag| if_match.precondition_passes(&etag));
+
+    if passes {
+        Ok(())
+    } else {
+        Err(highnoon::Error::http((
+            StatusCode::PRECONDITION_FAILED,
+            "the job has changed since it was read, get it again and reapply your changes",
+        )))
+    }
+}
+
+/// Apply a JSON Patch (RFC 6902) if the content type says it is one, otherwise
+/// a JSON Merge Patch (RFC 7396).
+fn apply_patch(
+    definition: &mut JsonValue,
+    content_type: Option<&mime::Mime>,
+    body: &[u8],
+) -> highnoon::Result<()> {
+    let patch: JsonValue = serde_json::from_slice(body).map_err(|err| {
+        highnoon::Error::bad_request(format!("error parsing patch as json: {err}"))
+    })?;
+
+    if content_type.map(|mime| mime.essence_str()) == Some(JSON_PATCH_MIME) {
+        let patch: json_patch::Patch = serde_json::from_value(patch)
+            .map_err(|err| highnoon::Error::bad_request(format!("invalid json patch: {err}")))?;
+        json_patch::patch(definition, &patch).map_err(|err| {
+            highnoon::Error::bad_request(format!("error applying json patch: {err}"))
+        })?;
+    } else {
+        json_patch::merge(definition, &patch);
+    }
+
+    Ok(())
+}
+
+/// Change part of a job, eg. one trigger's end date, without sending the whole
+/// definition. The patch is applied to the definition as it was submitted, then
+/// the result is applied like any other job.
+pub async fn patch_job(mut req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+    let content_type = req.header::<ContentType>().map(mime::Mime::from);
+    let if_match = req.header::<IfMatch>();
+    let body = req.body_bytes().await?;
+
+    auth::update().job(job_id, None).check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let current = match definition::load(&pool, job_id).await? {
+        Some(current) => current,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    check_if_match(if_match.as_ref(), Some(&current))?;
+
+    let mut definition: JsonValue = serde_json::from_str(&current)?;
+    apply_patch(&mut definition, content_type.as_ref(), &body)?;
+
+    let job: Job = serde_json::from_value(definition).map_err(|err| {
+        highnoon::Error::bad_request(format!("the patched job is invalid: {err}"))
+    })?;
+    if job.uuid != job_id {
+        return Err(highnoon::Error::bad_request(
+            "a patch can't change a job's uuid",
+        ));
+    }
+
+    // secrets are read over the network, so they're checked before the job is
+    // locked, and the patch is rejected if the job changed in the meantime
+    check_external_secrets(&req.state().config, &job).await?;
+
+    let mut txn = pool.begin().await?;
+
+    if load_locked(&mut txn, job_id).await?.as_deref() != Some(current.as_str()) {
+        return Err(highnoon::Error::http((
+            StatusCode::CONFLICT,
+            "the job changed while it was being patched, try again",
+        )));
+    }
+
+    let applied = match apply_job(&req, &mut txn, job, None, None).await? {
+        Some(applied) => applied,
+        None => return Ok(Response::status(StatusCode::CONFLICT)),
+    };
+
+    txn.commit().await?;
+
+    let etag = definition_etag(&applied.drift.definition_hash);
+    send_job_updates(&req, applied).await?;
+
+    Ok(Response::status(StatusCode::NO_CONTENT).header(etag))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_apply_merge_patch() {
+        let mut definition = json!({"name": "a", "paused": false, "triggers": []});
+        apply_patch(&mut definition, None, br#"{"paused": true}"#).unwrap();
+        assert_eq!(
+            definition,
+            json!({"name": "a", "paused": true, "triggers": []})
+        );
+    }
+
+    #[test]
+    fn test_apply_json_patch() {
+        let mut definition = json!({"triggers": [{"name": "daily", "end": null}]});
+        let mime: mime::Mime = JSON_PATCH_MIME.parse().unwrap();
+        let patch =
+            br#"[{"op": "replace", "path": "/triggers/0/end", "value": "2023-01-01T00:00:00Z"}]"#;
+        apply_patch(&mut definition, Some(&mime), patch).unwrap();
+        assert_eq!(
+            definition,
+            json!({"triggers": [{"name": "daily", "end": "2023-01-01T00:00:00Z"}]})
+        );
+
+        let bad = br#"[{"op": "remove", "path": "/nope"}]"#;
+        assert!(apply_patch(&mut definition, Some(&mime), bad).is_err());
+    }
+
+    #[test]
+    fn test_check_if_match() {
+        let current = r#"{"name":"a"}"#;
+        let etag = definition_etag(&drift::definition_hash(current));
+        let if_match = IfMatch::from(etag);
+
+        assert!(check_if_match(None, Some(current)).is_ok());
+        assert!(check_if_match(Some(&if_match), Some(current)).is_ok());
+        assert!(check_if_match(Some(&if_match), Some(r#"{"name":"b"}"#)).is_err());
+        assert!(check_if_match(Some(&IfMatch::any()), Some(current)).is_ok());
+        assert!(check_if_match(Some(&IfMatch::any()), None).is_err());
+    }
+}
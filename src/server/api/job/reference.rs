@@ -11,6 +11,7 @@ use std::{
 pub enum ReferenceKind {
     Trigger,
     Task,
+    Group,
 }
 
 impl FromStr for ReferenceKind {
@@ -20,11 +21,12 @@ impl FromStr for ReferenceKind {
         match s {
             "trigger" => Ok(ReferenceKind::Trigger),
             "task" => Ok(ReferenceKind::Task),
+            "group" => Ok(ReferenceKind::Group),
             _ => Err(highnoon::Error::http((
                 highnoon::StatusCode::BAD_REQUEST,
                 format!(
-                    "failed to parse reference kind (expected \"task\" \
-                         or \"trigger\", got \"{s}\")"
+                    "failed to parse reference kind (expected \"task\", \
+                         \"trigger\" or \"group\", got \"{s}\")"
                 ),
             ))),
         }
@@ -36,6 +38,7 @@ impl Display for ReferenceKind {
         match self {
             ReferenceKind::Trigger => write!(f, "trigger"),
             ReferenceKind::Task => write!(f, "task"),
+            ReferenceKind::Group => write!(f, "group"),
         }
     }
 }
@@ -74,7 +77,7 @@ static REFERENCE_PATTERN: Lazy<Regex> = Lazy::new(|| {
         ^\
         ([\\w\\s]+/)?\
         ([\\w\\s]+/)?\
-        (trigger|task)/\
+        (trigger|task|group)/\
         ([\\w\\s]+)\
         (@.+)?\
         $",
@@ -196,6 +199,18 @@ mod test {
             }
         );
 
+        let r = parse_reference("group/c").unwrap();
+        assert_eq!(
+            r,
+            Reference {
+                proj: None,
+                job: None,
+                kind: ReferenceKind::Group,
+                name: "c".to_string(),
+                offset: None
+            }
+        );
+
         let r = parse_reference("a/b/task/c").unwrap();
         assert_eq!(
             r,
@@ -0,0 +1,398 @@
+use crate::{
+    messages::{JobRunState, ProcessToken, TaskPriority, Token, TokenState},
+    server::{
+        api::{
+            audit, auth,
+            paging::{list_response, Paging},
+            request_ext::RequestExt,
+            updates, State,
+        },
+        job_run::update_job_run,
+        settings,
+        token_history::{self, Actor, TokenEvent},
+    },
+};
+use chrono::{DateTime, Utc};
+use highnoon::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+struct ListJobRunsQuery {
+    state: Option<JobRunState>,
+    before: Option<DateTime<Utc>>,
+    /// only runs triggered at or after this time
+    since: Option<DateTime<Utc>>,
+    /// only runs triggered before this time
+    until: Option<DateTime<Utc>>,
+}
+
+const LIST_JOB_RUNS_SORT: &[(&str, &str)] = &[
+    ("trigger_datetime", "trigger_datetime"),
+    ("started", "started_datetime"),
+    ("updated", "updated_datetime"),
+    ("finished", "finish_datetime"),
+];
+
+#[derive(Serialize, sqlx::FromRow)]
+struct JobRun {
+    trigger_datetime: DateTime<Utc>,
+    state: JobRunState,
+    running_tasks: i32,
+    success_tasks: i32,
+    failed_tasks: i32,
+    started_datetime: DateTime<Utc>,
+    updated_datetime: DateTime<Utc>,
+    finish_datetime: Option<DateTime<Utc>>,
+    /// seconds from the first task starting to the last finishing, None while running
+    duration_secs: Option<f64>,
+    /// the version of the job's definition the run used, see versions.rs
+    definition_version: Option<i32>,
+}
+
+/// Most recent runs of a job first, paged with `before` or `offset`, and filtered
+/// by state and a range of trigger times, eg. `?state=failed&since=...&until=...`.
+pub async fn list_job_runs(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+    let query: ListJobRunsQuery = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(LIST_JOB_RUNS_SORT, "-trigger_datetime")?;
+
+    if let (Some(since), Some(until)) = (query.since, query.until) {
+        if since > until {
+            return Err(highnoon::Error::bad_request(
+                "since must not be after until",
+            ));
+        }
+    }
+
+    auth::list().job(job_id, None).check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let filter = "FROM job_run
+        WHERE job_id = $1
+        AND ($2::VARCHAR IS NULL OR state = $2)
+        AND ($3::TIMESTAMPTZ IS NULL OR trigger_datetime < $3)
+        AND ($4::TIMESTAMPTZ IS NULL OR trigger_datetime >= $4)
+        AND ($5::TIMESTAMPTZ IS NULL OR trigger_datetime < $5)";
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(job_id)
+        .bind(query.state)
+        .bind(query.before)
+        .bind(query.since)
+        .bind(query.until)
+        .fetch_one(&pool)
+        .await?;
+
+    let runs: Vec<JobRun> = sqlx::query_as(&format!(
+        "SELECT
+            trigger_datetime,
+            state,
+            running_tasks,
+            success_tasks,
+            failed_tasks,
+            started_datetime,
+            updated_datetime,
+            finish_datetime,
+            EXTRACT(EPOCH FROM finish_datetime - started_datetime)::DOUBLE PRECISION
+                AS duration_secs,
+            definition_version
+        {filter}
+        ORDER BY {order_by}, trigger_datetime DESC
+        LIMIT $6
+        OFFSET $7"
+    ))
+    .bind(job_id)
+    .bind(query.state)
+    .bind(query.before)
+    .bind(query.since)
+    .bind(query.until)
+    .bind(paging.limit(100))
+    .bind(paging.offset())
+    .fetch_all(&pool)
+    .await?;
+
+    list_response(runs, total)
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct JobRunTask {
+    task_id: Uuid,
+    name: String,
+    /// None if the task has no token for this trigger time
+    state: Option<TokenState>,
+}
+
+/// a trigger that has fired into a job run
+#[derive(Serialize, sqlx::FromRow)]
+struct JobRunTrigger {
+    trigger_id: Uuid,
+    name: String,
+    fired_datetime: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct GetJobRun {
+    #[serde(flatten)]
+    run: JobRun,
+    triggers: Vec<JobRunTrigger>,
+    tasks: Vec<JobRunTask>,
+}
+
+/// a single run of a job with the state of each of its tasks, and the triggers that fed it
+pub async fn get_job_run(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
+
+    auth::get().job(job_id, None).check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let maybe_run: Option<JobRun> = sqlx::query_as(
+        "SELECT
+            trigger_datetime,
+            state,
+            running_tasks,
+            success_tasks,
+            failed_tasks,
+            started_datetime,
+            updated_datetime,
+            finish_datetime,
+            EXTRACT(EPOCH FROM finish_datetime - started_datetime)::DOUBLE PRECISION
+                AS duration_secs,
+            definition_version
+        FROM job_run
+        WHERE job_id = $1
+        AND trigger_datetime = $2",
+    )
+    .bind(job_id)
+    .bind(trigger_datetime)
+    .fetch_optional(&pool)
+    .await?;
+
+    let run = match maybe_run {
+        Some(run) => run,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    let tasks: Vec<JobRunTask> = sqlx::query_as(
+        "SELECT
+            t.id AS task_id,
+            t.name,
+            k.state
+        FROM task t
+        LEFT JOIN token k ON k.task_id = t.id AND k.trigger_datetime = $2
+        WHERE t.job_id = $1
+        ORDER BY t.name",
+    )
+    .bind(job_id)
+    .bind(trigger_datetime)
+    .fetch_all(&pool)
+    .await?;
+
+    let triggers: Vec<JobRunTrigger> = sqlx::query_as(
+        "SELECT
+            g.id AS trigger_id,
+            g.name,
+            jt.fired_datetime
+        FROM job_run_trigger jt
+        JOIN trigger g ON g.id = jt.trigger_id
+        WHERE jt.job_id = $1
+        AND jt.trigger_datetime = $2
+        ORDER BY jt.fired_datetime",
+    )
+    .bind(job_id)
+    .bind(trigger_datetime)
+    .fetch_all(&pool)
+    .await?;
+
+    Response::ok().json(GetJobRun {
+        run,
+        triggers,
+        tasks,
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct RerunJobRunParams {
+    priority: Option<TaskPriority>,
+    /// only rerun the tasks that failed, and whatever is downstream of them
+    #[serde(default)]
+    only_failed: bool,
+}
+
+#[derive(Serialize)]
+struct RerunJobRunReply {
+    activated: u64,
+    downstream_cleared: u64,
+}
+
+/// Rerun a whole job run, whichever triggers its tasks came from. The entry tasks
+/// (those fed only by triggers) are activated and every other token is cleared, so
+/// the rest of the run flows down again as if all the triggers had just fired.
+/// With `only_failed` the failed tasks are activated instead.
+pub async fn rerun_job_run(mut req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
+    let params: RerunJobRunParams = req.body_json().await?;
+
+    auth::update()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
+    let pool = req.get_pool();
+    let mut txn = pool.begin().await?;
+
+    let start_task_ids: Vec<(Uuid,)> = if params.only_failed {
+        sqlx::query_as(
+            "SELECT k.task_id
+            FROM token k
+            JOIN task t ON t.id = k.task_id
+            WHERE t.job_id = $1
+            AND k.trigger_datetime = $2
+            AND k.state IN ('failure', 'error', 'timeout', 'expired', 'infra_failure')",
+        )
+        .bind(job_id)
+        .bind(trigger_datetime)
+        .fetch_all(&mut txn)
+        .await?
+    } else {
+        sqlx::query_as(
+            "SELECT k.task_id
+            FROM token k
+            JOIN task t ON t.id = k.task_id
+            WHERE t.job_id = $1
+            AND k.trigger_datetime = $2
+            AND EXISTS (SELECT 1 FROM trigger_edge te WHERE te.task_id = t.id)
+            AND NOT EXISTS (SELECT 1 FROM task_edge e WHERE e.child_task_id = t.id)",
+        )
+        .bind(job_id)
+        .bind(trigger_datetime)
+        .fetch_all(&mut txn)
+        .await?
+    };
+
+    if start_task_ids.is_empty() {
+        return Err(highnoon::Error::http((
+            StatusCode::NOT_FOUND,
+            "no tasks in this run to rerun",
+        )));
+    }
+
+    let start_task_ids: Vec<Uuid> = start_task_ids.into_iter().map(|(id,)| id).collect();
+
+    // follow the edges (and their offsets) through the existing tokens only,
+    // the same as rerunning a single task
+    let downstream: Vec<(Uuid, DateTime<Utc>)> = sqlx::query_as(
+        "WITH RECURSIVE downstream(task_id, trigger_datetime) AS (
+            SELECT task_id, $2::TIMESTAMP WITH TIME ZONE
+            FROM UNNEST($1::UUID[]) AS s(task_id)
+            UNION
+            SELECT
+                k.task_id,
+                k.trigger_datetime
+            FROM downstream d
+            JOIN task_edge e ON e.parent_task_id = d.task_id
+            JOIN token k
+                ON k.task_id = e.child_task_id
+                AND k.trigger_datetime =
+                    d.trigger_datetime + (INTERVAL '1s' * COALESCE(e.edge_offset, 0))
+        )
+        UPDATE token k
+        SET count = 0,
+            state = 'waiting'
+        FROM downstream d
+        WHERE k.task_id = d.task_id
+        AND k.trigger_datetime = d.trigger_datetime
+        AND NOT (k.task_id = ANY($1) AND k.trigger_datetime = $2)
+        RETURNING k.task_id, k.trigger_datetime",
+    )
+    .bind(&start_task_ids)
+    .bind(trigger_datetime)
+    .fetch_all(&mut txn)
+    .await?;
+
+    sqlx::query(
+        "UPDATE token k
+        SET count = t.threshold,
+            state = 'waiting'
+        FROM task t
+        WHERE t.id = k.task_id
+        AND k.task_id = ANY($1)
+        AND k.trigger_datetime = $2",
+    )
+    .bind(&start_task_ids)
+    .bind(trigger_datetime)
+    .execute(&mut txn)
+    .await?;
+
+    let start_tokens: Vec<Token> = start_task_ids
+        .iter()
+        .map(|&task_id| Token {
+            task_id,
+            trigger_datetime,
+        })
+        .collect();
+    let downstream_tokens: Vec<Token> = downstream
+        .iter()
+        .map(|&(task_id, trigger_datetime)| Token {
+            task_id,
+            trigger_datetime,
+        })
+        .collect();
+
+    let details = format!("rerun of job run {}", trigger_datetime.to_rfc3339());
+    token_history::record(
+        &mut txn,
+        &downstream_tokens,
+        TokenEvent::Clear,
+        Actor::Operator,
+        None,
+        Some(&details),
+    )
+    .await?;
+    token_history::record(
+        &mut txn,
+        &start_tokens,
+        TokenEvent::Activate,
+        Actor::Operator,
+        None,
+        Some(&details),
+    )
+    .await?;
+
+    update_job_run(&mut txn, start_task_ids[0], trigger_datetime).await?;
+
+    audit::action("rerun", "job_run")
+        .job(job_id, None)
+        .object(trigger_datetime.to_rfc3339())
+        .details(&params)
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    for token in &downstream_tokens {
+        updates::send_token_update(req.get_broker(), ProcessToken::Clear(token.clone())).await?;
+    }
+
+    let priority = params
+        .priority
+        .unwrap_or_else(|| settings::current(&req.state().config).activate_priority);
+    for token in &start_tokens {
+        updates::send_token_update(
+            req.get_broker(),
+            ProcessToken::Activate(token.clone(), priority),
+        )
+        .await?;
+    }
+
+    Response::ok().json(RerunJobRunReply {
+        activated: start_tokens.len() as u64,
+        downstream_cleared: downstream_tokens.len() as u64,
+    })
+}
@@ -0,0 +1,261 @@
+use crate::server::{
+    api::{auth, request_ext::RequestExt, State},
+    triggers::Period,
+};
+use chrono::{DateTime, Duration, Utc};
+use highnoon::{headers::ContentType, Request, Response, StatusCode};
+use serde::Deserialize;
+use uuid::Uuid;
+
+/// how many runs to export per trigger if the query doesn't say
+const DEFAULT_COUNT: usize = 50;
+const MAX_COUNT: usize = 1000;
+
+#[derive(Deserialize)]
+struct ScheduleQuery {
+    count: Option<usize>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ScheduleTrigger {
+    id: Uuid,
+    name: String,
+    start_datetime: DateTime<Utc>,
+    end_datetime: Option<DateTime<Utc>>,
+    latest_trigger_datetime: Option<DateTime<Utc>>,
+    period: Option<i64>,
+    cron: Option<String>,
+    trigger_offset: Option<i64>,
+}
+
+/// a single upcoming run of a trigger
+#[derive(Debug, PartialEq)]
+struct Run {
+    trigger_id: Uuid,
+    trigger_name: String,
+    trigger_datetime: DateTime<Utc>,
+    /// when the trigger actually fires (the trigger time plus its offset)
+    scheduled_datetime: DateTime<Utc>,
+}
+
+impl ScheduleTrigger {
+    /// the trigger times that fire from `now` on (skipping any missed ones)
+    fn upcoming(&self, now: DateTime<Utc>, count: usize) -> anyhow::Result<Vec<Run>> {
+        let offset = Duration::seconds(self.trigger_offset.unwrap_or(0));
+        let period = Period::new(self.period, self.cron.as_deref())?;
+
+        let first = match self.latest_trigger_datetime {
+            Some(latest) => period.next_after(latest),
+            None => Some(self.start_datetime),
+        };
+
+        let runs = first
+            .into_iter()
+            .flat_map(|first| period.times_from(first).skip_until(now - offset))
+            .take_while(|datetime| self.end_datetime.map_or(true, |end| *datetime < end))
+            .take(count)
+            .map(|datetime| Run {
+                trigger_id: self.id,
+                trigger_name: self.name.clone(),
+                trigger_datetime: datetime,
+                scheduled_datetime: datetime + offset,
+            })
+            .collect();
+
+        Ok(runs)
+    }
+}
+
+/// escape text values as described in RFC 5545 section 3.3.11
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+fn format_datetime(datetime: DateTime<Utc>) -> String {
+    datetime.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn to_ical(project_name: &str, job_name: &str, runs: &[Run], now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        "PRODID:-//Waterwheel//Schedule//EN".to_owned(),
+        format!("X-WR-CALNAME:{}", escape_text(&format!("{project_name}/{job_name}"))),
+    ];
+
+    for run in runs {
+        lines.push("BEGIN:VEVENT".to_owned());
+        lines.push(format!(
+            "UID:{}-{}@waterwheel",
+            run.trigger_id,
+            run.trigger_datetime.timestamp()
+        ));
+        lines.push(format!("DTSTAMP:{}", format_datetime(now)));
+        lines.push(format!("DTSTART:{}", format_datetime(run.scheduled_datetime)));
+        lines.push(format!(
+            "SUMMARY:{}",
+            escape_text(&format!("{job_name} ({})", run.trigger_name))
+        ));
+        lines.push(format!(
+            "DESCRIPTION:{}",
+            escape_text(&format!(
+                "Trigger time {}",
+                run.trigger_datetime.to_rfc3339()
+            ))
+        ));
+        lines.push("END:VEVENT".to_owned());
+    }
+
+    lines.push("END:VCALENDAR".to_owned());
+
+    // iCalendar lines are CRLF terminated
+    let mut ical = lines.join("\r\n");
+    ical.push_str("\r\n");
+    ical
+}
+
+pub async fn get_schedule_ics(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+    let query: ScheduleQuery = req.query()?;
+    let count = query.count.unwrap_or(DEFAULT_COUNT).min(MAX_COUNT);
+
+    auth::get().job(job_id, None).check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let maybe_job: Option<(String, String, bool)> = sqlx::query_as(
+        "SELECT
+            p.name AS project_name,
+            j.name AS job_name,
+            j.paused
+        FROM job j
+        JOIN project p ON p.id = j.project_id
+        WHERE j.id = $1",
+    )
+    .bind(job_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let (project_name, job_name, paused) = match maybe_job {
+        Some(job) => job,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    let triggers: Vec<ScheduleTrigger> = sqlx::query_as(
+        "SELECT
+            id,
+            name,
+            start_datetime,
+            end_datetime,
+            latest_trigger_datetime,
+            period,
+            cron,
+            trigger_offset
+        FROM trigger
+        WHERE job_id = $1",
+    )
+    .bind(job_id)
+    .fetch_all(&pool)
+    .await?;
+
+    let now = Utc::now();
+
+    // a paused job has nothing scheduled
+    let mut runs = Vec::new();
+    if !paused {
+        for trigger in &triggers {
+            runs.extend(trigger.upcoming(now, count)?);
+        }
+    }
+    runs.sort_by_key(|run| run.scheduled_datetime);
+
+    let ical = to_ical(&project_name, &job_name, &runs, now);
+    let mime = "text/calendar; charset=utf-8"
+        .parse::<mime::Mime>()
+        .expect("valid mime type");
+
+    Ok(Response::ok().header(ContentType::from(mime)).body(ical))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    fn trigger(period: Option<i64>, cron: Option<&str>) -> ScheduleTrigger {
+        ScheduleTrigger {
+            id: Uuid::nil(),
+            name: "daily".to_owned(),
+            start_datetime: Utc.ymd(2022, 1, 1).and_hms(0, 0, 0),
+            end_datetime: None,
+            latest_trigger_datetime: None,
+            period,
+            cron: cron.map(ToOwned::to_owned),
+            trigger_offset: Some(3600),
+        }
+    }
+
+    #[test]
+    fn test_upcoming_period() {
+        let now = Utc.ymd(2022, 3, 10).and_hms(12, 0, 0);
+        let runs = trigger(Some(86400), None).upcoming(now, 2).unwrap();
+
+        let times: Vec<_> = runs.iter().map(|r| r.scheduled_datetime).collect();
+        assert_eq!(
+            times,
+            vec![
+                Utc.ymd(2022, 3, 11).and_hms(1, 0, 0),
+                Utc.ymd(2022, 3, 12).and_hms(1, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_upcoming_cron() {
+        let now = Utc.ymd(2022, 3, 10).and_hms(12, 0, 0);
+        let runs = trigger(None, Some("0 0 0 1 * * *")).upcoming(now, 2).unwrap();
+
+        let times: Vec<_> = runs.iter().map(|r| r.trigger_datetime).collect();
+        assert_eq!(
+            times,
+            vec![
+                Utc.ymd(2022, 4, 1).and_hms(0, 0, 0),
+                Utc.ymd(2022, 5, 1).and_hms(0, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_upcoming_until_end() {
+        let now = Utc.ymd(2022, 3, 10).and_hms(12, 0, 0);
+        let mut trigger = trigger(Some(86400), None);
+        trigger.latest_trigger_datetime = Some(Utc.ymd(2022, 3, 10).and_hms(0, 0, 0));
+        trigger.end_datetime = Some(Utc.ymd(2022, 3, 13).and_hms(0, 0, 0));
+        let runs = trigger.upcoming(now, 10).unwrap();
+
+        let times: Vec<_> = runs.iter().map(|r| r.trigger_datetime).collect();
+        assert_eq!(
+            times,
+            vec![
+                Utc.ymd(2022, 3, 11).and_hms(0, 0, 0),
+                Utc.ymd(2022, 3, 12).and_hms(0, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_ical() {
+        let now = Utc.ymd(2022, 3, 10).and_hms(12, 0, 0);
+        let runs = trigger(Some(86400), None).upcoming(now, 1).unwrap();
+        let ical = to_ical("proj", "report, daily", &runs, now);
+
+        assert!(ical.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ical.contains("DTSTART:20220311T010000Z\r\n"));
+        assert!(ical.contains("SUMMARY:report\\, daily (daily)\r\n"));
+        assert!(ical.ends_with("END:VCALENDAR\r\n"));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{
+    config::Config,
+    messages::{SecretEnv, SecretRef, SensorCheck},
+    secrets,
+    server::api::types::{EnvEntry, Job, Task},
+};
+use sqlx::{Postgres, Transaction};
+
+/// split a task's env into plain `KEY=VALUE` strings and secret references
+pub fn split_env(env: &[EnvEntry]) -> (Vec<String>, Vec<SecretEnv>) {
+    let mut values = Vec::new();
+    let mut secret_env = Vec::new();
+
+    for entry in env {
+        match entry {
+            EnvEntry::Value(kv) => values.push(kv.clone()),
+            EnvEntry::Secret(secret) => secret_env.push(secret.clone()),
+        }
+    }
+
+    (values, secret_env)
+}
+
+/// a task's env, from whichever of its engines is set, split by `split_env`
+pub fn task_env(task: &Task) -> Option<(Vec<String>, Vec<SecretEnv>)> {
+    task.docker
+        .as_ref()
+        .map(|d| &d.env)
+        .or_else(|| task.wasm.as_ref().map(|w| &w.env))
+        .or_else(|| task.process.as_ref().map(|p| &p.env))
+        .and_then(|env| env.as_deref())
+        .map(split_env)
+}
+
+/// the secret holding a SQL sensor's connection URL, as if it were an env entry
+pub fn sensor_database(task: &Task) -> Option<SecretEnv> {
+    match task.sensor.as_ref().map(|s| &s.check) {
+        Some(SensorCheck::Sql { database, .. }) => Some(SecretEnv {
+            name: "database".to_owned(),
+            secret_ref: database.clone(),
+        }),
+        _ => None,
+    }
+}
+
+fn missing(task_name: &str, secret: &SecretEnv, reason: impl std::fmt::Display) -> highnoon::Error {
+    highnoon::Error::bad_request(format!(
+        "task '{}' env '{}' references {}: {}",
+        task_name, secret.name, secret.secret_ref, reason
+    ))
+}
+
+/// Check that the Vault and AWS secrets a job's tasks reference are in its
+/// project's scope and exist. They're read over the network, so this is done
+/// before the job's transaction starts rather than while it's held open.
+pub async fn check_external_secrets(config: &Config, job: &Job) -> highnoon::Result<()> {
+    for task in &job.tasks {
+        let secret_env = task_env(task)
+            .map(|(_, secret_env)| secret_env)
+            .unwrap_or_default();
+
+        for secret in secret_env.iter().chain(sensor_database(task).as_ref()) {
+            let read = match &secret.secret_ref {
+                SecretRef::Vault { path, field } => {
+                    secrets::check_scope(config, &job.project, &secret.secret_ref)
+                        .map_err(|err| missing(&task.name, secret, format!("{err:#}")))?;
+                    secrets::read_vault(
+                        config.vault_addr.as_deref(),
+                        config.vault_token.as_deref(),
+                        path,
+                        field,
+                    )
+                    .await
+                }
+                SecretRef::Aws { secret_id, field } => {
+                    secrets::check_scope(config, &job.project, &secret.secret_ref)
+                        .map_err(|err| missing(&task.name, secret, format!("{err:#}")))?;
+                    secrets::read_aws(secret_id, field.as_deref()).await
+                }
+                _ => continue,
+            };
+            read.map_err(|err| missing(&task.name, secret, format!("{err:#}")))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every stash secret a task references exists, so a bad reference
+/// fails when the job is submitted instead of when the task runs.
+pub async fn validate_secrets(
+    txn: &mut Transaction<'_, Postgres>,
+    job: &Job,
+    task_name: &str,
+    secret_env: &[SecretEnv],
+) -> highnoon::Result<()> {
+    for secret in secret_env {
+        match &secret.secret_ref {
+            SecretRef::Global { key } => {
+                let found: Option<(bool,)> = sqlx::query_as(
+                    "SELECT TRUE
+                    FROM global_stash
+                    WHERE name = $1",
+                )
+                .bind(key)
+                .fetch_optional(&mut *txn)
+                .await?;
+
+                if found.is_none() {
+                    return Err(missing(task_name, secret, "not found"));
+                }
+            }
+            SecretRef::Project { key } => {
+                let found: Option<(bool,)> = sqlx::query_as(
+                    "SELECT TRUE
+                    FROM project_stash s
+                    JOIN project p ON p.id = s.project_id
+                    WHERE p.name = $1
+                    AND s.name = $2",
+                )
+                .bind(&job.project)
+                .bind(key)
+                .fetch_optional(&mut *txn)
+                .await?;
+
+                if found.is_none() {
+                    return Err(missing(task_name, secret, "not found"));
+                }
+            }
+            SecretRef::Job { key } => {
+                if key.is_empty() {
+                    return Err(missing(task_name, secret, "key is empty"));
+                }
+            }
+            // read with the worker's access to the cluster, which the server may not have
+            SecretRef::Kubernetes { name, key } => {
+                if name.is_empty() || key.is_empty() {
+                    return Err(missing(task_name, secret, "name and key are required"));
+                }
+            }
+            // checked before the job's transaction, by `check_external_secrets`
+            SecretRef::Vault { .. } | SecretRef::Aws { .. } => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,316 @@
+use super::overrides::get_trigger_job;
+use crate::server::api::{
+    auth,
+    request_ext::RequestExt,
+    types::{duration_from_string, Catchup},
+    State,
+};
+use chrono::{DateTime, Duration, Utc};
+use cron::Schedule;
+use highnoon::{Request, Response};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// the most fire times a single simulation will return
+const MAX_FIRES: usize = 10_000;
+
+/// The window to simulate, and optionally a schedule to try instead of the
+/// trigger's current one. Omitted fields keep the trigger's current values.
+#[derive(Deserialize)]
+struct SimulateRequest {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    period: Option<String>,
+    cron: Option<String>,
+    offset: Option<String>,
+    catchup: Option<Catchup>,
+}
+
+#[derive(sqlx::FromRow)]
+struct SimTrigger {
+    start_datetime: DateTime<Utc>,
+    end_datetime: Option<DateTime<Utc>>,
+    earliest_trigger_datetime: Option<DateTime<Utc>>,
+    latest_trigger_datetime: Option<DateTime<Utc>>,
+    period: Option<i64>,
+    cron: Option<String>,
+    trigger_offset: Option<i64>,
+    catchup: Catchup,
+}
+
+#[derive(Serialize, sqlx::FromRow, Clone)]
+struct SimEdge {
+    task_id: Uuid,
+    task_name: String,
+    edge_offset: Option<i64>,
+}
+
+/// what the scheduler would do with a trigger time
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum FireStatus {
+    /// the trigger has already fired at this time
+    Fired,
+    /// in the past, would be run by catchup
+    Catchup,
+    /// in the past, skipped because catchup is disabled
+    Skipped,
+    /// in the future, will fire when it's due
+    Scheduled,
+}
+
+#[derive(Serialize)]
+struct SimToken {
+    task_id: Uuid,
+    task_name: String,
+    trigger_datetime: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct SimFire {
+    trigger_datetime: DateTime<Utc>,
+    /// when it fires, the trigger time plus the trigger's offset
+    scheduled_datetime: DateTime<Utc>,
+    status: FireStatus,
+    tokens: Vec<SimToken>,
+}
+
+#[derive(Serialize)]
+struct SimulateResponse {
+    catchup: Catchup,
+    edges: Vec<SimEdge>,
+    fires: Vec<SimFire>,
+}
+
+enum Period {
+    Duration(Duration),
+    Cron(Box<Schedule>),
+}
+
+impl Period {
+    fn new(period: Option<i64>, cron: Option<&str>) -> highnoon::Result<Period> {
+        match (period, cron) {
+            (_, Some(cron)) => Schedule::from_str(cron)
+                .map(|schedule| Period::Cron(Box::new(schedule)))
+                .map_err(|err| highnoon::Error::bad_request(err.to_string())),
+            (Some(period), None) if period > 0 => Ok(Period::Duration(Duration::seconds(period))),
+            _ => Err(highnoon::Error::bad_request(
+                "either a positive period or a cron must be provided",
+            )),
+        }
+    }
+
+    fn next_after(&self, datetime: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Period::Duration(duration) => Some(datetime + *duration),
+            Period::Cron(schedule) => schedule.after(&datetime).next(),
+        }
+    }
+}
+
+/// Every trigger time in `from..to`, walked from the start the same way the
+/// scheduler does, so the times line up with the ones it would really use.
+fn fire_times(
+    period: &Period,
+    start: DateTime<Utc>,
+    end: Option<DateTime<Utc>>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> highnoon::Result<Vec<DateTime<Utc>>> {
+    let mut next = Some(start);
+
+    // skip forward to the window without walking every period before it
+    if let Period::Duration(duration) = period {
+        if start < from {
+            let missed = (from - start).num_seconds() / duration.num_seconds();
+            next = Some(start + Duration::seconds(missed * duration.num_seconds()));
+        }
+    }
+
+    let last = match end {
+        Some(end) => end.min(to),
+        None => to,
+    };
+
+    let mut times = Vec::new();
+    while let Some(datetime) = next {
+        if datetime >= last {
+            break;
+        }
+        if datetime >= from {
+            if times.len() >= MAX_FIRES {
+                return Err(highnoon::Error::bad_request(format!(
+                    "the window has more than {MAX_FIRES} trigger times, try a shorter one"
+                )));
+            }
+            times.push(datetime);
+        }
+        next = period.next_after(datetime);
+    }
+
+    Ok(times)
+}
+
+fn fire_status(
+    trigger: &SimTrigger,
+    catchup: Catchup,
+    scheduled_datetime: DateTime<Utc>,
+    trigger_datetime: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> FireStatus {
+    let fired = match (
+        trigger.earliest_trigger_datetime,
+        trigger.latest_trigger_datetime,
+    ) {
+        (Some(earliest), Some(latest)) => {
+            earliest <= trigger_datetime && trigger_datetime <= latest
+        }
+        _ => false,
+    };
+
+    if fired {
+        FireStatus::Fired
+    } else if scheduled_datetime >= now {
+        FireStatus::Scheduled
+    } else if catchup == Catchup::None {
+        FireStatus::Skipped
+    } else {
+        FireStatus::Catchup
+    }
+}
+
+/// Work out when a trigger would fire over a window and which tokens each
+/// firing would increment, without changing anything.
+pub async fn simulate_trigger(mut req: Request<State>) -> highnoon::Result<Response> {
+    let trigger_id = req.param("id")?.parse::<Uuid>()?;
+    let body: SimulateRequest = req.body_json().await?;
+
+    let pool = req.get_pool();
+    let (job_id, project_id) = get_trigger_job(&pool, trigger_id).await?;
+
+    auth::get()
+        .job(job_id, project_id)
+        .kind("trigger")
+        .check(&req)
+        .await?;
+
+    if body.to <= body.from {
+        return Err(highnoon::Error::bad_request("'to' must be after 'from'"));
+    }
+
+    let mut trigger: SimTrigger = sqlx::query_as(
+        "SELECT
+            start_datetime,
+            end_datetime,
+            earliest_trigger_datetime,
+            latest_trigger_datetime,
+            period,
+            cron,
+            trigger_offset,
+            catchup
+        FROM trigger
+        WHERE id = $1",
+    )
+    .bind(trigger_id)
+    .fetch_one(&pool)
+    .await?;
+
+    // a new schedule replaces both the period and cron, since only one can be set
+    if body.period.is_some() || body.cron.is_some() {
+        trigger.period = duration_from_string(body.period.as_deref())?.map(i64::from);
+        trigger.cron = body.cron;
+    }
+    if body.offset.is_some() {
+        trigger.trigger_offset = duration_from_string(body.offset.as_deref())?.map(i64::from);
+    }
+    let start = body.start.unwrap_or(trigger.start_datetime);
+    let end = body.end.or(trigger.end_datetime);
+    let catchup = body.catchup.unwrap_or(trigger.catchup);
+
+    let period = Period::new(trigger.period, trigger.cron.as_deref())?;
+    let offset = Duration::seconds(trigger.trigger_offset.unwrap_or(0));
+
+    let edges: Vec<SimEdge> = sqlx::query_as(
+        "SELECT
+            te.task_id AS task_id,
+            t.name AS task_name,
+            te.edge_offset AS edge_offset
+        FROM trigger_edge te
+        JOIN task t ON t.id = te.task_id
+        WHERE te.trigger_id = $1
+        ORDER BY t.name",
+    )
+    .bind(trigger_id)
+    .fetch_all(&pool)
+    .await?;
+
+    let now = Utc::now();
+
+    let fires = fire_times(&period, start, end, body.from, body.to)?
+        .into_iter()
+        .map(|trigger_datetime| {
+            let scheduled_datetime = trigger_datetime + offset;
+            SimFire {
+                trigger_datetime,
+                scheduled_datetime,
+                status: fire_status(&trigger, catchup, scheduled_datetime, trigger_datetime, now),
+                tokens: edges
+                    .iter()
+                    .map(|edge| SimToken {
+                        task_id: edge.task_id,
+                        task_name: edge.task_name.clone(),
+                        trigger_datetime: trigger_datetime
+                            + Duration::seconds(edge.edge_offset.unwrap_or(0)),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+
+    Response::ok().json(SimulateResponse {
+        catchup,
+        edges,
+        fires,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.ymd(2022, 1, 1).and_hms(hour, 0, 0)
+    }
+
+    #[test]
+    fn test_period_fire_times() {
+        let period = Period::new(Some(3600), None).unwrap();
+
+        let times = fire_times(&period, at(0), None, at(3), at(6)).unwrap();
+        assert_eq!(times, vec![at(3), at(4), at(5)]);
+
+        let times = fire_times(&period, at(0), Some(at(5)), at(3), at(6)).unwrap();
+        assert_eq!(times, vec![at(3), at(4)]);
+
+        let times = fire_times(&period, at(4), None, at(0), at(6)).unwrap();
+        assert_eq!(times, vec![at(4), at(5)]);
+    }
+
+    #[test]
+    fn test_cron_fire_times() {
+        let period = Period::new(None, Some("0 0 */2 * * *")).unwrap();
+
+        let times = fire_times(&period, at(1), None, at(0), at(7)).unwrap();
+        assert_eq!(times, vec![at(1), at(2), at(4), at(6)]);
+    }
+
+    #[test]
+    fn test_too_many_fire_times() {
+        let period = Period::new(Some(1), None).unwrap();
+        assert!(fire_times(&period, at(0), None, at(0), at(12)).is_err());
+    }
+}
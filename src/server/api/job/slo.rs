@@ -0,0 +1,91 @@
+use crate::server::{
+    api::{
+        auth,
+        request_ext::RequestExt,
+        types::{duration_from_string, Slo},
+        State,
+    },
+    slo::{self, SloTargets, WindowStats},
+};
+use highnoon::{Request, Response, StatusCode};
+use serde::Serialize;
+use uuid::Uuid;
+
+fn parse_target(name: &str, target: Option<&str>) -> highnoon::Result<Option<i64>> {
+    match duration_from_string(target) {
+        Ok(Some(secs)) if secs < 0 => Err(highnoon::Error::bad_request(format!(
+            "slo {name} must not be negative"
+        ))),
+        Ok(secs) => Ok(secs.map(i64::from)),
+        Err(err) => Err(highnoon::Error::bad_request(format!(
+            "slo {name} is not valid: {err}"
+        ))),
+    }
+}
+
+/// validate a job's SLO and convert it to the targets stored with the job
+pub fn parse_slo(slo: &Slo) -> highnoon::Result<SloTargets> {
+    let targets = SloTargets {
+        start_secs: parse_target("start_within", slo.start_within.as_deref())?,
+        finish_secs: parse_target("finish_within", slo.finish_within.as_deref())?,
+        objective: slo.objective,
+    };
+
+    if targets.start_secs.is_none() && targets.finish_secs.is_none() {
+        return Err(highnoon::Error::bad_request(
+            "slo must set start_within, finish_within or both",
+        ));
+    }
+
+    if !(targets.objective > 0.0 && targets.objective < 1.0) {
+        return Err(highnoon::Error::bad_request(
+            "slo objective must be between 0 and 1, eg. 0.99",
+        ));
+    }
+
+    Ok(targets)
+}
+
+#[derive(Serialize)]
+struct SloWindow {
+    window: &'static str,
+    #[serde(flatten)]
+    stats: WindowStats,
+    attainment: Option<f64>,
+    burn_rate: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct GetSlo {
+    targets: SloTargets,
+    windows: Vec<SloWindow>,
+}
+
+/// a job's SLO attainment and burn rate over each of the rolling windows
+pub async fn get_slo(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+
+    auth::get().job(job_id, None).check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let targets = match slo::get_slo_targets(&pool, job_id).await? {
+        Some(targets) => targets,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    let mut windows = Vec::new();
+    for (window, window_secs) in slo::WINDOWS {
+        let stats = slo::window_stats(&pool, job_id, &targets, *window_secs).await?;
+        let attainment = stats.attainment();
+
+        windows.push(SloWindow {
+            window: *window,
+            stats,
+            attainment,
+            burn_rate: attainment.map(|attainment| slo::burn_rate(attainment, targets.objective)),
+        });
+    }
+
+    Response::ok().json(GetSlo { targets, windows })
+}
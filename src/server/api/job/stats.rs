@@ -0,0 +1,58 @@
+use crate::server::api::{auth, request_ext::RequestExt, State};
+use chrono::NaiveDate;
+use highnoon::{Json, Request, Responder};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct GetStatsQuery {
+    days: Option<i32>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct DailyStats {
+    day: NaiveDate,
+    total_runs: i64,
+    success_runs: i64,
+    failure_runs: i64,
+    total_duration_secs: Option<f64>,
+    max_duration_secs: Option<f64>,
+    median_queue_secs: Option<f64>,
+    median_duration_secs: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct GetStats {
+    pub days: Vec<DailyStats>,
+}
+
+/// daily run totals for a job, read from the scheduler's rollup rather than the raw task runs
+pub async fn get_stats(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+
+    let query: GetStatsQuery = req.query()?;
+
+    auth::get().job(job_id, None).check(&req).await?;
+
+    let days: Vec<DailyStats> = sqlx::query_as(
+        "SELECT
+            day,
+            total_runs,
+            success_runs,
+            failure_runs,
+            total_duration_secs,
+            max_duration_secs,
+            median_queue_secs,
+            median_duration_secs
+        FROM job_run_daily
+        WHERE job_id = $1
+        AND day > (CURRENT_TIMESTAMP AT TIME ZONE 'UTC')::DATE - $2::INT
+        ORDER BY day",
+    )
+    .bind(job_id)
+    .bind(query.days.unwrap_or(30))
+    .fetch_all(&req.get_pool())
+    .await?;
+
+    Ok(Json(GetStats { days }))
+}
@@ -1,17 +1,22 @@
 use crate::{
-    messages::{TaskPriority, TokenState},
-    server::api::{auth, request_ext::RequestExt, State},
+    messages::{ErrorClass, TaskPriority, TokenState},
+    server::{
+        api::{
+            auth,
+            paging::{list_response, Paging},
+            request_ext::RequestExt,
+            task::get_task_job_id,
+            State,
+        },
+        token_history::TokenHistory,
+    },
 };
 use chrono::{DateTime, Utc};
-use highnoon::{Json, Request, Responder};
-use serde::{Deserialize, Serialize};
+use highnoon::{Request, Response, StatusCode};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
 use uuid::Uuid;
 
-#[derive(Deserialize)]
-struct ListTaskRunsQuery {
-    limit: Option<i32>,
-}
-
 #[derive(Serialize, sqlx::FromRow)]
 struct ListJobAllTaskRuns {
     task_id: Uuid,
@@ -25,14 +30,34 @@ struct ListJobAllTaskRuns {
     state: TokenState,
     priority: TaskPriority,
     worker_id: Option<Uuid>,
+    exit_code: Option<i64>,
+    error_class: Option<ErrorClass>,
+    error_details: Option<String>,
+    operator_override: bool,
+    /// outputs and artifacts from the task's result file
+    outputs: Option<JsonValue>,
 }
-pub async fn list_job_all_task_runs(req: Request<State>) -> highnoon::Result<impl Responder> {
+pub async fn list_job_all_task_runs(req: Request<State>) -> highnoon::Result<Response> {
     let job_id: Uuid = req.param("id")?.parse()?;
     let trigger_datetime: DateTime<Utc> = req.param("trigger_datetime")?.parse()?;
-    let query: ListTaskRunsQuery = req.query()?;
+    let paging: Paging = req.query()?;
 
     auth::list().job(job_id, None).check(&req).await?;
 
+    let pool = req.get_pool();
+
+    let (total,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(1)
+        FROM task_run tr
+        JOIN task t ON t.id = tr.task_id
+        WHERE t.job_id = $1
+        AND tr.trigger_datetime = $2",
+    )
+    .bind(job_id)
+    .bind(trigger_datetime)
+    .fetch_one(&pool)
+    .await?;
+
     let tasks: Vec<ListJobAllTaskRuns> = sqlx::query_as(
         "SELECT
             tr.task_id AS task_id,
@@ -49,21 +74,28 @@ pub async fn list_job_all_task_runs(req: Request<State>) -> highnoon::Result<imp
             finish_datetime,
             state,
             priority,
-            worker_id
+            worker_id,
+            exit_code,
+            error_class,
+            error_details,
+            operator_override,
+            outputs
         FROM task_run tr
         JOIN task t ON t.id = tr.task_id
         WHERE t.job_id = $1
         AND tr.trigger_datetime = $2
         ORDER BY t.name ASC, tr.queued_datetime ASC
-        LIMIT $3",
+        LIMIT $3
+        OFFSET $4",
     )
     .bind(job_id)
     .bind(trigger_datetime)
-    .bind(query.limit)
-    .fetch_all(&req.get_pool())
+    .bind(paging.limit(1000))
+    .bind(paging.offset())
+    .fetch_all(&pool)
     .await?;
 
-    Ok(Json(tasks))
+    list_response(tasks, total)
 }
 
 #[derive(Serialize, sqlx::FromRow)]
@@ -76,14 +108,38 @@ struct ListTaskRuns {
     state: TokenState,
     priority: TaskPriority,
     worker_id: Option<Uuid>,
+    exit_code: Option<i64>,
+    error_class: Option<ErrorClass>,
+    error_details: Option<String>,
+    operator_override: bool,
+    /// outputs and artifacts from the task's result file
+    outputs: Option<JsonValue>,
 }
 
-pub async fn list_task_runs(req: Request<State>) -> highnoon::Result<impl Responder> {
+pub async fn list_task_runs(req: Request<State>) -> highnoon::Result<Response> {
     let task_id: Uuid = req.param("id")?.parse()?;
     let trigger_datetime: DateTime<Utc> = req.param("trigger_datetime")?.parse()?;
+    let paging: Paging = req.query()?;
+
+    let pool = req.get_pool();
+    let job_id = get_task_job_id(&pool, task_id).await?;
 
-    // TODO - auth via a task id?
-    //auth::list().job(job_id, None).check(&req).await?;
+    auth::list()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
+    let (total,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(1)
+        FROM task_run
+        WHERE task_id = $1
+        AND trigger_datetime = $2",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_one(&pool)
+    .await?;
 
     let tasks: Vec<ListTaskRuns> = sqlx::query_as(
         "SELECT
@@ -97,17 +153,137 @@ pub async fn list_task_runs(req: Request<State>) -> highnoon::Result<impl Respon
             finish_datetime,
             state,
             priority,
-            worker_id
+            worker_id,
+            exit_code,
+            error_class,
+            error_details,
+            operator_override,
+            outputs
         FROM task_run tr
         JOIN task t ON t.id = tr.task_id
         WHERE tr.task_id = $1
         AND tr.trigger_datetime = $2
-        ORDER BY queued_datetime",
+        ORDER BY queued_datetime
+        LIMIT $3
+        OFFSET $4",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .bind(paging.limit(1000))
+    .bind(paging.offset())
+    .fetch_all(&pool)
+    .await?;
+
+    list_response(tasks, total)
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct TaskAttempt {
+    attempt: i64,
+    task_run_id: Uuid,
+    worker_id: Option<Uuid>,
+    /// the worker's address, unless it has since been deleted
+    worker_addr: Option<String>,
+    queued_datetime: DateTime<Utc>,
+    started_datetime: Option<DateTime<Utc>>,
+    finish_datetime: Option<DateTime<Utc>>,
+    state: TokenState,
+    exit_code: Option<i64>,
+    /// why the attempt failed, eg. `image_pull` or `oom_killed`
+    error_class: Option<ErrorClass>,
+    error_details: Option<String>,
+    operator_override: bool,
+}
+
+/// Each attempt at running a token, oldest first, with where it ran and how it
+/// ended. Helps with debugging flaky tasks without searching worker logs.
+pub async fn list_task_attempts(req: Request<State>) -> highnoon::Result<Response> {
+    let task_id: Uuid = req.param("id")?.parse()?;
+    let trigger_datetime: DateTime<Utc> = req.param("trigger_datetime")?.parse()?;
+
+    let pool = req.get_pool();
+
+    let maybe_job: Option<(Uuid,)> = sqlx::query_as("SELECT job_id FROM task WHERE id = $1")
+        .bind(task_id)
+        .fetch_optional(&pool)
+        .await?;
+
+    let job_id = match maybe_job {
+        Some((job_id,)) => job_id,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    auth::get()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
+    let attempts: Vec<TaskAttempt> = sqlx::query_as(
+        "SELECT
+            tr.attempt,
+            tr.id AS task_run_id,
+            tr.worker_id,
+            w.addr AS worker_addr,
+            tr.queued_datetime,
+            tr.started_datetime,
+            tr.finish_datetime,
+            tr.state,
+            tr.exit_code,
+            tr.error_class,
+            tr.error_details,
+            tr.operator_override
+        FROM task_run tr
+        LEFT JOIN worker w ON w.id = tr.worker_id
+        WHERE tr.task_id = $1
+        AND tr.trigger_datetime = $2
+        ORDER BY tr.attempt, tr.queued_datetime",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_all(&pool)
+    .await?;
+
+    Response::ok().json(attempts)
+}
+
+/// every change made to a token, oldest first, eg. to find out why a task ran twice
+pub async fn get_token_history(req: Request<State>) -> highnoon::Result<Response> {
+    let task_id: Uuid = req.param("id")?.parse()?;
+    let trigger_datetime: DateTime<Utc> = req.param("trigger_datetime")?.parse()?;
+
+    let pool = req.get_pool();
+
+    let maybe_job: Option<(Uuid,)> = sqlx::query_as("SELECT job_id FROM task WHERE id = $1")
+        .bind(task_id)
+        .fetch_optional(&pool)
+        .await?;
+
+    let job_id = match maybe_job {
+        Some((job_id,)) => job_id,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    auth::get().job(job_id, None).kind("task").check(&req).await?;
+
+    let history: Vec<TokenHistory> = sqlx::query_as(
+        "SELECT
+            event,
+            state,
+            count,
+            actor,
+            task_run_id,
+            details,
+            created_datetime
+        FROM token_history
+        WHERE task_id = $1
+        AND trigger_datetime = $2
+        ORDER BY id",
     )
     .bind(task_id)
     .bind(trigger_datetime)
-    .fetch_all(&req.get_pool())
+    .fetch_all(&pool)
     .await?;
 
-    Ok(Json(tasks))
+    Response::ok().json(history)
 }
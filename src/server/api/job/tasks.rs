@@ -1,19 +1,38 @@
 use crate::{
+    messages::{
+        is_valid_queue_name, is_valid_staged_path, ContainerOs, DockerOptions, PodContainers,
+        Staging, TaskResources, DEFAULT_QUEUE,
+    },
     server::api::{
         auth,
-        job::reference::{parse_reference, resolve_reference, Reference, ReferenceKind},
+        job::{
+            reference::{parse_reference, resolve_reference, Reference, ReferenceKind},
+            secrets::{sensor_database, task_env, validate_secrets},
+        },
+        paging::{list_response, Paging},
         request_ext::RequestExt,
-        types::{Job, Task},
+        types::{memory_from_string, Job, Task},
         State,
     },
     util::{is_pg_integrity_error, pg_error},
 };
-use highnoon::{Json, Request, Responder};
-use serde::Serialize;
+use highnoon::{Request, Response};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 use sqlx::{Postgres, Transaction};
+use std::collections::HashSet;
 use tracing::debug;
 use uuid::Uuid;
 
+/// how often a sensor is checked if the task doesn't say
+const DEFAULT_POKE_INTERVAL_SECS: i64 = 60;
+
+/// `<source>:<drive>:\<path>[:<options>]`, the source may have a drive letter too
+static WINDOWS_VOLUME: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^.+:[A-Za-z]:\\[^:]*(:[a-z]+)?$").unwrap());
+
 pub async fn create_task(
     txn: &mut Transaction<'_, Postgres>,
     task: &Task,
@@ -27,6 +46,48 @@ pub async fn create_task(
         }
     });
 
+    let kinds = [
+        task.docker.is_some(),
+        task.wasm.is_some(),
+        task.process.is_some(),
+        task.lambda.is_some(),
+        task.sensor.is_some(),
+    ];
+    if kinds.into_iter().filter(|kind| *kind).count() > 1 {
+        return Err(highnoon::Error::bad_request(format!(
+            "task '{}' can only have one of docker, wasm, process, lambda or sensor",
+            task.name
+        )));
+    }
+
+    // the default queue is stored as no queue at all
+    let queue = task
+        .queue
+        .as_deref()
+        .filter(|queue| *queue != DEFAULT_QUEUE);
+    if let Some(queue) = queue {
+        if !is_valid_queue_name(queue) {
+            return Err(highnoon::Error::bad_request(format!(
+                "task '{}' has an invalid queue '{}', queue names are lowercase letters, digits, \
+                 '-' and '_'",
+                task.name, queue
+            )));
+        }
+    }
+
+    let poke_interval_secs = match &task.sensor {
+        Some(sensor) => Some(
+            sensor
+                .poke_interval
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()?
+                .map_or(DEFAULT_POKE_INTERVAL_SECS, |dur| dur.as_secs() as i64)
+                .max(1),
+        ),
+        None => None,
+    };
+
     let retry_delay_secs = task
         .retry
         .as_ref()
@@ -42,6 +103,140 @@ pub async fn create_task(
         .transpose()?
         .map(|dur| dur.as_secs() as i32);
 
+    let expires_after_secs = task
+        .expires_after
+        .as_ref()
+        .map(|s| humantime::parse_duration(s))
+        .transpose()?
+        .map(|dur| dur.as_secs() as i64);
+
+    let resources = match task.docker.as_ref().and_then(|d| d.resources.as_ref()) {
+        Some(resources) => {
+            if matches!(resources.cpu, Some(cpu) if cpu <= 0.0) {
+                return Err(highnoon::Error::bad_request(format!(
+                    "task '{}' must have a cpu limit above 0",
+                    task.name
+                )));
+            }
+
+            let memory = resources
+                .memory
+                .as_deref()
+                .map(memory_from_string)
+                .transpose()
+                .map_err(|err| {
+                    highnoon::Error::bad_request(format!("task '{}': {}", task.name, err))
+                })?;
+
+            Some(TaskResources {
+                cpu: resources.cpu,
+                memory,
+                gpus: resources.gpus.filter(|gpus| *gpus > 0),
+                ulimits: resources.ulimits.clone(),
+            })
+        }
+        None => None,
+    };
+
+    let docker_options = match &task.docker {
+        Some(docker) => {
+            let os = docker.os.unwrap_or_default();
+            if let Some(volume) = docker.volumes.iter().find(|v| !is_valid_volume(v, os)) {
+                return Err(highnoon::Error::bad_request(format!(
+                    "task '{}' has an invalid volume '{}', volumes are \
+                     `<source>:<container path>[:<options>]`",
+                    task.name, volume
+                )));
+            }
+
+            let options = DockerOptions {
+                network_mode: docker.network_mode.clone(),
+                volumes: docker.volumes.clone(),
+                working_dir: docker.working_dir.clone(),
+                user: docker.user.clone(),
+                os: docker.os,
+            };
+            (options != DockerOptions::default()).then_some(options)
+        }
+        None => None,
+    };
+
+    let pod_containers = match &task.docker {
+        Some(docker) => {
+            // names must be unique in the pod, including the task's own containers
+            let mut names = HashSet::from(["task", "waterwheel-task"]);
+            let mut declared = docker.init_containers.iter().chain(&docker.sidecars);
+            let valid = declared.all(|container| {
+                let name = container.get("name").and_then(JsonValue::as_str);
+                let has_image = container.get("image").map_or(false, JsonValue::is_string);
+                has_image && name.map_or(false, |name| names.insert(name))
+            });
+            if !valid {
+                return Err(highnoon::Error::bad_request(format!(
+                    "task '{}' has an invalid init container or sidecar, each needs an `image` \
+                     and a unique `name` (other than `task` and `waterwheel-task`)",
+                    task.name
+                )));
+            }
+
+            let containers = PodContainers {
+                init_containers: docker.init_containers.clone(),
+                sidecars: docker.sidecars.clone(),
+            };
+            (containers != PodContainers::default()).then_some(containers)
+        }
+        None => None,
+    };
+
+    // staged files are moved through a working directory on the worker's host
+    let has_staging = !task.inputs.is_empty() || !task.outputs.is_empty();
+    if has_staging && (task.lambda.is_some() || task.sensor.is_some()) {
+        return Err(highnoon::Error::bad_request(format!(
+            "task '{}' can't have inputs or outputs, it doesn't run on a worker",
+            task.name
+        )));
+    }
+
+    if let Some(input) = task.inputs.iter().find(|input| {
+        let scheme = input.uri.split_once("://").map(|(scheme, _)| scheme);
+        !matches!(scheme, Some("s3" | "http" | "https" | "stash"))
+            || !is_valid_staged_path(input.path())
+    }) {
+        return Err(highnoon::Error::bad_request(format!(
+            "task '{}' has an invalid input '{}', inputs are s3://, https:// or stash:// URIs \
+             with a relative path in the working directory",
+            task.name, input.uri
+        )));
+    }
+
+    if let Some(output) = task
+        .outputs
+        .iter()
+        .find(|output| !output.uri.starts_with("s3://") || !is_valid_staged_path(&output.glob))
+    {
+        return Err(highnoon::Error::bad_request(format!(
+            "task '{}' has an invalid output '{}', outputs are a relative glob and an s3:// URI \
+             to upload the matching files to",
+            task.name, output.glob
+        )));
+    }
+
+    let staging = has_staging.then(|| Staging {
+        inputs: task.inputs.clone(),
+        outputs: task.outputs.clone(),
+    });
+
+    let (env, secret_env) = match task_env(task) {
+        Some((values, secret_env)) => (Some(values), secret_env),
+        None => (None, Vec::new()),
+    };
+
+    validate_secrets(&mut *txn, job, &task.name, &secret_env).await?;
+
+    if let Some(database) = sensor_database(task) {
+        validate_secrets(&mut *txn, job, &task.name, &[database]).await?;
+    }
+
     let new_id = Uuid::new_v4();
 
     let (task_id,): (Uuid,) = sqlx::query_as(
@@ -55,9 +250,25 @@ pub async fn create_task(
             timeout_secs,
             image,
             args,
-            env
+            env,
+            expires_after_secs,
+            wasm_module,
+            secret_env,
+            sensor,
+            poke_interval_secs,
+            command,
+            resources,
+            image_pull_policy,
+            lambda,
+            queue,
+            docker_options,
+            pod_containers,
+            staging
+         )
+         VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19,
+            $20, $21, $22, $23
          )
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
          ON CONFLICT(name, job_id)
          DO UPDATE
          SET threshold = $4,
@@ -66,7 +277,20 @@ pub async fn create_task(
              timeout_secs = $7,
              image = $8,
              args = $9,
-             env = $10
+             env = $10,
+             expires_after_secs = $11,
+             wasm_module = $12,
+             secret_env = $13,
+             sensor = $14,
+             poke_interval_secs = $15,
+             command = $16,
+             resources = $17,
+             image_pull_policy = $18,
+             lambda = $19,
+             queue = $20,
+             docker_options = $21,
+             pod_containers = $22,
+             staging = $23
          RETURNING id",
     )
     .bind(new_id)
@@ -77,14 +301,49 @@ pub async fn create_task(
     .bind(retry_delay_secs)
     .bind(timeout_secs)
     .bind(task.docker.as_ref().map(|d| &d.image))
-    .bind(task.docker.as_ref().map(|d| &d.args))
-    .bind(task.docker.as_ref().map(|d| &d.env))
+    .bind(
+        task.docker
+            .as_ref()
+            .map(|d| &d.args)
+            .or_else(|| task.wasm.as_ref().map(|w| &w.args))
+            .or_else(|| task.process.as_ref().map(|p| &p.args)),
+    )
+    .bind(env)
+    .bind(expires_after_secs)
+    .bind(task.wasm.as_ref().map(|w| &w.module))
+    .bind(sqlx::types::Json(&secret_env))
+    .bind(task.sensor.as_ref().map(|s| sqlx::types::Json(&s.check)))
+    .bind(poke_interval_secs)
+    .bind(task.process.as_ref().map(|p| &p.command))
+    .bind(resources.as_ref().map(sqlx::types::Json))
+    .bind(task.docker.as_ref().and_then(|d| d.image_pull_policy))
+    .bind(task.lambda.as_ref().map(sqlx::types::Json))
+    .bind(queue)
+    .bind(docker_options.as_ref().map(sqlx::types::Json))
+    .bind(pod_containers.as_ref().map(sqlx::types::Json))
+    .bind(staging.as_ref().map(sqlx::types::Json))
     .fetch_one(&mut *txn)
     .await?;
 
     Ok(task_id)
 }
 
+/// a volume has a source and an absolute path in the container, and maybe options.
+/// Windows paths have a drive letter, eg. `C:\data:C:\data:ro`
+fn is_valid_volume(volume: &str, os: ContainerOs) -> bool {
+    match os {
+        ContainerOs::Linux => {
+            let parts = volume.split(':').collect::<Vec<_>>();
+            matches!(
+                parts.as_slice(),
+                [source, target] | [source, target, _]
+                    if !source.is_empty() && target.starts_with('/')
+            )
+        }
+        ContainerOs::Windows => WINDOWS_VOLUME.is_match(volume),
+    }
+}
+
 pub async fn create_task_edges(
     txn: &mut Transaction<'_, Postgres>,
     task: &Task,
@@ -130,6 +389,7 @@ pub async fn create_task_edges(
                 ReferenceKind::Task => {
                     create_task_edge(&mut *txn, &task_id, reference, "success").await?
                 }
+                ReferenceKind::Group => return Err(unexpanded_group(&reference)),
             }
         }
     }
@@ -150,6 +410,7 @@ pub async fn create_task_edges(
                 ReferenceKind::Task => {
                     create_task_edge(&mut *txn, &task_id, reference, "failure").await?
                 }
+                ReferenceKind::Group => return Err(unexpanded_group(&reference)),
             }
         }
     }
@@ -157,6 +418,50 @@ pub async fn create_task_edges(
     Ok(())
 }
 
+/// set (or clear) the task to activate when any task in the job fails
+pub async fn set_on_failure_task(
+    txn: &mut Transaction<'_, Postgres>,
+    job: &Job,
+) -> highnoon::Result<()> {
+    let task_id = match &job.on_failure {
+        Some(name) => {
+            let row: Option<(Uuid,)> = sqlx::query_as(
+                "SELECT id
+                FROM task
+                WHERE job_id = $1
+                AND name = $2",
+            )
+            .bind(job.uuid)
+            .bind(name)
+            .fetch_optional(&mut *txn)
+            .await?;
+
+            let (id,) = row.ok_or_else(|| {
+                highnoon::Error::bad_request(format!("on_failure task '{name}' does not exist"))
+            })?;
+            Some(id)
+        }
+        None => None,
+    };
+
+    sqlx::query(
+        "UPDATE job
+        SET on_failure_task_id = $2
+        WHERE id = $1",
+    )
+    .bind(job.uuid)
+    .bind(task_id)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(())
+}
+
+/// group references are replaced with task references by `groups::expand_groups`
+fn unexpanded_group(reference: &Reference) -> highnoon::Error {
+    highnoon::Error::bad_request(format!("unexpected group reference: {reference}"))
+}
+
 async fn create_trigger_edge(
     txn: &mut Transaction<'_, Postgres>,
     task: &Uuid,
@@ -258,25 +563,49 @@ struct ListTask {
     name: String,
 }
 
-pub async fn list_tasks(req: Request<State>) -> highnoon::Result<impl Responder> {
+#[derive(Deserialize)]
+struct ListTasksQuery {
+    /// only tasks with names containing this
+    q: Option<String>,
+}
+
+pub async fn list_tasks(req: Request<State>) -> highnoon::Result<Response> {
     let job_id: Uuid = req.param("id")?.parse()?;
+    let query: ListTasksQuery = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(&[("name", "name")], "name")?;
 
     auth::list().job(job_id, None).check(&req).await?;
 
-    let tasks: Vec<ListTask> = sqlx::query_as(
+    let pool = req.get_pool();
+
+    let filter = "FROM task
+        WHERE job_id = $1
+        AND ($2::VARCHAR IS NULL OR STRPOS(LOWER(name), LOWER($2)) > 0)";
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(job_id)
+        .bind(&query.q)
+        .fetch_one(&pool)
+        .await?;
+
+    let tasks: Vec<ListTask> = sqlx::query_as(&format!(
         "SELECT
             id AS task_id,
             name
-        FROM task
-        WHERE job_id = $1
-        ORDER BY name
-        LIMIT 200",
-    )
+        {filter}
+        ORDER BY {order_by}
+        LIMIT $3
+        OFFSET $4"
+    ))
     .bind(job_id)
-    .fetch_all(&req.get_pool())
+    .bind(&query.q)
+    .bind(paging.limit(200))
+    .bind(paging.offset())
+    .fetch_all(&pool)
     .await?;
 
     // TODO - check for job_id not found
 
-    Ok(Json(tasks))
+    list_response(tasks, total)
 }
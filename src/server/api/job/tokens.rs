@@ -1,9 +1,18 @@
 use crate::{
     messages::{ProcessToken, Token, TokenState},
-    server::api::{auth, request_ext::RequestExt, updates, State},
+    server::{
+        api::{
+            audit, auth,
+            conditional::Conditional,
+            paging::{list_response, Paging},
+            request_ext::RequestExt,
+            updates, State,
+        },
+        token_history::{self, Actor, TokenEvent},
+    },
 };
 use chrono::{DateTime, Utc};
-use highnoon::{Json, Request, Responder};
+use highnoon::{Json, Request, Responder, Response};
 use serde::{Deserialize, Serialize};
 use std::{cmp::Reverse, collections::BTreeMap};
 use uuid::Uuid;
@@ -12,7 +21,6 @@ use uuid::Uuid;
 struct QueryToken {
     state: Option<String>,
     before: Option<DateTime<Utc>>,
-    limit: Option<i32>,
 }
 
 #[derive(Serialize, sqlx::FromRow)]
@@ -23,11 +31,16 @@ struct GetToken {
     state: String,
 }
 
-async fn get_tokens_common(req: Request<State>) -> highnoon::Result<Vec<GetToken>> {
+/// Tokens for the most recent trigger times, paged by trigger time rather than by token.
+/// Also returns how many trigger times there are in total. The trigger times are found
+/// from the runs' state counts rather than by scanning every token the job has.
+async fn get_tokens_common(req: &Request<State>) -> highnoon::Result<(Vec<GetToken>, i64)> {
     let job_id = req.param("id")?.parse::<Uuid>()?;
     let q = req.query::<QueryToken>()?;
+    let paging: Paging = req.query()?;
+    let pool = req.get_pool();
 
-    auth::get().job(job_id, None).check(&req).await?;
+    auth::get().job(job_id, None).check(req).await?;
 
     let maybe_states: Option<Vec<_>> = q.state.as_ref().map(|s| s.split(',').collect());
 
@@ -39,49 +52,57 @@ async fn get_tokens_common(req: Request<State>) -> highnoon::Result<Vec<GetToken
         }
     }
 
+    let (total,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(DISTINCT trigger_datetime)
+        FROM job_run_state_count
+        WHERE job_id = $1
+        AND ($2 IS NULL OR trigger_datetime < $2)
+        AND ($3 IS NULL OR state = ANY($3))",
+    )
+    .bind(job_id)
+    .bind(q.before)
+    .bind(&maybe_states)
+    .fetch_one(&pool)
+    .await?;
+
     let tokens: Vec<GetToken> = sqlx::query_as(
-        "WITH these_tokens AS (
-            SELECT
-                t.id AS task_id,
-                t.name AS task_name,
-                k.trigger_datetime AS trigger_datetime,
-                k.state AS state
-            FROM task t
-            JOIN token k ON k.task_id = t.id
-            WHERE t.job_id = $1
-            AND ($4 IS NULL OR state = ANY($4))
-        ),
-        these_datetimes AS (
+        "WITH these_datetimes AS (
             SELECT DISTINCT
                 trigger_datetime
-            FROM these_tokens
-            WHERE ($2 IS NULL OR trigger_datetime < $2)
+            FROM job_run_state_count
+            WHERE job_id = $1
+            AND ($2 IS NULL OR trigger_datetime < $2)
+            AND ($4 IS NULL OR state = ANY($4))
             ORDER BY trigger_datetime DESC
             LIMIT $3
+            OFFSET $5
         )
         SELECT
-            task_id,
-            task_name,
-            tt.trigger_datetime AS trigger_datetime,
-            state
-        FROM these_tokens tt
-        JOIN these_datetimes td ON td.trigger_datetime = tt.trigger_datetime
+            t.id AS task_id,
+            t.name AS task_name,
+            k.trigger_datetime AS trigger_datetime,
+            k.state AS state
+        FROM these_datetimes td
+        JOIN task t ON t.job_id = $1
+        JOIN token k ON k.task_id = t.id AND k.trigger_datetime = td.trigger_datetime
+        WHERE ($4 IS NULL OR k.state = ANY($4))
         ORDER BY trigger_datetime DESC
         ",
     )
     .bind(job_id)
     .bind(q.before)
-    .bind(q.limit.unwrap_or(200))
+    .bind(paging.limit(200))
     .bind(maybe_states)
-    .fetch_all(&req.get_pool())
+    .bind(paging.offset())
+    .fetch_all(&pool)
     .await?;
 
-    Ok(tokens)
+    Ok((tokens, total))
 }
 
-pub async fn get_tokens(req: Request<State>) -> highnoon::Result<impl Responder> {
-    let tokens = get_tokens_common(req).await?;
-    Ok(Json(tokens))
+pub async fn get_tokens(req: Request<State>) -> highnoon::Result<Response> {
+    let (tokens, total) = get_tokens_common(&req).await?;
+    list_response(tokens, total)
 }
 
 #[derive(Serialize)]
@@ -95,16 +116,47 @@ struct TokenOverviewState {
 struct TokenOverviewRow {
     trigger_datetime: DateTime<Utc>,
     task_states: BTreeMap<String, TokenOverviewState>,
+    /// how many of the run's tokens are in each state, whatever the filter
+    state_counts: BTreeMap<String, i64>,
 }
 
 #[derive(Serialize)]
 struct GetTokensOverview {
     tokens: Vec<TokenOverviewRow>,
     tasks: Vec<String>,
+    /// how many trigger times there are, across all pages
+    total: i64,
 }
 
-pub async fn get_tokens_overview(req: Request<State>) -> highnoon::Result<impl Responder> {
-    let tokens = get_tokens_common(req).await?;
+/// Tokens pivoted into a row per trigger time, for the UI's overview. The UI polls
+/// this, so it's compressed and can be revalidated with its ETag.
+pub async fn get_tokens_overview(req: Request<State>) -> highnoon::Result<Response> {
+    let conditional = Conditional::from_request(&req);
+    let (tokens, total) = get_tokens_common(&req).await?;
+
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+    let mut trigger_datetimes: Vec<DateTime<Utc>> =
+        tokens.iter().map(|t| t.trigger_datetime).collect();
+    trigger_datetimes.dedup();
+
+    let counts: Vec<(DateTime<Utc>, String, i32)> = sqlx::query_as(
+        "SELECT trigger_datetime, state, tokens
+        FROM job_run_state_count
+        WHERE job_id = $1
+        AND trigger_datetime = ANY($2)",
+    )
+    .bind(job_id)
+    .bind(&trigger_datetimes)
+    .fetch_all(&req.get_pool())
+    .await?;
+
+    let mut counts_by_time = BTreeMap::<DateTime<Utc>, BTreeMap<String, i64>>::new();
+    for (trigger_datetime, state, tokens) in counts {
+        counts_by_time
+            .entry(trigger_datetime)
+            .or_default()
+            .insert(state, tokens.into());
+    }
 
     let mut tasks = tokens
         .iter()
@@ -135,15 +187,17 @@ pub async fn get_tokens_overview(req: Request<State>) -> highnoon::Result<impl R
         .map(|(k, v)| TokenOverviewRow {
             trigger_datetime: k,
             task_states: v,
+            state_counts: counts_by_time.remove(&k).unwrap_or_default(),
         })
         .collect::<Vec<_>>();
 
     tokens_by_time.sort_by_key(|item| Reverse(item.trigger_datetime));
 
-    Ok(Json(GetTokensOverview {
+    conditional.json(GetTokensOverview {
         tokens: tokens_by_time,
         tasks,
-    }))
+        total,
+    })
 }
 
 pub async fn get_tokens_trigger_datetime(req: Request<State>) -> highnoon::Result<impl Responder> {
@@ -185,7 +239,14 @@ pub async fn clear_tokens_trigger_datetime(
     let job_id = req.param("id")?.parse::<Uuid>()?;
     let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
 
-    auth::delete().job(job_id, None).check(&req).await?;
+    auth::delete()
+        .job(job_id, None)
+        .kind("token")
+        .check(&req)
+        .await?;
+
+    let pool = req.get_pool();
+    let mut txn = pool.begin().await?;
 
     let task_ids: Vec<(Uuid,)> = sqlx::query_as(
         "UPDATE token k
@@ -199,15 +260,33 @@ pub async fn clear_tokens_trigger_datetime(
     )
     .bind(job_id)
     .bind(trigger_datetime)
-    .fetch_all(&req.get_pool())
+    .fetch_all(&mut txn)
     .await?;
 
-    for &(id,) in &task_ids {
-        let token = Token {
-            task_id: id,
+    let tokens: Vec<Token> = task_ids
+        .iter()
+        .map(|&(task_id,)| Token {
+            task_id,
             trigger_datetime,
-        };
-        updates::send_token_update(req.get_channel(), ProcessToken::Clear(token)).await?;
+        })
+        .collect();
+
+    token_history::record(&mut txn, &tokens, TokenEvent::Clear, Actor::Operator, None, None)
+        .await?;
+
+    audit::action("clear_tokens", "job_run")
+        .job(job_id, None)
+        .object(trigger_datetime.to_rfc3339())
+        .details(ClearTokens {
+            tokens_cleared: task_ids.len() as u64,
+        })
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    for token in tokens {
+        updates::send_token_update(req.get_broker(), ProcessToken::Clear(token)).await?;
     }
 
     let body = ClearTokens {
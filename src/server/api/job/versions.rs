@@ -0,0 +1,263 @@
+use super::{
+    apply_job, definition, get_job_project_id, secrets::check_external_secrets, send_job_updates,
+};
+use crate::server::api::{
+    auth,
+    paging::{list_response, Paging},
+    request_ext::RequestExt,
+    types::Job,
+    State,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use highnoon::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::info;
+use uuid::Uuid;
+
+/// a definition being stored as a new version of a job
+pub struct NewVersion<'a> {
+    pub definition_hash: &'a str,
+    pub definition_zstd: &'a [u8],
+    pub definition_size: i32,
+    pub author: Option<String>,
+    /// the version this one restores, if it's a rollback
+    pub rollback_of: Option<i32>,
+}
+
+/// Store a job's definition as its next version, and make it the job's current
+/// version. Applying the same definition again doesn't add a version, so jobs
+/// applied on every deploy only get a new version when they change.
+pub async fn record_version(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+    new: NewVersion<'_>,
+) -> Result<i32> {
+    let latest: Option<(i32, String)> = sqlx::query_as(
+        "SELECT version, definition_hash
+        FROM job_version
+        WHERE job_id = $1
+        ORDER BY version DESC
+        LIMIT 1",
+    )
+    .bind(job_id)
+    .fetch_optional(&mut *txn)
+    .await?;
+
+    let version = match latest {
+        Some((version, hash)) if hash == new.definition_hash => version,
+        latest => {
+            let version = latest.map(|(version, _)| version + 1).unwrap_or(1);
+
+            sqlx::query(
+                "INSERT INTO job_version(
+                    job_id, version, definition_hash, definition_zstd, definition_size,
+                    author, rollback_of, created_datetime
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, CURRENT_TIMESTAMP)",
+            )
+            .bind(job_id)
+            .bind(version)
+            .bind(new.definition_hash)
+            .bind(new.definition_zstd)
+            .bind(new.definition_size)
+            .bind(&new.author)
+            .bind(new.rollback_of)
+            .execute(&mut *txn)
+            .await?;
+
+            version
+        }
+    };
+
+    sqlx::query(
+        "UPDATE job
+        SET definition_version = $2
+        WHERE id = $1",
+    )
+    .bind(job_id)
+    .bind(version)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(version)
+}
+
+#[derive(Deserialize)]
+struct ListVersionsQuery {
+    /// only versions created before this, so `before=...&limit=1` is the
+    /// definition that was current at that time
+    before: Option<DateTime<Utc>>,
+}
+
+const LIST_VERSIONS_SORT: &[(&str, &str)] =
+    &[("version", "version"), ("created", "created_datetime")];
+
+#[derive(Serialize, sqlx::FromRow)]
+struct JobVersion {
+    version: i32,
+    definition_hash: String,
+    definition_size: i32,
+    author: Option<String>,
+    rollback_of: Option<i32>,
+    created_datetime: DateTime<Utc>,
+}
+
+/// every stored definition of a job, newest first
+pub async fn list_versions(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+    let query: ListVersionsQuery = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(LIST_VERSIONS_SORT, "-version")?;
+
+    auth::list().job(job_id, None).check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let filter = "FROM job_version
+        WHERE job_id = $1
+        AND ($2::TIMESTAMPTZ IS NULL OR created_datetime < $2)";
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(job_id)
+        .bind(query.before)
+        .fetch_one(&pool)
+        .await?;
+
+    let versions: Vec<JobVersion> = sqlx::query_as(&format!(
+        "SELECT
+            version,
+            definition_hash,
+            definition_size,
+            author,
+            rollback_of,
+            created_datetime
+        {filter}
+        ORDER BY {order_by}, version DESC
+        LIMIT $3
+        OFFSET $4"
+    ))
+    .bind(job_id)
+    .bind(query.before)
+    .bind(paging.limit(50))
+    .bind(paging.offset())
+    .fetch_all(&pool)
+    .await?;
+
+    list_response(versions, total)
+}
+
+#[derive(Serialize)]
+struct GetJobVersion {
+    #[serde(flatten)]
+    version: JobVersion,
+    /// whether the job is currently using this version
+    current: bool,
+    raw_definition: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct StoredVersion {
+    #[sqlx(flatten)]
+    version: JobVersion,
+    definition_zstd: Vec<u8>,
+}
+
+async fn load_version(
+    pool: &PgPool,
+    job_id: Uuid,
+    version: i32,
+) -> highnoon::Result<(JobVersion, String)> {
+    let row: Option<StoredVersion> = sqlx::query_as(
+        "SELECT
+            version,
+            definition_hash,
+            definition_size,
+            author,
+            rollback_of,
+            created_datetime,
+            definition_zstd
+        FROM job_version
+        WHERE job_id = $1
+        AND version = $2",
+    )
+    .bind(job_id)
+    .bind(version)
+    .fetch_optional(pool)
+    .await?;
+
+    let stored =
+        row.ok_or_else(|| highnoon::Error::http((StatusCode::NOT_FOUND, "job version not found")))?;
+
+    let raw_definition =
+        definition::decompress(None, Some(&stored.definition_zstd))?.unwrap_or_default();
+
+    Ok((stored.version, raw_definition))
+}
+
+/// a single version of a job, with its definition as it was submitted
+pub async fn get_version(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+    let version = req.param("version")?.parse::<i32>()?;
+
+    auth::get().job(job_id, None).check(&req).await?;
+
+    let pool = req.get_pool();
+    let (version, raw_definition) = load_version(&pool, job_id, version).await?;
+
+    let (current,): (Option<i32>,) = sqlx::query_as(
+        "SELECT definition_version
+        FROM job
+        WHERE id = $1",
+    )
+    .bind(job_id)
+    .fetch_one(&pool)
+    .await?;
+
+    Response::ok().json(GetJobVersion {
+        current: current == Some(version.version),
+        version,
+        raw_definition,
+    })
+}
+
+#[derive(Serialize)]
+struct RollbackReply {
+    /// the job's version after the rollback, a copy of the one rolled back to
+    version: i32,
+}
+
+/// Apply an earlier version of a job again. This adds a new version rather than
+/// removing the later ones, so the history still shows what was rolled back.
+pub async fn rollback(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id = req.param("id")?.parse::<Uuid>()?;
+    let version = req.param("version")?.parse::<i32>()?;
+
+    let pool = req.get_pool();
+    let project_id = get_job_project_id(&pool, job_id).await?;
+    auth::update().job(job_id, project_id).check(&req).await?;
+
+    let (_, raw_definition) = load_version(&pool, job_id, version).await?;
+    let job: Job = serde_json::from_str(&raw_definition)?;
+
+    // secrets may have been removed or moved since the version was applied
+    check_external_secrets(&req.state().config, &job).await?;
+
+    let mut txn = pool.begin().await?;
+
+    let applied = match apply_job(&req, &mut txn, job, Some(version), None).await? {
+        Some(applied) => applied,
+        None => return StatusCode::CONFLICT.into_response(),
+    };
+
+    txn.commit().await?;
+
+    let new_version = applied.version;
+    info!(?job_id, version, new_version, "rolled back job");
+
+    send_job_updates(&req, applied).await?;
+
+    Response::ok().json(RollbackReply {
+        version: new_version,
+    })
+}
@@ -1,18 +1,28 @@
 use crate::{config::Config, server::api::State};
 use anyhow::{format_err, Result};
-use highnoon::{Error, Request, StatusCode};
+use highnoon::{Error, Request, Response, StatusCode};
 use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::types::Uuid;
 use std::{
     fs,
     time::{Duration, SystemTime},
 };
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 const WATERWHEEL_ISSUER: &str = "waterwheel";
 const STASH_AUDIENCE: &str = "waterwheel.stash";
 const CONFIG_AUDIENCE: &str = "waterwheel.config";
+const LOGS_AUDIENCE: &str = "waterwheel.logs";
+const WORKER_AUDIENCE: &str = "waterwheel.worker";
+/// refresh tokens can only be exchanged for new stash tokens
+const STASH_REFRESH_AUDIENCE: &str = "waterwheel.stash.refresh";
+const SESSION_AUDIENCE: &str = "waterwheel.session";
+const LOGIN_AUDIENCE: &str = "waterwheel.login";
+
+/// how long a token is valid for, tasks that run longer use their refresh token
+const TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
@@ -22,16 +32,76 @@ pub struct Claims {
     exp: u64,
 }
 
-pub struct JwtKeys {
+/// the standard claims, and some of our own
+#[derive(Debug, Serialize, Deserialize)]
+struct ClaimsWith<T> {
+    #[serde(flatten)]
+    claims: Claims,
+    #[serde(flatten)]
+    extra: T,
+}
+
+/// a key that tokens can be verified with
+#[derive(Clone)]
+struct VerifyingKey {
+    kid: String,
     algorithm: Algorithm,
     decoding: DecodingKey,
+}
+
+/// The key tokens are signed with, and every key they're accepted from. Each
+/// key has an ID derived from it, set as the `kid` of the tokens it signs, so
+/// the same key has the same ID in every server and worker.
+#[derive(Clone)]
+pub struct JwtKeys {
+    kid: String,
+    algorithm: Algorithm,
     encoding: EncodingKey,
+    /// the signing key first, then any others that are still accepted
+    verifying: Vec<VerifyingKey>,
 }
 
 /// Loads the encryption/decryption keys used to verify access to the stash
 /// Prefers an RSA key pair if one is provided, otherwise will use an HMAC shared secret
-/// (which is easier to generate and share for local development)
+/// (which is easier to generate and share for local development).
+/// The `verify_*` keys are also accepted, so a key can be rotated by first adding
+/// the new one to those everywhere, then signing with it, then removing the old one.
 pub fn load_keys(config: &Config) -> Result<JwtKeys> {
+    let mut keys = load_signing_keys(config)?;
+
+    for secret in &config.verify_hmac_secrets {
+        keys.verifying.push(VerifyingKey {
+            kid: key_id(secret.as_bytes()),
+            algorithm: Algorithm::HS256,
+            decoding: DecodingKey::from_secret(secret.as_bytes()),
+        });
+    }
+
+    for pub_key_file in &config.verify_public_keys {
+        let pub_key = fs::read(pub_key_file)?;
+        keys.verifying.push(VerifyingKey {
+            kid: key_id(&pub_key),
+            algorithm: Algorithm::RS256,
+            decoding: DecodingKey::from_rsa_pem(&pub_key)?,
+        });
+    }
+
+    debug!(
+        kid = %keys.kid,
+        accepted = keys.verifying.len(),
+        "loaded JWT keys"
+    );
+
+    Ok(keys)
+}
+
+/// An ID for a key that doesn't give anything away about it. For a key pair
+/// it's derived from the public key, so it's the same wherever it's loaded.
+fn key_id(key: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(key))[..16].to_owned()
+}
+
+fn load_signing_keys(config: &Config) -> Result<JwtKeys> {
     match config.public_key.as_deref() {
         Some(pub_key_file) => {
             let priv_key_file = config
@@ -60,11 +130,17 @@ fn load_rsa_keys(pub_key_file: &str, priv_key_file: &str) -> Result<JwtKeys> {
 
     let pub_key = fs::read(pub_key_file)?;
     let priv_key = fs::read(priv_key_file)?;
+    let kid = key_id(&pub_key);
 
     Ok(JwtKeys {
+        kid: kid.clone(),
         algorithm: Algorithm::RS256,
-        decoding: DecodingKey::from_rsa_pem(&pub_key)?,
         encoding: EncodingKey::from_rsa_pem(&priv_key)?,
+        verifying: vec![VerifyingKey {
+            kid,
+            algorithm: Algorithm::RS256,
+            decoding: DecodingKey::from_rsa_pem(&pub_key)?,
+        }],
     })
 }
 
@@ -72,10 +148,17 @@ fn load_rsa_keys(pub_key_file: &str, priv_key_file: &str) -> Result<JwtKeys> {
 fn load_hmac_secret(secret: &str) -> Result<JwtKeys> {
     debug!("using HMAC for stash keys");
 
+    let kid = key_id(secret.as_bytes());
+
     Ok(JwtKeys {
+        kid: kid.clone(),
         algorithm: Algorithm::HS256,
-        decoding: DecodingKey::from_secret(secret.as_bytes()),
         encoding: EncodingKey::from_secret(secret.as_bytes()),
+        verifying: vec![VerifyingKey {
+            kid,
+            algorithm: Algorithm::HS256,
+            decoding: DecodingKey::from_secret(secret.as_bytes()),
+        }],
     })
 }
 
@@ -88,25 +171,121 @@ pub fn generate_config_jwt(keys: &JwtKeys, id: Uuid) -> Result<String> {
     generate_jwt(keys, CONFIG_AUDIENCE.to_owned(), id.to_string())
 }
 
+/// lets a worker send a task run's logs to the server, tasks don't get these
+pub fn generate_logs_jwt(keys: &JwtKeys, task_run_id: Uuid) -> Result<String> {
+    generate_jwt(keys, LOGS_AUDIENCE.to_owned(), task_run_id.to_string())
+}
+
+/// lets a worker call the internal endpoints about itself, eg. heartbeats
+pub fn generate_worker_jwt(keys: &JwtKeys, worker_id: Uuid) -> Result<String> {
+    generate_jwt(keys, WORKER_AUDIENCE.to_owned(), worker_id.to_string())
+}
+
+/// A long-lived token a task can exchange for new stash tokens, until it's
+/// killed at its deadline.
+pub fn generate_stash_refresh_jwt(
+    keys: &JwtKeys,
+    task_id: &str,
+    expires: SystemTime,
+) -> Result<String> {
+    encode_jwt(
+        keys,
+        STASH_REFRESH_AUDIENCE.to_owned(),
+        task_id.to_owned(),
+        expires,
+    )
+}
+
+/// the session of someone who has logged in, see `oidc.rs`
+pub fn generate_session_jwt<T: Serialize>(
+    keys: &JwtKeys,
+    principal: &str,
+    lifetime: Duration,
+    session: &T,
+) -> Result<String> {
+    encode_jwt_with(
+        keys,
+        SESSION_AUDIENCE.to_owned(),
+        principal.to_owned(),
+        SystemTime::now() + lifetime,
+        session,
+    )
+}
+
+/// returns the principal and the session
+pub fn validate_session_jwt<T: DeserializeOwned>(keys: &JwtKeys, jwt: &str) -> Result<(String, T)> {
+    let token: ClaimsWith<T> = validate_jwt(keys, jwt, SESSION_AUDIENCE)?;
+    Ok((token.claims.sub, token.extra))
+}
+
+/// a login in progress, its subject is the `state` sent to the identity provider
+pub fn generate_login_jwt<T: Serialize>(
+    keys: &JwtKeys,
+    state: &str,
+    lifetime: Duration,
+    login: &T,
+) -> Result<String> {
+    encode_jwt_with(
+        keys,
+        LOGIN_AUDIENCE.to_owned(),
+        state.to_owned(),
+        SystemTime::now() + lifetime,
+        login,
+    )
+}
+
+/// returns the state and the login
+pub fn validate_login_jwt<T: DeserializeOwned>(keys: &JwtKeys, jwt: &str) -> Result<(String, T)> {
+    let token: ClaimsWith<T> = validate_jwt(keys, jwt, LOGIN_AUDIENCE)?;
+    Ok((token.claims.sub, token.extra))
+}
+
 pub fn generate_jwt(keys: &JwtKeys, aud: String, sub: String) -> Result<String> {
+    encode_jwt(keys, aud, sub, SystemTime::now() + TOKEN_LIFETIME)
+}
+
+fn encode_jwt(keys: &JwtKeys, aud: String, sub: String, expires: SystemTime) -> Result<String> {
     trace!("generating jwt for aud={} sub={}", aud, sub);
-    let header = Header::new(keys.algorithm);
+    sign_jwt(keys, &claims(aud, sub, expires)?)
+}
 
-    let claims = Claims {
+fn encode_jwt_with<T: Serialize>(
+    keys: &JwtKeys,
+    aud: String,
+    sub: String,
+    expires: SystemTime,
+    extra: &T,
+) -> Result<String> {
+    trace!("generating jwt for aud={} sub={}", aud, sub);
+    sign_jwt(
+        keys,
+        &ClaimsWith {
+            claims: claims(aud, sub, expires)?,
+            extra,
+        },
+    )
+}
+
+fn claims(aud: String, sub: String, expires: SystemTime) -> Result<Claims> {
+    Ok(Claims {
         iss: WATERWHEEL_ISSUER.to_owned(),
         sub,
         aud,
-        exp: (SystemTime::now() + Duration::from_secs(5 * 60))
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs(),
-    };
+        exp: expires.duration_since(SystemTime::UNIX_EPOCH)?.as_secs(),
+    })
+}
+
+fn sign_jwt<T: Serialize>(keys: &JwtKeys, claims: &T) -> Result<String> {
+    let mut header = Header::new(keys.algorithm);
+    header.kid = Some(keys.kid.clone());
 
-    let token = jsonwebtoken::encode(&header, &claims, &keys.encoding)?;
+    let token = jsonwebtoken::encode(&header, claims, &keys.encoding)?;
     Ok(token)
 }
 
 pub fn validate_stash_jwt(keys: &JwtKeys, jwt: &str) -> Result<String> {
-    validate_jwt(keys, jwt, STASH_AUDIENCE)
+    let claims: Claims = validate_jwt(keys, jwt, STASH_AUDIENCE)?;
+    Ok(claims.sub)
 }
 
 pub fn validate_config_jwt(req: &Request<State>, id: Uuid) -> highnoon::Result<String> {
@@ -118,7 +297,8 @@ pub fn validate_config_jwt(req: &Request<State>, id: Uuid) -> highnoon::Result<S
 
     let keys = &req.state().jwt_keys;
 
-    let sub = validate_jwt(keys, bearer.0.token(), CONFIG_AUDIENCE)?;
+    let claims: Claims = validate_jwt(keys, bearer.0.token(), CONFIG_AUDIENCE)?;
+    let sub = claims.sub;
     if sub != id.to_string() {
         Err(Error::http(StatusCode::FORBIDDEN))
     } else {
@@ -126,12 +306,149 @@ pub fn validate_config_jwt(req: &Request<State>, id: Uuid) -> highnoon::Result<S
     }
 }
 
-fn validate_jwt(keys: &JwtKeys, jwt: &str, aud: &str) -> Result<String> {
-    let mut validation = Validation::new(keys.algorithm);
-    validation.set_audience(&[aud]);
-    validation.set_issuer(&[WATERWHEEL_ISSUER]);
+pub fn validate_logs_jwt(req: &Request<State>, task_run_id: Uuid) -> highnoon::Result<()> {
+    use highnoon::headers::{authorization::Bearer, Authorization};
+
+    let bearer = req
+        .header::<Authorization<Bearer>>()
+        .ok_or_else(|| Error::http(StatusCode::FORBIDDEN))?;
+
+    let keys = &req.state().jwt_keys;
+
+    let claims: Claims = validate_jwt(keys, bearer.0.token(), LOGS_AUDIENCE)?;
+    if claims.sub != task_run_id.to_string() {
+        Err(Error::http(StatusCode::FORBIDDEN))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check the caller is a worker, and the one it says it is if `worker_id` is
+/// given. Returns the caller's worker id.
+pub fn validate_worker_jwt(
+    req: &Request<State>,
+    worker_id: Option<Uuid>,
+) -> highnoon::Result<Uuid> {
+    use highnoon::headers::{authorization::Bearer, Authorization};
+
+    let bearer = req
+        .header::<Authorization<Bearer>>()
+        .ok_or_else(|| Error::http(StatusCode::FORBIDDEN))?;
+
+    let keys = &req.state().jwt_keys;
+
+    let claims: Claims = validate_jwt(keys, bearer.0.token(), WORKER_AUDIENCE)?;
+    let caller = claims
+        .sub
+        .parse::<Uuid>()
+        .map_err(|_| Error::http(StatusCode::FORBIDDEN))?;
+
+    match worker_id {
+        Some(worker_id) if worker_id != caller => Err(Error::http(StatusCode::FORBIDDEN)),
+        _ => Ok(caller),
+    }
+}
+
+/// Validate a token with the key named by its `kid`. Tokens without one were
+/// signed before keys had IDs, so every key is tried.
+fn validate_jwt<T: DeserializeOwned>(keys: &JwtKeys, jwt: &str, aud: &str) -> Result<T> {
+    let header = jsonwebtoken::decode_header(jwt)?;
 
-    let token: TokenData<Claims> = jsonwebtoken::decode(jwt, &keys.decoding, &validation)?;
+    let mut candidates = keys
+        .verifying
+        .iter()
+        .filter(|key| key.algorithm == header.alg)
+        .filter(|key| header.kid.as_ref().map_or(true, |kid| *kid == key.kid))
+        .peekable();
 
-    Ok(token.claims.sub)
+    if candidates.peek().is_none() {
+        return Err(format_err!(
+            "token signed with an unknown key (kid={:?}, alg={:?})",
+            header.kid,
+            header.alg
+        ));
+    }
+
+    let mut error = None;
+    for key in candidates {
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_audience(&[aud]);
+        validation.set_issuer(&[WATERWHEEL_ISSUER]);
+
+        match jsonwebtoken::decode::<T>(jwt, &key.decoding, &validation) {
+            Ok(TokenData { claims, .. }) => return Ok(claims),
+            Err(err) => error = Some(err),
+        }
+    }
+
+    Err(error.expect("at least one key was tried").into())
+}
+
+#[derive(Serialize)]
+struct RefreshReply {
+    token: String,
+    /// a replacement for the refresh token, signed with the current key
+    refresh_token: String,
+}
+
+/// Exchange a task's refresh token for a new stash token. The refresh token is
+/// replaced too, keeping its expiry, so tasks move onto the current key and keep
+/// working once an old key is no longer accepted.
+pub async fn refresh(req: Request<State>) -> highnoon::Result<Response> {
+    use highnoon::headers::{authorization::Bearer, Authorization};
+
+    let bearer = req
+        .header::<Authorization<Bearer>>()
+        .ok_or_else(|| Error::http(StatusCode::UNAUTHORIZED))?;
+
+    let keys = &req.state().jwt_keys;
+
+    let claims =
+        validate_jwt::<Claims>(keys, bearer.0.token(), STASH_REFRESH_AUDIENCE).map_err(|err| {
+            warn!("error validating refresh JWT: {}", err);
+            Error::http(StatusCode::UNAUTHORIZED)
+        })?;
+
+    let expires = SystemTime::UNIX_EPOCH + Duration::from_secs(claims.exp);
+
+    Response::ok().json(RefreshReply {
+        token: generate_stash_jwt(keys, &claims.sub)?,
+        refresh_token: generate_stash_refresh_jwt(keys, &claims.sub, expires)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotation() -> Result<()> {
+        let old = load_hmac_secret("old")?;
+        let mut new = load_hmac_secret("new")?;
+
+        let old_token = generate_stash_jwt(&old, "task")?;
+        let new_token = generate_stash_jwt(&new, "task")?;
+
+        assert!(validate_stash_jwt(&new, &old_token).is_err());
+
+        new.verifying.extend(old.verifying.clone());
+        assert_eq!(validate_stash_jwt(&new, &old_token)?, "task");
+        assert_eq!(validate_stash_jwt(&new, &new_token)?, "task");
+        assert!(validate_stash_jwt(&old, &new_token).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_audience() -> Result<()> {
+        let keys = load_hmac_secret("secret")?;
+        let expires = SystemTime::now() + Duration::from_secs(3600);
+        let refresh = generate_stash_refresh_jwt(&keys, "task", expires)?;
+
+        assert!(validate_stash_jwt(&keys, &refresh).is_err());
+        let claims: Claims = validate_jwt(&keys, &refresh, STASH_REFRESH_AUDIENCE)?;
+        assert_eq!(claims.sub, "task");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,34 @@
+use crate::server::api::State;
+use highnoon::{filter::Next, Request, Response, StatusCode};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+/// Reject requests with a 503 while too many are already in progress, so a flood
+/// of requests fails fast instead of queueing up behind each other.
+pub struct ConcurrencyLimit {
+    permits: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max_requests: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_requests)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl highnoon::filter::Filter<State> for ConcurrencyLimit {
+    async fn apply(&self, req: Request<State>, next: Next<'_, State>) -> highnoon::Result<Response> {
+        let _permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!(uri=%req.uri(), "too many requests in progress, rejecting");
+                return Ok(Response::status(StatusCode::SERVICE_UNAVAILABLE));
+            }
+        };
+
+        next.next(req).await
+    }
+}
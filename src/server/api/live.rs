@@ -0,0 +1,81 @@
+use crate::{
+    broker::{Broker, Topic},
+    messages::LiveUpdate,
+    server::api::{auth, State},
+};
+use anyhow::Result;
+use futures::TryStreamExt;
+use highnoon::{
+    ws::{WebSocketReceiver, WebSocketSender},
+    Message, Request,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, trace, warn};
+use uuid::Uuid;
+
+/// how many updates a slow client can fall behind by before it starts missing them
+pub const LIVE_BUFFER: usize = 1024;
+
+/// Consume the live updates from every scheduler and API process and hand them to
+/// the websockets connected to this one.
+pub async fn consume(ctx: (Arc<dyn Broker>, broadcast::Sender<LiveUpdate>)) -> Result<!> {
+    let (broker, live_tx) = ctx;
+
+    let mut updates = broker.subscribe(Topic::Live).await?;
+
+    while let Some(data) = updates.try_next().await? {
+        match serde_json::from_slice::<LiveUpdate>(&data) {
+            Ok(update) => {
+                // an error only means no one is connected right now
+                let _ = live_tx.send(update);
+            }
+            Err(err) => warn!("ignoring invalid live update: {}", err),
+        }
+    }
+
+    anyhow::bail!("live update consumer stopped consuming")
+}
+
+#[derive(Deserialize)]
+struct UpdatesQuery {
+    /// only send updates about this job
+    job_id: Option<Uuid>,
+}
+
+/// Push token states, finished runs and worker heartbeats to the client as JSON
+/// text messages, so the UI doesn't have to poll for them.
+pub async fn updates(
+    req: Request<State>,
+    mut tx: WebSocketSender,
+    mut _rx: WebSocketReceiver,
+) -> highnoon::Result<()> {
+    let q: UpdatesQuery = req.query()?;
+
+    match q.job_id {
+        Some(job_id) => auth::get().job(job_id, None).check(&req).await?,
+        None => auth::list().kind("updates").check(&req).await?,
+    }
+
+    let mut live_rx = req.state().live_tx.subscribe();
+
+    debug!(job_id=?q.job_id, "client watching live updates");
+    loop {
+        let update = match live_rx.recv().await {
+            Ok(update) => update,
+            Err(RecvError::Lagged(missed)) => {
+                trace!(missed, "client fell behind on live updates");
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        if q.job_id.is_some() && update.job_id() != q.job_id {
+            continue;
+        }
+
+        tx.send(Message::text(serde_json::to_string(&update)?))
+            .await?;
+    }
+}
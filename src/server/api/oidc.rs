@@ -0,0 +1,546 @@
+use crate::server::api::{jwt, request_ext::RequestExt, roles::Role, State};
+use highnoon::{
+    headers::{self, authorization::Bearer, Authorization, Header, HeaderName, HeaderValue},
+    Error, Request, Response, StatusCode,
+};
+use jsonwebtoken::{DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use url::Url;
+use uuid::Uuid;
+
+/// holds the session JWT issued after logging in
+const SESSION_COOKIE: &str = "waterwheel_session";
+
+/// holds the state of a login in progress, between the redirect to the identity
+/// provider and the callback from it
+const LOGIN_COOKIE: &str = "waterwheel_login";
+
+/// how long someone has to log in at the identity provider
+const LOGIN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+static LOCATION: Lazy<HeaderName> = Lazy::new(|| HeaderName::from_static("location"));
+static SET_COOKIE: Lazy<HeaderName> = Lazy::new(|| HeaderName::from_static("set-cookie"));
+
+/// A logged in user, from the claims of their session JWT. The subject of the
+/// JWT is the principal.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub name: Option<String>,
+    pub groups: Vec<String>,
+    /// roles given by the user's groups, checked as well as any bound to them
+    pub roles: Vec<SessionRole>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionRole {
+    /// the project the role is in, or `None` for a global role
+    pub project_id: Option<Uuid>,
+    pub role: Role,
+}
+
+impl Session {
+    /// the session's roles that apply to a project, with whether each is global
+    pub fn roles_in(&self, project_id: Option<Uuid>) -> impl Iterator<Item = (Role, bool)> + '_ {
+        self.roles
+            .iter()
+            .filter(move |r| r.project_id.is_none() || r.project_id == project_id)
+            .map(|r| (r.role, r.project_id.is_none()))
+    }
+}
+
+/// The session a request was made with, from the session cookie or a bearer
+/// token, or `None` if there isn't a valid one.
+pub fn session(req: &Request<State>) -> Option<(String, Session)> {
+    let token = cookie(req, SESSION_COOKIE).or_else(|| {
+        req.header::<Authorization<Bearer>>()
+            .map(|header| header.0.token().to_owned())
+    })?;
+
+    match jwt::validate_session_jwt(&req.state().jwt_keys, &token) {
+        Ok(session) => Some(session),
+        Err(err) => {
+            debug!("not a valid session: {}", err);
+            None
+        }
+    }
+}
+
+fn cookie(req: &Request<State>, name: &str) -> Option<String> {
+    req.header::<headers::Cookie>()
+        .and_then(|cookie| cookie.get(name).map(str::to_owned))
+}
+
+/// the parts of the identity provider's discovery document that are used
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+    jwks_uri: Url,
+}
+
+/// where to send someone to log in, and how to check what comes back
+struct Provider {
+    metadata: ProviderMetadata,
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+}
+
+/// Look up the identity provider's endpoints. This is only done when someone
+/// logs in, so isn't cached.
+async fn provider(req: &Request<State>) -> highnoon::Result<Provider> {
+    let config = &req.state().config;
+
+    let (issuer, client_id, client_secret) = match (
+        &config.oidc_issuer,
+        &config.oidc_client_id,
+        &config.oidc_client_secret,
+    ) {
+        (Some(issuer), Some(client_id), Some(client_secret)) => (issuer, client_id, client_secret),
+        _ => {
+            return Err(Error::http((
+                StatusCode::NOT_FOUND,
+                "OIDC login is not configured",
+            )))
+        }
+    };
+
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.as_str().trim_end_matches('/')
+    );
+    let metadata: ProviderMetadata = reqwest::get(url).await?.error_for_status()?.json().await?;
+
+    Ok(Provider {
+        metadata,
+        client_id: client_id.clone(),
+        client_secret: client_secret.clone(),
+        redirect_uri: format!("{}auth/callback", config.server_addr),
+    })
+}
+
+fn random_hex() -> String {
+    let mut bytes = [0u8; 16];
+    thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Only redirect back to paths on this server, so the login can't be used to
+/// send someone elsewhere.
+fn safe_redirect(redirect: Option<&str>) -> String {
+    match redirect {
+        Some(path) if path.starts_with('/') && !path.starts_with("//") && !path.contains('\\') => {
+            path.to_owned()
+        }
+        _ => "/".to_owned(),
+    }
+}
+
+/// a login in progress, kept in a signed cookie
+#[derive(Serialize, Deserialize)]
+struct Login {
+    nonce: String,
+    redirect: String,
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    /// where to go once logged in
+    redirect: Option<String>,
+}
+
+/// start logging in by sending the browser to the identity provider
+pub async fn login(req: Request<State>) -> highnoon::Result<Response> {
+    let query: LoginQuery = req.query()?;
+    let provider = provider(&req).await?;
+    let config = &req.state().config;
+
+    let state = random_hex();
+    let login = Login {
+        nonce: random_hex(),
+        redirect: safe_redirect(query.redirect.as_deref()),
+    };
+    let login_jwt = jwt::generate_login_jwt(&req.state().jwt_keys, &state, LOGIN_LIFETIME, &login)?;
+
+    let mut url = provider.metadata.authorization_endpoint;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &provider.redirect_uri)
+        .append_pair("scope", &config.oidc_scopes)
+        .append_pair("state", &state)
+        .append_pair("nonce", &login.nonce);
+
+    Ok(redirect(url.as_str()).header(SetCookies(vec![set_cookie(
+        &req,
+        LOGIN_COOKIE,
+        &login_jwt,
+        LOGIN_LIFETIME,
+    )])))
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenReply {
+    id_token: String,
+}
+
+/// The identity provider sends the browser back here with a code, which is
+/// exchanged for an ID token. Its claims become a session JWT in a cookie.
+pub async fn callback(req: Request<State>) -> highnoon::Result<Response> {
+    let query: CallbackQuery = req.query()?;
+
+    if let Some(error) = query.error {
+        warn!(%error, description=?query.error_description, "OIDC login failed");
+        return Err(Error::http((
+            StatusCode::UNAUTHORIZED,
+            format!("login failed: {}", query.error_description.unwrap_or(error)),
+        )));
+    }
+
+    let (code, state) = match (query.code, query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(Error::bad_request("missing code or state")),
+    };
+
+    let keys = &req.state().jwt_keys;
+    let login: Login = cookie(&req, LOGIN_COOKIE)
+        .and_then(|token| jwt::validate_login_jwt(keys, &token).ok())
+        .filter(|(login_state, _)| *login_state == state)
+        .map(|(_, login)| login)
+        .ok_or_else(|| Error::http((StatusCode::UNAUTHORIZED, "login expired, try again")))?;
+
+    let provider = provider(&req).await?;
+
+    let reply: TokenReply = reqwest::Client::new()
+        .post(provider.metadata.token_endpoint.clone())
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("client_secret", provider.client_secret.as_str()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let claims = validate_id_token(&provider, &reply.id_token, &login.nonce)
+        .await
+        .map_err(|err| {
+            warn!("invalid ID token: {}", err);
+            Error::http((StatusCode::UNAUTHORIZED, "invalid ID token"))
+        })?;
+
+    let config = &req.state().config;
+    let principal = claims
+        .get(&config.oidc_principal_claim)
+        .or_else(|| claims.get("sub"))
+        .and_then(JsonValue::as_str)
+        .ok_or_else(|| Error::http((StatusCode::UNAUTHORIZED, "ID token has no subject")))?
+        .to_owned();
+
+    let groups: Vec<String> = claims
+        .get(&config.oidc_groups_claim)
+        .and_then(JsonValue::as_array)
+        .map(|groups| {
+            groups
+                .iter()
+                .filter_map(|group| group.as_str().map(str::to_owned))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let session = Session {
+        name: claims
+            .get("name")
+            .and_then(JsonValue::as_str)
+            .map(str::to_owned),
+        roles: group_roles(&req.get_pool(), &config.oidc_group_roles, &groups).await?,
+        groups,
+    };
+
+    let lifetime = Duration::from_secs(config.session_lifetime);
+    let session_jwt = jwt::generate_session_jwt(keys, &principal, lifetime, &session)?;
+
+    info!(%principal, groups=?session.groups, roles=?session.roles, "logged in");
+
+    Ok(redirect(&login.redirect).header(SetCookies(vec![
+        set_cookie(&req, SESSION_COOKIE, &session_jwt, lifetime),
+        set_cookie(&req, LOGIN_COOKIE, "", Duration::ZERO),
+    ])))
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+/// Check the ID token was signed by the identity provider, for us, for this login.
+async fn validate_id_token(
+    provider: &Provider,
+    id_token: &str,
+    nonce: &str,
+) -> anyhow::Result<Map<String, JsonValue>> {
+    let header = jsonwebtoken::decode_header(id_token)?;
+
+    let jwks: Jwks = reqwest::get(provider.metadata.jwks_uri.clone())
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let jwk = jwks
+        .keys
+        .iter()
+        .filter(|jwk| jwk.kty == "RSA")
+        .find(|jwk| header.kid.is_none() || jwk.kid == header.kid)
+        .ok_or_else(|| anyhow::format_err!("no RSA key with kid {:?}", header.kid))?;
+
+    let key = match (&jwk.n, &jwk.e) {
+        (Some(n), Some(e)) => DecodingKey::from_rsa_components(n, e)?,
+        _ => anyhow::bail!("RSA key {:?} is missing its modulus or exponent", jwk.kid),
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&provider.client_id]);
+    validation.set_issuer(&[&provider.metadata.issuer]);
+
+    let claims =
+        jsonwebtoken::decode::<Map<String, JsonValue>>(id_token, &key, &validation)?.claims;
+
+    if claims.get("nonce").and_then(JsonValue::as_str) != Some(nonce) {
+        anyhow::bail!("nonce doesn't match the login");
+    }
+
+    Ok(claims)
+}
+
+/// a role given to the members of a group
+#[derive(Debug, PartialEq, Eq)]
+struct GroupRole<'a> {
+    group: &'a str,
+    /// the name of the project, or `None` for a global role
+    project: Option<&'a str>,
+    role: Role,
+}
+
+/// Parse a mapping like `platform=admin` or `data-eng=analytics:operator`. The
+/// group is everything before the last `=`, since LDAP groups contain them.
+fn parse_group_role(mapping: &str) -> Option<GroupRole<'_>> {
+    let (group, role) = mapping.rsplit_once('=')?;
+    let (project, role) = match role.split_once(':') {
+        Some((project, role)) => (Some(project), role),
+        None => (None, role),
+    };
+
+    let role = serde_json::from_value(JsonValue::String(role.to_owned())).ok()?;
+
+    Some(GroupRole {
+        group,
+        project,
+        role,
+    })
+}
+
+/// The roles the mappings give to someone in these groups. Projects are looked
+/// up by name now, so a project created later only applies from the next login.
+async fn group_roles(
+    pool: &PgPool,
+    mappings: &[String],
+    groups: &[String],
+) -> highnoon::Result<Vec<SessionRole>> {
+    let mut roles = Vec::new();
+
+    for mapping in mappings {
+        let group_role = match parse_group_role(mapping) {
+            Some(group_role) => group_role,
+            None => {
+                warn!(%mapping, "invalid OIDC group role, expected <group>=[<project>:]<role>");
+                continue;
+            }
+        };
+
+        if !groups.iter().any(|group| group == group_role.group) {
+            continue;
+        }
+
+        let project_id = match group_role.project {
+            Some(project) => {
+                let row: Option<(Uuid,)> = sqlx::query_as("SELECT id FROM project WHERE name = $1")
+                    .bind(project)
+                    .fetch_optional(pool)
+                    .await?;
+                match row {
+                    Some((id,)) => Some(id),
+                    None => {
+                        warn!(%mapping, "OIDC group role is for a project that doesn't exist");
+                        continue;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let role = SessionRole {
+            project_id,
+            role: group_role.role,
+        };
+        if !roles.contains(&role) {
+            roles.push(role);
+        }
+    }
+
+    Ok(roles)
+}
+
+#[derive(Serialize)]
+struct SessionReply {
+    principal: String,
+    #[serde(flatten)]
+    session: Session,
+}
+
+/// who is logged in, for the UI
+pub async fn get_session(req: Request<State>) -> highnoon::Result<Response> {
+    match session(&req) {
+        Some((principal, session)) => Response::ok().json(SessionReply { principal, session }),
+        None => Ok(Response::status(StatusCode::UNAUTHORIZED)),
+    }
+}
+
+pub async fn logout(req: Request<State>) -> highnoon::Result<Response> {
+    Ok(redirect("/").header(SetCookies(vec![set_cookie(
+        &req,
+        SESSION_COOKIE,
+        "",
+        Duration::ZERO,
+    )])))
+}
+
+pub(super) fn redirect(location: &str) -> Response {
+    Response::status(StatusCode::FOUND).header(Location(location.to_owned()))
+}
+
+/// Cookies are only marked secure when the server is served over HTTPS, so
+/// logging in still works locally.
+fn set_cookie(req: &Request<State>, name: &str, value: &str, max_age: Duration) -> String {
+    let secure = if req.state().config.server_addr.starts_with("https:") {
+        "; Secure"
+    } else {
+        ""
+    };
+
+    format!(
+        "{name}={value}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{secure}",
+        max_age.as_secs()
+    )
+}
+
+struct Location(String);
+
+impl Header for Location {
+    fn name() -> &'static HeaderName {
+        &LOCATION
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(
+        values: &mut I,
+    ) -> Result<Self, headers::Error> {
+        values
+            .next()
+            .and_then(|value| value.to_str().ok())
+            .map(|value| Location(value.to_owned()))
+            .ok_or_else(headers::Error::invalid)
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(HeaderValue::from_str(&self.0).ok());
+    }
+}
+
+struct SetCookies(Vec<String>);
+
+impl Header for SetCookies {
+    fn name() -> &'static HeaderName {
+        &SET_COOKIE
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(
+        values: &mut I,
+    ) -> Result<Self, headers::Error> {
+        Ok(SetCookies(
+            values
+                .filter_map(|value| value.to_str().ok())
+                .map(str::to_owned)
+                .collect(),
+        ))
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(
+            self.0
+                .iter()
+                .filter_map(|cookie| HeaderValue::from_str(cookie).ok()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_group_role() {
+        assert_eq!(
+            parse_group_role("platform=admin"),
+            Some(GroupRole {
+                group: "platform",
+                project: None,
+                role: Role::Admin
+            })
+        );
+        assert_eq!(
+            parse_group_role("cn=data,ou=groups=analytics:operator"),
+            Some(GroupRole {
+                group: "cn=data,ou=groups",
+                project: Some("analytics"),
+                role: Role::Operator
+            })
+        );
+        assert_eq!(parse_group_role("platform=owner"), None);
+        assert_eq!(parse_group_role("platform"), None);
+    }
+
+    #[test]
+    fn test_safe_redirect() {
+        assert_eq!(safe_redirect(Some("/jobs/1")), "/jobs/1");
+        assert_eq!(safe_redirect(Some("//evil.example")), "/");
+        assert_eq!(safe_redirect(Some("https://evil.example")), "/");
+        assert_eq!(safe_redirect(None), "/");
+    }
+}
@@ -0,0 +1,110 @@
+use highnoon::{
+    headers::{self, Header, HeaderName, HeaderValue},
+    Response,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// most rows any list endpoint will return at once
+const MAX_LIMIT: i64 = 1000;
+
+static X_TOTAL_COUNT: Lazy<HeaderName> = Lazy::new(|| HeaderName::from_static("x-total-count"));
+
+/// Query parameters shared by the list endpoints: `limit`, `offset` and `sort`.
+/// `sort` is the name of a field, prefixed with `-` to sort in descending order.
+/// Parse it separately from the endpoint's own filters, since `flatten` doesn't
+/// work with query strings.
+#[derive(Deserialize, Default)]
+pub struct Paging {
+    limit: Option<i64>,
+    offset: Option<i64>,
+    sort: Option<String>,
+}
+
+impl Paging {
+    pub fn limit(&self, default: i64) -> i64 {
+        self.limit.unwrap_or(default).clamp(1, MAX_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    /// Turn `sort` into an ORDER BY clause. `columns` maps the names that can be
+    /// sorted on to their SQL expressions, so nothing from the query is put in the SQL.
+    pub fn order_by(&self, columns: &[(&str, &str)], default: &str) -> highnoon::Result<String> {
+        let sort = self.sort.as_deref().unwrap_or(default);
+        let (name, direction) = match sort.strip_prefix('-') {
+            Some(name) => (name, "DESC"),
+            None => (sort, "ASC"),
+        };
+
+        match columns.iter().find(|(column, _)| *column == name) {
+            Some((_, expr)) => Ok(format!("{expr} {direction}")),
+            None => {
+                let names: Vec<&str> = columns.iter().map(|(column, _)| *column).collect();
+                Err(highnoon::Error::bad_request(format!(
+                    "can't sort by '{name}', expected one of: {}",
+                    names.join(", ")
+                )))
+            }
+        }
+    }
+}
+
+/// the total number of rows matching a list request, before the limit and offset
+pub struct TotalCount(pub i64);
+
+impl Header for TotalCount {
+    fn name() -> &'static HeaderName {
+        &X_TOTAL_COUNT
+    }
+
+    fn decode<'i, I: Iterator<Item = &'i HeaderValue>>(values: &mut I) -> Result<Self, headers::Error> {
+        values
+            .next()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map(TotalCount)
+            .ok_or_else(headers::Error::invalid)
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(std::iter::once(HeaderValue::from(self.0)));
+    }
+}
+
+/// a list response, with the total count in the `X-Total-Count` header
+pub fn list_response(rows: impl Serialize, total: i64) -> highnoon::Result<Response> {
+    Response::ok().header(TotalCount(total)).json(rows)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const COLUMNS: &[(&str, &str)] = &[("name", "j.name"), ("paused", "j.paused")];
+
+    fn paging(sort: Option<&str>) -> Paging {
+        Paging {
+            sort: sort.map(str::to_owned),
+            ..Paging::default()
+        }
+    }
+
+    #[test]
+    fn test_order_by() {
+        assert_eq!(paging(None).order_by(COLUMNS, "name").unwrap(), "j.name ASC");
+        assert_eq!(paging(Some("-name")).order_by(COLUMNS, "name").unwrap(), "j.name DESC");
+        assert_eq!(paging(Some("paused")).order_by(COLUMNS, "name").unwrap(), "j.paused ASC");
+        assert!(paging(Some("1; DROP TABLE job")).order_by(COLUMNS, "name").is_err());
+    }
+
+    #[test]
+    fn test_limit_and_offset() {
+        assert_eq!(Paging::default().limit(50), 50);
+        assert_eq!(Paging { limit: Some(5000), ..Paging::default() }.limit(50), MAX_LIMIT);
+        assert_eq!(Paging { limit: Some(0), ..Paging::default() }.limit(50), 1);
+        assert_eq!(Paging { offset: Some(-3), ..Paging::default() }.offset(), 0);
+    }
+}
@@ -1,4 +1,11 @@
-use super::{auth, config_cache, request_ext::RequestExt, State};
+use super::{
+    audit, auth, config_cache,
+    job::{send_pause_updates, Paused},
+    paging::{list_response, Paging},
+    quota::{set_quotas, Quotas},
+    request_ext::RequestExt,
+    State,
+};
 use crate::{
     messages::ConfigUpdate,
     server::api::jwt,
@@ -6,7 +13,7 @@ use crate::{
 };
 use highnoon::{Json, Request, Responder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use serde_json::Value as JsonValue;
+use serde_json::{json, Value as JsonValue};
 use tracing::{info, warn};
 use uuid::Uuid;
 
@@ -16,6 +23,12 @@ struct NewProject {
     pub name: String,
     pub description: String,
     pub config: Option<JsonValue>,
+    /// only platform admins may set quotas, new projects get the configured defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quotas: Option<Quotas>,
+    /// principals that own the project, these replace any existing owners
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owners: Option<Vec<String>>,
 }
 
 pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
@@ -25,30 +38,78 @@ pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
 
     auth::update().project(id).check(&req).await?;
 
-    let res = sqlx::query(
+    if proj.quotas.is_some() {
+        auth::update().project(id).kind("quota").check(&req).await?;
+    }
+
+    let config = &req.state().config;
+    let default_config = config
+        .default_project_config
+        .as_deref()
+        .map(serde_json::from_str::<JsonValue>)
+        .transpose()?;
+
+    let pool = req.get_pool();
+    let mut txn = pool.begin().await?;
+
+    let before = project_for_audit(&mut txn, id).await?;
+
+    // xmax is only zero for newly inserted rows
+    let res: sqlx::Result<(bool,)> = sqlx::query_as(
         "INSERT INTO project(id, name, description, config)
-        VALUES($1, $2, $3, $4)
+        VALUES($1, $2, $3, COALESCE($4, $5))
         ON CONFLICT(id)
         DO UPDATE
         SET name = $2,
             description = $3,
-            config = COALESCE($4, project.config)",
+            config = COALESCE($4, project.config)
+        RETURNING (xmax = 0) AS inserted",
     )
     .bind(id)
     .bind(&proj.name)
     .bind(&proj.description)
     .bind(&proj.config)
-    .execute(&req.get_pool())
+    .bind(&default_config)
+    .fetch_one(&mut txn)
     .await;
 
     match pg_error(res)? {
-        Ok(_done) => {
+        Ok((inserted,)) => {
+            let quotas = match &proj.quotas {
+                Some(quotas) => Some(quotas.clone()),
+                None if inserted => Some(Quotas::default_from_config(config)),
+                None => None,
+            };
+            if let Some(quotas) = &quotas {
+                set_quotas(&mut txn, id, quotas).await?;
+            }
+
+            if let Some(owners) = &proj.owners {
+                set_owners(&mut txn, id, owners).await?;
+            }
+
+            let after = project_for_audit(&mut txn, id).await?;
+            let audit = if inserted {
+                audit::create("project")
+            } else {
+                audit::update("project")
+            };
+            audit
+                .project(id)
+                .diff(&before, &after)
+                .details(json!({ "quotas": &proj.quotas, "owners": &proj.owners }))
+                .record(&req, &mut txn)
+                .await?;
+
+            txn.commit().await?;
+
             info!("updated project {} -> {}", id, proj.name);
 
-            config_cache::send(req.get_channel(), ConfigUpdate::Project(id)).await?;
+            config_cache::send(req.get_broker(), ConfigUpdate::Project(id)).await?;
 
             let proj = NewProject {
                 uuid: Some(id),
+                quotas,
                 ..proj
             };
             (StatusCode::CREATED, Json(proj)).into_response()
@@ -68,11 +129,68 @@ pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
     }
 }
 
+/// the fields of a project that are compared in the audit log, or null if it doesn't exist
+async fn project_for_audit(
+    txn: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    project_id: Uuid,
+) -> highnoon::Result<JsonValue> {
+    let row: Option<(String, Option<String>, Option<JsonValue>)> = sqlx::query_as(
+        "SELECT name, description, config
+        FROM project
+        WHERE id = $1
+        FOR UPDATE",
+    )
+    .bind(project_id)
+    .fetch_optional(&mut *txn)
+    .await?;
+
+    Ok(match row {
+        Some((name, description, config)) => json!({
+            "name": name,
+            "description": description,
+            "config": config,
+        }),
+        None => JsonValue::Null,
+    })
+}
+
+async fn set_owners(
+    txn: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    project_id: Uuid,
+    owners: &[String],
+) -> highnoon::Result<()> {
+    sqlx::query(
+        "DELETE FROM project_owner
+        WHERE project_id = $1",
+    )
+    .bind(project_id)
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO project_owner(project_id, owner)
+        SELECT $1, UNNEST($2::VARCHAR[])
+        ON CONFLICT DO NOTHING",
+    )
+    .bind(project_id)
+    .bind(owners)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
 struct QueryProject {
     pub name: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct ListProjectQuery {
+    /// only projects with names containing this
+    q: Option<String>,
+}
+
 #[derive(Serialize, sqlx::FromRow)]
 struct ListProject {
     pub id: Uuid,
@@ -83,16 +201,34 @@ struct ListProject {
 pub async fn list(req: Request<State>) -> highnoon::Result<Response> {
     auth::list().project(None).check(&req).await?;
 
-    let projects: Vec<ListProject> = sqlx::query_as(
+    let query: ListProjectQuery = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(&[("name", "name")], "name")?;
+
+    let filter = "FROM project
+        WHERE ($1::VARCHAR IS NULL OR STRPOS(LOWER(name), LOWER($1)) > 0)";
+
+    let pool = req.get_pool();
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(&query.q)
+        .fetch_one(&pool)
+        .await?;
+
+    let projects: Vec<ListProject> = sqlx::query_as(&format!(
         "SELECT id, name, description
-        FROM project
-        ORDER BY name
-        LIMIT 100",
-    )
-    .fetch_all(&req.get_pool())
+        {filter}
+        ORDER BY {order_by}
+        LIMIT $2
+        OFFSET $3"
+    ))
+    .bind(&query.q)
+    .bind(paging.limit(100))
+    .bind(paging.offset())
+    .fetch_all(&pool)
     .await?;
 
-    Json(projects).into_response()
+    list_response(projects, total)
 }
 
 pub async fn get_by_name(req: Request<State>) -> highnoon::Result<Response> {
@@ -126,6 +262,7 @@ struct ProjectExtra {
     pub name: String,
     pub description: String,
     pub num_jobs: i64,
+    pub paused: bool,
     // TODO - harmonise these with the ListProject call
     pub running_tasks: i64,
     pub waiting_tasks: i64,
@@ -159,6 +296,7 @@ pub async fn get_by_id(req: Request<State>) -> highnoon::Result<Response> {
                 FROM job j
                 WHERE j.project_id = $1
             ) AS num_jobs,
+            paused,
             (
                 SELECT COUNT(1)
                 FROM these_tasks t
@@ -244,6 +382,10 @@ pub async fn delete(req: Request<State>) -> highnoon::Result<StatusCode> {
         Ok(done) => {
             if done.rows_affected() == 1 {
                 info!("deleted project {}", id);
+                audit::delete("project")
+                    .project(id)
+                    .record(&req, &req.get_pool())
+                    .await?;
                 Ok(StatusCode::NO_CONTENT)
             } else {
                 info!("no project with id {}", id);
@@ -257,13 +399,121 @@ pub async fn delete(req: Request<State>) -> highnoon::Result<StatusCode> {
     }
 }
 
+pub async fn get_paused(req: Request<State>) -> highnoon::Result<Response> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+
+    let row: Option<(bool,)> = sqlx::query_as(
+        "SELECT paused
+        FROM project
+        WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(&req.get_pool())
+    .await?;
+
+    match row {
+        Some((paused,)) => {
+            auth::get().project(id).check(&req).await?;
+            Response::ok().json(paused)
+        }
+        None => Ok(Response::status(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// Pause every job in a project at once, eg. during an incident. Jobs that were
+/// already paused are remembered, so resuming the project leaves them paused.
+pub async fn set_paused(mut req: Request<State>) -> highnoon::Result<StatusCode> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+
+    auth::update().project(id).kind("pause").check(&req).await?;
+
+    let Paused { paused } = req.body_json().await?;
+
+    let mut txn = req.get_pool().begin().await?;
+
+    let row: Option<(bool,)> = sqlx::query_as(
+        "UPDATE project p
+        SET paused = $2
+        FROM (SELECT paused FROM project WHERE id = $1 FOR UPDATE) old
+        WHERE p.id = $1
+        RETURNING old.paused",
+    )
+    .bind(id)
+    .bind(paused)
+    .fetch_optional(&mut txn)
+    .await?;
+
+    let was_paused = match row {
+        Some((was_paused,)) => was_paused,
+        None => return Ok(StatusCode::NOT_FOUND),
+    };
+
+    // only the jobs this changes, a job paused by hand stays paused on resume
+    let job_ids: Vec<(Uuid,)> = if paused {
+        sqlx::query_as(
+            "UPDATE job
+            SET paused = TRUE,
+                paused_by_project = TRUE
+            WHERE project_id = $1
+            AND NOT paused
+            RETURNING id",
+        )
+        .bind(id)
+        .fetch_all(&mut txn)
+        .await?
+    } else {
+        sqlx::query_as(
+            "UPDATE job
+            SET paused = FALSE,
+                paused_by_project = FALSE
+            WHERE project_id = $1
+            AND paused_by_project
+            RETURNING id",
+        )
+        .bind(id)
+        .fetch_all(&mut txn)
+        .await?
+    };
+    let job_ids: Vec<Uuid> = job_ids.into_iter().map(|(id,)| id).collect();
+
+    info!(project_id=?id, paused, jobs=job_ids.len(), "set project paused");
+
+    audit::action(if paused { "pause" } else { "unpause" }, "project")
+        .project(id)
+        .diff(
+            &json!({ "paused": was_paused }),
+            &json!({ "paused": paused }),
+        )
+        .details(json!({ "jobs": job_ids }))
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    send_pause_updates(&req, &job_ids, paused).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[derive(Deserialize)]
 struct ListJobQuery {
-    limit: Option<i32>,
     after: Option<String>,
     name: Option<String>,
+    /// only jobs with names containing this
+    q: Option<String>,
+    paused: Option<bool>,
 }
 
+const LIST_JOB_SORT: &[(&str, &str)] = &[
+    ("name", "name"),
+    ("paused", "paused"),
+    ("success", "success"),
+    ("running", "running"),
+    ("failure", "failure"),
+    ("waiting", "waiting"),
+    ("error", "error"),
+];
+
 #[derive(Serialize, sqlx::FromRow)]
 struct ListJob {
     job_id: Uuid,
@@ -277,15 +527,35 @@ struct ListJob {
     error: i64,
 }
 
-pub async fn list_jobs(req: Request<State>) -> highnoon::Result<impl Responder> {
+pub async fn list_jobs(req: Request<State>) -> highnoon::Result<Response> {
     let id_str = req.param("id")?;
     let id = Uuid::parse_str(id_str)?;
 
     let query: ListJobQuery = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(LIST_JOB_SORT, "name")?;
 
     auth::list().project(id).check(&req).await?;
 
-    let jobs: Vec<ListJob> = sqlx::query_as(
+    let pool = req.get_pool();
+
+    // the total ignores `after`, which is a cursor rather than a filter
+    let (total,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(1)
+        FROM job
+        WHERE project_id = $1
+        AND ($2::VARCHAR IS NULL OR name = $2)
+        AND ($3::VARCHAR IS NULL OR STRPOS(LOWER(name), LOWER($3)) > 0)
+        AND ($4::BOOLEAN IS NULL OR paused = $4)",
+    )
+    .bind(id)
+    .bind(query.name.as_ref())
+    .bind(query.q.as_ref())
+    .bind(query.paused)
+    .fetch_one(&pool)
+    .await?;
+
+    let jobs: Vec<ListJob> = sqlx::query_as(&format!(
         "WITH these_runs AS (
             SELECT
                 t.job_id AS job_id,
@@ -326,17 +596,23 @@ pub async fn list_jobs(req: Request<State>) -> highnoon::Result<impl Responder>
         FROM job j
         LEFT OUTER JOIN job_stats js ON j.id = js.job_id
         WHERE project_id = $1
-        AND ($2 IS NULL OR name > $2)
-        AND ($3 IS NULL OR name = $3)
-        ORDER BY name
-        LIMIT $4",
-    )
+        AND ($2::VARCHAR IS NULL OR name > $2)
+        AND ($3::VARCHAR IS NULL OR name = $3)
+        AND ($4::VARCHAR IS NULL OR STRPOS(LOWER(name), LOWER($4)) > 0)
+        AND ($5::BOOLEAN IS NULL OR paused = $5)
+        ORDER BY {order_by}, name
+        LIMIT $6
+        OFFSET $7"
+    ))
     .bind(id)
     .bind(query.after.as_ref())
     .bind(query.name.as_ref())
-    .bind(query.limit.unwrap_or(50))
-    .fetch_all(&req.get_pool())
+    .bind(query.q.as_ref())
+    .bind(query.paused)
+    .bind(paging.limit(50))
+    .bind(paging.offset())
+    .fetch_all(&pool)
     .await?;
 
-    Ok(Json(jobs))
+    list_response(jobs, total)
 }
@@ -0,0 +1,431 @@
+use crate::{
+    config::Config,
+    server::api::{auth, request_ext::RequestExt, State},
+};
+use chrono::{DateTime, Duration, Utc};
+use cron::Schedule;
+use highnoon::{Json, Request, Responder, StatusCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// how far ahead a cron schedule is walked to find its busiest hour
+const CRON_SAMPLE_HOURS: i64 = 24;
+/// most fire times taken from a cron schedule, so one firing every second is quick to check
+const CRON_SAMPLE_MAX: usize = 10_000;
+
+/// Limits on what a project can use. Unset limits are unlimited.
+#[derive(Serialize, Deserialize, sqlx::FromRow, Clone, Debug, Default)]
+pub struct Quotas {
+    pub max_jobs: Option<i32>,
+    pub max_concurrent_tasks: Option<i32>,
+    pub max_stash_bytes: Option<i64>,
+    /// the shortest period any trigger may have
+    pub min_trigger_period_secs: Option<i64>,
+    /// most times the project's triggers may fire in an hour, all together
+    pub max_fires_per_hour: Option<i32>,
+    /// most triggers across all the project's jobs, not counting ended ones
+    pub max_triggers: Option<i32>,
+}
+
+impl Quotas {
+    /// the quotas given to new projects that don't ask for any
+    pub fn default_from_config(config: &Config) -> Self {
+        Quotas {
+            max_jobs: config.default_project_max_jobs,
+            max_concurrent_tasks: config.default_project_max_concurrent_tasks,
+            max_stash_bytes: config.default_project_max_stash_bytes,
+            min_trigger_period_secs: config.default_project_min_trigger_period_secs,
+            max_fires_per_hour: config.default_project_max_fires_per_hour,
+            max_triggers: config.default_project_max_triggers,
+        }
+    }
+}
+
+pub async fn set_quotas(
+    txn: &mut Transaction<'_, Postgres>,
+    project_id: Uuid,
+    quotas: &Quotas,
+) -> highnoon::Result<()> {
+    sqlx::query(
+        "INSERT INTO project_quota(project_id, max_jobs, max_concurrent_tasks, max_stash_bytes,
+            min_trigger_period_secs, max_fires_per_hour, max_triggers)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT(project_id)
+        DO UPDATE
+        SET max_jobs = $2,
+            max_concurrent_tasks = $3,
+            max_stash_bytes = $4,
+            min_trigger_period_secs = $5,
+            max_fires_per_hour = $6,
+            max_triggers = $7",
+    )
+    .bind(project_id)
+    .bind(quotas.max_jobs)
+    .bind(quotas.max_concurrent_tasks)
+    .bind(quotas.max_stash_bytes)
+    .bind(quotas.min_trigger_period_secs)
+    .bind(quotas.max_fires_per_hour)
+    .bind(quotas.max_triggers)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(())
+}
+
+fn over_quota(msg: String) -> highnoon::Error {
+    highnoon::Error::http((StatusCode::FORBIDDEN, msg))
+}
+
+/// Lock the project's row until the transaction ends, so two jobs being applied
+/// to the project at once can't both count its usage before the other is written.
+async fn lock_project(txn: &mut Transaction<'_, Postgres>, project_id: Uuid) -> sqlx::Result<()> {
+    sqlx::query("SELECT id FROM project WHERE id = $1 FOR UPDATE")
+        .bind(project_id)
+        .execute(&mut *txn)
+        .await?;
+    Ok(())
+}
+
+/// reject creating a job if the project already has as many jobs as it's allowed
+/// (updating an existing job is always allowed)
+pub async fn check_job_quota(
+    txn: &mut Transaction<'_, Postgres>,
+    project_id: Uuid,
+    job_id: Uuid,
+) -> highnoon::Result<()> {
+    lock_project(txn, project_id).await?;
+
+    let row: Option<(i32, i64)> = sqlx::query_as(
+        "SELECT
+            q.max_jobs,
+            (
+                SELECT COUNT(1)
+                FROM job j
+                WHERE j.project_id = q.project_id
+                AND j.id != $2
+            )
+        FROM project_quota q
+        WHERE q.project_id = $1
+        AND q.max_jobs IS NOT NULL",
+    )
+    .bind(project_id)
+    .bind(job_id)
+    .fetch_optional(&mut *txn)
+    .await?;
+
+    match row {
+        Some((max_jobs, num_jobs)) if num_jobs >= max_jobs as i64 => Err(over_quota(format!(
+            "project already has {num_jobs} jobs (quota is {max_jobs})"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// how often a schedule fires at its busiest
+#[derive(Debug, PartialEq, Eq)]
+struct Frequency {
+    /// the shortest gap between two fires, `None` if it fires at most once in the sample
+    min_gap_secs: Option<i64>,
+    /// the most fires in any hour
+    fires_per_hour: i64,
+}
+
+/// Work out how often a trigger fires. A cron schedule is walked over the next day,
+/// since its fires can be bunched up (eg. every second of one minute each hour).
+fn frequency(period: Option<i64>, cron: Option<&str>, from: DateTime<Utc>) -> Option<Frequency> {
+    if let Some(cron) = cron {
+        let schedule = Schedule::from_str(cron).ok()?;
+        let until = from + Duration::hours(CRON_SAMPLE_HOURS);
+        let times: Vec<DateTime<Utc>> = schedule
+            .after(&from)
+            .take_while(|datetime| *datetime < until)
+            .take(CRON_SAMPLE_MAX)
+            .collect();
+
+        let min_gap_secs = times
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).num_seconds())
+            .min();
+
+        let mut fires_per_hour = 0;
+        let mut end = 0;
+        for (start, datetime) in times.iter().enumerate() {
+            while end < times.len() && times[end] < *datetime + Duration::hours(1) {
+                end += 1;
+            }
+            fires_per_hour = fires_per_hour.max((end - start) as i64);
+        }
+
+        Some(Frequency {
+            min_gap_secs,
+            fires_per_hour,
+        })
+    } else {
+        let period = period.filter(|period| *period > 0)?;
+        Some(Frequency {
+            min_gap_secs: Some(period),
+            fires_per_hour: (3600 + period - 1) / period,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct QuotaTrigger {
+    job_name: String,
+    trigger_name: String,
+    period: Option<i64>,
+    cron: Option<String>,
+}
+
+/// Reject a job whose triggers fire more often than the project allows, either one
+/// trigger on its own or all of the project's triggers together, or that takes the
+/// project over its number of triggers. Call this after the job's triggers have
+/// been written, so they replace their old versions.
+pub async fn check_trigger_quota(
+    txn: &mut Transaction<'_, Postgres>,
+    project_id: Uuid,
+) -> highnoon::Result<()> {
+    lock_project(txn, project_id).await?;
+
+    let quotas: Option<(Option<i64>, Option<i32>, Option<i32>)> = sqlx::query_as(
+        "SELECT min_trigger_period_secs, max_fires_per_hour, max_triggers
+        FROM project_quota
+        WHERE project_id = $1
+        AND (min_trigger_period_secs IS NOT NULL
+            OR max_fires_per_hour IS NOT NULL
+            OR max_triggers IS NOT NULL)",
+    )
+    .bind(project_id)
+    .fetch_optional(&mut *txn)
+    .await?;
+
+    let (min_period, max_fires, max_triggers) = match quotas {
+        Some(quotas) => quotas,
+        None => return Ok(()),
+    };
+
+    let triggers: Vec<QuotaTrigger> = sqlx::query_as(
+        "SELECT
+            j.name AS job_name,
+            t.name AS trigger_name,
+            t.period,
+            t.cron
+        FROM trigger t
+        JOIN job j ON j.id = t.job_id
+        WHERE j.project_id = $1
+        AND (t.end_datetime IS NULL OR t.end_datetime > CURRENT_TIMESTAMP)",
+    )
+    .bind(project_id)
+    .fetch_all(&mut *txn)
+    .await?;
+
+    if let Some(max_triggers) = max_triggers {
+        if triggers.len() > max_triggers as usize {
+            return Err(over_quota(format!(
+                "project would have {} triggers (quota is {max_triggers})",
+                triggers.len()
+            )));
+        }
+    }
+
+    let now = Utc::now();
+    let mut total_fires = 0;
+
+    for trigger in &triggers {
+        let freq = match frequency(trigger.period, trigger.cron.as_deref(), now) {
+            Some(freq) => freq,
+            None => continue,
+        };
+
+        if let (Some(min_period), Some(gap)) = (min_period, freq.min_gap_secs) {
+            if gap < min_period {
+                return Err(over_quota(format!(
+                    "trigger '{}' of job '{}' fires {gap}s apart (quota is at least {min_period}s)",
+                    trigger.trigger_name, trigger.job_name
+                )));
+            }
+        }
+
+        total_fires += freq.fires_per_hour;
+    }
+
+    match max_fires {
+        Some(max_fires) if total_fires > max_fires as i64 => Err(over_quota(format!(
+            "project triggers would fire {total_fires} times an hour (quota is {max_fires})"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// the stash item being written, which doesn't count towards usage since it's replaced
+pub enum StashItem<'a> {
+    Project(&'a str),
+    Job(Uuid, DateTime<Utc>, &'a str),
+}
+
+/// reject a stash write that would take the project (and its jobs) over its stash quota
+pub async fn check_stash_quota(
+    pool: &PgPool,
+    project_id: Uuid,
+    item: StashItem<'_>,
+    new_bytes: usize,
+) -> highnoon::Result<()> {
+    let (project_key, job_id, trigger_datetime, job_key) = match item {
+        StashItem::Project(key) => (Some(key), None, None, None),
+        StashItem::Job(job_id, trigger_datetime, key) => {
+            (None, Some(job_id), Some(trigger_datetime), Some(key))
+        }
+    };
+
+    let row: Option<(i64, i64)> = sqlx::query_as(
+        "SELECT
+            q.max_stash_bytes,
+            (
+                SELECT COALESCE(SUM(LENGTH(ps.data)), 0)
+                FROM project_stash ps
+                WHERE ps.project_id = q.project_id
+                AND ps.name IS DISTINCT FROM $2
+            ) + (
+                SELECT COALESCE(SUM(COALESCE(js.bytes, LENGTH(js.data))), 0)
+                FROM job_stash js
+                JOIN job j ON j.id = js.job_id
+                WHERE j.project_id = q.project_id
+                AND (js.job_id = $3 AND js.trigger_datetime = $4 AND js.name = $5) IS NOT TRUE
+            )
+        FROM project_quota q
+        WHERE q.project_id = $1
+        AND q.max_stash_bytes IS NOT NULL",
+    )
+    .bind(project_id)
+    .bind(project_key)
+    .bind(job_id)
+    .bind(trigger_datetime)
+    .bind(job_key)
+    .fetch_optional(pool)
+    .await?;
+
+    match row {
+        Some((max_bytes, used_bytes)) if used_bytes + new_bytes as i64 > max_bytes => {
+            Err(over_quota(format!(
+                "project stash would use {} bytes (quota is {max_bytes})",
+                used_bytes + new_bytes as i64
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct Usage {
+    jobs: i64,
+    concurrent_tasks: i64,
+    stash_bytes: i64,
+    fires_last_hour: i64,
+    triggers: i64,
+}
+
+#[derive(Serialize)]
+struct GetQuotas {
+    quotas: Quotas,
+    usage: Usage,
+}
+
+pub async fn get_project_quotas(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let project_id = req.param("id")?.parse::<Uuid>()?;
+
+    auth::get().project(project_id).kind("quota").check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let quotas: Option<Quotas> = sqlx::query_as(
+        "SELECT max_jobs, max_concurrent_tasks, max_stash_bytes,
+            min_trigger_period_secs, max_fires_per_hour, max_triggers
+        FROM project_quota
+        WHERE project_id = $1",
+    )
+    .bind(project_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let usage: Usage = sqlx::query_as(
+        "SELECT
+            (
+                SELECT COUNT(1)
+                FROM job j
+                WHERE j.project_id = $1
+            ) AS jobs,
+            (
+                SELECT COUNT(1)
+                FROM task_run r
+                JOIN task t ON t.id = r.task_id
+                JOIN job j ON j.id = t.job_id
+                WHERE j.project_id = $1
+                AND r.state IN ('active', 'running')
+            ) AS concurrent_tasks,
+            (
+                SELECT COALESCE(SUM(LENGTH(ps.data)), 0)
+                FROM project_stash ps
+                WHERE ps.project_id = $1
+            ) + (
+                SELECT COALESCE(SUM(COALESCE(js.bytes, LENGTH(js.data))), 0)
+                FROM job_stash js
+                JOIN job j ON j.id = js.job_id
+                WHERE j.project_id = $1
+            ) AS stash_bytes,
+            (
+                SELECT COUNT(DISTINCT (r.trigger_id, r.fired_datetime))
+                FROM job_run_trigger r
+                JOIN job j ON j.id = r.job_id
+                WHERE j.project_id = $1
+                AND r.fired_datetime > CURRENT_TIMESTAMP - INTERVAL '1 hour'
+            ) AS fires_last_hour,
+            (
+                SELECT COUNT(1)
+                FROM trigger t
+                JOIN job j ON j.id = t.job_id
+                WHERE j.project_id = $1
+                AND (t.end_datetime IS NULL OR t.end_datetime > CURRENT_TIMESTAMP)
+            ) AS triggers",
+    )
+    .bind(project_id)
+    .fetch_one(&pool)
+    .await?;
+
+    Ok(Json(GetQuotas {
+        quotas: quotas.unwrap_or_default(),
+        usage,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_period_frequency() {
+        let from = Utc.ymd(2022, 1, 1).and_hms(0, 0, 0);
+
+        let freq = frequency(Some(600), None, from).unwrap();
+        assert_eq!(freq, Frequency { min_gap_secs: Some(600), fires_per_hour: 6 });
+
+        let freq = frequency(Some(7 * 60), None, from).unwrap();
+        assert_eq!(freq.fires_per_hour, 9);
+
+        assert!(frequency(None, None, from).is_none());
+    }
+
+    #[test]
+    fn test_cron_frequency() {
+        let from = Utc.ymd(2022, 1, 1).and_hms(0, 0, 0);
+
+        // every second of the first minute of each hour
+        let freq = frequency(None, Some("* 0 * * * *"), from).unwrap();
+        assert_eq!(freq, Frequency { min_gap_secs: Some(1), fires_per_hour: 60 });
+
+        let freq = frequency(None, Some("0 0 0 * * *"), from).unwrap();
+        assert_eq!(freq, Frequency { min_gap_secs: None, fires_per_hour: 1 });
+    }
+}
@@ -0,0 +1,30 @@
+use crate::server::api::State;
+use highnoon::{filter::Next, Request, Response, StatusCode};
+use tracing::debug;
+
+/// Reject any request that could change something with a 403, so a read-only
+/// deployment (eg. a DR replica or a public status page) can still be browsed.
+/// This is checked here rather than in each handler so new routes can't forget it.
+pub struct ReadOnly;
+
+/// methods that only read, anything else is assumed to mutate
+fn is_read_only(method: &str, path: &str) -> bool {
+    match method {
+        "GET" | "HEAD" | "OPTIONS" => true,
+        // simulations are a POST because of their body, but don't change anything
+        "POST" => path.ends_with("/simulate"),
+        _ => false,
+    }
+}
+
+#[async_trait::async_trait]
+impl highnoon::filter::Filter<State> for ReadOnly {
+    async fn apply(&self, req: Request<State>, next: Next<'_, State>) -> highnoon::Result<Response> {
+        if !is_read_only(req.method().as_str(), req.uri().path()) {
+            debug!(method=%req.method(), uri=%req.uri(), "rejecting request, server is read-only");
+            return Ok(Response::status(StatusCode::FORBIDDEN));
+        }
+
+        next.next(req).await
+    }
+}
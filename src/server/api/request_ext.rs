@@ -1,13 +1,13 @@
 use super::State;
+use crate::broker::Broker;
 use cadence::StatsdClient;
 use highnoon::Request;
-use lapin::Channel;
 use sqlx::PgPool;
 
 // extension methods for State
 pub trait RequestExt {
     fn get_pool(&self) -> PgPool;
-    fn get_channel(&self) -> &Channel;
+    fn get_broker(&self) -> &dyn Broker;
     fn get_statsd(&self) -> &StatsdClient;
 }
 
@@ -16,8 +16,8 @@ impl RequestExt for Request<State> {
         self.state().db_pool.clone()
     }
 
-    fn get_channel(&self) -> &Channel {
-        &self.state().amqp_channel
+    fn get_broker(&self) -> &dyn Broker {
+        self.state().broker.as_ref()
     }
 
     fn get_statsd(&self) -> &StatsdClient {
@@ -0,0 +1,280 @@
+use crate::server::api::{
+    audit,
+    auth::{self, Action},
+    request_ext::RequestExt,
+    State,
+};
+use chrono::{DateTime, Utc};
+use highnoon::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use tracing::info;
+use uuid::Uuid;
+
+/// A role bound to a principal, either in one project or globally. Each role
+/// can do everything the ones before it can.
+#[derive(
+    Serialize, Deserialize, sqlx::Type, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+#[sqlx(type_name = "VARCHAR")]
+pub enum Role {
+    /// can see everything
+    Viewer,
+    /// can also run, rerun and clear tasks, pause jobs, override triggers and edit the stash
+    Operator,
+    /// can also change definitions, delete things and manage role bindings
+    Admin,
+}
+
+/// kinds of object an operator may update
+const OPERATOR_UPDATE_KINDS: &[&str] = &["task", "trigger", "token", "pause", "stash", "workers"];
+
+/// kinds of object an operator may delete
+const OPERATOR_DELETE_KINDS: &[&str] = &["token", "stash"];
+
+/// Whether a role allows an action. Quotas are platform limits, so only global
+/// admins can change them.
+pub fn role_allows(role: Role, global: bool, action: Action, kind: &str) -> bool {
+    match (role, action) {
+        (_, Action::Get | Action::List) => true,
+        (Role::Viewer, _) => false,
+        (Role::Operator, Action::Update) => OPERATOR_UPDATE_KINDS.contains(&kind),
+        (Role::Operator, Action::Delete) => OPERATOR_DELETE_KINDS.contains(&kind),
+        (Role::Admin, _) => global || kind != "quota",
+    }
+}
+
+/// A principal's roles that apply to a project, with whether each is global.
+/// Without a project only the global roles apply.
+pub async fn principal_roles(
+    pool: &PgPool,
+    principal: &str,
+    project_id: Option<Uuid>,
+) -> highnoon::Result<Vec<(Role, bool)>> {
+    let roles: Vec<(Role, bool)> = sqlx::query_as(
+        "SELECT role, project_id IS NULL
+        FROM role_binding
+        WHERE principal = $1
+        AND (project_id IS NULL OR project_id = $2)",
+    )
+    .bind(principal)
+    .bind(project_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(roles)
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct RoleBinding {
+    principal: String,
+    role: Role,
+    created_datetime: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct SetRoleBinding {
+    principal: String,
+    role: Role,
+}
+
+#[derive(Deserialize)]
+struct PrincipalQuery {
+    principal: String,
+}
+
+async fn list_bindings(
+    req: Request<State>,
+    project_id: Option<Uuid>,
+) -> highnoon::Result<Response> {
+    auth::list()
+        .project(project_id)
+        .kind("roles")
+        .check(&req)
+        .await?;
+
+    let bindings: Vec<RoleBinding> = sqlx::query_as(
+        "SELECT principal, role, created_datetime
+        FROM role_binding
+        WHERE project_id IS NOT DISTINCT FROM $1
+        ORDER BY principal",
+    )
+    .bind(project_id)
+    .fetch_all(&req.get_pool())
+    .await?;
+
+    Response::ok().json(bindings)
+}
+
+async fn set_binding(
+    mut req: Request<State>,
+    project_id: Option<Uuid>,
+) -> highnoon::Result<Response> {
+    let body: SetRoleBinding = req.body_json().await?;
+
+    auth::update()
+        .project(project_id)
+        .kind("roles")
+        .check(&req)
+        .await?;
+
+    if body.principal.trim().is_empty() {
+        return Err(highnoon::Error::bad_request("principal must not be empty"));
+    }
+
+    let mut txn = req.get_pool().begin().await?;
+
+    let before: Option<(Role,)> = sqlx::query_as(
+        "SELECT role
+        FROM role_binding
+        WHERE project_id IS NOT DISTINCT FROM $1
+        AND principal = $2
+        FOR UPDATE",
+    )
+    .bind(project_id)
+    .bind(&body.principal)
+    .fetch_optional(&mut txn)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO role_binding(project_id, principal, role, created_datetime)
+        VALUES ($1, $2, $3, CURRENT_TIMESTAMP)
+        ON CONFLICT(COALESCE(project_id, '00000000-0000-0000-0000-000000000000'::UUID), principal)
+        DO UPDATE
+        SET role = $3,
+            created_datetime = CURRENT_TIMESTAMP",
+    )
+    .bind(project_id)
+    .bind(&body.principal)
+    .bind(body.role)
+    .execute(&mut txn)
+    .await?;
+
+    audit::update("role_binding")
+        .project(project_id)
+        .object(&body.principal)
+        .diff(
+            &json!({ "role": before.map(|(role,)| role) }),
+            &json!({ "role": body.role }),
+        )
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    info!(?project_id, principal=%body.principal, role=?body.role, "bound role");
+
+    Ok(Response::status(StatusCode::NO_CONTENT))
+}
+
+async fn delete_binding(
+    req: Request<State>,
+    project_id: Option<Uuid>,
+) -> highnoon::Result<Response> {
+    let query: PrincipalQuery = req.query()?;
+
+    auth::delete()
+        .project(project_id)
+        .kind("roles")
+        .check(&req)
+        .await?;
+
+    let mut txn = req.get_pool().begin().await?;
+
+    let removed: Option<(Role,)> = sqlx::query_as(
+        "DELETE FROM role_binding
+        WHERE project_id IS NOT DISTINCT FROM $1
+        AND principal = $2
+        RETURNING role",
+    )
+    .bind(project_id)
+    .bind(&query.principal)
+    .fetch_optional(&mut txn)
+    .await?;
+
+    let (role,) = match removed {
+        Some(removed) => removed,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    audit::delete("role_binding")
+        .project(project_id)
+        .object(&query.principal)
+        .diff(&json!({ "role": role }), &json!({ "role": null }))
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    info!(?project_id, principal=%query.principal, "removed role binding");
+
+    Ok(Response::status(StatusCode::NO_CONTENT))
+}
+
+fn project_param(req: &Request<State>) -> highnoon::Result<Uuid> {
+    Ok(req.param("id")?.parse::<Uuid>()?)
+}
+
+/// the roles bound in a project
+pub async fn list_project(req: Request<State>) -> highnoon::Result<Response> {
+    let project_id = project_param(&req)?;
+    list_bindings(req, Some(project_id)).await
+}
+
+/// bind a principal to a role in a project, replacing any role it had there
+pub async fn set_project(req: Request<State>) -> highnoon::Result<Response> {
+    let project_id = project_param(&req)?;
+    set_binding(req, Some(project_id)).await
+}
+
+pub async fn delete_project(req: Request<State>) -> highnoon::Result<Response> {
+    let project_id = project_param(&req)?;
+    delete_binding(req, Some(project_id)).await
+}
+
+/// the roles bound globally, which apply in every project
+pub async fn list_global(req: Request<State>) -> highnoon::Result<Response> {
+    list_bindings(req, None).await
+}
+
+pub async fn set_global(req: Request<State>) -> highnoon::Result<Response> {
+    set_binding(req, None).await
+}
+
+pub async fn delete_global(req: Request<State>) -> highnoon::Result<Response> {
+    delete_binding(req, None).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_viewer() {
+        assert!(role_allows(Role::Viewer, false, Action::Get, "job"));
+        assert!(role_allows(Role::Viewer, false, Action::List, "stash"));
+        assert!(!role_allows(Role::Viewer, false, Action::Update, "pause"));
+    }
+
+    #[test]
+    fn test_operator() {
+        assert!(role_allows(Role::Operator, false, Action::Update, "pause"));
+        assert!(role_allows(Role::Operator, false, Action::Update, "task"));
+        assert!(role_allows(Role::Operator, false, Action::Delete, "token"));
+        assert!(!role_allows(Role::Operator, false, Action::Update, "job"));
+        assert!(!role_allows(Role::Operator, false, Action::Delete, "job"));
+        assert!(!role_allows(Role::Operator, false, Action::Update, "roles"));
+    }
+
+    #[test]
+    fn test_admin() {
+        assert!(role_allows(Role::Admin, false, Action::Update, "job"));
+        assert!(role_allows(Role::Admin, false, Action::Delete, "project"));
+        assert!(role_allows(Role::Admin, false, Action::Update, "roles"));
+        assert!(!role_allows(Role::Admin, false, Action::Update, "quota"));
+        assert!(role_allows(Role::Admin, true, Action::Update, "quota"));
+    }
+}
@@ -1,6 +1,11 @@
-use crate::server::api::{auth, request_ext::RequestExt, State};
+use crate::server::api::{
+    auth,
+    paging::{list_response, Paging},
+    request_ext::RequestExt,
+    State,
+};
 use chrono::{DateTime, Utc};
-use highnoon::{Json, Request, Responder};
+use highnoon::{Request, Response};
 use serde::Serialize;
 use uuid::Uuid;
 
@@ -15,9 +20,20 @@ struct SchedulerState {
     pub status: String,
 }
 
-pub async fn list(req: Request<State>) -> highnoon::Result<impl Responder> {
+pub async fn list(req: Request<State>) -> highnoon::Result<Response> {
     auth::list().kind("schedulers").check(&req).await?;
 
+    let paging: Paging = req.query()?;
+    let pool = req.get_pool();
+
+    let (total,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(1)
+        FROM scheduler
+        WHERE CURRENT_TIMESTAMP - last_seen_datetime < INTERVAL '1 hour'",
+    )
+    .fetch_one(&pool)
+    .await?;
+
     let schedulers: Vec<SchedulerState> = sqlx::query_as(
         "SELECT
             s.id AS uuid,
@@ -33,10 +49,14 @@ pub async fn list(req: Request<State>) -> highnoon::Result<impl Responder> {
         FROM scheduler s
         LEFT JOIN trigger g ON s.waiting_for_trigger_id = g.id
         WHERE CURRENT_TIMESTAMP - s.last_seen_datetime < INTERVAL '1 hour'
-        ORDER BY s.last_seen_datetime DESC",
+        ORDER BY s.last_seen_datetime DESC
+        LIMIT $1
+        OFFSET $2",
     )
-    .fetch_all(&req.get_pool())
+    .bind(paging.limit(100))
+    .bind(paging.offset())
+    .fetch_all(&pool)
     .await?;
 
-    Ok(Json(schedulers))
+    list_response(schedulers, total)
 }
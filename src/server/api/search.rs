@@ -0,0 +1,179 @@
+use crate::{
+    db,
+    server::api::{auth, job::get_project_id, request_ext::RequestExt, State},
+};
+use highnoon::{Request, Response};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Shorter queries can't use the trigram indexes, so would scan every table.
+const MIN_QUERY_LEN: usize = 3;
+
+/// most results a search will return
+const MAX_RESULTS: i64 = 100;
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    /// only search within this project
+    project: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, sqlx::Type, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+#[sqlx(type_name = "VARCHAR")]
+enum SearchKind {
+    Project,
+    Job,
+    Task,
+    GlobalStash,
+    ProjectStash,
+    JobStash,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct SearchResult {
+    kind: SearchKind,
+    /// the project, job or task, stash keys don't have one
+    id: Option<Uuid>,
+    name: String,
+    project_id: Option<Uuid>,
+    project: Option<String>,
+    job_id: Option<Uuid>,
+    job: Option<String>,
+    /// trigram similarity to the query, from 0 to 1
+    score: f32,
+}
+
+/// Without `pg_trgm` rank by how much of the name the query covers instead.
+fn score_expr() -> &'static str {
+    if db::has_trigram() {
+        "SIMILARITY(name, $1)"
+    } else {
+        "LENGTH($1)::REAL / GREATEST(LENGTH(name), 1)"
+    }
+}
+
+/// Turn a query into an ILIKE pattern that matches it anywhere in a name,
+/// treating any wildcards in it as plain characters.
+fn contains_pattern(q: &str) -> String {
+    let mut pattern = String::with_capacity(q.len() + 2);
+    pattern.push('%');
+    for c in q.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Search the names of projects, jobs, tasks and stash keys for the UI's search
+/// box. Names containing the query are returned, most similar first.
+pub async fn search(req: Request<State>) -> highnoon::Result<Response> {
+    let query: SearchQuery = req.query()?;
+    let pool = req.get_pool();
+
+    let q = query.q.trim();
+    if q.chars().count() < MIN_QUERY_LEN {
+        return Err(highnoon::Error::bad_request(format!(
+            "the search must be at least {MIN_QUERY_LEN} characters"
+        )));
+    }
+
+    let project_id = match &query.project {
+        Some(project) => Some(get_project_id(&pool, project).await?),
+        None => None,
+    };
+
+    auth::list()
+        .project(project_id)
+        .kind("search")
+        .check(&req)
+        .await?;
+
+    let sql = format!(
+        "WITH matches AS (
+            SELECT
+                'project'::VARCHAR AS kind,
+                p.id AS id,
+                p.name AS name,
+                p.id AS project_id,
+                p.name AS project,
+                NULL::UUID AS job_id,
+                NULL::VARCHAR AS job
+            FROM project p
+            WHERE p.name ILIKE $2
+            AND ($3::UUID IS NULL OR p.id = $3)
+            UNION ALL
+            SELECT 'job', j.id, j.name, p.id, p.name, j.id, j.name
+            FROM job j
+            JOIN project p ON p.id = j.project_id
+            WHERE j.name ILIKE $2
+            AND ($3::UUID IS NULL OR p.id = $3)
+            UNION ALL
+            SELECT 'task', t.id, t.name, p.id, p.name, j.id, j.name
+            FROM task t
+            JOIN job j ON j.id = t.job_id
+            JOIN project p ON p.id = j.project_id
+            WHERE t.name ILIKE $2
+            AND ($3::UUID IS NULL OR p.id = $3)
+            UNION ALL
+            SELECT 'global_stash', NULL, s.name, NULL, NULL, NULL, NULL
+            FROM global_stash s
+            WHERE s.name ILIKE $2
+            AND $3::UUID IS NULL
+            UNION ALL
+            SELECT 'project_stash', NULL, s.name, p.id, p.name, NULL, NULL
+            FROM project_stash s
+            JOIN project p ON p.id = s.project_id
+            WHERE s.name ILIKE $2
+            AND ($3::UUID IS NULL OR p.id = $3)
+            UNION ALL
+            SELECT DISTINCT 'job_stash', NULL::UUID, s.name, p.id, p.name, j.id, j.name
+            FROM job_stash s
+            JOIN job j ON j.id = s.job_id
+            JOIN project p ON p.id = j.project_id
+            WHERE s.name ILIKE $2
+            AND ($3::UUID IS NULL OR p.id = $3)
+        )
+        SELECT
+            kind,
+            id,
+            name,
+            project_id,
+            project,
+            job_id,
+            job,
+            {} AS score
+        FROM matches
+        ORDER BY score DESC, name, kind
+        LIMIT $4",
+        score_expr()
+    );
+
+    let results: Vec<SearchResult> = sqlx::query_as(&sql)
+        .bind(q)
+        .bind(contains_pattern(q))
+        .bind(project_id)
+        .bind(query.limit.unwrap_or(20).clamp(1, MAX_RESULTS))
+        .fetch_all(&pool)
+        .await?;
+
+    Response::ok().json(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_contains_pattern() {
+        assert_eq!(contains_pattern("daily"), "%daily%");
+        assert_eq!(contains_pattern("50%_off"), "%50\\%\\_off%");
+        assert_eq!(contains_pattern("a\\b"), "%a\\\\b%");
+    }
+}
@@ -0,0 +1,505 @@
+use crate::server::api::{
+    audit,
+    auth::{self, Action},
+    request_ext::RequestExt,
+    roles::Role,
+    State,
+};
+use chrono::{DateTime, Utc};
+use highnoon::{Json, Request, Responder, Response, StatusCode};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// API keys start with this so they can be told apart from JWTs
+const KEY_PREFIX: &str = "wwk_";
+
+/// random bytes in a key, hex encoded after the prefix
+const KEY_BYTES: usize = 20;
+
+/// how much of a key is stored in the clear, so it can be recognised in lists
+/// and the audit log
+const KEY_DISPLAY_LEN: usize = KEY_PREFIX.len() + 8;
+
+/// A service account that has presented a valid API key.
+#[derive(Debug, sqlx::FromRow)]
+pub struct ServiceAccount {
+    pub id: Uuid,
+    pub name: String,
+    /// the only project the account can act in, or `None` for any
+    pub project_id: Option<Uuid>,
+    pub scopes: Vec<String>,
+}
+
+impl ServiceAccount {
+    /// Whether the account may do something. Accounts bound to a project can't
+    /// do anything outside it.
+    pub fn allows(&self, action: Action, kind: &str, project_id: Option<Uuid>) -> bool {
+        if self.project_id.is_some() && self.project_id != project_id {
+            return false;
+        }
+
+        self.scopes
+            .iter()
+            .any(|scope| scope_allows(scope, action, kind))
+    }
+
+    /// whether a scope grants nothing the account's own scopes don't
+    fn holds(&self, scope: &str) -> bool {
+        let (action, kind) = split_scope(scope);
+
+        self.scopes.iter().any(|held| {
+            let (held_action, held_kind) = split_scope(held);
+            (held_action == "*" || held_action == action) && (held_kind == "*" || held_kind == kind)
+        })
+    }
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(KEY_PREFIX)
+}
+
+fn generate_key() -> String {
+    let mut bytes = [0u8; KEY_BYTES];
+    thread_rng().fill_bytes(&mut bytes);
+
+    let mut key = KEY_PREFIX.to_owned();
+    for b in bytes {
+        key.push_str(&format!("{b:02x}"));
+    }
+    key
+}
+
+/// Keys are random so a plain hash is enough, there's nothing to brute force.
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// the part of a key that's shown in place of it
+pub fn key_display(key: &str) -> &str {
+    key.get(..KEY_DISPLAY_LEN).unwrap_or(key)
+}
+
+fn action_name(action: Action) -> &'static str {
+    match action {
+        Action::Get => "get",
+        Action::List => "list",
+        Action::Update => "update",
+        Action::Delete => "delete",
+    }
+}
+
+/// a scope's action and kind, which is `*` if it isn't limited to one
+fn split_scope(scope: &str) -> (&str, &str) {
+    scope.split_once(':').unwrap_or((scope, "*"))
+}
+
+/// Scopes are an action, optionally limited to a kind of object, eg. `list`,
+/// `update:job` or `*:stash`. `*` allows everything.
+fn scope_allows(scope: &str, action: Action, kind: &str) -> bool {
+    let (scope_action, scope_kind) = split_scope(scope);
+
+    (scope_action == "*" || scope_action == action_name(action))
+        && (scope_kind == "*" || scope_kind == kind)
+}
+
+fn valid_scope(scope: &str) -> bool {
+    let (scope_action, scope_kind) = match scope.split_once(':') {
+        Some((scope_action, scope_kind)) => (scope_action, Some(scope_kind)),
+        None => (scope, None),
+    };
+
+    ["*", "get", "list", "update", "delete"].contains(&scope_action)
+        && scope_kind.map_or(true, |kind| !kind.is_empty())
+}
+
+/// A new account can't be given rights its creator doesn't have. A key can only
+/// create accounts with scopes it holds itself, and with role bindings only an
+/// admin of the project (or a global admin, for a global account) can create
+/// them. Otherwise OPA must allow every action each scope grants.
+async fn check_grantable(
+    req: &Request<State>,
+    project_id: Option<Uuid>,
+    scopes: &[String],
+) -> highnoon::Result<()> {
+    let config = &req.state().config;
+    if config.no_authz {
+        return Ok(());
+    }
+
+    if let Some(key) = auth::api_key(req) {
+        let account = authenticate(&req.get_pool(), &key).await?;
+
+        return match scopes.iter().find(|scope| !account.holds(scope)) {
+            Some(scope) => {
+                warn!(?account, %scope, "service account can't grant a scope it doesn't hold");
+                Err(highnoon::Error::http(StatusCode::FORBIDDEN))
+            }
+            None => Ok(()),
+        };
+    }
+
+    if config.rbac {
+        let roles = auth::caller_roles(req, project_id).await?;
+
+        return if roles.iter().any(|&(role, _)| role == Role::Admin) {
+            Ok(())
+        } else {
+            warn!(
+                ?project_id,
+                ?roles,
+                "only admins can create service accounts"
+            );
+            Err(highnoon::Error::http(StatusCode::FORBIDDEN))
+        };
+    }
+
+    for scope in scopes {
+        let (scope_action, kind) = split_scope(scope);
+        for action in [Action::Get, Action::List, Action::Update, Action::Delete] {
+            if scope_action == "*" || scope_action == action_name(action) {
+                auth::action(action)
+                    .project(project_id)
+                    .kind(kind)
+                    .check(req)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Look up the service account an API key belongs to. Unknown, revoked and
+/// expired keys are rejected as unauthenticated.
+pub async fn authenticate(pool: &PgPool, key: &str) -> highnoon::Result<ServiceAccount> {
+    let key_hash = hash_key(key);
+
+    let account: Option<ServiceAccount> = sqlx::query_as(
+        "SELECT id, name, project_id, scopes
+        FROM service_account
+        WHERE key_hash = $1
+        AND revoked_datetime IS NULL
+        AND (expires_datetime IS NULL OR expires_datetime > CURRENT_TIMESTAMP)",
+    )
+    .bind(&key_hash)
+    .fetch_optional(pool)
+    .await?;
+
+    let account = match account {
+        Some(account) => account,
+        None => {
+            warn!(
+                key = key_display(key),
+                "unknown, revoked or expired API key"
+            );
+            return Err(highnoon::Error::http(StatusCode::UNAUTHORIZED));
+        }
+    };
+
+    // only roughly tracked, to avoid a write on every request
+    sqlx::query(
+        "UPDATE service_account
+        SET last_used_datetime = CURRENT_TIMESTAMP
+        WHERE id = $1
+        AND (last_used_datetime IS NULL
+            OR last_used_datetime < CURRENT_TIMESTAMP - INTERVAL '1 minute')",
+    )
+    .bind(account.id)
+    .execute(pool)
+    .await?;
+
+    Ok(account)
+}
+
+#[derive(Deserialize)]
+struct NewServiceAccount {
+    name: String,
+    #[serde(default)]
+    description: String,
+    project_id: Option<Uuid>,
+    scopes: Vec<String>,
+    /// when the key stops working, it never expires if unset
+    expires_datetime: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ServiceAccountInfo {
+    id: Uuid,
+    name: String,
+    description: String,
+    project_id: Option<Uuid>,
+    scopes: Vec<String>,
+    /// the start of the key, to tell which one is in use
+    key_prefix: String,
+    created_by: Option<String>,
+    created_datetime: DateTime<Utc>,
+    expires_datetime: Option<DateTime<Utc>>,
+    last_used_datetime: Option<DateTime<Utc>>,
+    revoked_datetime: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct CreatedServiceAccount {
+    #[serde(flatten)]
+    account: ServiceAccountInfo,
+    /// the API key, this is the only time it's returned
+    key: String,
+}
+
+/// Create a service account and its API key. Only a hash of the key is stored,
+/// so it can't be retrieved again.
+pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
+    let body: NewServiceAccount = req.body_json().await?;
+
+    auth::update()
+        .project(body.project_id)
+        .kind("service_account")
+        .check(&req)
+        .await?;
+
+    if body.name.trim().is_empty() {
+        return Err(highnoon::Error::bad_request("name must not be empty"));
+    }
+    if body.scopes.is_empty() {
+        return Err(highnoon::Error::bad_request("at least one scope is needed"));
+    }
+    if let Some(scope) = body.scopes.iter().find(|scope| !valid_scope(scope)) {
+        return Err(highnoon::Error::bad_request(format!(
+            "invalid scope '{scope}', expected <action> or <action>:<kind>"
+        )));
+    }
+    check_grantable(&req, body.project_id, &body.scopes).await?;
+    if matches!(body.expires_datetime, Some(expires) if expires <= Utc::now()) {
+        return Err(highnoon::Error::bad_request(
+            "expires_datetime is in the past",
+        ));
+    }
+
+    let key = generate_key();
+    let id = Uuid::new_v4();
+
+    let mut txn = req.get_pool().begin().await?;
+
+    let account: ServiceAccountInfo = sqlx::query_as(
+        "INSERT INTO service_account(
+            id, name, description, project_id, scopes, key_prefix, key_hash,
+            created_by, created_datetime, expires_datetime
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, CURRENT_TIMESTAMP, $9)
+        RETURNING
+            id,
+            name,
+            description,
+            project_id,
+            scopes,
+            key_prefix,
+            created_by,
+            created_datetime,
+            expires_datetime,
+            last_used_datetime,
+            revoked_datetime",
+    )
+    .bind(id)
+    .bind(&body.name)
+    .bind(&body.description)
+    .bind(body.project_id)
+    .bind(&body.scopes)
+    .bind(key_display(&key))
+    .bind(hash_key(&key))
+    .bind(auth::author(&req))
+    .bind(body.expires_datetime)
+    .fetch_one(&mut txn)
+    .await?;
+
+    audit::create("service_account")
+        .project(body.project_id)
+        .object(id)
+        .details(json!({
+            "name": &body.name,
+            "scopes": &body.scopes,
+            "key_prefix": &account.key_prefix,
+            "expires_datetime": body.expires_datetime,
+        }))
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    info!(?id, name=%body.name, project_id=?body.project_id, "created service account");
+
+    (
+        StatusCode::CREATED,
+        Json(CreatedServiceAccount { account, key }),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct ListServiceAccountsQuery {
+    /// the project's accounts, otherwise the global ones
+    project_id: Option<Uuid>,
+    #[serde(default)]
+    include_revoked: bool,
+}
+
+/// the service accounts in a project or global ones, without their keys
+pub async fn list(req: Request<State>) -> highnoon::Result<Response> {
+    let query: ListServiceAccountsQuery = req.query()?;
+
+    auth::list()
+        .project(query.project_id)
+        .kind("service_account")
+        .check(&req)
+        .await?;
+
+    let accounts: Vec<ServiceAccountInfo> = sqlx::query_as(
+        "SELECT
+            id,
+            name,
+            description,
+            project_id,
+            scopes,
+            key_prefix,
+            created_by,
+            created_datetime,
+            expires_datetime,
+            last_used_datetime,
+            revoked_datetime
+        FROM service_account
+        WHERE project_id IS NOT DISTINCT FROM $1
+        AND ($2::BOOLEAN OR revoked_datetime IS NULL)
+        ORDER BY name, created_datetime DESC",
+    )
+    .bind(query.project_id)
+    .bind(query.include_revoked)
+    .fetch_all(&req.get_pool())
+    .await?;
+
+    Response::ok().json(accounts)
+}
+
+/// Revoke a service account's key. The account is kept so the audit log and
+/// lists can still refer to it.
+pub async fn revoke(req: Request<State>) -> highnoon::Result<Response> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+    let pool = req.get_pool();
+
+    let row: Option<(Option<Uuid>,)> =
+        sqlx::query_as("SELECT project_id FROM service_account WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&pool)
+            .await?;
+
+    let project_id = match row {
+        Some((project_id,)) => project_id,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    auth::delete()
+        .project(project_id)
+        .kind("service_account")
+        .check(&req)
+        .await?;
+
+    let mut txn = pool.begin().await?;
+
+    let revoked: Option<(String,)> = sqlx::query_as(
+        "UPDATE service_account
+        SET revoked_datetime = CURRENT_TIMESTAMP
+        WHERE id = $1
+        AND revoked_datetime IS NULL
+        RETURNING key_prefix",
+    )
+    .bind(id)
+    .fetch_optional(&mut txn)
+    .await?;
+
+    // revoking twice is a no-op
+    if let Some((key_prefix,)) = revoked {
+        audit::delete("service_account")
+            .project(project_id)
+            .object(id)
+            .details(json!({ "key_prefix": key_prefix }))
+            .record(&req, &mut txn)
+            .await?;
+
+        info!(?id, "revoked service account");
+    }
+
+    txn.commit().await?;
+
+    Ok(Response::status(StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn account(project_id: Option<Uuid>, scopes: &[&str]) -> ServiceAccount {
+        ServiceAccount {
+            id: Uuid::nil(),
+            name: "ci".to_owned(),
+            project_id,
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_scopes() {
+        let ci = account(None, &["get", "list", "update:job"]);
+        assert!(ci.allows(Action::Get, "job", None));
+        assert!(ci.allows(Action::List, "task", None));
+        assert!(ci.allows(Action::Update, "job", None));
+        assert!(!ci.allows(Action::Update, "project", None));
+        assert!(!ci.allows(Action::Delete, "job", None));
+
+        let stash = account(None, &["*:stash"]);
+        assert!(stash.allows(Action::Delete, "stash", None));
+        assert!(!stash.allows(Action::Get, "job", None));
+    }
+
+    #[test]
+    fn test_project_account() {
+        let project_id = Uuid::new_v4();
+        let ci = account(Some(project_id), &["*"]);
+        assert!(ci.allows(Action::Update, "job", Some(project_id)));
+        assert!(!ci.allows(Action::Update, "job", Some(Uuid::new_v4())));
+        assert!(!ci.allows(Action::List, "project", None));
+    }
+
+    #[test]
+    fn test_holds() {
+        let admin = account(None, &["update:service_account"]);
+        assert!(admin.holds("update:service_account"));
+        assert!(!admin.holds("*"));
+        assert!(!admin.holds("update"));
+        assert!(!admin.holds("*:service_account"));
+
+        let ci = account(None, &["get", "*:stash"]);
+        assert!(ci.holds("get:job"));
+        assert!(ci.holds("delete:stash"));
+        assert!(!ci.holds("update:job"));
+    }
+
+    #[test]
+    fn test_valid_scope() {
+        assert!(valid_scope("*"));
+        assert!(valid_scope("update:job"));
+        assert!(valid_scope("*:stash"));
+        assert!(!valid_scope("write"));
+        assert!(!valid_scope("update:"));
+    }
+
+    #[test]
+    fn test_key() {
+        let key = generate_key();
+        assert!(is_api_key(&key));
+        assert_eq!(key.len(), KEY_PREFIX.len() + KEY_BYTES * 2);
+        assert_eq!(key_display(&key).len(), KEY_DISPLAY_LEN);
+        assert_ne!(hash_key(&key), hash_key(&generate_key()));
+    }
+}
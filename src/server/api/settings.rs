@@ -0,0 +1,100 @@
+use crate::server::{
+    api::{audit, auth, request_ext::RequestExt, State},
+    settings::{self, Settings},
+};
+use highnoon::{Json, Request, Responder};
+use serde::Serialize;
+use serde_json::{Map, Value as JsonValue};
+
+#[derive(Serialize)]
+struct GetSettings {
+    settings: Settings,
+    /// the settings that have been changed from the config
+    overridden: Vec<String>,
+}
+
+pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
+    auth::get().kind("settings").check(&req).await?;
+
+    let overrides = settings::load_overrides(&req.get_pool()).await?;
+    let settings = Settings::from_config(&req.state().config)
+        .with_overrides(&overrides)
+        .map_err(|err| anyhow::format_err!(err))?;
+
+    Ok(Json(GetSettings {
+        settings,
+        overridden: overrides.into_keys().collect(),
+    }))
+}
+
+/// Change some settings, which take effect on every server within a few
+/// seconds. Setting one to `null` returns it to its value in the config.
+pub async fn update(mut req: Request<State>) -> highnoon::Result<impl Responder> {
+    let changes: Map<String, JsonValue> = req.body_json().await?;
+
+    auth::update().kind("settings").check(&req).await?;
+
+    let config = &req.state().config;
+    let mut txn = req.get_pool().begin().await?;
+
+    // only one update at a time, so they can't undo each other's checks
+    sqlx::query("LOCK TABLE setting IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut txn)
+        .await?;
+
+    let before = settings::load_overrides(&mut txn).await?;
+    let mut after = before.clone();
+    for (name, value) in &changes {
+        if value.is_null() {
+            after.remove(name);
+        } else {
+            after.insert(name.clone(), value.clone());
+        }
+    }
+
+    let old_settings = Settings::from_config(config)
+        .with_overrides(&before)
+        .map_err(|err| anyhow::format_err!(err))?;
+    let new_settings = Settings::from_config(config)
+        .with_overrides(&after)
+        .map_err(highnoon::Error::bad_request)?;
+
+    for (name, value) in &changes {
+        if value.is_null() {
+            sqlx::query("DELETE FROM setting WHERE name = $1")
+                .bind(name)
+                .execute(&mut txn)
+                .await?;
+        } else {
+            sqlx::query(
+                "INSERT INTO setting(name, value, updated_datetime)
+                VALUES ($1, $2, CURRENT_TIMESTAMP)
+                ON CONFLICT (name)
+                DO UPDATE
+                SET value = $2,
+                    updated_datetime = CURRENT_TIMESTAMP",
+            )
+            .bind(name)
+            .bind(value)
+            .execute(&mut txn)
+            .await?;
+        }
+    }
+
+    audit::update("settings")
+        .diff(
+            &serde_json::to_value(&old_settings)?,
+            &serde_json::to_value(&new_settings)?,
+        )
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    settings::apply(config, new_settings.clone());
+
+    Ok(Json(GetSettings {
+        settings: new_settings,
+        overridden: after.into_keys().collect(),
+    }))
+}
@@ -1,7 +1,8 @@
 use super::State;
 use crate::server::api::jwt;
+use chrono::{DateTime, Utc};
 use highnoon::{
-    headers::{authorization::Bearer, Authorization},
+    headers::{authorization::Bearer, Authorization, ContentLength},
     Error, Request, Responder, StatusCode,
 };
 
@@ -12,6 +13,40 @@ pub mod project;
 #[derive(sqlx::FromRow, serde::Serialize)]
 struct StashName(String);
 
+/// a global or project stash item, without its data
+#[derive(sqlx::FromRow, serde::Serialize)]
+struct StashInfo {
+    name: String,
+    bytes: i32,
+    /// unset for items written before stash items were versioned
+    version: Option<i32>,
+    updated_datetime: Option<DateTime<Utc>>,
+    author: Option<String>,
+}
+
+/// an earlier value of a global or project stash item, without its data
+#[derive(sqlx::FromRow, serde::Serialize)]
+struct StashVersion {
+    version: i32,
+    bytes: i32,
+    created_datetime: DateTime<Utc>,
+    author: Option<String>,
+}
+
+const LIST_STASH_SORT: &[(&str, &str)] = &[("name", "name"), ("updated", "updated_datetime")];
+
+#[derive(serde::Deserialize)]
+struct ListStashQuery {
+    /// only items with names containing this
+    q: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct StashVersionQuery {
+    /// read this version instead of the current one
+    version: Option<i32>,
+}
+
 #[derive(sqlx::FromRow)]
 struct StashData(Vec<u8>);
 
@@ -21,6 +56,36 @@ impl Responder for StashData {
     }
 }
 
+fn too_large(max_bytes: u64) -> Error {
+    Error::http((
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("stash items can be at most {max_bytes} bytes"),
+    ))
+}
+
+/// Reject a write that says up front it's bigger than `max_stash_item_bytes`,
+/// before reading any of it. Returns the size it says it is, if it does.
+fn check_content_length(req: &Request<State>) -> highnoon::Result<Option<u64>> {
+    let max_bytes = req.state().config.max_stash_item_bytes;
+
+    match req.header::<ContentLength>() {
+        Some(ContentLength(len)) if len > max_bytes => Err(too_large(max_bytes)),
+        Some(ContentLength(len)) => Ok(Some(len)),
+        None => Ok(None),
+    }
+}
+
+/// check the size of a body that's been read, in case it didn't say up front
+fn check_size(req: &Request<State>, len: usize) -> highnoon::Result<()> {
+    let max_bytes = req.state().config.max_stash_item_bytes;
+
+    if len as u64 > max_bytes {
+        Err(too_large(max_bytes))
+    } else {
+        Ok(())
+    }
+}
+
 pub fn get_jwt_subject(req: &Request<State>) -> highnoon::Result<String> {
     let jwt = req
         .header::<Authorization<Bearer>>()
@@ -1,67 +1,246 @@
-use crate::server::api::{auth, request_ext::RequestExt, State};
-use highnoon::{Json, Request, Responder, StatusCode};
+use crate::server::api::{
+    audit, auth,
+    paging::{list_response, Paging},
+    request_ext::RequestExt,
+    State,
+};
+use highnoon::{Request, Responder, Response, StatusCode};
+use serde_json::json;
+use sqlx::{Postgres, Transaction};
 use tracing::info;
 
-use super::{get_jwt_subject, StashData, StashName};
+use super::{
+    check_content_length, check_size, get_jwt_subject, ListStashQuery, StashData, StashInfo,
+    StashVersion, StashVersionQuery, LIST_STASH_SORT,
+};
 use cadence::CountedExt;
 
+/// Write a new version of an item, which becomes its current value. Versions
+/// carry on from the last one if the item was deleted and written again.
+async fn put_version(
+    txn: &mut Transaction<'_, Postgres>,
+    key: &str,
+    data: &[u8],
+    author: Option<&str>,
+) -> highnoon::Result<i32> {
+    let (version,): (i32,) = sqlx::query_as(
+        "INSERT INTO global_stash(name, data, version, author, updated_datetime)
+        VALUES (
+            $1,
+            $2,
+            (
+                SELECT COALESCE(MAX(version), 0) + 1
+                FROM global_stash_version
+                WHERE name = $1
+            ),
+            $3,
+            CURRENT_TIMESTAMP
+        )
+        ON CONFLICT (name)
+        DO UPDATE
+        SET data = $2,
+            version = COALESCE(global_stash.version, 0) + 1,
+            author = $3,
+            updated_datetime = CURRENT_TIMESTAMP
+        RETURNING version",
+    )
+    .bind(key)
+    .bind(data)
+    .bind(author)
+    .fetch_one(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO global_stash_version(name, version, data, author, created_datetime)
+        VALUES ($1, $2, $3, $4, CURRENT_TIMESTAMP)",
+    )
+    .bind(key)
+    .bind(version)
+    .bind(data)
+    .bind(author)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(version)
+}
+
 pub async fn create(mut req: Request<State>) -> highnoon::Result<impl Responder> {
+    check_content_length(&req)?;
     let data = req.body_bytes().await?;
+    check_size(&req, data.len())?;
     let key = req.param("key")?;
 
     auth::update().kind("stash").check(&req).await?;
 
-    let db = req.get_pool();
+    let mut txn = req.get_pool().begin().await?;
 
-    sqlx::query(
-        "INSERT INTO global_stash(name, data)
-        VALUES ($1, $2)
-        ON CONFLICT (name)
-        DO UPDATE
-        SET data = $2",
-    )
-    .bind(key)
-    .bind(&data)
-    .execute(&db)
-    .await?;
+    let version = put_version(&mut txn, key, &data, audit::principal(&req).as_deref()).await?;
 
-    info!(key, "created global stash item");
+    info!(key, version, "created global stash item");
+
+    // only the size is recorded, stash items are often secrets
+    audit::update("global_stash")
+        .object(key)
+        .details(json!({ "bytes": data.len(), "version": version }))
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
 
     Ok(StatusCode::CREATED)
 }
 
-pub async fn list(req: Request<State>) -> highnoon::Result<impl Responder> {
+pub async fn list(req: Request<State>) -> highnoon::Result<Response> {
     let db = req.get_pool();
 
     auth::list().kind("stash").check(&req).await?;
 
-    let rows: Vec<StashName> = sqlx::query_as(
-        "SELECT name
-        FROM global_stash",
-    )
+    let query: ListStashQuery = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(LIST_STASH_SORT, "name")?;
+
+    let filter = "FROM global_stash
+        WHERE ($1::VARCHAR IS NULL OR STRPOS(LOWER(name), LOWER($1)) > 0)";
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(&query.q)
+        .fetch_one(&db)
+        .await?;
+
+    let rows: Vec<StashInfo> = sqlx::query_as(&format!(
+        "SELECT
+            name,
+            COALESCE(LENGTH(data), 0) AS bytes,
+            version,
+            updated_datetime,
+            author
+        {filter}
+        ORDER BY {order_by}
+        LIMIT $2
+        OFFSET $3"
+    ))
+    .bind(&query.q)
+    .bind(paging.limit(1000))
+    .bind(paging.offset())
     .fetch_all(&db)
     .await?;
 
-    Ok(Json(rows))
+    list_response(rows, total)
 }
 
-pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
+/// every version of an item, newest first
+pub async fn list_versions(req: Request<State>) -> highnoon::Result<Response> {
     let db = req.get_pool();
 
-    let subject = get_jwt_subject(&req)?;
+    auth::list().kind("stash").check(&req).await?;
+
+    let key = req.param("key")?;
+    let paging: Paging = req.query()?;
+
+    let (total,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(1)
+        FROM global_stash_version
+        WHERE name = $1",
+    )
+    .bind(key)
+    .fetch_one(&db)
+    .await?;
+
+    let rows: Vec<StashVersion> = sqlx::query_as(
+        "SELECT
+            version,
+            COALESCE(LENGTH(data), 0) AS bytes,
+            created_datetime,
+            author
+        FROM global_stash_version
+        WHERE name = $1
+        ORDER BY version DESC
+        LIMIT $2
+        OFFSET $3",
+    )
+    .bind(key)
+    .bind(paging.limit(100))
+    .bind(paging.offset())
+    .fetch_all(&db)
+    .await?;
+
+    list_response(rows, total)
+}
+
+/// make an earlier version current again, by writing it as a new version
+pub async fn restore(req: Request<State>) -> highnoon::Result<StatusCode> {
     let key = req.param("key")?;
+    let from_version = req.param("version")?.parse::<i32>()?;
+
+    auth::update().kind("stash").check(&req).await?;
 
-    info!(task_id=?subject, key, "task requested global stash");
+    let mut txn = req.get_pool().begin().await?;
 
-    let row: Option<StashData> = sqlx::query_as(
+    let data: Option<StashData> = sqlx::query_as(
         "SELECT data
-        FROM global_stash
-        WHERE name = $1",
+        FROM global_stash_version
+        WHERE name = $1
+        AND version = $2",
     )
     .bind(key)
-    .fetch_optional(&db)
+    .bind(from_version)
+    .fetch_optional(&mut txn)
     .await?;
 
+    let data = match data {
+        Some(data) => data.0,
+        None => return Ok(StatusCode::NOT_FOUND),
+    };
+
+    let version = put_version(&mut txn, key, &data, audit::principal(&req).as_deref()).await?;
+
+    info!(key, from_version, version, "restored global stash item");
+
+    audit::action("restore", "global_stash")
+        .object(key)
+        .details(json!({ "from_version": from_version, "version": version }))
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let db = req.get_pool();
+
+    let subject = get_jwt_subject(&req)?;
+    let key = req.param("key")?;
+    let query: StashVersionQuery = req.query()?;
+
+    info!(task_id=?subject, key, version=?query.version, "task requested global stash");
+
+    let row: Option<StashData> = match query.version {
+        Some(version) => {
+            sqlx::query_as(
+                "SELECT data
+                FROM global_stash_version
+                WHERE name = $1
+                AND version = $2",
+            )
+            .bind(key)
+            .bind(version)
+            .fetch_optional(&db)
+            .await?
+        }
+        None => {
+            sqlx::query_as(
+                "SELECT data
+                FROM global_stash
+                WHERE name = $1",
+            )
+            .bind(key)
+            .fetch_optional(&db)
+            .await?
+        }
+    };
+
     req.get_statsd()
         .incr_with_tags("stash.get")
         .with_tag_value("global")
@@ -70,6 +249,7 @@ pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
     Ok(row)
 }
 
+/// Delete an item. Its versions are kept, so it can be restored.
 pub async fn delete(req: Request<State>) -> highnoon::Result<impl Responder> {
     let db = req.get_pool();
 
@@ -88,5 +268,10 @@ pub async fn delete(req: Request<State>) -> highnoon::Result<impl Responder> {
 
     info!(key, "deleted global stash item");
 
+    audit::delete("global_stash")
+        .object(key)
+        .record(&req, &db)
+        .await?;
+
     Ok(StatusCode::NO_CONTENT)
 }
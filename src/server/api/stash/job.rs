@@ -1,19 +1,65 @@
-use crate::server::api::{auth, request_ext::RequestExt, State};
-use highnoon::{Json, Request, Responder, StatusCode};
+use crate::server::api::{
+    auth,
+    job::get_job_project_id,
+    paging::{list_response, Paging},
+    quota::{check_stash_quota, StashItem},
+    request_ext::RequestExt,
+    State,
+};
+use futures::stream;
+use highnoon::{
+    headers::{ContentLength, ContentType},
+    Request, Responder, Response, StatusCode,
+};
+use hyper::body::HttpBody;
+use sqlx::{Postgres, Transaction};
 use tracing::info;
 use uuid::Uuid;
 
-use super::{get_jwt_subject, StashData, StashName};
+use super::{check_content_length, check_size, get_jwt_subject, ListStashQuery, StashName};
 use cadence::CountedExt;
 use chrono::{DateTime, Utc};
 
+/// Values up to this size are kept in the item's row. Bigger ones are split
+/// into chunks of this size, so they're never all in memory at once.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+async fn insert_chunk(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+    trigger_datetime: DateTime<Utc>,
+    key: &str,
+    seq: i32,
+    data: &[u8],
+) -> highnoon::Result<()> {
+    sqlx::query(
+        "INSERT INTO job_stash_chunk(job_id, trigger_datetime, name, seq, data)
+        VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(job_id)
+    .bind(trigger_datetime)
+    .bind(key)
+    .bind(seq)
+    .bind(data)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(())
+}
+
+/// Tasks can stash large intermediate results here, the body is streamed into
+/// the database a chunk at a time and its content type is kept.
 pub async fn create(mut req: Request<State>) -> highnoon::Result<impl Responder> {
-    let data = req.body_bytes().await?;
+    let content_length = check_content_length(&req)?;
+    let mut body = std::mem::take(req.body_mut());
 
     let job_id = req.param("id")?.parse::<Uuid>()?;
     let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
     let task_id = get_jwt_subject(&req)?.parse::<Uuid>()?;
     let key = req.param("key")?;
+    let content_type = req
+        .header::<ContentType>()
+        .map(|content_type| mime::Mime::from(content_type).to_string());
 
     // don't check authz here - job stash are expected to be created by tasks
     // and so we want to check permissions using the Stash JWT
@@ -21,33 +67,104 @@ pub async fn create(mut req: Request<State>) -> highnoon::Result<impl Responder>
 
     let db = req.get_pool();
 
-    sqlx::query(
-        "INSERT INTO job_stash(job_id, trigger_datetime, name, data)
-        SELECT $1, $2, $3, $4
-        WHERE (
-            SELECT TRUE
+    // check the size the body says it is before reading it, and again once it's read
+    let project_id = get_job_project_id(&db, job_id).await?;
+    let item = || StashItem::Job(job_id, trigger_datetime, key);
+    if let Some(len) = content_length {
+        check_stash_quota(&db, project_id, item(), len as usize).await?;
+    }
+
+    let mut txn = db.begin().await?;
+
+    let (task_in_job,): (bool,) = sqlx::query_as(
+        "SELECT EXISTS (
+            SELECT 1
             FROM task
-            WHERE id = $5
-            AND job_id = $1
-        )
+            WHERE id = $1
+            AND job_id = $2
+        )",
+    )
+    .bind(task_id)
+    .bind(job_id)
+    .fetch_one(&mut txn)
+    .await?;
+
+    if !task_in_job {
+        return Ok(StatusCode::FORBIDDEN);
+    }
+
+    sqlx::query(
+        "DELETE FROM job_stash_chunk
+        WHERE job_id = $1
+        AND trigger_datetime = $2
+        AND name = $3",
+    )
+    .bind(job_id)
+    .bind(trigger_datetime)
+    .bind(key)
+    .execute(&mut txn)
+    .await?;
+
+    let mut buf = Vec::new();
+    let mut chunks = 0;
+    let mut total = 0;
+
+    while let Some(data) = body.data().await {
+        let data = data?;
+        total += data.len();
+        check_size(&req, total)?;
+        buf.extend_from_slice(&data);
+
+        while buf.len() > CHUNK_SIZE {
+            let rest = buf.split_off(CHUNK_SIZE);
+            let chunk = std::mem::replace(&mut buf, rest);
+            insert_chunk(&mut txn, job_id, trigger_datetime, key, chunks, &chunk).await?;
+            chunks += 1;
+        }
+    }
+
+    // anything that fits in one chunk is kept inline
+    let inline = if chunks == 0 {
+        Some(buf)
+    } else {
+        if !buf.is_empty() {
+            insert_chunk(&mut txn, job_id, trigger_datetime, key, chunks, &buf).await?;
+            chunks += 1;
+        }
+        None
+    };
+
+    check_stash_quota(&db, project_id, item(), total).await?;
+
+    sqlx::query(
+        "INSERT INTO job_stash(job_id, trigger_datetime, name, data, bytes, content_type, chunks)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
         ON CONFLICT (job_id, trigger_datetime, name)
         DO UPDATE
-        SET data = $4",
+        SET data = $4,
+            bytes = $5,
+            content_type = $6,
+            chunks = $7",
     )
     .bind(job_id)
     .bind(trigger_datetime)
     .bind(key)
-    .bind(&data)
-    .bind(task_id)
-    .execute(&db)
+    .bind(&inline)
+    .bind(total as i64)
+    .bind(&content_type)
+    .bind(inline.is_none().then_some(chunks))
+    .execute(&mut txn)
     .await?;
 
-    info!(?job_id, trigger_datetime=?trigger_datetime.to_rfc3339(), %key, "created job stash item");
+    txn.commit().await?;
+
+    info!(?job_id, trigger_datetime=?trigger_datetime.to_rfc3339(), %key, bytes=total, chunks,
+        "created job stash item");
 
     Ok(StatusCode::CREATED)
 }
 
-pub async fn list(req: Request<State>) -> highnoon::Result<impl Responder> {
+pub async fn list(req: Request<State>) -> highnoon::Result<Response> {
     let db = req.get_pool();
 
     let job_id = req.param("id")?.parse::<Uuid>()?;
@@ -59,21 +176,50 @@ pub async fn list(req: Request<State>) -> highnoon::Result<impl Responder> {
         .check(&req)
         .await?;
 
-    let rows: Vec<StashName> = sqlx::query_as(
-        "SELECT name
-        FROM job_stash
+    let query: ListStashQuery = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(&[("name", "name")], "name")?;
+
+    let filter = "FROM job_stash
         WHERE job_id = $1
-        AND trigger_datetime = $2",
-    )
+        AND trigger_datetime = $2
+        AND ($3::VARCHAR IS NULL OR STRPOS(LOWER(name), LOWER($3)) > 0)";
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(job_id)
+        .bind(trigger_datetime)
+        .bind(&query.q)
+        .fetch_one(&db)
+        .await?;
+
+    let rows: Vec<StashName> = sqlx::query_as(&format!(
+        "SELECT name
+        {filter}
+        ORDER BY {order_by}
+        LIMIT $4
+        OFFSET $5"
+    ))
     .bind(job_id)
     .bind(trigger_datetime)
+    .bind(&query.q)
+    .bind(paging.limit(1000))
+    .bind(paging.offset())
     .fetch_all(&db)
     .await?;
 
-    Ok(Json(rows))
+    list_response(rows, total)
+}
+
+#[derive(sqlx::FromRow)]
+struct JobStashItem {
+    /// unset if the value is in chunks
+    data: Option<Vec<u8>>,
+    bytes: Option<i64>,
+    content_type: Option<String>,
+    chunks: Option<i32>,
 }
 
-pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
+pub async fn get(req: Request<State>) -> highnoon::Result<Response> {
     let db = req.get_pool();
 
     let job_id = req.param("id")?.parse::<Uuid>()?;
@@ -87,8 +233,12 @@ pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
         key,
         "task requested job stash");
 
-    let row: Option<StashData> = sqlx::query_as(
-        "SELECT js.data
+    let row: Option<JobStashItem> = sqlx::query_as(
+        "SELECT
+            js.data,
+            js.bytes,
+            js.content_type,
+            js.chunks
         FROM job_stash js
         WHERE js.job_id = $1
         AND js.trigger_datetime = $2
@@ -111,7 +261,55 @@ pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
         .with_tag("job_id", &job_id.to_string())
         .send();
 
-    Ok(row)
+    let item = match row {
+        Some(item) => item,
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+    };
+
+    let content_type = item
+        .content_type
+        .and_then(|content_type| content_type.parse::<mime::Mime>().ok())
+        .map_or_else(ContentType::octet_stream, ContentType::from);
+    let response = Response::ok().header(content_type);
+
+    let (chunks, bytes) = match (item.data, item.chunks, item.bytes) {
+        (Some(data), _, _) => return Ok(response.body(data)),
+        (None, Some(chunks), Some(bytes)) => (chunks, bytes),
+        _ => return Ok(response),
+    };
+
+    // read one chunk at a time as the client takes them
+    let key = key.to_owned();
+    let body = stream::unfold(0, move |seq| {
+        let db = db.clone();
+        let key = key.clone();
+        async move {
+            if seq >= chunks {
+                return None;
+            }
+
+            let chunk: sqlx::Result<(Vec<u8>,)> = sqlx::query_as(
+                "SELECT data
+                FROM job_stash_chunk
+                WHERE job_id = $1
+                AND trigger_datetime = $2
+                AND name = $3
+                AND seq = $4",
+            )
+            .bind(job_id)
+            .bind(trigger_datetime)
+            .bind(&key)
+            .bind(seq)
+            .fetch_one(&db)
+            .await;
+
+            Some((chunk.map(|(data,)| data), seq + 1))
+        }
+    });
+
+    Ok(response
+        .header(ContentLength(bytes as u64))
+        .body(hyper::Body::wrap_stream(body)))
 }
 
 pub async fn delete(req: Request<State>) -> highnoon::Result<impl Responder> {
@@ -127,6 +325,21 @@ pub async fn delete(req: Request<State>) -> highnoon::Result<impl Responder> {
         .check(&req)
         .await?;
 
+    let mut txn = db.begin().await?;
+
+    sqlx::query(
+        "DELETE
+        FROM job_stash_chunk
+        WHERE job_id = $1
+        AND trigger_datetime = $2
+        AND name = $3",
+    )
+    .bind(job_id)
+    .bind(trigger_datetime)
+    .bind(key)
+    .execute(&mut txn)
+    .await?;
+
     let _done = sqlx::query(
         "DELETE
         FROM job_stash
@@ -137,9 +350,11 @@ pub async fn delete(req: Request<State>) -> highnoon::Result<impl Responder> {
     .bind(job_id)
     .bind(trigger_datetime)
     .bind(key)
-    .execute(&db)
+    .execute(&mut txn)
     .await?;
 
+    txn.commit().await?;
+
     info!(?job_id, trigger_datetime=?trigger_datetime.to_rfc3339(), key, "deleted job stash item");
 
     Ok(StatusCode::NO_CONTENT)
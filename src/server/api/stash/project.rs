@@ -1,13 +1,82 @@
-use crate::server::api::{auth, request_ext::RequestExt, State};
-use highnoon::{Json, Request, Responder, StatusCode};
+use crate::server::api::{
+    audit, auth,
+    paging::{list_response, Paging},
+    quota::{check_stash_quota, StashItem},
+    request_ext::RequestExt,
+    State,
+};
+use highnoon::{Request, Responder, Response, StatusCode};
+use serde_json::json;
+use sqlx::{Postgres, Transaction};
 use tracing::info;
 use uuid::Uuid;
 
-use super::{get_jwt_subject, StashData, StashName};
+use super::{
+    check_content_length, check_size, get_jwt_subject, ListStashQuery, StashData, StashInfo,
+    StashVersion, StashVersionQuery, LIST_STASH_SORT,
+};
 use cadence::CountedExt;
 
+/// Write a new version of an item, which becomes its current value. Versions
+/// carry on from the last one if the item was deleted and written again.
+async fn put_version(
+    txn: &mut Transaction<'_, Postgres>,
+    proj_id: Uuid,
+    key: &str,
+    data: &[u8],
+    author: Option<&str>,
+) -> highnoon::Result<i32> {
+    let (version,): (i32,) = sqlx::query_as(
+        "INSERT INTO project_stash(project_id, name, data, version, author, updated_datetime)
+        VALUES (
+            $1,
+            $2,
+            $3,
+            (
+                SELECT COALESCE(MAX(version), 0) + 1
+                FROM project_stash_version
+                WHERE project_id = $1
+                AND name = $2
+            ),
+            $4,
+            CURRENT_TIMESTAMP
+        )
+        ON CONFLICT (project_id, name)
+        DO UPDATE
+        SET data = $3,
+            version = COALESCE(project_stash.version, 0) + 1,
+            author = $4,
+            updated_datetime = CURRENT_TIMESTAMP
+        RETURNING version",
+    )
+    .bind(proj_id)
+    .bind(key)
+    .bind(data)
+    .bind(author)
+    .fetch_one(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO project_stash_version(
+            project_id, name, version, data, author, created_datetime
+        )
+        VALUES ($1, $2, $3, $4, $5, CURRENT_TIMESTAMP)",
+    )
+    .bind(proj_id)
+    .bind(key)
+    .bind(version)
+    .bind(data)
+    .bind(author)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(version)
+}
+
 pub async fn create(mut req: Request<State>) -> highnoon::Result<impl Responder> {
+    check_content_length(&req)?;
     let data = req.body_bytes().await?;
+    check_size(&req, data.len())?;
 
     let proj_id = req.param("id")?.parse::<Uuid>()?;
     let key = req.param("key")?;
@@ -20,25 +89,29 @@ pub async fn create(mut req: Request<State>) -> highnoon::Result<impl Responder>
 
     let db = req.get_pool();
 
-    sqlx::query(
-        "INSERT INTO project_stash(project_id, name, data)
-        VALUES ($1, $2, $3)
-        ON CONFLICT (project_id, name)
-        DO UPDATE
-        SET data = $3",
-    )
-    .bind(proj_id)
-    .bind(key)
-    .bind(&data)
-    .execute(&db)
-    .await?;
+    check_stash_quota(&db, proj_id, StashItem::Project(key), data.len()).await?;
 
-    info!(project_id=?proj_id, key, "created project stash item");
+    let mut txn = db.begin().await?;
+
+    let author = audit::principal(&req);
+    let version = put_version(&mut txn, proj_id, key, &data, author.as_deref()).await?;
+
+    info!(project_id=?proj_id, key, version, "created project stash item");
+
+    // only the size is recorded, stash items are often secrets
+    audit::update("project_stash")
+        .project(proj_id)
+        .object(key)
+        .details(json!({ "bytes": data.len(), "version": version }))
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
 
     Ok(StatusCode::CREATED)
 }
 
-pub async fn list(req: Request<State>) -> highnoon::Result<impl Responder> {
+pub async fn list(req: Request<State>) -> highnoon::Result<Response> {
     let db = req.get_pool();
 
     let proj_id = req.param("id")?.parse::<Uuid>()?;
@@ -49,45 +122,197 @@ pub async fn list(req: Request<State>) -> highnoon::Result<impl Responder> {
         .check(&req)
         .await?;
 
-    let rows: Vec<StashName> = sqlx::query_as(
-        "SELECT name
-        FROM project_stash
-        WHERE project_id = $1",
-    )
+    let query: ListStashQuery = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(LIST_STASH_SORT, "name")?;
+
+    let filter = "FROM project_stash
+        WHERE project_id = $1
+        AND ($2::VARCHAR IS NULL OR STRPOS(LOWER(name), LOWER($2)) > 0)";
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(proj_id)
+        .bind(&query.q)
+        .fetch_one(&db)
+        .await?;
+
+    let rows: Vec<StashInfo> = sqlx::query_as(&format!(
+        "SELECT
+            name,
+            COALESCE(LENGTH(data), 0) AS bytes,
+            version,
+            updated_datetime,
+            author
+        {filter}
+        ORDER BY {order_by}
+        LIMIT $3
+        OFFSET $4"
+    ))
     .bind(proj_id)
+    .bind(&query.q)
+    .bind(paging.limit(1000))
+    .bind(paging.offset())
     .fetch_all(&db)
     .await?;
 
-    Ok(Json(rows))
+    list_response(rows, total)
 }
 
-pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
+/// every version of an item, newest first
+pub async fn list_versions(req: Request<State>) -> highnoon::Result<Response> {
     let db = req.get_pool();
 
     let proj_id = req.param("id")?.parse::<Uuid>()?;
-    let task_id = get_jwt_subject(&req)?.parse::<Uuid>()?;
     let key = req.param("key")?;
 
-    info!(?proj_id, ?task_id, %key, "task requested project stash");
+    auth::list()
+        .project(proj_id)
+        .kind("stash")
+        .check(&req)
+        .await?;
+
+    let paging: Paging = req.query()?;
 
-    let row: Option<StashData> = sqlx::query_as(
+    let (total,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(1)
+        FROM project_stash_version
+        WHERE project_id = $1
+        AND name = $2",
+    )
+    .bind(proj_id)
+    .bind(key)
+    .fetch_one(&db)
+    .await?;
+
+    let rows: Vec<StashVersion> = sqlx::query_as(
+        "SELECT
+            version,
+            COALESCE(LENGTH(data), 0) AS bytes,
+            created_datetime,
+            author
+        FROM project_stash_version
+        WHERE project_id = $1
+        AND name = $2
+        ORDER BY version DESC
+        LIMIT $3
+        OFFSET $4",
+    )
+    .bind(proj_id)
+    .bind(key)
+    .bind(paging.limit(100))
+    .bind(paging.offset())
+    .fetch_all(&db)
+    .await?;
+
+    list_response(rows, total)
+}
+
+/// make an earlier version current again, by writing it as a new version
+pub async fn restore(req: Request<State>) -> highnoon::Result<StatusCode> {
+    let proj_id = req.param("id")?.parse::<Uuid>()?;
+    let key = req.param("key")?;
+    let from_version = req.param("version")?.parse::<i32>()?;
+
+    auth::update()
+        .project(proj_id)
+        .kind("stash")
+        .check(&req)
+        .await?;
+
+    let db = req.get_pool();
+
+    let data: Option<StashData> = sqlx::query_as(
         "SELECT data
-        FROM project_stash
+        FROM project_stash_version
         WHERE project_id = $1
-        AND (SELECT TRUE
-             FROM task t
-             JOIN job j ON j.id = t.job_id
-             WHERE t.id = $2
-             AND j.project_id = $1
-        )
-        AND name = $3",
+        AND name = $2
+        AND version = $3",
     )
     .bind(proj_id)
-    .bind(task_id)
     .bind(key)
+    .bind(from_version)
     .fetch_optional(&db)
     .await?;
 
+    let data = match data {
+        Some(data) => data.0,
+        None => return Ok(StatusCode::NOT_FOUND),
+    };
+
+    check_stash_quota(&db, proj_id, StashItem::Project(key), data.len()).await?;
+
+    let mut txn = db.begin().await?;
+
+    let author = audit::principal(&req);
+    let version = put_version(&mut txn, proj_id, key, &data, author.as_deref()).await?;
+
+    info!(project_id=?proj_id, key, from_version, version, "restored project stash item");
+
+    audit::action("restore", "project_stash")
+        .project(proj_id)
+        .object(key)
+        .details(json!({ "from_version": from_version, "version": version }))
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let db = req.get_pool();
+
+    let proj_id = req.param("id")?.parse::<Uuid>()?;
+    let task_id = get_jwt_subject(&req)?.parse::<Uuid>()?;
+    let key = req.param("key")?;
+    let query: StashVersionQuery = req.query()?;
+
+    info!(?proj_id, ?task_id, %key, version=?query.version, "task requested project stash");
+
+    let row: Option<StashData> = match query.version {
+        Some(version) => {
+            sqlx::query_as(
+                "SELECT data
+                FROM project_stash_version
+                WHERE project_id = $1
+                AND (SELECT TRUE
+                     FROM task t
+                     JOIN job j ON j.id = t.job_id
+                     WHERE t.id = $2
+                     AND j.project_id = $1
+                )
+                AND name = $3
+                AND version = $4",
+            )
+            .bind(proj_id)
+            .bind(task_id)
+            .bind(key)
+            .bind(version)
+            .fetch_optional(&db)
+            .await?
+        }
+        None => {
+            sqlx::query_as(
+                "SELECT data
+                FROM project_stash
+                WHERE project_id = $1
+                AND (SELECT TRUE
+                     FROM task t
+                     JOIN job j ON j.id = t.job_id
+                     WHERE t.id = $2
+                     AND j.project_id = $1
+                )
+                AND name = $3",
+            )
+            .bind(proj_id)
+            .bind(task_id)
+            .bind(key)
+            .fetch_optional(&db)
+            .await?
+        }
+    };
+
     req.get_statsd()
         .incr_with_tags("stash.get")
         .with_tag_value("project")
@@ -97,6 +322,7 @@ pub async fn get(req: Request<State>) -> highnoon::Result<impl Responder> {
     Ok(row)
 }
 
+/// Delete an item. Its versions are kept, so it can be restored.
 pub async fn delete(req: Request<State>) -> highnoon::Result<impl Responder> {
     let db = req.get_pool();
 
@@ -122,5 +348,11 @@ pub async fn delete(req: Request<State>) -> highnoon::Result<impl Responder> {
 
     info!(?proj_id, ?key, "deleted project stash item");
 
+    audit::delete("project_stash")
+        .project(proj_id)
+        .object(key)
+        .record(&req, &db)
+        .await?;
+
     Ok(StatusCode::NO_CONTENT)
 }
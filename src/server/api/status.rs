@@ -1,18 +1,34 @@
-use crate::server::api::{auth, request_ext::RequestExt, State};
-use highnoon::{Json, Request, Responder};
-use serde::Serialize;
+use crate::{
+    messages::{TaskPriority, DEFAULT_QUEUE},
+    server::api::{
+        auth,
+        paging::{list_response, Paging},
+        request_ext::RequestExt,
+        State,
+    },
+    util::first,
+};
+use chrono::{DateTime, Utc};
+use highnoon::{Json, Request, Responder, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
 
 #[derive(Serialize, sqlx::FromRow)]
 pub struct ServerStatus {
     pub num_projects: i64,
     pub num_workers: i64,
     pub running_tasks: i64,
+    #[sqlx(default)]
+    pub read_only: bool,
 }
 
 pub async fn status(req: Request<State>) -> highnoon::Result<impl Responder> {
     auth::get().kind("status").check(&req).await?;
 
-    let status: ServerStatus = sqlx::query_as(
+    let mut status: ServerStatus = sqlx::query_as(
         "SELECT
             (
                 SELECT COUNT(1)
@@ -32,5 +48,248 @@ pub async fn status(req: Request<State>) -> highnoon::Result<impl Responder> {
     .fetch_one(&req.get_pool())
     .await?;
 
+    status.read_only = req.state().config.read_only;
+
     Ok(Json(status))
 }
+
+#[derive(Deserialize)]
+struct SummaryQuery {
+    /// comma separated durations, eg. "7d,30d"
+    windows: Option<String>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct SummaryTotals {
+    num_jobs: i64,
+    num_paused_jobs: i64,
+    num_triggers: i64,
+    num_tasks: i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct WindowTotals {
+    total_runs: i64,
+    success_runs: i64,
+    median_queue_secs: Option<f64>,
+    median_duration_secs: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct SummaryWindow {
+    window: String,
+    days: i64,
+    total_runs: i64,
+    runs_per_day: f64,
+    success_rate: Option<f64>,
+    /// approximate - the daily medians averaged over the window, weighted by runs
+    median_queue_secs: Option<f64>,
+    median_duration_secs: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    #[serde(flatten)]
+    totals: SummaryTotals,
+    windows: Vec<SummaryWindow>,
+}
+
+/// Totals and task run statistics suitable for a periodic reliability report.
+/// Run statistics come from the daily rollup maintained by the scheduler.
+pub async fn summary(req: Request<State>) -> highnoon::Result<impl Responder> {
+    auth::get().kind("status").check(&req).await?;
+
+    let query: SummaryQuery = req.query()?;
+    let windows = query.windows.as_deref().unwrap_or("1d,7d,30d");
+
+    let pool = req.get_pool();
+
+    let totals: SummaryTotals = sqlx::query_as(
+        "SELECT
+            (SELECT COUNT(1) FROM job) AS num_jobs,
+            (SELECT COUNT(1) FROM job WHERE paused) AS num_paused_jobs,
+            (SELECT COUNT(1) FROM trigger) AS num_triggers,
+            (SELECT COUNT(1) FROM task) AS num_tasks",
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    let mut summary_windows = Vec::new();
+    for window in windows.split(',').map(str::trim) {
+        let duration = humantime::parse_duration(window)
+            .map_err(|err| highnoon::Error::bad_request(format!("invalid window '{window}': {err}")))?;
+        let days = ((duration.as_secs() + SECS_PER_DAY - 1) / SECS_PER_DAY).max(1) as i64;
+
+        let row: WindowTotals = sqlx::query_as(
+            "SELECT
+                COALESCE(SUM(total_runs), 0)::BIGINT AS total_runs,
+                COALESCE(SUM(success_runs), 0)::BIGINT AS success_runs,
+                SUM(median_queue_secs * total_runs) / NULLIF(SUM(total_runs), 0) AS median_queue_secs,
+                SUM(median_duration_secs * total_runs) / NULLIF(SUM(total_runs), 0) AS median_duration_secs
+            FROM job_run_daily
+            WHERE day > (CURRENT_TIMESTAMP AT TIME ZONE 'UTC')::DATE - $1::INT",
+        )
+        .bind(days as i32)
+        .fetch_one(&pool)
+        .await?;
+
+        summary_windows.push(SummaryWindow {
+            window: window.to_owned(),
+            days,
+            total_runs: row.total_runs,
+            runs_per_day: row.total_runs as f64 / days as f64,
+            success_rate: if row.total_runs > 0 {
+                Some(row.success_runs as f64 / row.total_runs as f64)
+            } else {
+                None
+            },
+            median_queue_secs: row.median_queue_secs,
+            median_duration_secs: row.median_duration_secs,
+        });
+    }
+
+    Ok(Json(Summary {
+        totals,
+        windows: summary_windows,
+    }))
+}
+
+#[derive(Serialize, Default)]
+struct QueueDepth {
+    /// sent to the workers and waiting for one to start them
+    queued: i64,
+    running: i64,
+    /// the queued tasks by priority
+    priorities: BTreeMap<TaskPriority, i64>,
+}
+
+#[derive(Serialize, Default)]
+struct QueueDepths {
+    queued: i64,
+    running: i64,
+    queues: BTreeMap<String, QueueDepth>,
+}
+
+/// How many tasks are waiting in each task queue, and how many are running, for
+/// scaling workers on their backlog (eg. with KEDA's `metrics-api` scaler).
+/// Tasks held back by `max_queued_tasks` or quotas aren't in any queue yet.
+pub async fn queue_depths(req: Request<State>) -> highnoon::Result<impl Responder> {
+    auth::get().kind("status").check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let rows: Vec<(String, TaskPriority, String, i64)> = sqlx::query_as(
+        "SELECT
+            COALESCE(t.queue, CASE
+                WHEN COALESCE((t.resources->>'gpus')::INT, 0) > 0 THEN 'gpu'
+                ELSE $1
+            END),
+            r.priority,
+            r.state,
+            COUNT(1)
+        FROM task_run r
+        JOIN task t ON t.id = r.task_id
+        WHERE r.state IN ('active', 'running')
+        AND t.sensor IS NULL
+        GROUP BY 1, 2, 3",
+    )
+    .bind(DEFAULT_QUEUE)
+    .fetch_all(&pool)
+    .await?;
+
+    // every queue a task names is reported even when it's empty, as a scaler
+    // treats a missing value as an error rather than zero
+    let named: Vec<(String,)> = sqlx::query_as(
+        "SELECT DISTINCT queue
+        FROM task
+        WHERE queue IS NOT NULL",
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut depths = QueueDepths::default();
+    for queue in named.into_iter().map(first) {
+        depths.queues.insert(queue, QueueDepth::default());
+    }
+    depths.queues.entry(DEFAULT_QUEUE.to_owned()).or_default();
+
+    for (queue, priority, state, count) in rows {
+        let depth = depths.queues.entry(queue).or_default();
+
+        if state == "running" {
+            depth.running += count;
+            depths.running += count;
+        } else {
+            depth.queued += count;
+            *depth.priorities.entry(priority).or_default() += count;
+            depths.queued += count;
+        }
+    }
+
+    Ok(Json(depths))
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct UnmappedResult {
+    project_name: String,
+    job_name: String,
+    job_id: Uuid,
+    task_name: String,
+    task_id: Uuid,
+    trigger_datetime: DateTime<Utc>,
+    task_run_id: Option<Uuid>,
+    created_datetime: DateTime<Utc>,
+    details: Option<String>,
+}
+
+const UNMAPPED_RESULT_SORT: &[(&str, &str)] = &[
+    ("created_datetime", "h.created_datetime"),
+    ("trigger_datetime", "h.trigger_datetime"),
+    ("job_name", "j.name"),
+];
+
+/// Recent task results that none of the task's edges handled, recorded when
+/// `strict_results` is enabled. These are usually typos in an edge kind.
+pub async fn unmapped_results(req: Request<State>) -> highnoon::Result<Response> {
+    auth::list().kind("status").check(&req).await?;
+
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(UNMAPPED_RESULT_SORT, "-created_datetime")?;
+
+    let pool = req.get_pool();
+
+    let (total,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(1)
+        FROM token_history
+        WHERE event = 'unmapped_result'",
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    let rows: Vec<UnmappedResult> = sqlx::query_as(&format!(
+        "SELECT
+            p.name AS project_name,
+            j.name AS job_name,
+            j.id AS job_id,
+            t.name AS task_name,
+            t.id AS task_id,
+            h.trigger_datetime,
+            h.task_run_id,
+            h.created_datetime,
+            h.details
+        FROM token_history h
+        JOIN task t ON t.id = h.task_id
+        JOIN job j ON j.id = t.job_id
+        JOIN project p ON p.id = j.project_id
+        WHERE h.event = 'unmapped_result'
+        ORDER BY {order_by}
+        LIMIT $1
+        OFFSET $2"
+    ))
+    .bind(paging.limit(100))
+    .bind(paging.offset())
+    .fetch_all(&pool)
+    .await?;
+
+    list_response(rows, total)
+}
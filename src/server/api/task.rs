@@ -1,15 +1,35 @@
 use crate::{
-    messages::{ProcessToken, TaskDef, TaskPriority, Token},
-    server::api::{auth, jwt, request_ext::RequestExt, updates, State},
+    messages::{
+        DockerOptions, ImagePullPolicy, LambdaInvoke, PodContainers, ProcessToken, SecretEnv,
+        Staging, TaskDef, TaskPriority, TaskProgress, TaskResources, Token, TokenState,
+        WorkerCommand, WorkerControl,
+    },
+    server::{
+        api::{audit, auth, jwt, request_ext::RequestExt, updates, worker_control, State},
+        settings,
+        token_history::{self, Actor, TokenEvent},
+    },
 };
 use chrono::{DateTime, Utc};
 use futures::TryStreamExt;
 use highnoon::{Json, Request, Responder, Response, StatusCode};
 use std::time::Duration;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
 use uuid::Uuid;
 
-#[derive(Deserialize)]
+pub async fn get_task_job_id(pool: &PgPool, task_id: Uuid) -> highnoon::Result<Uuid> {
+    let row: Option<(Uuid,)> = sqlx::query_as("SELECT job_id FROM task WHERE id = $1")
+        .bind(task_id)
+        .fetch_optional(pool)
+        .await?;
+
+    row.map(|(job_id,)| job_id)
+        .ok_or_else(|| highnoon::Error::http((StatusCode::NOT_FOUND, "task not found")))
+}
+
+#[derive(Serialize, Deserialize)]
 struct ActivateTokenParams {
     priority: Option<TaskPriority>,
 }
@@ -19,14 +39,20 @@ pub async fn activate_token(mut req: Request<State>) -> highnoon::Result<impl Re
     let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
     let params: ActivateTokenParams = req.body_json().await?;
 
-    // TODO auth check
+    let pool = req.get_pool();
+    let job_id = get_task_job_id(&pool, task_id).await?;
+
+    auth::update()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
 
     let token = Token {
         task_id,
         trigger_datetime,
     };
 
-    let pool = req.get_pool();
     let mut txn = pool.begin().await?;
 
     sqlx::query(
@@ -42,16 +68,378 @@ pub async fn activate_token(mut req: Request<State>) -> highnoon::Result<impl Re
     .execute(&mut txn)
     .await?;
 
-    let priority = params.priority.unwrap_or(TaskPriority::High);
+    token_history::record(
+        &mut txn,
+        std::slice::from_ref(&token),
+        TokenEvent::Activate,
+        Actor::Operator,
+        None,
+        None,
+    )
+    .await?;
+
+    audit::action("activate", "token")
+        .job(job_id, None)
+        .task(task_id)
+        .object(&token)
+        .details(&params)
+        .record(&req, &mut txn)
+        .await?;
+
+    let priority = params
+        .priority
+        .unwrap_or_else(|| settings::current(&req.state().config).activate_priority);
 
-    updates::send_token_update(req.get_channel(), ProcessToken::Activate(token, priority)).await?;
+    updates::send_token_update(req.get_broker(), ProcessToken::Activate(token, priority)).await?;
 
     txn.commit().await?;
 
     Ok(StatusCode::CREATED)
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
+struct RerunTokenParams {
+    priority: Option<TaskPriority>,
+}
+
+#[derive(Serialize)]
+struct RerunTokenReply {
+    downstream_cleared: u64,
+}
+
+/// Clear the token for a task and every token downstream of it, then activate
+/// only the task itself. The downstream tasks will run again as the tokens flow down.
+pub async fn rerun_token(mut req: Request<State>) -> highnoon::Result<Response> {
+    let task_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
+    let params: RerunTokenParams = req.body_json().await?;
+
+    let pool = req.get_pool();
+
+    let maybe_job: Option<(Uuid,)> = sqlx::query_as("SELECT job_id FROM task WHERE id = $1")
+        .bind(task_id)
+        .fetch_optional(&pool)
+        .await?;
+
+    let job_id = match maybe_job {
+        Some((job_id,)) => job_id,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    auth::update()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
+    let mut txn = pool.begin().await?;
+
+    // follow the edges (and their offsets) through the existing tokens only,
+    // so self-dependencies on other trigger times don't recurse forever
+    let downstream: Vec<(Uuid, DateTime<Utc>)> = sqlx::query_as(
+        "WITH RECURSIVE downstream(task_id, trigger_datetime) AS (
+            SELECT $1::UUID, $2::TIMESTAMP WITH TIME ZONE
+            UNION
+            SELECT
+                k.task_id,
+                k.trigger_datetime
+            FROM downstream d
+            JOIN task_edge e ON e.parent_task_id = d.task_id
+            JOIN token k
+                ON k.task_id = e.child_task_id
+                AND k.trigger_datetime =
+                    d.trigger_datetime + (INTERVAL '1s' * COALESCE(e.edge_offset, 0))
+        )
+        UPDATE token k
+        SET count = 0,
+            state = 'waiting'
+        FROM downstream d
+        WHERE k.task_id = d.task_id
+        AND k.trigger_datetime = d.trigger_datetime
+        AND NOT (k.task_id = $1 AND k.trigger_datetime = $2)
+        RETURNING k.task_id, k.trigger_datetime",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_all(&mut txn)
+    .await?;
+
+    let token = Token {
+        task_id,
+        trigger_datetime,
+    };
+
+    sqlx::query(
+        "INSERT INTO token(task_id, trigger_datetime, count, state)
+            VALUES ($1, $2, (SELECT threshold FROM task WHERE id = $1), 'waiting')
+            ON CONFLICT(task_id, trigger_datetime)
+            DO UPDATE
+            SET count = (SELECT threshold FROM task WHERE id = $1),
+                state = 'waiting'",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .execute(&mut txn)
+    .await?;
+
+    let downstream_tokens: Vec<Token> = downstream
+        .iter()
+        .map(|&(task_id, trigger_datetime)| Token {
+            task_id,
+            trigger_datetime,
+        })
+        .collect();
+    let details = format!("rerun of {token}");
+    token_history::record(
+        &mut txn,
+        &downstream_tokens,
+        TokenEvent::Clear,
+        Actor::Operator,
+        None,
+        Some(&details),
+    )
+    .await?;
+    token_history::record(
+        &mut txn,
+        std::slice::from_ref(&token),
+        TokenEvent::Activate,
+        Actor::Operator,
+        None,
+        Some("rerun"),
+    )
+    .await?;
+
+    audit::action("rerun", "token")
+        .job(job_id, None)
+        .task(task_id)
+        .object(&token)
+        .details(&params)
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    for &(task_id, trigger_datetime) in &downstream {
+        let token = Token {
+            task_id,
+            trigger_datetime,
+        };
+        updates::send_token_update(req.get_broker(), ProcessToken::Clear(token)).await?;
+    }
+
+    let priority = params
+        .priority
+        .unwrap_or_else(|| settings::current(&req.state().config).activate_priority);
+    updates::send_token_update(req.get_broker(), ProcessToken::Activate(token, priority)).await?;
+
+    Json(RerunTokenReply {
+        downstream_cleared: downstream.len() as u64,
+    })
+    .into_response()
+}
+
+#[derive(Serialize, Deserialize)]
+struct SetTaskRunStateParams {
+    state: TokenState,
+}
+
+#[derive(Serialize)]
+struct SetTaskRunStateReply {
+    task_run_id: Uuid,
+}
+
+/// Manually mark a task run as succeeded or failed. This records an operator override
+/// task_run and sends the result to the scheduler, which advances the downstream tokens
+/// as if a worker had reported it.
+pub async fn set_task_run_state(mut req: Request<State>) -> highnoon::Result<Response> {
+    let task_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
+    let params: SetTaskRunStateParams = req.body_json().await?;
+
+    if !matches!(params.state, TokenState::Success | TokenState::Failure) {
+        return Err(highnoon::Error::bad_request(
+            "state must be 'success' or 'failure'",
+        ));
+    }
+
+    let pool = req.get_pool();
+
+    let maybe_job: Option<(Uuid,)> = sqlx::query_as("SELECT job_id FROM task WHERE id = $1")
+        .bind(task_id)
+        .fetch_optional(&pool)
+        .await?;
+
+    let job_id = match maybe_job {
+        Some((job_id,)) => job_id,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    auth::update()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
+    let mut txn = pool.begin().await?;
+
+    // the run's own result would advance the downstream tasks a second time
+    let in_progress: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT id
+        FROM task_run
+        WHERE task_id = $1
+        AND trigger_datetime = $2
+        AND state IN ('active', 'running')
+        LIMIT 1
+        FOR UPDATE",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_optional(&mut txn)
+    .await?;
+
+    if in_progress.is_some() {
+        return Err(highnoon::Error::http((
+            StatusCode::CONFLICT,
+            "the task is queued or running, kill it before setting its state",
+        )));
+    }
+
+    let task_run_id = Uuid::new_v4();
+    let now = Utc::now();
+
+    sqlx::query(
+        "INSERT INTO token(task_id, trigger_datetime, count, state)
+            VALUES ($1, $2, 0, $3)
+            ON CONFLICT(task_id, trigger_datetime)
+            DO UPDATE
+            SET count = 0,
+                state = $3",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .bind(params.state)
+    .execute(&mut txn)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO task_run(id, task_id, trigger_datetime,
+            queued_datetime, started_datetime, finish_datetime, updated_datetime,
+            worker_id, state, priority, attempt, operator_override)
+        VALUES ($1, $2, $3,
+            $4, $4, $4, $4,
+            NULL, $5, $6,
+            COALESCE((
+                SELECT MAX(attempt)
+                FROM task_run
+                WHERE task_id = $2
+                AND trigger_datetime = $3
+            ), 0),
+            TRUE)",
+    )
+    .bind(task_run_id)
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .bind(now)
+    .bind(params.state)
+    .bind(TaskPriority::High)
+    .execute(&mut txn)
+    .await?;
+
+    audit::action("set_state", "task_run")
+        .job(job_id, None)
+        .task(task_id)
+        .object(task_run_id)
+        .details(&params)
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    updates::send_task_progress(
+        req.get_broker(),
+        TaskProgress {
+            task_run_id,
+            task_id,
+            trigger_datetime,
+            started_datetime: now,
+            finished_datetime: Some(now),
+            result: params.state,
+            worker_id: None,
+            error_details: None,
+            operator_override: true,
+            outputs: None,
+            exit_code: None,
+            error_class: None,
+        },
+    )
+    .await?;
+
+    Json(SetTaskRunStateReply { task_run_id }).into_response()
+}
+
+/// Kill a task run while it's running. Its worker stops the container (or
+/// deletes the Kubernetes job) and reports the run as cancelled.
+pub async fn kill_task_run(req: Request<State>) -> highnoon::Result<StatusCode> {
+    let task_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
+
+    let pool = req.get_pool();
+    let job_id = get_task_job_id(&pool, task_id).await?;
+
+    auth::update()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
+    // the killed time stops the run being requeued once it's cancelled
+    let running: Option<(Uuid, Uuid)> = sqlx::query_as(
+        "UPDATE task_run
+        SET killed_datetime = CURRENT_TIMESTAMP
+        WHERE id = (
+            SELECT id
+            FROM task_run
+            WHERE task_id = $1
+            AND trigger_datetime = $2
+            AND state = $3
+            AND worker_id IS NOT NULL
+            ORDER BY attempt DESC
+            LIMIT 1
+        )
+        RETURNING id, worker_id",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .bind(TokenState::Running)
+    .fetch_optional(&pool)
+    .await?;
+
+    let (task_run_id, worker_id) = match running {
+        Some(running) => running,
+        None => return Ok(StatusCode::NOT_FOUND),
+    };
+
+    worker_control::send(
+        req.get_broker(),
+        WorkerControl {
+            worker_id: Some(worker_id),
+            command: WorkerCommand::Kill { task_run_id },
+        },
+    )
+    .await?;
+
+    audit::action("kill", "task_run")
+        .job(job_id, None)
+        .task(task_id)
+        .object(task_run_id)
+        .details(json!({ "worker_id": worker_id }))
+        .record(&req, &pool)
+        .await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Serialize, Deserialize)]
 struct ActivateMultipleTokensParams {
     priority: Option<TaskPriority>,
     first: Option<DateTime<Utc>>,
@@ -76,9 +464,15 @@ pub async fn activate_multiple_tokens(mut req: Request<State>) -> highnoon::Resu
             .into_response();
     }
 
-    // TODO auth check
-
     let pool = req.get_pool();
+    let job_id = get_task_job_id(&pool, task_id).await?;
+
+    auth::update()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
     let mut txn = pool.begin().await?;
 
     let mut cursor = sqlx::query_as(
@@ -99,21 +493,41 @@ pub async fn activate_multiple_tokens(mut req: Request<State>) -> highnoon::Resu
 
     let priority = params.priority.unwrap_or(TaskPriority::BackFill);
 
-    let mut count = 0u64;
+    let mut activated = Vec::new();
     while let Some((trigger_datetime,)) = cursor.try_next().await? {
-        count += 1;
-
         let token = Token {
             task_id,
             trigger_datetime,
         };
 
-        updates::send_token_update(req.get_channel(), ProcessToken::Activate(token, priority))
-            .await?;
+        updates::send_token_update(
+            req.get_broker(),
+            ProcessToken::Activate(token.clone(), priority),
+        )
+        .await?;
+        activated.push(token);
     }
 
     drop(cursor);
 
+    let count = activated.len() as u64;
+    token_history::record(
+        &mut txn,
+        &activated,
+        TokenEvent::Activate,
+        Actor::Operator,
+        None,
+        None,
+    )
+    .await?;
+
+    audit::action("activate", "token")
+        .job(job_id, None)
+        .task(task_id)
+        .details(json!({ "params": &params, "activated": count }))
+        .record(&req, &mut txn)
+        .await?;
+
     txn.commit().await?;
 
     Json(ActivateTokenReply { cleared: count }).into_response()
@@ -152,10 +566,19 @@ struct DbTaskDef {
     pub project_id: Uuid,
     pub project_name: String,
     pub image: Option<String>,
+    pub wasm_module: Option<String>,
+    pub command: Option<String>,
     pub args: Vec<String>,
     pub env: Option<Vec<String>>,
+    pub secret_env: sqlx::types::Json<Vec<SecretEnv>>,
     pub paused: bool,
     pub timeout_secs: Option<i64>,
+    pub resources: Option<sqlx::types::Json<TaskResources>>,
+    pub image_pull_policy: Option<ImagePullPolicy>,
+    pub lambda: Option<sqlx::types::Json<LambdaInvoke>>,
+    pub docker_options: Option<sqlx::types::Json<DockerOptions>>,
+    pub pod_containers: Option<sqlx::types::Json<PodContainers>>,
+    pub staging: Option<sqlx::types::Json<Staging>>,
 }
 
 impl From<DbTaskDef> for TaskDef {
@@ -168,10 +591,19 @@ impl From<DbTaskDef> for TaskDef {
             project_id: other.project_id,
             project_name: other.project_name,
             image: other.image,
+            wasm_module: other.wasm_module,
+            command: other.command,
             args: other.args,
             env: other.env,
+            secret_env: other.secret_env.0,
             paused: other.paused,
             timeout: other.timeout_secs.map(|secs| Duration::from_secs(secs as u64)),
+            resources: other.resources.map(|resources| resources.0),
+            image_pull_policy: other.image_pull_policy,
+            lambda: other.lambda.map(|lambda| lambda.0),
+            docker_options: other.docker_options.map(|options| options.0),
+            pod_containers: other.pod_containers.map(|containers| containers.0),
+            staging: other.staging.map(|staging| staging.0),
         }
     }
 }
@@ -188,10 +620,19 @@ async fn get_task_def_common(req: &Request<State>) -> highnoon::Result<Option<Ta
                 p.id AS project_id,
                 p.name AS project_name,
                 image,
+                wasm_module,
+                command,
                 COALESCE(args, ARRAY[]::VARCHAR[]) AS args,
                 env,
+                secret_env,
                 j.paused,
-                t.timeout_secs
+                t.timeout_secs,
+                t.resources,
+                t.image_pull_policy,
+                t.lambda,
+                t.docker_options,
+                t.pod_containers,
+                t.staging
             FROM task t
             JOIN job j on t.job_id = j.id
             JOIN project p ON j.project_id = p.id
@@ -1,13 +1,29 @@
-use super::State;
+use super::{
+    auth, jwt,
+    oidc::redirect,
+    paging::{list_response, Paging},
+    request_ext::RequestExt,
+    task::get_task_job_id,
+    State,
+};
+use crate::log_store::{LogLine, LogWriter, PostgresLogStore};
+use chrono::{DateTime, Utc};
 use highnoon::{
     ws::{WebSocketReceiver, WebSocketSender},
-    Message, Request,
+    Message, Request, Response, StatusCode,
 };
 use redis::{
     streams::{StreamReadOptions, StreamReadReply},
     AsyncCommands,
 };
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::time::Duration;
 use tracing::{debug, trace};
+use uuid::Uuid;
+
+/// how long a link to an archived log works for
+const ARCHIVE_LINK_EXPIRY: Duration = Duration::from_secs(15 * 60);
 
 fn get_as_string(value: &redis::Value) -> highnoon::Result<String> {
     match value {
@@ -16,6 +32,7 @@ fn get_as_string(value: &redis::Value) -> highnoon::Result<String> {
     }
 }
 
+/// tail a running task's logs from Redis
 pub async fn logs(
     req: Request<State>,
     mut tx: WebSocketSender,
@@ -57,3 +74,139 @@ pub async fn logs(
         id = reply.keys[0].ids.last().unwrap().id.clone();
     }
 }
+
+#[derive(Deserialize)]
+struct TaskLogsQuery {
+    /// which attempt to get the logs of, defaults to the latest
+    attempt: Option<i64>,
+}
+
+/// a task run's stored logs, a page of lines at a time
+pub async fn list(req: Request<State>) -> highnoon::Result<Response> {
+    let task_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime: DateTime<Utc> = req.param("trigger_datetime")?.parse()?;
+    let query: TaskLogsQuery = req.query()?;
+    let paging: Paging = req.query()?;
+
+    let pool = req.get_pool();
+    let job_id = get_task_job_id(&pool, task_id).await?;
+
+    auth::list()
+        .job(job_id, None)
+        .kind("logs")
+        .check(&req)
+        .await?;
+
+    let reader = req.state().log_reader.as_ref().ok_or_else(|| {
+        highnoon::Error::http((
+            StatusCode::NOT_FOUND,
+            "task logs aren't stored (WATERWHEEL_LOG_STORE is none)",
+        ))
+    })?;
+
+    let (task_run_id, _) = find_task_run(&pool, task_id, trigger_datetime, query.attempt).await?;
+
+    let page = reader
+        .read(task_run_id, paging.offset(), paging.limit(1000))
+        .await?;
+
+    list_response(page.lines, page.total)
+}
+
+/// the id of a task run, and where its logs are archived if they are
+async fn find_task_run(
+    pool: &PgPool,
+    task_id: Uuid,
+    trigger_datetime: DateTime<Utc>,
+    attempt: Option<i64>,
+) -> highnoon::Result<(Uuid, Option<String>)> {
+    let task_run: Option<(Uuid, Option<String>)> = sqlx::query_as(
+        "SELECT id, log_location
+        FROM task_run
+        WHERE task_id = $1
+        AND trigger_datetime = $2
+        AND ($3::BIGINT IS NULL OR attempt = $3)
+        ORDER BY attempt DESC, queued_datetime DESC
+        LIMIT 1",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .bind(attempt)
+    .fetch_optional(pool)
+    .await?;
+
+    task_run.ok_or_else(|| highnoon::Error::http((StatusCode::NOT_FOUND, "task run not found")))
+}
+
+/// redirect to a task run's whole output in the log archive
+pub async fn archive(req: Request<State>) -> highnoon::Result<Response> {
+    let task_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime: DateTime<Utc> = req.param("trigger_datetime")?.parse()?;
+    let query: TaskLogsQuery = req.query()?;
+
+    let pool = req.get_pool();
+    let job_id = get_task_job_id(&pool, task_id).await?;
+
+    auth::list()
+        .job(job_id, None)
+        .kind("logs")
+        .check(&req)
+        .await?;
+
+    let archive = req.state().log_archive.as_ref().ok_or_else(|| {
+        highnoon::Error::http((
+            StatusCode::NOT_FOUND,
+            "task logs aren't archived (WATERWHEEL_LOG_ARCHIVE_BUCKET is unset)",
+        ))
+    })?;
+
+    let (_, location) = find_task_run(&pool, task_id, trigger_datetime, query.attempt).await?;
+    let location = location.ok_or_else(|| {
+        highnoon::Error::http((StatusCode::NOT_FOUND, "the task run's logs aren't archived"))
+    })?;
+
+    let link = archive.presign(&location, ARCHIVE_LINK_EXPIRY).await?;
+
+    Ok(redirect(&link))
+}
+
+#[derive(Deserialize)]
+struct ArchiveLocation {
+    location: String,
+}
+
+/// workers say where they've archived a task run's logs here
+pub async fn store_archive(mut req: Request<State>) -> highnoon::Result<Response> {
+    let task_run_id = req.param("id")?.parse::<Uuid>()?;
+
+    jwt::validate_logs_jwt(&req, task_run_id)?;
+
+    let archive: ArchiveLocation = req.body_json().await?;
+
+    sqlx::query(
+        "UPDATE task_run
+        SET log_location = $2
+        WHERE id = $1",
+    )
+    .bind(task_run_id)
+    .bind(archive.location)
+    .execute(&req.get_pool())
+    .await?;
+
+    Ok(Response::status(StatusCode::NO_CONTENT))
+}
+
+/// workers send the logs of the tasks they run here, with the `server` log store
+pub async fn store(mut req: Request<State>) -> highnoon::Result<Response> {
+    let task_run_id = req.param("id")?.parse::<Uuid>()?;
+
+    jwt::validate_logs_jwt(&req, task_run_id)?;
+
+    let lines: Vec<LogLine> = req.body_json().await?;
+
+    PostgresLogStore::new(req.get_pool())
+        .write(task_run_id, &lines)
+        .await?;
+
+    Ok(Response::status(StatusCode::NO_CONTENT))
+}
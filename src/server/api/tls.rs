@@ -0,0 +1,85 @@
+//! TLS for the internal endpoints, with workers required to present a client
+//! certificate signed by `internal_ca_cert`. highnoon only serves plain HTTP, so
+//! connections are decrypted here and passed on to the internal app, which
+//! listens on a loopback port.
+
+use crate::config::Config;
+use anyhow::{Context, Result};
+use std::{fs::File, io::BufReader, net::SocketAddr, sync::Arc};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore},
+    TlsAcceptor,
+};
+use tracing::debug;
+
+/// the TLS config for the internal endpoints, if `internal_tls_cert` is set
+pub fn acceptor(config: &Config) -> Result<Option<TlsAcceptor>> {
+    let (cert, key) = match (&config.internal_tls_cert, &config.internal_tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => anyhow::bail!("internal_tls_cert and internal_tls_key must be set together"),
+    };
+
+    let ca = config
+        .internal_ca_cert
+        .as_ref()
+        .context("internal_ca_cert must be set to verify the workers' client certificates")?;
+
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&read_pem(ca, rustls_pemfile::certs)?);
+    if added == 0 {
+        anyhow::bail!("no certificates in '{ca}'");
+    }
+
+    let certs = read_pem(cert, rustls_pemfile::certs)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = read_pem(key, rustls_pemfile::pkcs8_private_keys)?
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .with_context(|| format!("no PKCS #8 private key in '{key}'"))?;
+
+    let tls_config = tokio_rustls::rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        .with_single_cert(certs, key)
+        .context("invalid internal_tls_cert or internal_tls_key")?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(tls_config))))
+}
+
+fn read_pem(
+    path: &str,
+    parse: fn(&mut dyn std::io::BufRead) -> std::io::Result<Vec<Vec<u8>>>,
+) -> Result<Vec<Vec<u8>>> {
+    let file = File::open(path).with_context(|| format!("failed to read '{path}'"))?;
+    parse(&mut BufReader::new(file)).with_context(|| format!("invalid PEM in '{path}'"))
+}
+
+/// accept TLS connections on `bind`, passing what's decrypted to `upstream`
+pub async fn serve(bind: &str, acceptor: TlsAcceptor, upstream: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = proxy(stream, acceptor, upstream).await {
+                debug!(%peer, "internal TLS connection failed: {:#}", err);
+            }
+        });
+    }
+}
+
+async fn proxy(stream: TcpStream, acceptor: TlsAcceptor, upstream: SocketAddr) -> Result<()> {
+    // the handshake fails unless the client presents a certificate signed by the CA
+    let mut tls = acceptor.accept(stream).await?;
+    let mut upstream = TcpStream::connect(upstream).await?;
+
+    tokio::io::copy_bidirectional(&mut tls, &mut upstream).await?;
+    Ok(())
+}
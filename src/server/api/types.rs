@@ -1,7 +1,12 @@
+use crate::messages::{
+    ContainerOs, ImagePullPolicy, LambdaInvoke, SecretEnv, SensorCheck, StagedInput, StagedOutput,
+};
+use anyhow::format_err;
 use chrono::{DateTime, Utc};
 /// API Types - used to parse the YAML file.
 /// These get converted into internal types
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
 pub fn duration_from_string(period: Option<&str>) -> anyhow::Result<Option<i32>> {
@@ -22,6 +27,34 @@ pub fn duration_from_string(period: Option<&str>) -> anyhow::Result<Option<i32>>
     }
 }
 
+/// Parse a memory size in bytes, with an optional Kubernetes style suffix, eg.
+/// `512Mi` or `1G`
+pub fn memory_from_string(s: &str) -> anyhow::Result<u64> {
+    const SUFFIXES: &[(&str, u64)] = &[
+        ("Ki", 1 << 10),
+        ("Mi", 1 << 20),
+        ("Gi", 1 << 30),
+        ("Ti", 1 << 40),
+        ("K", 1_000),
+        ("M", 1_000_000),
+        ("G", 1_000_000_000),
+        ("T", 1_000_000_000_000),
+    ];
+
+    let s = s.trim();
+    let (number, multiplier) = SUFFIXES
+        .iter()
+        .find_map(|(suffix, multiplier)| Some((s.strip_suffix(suffix)?, *multiplier)))
+        .unwrap_or((s, 1));
+
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format_err!("'{s}' is not a memory size, eg. 512Mi or 2G"))
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Job {
     pub uuid: Uuid,
@@ -31,6 +64,28 @@ pub struct Job {
     pub paused: Option<bool>,
     pub triggers: Vec<Trigger>,
     pub tasks: Vec<Task>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<TaskGroup>,
+    /// name of a task to activate whenever any other task in the job fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_failure: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slo: Option<Slo>,
+    /// hash of the source this job was applied from, set by GitOps tools so
+    /// changes made any other way can be detected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_hash: Option<String>,
+}
+
+/// latency targets for each run of a job, measured from the trigger time
+#[derive(Deserialize, Serialize)]
+pub struct Slo {
+    /// the first task should start within this long
+    pub start_within: Option<String>,
+    /// the last task should finish within this long
+    pub finish_within: Option<String>,
+    /// fraction of runs that should meet the targets, eg. 0.99
+    pub objective: f64,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, sqlx::Type)]
@@ -59,11 +114,72 @@ pub struct Trigger {
     pub catchup: Option<Catchup>,
 }
 
+/// an environment variable, either `KEY=VALUE` or a reference to a secret
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EnvEntry {
+    Value(String),
+    Secret(SecretEnv),
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Docker {
     pub image: String,
     pub args: Vec<String>,
-    pub env: Option<Vec<String>>,
+    pub env: Option<Vec<EnvEntry>>,
+    pub resources: Option<Resources>,
+    pub image_pull_policy: Option<ImagePullPolicy>,
+    pub network_mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<String>,
+    pub working_dir: Option<String>,
+    pub user: Option<String>,
+    /// `windows` for an image that needs a Windows worker
+    pub os: Option<ContainerOs>,
+    /// Kubernetes containers run before the task, in the pod spec's form
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub init_containers: Vec<serde_json::Value>,
+    /// Kubernetes containers run alongside the task, in the pod spec's form
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sidecars: Vec<serde_json::Value>,
+}
+
+/// Limits on a task's container, so one greedy task can't starve the others on a node
+#[derive(Deserialize, Serialize)]
+pub struct Resources {
+    /// CPU cores, eg. `0.5`
+    pub cpu: Option<f64>,
+    /// eg. `512Mi` or `2G`
+    pub memory: Option<String>,
+    pub gpus: Option<u32>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ulimits: BTreeMap<String, i64>,
+}
+
+/// A WASI module run in-process by the worker, for small tasks that don't need a container
+#[derive(Deserialize, Serialize)]
+pub struct Wasm {
+    pub module: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub env: Option<Vec<EnvEntry>>,
+}
+
+/// A command run as a process on the worker's host, for installs without a container runtime
+#[derive(Deserialize, Serialize)]
+pub struct Process {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub env: Option<Vec<EnvEntry>>,
+}
+
+/// A task that waits for a condition, checked by the scheduler every `poke_interval`
+#[derive(Deserialize, Serialize)]
+pub struct Sensor {
+    #[serde(flatten)]
+    pub check: SensorCheck,
+    pub poke_interval: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -76,16 +192,38 @@ pub struct Retry {
 pub struct Task {
     pub name: String,
     pub docker: Option<Docker>,
+    pub wasm: Option<Wasm>,
+    pub process: Option<Process>,
+    pub lambda: Option<LambdaInvoke>,
+    pub sensor: Option<Sensor>,
+    /// the queue the task is sent to, only workers taking from it run the task
+    pub queue: Option<String>,
     pub depends: Option<Vec<String>>,
     pub depends_failure: Option<Vec<String>>, // TODO - better name for this?
     pub threshold: Option<i32>,
     pub retry: Option<Retry>,
     pub timeout: Option<String>,
+    pub expires_after: Option<String>,
+    /// files downloaded into the task's working directory before it starts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<StagedInput>,
+    /// files uploaded from the task's working directory after it succeeds
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<StagedOutput>,
+}
+
+/// A named sub-graph of tasks (and other groups) within a job
+#[derive(Deserialize, Serialize)]
+pub struct TaskGroup {
+    pub name: String,
+    pub tasks: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+    pub depends: Option<Vec<String>>,
 }
 
 #[cfg(test)]
 mod test {
-    use super::duration_from_string;
+    use super::{duration_from_string, memory_from_string};
 
     #[test]
     fn test_period_from_string() -> anyhow::Result<()> {
@@ -127,4 +265,19 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_memory_from_string() -> anyhow::Result<()> {
+        assert_eq!(memory_from_string("1024")?, 1024);
+        assert_eq!(memory_from_string("512Mi")?, 512 * 1024 * 1024);
+        assert_eq!(memory_from_string("2G")?, 2_000_000_000);
+        assert_eq!(memory_from_string(" 1Ki ")?, 1024);
+
+        assert!(memory_from_string("").is_err());
+        assert!(memory_from_string("1.5Gi").is_err());
+        assert!(memory_from_string("12Mb").is_err());
+        assert!(memory_from_string("99999999999T").is_err());
+
+        Ok(())
+    }
 }
@@ -1,64 +1,32 @@
 use crate::{
-    messages::{ProcessToken, TriggerUpdate},
-    server::updates::{TOKEN_UPDATES_EXCHANGE, TRIGGER_UPDATES_EXCHANGE},
+    broker::{Broker, Queue, SendOptions, Topic},
+    messages::{ProcessToken, TaskProgress, TriggerUpdate},
 };
 use anyhow::Result;
-use lapin::{
-    options::{BasicPublishOptions, ExchangeDeclareOptions},
-    types::FieldTable,
-    BasicProperties, Channel, ExchangeKind,
-};
-
-pub async fn setup(chan: &Channel) -> Result<()> {
-    // declare outgoing exchange and queue for trigger updates
-    chan.exchange_declare(
-        TRIGGER_UPDATES_EXCHANGE,
-        ExchangeKind::Fanout,
-        ExchangeDeclareOptions {
-            durable: true,
-            ..ExchangeDeclareOptions::default()
-        },
-        FieldTable::default(),
-    )
-    .await?;
-
-    // declare outgoing exchange and queue for token updates
-    chan.exchange_declare(
-        TOKEN_UPDATES_EXCHANGE,
-        ExchangeKind::Direct,
-        ExchangeDeclareOptions {
-            durable: true,
-            ..ExchangeDeclareOptions::default()
-        },
-        FieldTable::default(),
-    )
-    .await?;
 
-    Ok(())
+pub async fn send_trigger_update(broker: &dyn Broker, update: TriggerUpdate) -> Result<()> {
+    broker
+        .publish(Topic::TriggerUpdates, &serde_json::to_vec(&update)?)
+        .await
 }
 
-pub async fn send_trigger_update(chan: &Channel, update: TriggerUpdate) -> Result<()> {
-    chan.basic_publish(
-        TRIGGER_UPDATES_EXCHANGE,
-        "",
-        BasicPublishOptions::default(),
-        &serde_json::to_vec(&update)?,
-        BasicProperties::default(),
-    )
-    .await?;
-
-    Ok(())
+pub async fn send_token_update(broker: &dyn Broker, update: ProcessToken) -> Result<()> {
+    broker
+        .send(
+            Queue::TokenUpdates,
+            &serde_json::to_vec(&update)?,
+            SendOptions::default(),
+        )
+        .await
 }
 
-pub async fn send_token_update(chan: &Channel, update: ProcessToken) -> Result<()> {
-    chan.basic_publish(
-        TOKEN_UPDATES_EXCHANGE,
-        "",
-        BasicPublishOptions::default(),
-        &serde_json::to_vec(&update)?,
-        BasicProperties::default(),
-    )
-    .await?;
-
-    Ok(())
+/// publish a task result directly to the scheduler's results queue, as a worker would
+pub async fn send_task_progress(broker: &dyn Broker, progress: TaskProgress) -> Result<()> {
+    broker
+        .send(
+            Queue::Results,
+            &serde_json::to_vec(&progress)?,
+            SendOptions::default(),
+        )
+        .await
 }
@@ -0,0 +1,11 @@
+use crate::{
+    broker::{Broker, Topic},
+    messages::WorkerControl,
+};
+use anyhow::Result;
+
+pub async fn send(broker: &dyn Broker, control: WorkerControl) -> Result<()> {
+    broker
+        .publish(Topic::WorkerControl, &serde_json::to_vec(&control)?)
+        .await
+}
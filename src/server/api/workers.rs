@@ -1,7 +1,19 @@
-use crate::server::api::{auth, request_ext::RequestExt, State};
+use crate::{
+    messages::{ConfigUpdate, WorkerCommand, WorkerControl, WorkerLabels},
+    server::{
+        api::{
+            audit, auth, config_cache,
+            paging::{list_response, Paging},
+            request_ext::RequestExt,
+            worker_control, State,
+        },
+        workers::delete_workers,
+    },
+};
 use chrono::{DateTime, Utc};
-use highnoon::{Json, Request, Responder, Response, StatusCode};
+use highnoon::{Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
 use uuid::Uuid;
 
 #[derive(Serialize, sqlx::FromRow)]
@@ -13,31 +25,79 @@ struct WorkerState {
     pub running_tasks: i32,
     pub total_tasks: i32,
     pub status: String,
+    pub profile: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub labels: Option<Json<WorkerLabels>>,
 }
 
-pub async fn list(req: Request<State>) -> highnoon::Result<impl Responder> {
+#[derive(Deserialize)]
+struct ListWorkers {
+    /// 'up', 'draining', 'maintenance', 'gone' or 'retired'
+    status: Option<String>,
+    profile: Option<String>,
+}
+
+const LIST_WORKERS_SORT: &[(&str, &str)] = &[
+    ("last_seen", "last_seen_datetime"),
+    ("addr", "addr"),
+    ("running_tasks", "running_tasks"),
+    ("total_tasks", "total_tasks"),
+];
+
+pub async fn list(req: Request<State>) -> highnoon::Result<Response> {
     auth::list().kind("workers").check(&req).await?;
 
-    let workers: Vec<WorkerState> = sqlx::query_as(
-        "SELECT
-            id AS uuid,
-            addr,
-            version,
-            last_seen_datetime,
-            running_tasks,
-            total_tasks,
-            CASE
-                WHEN CURRENT_TIMESTAMP - last_seen_datetime > INTERVAL '15 minutes' THEN 'gone'
-                ELSE 'up'
-            END AS status
-        FROM worker w
-        WHERE CURRENT_TIMESTAMP - last_seen_datetime < INTERVAL '24 hours'
-        ORDER BY last_seen_datetime DESC",
-    )
-    .fetch_all(&req.get_pool())
+    let q: ListWorkers = req.query()?;
+    let paging: Paging = req.query()?;
+    let order_by = paging.order_by(LIST_WORKERS_SORT, "-last_seen")?;
+    let pool = req.get_pool();
+
+    let filter = "FROM (
+            SELECT
+                id AS uuid,
+                addr,
+                version,
+                last_seen_datetime,
+                running_tasks,
+                total_tasks,
+                profile,
+                tags,
+                labels,
+                CASE
+                    WHEN retired_datetime IS NOT NULL THEN 'retired'
+                    WHEN gone_datetime IS NOT NULL THEN 'gone'
+                    WHEN CURRENT_TIMESTAMP - last_seen_datetime > INTERVAL '15 minutes' THEN 'gone'
+                    WHEN draining_datetime IS NOT NULL THEN 'draining'
+                    WHEN maintenance THEN 'maintenance'
+                    ELSE 'up'
+                END AS status
+            FROM worker w
+            WHERE CURRENT_TIMESTAMP - last_seen_datetime < INTERVAL '24 hours'
+        ) w
+        WHERE ($1::VARCHAR IS NULL OR status = $1)
+        AND ($2::VARCHAR IS NULL OR profile = $2)";
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(1) {filter}"))
+        .bind(&q.status)
+        .bind(&q.profile)
+        .fetch_one(&pool)
+        .await?;
+
+    let workers: Vec<WorkerState> = sqlx::query_as(&format!(
+        "SELECT *
+        {filter}
+        ORDER BY {order_by}, uuid
+        LIMIT $3
+        OFFSET $4"
+    ))
+    .bind(&q.status)
+    .bind(&q.profile)
+    .bind(paging.limit(100))
+    .bind(paging.offset())
+    .fetch_all(&pool)
     .await?;
 
-    Ok(Json(workers))
+    list_response(workers, total)
 }
 
 #[derive(Deserialize)]
@@ -53,6 +113,9 @@ struct GetWorker {
     tasks: Vec<GetWorkerTask>,
     status: String,
     version: String,
+    profile: Option<String>,
+    tags: Option<Vec<String>>,
+    labels: Option<Json<WorkerLabels>>,
 }
 
 #[derive(Serialize, sqlx::FromRow)]
@@ -118,8 +181,15 @@ pub async fn tasks(req: Request<State>) -> highnoon::Result<Response> {
             last_seen_datetime,
             running_tasks,
             total_tasks,
+            profile,
+            tags,
+            labels,
             CASE
+                WHEN retired_datetime IS NOT NULL THEN 'retired'
+                WHEN gone_datetime IS NOT NULL THEN 'gone'
                 WHEN CURRENT_TIMESTAMP - last_seen_datetime > INTERVAL '15 minutes' THEN 'gone'
+                WHEN draining_datetime IS NOT NULL THEN 'draining'
+                WHEN maintenance THEN 'maintenance'
                 ELSE 'up'
             END AS status
         FROM worker w
@@ -137,8 +207,189 @@ pub async fn tasks(req: Request<State>) -> highnoon::Result<Response> {
             tasks,
             status: worker.status,
             version: worker.version,
+            profile: worker.profile,
+            tags: worker.tags,
+            labels: worker.labels,
         })
     } else {
         Ok(Response::status(StatusCode::NOT_FOUND))
     }
 }
+
+/// ask every worker to reload its config
+pub async fn reload_all(req: Request<State>) -> highnoon::Result<StatusCode> {
+    auth::update().kind("workers").check(&req).await?;
+
+    worker_control::send(
+        req.get_broker(),
+        WorkerControl {
+            worker_id: None,
+            command: WorkerCommand::Reload,
+        },
+    )
+    .await?;
+
+    audit::action("reload", "workers")
+        .record(&req, &req.get_pool())
+        .await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Ask every worker to drop its cached project configs and task definitions,
+/// so changes are picked up without waiting for the cache to expire
+pub async fn flush_config_cache(req: Request<State>) -> highnoon::Result<StatusCode> {
+    auth::update().kind("workers").check(&req).await?;
+
+    config_cache::send(req.get_broker(), ConfigUpdate::Flush).await?;
+
+    audit::action("flush_config_cache", "workers")
+        .record(&req, &req.get_pool())
+        .await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// ask a single worker to reload its config
+pub async fn reload(req: Request<State>) -> highnoon::Result<StatusCode> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+
+    auth::update().kind("workers").check(&req).await?;
+
+    worker_control::send(
+        req.get_broker(),
+        WorkerControl {
+            worker_id: Some(id),
+            command: WorkerCommand::Reload,
+        },
+    )
+    .await?;
+
+    audit::action("reload", "workers")
+        .object(id)
+        .record(&req, &req.get_pool())
+        .await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Put a worker in maintenance mode, where it stops taking new tasks but
+/// stays in the fleet, or take it out again
+async fn set_maintenance(req: Request<State>, enabled: bool) -> highnoon::Result<StatusCode> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+
+    auth::update().kind("workers").check(&req).await?;
+
+    worker_control::send(
+        req.get_broker(),
+        WorkerControl {
+            worker_id: Some(id),
+            command: WorkerCommand::Maintenance { enabled },
+        },
+    )
+    .await?;
+
+    let action = if enabled {
+        "start_maintenance"
+    } else {
+        "stop_maintenance"
+    };
+    audit::action(action, "workers")
+        .object(id)
+        .record(&req, &req.get_pool())
+        .await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn start_maintenance(req: Request<State>) -> highnoon::Result<StatusCode> {
+    set_maintenance(req, true).await
+}
+
+pub async fn stop_maintenance(req: Request<State>) -> highnoon::Result<StatusCode> {
+    set_maintenance(req, false).await
+}
+
+/// Ask a worker to stop taking new tasks, finish the ones it's running, then
+/// retire and exit. Used to take workers out of the fleet without failing tasks.
+pub async fn drain(req: Request<State>) -> highnoon::Result<StatusCode> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+
+    auth::update().kind("workers").check(&req).await?;
+
+    let found = sqlx::query(
+        "UPDATE worker
+        SET draining_datetime = COALESCE(draining_datetime, CURRENT_TIMESTAMP)
+        WHERE id = $1
+        AND retired_datetime IS NULL",
+    )
+    .bind(id)
+    .execute(&req.get_pool())
+    .await?
+    .rows_affected()
+        > 0;
+
+    if !found {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+
+    worker_control::send(
+        req.get_broker(),
+        WorkerControl {
+            worker_id: Some(id),
+            command: WorkerCommand::Drain,
+        },
+    )
+    .await?;
+
+    audit::action("drain", "workers")
+        .object(id)
+        .record(&req, &req.get_pool())
+        .await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Forget a worker that's gone or retired. Its task runs are kept, but no
+/// longer say which worker ran them.
+pub async fn delete(req: Request<State>) -> highnoon::Result<Response> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+
+    auth::delete().kind("workers").check(&req).await?;
+
+    let mut txn = req.get_pool().begin().await?;
+
+    let up: Option<(bool,)> = sqlx::query_as(
+        "SELECT retired_datetime IS NULL
+            AND gone_datetime IS NULL
+            AND CURRENT_TIMESTAMP - last_seen_datetime <= INTERVAL '15 minutes'
+        FROM worker
+        WHERE id = $1
+        FOR UPDATE",
+    )
+    .bind(id)
+    .fetch_optional(&mut txn)
+    .await?;
+
+    match up {
+        None => return Ok(Response::status(StatusCode::NOT_FOUND)),
+        Some((true,)) => {
+            return Err(highnoon::Error::http((
+                StatusCode::CONFLICT,
+                "the worker is still up, drain it first",
+            )))
+        }
+        Some((false,)) => {}
+    }
+
+    delete_workers(&mut txn, &[id]).await?;
+
+    audit::delete("workers")
+        .object(id)
+        .record(&req, &mut txn)
+        .await?;
+
+    txn.commit().await?;
+
+    Ok(Response::status(StatusCode::NO_CONTENT))
+}
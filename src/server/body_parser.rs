@@ -2,41 +2,110 @@ use crate::server::api::State;
 use highnoon::headers::ContentType;
 use serde::de::DeserializeOwned;
 
-const YAML_MIMES: &[&str] = &["application/x-yaml"];
+const YAML_MIMES: &[&str] = &[
+    "application/yaml",
+    "application/x-yaml",
+    "text/yaml",
+    "text/x-yaml",
+];
 const SUPPORTED_MIMES: &[&str] = &["application/json"];
 
-pub fn content_type_is_yaml(content_type: &ContentType) -> bool {
-    YAML_MIMES
-        .iter()
-        .any(|mime| *content_type == ContentType::from(mime.parse::<mime::Mime>().unwrap()))
+/// content types that don't say what the body is, so it's detected from the body.
+/// `curl -d` sends a form content type unless told otherwise.
+const GENERIC_MIMES: &[&str] = &[
+    "text/plain",
+    "application/octet-stream",
+    "application/x-www-form-urlencoded",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum Format {
+    Json,
+    Yaml,
+}
+
+/// Work out how to parse a body. Parameters such as `charset` are ignored.
+/// Returns `None` if the content type is one we can't parse.
+fn body_format(content_type: Option<&mime::Mime>, body: &[u8]) -> Option<Format> {
+    let essence = match content_type {
+        Some(mime) => mime.essence_str(),
+        None => return Some(detect_format(body)),
+    };
+
+    if SUPPORTED_MIMES.contains(&essence) {
+        Some(Format::Json)
+    } else if YAML_MIMES.contains(&essence) {
+        Some(Format::Yaml)
+    } else if GENERIC_MIMES.contains(&essence) {
+        Some(detect_format(body))
+    } else {
+        None
+    }
+}
+
+/// A JSON job is always an object or an array, while a YAML one starts with a
+/// key, a comment or `---`.
+fn detect_format(body: &[u8]) -> Format {
+    match body.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') | Some(b'[') => Format::Json,
+        _ => Format::Yaml,
+    }
 }
 
+/// Parse a JSON or YAML body, going by the `Content-Type` if it has one and
+/// otherwise detecting it from the body.
 pub async fn read_from_body<T: DeserializeOwned>(
     req: &mut highnoon::Request<State>,
 ) -> highnoon::Result<T> {
-    let content_type = req
-        .header::<ContentType>()
-        .unwrap_or_else(ContentType::json);
-    let reader = req.reader().await?;
+    let content_type = req.header::<ContentType>().map(mime::Mime::from);
+    let body = req.body_bytes().await?;
 
-    if content_type == ContentType::json() {
-        return serde_json::from_reader(reader).map_err(|err| {
+    match body_format(content_type.as_ref(), &body) {
+        Some(Format::Json) => serde_json::from_slice(&body).map_err(|err| {
             highnoon::Error::bad_request(format!("error parsing request body as json: {err}"))
-        });
-    } else if content_type_is_yaml(&content_type) {
-        return serde_yaml::from_reader(reader).map_err(|err| {
+        }),
+        Some(Format::Yaml) => serde_yaml::from_slice(&body).map_err(|err| {
             highnoon::Error::bad_request(format!("error parsing request body as yaml: {err}"))
-        });
+        }),
+        None => Err(highnoon::Error::http((
+            highnoon::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!(
+                "Unsupported media type.\n\
+                Content-Type must be one of:\n\
+                {}\n{}",
+                SUPPORTED_MIMES.join("\n"),
+                YAML_MIMES.join("\n"),
+            ),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn format(content_type: Option<&str>, body: &str) -> Option<Format> {
+        let mime = content_type.map(|ct| ct.parse::<mime::Mime>().unwrap());
+        body_format(mime.as_ref(), body.as_bytes())
     }
 
-    Err(highnoon::Error::http((
-        highnoon::StatusCode::UNSUPPORTED_MEDIA_TYPE,
-        format!(
-            "Unsupported media type.\n\
-            Content-Type must be one of:\n\
-            {}\n{}",
-            SUPPORTED_MIMES.join("\n"),
-            YAML_MIMES.join("\n"),
-        ),
-    )))
+    #[test]
+    fn test_body_format() {
+        assert_eq!(format(Some("application/json"), "name: x"), Some(Format::Json));
+        assert_eq!(
+            format(Some("application/json; charset=utf-8"), "{}"),
+            Some(Format::Json)
+        );
+        assert_eq!(format(Some("application/yaml"), "{}"), Some(Format::Yaml));
+        assert_eq!(format(Some("application/x-yaml"), "a: b"), Some(Format::Yaml));
+        assert_eq!(format(Some("text/html"), "{}"), None);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(format(None, "  {\"name\": \"x\"}"), Some(Format::Json));
+        assert_eq!(format(None, "[]"), Some(Format::Json));
+        assert_eq!(format(None, "# a job\nname: x"), Some(Format::Yaml));
+        assert_eq!(format(Some("text/plain"), "---\nname: x"), Some(Format::Yaml));
+    }
 }
@@ -0,0 +1,92 @@
+use crate::{
+    messages::{TaskPriority, Token, TokenState},
+    server::{execute::ExecuteToken, Server},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use postage::prelude::*;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+/// how often to look for backfill tasks that have waited too long
+const ESCALATION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// the priority long-waiting backfill tasks are promoted to
+const ESCALATED_PRIORITY: TaskPriority = TaskPriority::Normal;
+
+#[derive(sqlx::FromRow)]
+struct Escalation {
+    task_run_id: Uuid,
+    task_id: Uuid,
+    trigger_datetime: DateTime<Utc>,
+    attempt: i64,
+}
+
+/// Backfill tasks can be starved by a steady stream of normal priority work.
+/// Backfill messages are published with an expiration, and the time they
+/// expire is stored on the task run as `escalate_datetime`. Once that has
+/// passed the run is re-published at a higher priority, and RabbitMQ drops
+/// the original instead of delivering it. The stored time is used rather than
+/// the current `backfill_escalation_delay`, which may have changed since.
+pub async fn process_escalation(server: Arc<Server>) -> Result<!> {
+    let mut execute_tx = server.post_office.post_mail::<ExecuteToken>().await?;
+    let mut ticker = tokio::time::interval(ESCALATION_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        debug!("checking for backfill tasks to escalate");
+
+        let escalations = sqlx::query_as::<_, Escalation>(
+            "SELECT
+                r.id AS task_run_id,
+                r.task_id,
+                r.trigger_datetime,
+                r.attempt
+            FROM task_run r
+            JOIN task t ON r.task_id = t.id
+            JOIN job j ON t.job_id = j.id
+            WHERE r.state = $1
+            AND r.priority = $2
+            AND NOT j.paused
+            AND r.escalate_datetime < CURRENT_TIMESTAMP",
+        )
+        .bind(TokenState::Active)
+        .bind(TaskPriority::BackFill)
+        .fetch_all(&server.db_pool)
+        .await?;
+
+        // each run is escalated by the scheduler that owns it, and the
+        // execution processor only re-publishes it if it's still backfill
+        let escalations: Vec<_> = {
+            let rendezvous = server.on_cluster_membership_change.borrow();
+            escalations
+                .into_iter()
+                .filter(|escalation| {
+                    rendezvous.item_is_mine(&server.node_id, &escalation.task_run_id)
+                })
+                .collect()
+        };
+
+        for escalation in escalations {
+            info!(task_run_id=?escalation.task_run_id,
+                task_id=?escalation.task_id,
+                trigger_datetime=?escalation.trigger_datetime.to_rfc3339(),
+                priority=?ESCALATED_PRIORITY,
+                "escalating backfill task");
+
+            execute_tx
+                .send(ExecuteToken {
+                    token: Token {
+                        task_id: escalation.task_id,
+                        trigger_datetime: escalation.trigger_datetime,
+                    },
+                    priority: ESCALATED_PRIORITY,
+                    attempt: u32::try_from(escalation.attempt)?,
+                    task_run_id: Some(escalation.task_run_id),
+                })
+                .await?;
+        }
+    }
+}
@@ -1,31 +1,47 @@
 use crate::{
-    messages::{TaskPriority, TaskRequest, Token},
-    server::Server,
+    broker::{Queue, SendOptions},
+    messages::{
+        TaskFailure, TaskPriority, TaskProgress, TaskRequest, Token, TokenState, DEFAULT_QUEUE,
+    },
+    server::{
+        expiry::expire_if_late,
+        fair_queue::FairQueue,
+        hooks::Dispatch,
+        sensors, settings,
+        token_history::{self, Actor, TokenEvent},
+        Server,
+    },
 };
 use anyhow::Result;
-use cadence::CountedExt;
+use cadence::{CountedExt, Gauged};
 use chrono::Utc;
-use lapin::{
-    options::{BasicPublishOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
-    types::FieldTable,
-    BasicProperties, ExchangeKind,
-};
 use postage::prelude::*;
-use sqlx::Connection;
-use std::sync::Arc;
-use tracing::{debug, info};
+use serde_json::{Map, Value as JsonValue};
+use sqlx::{Connection, PgPool, Postgres, Transaction};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
-const TASK_EXCHANGE: &str = "waterwheel.tasks";
-const TASK_QUEUE: &str = "waterwheel.tasks";
+/// tasks that need a GPU and don't name a queue are routed to this one, taken
+/// from by GPU workers
+const GPU_QUEUE: &str = "gpu";
+/// tasks that need a Windows worker and don't name a queue are routed to this one
+const WINDOWS_QUEUE: &str = "windows";
 
-const PERSISTENT: u8 = 2;
+/// how often to recount the tasks waiting in the broker and running
+const QUEUED_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone)]
 pub struct ExecuteToken {
     pub token: Token,
     pub priority: TaskPriority,
     pub attempt: u32,
+    /// set when re-publishing a run that is already queued, eg. to escalate its priority
+    pub task_run_id: Option<Uuid>,
 }
 
 pub async fn process_executions(server: Arc<Server>) -> Result<!> {
@@ -34,81 +50,317 @@ pub async fn process_executions(server: Arc<Server>) -> Result<!> {
 
     let mut execute_rx = server.post_office.receive_mail::<ExecuteToken>().await?;
 
-    let chan = server.amqp_conn.create_channel().await?;
+    // other queues are declared when their first task is sent
+    for queue in [DEFAULT_QUEUE, GPU_QUEUE, WINDOWS_QUEUE] {
+        server.broker.declare(Queue::Tasks(queue)).await?;
+    }
+
+    // TODO - recover any tasks
+
+    let mut fair_queue = FairQueue::default();
+    // a token incremented again while it's held would otherwise be dispatched twice
+    let mut buffered = HashSet::new();
+    let mut in_flight = count_in_flight(&pool).await?;
+    let mut project_limits = get_project_limits(&pool).await?;
+    let mut refresh = tokio::time::interval(QUEUED_REFRESH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            msg = execute_rx.recv() => {
+                let msg = msg.expect("ExecuteToken channel was closed!");
+
+                if msg.task_run_id.is_some() {
+                    // re-published runs are already counted as queued
+                    dispatch(&server, msg).await?;
+                } else if buffered.contains(&msg.token) {
+                    debug!(task_id=?msg.token.task_id,
+                        trigger_datetime=%msg.token.trigger_datetime.to_rfc3339(),
+                        "token already waiting to be dispatched");
+                } else {
+                    match get_project_weight(&pool, &msg.token).await? {
+                        Some((project_id, weight)) => {
+                            buffered.insert(msg.token.clone());
+                            fair_queue.push(project_id, weight, msg.priority, msg)
+                        }
+                        None => warn!(task_id=?msg.token.task_id, "task not found, not enqueueing"),
+                    }
+                }
+            }
+            _ = refresh.tick() => {
+                in_flight = count_in_flight(&pool).await?;
+                project_limits = get_project_limits(&pool).await?;
+            }
+        }
+
+        // the limits can be changed at runtime, through /api/settings
+        let settings = settings::current(&server.config);
+        let max_queued = settings.max_queued_tasks;
+        let max_concurrent = settings.max_concurrent_tasks;
+
+        while (max_queued == 0 || in_flight.queued < max_queued)
+            && (max_concurrent == 0 || in_flight.total() < max_concurrent)
+        {
+            let next = fair_queue.pop(|project_id| {
+                project_limits
+                    .get(&project_id)
+                    .map_or(true, |limit| limit.running < limit.max_concurrent_tasks)
+            });
+
+            match next {
+                Some((project_id, msg)) => {
+                    buffered.remove(&msg.token);
+                    dispatch(&server, msg).await?;
+                    if let Some(limit) = project_limits.get_mut(&project_id) {
+                        limit.running += 1;
+                    }
+                }
+                None => break,
+            }
+            in_flight.queued += 1;
+        }
+
+        statsd
+            .gauge_with_tags("tasks.buffered", fair_queue.len() as u64)
+            .send();
+        statsd
+            .gauge_with_tags("tasks.in_flight", in_flight.total())
+            .send();
+    }
+}
+
+struct InFlight {
+    /// sent to the broker but not started by a worker yet
+    queued: u64,
+    running: u64,
+}
 
-    chan.exchange_declare(
-        TASK_EXCHANGE,
-        ExchangeKind::Direct,
-        ExchangeDeclareOptions {
-            durable: true,
-            ..ExchangeDeclareOptions::default()
-        },
-        FieldTable::default(),
+impl InFlight {
+    fn total(&self) -> u64 {
+        self.queued + self.running
+    }
+}
+
+async fn count_in_flight(pool: &PgPool) -> Result<InFlight> {
+    let (queued, running): (i64, i64) = sqlx::query_as(
+        "SELECT
+            COUNT(1) FILTER (WHERE state = 'active'),
+            COUNT(1) FILTER (WHERE state = 'running')
+        FROM task_run
+        WHERE state IN ('active', 'running')",
     )
+    .fetch_one(pool)
     .await?;
 
-    let mut args = FieldTable::default();
-    args.insert("x-max-priority".into(), 3i8.into());
+    Ok(InFlight {
+        queued: queued as u64,
+        running: running as u64,
+    })
+}
 
-    let timeout_ms = server.config.amqp_consumer_timeout * 1000;
-    args.insert("x-consumer-timeout".into(), timeout_ms.into());
+#[derive(sqlx::FromRow)]
+struct ProjectLimit {
+    project_id: Uuid,
+    max_concurrent_tasks: i64,
+    running: i64,
+}
 
-    chan.queue_declare(
-        TASK_QUEUE,
-        QueueDeclareOptions {
-            durable: true,
-            ..QueueDeclareOptions::default()
-        },
-        args,
+/// projects with a concurrent task quota, and how many of their tasks are queued or running
+async fn get_project_limits(pool: &PgPool) -> Result<HashMap<Uuid, ProjectLimit>> {
+    let limits: Vec<ProjectLimit> = sqlx::query_as(
+        "SELECT
+            q.project_id,
+            q.max_concurrent_tasks::BIGINT AS max_concurrent_tasks,
+            COUNT(r.id) AS running
+        FROM project_quota q
+        LEFT JOIN job j ON j.project_id = q.project_id
+        LEFT JOIN task t ON t.job_id = j.id
+        LEFT JOIN task_run r ON r.task_id = t.id AND r.state IN ('active', 'running')
+        WHERE q.max_concurrent_tasks IS NOT NULL
+        GROUP BY 1, 2",
     )
+    .fetch_all(pool)
     .await?;
 
-    chan.queue_bind(
-        TASK_QUEUE,
-        TASK_EXCHANGE,
-        "",
-        QueueBindOptions::default(),
-        FieldTable::default(),
+    Ok(limits
+        .into_iter()
+        .map(|limit| (limit.project_id, limit))
+        .collect())
+}
+
+/// the project a task belongs to and its `dispatch_weight` from the project config
+async fn get_project_weight(pool: &PgPool, token: &Token) -> Result<Option<(Uuid, u32)>> {
+    let row: Option<(Uuid, i32)> = sqlx::query_as(
+        "SELECT
+            p.id,
+            COALESCE((p.config->>'dispatch_weight')::INT, 1)
+        FROM task t
+        JOIN job j ON j.id = t.job_id
+        JOIN project p ON p.id = j.project_id
+        WHERE t.id = $1",
     )
+    .bind(token.task_id)
+    .fetch_optional(pool)
     .await?;
 
-    // TODO - recover any tasks
+    Ok(row.map(|(project_id, weight)| (project_id, weight.max(1) as u32)))
+}
+
+/// send a task to the workers and record the task run
+async fn dispatch(server: &Server, msg: ExecuteToken) -> Result<()> {
+    let pool = &server.db_pool;
+    let statsd = &server.statsd;
+
+    let ExecuteToken {
+        token,
+        priority,
+        attempt,
+        task_run_id,
+    } = msg;
+
+    // the token's count was already deducted when the run was first queued
+    let requeued = task_run_id.is_some();
+
+    debug!(task_id=?token.task_id,
+        trigger_datetime=%token.trigger_datetime.to_rfc3339(),
+        ?priority,
+        ?attempt,
+        "enqueueing");
+
+    let mut conn = pool.acquire().await?;
+    let mut txn = conn.begin().await?;
+
+    if !requeued && expire_if_late(&mut txn, &token).await? {
+        info!(task_id=?token.task_id,
+            trigger_datetime=%token.trigger_datetime.to_rfc3339(),
+            "token expired, not enqueueing");
+        txn.commit().await?;
+
+        statsd
+            .incr_with_tags("tasks.expired")
+            .with_tag("priority", priority.as_str())
+            .send();
+        return Ok(());
+    }
 
-    while let Some(msg) = execute_rx.recv().await {
-        let ExecuteToken {
-            token,
-            priority,
-            attempt,
-        } = msg;
+    let failure = get_failure(&mut txn, &token).await?;
+    let upstream_outputs = get_upstream_outputs(&mut txn, &token).await?;
 
-        debug!(task_id=?token.task_id,
+    let mut task_req = TaskRequest {
+        task_run_id: task_run_id.unwrap_or_else(Uuid::new_v4),
+        task_id: token.task_id,
+        trigger_datetime: token.trigger_datetime,
+        attempt,
+        failure,
+        env: Default::default(),
+        upstream_outputs,
+    };
+
+    if let Dispatch::Veto(reason) = server.hooks.dispatch(server, &mut task_req, priority).await {
+        warn!(task_id=?token.task_id,
             trigger_datetime=%token.trigger_datetime.to_rfc3339(),
-            ?priority,
-            ?attempt,
-            "enqueueing");
+            "task not enqueued: {}", reason);
 
-        let mut conn = pool.acquire().await?;
-        let mut txn = conn.begin().await?;
+        record_veto(&mut txn, &task_req, priority, attempt, &reason, requeued).await?;
+        txn.commit().await?;
 
-        let task_req = TaskRequest {
-            task_run_id: Uuid::new_v4(),
-            task_id: token.task_id,
-            trigger_datetime: token.trigger_datetime,
-        };
+        send_veto_result(server, &task_req, reason).await?;
+
+        statsd
+            .incr_with_tags("tasks.vetoed")
+            .with_tag("priority", priority.as_str())
+            .send();
+        return Ok(());
+    }
+
+    let mut options = SendOptions {
+        priority: Some(priority),
+        ..SendOptions::default()
+    };
 
-        let props = BasicProperties::default()
-            .with_delivery_mode(PERSISTENT)
-            .with_priority(priority as u8);
+    // backfill messages expire when they're due to be escalated, so that only
+    // the copy re-published at the higher priority is ever delivered
+    let escalation_delay = settings::current(&server.config).backfill_escalation_delay;
+    let mut escalate_datetime = None;
+    if priority == TaskPriority::BackFill && escalation_delay > 0 && !requeued {
+        options.expiration_ms = Some(escalation_delay * 1000);
+        escalate_datetime =
+            Some(Utc::now() + chrono::Duration::seconds(i64::try_from(escalation_delay)?));
+    }
+
+    // runs not started in time are requeued, by which time their message must be gone
+    let unstarted_ms = server.config.requeue_unstarted_after * 1000;
+    if unstarted_ms > 0 {
+        options.expiration_ms = Some(
+            options
+                .expiration_ms
+                .map_or(unstarted_ms, |ms| ms.min(unstarted_ms)),
+        );
+    }
 
-        chan.basic_publish(
-            TASK_EXCHANGE,
-            "",
-            BasicPublishOptions::default(),
-            &serde_json::to_vec(&task_req)?,
-            props,
+    if requeued {
+        // only the first to flip the priority re-publishes, the original
+        // message has expired so the task still only runs once
+        let escalated = sqlx::query(
+            "UPDATE task_run
+            SET priority = $2,
+                escalate_datetime = NULL,
+                updated_datetime = CURRENT_TIMESTAMP
+            WHERE id = $1
+            AND state = 'active'
+            AND priority = $3",
         )
+        .bind(task_req.task_run_id)
+        .bind(priority)
+        .bind(TaskPriority::BackFill)
+        .execute(&mut txn)
         .await?;
 
+        if escalated.rows_affected() == 0 {
+            debug!(task_run_id=?task_req.task_run_id,
+                "task run already escalated or started, not re-publishing");
+            return Ok(());
+        }
+    }
+
+    let (is_sensor, needs_gpu, needs_windows, queue): (bool, bool, bool, Option<String>) =
+        sqlx::query_as(
+            "SELECT
+                sensor IS NOT NULL,
+                COALESCE((resources->>'gpus')::INT, 0) > 0,
+                COALESCE(docker_options->>'os' = 'windows', FALSE),
+                queue
+            FROM task
+            WHERE id = $1",
+        )
+        .bind(token.task_id)
+        .fetch_one(&mut txn)
+        .await?;
+
+    if is_sensor {
+        // sensors are checked by the scheduler rather than sent to a worker
+        if !requeued {
+            sensors::add_sensor(&mut txn, &task_req).await?;
+        }
+    } else {
+        let queue = match queue.as_deref() {
+            Some(queue) => queue,
+            None if needs_windows => WINDOWS_QUEUE,
+            None if needs_gpu => GPU_QUEUE,
+            None => DEFAULT_QUEUE,
+        };
+
+        server
+            .broker
+            .send(
+                Queue::Tasks(queue),
+                &serde_json::to_vec(&task_req)?,
+                options,
+            )
+            .await?;
+    }
+
+    // a re-published run keeps its original queued time so queue latency stays accurate
+    if !requeued {
         sqlx::query(
             "UPDATE token
             SET state = 'active',
@@ -125,11 +377,11 @@ pub async fn process_executions(server: Arc<Server>) -> Result<!> {
             "INSERT INTO task_run(id, task_id, trigger_datetime,
                 queued_datetime, started_datetime, finish_datetime,
                 updated_datetime,
-                worker_id, state, priority, attempt)
+                worker_id, state, priority, attempt, escalate_datetime)
             VALUES ($1, $2, $3,
                 $4, NULL, NULL,
                 NULL,
-                NULL, 'active', $5, $6)",
+                NULL, 'active', $5, $6, $7)",
         )
         .bind(task_req.task_run_id)
         .bind(token.task_id)
@@ -137,22 +389,186 @@ pub async fn process_executions(server: Arc<Server>) -> Result<!> {
         .bind(Utc::now())
         .bind(priority)
         .bind(attempt as i64)
+        .bind(escalate_datetime.filter(|_| !is_sensor))
         .execute(&mut txn)
         .await?;
+    }
 
-        txn.commit().await?;
+    token_history::record(
+        &mut txn,
+        std::slice::from_ref(&token),
+        TokenEvent::Dispatch,
+        Actor::Scheduler,
+        Some(task_req.task_run_id),
+        requeued.then_some("republished"),
+    )
+    .await?;
 
-        info!(task_id=?token.task_id,
-            trigger_datetime=%token.trigger_datetime.to_rfc3339(),
-            ?priority,
-            ?attempt,
-            "task enqueued");
+    txn.commit().await?;
 
-        statsd
-            .incr_with_tags("tasks.enqueued")
-            .with_tag("priority", priority.as_str())
-            .send();
-    }
+    info!(task_id=?token.task_id,
+        trigger_datetime=%token.trigger_datetime.to_rfc3339(),
+        ?priority,
+        ?attempt,
+        "task enqueued");
+
+    statsd
+        .incr_with_tags("tasks.enqueued")
+        .with_tag("priority", priority.as_str())
+        .send();
+
+    Ok(())
+}
+
+/// A task vetoed by a dispatch hook is recorded as a run that's queued, then
+/// fails like any other run (see `send_veto_result`), so its retries, failure
+/// edges and on_failure callback all apply.
+async fn record_veto(
+    txn: &mut Transaction<'_, Postgres>,
+    task_req: &TaskRequest,
+    priority: TaskPriority,
+    attempt: u32,
+    reason: &str,
+    requeued: bool,
+) -> Result<()> {
+    sqlx::query(
+        "UPDATE token
+        SET state = 'active',
+            count = count - CASE WHEN $3 THEN 0
+                ELSE (SELECT threshold FROM task WHERE id = $1) END
+        WHERE task_id = $1
+        AND trigger_datetime = $2",
+    )
+    .bind(task_req.task_id)
+    .bind(task_req.trigger_datetime)
+    .bind(requeued)
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO task_run(id, task_id, trigger_datetime,
+            queued_datetime, started_datetime, finish_datetime,
+            updated_datetime,
+            worker_id, state, priority, attempt)
+        VALUES ($1, $2, $3,
+            $4, NULL, NULL,
+            $4,
+            NULL, 'active', $5, $6)
+        ON CONFLICT(id)
+        DO UPDATE
+        SET updated_datetime = EXCLUDED.updated_datetime,
+            priority = EXCLUDED.priority,
+            escalate_datetime = NULL",
+    )
+    .bind(task_req.task_run_id)
+    .bind(task_req.task_id)
+    .bind(task_req.trigger_datetime)
+    .bind(Utc::now())
+    .bind(priority)
+    .bind(attempt as i64)
+    .execute(&mut *txn)
+    .await?;
+
+    let token = Token {
+        task_id: task_req.task_id,
+        trigger_datetime: task_req.trigger_datetime,
+    };
+    token_history::record(
+        txn,
+        &[token],
+        TokenEvent::Veto,
+        Actor::Scheduler,
+        Some(task_req.task_run_id),
+        Some(reason),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Fail a vetoed run through the progress processor, the same as a worker reporting failure
+async fn send_veto_result(server: &Server, task_req: &TaskRequest, reason: String) -> Result<()> {
+    let now = Utc::now();
+    let progress = TaskProgress {
+        task_run_id: task_req.task_run_id,
+        task_id: task_req.task_id,
+        trigger_datetime: task_req.trigger_datetime,
+        started_datetime: now,
+        finished_datetime: Some(now),
+        result: TokenState::Failure,
+        worker_id: None,
+        error_details: Some(reason),
+        operator_override: false,
+        outputs: None,
+        exit_code: None,
+        error_class: None,
+    };
+
+    server
+        .broker
+        .send(
+            Queue::Results,
+            &serde_json::to_vec(&progress)?,
+            SendOptions::default(),
+        )
+        .await
+}
+
+/// The outputs from the result files of the runs of the task's parents that
+/// triggered this one, by the parent's name. Only the latest run of each counts.
+async fn get_upstream_outputs(
+    txn: &mut Transaction<'_, Postgres>,
+    token: &Token,
+) -> Result<BTreeMap<String, Map<String, JsonValue>>> {
+    let rows: Vec<(String, sqlx::types::Json<Map<String, JsonValue>>)> = sqlx::query_as(
+        "SELECT DISTINCT ON (t.id)
+            t.name,
+            tr.outputs->'outputs'
+        FROM task_edge e
+        JOIN task t ON t.id = e.parent_task_id
+        JOIN task_run tr
+            ON tr.task_id = e.parent_task_id
+            AND tr.trigger_datetime = $2 - (INTERVAL '1s' * COALESCE(e.edge_offset, 0))
+        WHERE e.child_task_id = $1
+        AND jsonb_typeof(tr.outputs->'outputs') = 'object'
+        ORDER BY t.id, tr.finish_datetime DESC NULLS LAST",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .fetch_all(&mut *txn)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(name, outputs)| (name, outputs.0))
+        .collect())
+}
+
+/// if the task is a job's on_failure callback, find the most recent failure it was activated by
+async fn get_failure(
+    txn: &mut Transaction<'_, Postgres>,
+    token: &Token,
+) -> Result<Option<TaskFailure>> {
+    let failure = sqlx::query_as(
+        "SELECT
+            t.id AS task_id,
+            t.name AS task_name,
+            tr.state AS state,
+            tr.error_details AS error_details
+        FROM job j
+        JOIN task t ON t.job_id = j.id
+        JOIN task_run tr ON tr.task_id = t.id
+        WHERE j.on_failure_task_id = $1
+        AND t.id != $1
+        AND tr.trigger_datetime = $2
+        AND tr.state IN ('failure', 'error', 'timeout', 'infra_failure')
+        ORDER BY tr.finish_datetime DESC
+        LIMIT 1",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .fetch_optional(&mut *txn)
+    .await?;
 
-    unreachable!("ExecuteToken channel was closed!")
+    Ok(failure)
 }
@@ -0,0 +1,115 @@
+use crate::{
+    messages::Token,
+    server::{
+        token_history::{self, Actor, TokenEvent},
+        Server,
+    },
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{Postgres, Transaction};
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+/// periodically mark waiting tokens that are past their task's `expires_after` as expired
+pub async fn process_expiry(server: Arc<Server>) -> Result<!> {
+    let pool = server.db_pool.clone();
+    let interval = Duration::from_secs(server.config.token_expiry_interval);
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        debug!("checking for expired tokens");
+
+        // each scheduler only sweeps the tasks it owns
+        let task_ids: Vec<Uuid> = sqlx::query_scalar(
+            "SELECT id
+            FROM task
+            WHERE expires_after_secs IS NOT NULL",
+        )
+        .fetch_all(&pool)
+        .await?;
+
+        let task_ids: Vec<Uuid> = {
+            let rendezvous = server.on_cluster_membership_change.borrow();
+            task_ids
+                .into_iter()
+                .filter(|task_id| rendezvous.item_is_mine(&server.node_id, task_id))
+                .collect()
+        };
+
+        if task_ids.is_empty() {
+            continue;
+        }
+
+        let mut txn = pool.begin().await?;
+
+        let expired: Vec<(Uuid, DateTime<Utc>)> = sqlx::query_as(
+            "UPDATE token k
+            SET state = 'expired'
+            FROM task t
+            WHERE k.task_id = t.id
+            AND t.id = ANY($1)
+            AND t.expires_after_secs IS NOT NULL
+            AND k.state = 'waiting'
+            AND k.trigger_datetime < CURRENT_TIMESTAMP - (INTERVAL '1s' * t.expires_after_secs)
+            RETURNING k.task_id, k.trigger_datetime",
+        )
+        .bind(&task_ids)
+        .fetch_all(&mut txn)
+        .await?;
+
+        let tokens: Vec<Token> = expired
+            .into_iter()
+            .map(|(task_id, trigger_datetime)| Token {
+                task_id,
+                trigger_datetime,
+            })
+            .collect();
+
+        token_history::record(&mut txn, &tokens, TokenEvent::Expire, Actor::Scheduler, None, None)
+            .await?;
+
+        txn.commit().await?;
+
+        if !tokens.is_empty() {
+            info!("marked {} tokens as expired", tokens.len());
+        }
+    }
+}
+
+/// If the token is past its task's `expires_after` mark it expired and return true.
+/// The threshold is deducted from the count just as if the task had been dispatched.
+pub async fn expire_if_late(txn: &mut Transaction<'_, Postgres>, token: &Token) -> Result<bool> {
+    let result = sqlx::query(
+        "UPDATE token k
+        SET state = 'expired',
+            count = k.count - t.threshold
+        FROM task t
+        WHERE k.task_id = t.id
+        AND k.task_id = $1
+        AND k.trigger_datetime = $2
+        AND t.expires_after_secs IS NOT NULL
+        AND k.trigger_datetime + (INTERVAL '1s' * t.expires_after_secs) < CURRENT_TIMESTAMP",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .execute(&mut *txn)
+    .await?;
+
+    let expired = result.rows_affected() > 0;
+    if expired {
+        token_history::record(
+            txn,
+            std::slice::from_ref(token),
+            TokenEvent::Expire,
+            Actor::Scheduler,
+            None,
+            None,
+        )
+        .await?;
+    }
+
+    Ok(expired)
+}
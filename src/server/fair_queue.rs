@@ -0,0 +1,199 @@
+use crate::messages::TaskPriority;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap},
+};
+use uuid::Uuid;
+
+/// the pass a project advances by per item is `STRIDE / weight`
+const STRIDE: u64 = 1 << 20;
+
+struct Entry<T> {
+    priority: TaskPriority,
+    seq: u64,
+    item: T,
+}
+
+impl<T> Entry<T> {
+    fn key(&self) -> (TaskPriority, Reverse<u64>) {
+        (self.priority, Reverse(self.seq))
+    }
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+struct ProjectQueue<T> {
+    weight: u32,
+    pass: u64,
+    items: BinaryHeap<Entry<T>>,
+}
+
+/// Interleaves items across projects in proportion to their weights, using
+/// stride scheduling. Within a project, higher priority items go first and
+/// otherwise items are kept in the order they were pushed.
+pub struct FairQueue<T> {
+    projects: HashMap<Uuid, ProjectQueue<T>>,
+    /// the pass of the last project popped from, idle projects rejoin from here
+    /// so they can't save up credit and then burst
+    global_pass: u64,
+    seq: u64,
+    len: usize,
+}
+
+impl<T> Default for FairQueue<T> {
+    fn default() -> Self {
+        Self {
+            projects: HashMap::new(),
+            global_pass: 0,
+            seq: 0,
+            len: 0,
+        }
+    }
+}
+
+impl<T> FairQueue<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, project_id: Uuid, weight: u32, priority: TaskPriority, item: T) {
+        let global_pass = self.global_pass;
+        let queue = self
+            .projects
+            .entry(project_id)
+            .or_insert_with(|| ProjectQueue {
+                weight,
+                pass: global_pass,
+                items: BinaryHeap::new(),
+            });
+
+        queue.weight = weight.max(1);
+        queue.items.push(Entry {
+            priority,
+            seq: self.seq,
+            item,
+        });
+
+        self.seq += 1;
+        self.len += 1;
+    }
+
+    /// pop the next item from a project that `eligible` allows (eg. one under its concurrency quota)
+    pub fn pop(&mut self, eligible: impl Fn(Uuid) -> bool) -> Option<(Uuid, T)> {
+        let (&project_id, queue) = self
+            .projects
+            .iter_mut()
+            .filter(|(id, _)| eligible(**id))
+            .min_by_key(|(id, queue)| (queue.pass, **id))?;
+
+        let entry = queue.items.pop().expect("project queues are never empty");
+        self.global_pass = queue.pass;
+        queue.pass += STRIDE / queue.weight as u64;
+
+        if queue.items.is_empty() {
+            self.projects.remove(&project_id);
+        }
+
+        self.len -= 1;
+        Some((project_id, entry.item))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn drain(queue: &mut FairQueue<&'static str>) -> Vec<&'static str> {
+        std::iter::from_fn(|| queue.pop(|_| true))
+            .map(|(_, item)| item)
+            .collect()
+    }
+
+    #[test]
+    fn test_weighted_interleave() {
+        let a = Uuid::from_u128(1);
+        let b = Uuid::from_u128(2);
+
+        let mut queue = FairQueue::default();
+        for _ in 0..6 {
+            queue.push(a, 2, TaskPriority::Normal, "a");
+        }
+        for _ in 0..3 {
+            queue.push(b, 1, TaskPriority::Normal, "b");
+        }
+
+        assert_eq!(queue.len(), 9);
+        assert_eq!(drain(&mut queue), vec!["a", "b", "a", "a", "b", "a", "a", "b", "a"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_priority_within_project() {
+        let a = Uuid::from_u128(1);
+
+        let mut queue = FairQueue::default();
+        queue.push(a, 1, TaskPriority::BackFill, "backfill");
+        queue.push(a, 1, TaskPriority::Normal, "first");
+        queue.push(a, 1, TaskPriority::High, "high");
+        queue.push(a, 1, TaskPriority::Normal, "second");
+
+        assert_eq!(drain(&mut queue), vec!["high", "first", "second", "backfill"]);
+    }
+
+    #[test]
+    fn test_idle_project_has_no_credit() {
+        let a = Uuid::from_u128(1);
+        let b = Uuid::from_u128(2);
+
+        let mut queue = FairQueue::default();
+        for _ in 0..4 {
+            queue.push(a, 1, TaskPriority::Normal, "a");
+        }
+        queue.pop(|_| true);
+        queue.pop(|_| true);
+
+        // b arrives late, it shares from now on rather than catching up
+        for _ in 0..3 {
+            queue.push(b, 1, TaskPriority::Normal, "b");
+        }
+
+        assert_eq!(drain(&mut queue), vec!["b", "a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn test_ineligible_project_is_skipped() {
+        let a = Uuid::from_u128(1);
+        let b = Uuid::from_u128(2);
+
+        let mut queue = FairQueue::default();
+        queue.push(a, 1, TaskPriority::Normal, "a");
+        queue.push(b, 1, TaskPriority::Normal, "b");
+
+        assert_eq!(queue.pop(|id| id != a), Some((b, "b")));
+        assert_eq!(queue.pop(|id| id != a), None);
+        assert_eq!(queue.pop(|_| true), Some((a, "a")));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{
+    messages::{TaskPriority, TaskProgress, TaskRequest},
+    server::Server,
+};
+use anyhow::Result;
+use std::sync::Arc;
+use tracing::warn;
+
+/// whether a task may be sent to the workers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dispatch {
+    Continue,
+    /// don't run the task, it's recorded as a failure with this reason
+    Veto(String),
+}
+
+#[async_trait::async_trait]
+pub trait TaskHook: Send + Sync {
+    /// name used in logs and veto messages
+    fn name(&self) -> &str;
+
+    /// Called before a task is sent to the workers. The hook can add to
+    /// `task_req.env` to set extra environment variables, or veto the task.
+    async fn on_dispatch(
+        &self,
+        _server: &Server,
+        _task_req: &mut TaskRequest,
+        _priority: TaskPriority,
+    ) -> Result<Dispatch> {
+        Ok(Dispatch::Continue)
+    }
+
+    /// Called after a task result has been processed and committed.
+    async fn on_result(&self, _server: &Server, _task_progress: &TaskProgress) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Plugin points for injecting policy into the scheduler without forking it.
+///
+/// Hooks are registered when the scheduler is built, so a custom binary can
+/// link against this crate and add its own:
+///
+/// ```ignore
+/// let mut hooks = Hooks::default();
+/// hooks.register(MyPolicy::new());
+/// let server = Server::with_hooks(config, hooks).await?;
+/// server.run_scheduler().await?;
+/// ```
+#[derive(Default, Clone)]
+pub struct Hooks {
+    hooks: Vec<Arc<dyn TaskHook>>,
+}
+
+impl Hooks {
+    pub fn register(&mut self, hook: impl TaskHook + 'static) {
+        self.hooks.push(Arc::new(hook));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Run every hook in the order they were registered, stopping at the first veto.
+    /// A hook that returns an error vetoes the task, so policy fails closed.
+    pub async fn dispatch(
+        &self,
+        server: &Server,
+        task_req: &mut TaskRequest,
+        priority: TaskPriority,
+    ) -> Dispatch {
+        for hook in &self.hooks {
+            match hook.on_dispatch(server, task_req, priority).await {
+                Ok(Dispatch::Continue) => {}
+                Ok(Dispatch::Veto(reason)) => {
+                    return Dispatch::Veto(format!("vetoed by hook '{}': {reason}", hook.name()));
+                }
+                Err(err) => {
+                    warn!(hook=hook.name(), task_id=?task_req.task_id, "dispatch hook failed: {:#}", err);
+                    return Dispatch::Veto(format!("hook '{}' failed: {err:#}", hook.name()));
+                }
+            }
+        }
+
+        Dispatch::Continue
+    }
+
+    /// Run every result hook. These are only for side effects, so errors are logged and ignored.
+    pub async fn result(&self, server: &Server, task_progress: &TaskProgress) {
+        for hook in &self.hooks {
+            if let Err(err) = hook.on_result(server, task_progress).await {
+                warn!(hook=hook.name(), task_id=?task_progress.task_id, "result hook failed: {:#}", err);
+            }
+        }
+    }
+}
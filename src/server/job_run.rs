@@ -0,0 +1,221 @@
+use crate::messages::{JobRunState, Token};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{Postgres, Transaction};
+use tracing::trace;
+use uuid::Uuid;
+
+#[derive(sqlx::FromRow)]
+struct TaskCounts {
+    job_id: Uuid,
+    running: i64,
+    success: i64,
+    failed: i64,
+    first_started_datetime: Option<DateTime<Utc>>,
+}
+
+fn state_from_counts(running: i64, success: i64, failed: i64) -> JobRunState {
+    if running > 0 || (success == 0 && failed == 0) {
+        JobRunState::Running
+    } else if failed == 0 {
+        JobRunState::Success
+    } else if success == 0 {
+        JobRunState::Failed
+    } else {
+        JobRunState::Partial
+    }
+}
+
+/// Record which job runs a trigger fired into. A job run covers every token for a
+/// trigger time, whichever trigger they came from, so a job with several triggers
+/// feeding different tasks has one run per trigger time rather than one per trigger.
+pub async fn add_run_triggers(
+    txn: &mut Transaction<'_, Postgres>,
+    trigger_id: Uuid,
+    tokens: &[Token],
+) -> Result<()> {
+    let mut trigger_datetimes: Vec<DateTime<Utc>> =
+        tokens.iter().map(|t| t.trigger_datetime).collect();
+    trigger_datetimes.sort();
+    trigger_datetimes.dedup();
+
+    if trigger_datetimes.is_empty() {
+        return Ok(());
+    }
+
+    sqlx::query(
+        "INSERT INTO job_run_trigger(job_id, trigger_datetime, trigger_id, fired_datetime)
+        SELECT g.job_id, u.trigger_datetime, g.id, CURRENT_TIMESTAMP
+        FROM trigger g
+        CROSS JOIN UNNEST($2::TIMESTAMP WITH TIME ZONE[]) AS u(trigger_datetime)
+        WHERE g.id = $1
+        ON CONFLICT(job_id, trigger_datetime, trigger_id)
+        DO UPDATE
+        SET fired_datetime = EXCLUDED.fired_datetime",
+    )
+    .bind(trigger_id)
+    .bind(&trigger_datetimes)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(())
+}
+
+/// Recount the token states of each job run the tokens are in, for the tokens
+/// overview. Only the runs' own tokens are counted, so this stays cheap however
+/// much history a job has. Call it after changing the tokens, in the same
+/// transaction.
+pub async fn update_state_counts(
+    txn: &mut Transaction<'_, Postgres>,
+    tokens: &[Token],
+) -> Result<()> {
+    if tokens.is_empty() {
+        return Ok(());
+    }
+
+    let task_ids: Vec<Uuid> = tokens.iter().map(|t| t.task_id).collect();
+    let trigger_datetimes: Vec<DateTime<Utc>> = tokens.iter().map(|t| t.trigger_datetime).collect();
+
+    // a state no token is in any more has to go, so start from nothing
+    sqlx::query(
+        "DELETE FROM job_run_state_count c
+        USING task t,
+            UNNEST($1::UUID[], $2::TIMESTAMP WITH TIME ZONE[]) AS u(task_id, trigger_datetime)
+        WHERE t.id = u.task_id
+        AND c.job_id = t.job_id
+        AND c.trigger_datetime = u.trigger_datetime",
+    )
+    .bind(&task_ids)
+    .bind(&trigger_datetimes)
+    .execute(&mut *txn)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO job_run_state_count(job_id, trigger_datetime, state, tokens)
+        SELECT r.job_id, r.trigger_datetime, k.state, COUNT(1)
+        FROM (
+            SELECT DISTINCT t.job_id, u.trigger_datetime
+            FROM UNNEST($1::UUID[], $2::TIMESTAMP WITH TIME ZONE[]) AS u(task_id, trigger_datetime)
+            JOIN task t ON t.id = u.task_id
+        ) r
+        JOIN task t ON t.job_id = r.job_id
+        JOIN token k ON k.task_id = t.id AND k.trigger_datetime = r.trigger_datetime
+        GROUP BY r.job_id, r.trigger_datetime, k.state
+        ON CONFLICT(job_id, trigger_datetime, state)
+        DO UPDATE
+        SET tokens = EXCLUDED.tokens",
+    )
+    .bind(&task_ids)
+    .bind(&trigger_datetimes)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(())
+}
+
+/// Recompute the `job_run` of the job containing `task_id` from its tasks' tokens.
+/// A task that has some but not all of its upstreams (eg. one fed by two triggers
+/// where only one has fired) counts as running, so the run isn't finished early.
+/// This must be called after the token states (and any downstream tokens) are updated,
+/// so that a task waiting on one that just finished still counts as running.
+/// Returns the job and the run's new state.
+pub async fn update_job_run(
+    txn: &mut Transaction<'_, Postgres>,
+    task_id: Uuid,
+    trigger_datetime: DateTime<Utc>,
+) -> Result<Option<(Uuid, JobRunState)>> {
+    let maybe_counts: Option<TaskCounts> = sqlx::query_as(
+        "SELECT
+            j.id AS job_id,
+            COUNT(1) FILTER (
+                WHERE k.state IN ('active', 'running', 'retry', 'preempted')
+                OR (k.state = 'waiting' AND k.count > 0)
+            ) AS running,
+            COUNT(1) FILTER (WHERE k.state = 'success') AS success,
+            COUNT(1) FILTER (
+                WHERE k.state IN (
+                    'failure', 'error', 'timeout', 'expired', 'upstream_failed', 'infra_failure'
+                )
+            ) AS failed,
+            (
+                SELECT MIN(r.started_datetime)
+                FROM task_run r
+                JOIN task rt ON rt.id = r.task_id
+                WHERE rt.job_id = j.id
+                AND r.trigger_datetime = $2
+            ) AS first_started_datetime
+        FROM task me
+        JOIN job j ON j.id = me.job_id
+        JOIN task t ON t.job_id = j.id
+        LEFT JOIN token k ON k.task_id = t.id AND k.trigger_datetime = $2
+        WHERE me.id = $1
+        GROUP BY j.id",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_optional(&mut *txn)
+    .await?;
+
+    let counts = match maybe_counts {
+        Some(counts) => counts,
+        None => return Ok(None),
+    };
+
+    let state = state_from_counts(counts.running, counts.success, counts.failed);
+
+    trace!(job_id=?counts.job_id,
+        trigger_datetime=?trigger_datetime.to_rfc3339(),
+        ?state,
+        "updating job run");
+
+    sqlx::query(
+        "INSERT INTO job_run(job_id, trigger_datetime, state,
+            running_tasks, success_tasks, failed_tasks,
+            started_datetime, updated_datetime, finish_datetime,
+            first_started_datetime, definition_version)
+        VALUES ($1, $2, $3,
+            $4, $5, $6,
+            CURRENT_TIMESTAMP, CURRENT_TIMESTAMP,
+            CASE WHEN $3 = 'running' THEN NULL ELSE CURRENT_TIMESTAMP END,
+            $7, (SELECT definition_version FROM job WHERE id = $1))
+        ON CONFLICT(job_id, trigger_datetime)
+        DO UPDATE
+        SET state = EXCLUDED.state,
+            -- a finished run that starts again (eg. a rerun) runs the current definition
+            definition_version = CASE
+                WHEN job_run.state <> 'running' AND EXCLUDED.state = 'running'
+                THEN EXCLUDED.definition_version
+                ELSE job_run.definition_version END,
+            running_tasks = EXCLUDED.running_tasks,
+            success_tasks = EXCLUDED.success_tasks,
+            failed_tasks = EXCLUDED.failed_tasks,
+            updated_datetime = EXCLUDED.updated_datetime,
+            finish_datetime = EXCLUDED.finish_datetime,
+            first_started_datetime = EXCLUDED.first_started_datetime",
+    )
+    .bind(counts.job_id)
+    .bind(trigger_datetime)
+    .bind(state)
+    .bind(counts.running as i32)
+    .bind(counts.success as i32)
+    .bind(counts.failed as i32)
+    .bind(counts.first_started_datetime)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(Some((counts.job_id, state)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_state_from_counts() {
+        assert_eq!(state_from_counts(1, 3, 1), JobRunState::Running);
+        assert_eq!(state_from_counts(0, 0, 0), JobRunState::Running);
+        assert_eq!(state_from_counts(0, 4, 0), JobRunState::Success);
+        assert_eq!(state_from_counts(0, 0, 2), JobRunState::Failed);
+        assert_eq!(state_from_counts(0, 3, 1), JobRunState::Partial);
+    }
+}
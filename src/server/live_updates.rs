@@ -0,0 +1,21 @@
+use crate::{
+    broker::{Broker, Topic},
+    messages::LiveUpdate,
+};
+use tracing::warn;
+
+/// Publish a live update to every API process, so each can push it to its own
+/// clients. This only logs a warning on failure, since live updates are best
+/// effort and shouldn't hold up the work they describe.
+pub async fn send(broker: &dyn Broker, update: &LiveUpdate) {
+    let res = async {
+        broker
+            .publish(Topic::Live, &serde_json::to_vec(update)?)
+            .await
+    }
+    .await;
+
+    if let Err(err) = res {
+        warn!("failed to send live update: {:?}", err);
+    }
+}
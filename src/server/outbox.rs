@@ -0,0 +1,47 @@
+use crate::{
+    messages::{TaskPriority, Token},
+    server::Server,
+};
+use anyhow::Result;
+use postage::prelude::*;
+use sqlx::{Postgres, Transaction};
+use tracing::trace;
+
+/// Sent to the token processor after committing new rows to the outbox.
+/// It's only a wakeup, the outbox table is the source of truth.
+#[derive(Clone, Debug)]
+pub struct OutboxReady;
+
+/// Record token increments for the token processor in the same transaction that
+/// incremented the tokens, so they aren't lost if the scheduler crashes before
+/// the token processor sees them. Tokens are processed in the order they're added.
+pub async fn add(
+    txn: &mut Transaction<'_, Postgres>,
+    tokens: &[Token],
+    priority: TaskPriority,
+) -> Result<()> {
+    for token in tokens {
+        trace!(task_id=?token.task_id,
+            trigger_datetime=?token.trigger_datetime.to_rfc3339(),
+            "adding token to outbox");
+
+        sqlx::query(
+            "INSERT INTO token_outbox(task_id, trigger_datetime, priority)
+            VALUES ($1, $2, $3)",
+        )
+        .bind(token.task_id)
+        .bind(token.trigger_datetime)
+        .bind(priority)
+        .execute(&mut *txn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// wake up the token processor after committing rows to the outbox
+pub async fn notify(server: &Server) -> Result<()> {
+    let mut outbox_tx = server.post_office.post_mail::<OutboxReady>().await?;
+    outbox_tx.send(OutboxReady).await?;
+    Ok(())
+}
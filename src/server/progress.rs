@@ -1,56 +1,34 @@
 use crate::{
-    messages::{ProcessToken, TaskPriority, TaskProgress, Token, TokenState},
-    server::{tokens::increment_token, Server},
+    broker::{Broker, Queue},
+    config::StrictResults,
+    messages::{JobRunState, LiveUpdate, TaskPriority, TaskProgress, Token, TokenState},
+    server::{
+        job_run::update_job_run,
+        live_updates, outbox,
+        token_history::{self, Actor, TokenEvent},
+        tokens::increment_token,
+        Server,
+    },
     util::first,
 };
 use anyhow::Result;
 use chrono::{DateTime, Duration, Utc};
-use futures::TryStreamExt;
-use lapin::{
-    options::{BasicAckOptions, BasicConsumeOptions, BasicQosOptions, QueueDeclareOptions},
-    types::FieldTable,
-};
 use postage::prelude::*;
 use sqlx::{Connection, PgPool, Postgres, Transaction};
-use std::sync::Arc;
-use tracing::{debug, info, trace};
+use std::{collections::HashSet, sync::Arc};
+use tracing::{debug, info, trace, warn};
 use uuid::Uuid;
 use crate::postoffice::PostOffice;
 use crate::server::retries::{Retry, SubmitRetry};
 
-const RESULT_QUEUE: &str = "waterwheel.results";
-
 pub async fn process_progress(server: Arc<Server>) -> Result<!> {
     let pool = server.db_pool.clone();
-    let chan = server.amqp_conn.create_channel().await?;
-
-    let mut token_tx = server.post_office.post_mail::<ProcessToken>().await?;
-
-    // declare queue for consuming incoming messages
-    chan.queue_declare(
-        RESULT_QUEUE,
-        QueueDeclareOptions {
-            durable: true,
-            ..QueueDeclareOptions::default()
-        },
-        FieldTable::default(),
-    )
-    .await?;
 
     // to limit the number of redeliveries needed after a restart/crash
-    chan.basic_qos(100, BasicQosOptions::default()).await?;
-
-    let mut consumer = chan
-        .basic_consume(
-            RESULT_QUEUE,
-            "server",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+    let mut consumer = server.broker.consume(&[Queue::Results], 100).await?;
 
-    while let Some(delivery) = consumer.try_next().await? {
-        let task_progress: TaskProgress = serde_json::from_slice(&delivery.data)?;
+    while let Some(delivery) = consumer.next().await? {
+        let mut task_progress: TaskProgress = serde_json::from_slice(&delivery.data)?;
 
         debug!(result=task_progress.result.as_ref(),
             task_id=?task_progress.task_id,
@@ -60,37 +38,221 @@ pub async fn process_progress(server: Arc<Server>) -> Result<!> {
         let mut conn = pool.acquire().await?;
         let mut txn = conn.begin().await?;
 
+        if task_progress.result.is_final() && !mark_processed(&mut txn, &task_progress).await? {
+            info!(task_run_id=?task_progress.task_run_id,
+                task_id=?task_progress.task_id,
+                trigger_datetime=?task_progress.trigger_datetime.to_rfc3339(),
+                "ignoring duplicate task result");
+
+            txn.rollback().await?;
+            delivery.ack().await?;
+            continue;
+        }
+
+        // an operator has already decided the outcome, eg. after the run was requeued
+        if task_progress.result.is_final()
+            && !task_progress.operator_override
+            && overridden_since(&mut txn, &task_progress).await?
+        {
+            info!(task_run_id=?task_progress.task_run_id,
+                task_id=?task_progress.task_id,
+                trigger_datetime=?task_progress.trigger_datetime.to_rfc3339(),
+                "ignoring result for a task run an operator has since overridden");
+
+            txn.commit().await?;
+            delivery.ack().await?;
+            continue;
+        }
+
+        // a task that's always evicted would otherwise be retried forever
+        if task_progress.result == TokenState::Preempted
+            && !task_progress.operator_override
+            && preempted_retries_exhausted(&server, &mut txn, &task_progress).await?
+        {
+            warn!(task_run_id=?task_progress.task_run_id,
+                task_id=?task_progress.task_id,
+                trigger_datetime=?task_progress.trigger_datetime.to_rfc3339(),
+                "task was preempted too many times, failing it");
+
+            let max = server.config.max_preempted_retries;
+            task_progress.result = TokenState::Failure;
+            task_progress.error_details = Some(match task_progress.error_details.take() {
+                Some(details) => format!("preempted after {max} preempted retries: {details}"),
+                None => format!("preempted after {max} preempted retries"),
+            });
+        }
+
         let priority = update_task_progress(&server, &mut txn, &task_progress).await?;
 
         let mut tokens_to_tx = Vec::new();
 
         if task_progress.result.is_final() {
-            if task_progress.result.is_retryable()
+            // an operator override decides the outcome, so it's never retried
+            if task_progress.result == TokenState::Preempted && !task_progress.operator_override {
+                // infrastructure churn doesn't count against the task's retries
+                submit_retry(&server, &mut txn, &server.post_office, &task_progress).await?;
+            } else if task_progress.result.is_retryable()
+                && !task_progress.operator_override
                 && has_retries(&pool, task_progress.task_run_id).await?
             {
                 submit_retry(&server, &mut txn, &server.post_office, &task_progress).await?;
             } else {
                 tokens_to_tx = advance_tokens(&pool, &mut txn, &task_progress).await?;
+
+                if server.config.strict_results != StrictResults::Off {
+                    check_result_mapped(&server, &mut txn, &task_progress).await?;
+                }
+
+                if task_progress.result != TokenState::Success {
+                    if let Some(token) = failure_callback(&mut txn, &task_progress).await? {
+                        tokens_to_tx.push(token);
+                    }
+
+                    propagate_upstream_failed(&mut txn, &task_progress).await?;
+                }
             }
         }
 
+        outbox::add(&mut txn, &tokens_to_tx, priority).await?;
+
+        let job_run =
+            update_job_run(&mut txn, task_progress.task_id, task_progress.trigger_datetime)
+                .await?;
+
         txn.commit().await?;
 
-        delivery.ack(BasicAckOptions::default()).await?;
+        delivery.ack().await?;
 
         debug!("finished processing task results");
 
+        if let Some((job_id, run_state)) = job_run {
+            send_live_updates(server.broker.as_ref(), &task_progress, job_id, run_state).await;
+        }
+
+        server.hooks.result(&server, &task_progress).await;
+
         // after committing the transaction we can tell the token processor increment tokens
-        for token in tokens_to_tx {
-            token_tx
-                .send(ProcessToken::Increment(token, priority))
-                .await?;
+        if !tokens_to_tx.is_empty() {
+            outbox::notify(&server).await?;
         }
     }
 
     unreachable!("consumer stopped consuming")
 }
 
+/// Tell anyone watching about the new task state, and the run finishing if it has.
+async fn send_live_updates(
+    broker: &dyn Broker,
+    task_progress: &TaskProgress,
+    job_id: Uuid,
+    run_state: JobRunState,
+) {
+    live_updates::send(
+        broker,
+        &LiveUpdate::TokenState {
+            job_id,
+            task_id: task_progress.task_id,
+            trigger_datetime: task_progress.trigger_datetime,
+            state: task_progress.result,
+        },
+    )
+    .await;
+
+    if run_state != JobRunState::Running {
+        live_updates::send(
+            broker,
+            &LiveUpdate::RunFinished {
+                job_id,
+                trigger_datetime: task_progress.trigger_datetime,
+                state: run_state,
+            },
+        )
+        .await;
+    }
+}
+
+/// Record that the final result of a task run has been processed, returning false if
+/// it already was (eg. the message was redelivered after a restart before it was acked).
+/// This is keyed on the task run rather than the attempt number, since reruns start
+/// counting attempts again and preempted runs are retried without using an attempt.
+async fn mark_processed(
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<bool> {
+    let result = sqlx::query(
+        "INSERT INTO task_result_processed(task_run_id, task_id, trigger_datetime,
+            attempt, result, processed_datetime)
+        VALUES ($1, $2, $3,
+            (SELECT attempt FROM task_run WHERE id = $1), $4, CURRENT_TIMESTAMP)
+        ON CONFLICT(task_run_id) DO NOTHING",
+    )
+    .bind(task_progress.task_run_id)
+    .bind(task_progress.task_id)
+    .bind(task_progress.trigger_datetime)
+    .bind(task_progress.result)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Whether an operator set the state of the run's task since the run was queued
+async fn overridden_since(
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<bool> {
+    let (overridden,): (bool,) = sqlx::query_as(
+        "SELECT EXISTS(
+            SELECT 1
+            FROM task_run r
+            JOIN task_run o
+                ON o.task_id = r.task_id
+                AND o.trigger_datetime = r.trigger_datetime
+            WHERE r.id = $1
+            AND o.operator_override
+            AND o.queued_datetime > r.queued_datetime
+        )",
+    )
+    .bind(task_progress.task_run_id)
+    .fetch_one(&mut *txn)
+    .await?;
+
+    Ok(overridden)
+}
+
+/// Whether the token's task has already been preempted as many times as
+/// `max_preempted_retries` allows. The run being processed isn't counted, since
+/// its state hasn't been updated yet, and neither are runs from before the token
+/// was last cleared or activated by an operator.
+async fn preempted_retries_exhausted(
+    server: &Server,
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<bool> {
+    let (preempted,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*)
+        FROM task_run
+        WHERE task_id = $1
+        AND trigger_datetime = $2
+        AND state = 'preempted'
+        AND queued_datetime > COALESCE((
+            SELECT MAX(created_datetime)
+            FROM token_history
+            WHERE task_id = $1
+            AND trigger_datetime = $2
+            AND event IN ($3, $4)
+        ), '-infinity')",
+    )
+    .bind(task_progress.task_id)
+    .bind(task_progress.trigger_datetime)
+    .bind(TokenEvent::Clear)
+    .bind(TokenEvent::Activate)
+    .fetch_one(&mut *txn)
+    .await?;
+
+    Ok(preempted >= server.config.max_preempted_retries as i64)
+}
+
 #[derive(sqlx::FromRow)]
 struct TaskEdge {
     child_task_id: Uuid,
@@ -138,6 +300,275 @@ pub async fn advance_tokens(
     Ok(tokens_to_tx)
 }
 
+/// A result is unmapped when the task has edges for outcomes other than success,
+/// but none for this one, eg. a task with `depends_failure` children that timed out.
+/// Success never is, since a task with only failure handlers succeeding is normal.
+fn is_unmapped(result: TokenState, edge_kinds: &[String]) -> bool {
+    result != TokenState::Success
+        && edge_kinds.iter().any(|kind| kind != TokenState::Success.as_ref())
+        && !edge_kinds.iter().any(|kind| kind == result.as_ref())
+}
+
+/// In strict mode, record results that none of the task's edges handle so typos
+/// and missing mappings show up, and fail the rest of the run if configured to.
+async fn check_result_mapped(
+    server: &Server,
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<()> {
+    let edge_kinds: Vec<(String,)> = sqlx::query_as(
+        "SELECT DISTINCT kind
+        FROM task_edge
+        WHERE parent_task_id = $1
+        ORDER BY kind",
+    )
+    .bind(task_progress.task_id)
+    .fetch_all(&mut *txn)
+    .await?;
+    let edge_kinds: Vec<String> = edge_kinds.into_iter().map(|(kind,)| kind).collect();
+
+    if !is_unmapped(task_progress.result, &edge_kinds) {
+        return Ok(());
+    }
+
+    warn!(task_id=?task_progress.task_id,
+        trigger_datetime=?task_progress.trigger_datetime.to_rfc3339(),
+        result=task_progress.result.as_ref(),
+        "task result has no matching downstream edges");
+
+    server
+        .statsd
+        .incr_with_tags("tasks.unmapped_result")
+        .with_tag("result", task_progress.result.as_ref())
+        .send();
+
+    let details = format!(
+        "result '{}' has no downstream edges, the task has edges for: {}",
+        task_progress.result.as_ref(),
+        edge_kinds.join(", ")
+    );
+    token_history::record(
+        txn,
+        &[progress_token(task_progress)],
+        TokenEvent::UnmappedResult,
+        Actor::Scheduler,
+        Some(task_progress.task_run_id),
+        Some(&details),
+    )
+    .await?;
+
+    if server.config.strict_results == StrictResults::Fail {
+        fail_rest_of_run(txn, task_progress).await?;
+    }
+
+    Ok(())
+}
+
+/// mark every token in the run that hasn't started yet as `upstream_failed`
+async fn fail_rest_of_run(
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<()> {
+    let failed: Vec<(Uuid,)> = sqlx::query_as(
+        "UPDATE token k
+        SET state = $3
+        FROM task t, task me
+        WHERE me.id = $1
+        AND t.job_id = me.job_id
+        AND k.task_id = t.id
+        AND k.trigger_datetime = $2
+        AND k.state = 'waiting'
+        RETURNING k.task_id",
+    )
+    .bind(task_progress.task_id)
+    .bind(task_progress.trigger_datetime)
+    .bind(TokenState::UpstreamFailed)
+    .fetch_all(&mut *txn)
+    .await?;
+
+    let tokens: Vec<Token> = failed
+        .into_iter()
+        .map(|(task_id,)| Token {
+            task_id,
+            trigger_datetime: task_progress.trigger_datetime,
+        })
+        .collect();
+
+    let details = format!(
+        "run failed by unmapped result of {}",
+        progress_token(task_progress)
+    );
+    token_history::record(
+        txn,
+        &tokens,
+        TokenEvent::UpstreamFailed,
+        Actor::Scheduler,
+        None,
+        Some(&details),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// if the job has an on_failure task, add a token for it
+async fn failure_callback(
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<Option<Token>> {
+    let maybe_callback: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT j.on_failure_task_id
+        FROM task t
+        JOIN job j ON j.id = t.job_id
+        WHERE t.id = $1
+        AND j.on_failure_task_id IS NOT NULL
+        AND j.on_failure_task_id != t.id",
+    )
+    .bind(task_progress.task_id)
+    .fetch_optional(&mut *txn)
+    .await?;
+
+    let token = match maybe_callback {
+        Some((task_id,)) => Token {
+            task_id,
+            trigger_datetime: task_progress.trigger_datetime,
+        },
+        None => return Ok(None),
+    };
+
+    info!(task_id=?task_progress.task_id,
+        callback_task_id=?token.task_id,
+        "task failed, activating on_failure task");
+
+    increment_token(&mut *txn, &token).await?;
+
+    Ok(Some(token))
+}
+
+fn progress_token(task_progress: &TaskProgress) -> Token {
+    Token {
+        task_id: task_progress.task_id,
+        trigger_datetime: task_progress.trigger_datetime,
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct Blocked {
+    threshold: i32,
+    count: i32,
+    state: Option<TokenState>,
+    /// edges into the task whose parent could still activate them
+    pending: i64,
+}
+
+/// A waiting token is blocked when, even if every edge that could still fire did, it
+/// wouldn't reach its threshold. Triggers are assumed to have fired already.
+async fn is_blocked(txn: &mut Transaction<'_, Postgres>, token: &Token) -> Result<bool> {
+    let blocked: Blocked = sqlx::query_as(
+        "SELECT
+            t.threshold,
+            COALESCE(k.count, 0) AS count,
+            k.state,
+            (
+                SELECT COUNT(1)
+                FROM task_edge e
+                LEFT JOIN token pk
+                    ON pk.task_id = e.parent_task_id
+                    AND pk.trigger_datetime =
+                        $2 - (INTERVAL '1s' * COALESCE(e.edge_offset, 0))
+                WHERE e.child_task_id = t.id
+                AND (pk.state IS NULL
+                    OR pk.state IN ('waiting', 'active', 'running', 'retry', 'preempted', 'cancelled'))
+            ) AS pending
+        FROM task t
+        LEFT JOIN token k ON k.task_id = t.id AND k.trigger_datetime = $2
+        WHERE t.id = $1",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .fetch_one(&mut *txn)
+    .await?;
+
+    Ok(matches!(blocked.state, None | Some(TokenState::Waiting))
+        && i64::from(blocked.count) + blocked.pending < i64::from(blocked.threshold))
+}
+
+/// When a task fails, mark every downstream token that can no longer be activated as
+/// `upstream_failed`, so the job run reaches a final state instead of waiting forever.
+/// Tokens in the future are left alone, since a task that depends on its own earlier
+/// runs would otherwise block every run after it.
+async fn propagate_upstream_failed(
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<()> {
+    let failed = progress_token(task_progress);
+    let details = format!("upstream {failed} finished with {}", task_progress.result.as_ref());
+
+    let mut visited = HashSet::new();
+    let mut frontier = vec![failed];
+
+    while let Some(parent) = frontier.pop() {
+        let children: Vec<(Uuid, Option<i64>)> = sqlx::query_as(
+            "SELECT DISTINCT
+                child_task_id,
+                edge_offset
+            FROM task_edge
+            WHERE parent_task_id = $1",
+        )
+        .bind(parent.task_id)
+        .fetch_all(&mut *txn)
+        .await?;
+
+        for (child_task_id, edge_offset) in children {
+            let token = Token {
+                task_id: child_task_id,
+                trigger_datetime: parent.trigger_datetime
+                    + Duration::seconds(edge_offset.unwrap_or(0)),
+            };
+
+            if token.trigger_datetime > Utc::now()
+                || !visited.insert(token.clone())
+                || !is_blocked(txn, &token).await?
+            {
+                continue;
+            }
+
+            debug!(task_id=?token.task_id,
+                trigger_datetime=?token.trigger_datetime.to_rfc3339(),
+                "marking token upstream_failed");
+
+            sqlx::query(
+                "INSERT INTO token(task_id, trigger_datetime, count, state)
+                    VALUES ($1, $2, 0, $3)
+                    ON CONFLICT(task_id, trigger_datetime)
+                    DO UPDATE SET state = $3",
+            )
+            .bind(token.task_id)
+            .bind(token.trigger_datetime)
+            .bind(TokenState::UpstreamFailed)
+            .execute(&mut *txn)
+            .await?;
+
+            token_history::record(
+                txn,
+                std::slice::from_ref(&token),
+                TokenEvent::UpstreamFailed,
+                Actor::Scheduler,
+                None,
+                Some(&details),
+            )
+            .await?;
+
+            // the task may be in another job, which needs its own job run updated
+            update_job_run(txn, token.task_id, token.trigger_datetime).await?;
+
+            frontier.push(token);
+        }
+    }
+
+    Ok(())
+}
+
 async fn update_task_progress(
     _server: &Server,
     txn: &mut Transaction<'_, Postgres>,
@@ -159,6 +590,21 @@ async fn update_task_progress(
     .execute(&mut *txn)
     .await?;
 
+    let actor = match task_progress.worker_id {
+        Some(worker_id) => Actor::Worker(worker_id),
+        None if task_progress.operator_override => Actor::Operator,
+        None => Actor::Scheduler,
+    };
+    token_history::record(
+        txn,
+        &[progress_token(task_progress)],
+        TokenEvent::Result,
+        actor,
+        Some(task_progress.task_run_id),
+        task_progress.error_details.as_deref(),
+    )
+    .await?;
+
     trace!(task_id=?task_progress.task_id,
         task_run_id=?task_progress.task_run_id,
         "updating task_run state");
@@ -169,15 +615,23 @@ async fn update_task_progress(
                 started_datetime = $2,
                 finish_datetime = $3,
                 updated_datetime = CURRENT_TIMESTAMP,
-                worker_id = $4
-        WHERE id = $5
+                worker_id = $4,
+                error_details = $5,
+                outputs = COALESCE($7, outputs),
+                exit_code = COALESCE($8, exit_code),
+                error_class = $9
+        WHERE id = $6
         RETURNING priority",
     )
     .bind(task_progress.result)
     .bind(task_progress.started_datetime)
     .bind(task_progress.finished_datetime)
     .bind(task_progress.worker_id)
+    .bind(&task_progress.error_details)
     .bind(task_progress.task_run_id)
+    .bind(&task_progress.outputs)
+    .bind(task_progress.exit_code)
+    .bind(task_progress.error_class)
     .fetch_optional(&mut *txn)
     .await?;
 
@@ -215,17 +669,23 @@ async fn submit_retry(
         task_run_id=?task_progress.task_run_id,
         "submitting retry");
 
-    let (retry_at_datetime,): (DateTime<Utc>,) = sqlx::query_as(
-        "SELECT $2 + (INTERVAL '1s' * COALESCE(t.retry_delay_secs, $3))
-        FROM task t
-        JOIN task_run r ON t.id = r.task_id
-        WHERE r.id = $1",
-    )
-    .bind(task_progress.task_run_id)
-    .bind(task_progress.finished_datetime.unwrap())
-    .bind(server.config.default_task_retry_delay as i64)
-    .fetch_one(&mut *txn)
-    .await?;
+    let retry_at_datetime = if task_progress.result == TokenState::Preempted {
+        let delay = Duration::seconds(server.config.preempted_retry_delay as i64);
+        task_progress.finished_datetime.unwrap_or_else(Utc::now) + delay
+    } else {
+        let (retry_at_datetime,): (DateTime<Utc>,) = sqlx::query_as(
+            "SELECT $2 + (INTERVAL '1s' * COALESCE(t.retry_delay_secs, $3))
+            FROM task t
+            JOIN task_run r ON t.id = r.task_id
+            WHERE r.id = $1",
+        )
+        .bind(task_progress.task_run_id)
+        .bind(task_progress.finished_datetime.unwrap())
+        .bind(server.config.default_task_retry_delay as i64)
+        .fetch_one(&mut *txn)
+        .await?;
+        retry_at_datetime
+    };
 
     info!(task_id=?task_progress.task_id,
         task_run_id=?task_progress.task_run_id,
@@ -255,6 +715,17 @@ async fn submit_retry(
     .execute(&mut *txn)
     .await?;
 
+    let details = format!("retry at {}", retry_at_datetime.to_rfc3339());
+    token_history::record(
+        txn,
+        &[progress_token(task_progress)],
+        TokenEvent::Retry,
+        Actor::Scheduler,
+        Some(task_progress.task_run_id),
+        Some(&details),
+    )
+    .await?;
+
     let mut retry_tx = post_office.post_mail::<SubmitRetry>().await?;
     retry_tx.send(SubmitRetry::Add(Retry {
         task_run_id: task_progress.task_run_id,
@@ -264,3 +735,23 @@ async fn submit_retry(
     Ok(())
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kinds(kinds: &[&str]) -> Vec<String> {
+        kinds.iter().map(|kind| kind.to_string()).collect()
+    }
+
+    #[test]
+    fn test_is_unmapped() {
+        // no edges, or only success edges, is a leaf or a task whose failures are fatal
+        assert!(!is_unmapped(TokenState::Failure, &kinds(&[])));
+        assert!(!is_unmapped(TokenState::Failure, &kinds(&["success"])));
+        assert!(!is_unmapped(TokenState::Success, &kinds(&["failure"])));
+
+        assert!(!is_unmapped(TokenState::Failure, &kinds(&["success", "failure"])));
+        assert!(is_unmapped(TokenState::Error, &kinds(&["success", "failure"])));
+    }
+}
@@ -1,6 +1,10 @@
 use crate::{
     messages::{TaskPriority, Token, TokenState},
-    server::{execute::ExecuteToken, Server},
+    server::{
+        execute::ExecuteToken,
+        token_history::{self, Actor, TokenEvent},
+        Server,
+    },
 };
 use anyhow::{format_err, Result};
 use chrono::{DateTime, Utc};
@@ -18,6 +22,8 @@ struct Requeue {
     priority: TaskPriority,
     attempt: i64,
     paused: bool,
+    /// queued but never started, eg. the message was lost or expired
+    unstarted: bool,
 }
 
 pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
@@ -28,6 +34,10 @@ pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
         .try_into()
         .map_err(|err| format_err!("error converting duration to pg_interval: {:?}", err))?;
 
+    let unstarted_after: PgInterval = Duration::from_secs(server.config.requeue_unstarted_after)
+        .try_into()
+        .map_err(|err| format_err!("error converting duration to pg_interval: {:?}", err))?;
+
     let mut ticker =
         tokio::time::interval(Duration::from_secs(server.config.requeue_interval));
 
@@ -46,25 +56,42 @@ pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
                 r.trigger_datetime,
                 r.priority,
                 r.attempt,
-                j.paused
+                j.paused,
+                r.state = $4 AS unstarted
             FROM task_run r
             JOIN task t ON r.task_id = t.id
             JOIN job j ON t.job_id = j.id
             WHERE (
-                r.state = $1
-            OR
-               (NOT j.paused AND r.state = $2)
+                (
+                    r.state = $1
+                OR
+                   (NOT j.paused AND r.state = $2 AND r.killed_datetime IS NULL)
+                )
+                AND r.updated_datetime < CURRENT_TIMESTAMP - $3
+            )
+            OR (
+                $5
+                AND r.state = $4
+                AND t.sensor IS NULL
+                AND COALESCE(r.updated_datetime, r.queued_datetime) < CURRENT_TIMESTAMP - $6
             )
-            AND r.updated_datetime < CURRENT_TIMESTAMP - $3
             FOR UPDATE OF r",
         )
         .bind(TokenState::Running)
         .bind(TokenState::Cancelled)
         .bind(&timeout)
+        .bind(TokenState::Active)
+        .bind(server.config.requeue_unstarted_after > 0)
+        .bind(&unstarted_after)
         .fetch_all(&mut txn)
         .await?;
 
         for requeue in requeues {
+            let token = Token {
+                task_id: requeue.task_id,
+                trigger_datetime: requeue.trigger_datetime,
+            };
+
             if requeue.paused {
                 warn!(task_run_id=?requeue.task_run_id,
                     task_id=?requeue.task_id,
@@ -78,12 +105,10 @@ pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
 
                 execute_tx
                     .send(ExecuteToken {
-                        token: Token {
-                            task_id: requeue.task_id,
-                            trigger_datetime: requeue.trigger_datetime,
-                        },
+                        token: token.clone(),
                         priority: requeue.priority,
                         attempt: u32::try_from(requeue.attempt)? + 1,
+                        task_run_id: None,
                     })
                     .await?;
             }
@@ -110,6 +135,23 @@ pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
             .bind(requeue.trigger_datetime)
             .execute(&mut txn)
             .await?;
+
+            let details = if requeue.paused {
+                "job is paused, cancelled"
+            } else if requeue.unstarted {
+                "task wasn't started by a worker, requeued"
+            } else {
+                "worker stopped sending heartbeats, requeued"
+            };
+            token_history::record(
+                &mut txn,
+                &[token],
+                TokenEvent::Requeue,
+                Actor::Scheduler,
+                Some(requeue.task_run_id),
+                Some(details),
+            )
+            .await?;
         }
 
         txn.commit().await?;
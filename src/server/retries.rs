@@ -6,7 +6,7 @@ use postage::prelude::*;
 use tokio::select;
 use tracing::{debug, info, trace, warn};
 use uuid::Uuid;
-use crate::messages::{TaskPriority, Token};
+use crate::messages::{TaskPriority, Token, TokenState};
 use crate::server::execute::ExecuteToken;
 use crate::server::Server;
 use crate::util::format_duration_approx;
@@ -93,6 +93,7 @@ struct RetryInfo {
     pub trigger_datetime: DateTime<Utc>,
     pub priority: TaskPriority,
     pub attempt: i64,
+    pub state: Option<TokenState>,
 }
 
 async fn do_retry(server: &Server, retry: Retry) -> Result<()> {
@@ -103,7 +104,8 @@ async fn do_retry(server: &Server, retry: Retry) -> Result<()> {
             task_id,
             trigger_datetime,
             priority,
-            attempt
+            attempt,
+            state
         FROM task_run
         WHERE id = $1")
     .bind(retry.task_run_id)
@@ -115,8 +117,16 @@ async fn do_retry(server: &Server, retry: Retry) -> Result<()> {
         trigger_datetime=?info.trigger_datetime,
         priority=?info.priority,
         attempt=?info.attempt,
+        state=?info.state,
         "retrying");
 
+    // preempted runs are retried without consuming an attempt
+    let attempt = if info.state == Some(TokenState::Preempted) {
+        u32::try_from(info.attempt)?
+    } else {
+        u32::try_from(info.attempt)? + 1
+    };
+
     execute_tx
         .send(ExecuteToken {
             token: Token {
@@ -124,7 +134,8 @@ async fn do_retry(server: &Server, retry: Retry) -> Result<()> {
                 trigger_datetime: info.trigger_datetime,
             },
             priority: info.priority,
-            attempt: u32::try_from(info.attempt)? + 1,
+            attempt,
+            task_run_id: None,
         })
         .await?;
 
@@ -0,0 +1,92 @@
+use crate::server::Server;
+use anyhow::Result;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, trace};
+
+/// how often to refresh the daily task run totals
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// how many days to recompute when the scheduler starts, to fill any gaps
+const STARTUP_ROLLUP_DAYS: i32 = 35;
+
+/// Recompute the per-job `job_run_daily` totals for the last `days` days.
+/// These back the reporting endpoints so they never have to scan all of `task_run`.
+async fn rollup(server: &Server, days: i32) -> Result<()> {
+    let result = sqlx::query(
+        "INSERT INTO job_run_daily(
+            job_id,
+            day,
+            total_runs,
+            success_runs,
+            failure_runs,
+            total_duration_secs,
+            max_duration_secs,
+            median_queue_secs,
+            median_duration_secs
+        )
+        SELECT
+            t.job_id,
+            date_trunc('day', r.finish_datetime, 'UTC')::DATE AS day,
+            COUNT(1) AS total_runs,
+            COUNT(1) FILTER (WHERE r.state = 'success') AS success_runs,
+            COUNT(1) FILTER (WHERE r.state != 'success') AS failure_runs,
+            SUM(EXTRACT(EPOCH FROM r.finish_datetime - r.started_datetime)) AS total_duration_secs,
+            MAX(EXTRACT(EPOCH FROM r.finish_datetime - r.started_datetime)) AS max_duration_secs,
+            percentile_cont(0.5) WITHIN GROUP (
+                ORDER BY EXTRACT(EPOCH FROM r.started_datetime - r.queued_datetime)
+            ) AS median_queue_secs,
+            percentile_cont(0.5) WITHIN GROUP (
+                ORDER BY EXTRACT(EPOCH FROM r.finish_datetime - r.started_datetime)
+            ) AS median_duration_secs
+        FROM task_run r
+        JOIN task t ON t.id = r.task_id
+        WHERE r.finish_datetime >= date_trunc('day', CURRENT_TIMESTAMP, 'UTC') - (INTERVAL '1 day' * $1)
+        AND r.state IN ('success', 'failure', 'error', 'timeout')
+        GROUP BY 1, 2
+        ON CONFLICT(job_id, day)
+        DO UPDATE
+        SET total_runs = EXCLUDED.total_runs,
+            success_runs = EXCLUDED.success_runs,
+            failure_runs = EXCLUDED.failure_runs,
+            total_duration_secs = EXCLUDED.total_duration_secs,
+            max_duration_secs = EXCLUDED.max_duration_secs,
+            median_queue_secs = EXCLUDED.median_queue_secs,
+            median_duration_secs = EXCLUDED.median_duration_secs",
+    )
+    .bind(days)
+    .execute(&server.db_pool)
+    .await?;
+
+    trace!("rolled up {} job days of task runs", result.rows_affected());
+
+    Ok(())
+}
+
+/// Forget processed task results older than the rollup horizon. Redeliveries only
+/// happen within minutes of a result, so these are never looked up again.
+async fn prune_processed(server: &Server) -> Result<()> {
+    let result = sqlx::query(
+        "DELETE FROM task_result_processed
+        WHERE processed_datetime < CURRENT_TIMESTAMP - (INTERVAL '1 day' * $1)",
+    )
+    .bind(STARTUP_ROLLUP_DAYS)
+    .execute(&server.db_pool)
+    .await?;
+
+    trace!("pruned {} processed task results", result.rows_affected());
+
+    Ok(())
+}
+
+pub async fn process_rollup(server: Arc<Server>) -> Result<!> {
+    debug!("rolling up the last {} days of task runs", STARTUP_ROLLUP_DAYS);
+    rollup(&server, STARTUP_ROLLUP_DAYS).await?;
+
+    loop {
+        tokio::time::sleep(ROLLUP_INTERVAL).await;
+
+        // yesterday can still change as late results arrive
+        rollup(&server, 1).await?;
+        prune_processed(&server).await?;
+    }
+}
@@ -0,0 +1,367 @@
+use crate::{
+    broker::{Broker, Queue, SendOptions},
+    messages::{SecretRef, SensorCheck, StashScope, TaskProgress, TaskRequest, TokenState},
+    secrets,
+    server::Server,
+};
+use anyhow::{format_err, Result};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Connection, PgConnection, PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// how often to look for sensors that are due to be checked
+const SENSOR_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// how long a single HTTP or SQL check may take
+const CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// start checking a sensor task, called in place of sending it to a worker
+pub async fn add_sensor(txn: &mut Transaction<'_, Postgres>, task_req: &TaskRequest) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO sensor_poke(task_run_id, task_id, trigger_datetime,
+            started_datetime, next_poke_datetime)
+        VALUES ($1, $2, $3, NULL, CURRENT_TIMESTAMP)",
+    )
+    .bind(task_req.task_run_id)
+    .bind(task_req.task_id)
+    .bind(task_req.trigger_datetime)
+    .execute(&mut *txn)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct Sensor {
+    task_run_id: Uuid,
+    task_id: Uuid,
+    trigger_datetime: DateTime<Utc>,
+    started_datetime: Option<DateTime<Utc>>,
+    next_poke_datetime: DateTime<Utc>,
+    sensor_check: sqlx::types::Json<SensorCheck>,
+    poke_interval_secs: i64,
+    timeout_secs: Option<i64>,
+    job_id: Uuid,
+    project_id: Uuid,
+    project_name: String,
+    /// false once the run has finished some other way, eg. an operator set its state
+    in_progress: bool,
+}
+
+/// Check the sensors owned by this scheduler when they're due. Results are sent to
+/// the results queue as a worker would, so they're processed like any other task.
+pub async fn process_sensors(server: Arc<Server>) -> Result<!> {
+    let default_timeout = server.config.default_task_timeout as i64;
+
+    let mut ticker = tokio::time::interval(SENSOR_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let sensors: Vec<Sensor> = sqlx::query_as(
+            "SELECT
+                p.task_run_id,
+                p.task_id,
+                p.trigger_datetime,
+                p.started_datetime,
+                p.next_poke_datetime,
+                t.sensor AS sensor_check,
+                t.poke_interval_secs,
+                t.timeout_secs,
+                j.id AS job_id,
+                j.project_id,
+                pr.name AS project_name,
+                COALESCE(r.state IN ('active', 'running'), FALSE) AS in_progress
+            FROM sensor_poke p
+            JOIN task t ON t.id = p.task_id
+            JOIN job j ON j.id = t.job_id
+            JOIN project pr ON pr.id = j.project_id
+            LEFT JOIN task_run r ON r.id = p.task_run_id
+            WHERE t.sensor IS NOT NULL",
+        )
+        .fetch_all(&server.db_pool)
+        .await?;
+
+        let sensors: Vec<Sensor> = {
+            let rendezvous = server.on_cluster_membership_change.borrow();
+            sensors
+                .into_iter()
+                .filter(|sensor| rendezvous.item_is_mine(&server.node_id, &sensor.task_run_id))
+                .collect()
+        };
+
+        if sensors.is_empty() {
+            continue;
+        }
+        debug!("{} sensors in progress", sensors.len());
+
+        // sensors are only checked now and then, so keep their runs from looking abandoned
+        let task_run_ids: Vec<Uuid> = sensors.iter().map(|sensor| sensor.task_run_id).collect();
+        sqlx::query(
+            "UPDATE task_run
+            SET updated_datetime = CURRENT_TIMESTAMP
+            WHERE id = ANY($1)
+            AND state = 'running'",
+        )
+        .bind(&task_run_ids)
+        .execute(&server.db_pool)
+        .await?;
+
+        let now = Utc::now();
+        for sensor in sensors {
+            if !sensor.in_progress {
+                debug!(task_run_id=?sensor.task_run_id, "sensor run is no longer in progress");
+                remove_sensor(&server.db_pool, sensor.task_run_id).await?;
+            } else if sensor.next_poke_datetime <= now {
+                poke(&server, &sensor, default_timeout).await?;
+            }
+        }
+    }
+}
+
+async fn poke(server: &Server, sensor: &Sensor, default_timeout: i64) -> Result<()> {
+    let broker = server.broker.as_ref();
+    let now = Utc::now();
+
+    let started_datetime = match sensor.started_datetime {
+        Some(started_datetime) => started_datetime,
+        None => {
+            sqlx::query(
+                "UPDATE sensor_poke
+                SET started_datetime = $2
+                WHERE task_run_id = $1",
+            )
+            .bind(sensor.task_run_id)
+            .bind(now)
+            .execute(&server.db_pool)
+            .await?;
+
+            send_progress(broker, sensor, now, TokenState::Running, None).await?;
+            now
+        }
+    };
+
+    let result = check(server, sensor).await;
+
+    let (state, error_details) = match result {
+        Ok(true) => (TokenState::Success, None),
+        Ok(false) | Err(_) => {
+            if let Err(err) = &result {
+                warn!(task_run_id=?sensor.task_run_id, "sensor check failed: {:#}", err);
+            }
+
+            let timeout = Duration::seconds(sensor.timeout_secs.unwrap_or(default_timeout));
+            if now - started_datetime < timeout {
+                sqlx::query(
+                    "UPDATE sensor_poke
+                    SET next_poke_datetime = $2
+                    WHERE task_run_id = $1",
+                )
+                .bind(sensor.task_run_id)
+                .bind(now + Duration::seconds(sensor.poke_interval_secs))
+                .execute(&server.db_pool)
+                .await?;
+
+                return Ok(());
+            }
+
+            let details = match result {
+                Err(err) => format!("sensor timed out, last check failed: {err:#}"),
+                Ok(_) => "sensor condition was not met before the timeout".to_owned(),
+            };
+            (TokenState::Timeout, Some(details))
+        }
+    };
+
+    info!(task_run_id=?sensor.task_run_id,
+        task_id=?sensor.task_id,
+        trigger_datetime=?sensor.trigger_datetime.to_rfc3339(),
+        ?state,
+        "sensor finished");
+
+    send_progress(broker, sensor, started_datetime, state, error_details).await?;
+    remove_sensor(&server.db_pool, sensor.task_run_id).await?;
+
+    Ok(())
+}
+
+async fn remove_sensor(pool: &PgPool, task_run_id: Uuid) -> Result<()> {
+    sqlx::query(
+        "DELETE FROM sensor_poke
+        WHERE task_run_id = $1",
+    )
+    .bind(task_run_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+async fn send_progress(
+    broker: &dyn Broker,
+    sensor: &Sensor,
+    started_datetime: DateTime<Utc>,
+    result: TokenState,
+    error_details: Option<String>,
+) -> Result<()> {
+    let progress = TaskProgress {
+        task_run_id: sensor.task_run_id,
+        task_id: sensor.task_id,
+        trigger_datetime: sensor.trigger_datetime,
+        started_datetime,
+        finished_datetime: (result != TokenState::Running).then(Utc::now),
+        result,
+        worker_id: None,
+        error_details,
+        operator_override: false,
+        outputs: None,
+        exit_code: None,
+        error_class: None,
+    };
+
+    broker
+        .send(
+            Queue::Results,
+            &serde_json::to_vec(&progress)?,
+            SendOptions::default(),
+        )
+        .await
+}
+
+async fn check(server: &Server, sensor: &Sensor) -> Result<bool> {
+    match &*sensor.sensor_check {
+        SensorCheck::Http { url, status } => {
+            let client = reqwest::Client::builder().timeout(CHECK_TIMEOUT).build()?;
+            let resp = client.get(url).send().await?;
+
+            Ok(match status {
+                Some(status) => resp.status().as_u16() == *status,
+                None => resp.status().is_success(),
+            })
+        }
+        SensorCheck::Sql { database, query } => {
+            let url = read_secret(server, sensor, database).await?;
+
+            let passed = tokio::time::timeout(CHECK_TIMEOUT, async {
+                let mut conn = PgConnection::connect(&url).await?;
+                let row: Option<(bool,)> = sqlx::query_as(query).fetch_optional(&mut conn).await?;
+                conn.close().await?;
+                anyhow::Ok(row.map_or(false, |(passed,)| passed))
+            })
+            .await
+            .map_err(|_| format_err!("query timed out"))??;
+
+            Ok(passed)
+        }
+        SensorCheck::Stash { scope, key } => {
+            stash_exists(&server.db_pool, sensor, *scope, key).await
+        }
+    }
+}
+
+async fn stash_exists(pool: &PgPool, sensor: &Sensor, scope: StashScope, key: &str) -> Result<bool> {
+    let found: Option<(bool,)> = match scope {
+        StashScope::Global => {
+            sqlx::query_as(
+                "SELECT TRUE
+                FROM global_stash
+                WHERE name = $1",
+            )
+            .bind(key)
+            .fetch_optional(pool)
+            .await?
+        }
+        StashScope::Project => {
+            sqlx::query_as(
+                "SELECT TRUE
+                FROM project_stash
+                WHERE project_id = $1
+                AND name = $2",
+            )
+            .bind(sensor.project_id)
+            .bind(key)
+            .fetch_optional(pool)
+            .await?
+        }
+        StashScope::Job => {
+            sqlx::query_as(
+                "SELECT TRUE
+                FROM job_stash
+                WHERE job_id = $1
+                AND trigger_datetime = $2
+                AND name = $3",
+            )
+            .bind(sensor.job_id)
+            .bind(sensor.trigger_datetime)
+            .bind(key)
+            .fetch_optional(pool)
+            .await?
+        }
+    };
+
+    Ok(found.is_some())
+}
+
+/// read a secret for a sensor, stash secrets are read straight from the database
+async fn read_secret(server: &Server, sensor: &Sensor, secret_ref: &SecretRef) -> Result<String> {
+    secrets::check_scope(&server.config, &sensor.project_name, secret_ref)?;
+
+    let row: Option<(Option<Vec<u8>>,)> = match secret_ref {
+        SecretRef::Global { key } => {
+            sqlx::query_as(
+                "SELECT data
+                FROM global_stash
+                WHERE name = $1",
+            )
+            .bind(key)
+            .fetch_optional(&server.db_pool)
+            .await?
+        }
+        SecretRef::Project { key } => {
+            sqlx::query_as(
+                "SELECT data
+                FROM project_stash
+                WHERE project_id = $1
+                AND name = $2",
+            )
+            .bind(sensor.project_id)
+            .bind(key)
+            .fetch_optional(&server.db_pool)
+            .await?
+        }
+        SecretRef::Job { key } => {
+            sqlx::query_as(
+                "SELECT data
+                FROM job_stash
+                WHERE job_id = $1
+                AND trigger_datetime = $2
+                AND name = $3",
+            )
+            .bind(sensor.job_id)
+            .bind(sensor.trigger_datetime)
+            .bind(key)
+            .fetch_optional(&server.db_pool)
+            .await?
+        }
+        SecretRef::Vault { path, field } => {
+            return secrets::read_vault(
+                server.config.vault_addr.as_deref(),
+                server.config.vault_token.as_deref(),
+                path,
+                field,
+            )
+            .await
+        }
+        SecretRef::Aws { secret_id, field } => {
+            return secrets::read_aws(secret_id, field.as_deref()).await
+        }
+        SecretRef::Kubernetes { name, key } => {
+            return secrets::read_kube(server.config.kube_namespace.as_deref(), name, key).await
+        }
+    };
+
+    let (data,) = row.ok_or_else(|| format_err!("{secret_ref} not found"))?;
+    String::from_utf8(data.unwrap_or_default()).map_err(|_| format_err!("{secret_ref} is not valid UTF-8"))
+}
@@ -0,0 +1,162 @@
+use crate::{config::Config, logging, messages::TaskPriority};
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use sqlx::{PgExecutor, PgPool};
+use std::{sync::RwLock, time::Duration};
+use tracing::{info, warn};
+
+/// how often each server process picks up settings changed by another one
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// the settings in effect in this process, unset until they've first been loaded
+static CURRENT: Lazy<RwLock<Option<Settings>>> = Lazy::new(RwLock::default);
+
+/// Server settings that can be changed at runtime through `/api/settings`.
+/// Each defaults to its value in the config, and is overridden by a row in the
+/// `setting` table.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    /// the log filter, see `WATERWHEEL_LOG`
+    pub log: String,
+    /// most tasks sent to RabbitMQ but not started by a worker, 0 is unlimited
+    pub max_queued_tasks: u64,
+    /// most tasks queued or running at once, 0 is unlimited
+    pub max_concurrent_tasks: u64,
+    /// seconds a backfill task waits before it's escalated, 0 never escalates
+    pub backfill_escalation_delay: u64,
+    /// the priority tasks from catchups are queued at
+    pub catchup_priority: TaskPriority,
+    /// the priority of tasks activated or rerun through the API that don't give one
+    pub activate_priority: TaskPriority,
+}
+
+impl Settings {
+    pub fn from_config(config: &Config) -> Self {
+        Settings {
+            log: config.log.clone(),
+            max_queued_tasks: config.max_queued_tasks,
+            max_concurrent_tasks: config.max_concurrent_tasks,
+            backfill_escalation_delay: config.backfill_escalation_delay,
+            catchup_priority: TaskPriority::BackFill,
+            activate_priority: TaskPriority::High,
+        }
+    }
+
+    /// Apply overrides to these settings, usually the config's. Fails if an
+    /// override isn't a setting, or isn't a valid value for it.
+    pub fn with_overrides(
+        self,
+        overrides: &Map<String, JsonValue>,
+    ) -> std::result::Result<Self, String> {
+        let mut settings = match serde_json::to_value(self) {
+            Ok(JsonValue::Object(settings)) => settings,
+            _ => unreachable!("settings are always an object"),
+        };
+
+        for (name, value) in overrides {
+            match settings.get_mut(name) {
+                Some(setting) => *setting = value.clone(),
+                None => return Err(format!("'{name}' is not a setting")),
+            }
+        }
+
+        serde_json::from_value(JsonValue::Object(settings)).map_err(|err| err.to_string())
+    }
+}
+
+/// the settings in effect, which are the config's until they've been loaded
+pub fn current(config: &Config) -> Settings {
+    CURRENT
+        .read()
+        .expect("settings lock poisoned")
+        .clone()
+        .unwrap_or_else(|| Settings::from_config(config))
+}
+
+/// the settings that have been overridden, by name
+pub async fn load_overrides(db: impl PgExecutor<'_>) -> sqlx::Result<Map<String, JsonValue>> {
+    let rows: Vec<(String, JsonValue)> = sqlx::query_as(
+        "SELECT name, value
+        FROM setting",
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().collect())
+}
+
+/// make settings take effect in this process
+pub fn apply(config: &Config, settings: Settings) {
+    let previous = current(config);
+    if previous == settings {
+        return;
+    }
+
+    if previous.log != settings.log {
+        if let Err(err) = logging::set_filter(&settings.log) {
+            warn!("failed to update the log filter: {:#}", err);
+        }
+    }
+
+    info!(?settings, "applying runtime settings");
+    *CURRENT.write().expect("settings lock poisoned") = Some(settings);
+}
+
+/// reload the settings periodically, so changes made through another server take effect here
+pub async fn refresh((pool, config): (PgPool, Config)) -> Result<!> {
+    loop {
+        let overrides = load_overrides(&pool).await?;
+
+        match Settings::from_config(&config).with_overrides(&overrides) {
+            Ok(settings) => apply(&config, settings),
+            Err(err) => warn!("ignoring invalid runtime settings: {}", err),
+        }
+
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn overrides(value: JsonValue) -> Map<String, JsonValue> {
+        match value {
+            JsonValue::Object(map) => map,
+            _ => panic!("overrides must be an object"),
+        }
+    }
+
+    #[test]
+    fn test_with_overrides() {
+        let defaults = Settings {
+            log: "warn".to_owned(),
+            max_queued_tasks: 100,
+            max_concurrent_tasks: 0,
+            backfill_escalation_delay: 3600,
+            catchup_priority: TaskPriority::BackFill,
+            activate_priority: TaskPriority::High,
+        };
+
+        let settings = defaults.clone().with_overrides(&Map::new()).unwrap();
+        assert_eq!(settings, defaults);
+
+        let settings = defaults
+            .clone()
+            .with_overrides(&overrides(
+                json!({"max_queued_tasks": 5, "catchup_priority": "low"}),
+            ))
+            .unwrap();
+        assert_eq!(settings.max_queued_tasks, 5);
+        assert_eq!(settings.catchup_priority, TaskPriority::Low);
+        assert_eq!(settings.log, "warn");
+
+        let unknown = overrides(json!({"nope": 1}));
+        assert!(defaults.clone().with_overrides(&unknown).is_err());
+        let invalid = overrides(json!({"max_queued_tasks": -1}));
+        assert!(defaults.with_overrides(&invalid).is_err());
+    }
+}
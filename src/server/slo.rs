@@ -0,0 +1,239 @@
+use crate::{server::Server, util::first};
+use anyhow::Result;
+use cadence::{CountedExt, Gauged};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// the rolling windows SLO attainment is reported over, in seconds
+pub const WINDOWS: &[(&str, i64)] = &[
+    ("1h", 60 * 60),
+    ("6h", 6 * 60 * 60),
+    ("1d", 24 * 60 * 60),
+    ("7d", 7 * 24 * 60 * 60),
+    ("30d", 30 * 24 * 60 * 60),
+];
+
+/// how often to check jobs' burn rates
+const BURN_RATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Burn rates that raise an alert: a fast burn over the last hour should page,
+/// a slower burn over the last six hours should be looked at soon.
+/// These are the thresholds from the Google SRE workbook for a 30 day SLO.
+const BURN_RATE_ALERTS: &[(&str, i64, f64)] = &[
+    ("page", 60 * 60, 14.4),
+    ("ticket", 6 * 60 * 60, 6.0),
+];
+
+/// a job's latency targets, in seconds from the trigger time
+#[derive(Serialize, sqlx::FromRow, Clone, Copy, Debug)]
+pub struct SloTargets {
+    pub start_secs: Option<i64>,
+    pub finish_secs: Option<i64>,
+    pub objective: f64,
+}
+
+/// Latencies of a job's runs in a window. A run only counts towards a target once
+/// it has met it, or the target has passed without it being met.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct WindowStats {
+    pub runs: i64,
+    pub start_p50_secs: Option<f64>,
+    pub start_p95_secs: Option<f64>,
+    pub finish_p50_secs: Option<f64>,
+    pub finish_p95_secs: Option<f64>,
+    pub start_eligible: i64,
+    pub start_met: i64,
+    pub finish_eligible: i64,
+    pub finish_met: i64,
+}
+
+fn ratio(met: i64, eligible: i64) -> Option<f64> {
+    if eligible == 0 {
+        None
+    } else {
+        Some(met as f64 / eligible as f64)
+    }
+}
+
+/// how many times faster than the objective allows the error budget is being used
+pub fn burn_rate(attainment: f64, objective: f64) -> f64 {
+    (1.0 - attainment) / (1.0 - objective)
+}
+
+impl WindowStats {
+    /// fraction of runs meeting every target, None if no runs have counted yet
+    pub fn attainment(&self) -> Option<f64> {
+        let start = ratio(self.start_met, self.start_eligible);
+        let finish = ratio(self.finish_met, self.finish_eligible);
+
+        match (start, finish) {
+            (Some(start), Some(finish)) => Some(start.min(finish)),
+            (start, finish) => start.or(finish),
+        }
+    }
+}
+
+pub async fn get_slo_targets(pool: &PgPool, job_id: Uuid) -> Result<Option<SloTargets>> {
+    let targets = sqlx::query_as(
+        "SELECT
+            slo_start_secs AS start_secs,
+            slo_finish_secs AS finish_secs,
+            slo_objective AS objective
+        FROM job
+        WHERE id = $1
+        AND slo_objective IS NOT NULL",
+    )
+    .bind(job_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(targets)
+}
+
+pub async fn window_stats(
+    pool: &PgPool,
+    job_id: Uuid,
+    targets: &SloTargets,
+    window_secs: i64,
+) -> Result<WindowStats> {
+    let stats = sqlx::query_as(
+        "WITH runs AS (
+            SELECT
+                trigger_datetime,
+                EXTRACT(EPOCH FROM first_started_datetime - trigger_datetime)::DOUBLE PRECISION
+                    AS start_secs,
+                EXTRACT(EPOCH FROM finish_datetime - trigger_datetime)::DOUBLE PRECISION
+                    AS finish_secs,
+                EXTRACT(EPOCH FROM CURRENT_TIMESTAMP - trigger_datetime)::DOUBLE PRECISION
+                    AS age_secs
+            FROM job_run
+            WHERE job_id = $1
+            AND trigger_datetime > CURRENT_TIMESTAMP - make_interval(secs => $2)
+            AND trigger_datetime <= CURRENT_TIMESTAMP
+        )
+        SELECT
+            COUNT(1) AS runs,
+            percentile_cont(0.5) WITHIN GROUP (ORDER BY start_secs) AS start_p50_secs,
+            percentile_cont(0.95) WITHIN GROUP (ORDER BY start_secs) AS start_p95_secs,
+            percentile_cont(0.5) WITHIN GROUP (ORDER BY finish_secs) AS finish_p50_secs,
+            percentile_cont(0.95) WITHIN GROUP (ORDER BY finish_secs) AS finish_p95_secs,
+            COUNT(1) FILTER (WHERE start_secs <= $3 OR age_secs > $3) AS start_eligible,
+            COUNT(1) FILTER (WHERE start_secs <= $3) AS start_met,
+            COUNT(1) FILTER (WHERE finish_secs <= $4 OR age_secs > $4) AS finish_eligible,
+            COUNT(1) FILTER (WHERE finish_secs <= $4) AS finish_met
+        FROM runs",
+    )
+    .bind(job_id)
+    .bind(window_secs as f64)
+    .bind(targets.start_secs.map(|secs| secs as f64))
+    .bind(targets.finish_secs.map(|secs| secs as f64))
+    .fetch_one(pool)
+    .await?;
+
+    Ok(stats)
+}
+
+/// Periodically check the burn rate of each job with an SLO that this scheduler
+/// owns, and raise an alert for any burning through their error budget too fast.
+pub async fn process_slo(server: Arc<Server>) -> Result<!> {
+    let statsd = server.statsd.clone();
+    let mut ticker = tokio::time::interval(BURN_RATE_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+        debug!("checking job SLO burn rates");
+
+        let jobs: Vec<(Uuid,)> = sqlx::query_as(
+            "SELECT id
+            FROM job
+            WHERE slo_objective IS NOT NULL
+            AND NOT paused",
+        )
+        .fetch_all(&server.db_pool)
+        .await?;
+
+        let jobs: Vec<Uuid> = {
+            let rendezvous = server.on_cluster_membership_change.borrow();
+            jobs.into_iter()
+                .map(first)
+                .filter(|job_id| rendezvous.item_is_mine(&server.node_id, job_id))
+                .collect()
+        };
+
+        for job_id in jobs {
+            let targets = match get_slo_targets(&server.db_pool, job_id).await? {
+                Some(targets) => targets,
+                None => continue,
+            };
+
+            for (severity, window_secs, threshold) in BURN_RATE_ALERTS {
+                let stats = window_stats(&server.db_pool, job_id, &targets, *window_secs).await?;
+                let attainment = match stats.attainment() {
+                    Some(attainment) => attainment,
+                    None => continue,
+                };
+
+                let burn = burn_rate(attainment, targets.objective);
+                let job_id_str = job_id.to_string();
+
+                statsd
+                    .gauge_with_tags("slo.burn_rate", burn)
+                    .with_tag("job_id", &job_id_str)
+                    .with_tag("severity", severity)
+                    .send();
+
+                if burn >= *threshold {
+                    warn!(?job_id,
+                        severity,
+                        burn_rate=burn,
+                        attainment,
+                        objective=targets.objective,
+                        "job is burning through its SLO error budget");
+
+                    statsd
+                        .incr_with_tags("slo.burn_rate_alert")
+                        .with_tag("job_id", &job_id_str)
+                        .with_tag("severity", severity)
+                        .send();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stats(start: (i64, i64), finish: (i64, i64)) -> WindowStats {
+        WindowStats {
+            runs: 10,
+            start_p50_secs: None,
+            start_p95_secs: None,
+            finish_p50_secs: None,
+            finish_p95_secs: None,
+            start_met: start.0,
+            start_eligible: start.1,
+            finish_met: finish.0,
+            finish_eligible: finish.1,
+        }
+    }
+
+    #[test]
+    fn test_attainment() {
+        assert_eq!(stats((0, 0), (0, 0)).attainment(), None);
+        assert_eq!(stats((9, 10), (0, 0)).attainment(), Some(0.9));
+        assert_eq!(stats((0, 0), (3, 4)).attainment(), Some(0.75));
+        assert_eq!(stats((9, 10), (3, 4)).attainment(), Some(0.75));
+    }
+
+    #[test]
+    fn test_burn_rate() {
+        assert!((burn_rate(0.99, 0.99) - 1.0).abs() < 1e-9);
+        assert!((burn_rate(0.9, 0.99) - 10.0).abs() < 1e-9);
+        assert_eq!(burn_rate(1.0, 0.99), 0.0);
+    }
+}
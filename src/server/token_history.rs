@@ -0,0 +1,117 @@
+use crate::{messages::Token, server::job_run};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Postgres, Transaction};
+use std::fmt;
+use uuid::Uuid;
+
+/// what happened to a token, recorded in the token's history
+#[derive(sqlx::Type, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(type_name = "VARCHAR")]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TokenEvent {
+    /// an upstream task or trigger added to the token's count
+    Increment,
+    /// the token activated and a task run was sent to a worker
+    Dispatch,
+    /// a worker (or an operator) reported progress on the task run
+    Result,
+    /// the task run failed and will be retried
+    Retry,
+    /// the task run was abandoned by its worker and failed
+    Requeue,
+    /// the token went past its deadline before it ran
+    Expire,
+    /// a policy refused to let the task run
+    Veto,
+    /// an upstream task failed so the token can never activate
+    #[serde(rename = "upstream_failed")]
+    #[sqlx(rename = "upstream_failed")]
+    UpstreamFailed,
+    /// the task finished with a result none of its downstream edges handle
+    #[serde(rename = "unmapped_result")]
+    #[sqlx(rename = "unmapped_result")]
+    UnmappedResult,
+    /// an operator cleared the token
+    Clear,
+    /// an operator activated the token
+    Activate,
+}
+
+/// who or what changed a token
+#[derive(Clone, Copy, Debug)]
+pub enum Actor {
+    Scheduler,
+    Worker(Uuid),
+    Operator,
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Actor::Scheduler => write!(f, "scheduler"),
+            Actor::Worker(worker_id) => write!(f, "worker:{worker_id}"),
+            Actor::Operator => write!(f, "operator"),
+        }
+    }
+}
+
+/// a single entry in a token's history
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct TokenHistory {
+    pub event: TokenEvent,
+    pub state: Option<String>,
+    pub count: Option<i32>,
+    pub actor: String,
+    pub task_run_id: Option<Uuid>,
+    pub details: Option<String>,
+    pub created_datetime: DateTime<Utc>,
+}
+
+/// Append an event to the history of each token, along with the state the token
+/// was left in. Call this after changing the tokens, in the same transaction, so
+/// the history only has changes that were committed. Every token change passes
+/// through here, so this also keeps the runs' token state counts up to date.
+pub async fn record(
+    txn: &mut Transaction<'_, Postgres>,
+    tokens: &[Token],
+    event: TokenEvent,
+    actor: Actor,
+    task_run_id: Option<Uuid>,
+    details: Option<&str>,
+) -> Result<()> {
+    if tokens.is_empty() {
+        return Ok(());
+    }
+
+    let task_ids: Vec<Uuid> = tokens.iter().map(|t| t.task_id).collect();
+    let trigger_datetimes: Vec<DateTime<Utc>> = tokens.iter().map(|t| t.trigger_datetime).collect();
+
+    sqlx::query(
+        "INSERT INTO token_history(task_id, trigger_datetime, event, state, count,
+            actor, task_run_id, details, created_datetime)
+        SELECT t.task_id, t.trigger_datetime, $3, k.state, k.count,
+            $4, $5, $6, CURRENT_TIMESTAMP
+        FROM (
+            SELECT DISTINCT task_id, trigger_datetime
+            FROM UNNEST($1::UUID[], $2::TIMESTAMP WITH TIME ZONE[]) AS u(task_id, trigger_datetime)
+        ) t
+        LEFT JOIN token k
+            ON k.task_id = t.task_id
+            AND k.trigger_datetime = t.trigger_datetime",
+    )
+    .bind(&task_ids)
+    .bind(&trigger_datetimes)
+    .bind(event)
+    .bind(actor.to_string())
+    .bind(task_run_id)
+    .bind(details)
+    .execute(&mut *txn)
+    .await?;
+
+    job_run::update_state_counts(txn, tokens).await?;
+
+    Ok(())
+}
@@ -1,15 +1,27 @@
 use crate::{
     messages::{ProcessToken, TaskPriority, Token},
-    server::{execute::ExecuteToken, Server},
+    server::{
+        execute::ExecuteToken,
+        outbox::OutboxReady,
+        token_history::{self, Actor, TokenEvent},
+        Server,
+    },
 };
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use futures::TryStreamExt;
-use postage::prelude::*;
-use sqlx::{PgPool, Postgres, Transaction};
-use std::{fmt, sync::Arc};
+use postage::{dispatch::Sender, prelude::*};
+use sqlx::{Connection, PgPool, Postgres, Transaction};
+use std::{fmt, sync::Arc, time::Duration};
 use tracing::{debug, trace};
 use uuid::Uuid;
 
+/// how often to check the outbox in case a wakeup was missed (eg. it was written by another scheduler)
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// how many outbox rows to claim at once
+const OUTBOX_BATCH_SIZE: i64 = 100;
+
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -48,32 +60,115 @@ async fn get_count_and_threshold(pool: &PgPool, token: &Token) -> Result<Increme
     Ok(info)
 }
 
+async fn check_threshold(
+    pool: &PgPool,
+    execute_tx: &mut Sender<ExecuteToken>,
+    token: Token,
+    priority: TaskPriority,
+) -> Result<()> {
+    let info = get_count_and_threshold(pool, &token).await?;
+
+    trace!(task_id=?token.task_id,
+        trigger_datetime=?token.trigger_datetime.to_rfc3339(),
+        "count is {} (threshold {})", info.count, info.threshold);
+
+    if !info.paused && info.count >= info.threshold {
+        execute_tx
+            .send(ExecuteToken {
+                token,
+                priority,
+                attempt: 1,
+                task_run_id: None,
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct OutboxRow {
+    id: i64,
+    task_id: Uuid,
+    trigger_datetime: DateTime<Utc>,
+    priority: TaskPriority,
+}
+
+/// Process the token increments written to the outbox. Rows are locked while they're
+/// processed so other schedulers skip them, and only deleted once they've been handled.
+async fn drain_outbox(pool: &PgPool, execute_tx: &mut Sender<ExecuteToken>) -> Result<()> {
+    loop {
+        let mut conn = pool.acquire().await?;
+        let mut txn = conn.begin().await?;
+
+        let rows: Vec<OutboxRow> = sqlx::query_as(
+            "SELECT
+                id,
+                task_id,
+                trigger_datetime,
+                priority
+            FROM token_outbox
+            ORDER BY id
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED",
+        )
+        .bind(OUTBOX_BATCH_SIZE)
+        .fetch_all(&mut txn)
+        .await?;
+
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        trace!("draining {} tokens from the outbox", rows.len());
+
+        let ids: Vec<i64> = rows.iter().map(|row| row.id).collect();
+
+        for row in rows {
+            let token = Token {
+                task_id: row.task_id,
+                trigger_datetime: row.trigger_datetime,
+            };
+            check_threshold(pool, execute_tx, token, row.priority).await?;
+        }
+
+        sqlx::query("DELETE FROM token_outbox WHERE id = ANY($1)")
+            .bind(&ids)
+            .execute(&mut txn)
+            .await?;
+
+        txn.commit().await?;
+    }
+}
+
 pub async fn process_tokens(server: Arc<Server>) -> Result<!> {
     let pool = server.db_pool.clone();
 
+    // anything left in the outbox, by this scheduler or another one, is drained by the poll
     restore_tokens(&server, None).await?;
 
     let mut token_rx = server.post_office.receive_mail::<ProcessToken>().await?;
+    let mut outbox_rx = server.post_office.receive_mail::<OutboxReady>().await?;
     let mut execute_tx = server.post_office.post_mail::<ExecuteToken>().await?;
+    let mut outbox_poll = tokio::time::interval(OUTBOX_POLL_INTERVAL);
+
+    loop {
+        let msg = tokio::select! {
+            msg = token_rx.recv() => msg.expect("ProcessToken channel was closed!"),
+            ready = outbox_rx.recv() => {
+                ready.expect("OutboxReady channel was closed!");
+                drain_outbox(&pool, &mut execute_tx).await?;
+                continue;
+            }
+            _ = outbox_poll.tick() => {
+                drain_outbox(&pool, &mut execute_tx).await?;
+                continue;
+            }
+        };
 
-    while let Some(msg) = token_rx.recv().await {
         match msg {
             ProcessToken::Increment(token, priority) => {
-                let info = get_count_and_threshold(&pool, &token).await?;
-
-                trace!(task_id=?token.task_id,
-                    trigger_datetime=?token.trigger_datetime.to_rfc3339(),
-                    "count is {} (threshold {})", info.count, info.threshold);
-
-                if !info.paused && info.count >= info.threshold {
-                    execute_tx
-                        .send(ExecuteToken {
-                            token,
-                            priority,
-                            attempt: 1,
-                        })
-                        .await?;
-                }
+                check_threshold(&pool, &mut execute_tx, token, priority).await?;
             }
             ProcessToken::Activate(token, priority) => {
                 execute_tx
@@ -81,6 +176,7 @@ pub async fn process_tokens(server: Arc<Server>) -> Result<!> {
                         token,
                         priority,
                         attempt: 1,
+                        task_run_id: None,
                     })
                     .await?;
             }
@@ -92,8 +188,6 @@ pub async fn process_tokens(server: Arc<Server>) -> Result<!> {
             }
         }
     }
-
-    unreachable!("ProcessToken channel was closed!")
 }
 
 /// Adds a token to a task node
@@ -111,13 +205,58 @@ pub async fn increment_token(txn: &mut Transaction<'_, Postgres>, token: &Token)
         "INSERT INTO token(task_id, trigger_datetime, count, state)
             VALUES ($1, $2, 1, 'waiting')
             ON CONFLICT(task_id, trigger_datetime)
-            DO UPDATE SET count = token.count + 1",
+            DO UPDATE
+            SET count = token.count + 1,
+                state = CASE WHEN token.state = 'upstream_failed' THEN 'waiting' ELSE token.state END",
     )
     .bind(token.task_id)
     .bind(token.trigger_datetime)
     .execute(&mut *txn)
     .await?;
 
+    token_history::record(
+        txn,
+        std::slice::from_ref(token),
+        TokenEvent::Increment,
+        Actor::Scheduler,
+        None,
+        None,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Like `increment_token` but for many tokens in a single statement.
+/// A token that appears more than once is incremented once per appearance.
+pub async fn increment_tokens(txn: &mut Transaction<'_, Postgres>, tokens: &[Token]) -> Result<()> {
+    if tokens.is_empty() {
+        return Ok(());
+    }
+
+    trace!("incrementing {} tokens", tokens.len());
+
+    let task_ids: Vec<Uuid> = tokens.iter().map(|t| t.task_id).collect();
+    let trigger_datetimes: Vec<DateTime<Utc>> = tokens.iter().map(|t| t.trigger_datetime).collect();
+
+    // grouping first because ON CONFLICT can't update the same row twice in one statement
+    sqlx::query(
+        "INSERT INTO token(task_id, trigger_datetime, count, state)
+            SELECT task_id, trigger_datetime, COUNT(1), 'waiting'
+            FROM UNNEST($1::UUID[], $2::TIMESTAMP WITH TIME ZONE[]) AS t(task_id, trigger_datetime)
+            GROUP BY task_id, trigger_datetime
+            ON CONFLICT(task_id, trigger_datetime)
+            DO UPDATE
+            SET count = token.count + EXCLUDED.count,
+                state = CASE WHEN token.state = 'upstream_failed' THEN 'waiting' ELSE token.state END",
+    )
+    .bind(&task_ids)
+    .bind(&trigger_datetimes)
+    .execute(&mut *txn)
+    .await?;
+
+    token_history::record(txn, tokens, TokenEvent::Increment, Actor::Scheduler, None, None).await?;
+
     Ok(())
 }
 
@@ -161,6 +300,7 @@ async fn restore_tokens(server: &Server, job_id: Option<Uuid>) -> Result<()> {
                 token: token.clone(),
                 priority: TaskPriority::Normal,
                 attempt: 1,
+                task_run_id: None,
             })
             .await?;
 
@@ -0,0 +1,61 @@
+use crate::{
+    messages::TriggerUpdate,
+    server::{triggers::trigger_update, Server},
+    util::first,
+};
+use anyhow::Result;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+/// how often to look for trigger overrides that have expired
+const OVERRIDE_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The scheduler ignores expired overrides, but a trigger is only requeued when
+/// something changes. Each scheduler sweeps up expired overrides on the triggers
+/// it owns and requeues them, so they revert to their normal schedule.
+pub async fn process_override_expiry(server: Arc<Server>) -> Result<!> {
+    let mut ticker = tokio::time::interval(OVERRIDE_EXPIRY_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+        debug!("checking for expired trigger overrides");
+
+        let expired: Vec<(Uuid,)> = sqlx::query_as(
+            "SELECT trigger_id
+            FROM trigger_override
+            WHERE expires_datetime <= CURRENT_TIMESTAMP",
+        )
+        .fetch_all(&server.db_pool)
+        .await?;
+
+        let mine: Vec<Uuid> = {
+            let rendezvous = server.on_cluster_membership_change.borrow();
+            expired
+                .into_iter()
+                .map(first)
+                .filter(|trigger_id| rendezvous.item_is_mine(&server.node_id, trigger_id))
+                .collect()
+        };
+
+        if mine.is_empty() {
+            continue;
+        }
+
+        for trigger_id in &mine {
+            info!(?trigger_id, "trigger override has expired, reverting");
+        }
+
+        // the override may have been replaced since we looked, so check it's still expired
+        sqlx::query(
+            "DELETE FROM trigger_override
+            WHERE trigger_id = ANY($1)
+            AND expires_datetime <= CURRENT_TIMESTAMP",
+        )
+        .bind(&mine)
+        .execute(&server.db_pool)
+        .await?;
+
+        trigger_update(server.clone(), TriggerUpdate(mine)).await?;
+    }
+}
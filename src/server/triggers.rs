@@ -1,14 +1,16 @@
 use crate::{
-    messages::{ProcessToken, TaskPriority, Token},
-    server::{api::types::Catchup, tokens::increment_token, trigger_time::TriggerTime, Server},
+    messages::{LiveUpdate, TaskPriority, Token},
+    server::{
+        api::types::Catchup, job_run::add_run_triggers, live_updates, outbox, settings,
+        tokens::increment_tokens, trigger_time::TriggerTime, Server,
+    },
     util::format_duration_approx,
 };
 use anyhow::Result;
 use binary_heap_plus::{BinaryHeap, MinComparator};
-use cadence::Gauged;
+use cadence::{CountedExt, Gauged};
 use chrono::{DateTime, Duration, Utc};
 use cron::Schedule;
-use futures::TryStreamExt;
 use postage::{prelude::*, stream::TryRecvError};
 use rand::{seq::SliceRandom, thread_rng};
 use serde::{Deserialize, Serialize};
@@ -26,6 +28,9 @@ use crate::util::{deref, first};
 
 type Queue = BinaryHeap<TriggerTime, MinComparator>;
 
+/// how long a trigger is held back when its project has used up its hourly fires
+const FIRES_QUOTA_DELAY_SECS: i64 = 60;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum TriggerChange {
     Add(Vec<Uuid>),
@@ -45,29 +50,86 @@ struct Trigger {
     catchup: Catchup,
 }
 
-enum Period {
+/// how a trigger steps from one trigger time to the next
+pub enum Period {
     Duration(Duration),
     Cron(Box<Schedule>),
 }
 
+impl Period {
+    /// a trigger's period from its `period` (in seconds) or `cron` columns
+    pub fn new(period: Option<i64>, cron: Option<&str>) -> Result<Self> {
+        Ok(match (cron, period) {
+            (Some(cron), _) => Period::Cron(Box::new(Schedule::from_str(cron)?)),
+            (None, Some(period)) if period > 0 => Period::Duration(Duration::seconds(period)),
+            _ => anyhow::bail!("trigger has neither a period nor a cron schedule"),
+        })
+    }
+
+    /// the trigger time after `datetime`, if the schedule has one
+    pub fn next_after(&self, datetime: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Period::Duration(duration) => Some(datetime + *duration),
+            Period::Cron(schedule) => schedule.after(&datetime).next(),
+        }
+    }
+
+    /// the trigger times starting at `start`
+    pub fn times_from(&self, start: DateTime<Utc>) -> TriggerTimes<'_> {
+        TriggerTimes {
+            period: self,
+            next: Some(start),
+        }
+    }
+}
+
 impl std::ops::Add<&Period> for DateTime<Utc> {
     type Output = Self;
 
     fn add(self, rhs: &Period) -> Self::Output {
-        match rhs {
-            Period::Duration(duration) => self + *duration,
-            Period::Cron(schedule) => schedule.after(&self).next().unwrap(),
+        rhs.next_after(self).unwrap()
+    }
+}
+
+/// iterator over the trigger times of a period, see `Period::times_from`
+pub struct TriggerTimes<'a> {
+    period: &'a Period,
+    next: Option<DateTime<Utc>>,
+}
+
+impl TriggerTimes<'_> {
+    /// Skip forward so the next time is the first at or after `datetime`,
+    /// without walking every time in between.
+    pub fn skip_until(mut self, datetime: DateTime<Utc>) -> Self {
+        if let Some(next) = self.next.filter(|next| *next < datetime) {
+            self.next = match self.period {
+                Period::Duration(duration) => {
+                    let step = duration.num_milliseconds();
+                    let steps = ((datetime - next).num_milliseconds() + step - 1) / step;
+                    Some(next + Duration::milliseconds(steps * step))
+                }
+                Period::Cron(schedule) => schedule
+                    .after(&(datetime - Duration::seconds(1)))
+                    .find(|time| *time >= datetime),
+            };
         }
+        self
+    }
+}
+
+impl Iterator for TriggerTimes<'_> {
+    type Item = DateTime<Utc>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next?;
+        self.next = self.period.next_after(current);
+        Some(current)
     }
 }
 
 impl Trigger {
     fn period(&self) -> Result<Period> {
-        Ok(if let Some(ref cron) = self.cron {
-            Period::Cron(Box::new(Schedule::from_str(cron)?))
-        } else {
-            Period::Duration(Duration::seconds(self.period.unwrap()))
-        })
+        Period::new(self.period, self.cron.as_deref())
     }
 
     fn offset_duration(&self) -> Duration {
@@ -167,16 +229,109 @@ pub async fn process_triggers(server: Arc<Server>) -> Result<!> {
                 }
                 _ = time::sleep(delay.to_std()?) => {
                     trace!("sleep completed, no updates");
-                    requeue_next_triggertime(&server, &next_triggertime, &mut queue).await?;
-                    activate_trigger(&server, next_triggertime, TaskPriority::Normal).await?;
+                    fire_trigger(&server, next_triggertime, &mut queue).await?;
                 }
             }
         } else {
             warn!("overslept trigger: {}", delay);
-            requeue_next_triggertime(&server, &next_triggertime, &mut queue).await?;
-            activate_trigger(&server, next_triggertime, TaskPriority::Normal).await?;
+            fire_trigger(&server, next_triggertime, &mut queue).await?;
+        }
+    }
+}
+
+/// Fire a trigger time that is due and queue the trigger's next time, unless the
+/// project's trigger quotas say it must wait, in which case it is put back in the
+/// queue for later. The next time isn't queued until this one fires, so a held
+/// back trigger slides rather than bunching up.
+async fn fire_trigger(
+    server: &Server,
+    trigger_time: TriggerTime,
+    queue: &mut Queue,
+) -> Result<()> {
+    if let Some(retry_at) = over_trigger_quota(&server.db_pool, trigger_time.trigger_id).await? {
+        warn!(trigger_id=?trigger_time.trigger_id,
+            trigger_datetime=?trigger_time.trigger_datetime.to_rfc3339(),
+            "trigger is over its project's quota, holding it until {}", retry_at.to_rfc3339());
+
+        server.statsd.incr_with_tags("triggers.throttled").send();
+
+        queue.push(TriggerTime {
+            scheduled_datetime: retry_at,
+            ..trigger_time
+        });
+        return Ok(());
+    }
+
+    requeue_next_triggertime(server, &trigger_time, queue).await?;
+    activate_trigger(server, trigger_time, TaskPriority::Normal).await?;
+
+    Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct TriggerQuota {
+    min_trigger_period_secs: Option<i64>,
+    max_fires_per_hour: Option<i32>,
+    last_fired_datetime: Option<DateTime<Utc>>,
+    fires_last_hour: i64,
+}
+
+/// Check a trigger against its project's quotas, returning when it may fire if it
+/// can't yet. Jobs are checked when they are created, so this only holds back
+/// triggers after a quota has been lowered, or when a catchup has used up the
+/// hour's fires (a catchup counts as a single fire of each of its triggers).
+async fn over_trigger_quota(pool: &PgPool, trigger_id: Uuid) -> Result<Option<DateTime<Utc>>> {
+    let quota: Option<TriggerQuota> = sqlx::query_as(
+        "SELECT
+            q.min_trigger_period_secs,
+            q.max_fires_per_hour,
+            (
+                SELECT MAX(r.fired_datetime)
+                FROM job_run_trigger r
+                WHERE r.trigger_id = t.id
+            ) AS last_fired_datetime,
+            (
+                SELECT COUNT(DISTINCT (r.trigger_id, r.fired_datetime))
+                FROM job_run_trigger r
+                JOIN job rj ON rj.id = r.job_id
+                WHERE rj.project_id = j.project_id
+                AND r.fired_datetime > CURRENT_TIMESTAMP - INTERVAL '1 hour'
+            ) AS fires_last_hour
+        FROM trigger t
+        JOIN job j ON j.id = t.job_id
+        JOIN project_quota q ON q.project_id = j.project_id
+        WHERE t.id = $1
+        AND (q.min_trigger_period_secs IS NOT NULL OR q.max_fires_per_hour IS NOT NULL)",
+    )
+    .bind(trigger_id)
+    .fetch_optional(pool)
+    .await?;
+
+    let quota = match quota {
+        Some(quota) => quota,
+        None => return Ok(None),
+    };
+
+    let now = Utc::now();
+    let mut retry_at = None;
+
+    if let (Some(min_period), Some(last_fired)) =
+        (quota.min_trigger_period_secs, quota.last_fired_datetime)
+    {
+        let next_allowed = last_fired + Duration::seconds(min_period);
+        if next_allowed > now {
+            retry_at = Some(next_allowed);
+        }
+    }
+
+    if let Some(max_fires) = quota.max_fires_per_hour {
+        if quota.fires_last_hour >= max_fires as i64 {
+            let delayed = now + Duration::seconds(FIRES_QUOTA_DELAY_SECS);
+            retry_at = Some(retry_at.map_or(delayed, |at| at.max(delayed)));
         }
     }
+
+    Ok(retry_at)
 }
 
 async fn activate_trigger(
@@ -189,13 +344,40 @@ async fn activate_trigger(
     let mut conn = pool.acquire().await?;
     let mut txn = conn.begin().await?;
 
-    let tokens_to_tx = do_activate_trigger(&pool, &mut txn, trigger_time).await?;
+    let (job_id, name): (Uuid, String) = sqlx::query_as(
+        "SELECT job_id, name
+        FROM trigger
+        WHERE id = $1",
+    )
+    .bind(trigger_time.trigger_id)
+    .fetch_one(&mut txn)
+    .await?;
+
+    let tokens_to_tx = do_activate_trigger(
+        &pool,
+        &mut txn,
+        trigger_time.trigger_id,
+        &[trigger_time.trigger_datetime],
+    )
+    .await?;
+    outbox::add(&mut txn, &tokens_to_tx, priority).await?;
 
     txn.commit().await?;
     trace!("done activating trigger: {}", trigger_time);
 
     // after committing the transaction we can tell the token processor to check thresholds
-    send_to_token_processor(server, tokens_to_tx, priority).await?;
+    outbox::notify(server).await?;
+
+    live_updates::send(
+        server.broker.as_ref(),
+        &LiveUpdate::TriggerFired {
+            job_id,
+            trigger_id: trigger_time.trigger_id,
+            name,
+            trigger_datetime: trigger_time.trigger_datetime,
+        },
+    )
+    .await;
 
     Ok(())
 }
@@ -206,52 +388,62 @@ struct TriggerEdge {
     edge_offset: Option<i64>,
 }
 
+/// Activate a trigger at one or more trigger times. All the tokens are incremented
+/// in a single statement, since a trigger can have hundreds of downstream tasks
+/// and a catchup can cover many periods.
 async fn do_activate_trigger(
     pool: &PgPool,
     txn: &mut Transaction<'_, Postgres>,
-    trigger_time: TriggerTime,
+    trigger_id: Uuid,
+    trigger_datetimes: &[DateTime<Utc>],
 ) -> Result<Vec<Token>> {
-    debug!(trigger_id=?trigger_time.trigger_id,
-        trigger_datetime=?trigger_time.trigger_datetime.to_rfc3339(),
-        "activating trigger");
+    let (earliest, latest) = match (trigger_datetimes.iter().min(), trigger_datetimes.iter().max()) {
+        (Some(earliest), Some(latest)) => (*earliest, *latest),
+        _ => return Ok(Vec::new()),
+    };
+
+    debug!(?trigger_id,
+        earliest=?earliest.to_rfc3339(),
+        latest=?latest.to_rfc3339(),
+        "activating trigger {} times", trigger_datetimes.len());
 
-    let mut cursor = sqlx::query_as(
+    let edges: Vec<TriggerEdge> = sqlx::query_as(
         "SELECT
             task_id,
             edge_offset
         FROM trigger_edge te
         WHERE trigger_id = $1",
     )
-    .bind(trigger_time.trigger_id)
-    .fetch(pool);
+    .bind(trigger_id)
+    .fetch_all(pool)
+    .await?;
 
     let mut tokens_to_tx = Vec::new();
 
-    while let Some(TriggerEdge {
-        task_id,
-        edge_offset,
-    }) = cursor.try_next().await?
-    {
-        let token = Token {
-            task_id,
-            trigger_datetime: trigger_time.trigger_datetime
-                + Duration::seconds(edge_offset.unwrap_or(0)),
-        };
-
-        increment_token(txn, &token).await?;
-        tokens_to_tx.push(token);
+    for trigger_datetime in trigger_datetimes {
+        for edge in &edges {
+            tokens_to_tx.push(Token {
+                task_id: edge.task_id,
+                trigger_datetime: *trigger_datetime
+                    + Duration::seconds(edge.edge_offset.unwrap_or(0)),
+            });
+        }
     }
 
-    trace!("updating trigger times for {}", trigger_time);
+    increment_tokens(txn, &tokens_to_tx).await?;
+    add_run_triggers(txn, trigger_id, &tokens_to_tx).await?;
+
+    trace!(?trigger_id, "updating trigger times");
     sqlx::query(
         "
         UPDATE trigger
         SET latest_trigger_datetime = GREATEST(latest_trigger_datetime, $2),
-            earliest_trigger_datetime = LEAST(earliest_trigger_datetime, $2)
+            earliest_trigger_datetime = LEAST(earliest_trigger_datetime, $3)
         WHERE id = $1",
     )
-    .bind(trigger_time.trigger_id)
-    .bind(trigger_time.trigger_datetime)
+    .bind(trigger_id)
+    .bind(latest)
+    .bind(earliest)
     .execute(txn)
     .await?;
 
@@ -267,7 +459,7 @@ async fn catchup_trigger(
 
     let pool = server.db_pool.clone();
 
-    let mut tokens_to_tx = Vec::new();
+    let mut catchup_datetimes = Vec::new();
 
     let mut conn = pool.acquire().await?;
     let mut txn = conn.begin().await?;
@@ -283,12 +475,11 @@ async fn catchup_trigger(
                     earliest, trigger.start_datetime
                 );
 
-                let mut next = trigger.start_datetime;
-                while next < earliest {
-                    let mut tokens = do_activate_trigger(&pool, &mut txn, trigger.at(next)).await?;
-                    tokens_to_tx.append(&mut tokens);
-                    next = next + &period;
-                }
+                catchup_datetimes.extend(
+                    period
+                        .times_from(trigger.start_datetime)
+                        .take_while(|next| *next < earliest),
+                );
             }
         }
     }
@@ -310,20 +501,20 @@ async fn catchup_trigger(
 
     while next < last {
         if trigger.catchup != Catchup::None {
-            let mut tokens = do_activate_trigger(&pool, &mut txn, trigger.at(next)).await?;
-            tokens_to_tx.append(&mut tokens);
+            catchup_datetimes.push(next);
         }
         next = next + &period;
     }
 
+    let mut tokens_to_tx =
+        do_activate_trigger(&pool, &mut txn, trigger.id, &catchup_datetimes).await?;
+
     if trigger.end_datetime.is_none() || next < trigger.end_datetime.unwrap() {
         // push one trigger in the future
         trace!(trigger_id=?trigger.id, "queueing trigger at {}", next);
         queue.push(trigger.at(next));
     }
 
-    txn.commit().await?;
-
     match trigger.catchup {
         Catchup::None => assert_eq!(
             tokens_to_tx.len(),
@@ -337,23 +528,13 @@ async fn catchup_trigger(
         Catchup::Random => tokens_to_tx.shuffle(&mut thread_rng()),
     }
 
-    send_to_token_processor(server, tokens_to_tx, TaskPriority::BackFill).await?;
+    // the outbox keeps the order the tokens are added in
+    let priority = settings::current(&server.config).catchup_priority;
+    outbox::add(&mut txn, &tokens_to_tx, priority).await?;
 
-    Ok(())
-}
+    txn.commit().await?;
 
-async fn send_to_token_processor(
-    server: &Server,
-    tokens_to_tx: Vec<Token>,
-    priority: TaskPriority,
-) -> Result<()> {
-    let mut token_tx = server.post_office.post_mail::<ProcessToken>().await?;
-
-    for token in tokens_to_tx {
-        token_tx
-            .send(ProcessToken::Increment(token, priority))
-            .await?;
-    }
+    outbox::notify(server).await?;
 
     Ok(())
 }
@@ -399,7 +580,7 @@ async fn update_one_trigger(server: &Server, uuid: Uuid, queue: &mut Queue) -> R
 
     remove_trigger(uuid, queue);
 
-    // get the trigger's new info from the DB
+    // get the trigger's new info from the DB, with any active override applied
     let maybe_trigger: Option<Trigger> = sqlx::query_as(
         "SELECT
             t.id AS id,
@@ -409,12 +590,15 @@ async fn update_one_trigger(server: &Server, uuid: Uuid, queue: &mut Queue) -> R
             latest_trigger_datetime,
             period,
             cron,
-            trigger_offset,
-            catchup
+            COALESCE(t.trigger_offset, 0) + COALESCE(o.shift, 0) AS trigger_offset,
+            COALESCE(o.catchup, t.catchup) AS catchup
         FROM trigger t
         JOIN job j ON t.job_id = j.id
+        LEFT JOIN trigger_override o ON o.trigger_id = t.id
+            AND o.expires_datetime > CURRENT_TIMESTAMP
         WHERE t.id = $1
         AND NOT j.paused
+        AND NOT COALESCE(o.paused, FALSE)
     ",
     )
     .bind(uuid)
@@ -425,7 +609,7 @@ async fn update_one_trigger(server: &Server, uuid: Uuid, queue: &mut Queue) -> R
         catchup_trigger(server, &trigger, queue).await?;
     } else {
         debug!(trigger_id=?uuid,
-            "trigger has been paused, it has been removed from the queue"
+            "trigger has been paused or overridden, it has been removed from the queue"
         );
     }
 
@@ -437,20 +621,22 @@ async fn requeue_next_triggertime(
     next_triggertime: &TriggerTime,
     queue: &mut Queue,
 ) -> Result<()> {
-    // get the trigger's info from the DB
+    // get the trigger's info from the DB, with any active override applied
     let trigger: Trigger = sqlx::query_as(
         "SELECT
-            id,
+            t.id AS id,
             start_datetime,
             end_datetime,
             earliest_trigger_datetime,
             latest_trigger_datetime,
             period,
             cron,
-            trigger_offset,
-            catchup
-        FROM trigger
-        WHERE id = $1
+            COALESCE(t.trigger_offset, 0) + COALESCE(o.shift, 0) AS trigger_offset,
+            COALESCE(o.catchup, t.catchup) AS catchup
+        FROM trigger t
+        LEFT JOIN trigger_override o ON o.trigger_id = t.id
+            AND o.expires_datetime > CURRENT_TIMESTAMP
+        WHERE t.id = $1
     ",
     )
     .bind(next_triggertime.trigger_id)
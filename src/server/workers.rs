@@ -0,0 +1,101 @@
+use crate::{
+    messages::LiveUpdate,
+    server::{live_updates, Server},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{Postgres, Transaction};
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// how often to look for workers that have stopped sending heartbeats
+const GONE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Delete workers, keeping their task runs but forgetting which worker ran them
+pub async fn delete_workers(
+    txn: &mut Transaction<'_, Postgres>,
+    worker_ids: &[Uuid],
+) -> sqlx::Result<u64> {
+    sqlx::query(
+        "UPDATE task_run
+        SET worker_id = NULL
+        WHERE worker_id = ANY($1)",
+    )
+    .bind(worker_ids)
+    .execute(&mut *txn)
+    .await?;
+
+    let deleted = sqlx::query("DELETE FROM worker WHERE id = ANY($1)")
+        .bind(worker_ids)
+        .execute(&mut *txn)
+        .await?
+        .rows_affected();
+
+    Ok(deleted)
+}
+
+/// delete workers that haven't sent a heartbeat for `worker_expiry`
+async fn expire_workers(server: &Server) -> Result<()> {
+    let mut txn = server.db_pool.begin().await?;
+
+    let expired: Vec<(Uuid,)> = sqlx::query_as(
+        "SELECT id
+        FROM worker
+        WHERE last_seen_datetime < CURRENT_TIMESTAMP - make_interval(secs => $1)
+        FOR UPDATE SKIP LOCKED",
+    )
+    .bind(server.config.worker_expiry as f64)
+    .fetch_all(&mut txn)
+    .await?;
+
+    if !expired.is_empty() {
+        let expired: Vec<Uuid> = expired.into_iter().map(|(id,)| id).collect();
+        let deleted = delete_workers(&mut txn, &expired).await?;
+        info!(deleted, "deleted expired workers");
+    }
+
+    txn.commit().await?;
+
+    Ok(())
+}
+
+/// Periodically mark workers that haven't been seen for as long as the workers
+/// API takes to call them `gone`, and tell anyone watching. Each worker is only
+/// marked once, by whichever scheduler gets to it first, until it comes back.
+/// Workers gone for longer than `worker_expiry` are deleted.
+pub async fn process_gone_workers(server: Arc<Server>) -> Result<!> {
+    loop {
+        tokio::time::sleep(GONE_CHECK_INTERVAL).await;
+
+        debug!("checking for gone workers");
+
+        let gone: Vec<(Uuid, DateTime<Utc>)> = sqlx::query_as(
+            "UPDATE worker
+            SET gone_datetime = CURRENT_TIMESTAMP
+            WHERE gone_datetime IS NULL
+            AND retired_datetime IS NULL
+            AND CURRENT_TIMESTAMP - last_seen_datetime > INTERVAL '15 minutes'
+            AND CURRENT_TIMESTAMP - last_seen_datetime < INTERVAL '24 hours'
+            RETURNING id, last_seen_datetime",
+        )
+        .fetch_all(&server.db_pool)
+        .await?;
+
+        for (worker_id, last_seen_datetime) in gone {
+            warn!(?worker_id, last_seen_datetime=?last_seen_datetime.to_rfc3339(),
+                "worker has stopped sending heartbeats");
+
+            live_updates::send(
+                server.broker.as_ref(),
+                &LiveUpdate::WorkerDied {
+                    worker_id,
+                    last_seen_datetime,
+                },
+            )
+            .await;
+        }
+
+        expire_workers(&server).await?;
+    }
+}
@@ -0,0 +1,133 @@
+//! The contract between the worker and the tasks it runs, so tasks in any
+//! language can report back without calling the server. Tasks are given the
+//! usual `WATERWHEEL_*` variables, plus the path of a result file they may
+//! write their result kind, outputs and artifacts to, either directly as JSON
+//! or with the `waterwheel-task` helper.
+
+use crate::messages::TokenState;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+
+/// the variable holding the path of the task's result file
+pub const RESULT_FILE_ENV: &str = "WATERWHEEL_RESULT_FILE";
+
+/// the variable holding the path of the task's working directory, when it has
+/// staged inputs or outputs
+pub const WORK_DIR_ENV: &str = "WATERWHEEL_WORK_DIR";
+
+/// the variable holding the path of the helper, when it's been injected
+pub const HELPER_ENV: &str = "WATERWHEEL_TASK_HELPER";
+
+/// where the result file is mounted in a container
+pub const RESULT_DIR: &str = "/waterwheel/out";
+pub const RESULT_FILE_NAME: &str = "result.json";
+
+/// where the helper is mounted in a container
+pub const HELPER_DIR: &str = "/waterwheel/bin";
+pub const HELPER_NAME: &str = "waterwheel-task";
+
+/// where the working directory is mounted in a container
+pub const WORK_DIR: &str = "/waterwheel/work";
+
+/// where the result file is mounted in a Windows container
+pub const WINDOWS_RESULT_DIR: &str = r"C:\waterwheel\out";
+pub const WINDOWS_WORK_DIR: &str = r"C:\waterwheel\work";
+
+/// result files bigger than this are rejected, they are stored with the task run
+pub const MAX_RESULT_FILE_BYTES: usize = 64 * 1024;
+
+/// the results a task can choose for itself
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultKind {
+    Success,
+    Failure,
+    Error,
+}
+
+impl From<ResultKind> for TokenState {
+    fn from(kind: ResultKind) -> Self {
+        match kind {
+            ResultKind::Success => TokenState::Success,
+            ResultKind::Failure => TokenState::Failure,
+            ResultKind::Error => TokenState::Error,
+        }
+    }
+}
+
+/// something the task produced and stored elsewhere, eg. a file in S3
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Artifact {
+    pub name: String,
+    pub uri: String,
+}
+
+/// What a task writes to its result file. Every field is optional, a task that
+/// doesn't write the file gets its result from its exit code as usual.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ResultFile {
+    /// overrides the result from the exit code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<ResultKind>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub outputs: BTreeMap<String, JsonValue>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<Artifact>,
+}
+
+impl ResultFile {
+    /// Parse a result file. An empty file is the same as no file.
+    pub fn parse(data: &[u8]) -> Result<Option<ResultFile>> {
+        if data.len() > MAX_RESULT_FILE_BYTES {
+            anyhow::bail!(
+                "result file is {} bytes, the most allowed is {MAX_RESULT_FILE_BYTES}",
+                data.len()
+            );
+        }
+        if data.iter().all(u8::is_ascii_whitespace) {
+            return Ok(None);
+        }
+
+        let file = serde_json::from_slice(data)
+            .map_err(|err| anyhow::Error::msg(format!("invalid result file: {err}")))?;
+        Ok(Some(file))
+    }
+
+    /// the outputs and artifacts to store with the task run, if there are any
+    pub fn outputs_json(&self) -> Option<JsonValue> {
+        if self.outputs.is_empty() && self.artifacts.is_empty() {
+            return None;
+        }
+        serde_json::to_value(ResultFile {
+            result: None,
+            ..self.clone()
+        })
+        .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(ResultFile::parse(b"").unwrap(), None);
+        assert_eq!(ResultFile::parse(b" \n").unwrap(), None);
+
+        let file = ResultFile::parse(br#"{"result": "failure", "outputs": {"rows": 12}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.result, Some(ResultKind::Failure));
+        assert_eq!(file.outputs["rows"], serde_json::json!(12));
+        assert_eq!(
+            file.outputs_json(),
+            Some(serde_json::json!({"outputs": {"rows": 12}}))
+        );
+
+        assert!(ResultFile::parse(br#"{"result": "timeout"}"#).is_err());
+        assert!(ResultFile::parse(&vec![b' '; MAX_RESULT_FILE_BYTES + 1]).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+-- trigram indexes for searching names, see server/api/search.rs
+-- run separately from schema.sql, as creating the extension needs privileges
+-- the database user may not have
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS project_name_trgm
+    ON project USING GIN (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS job_name_trgm
+    ON job USING GIN (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS task_name_trgm
+    ON task USING GIN (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS global_stash_name_trgm
+    ON global_stash USING GIN (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS project_stash_name_trgm
+    ON project_stash USING GIN (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS job_stash_name_trgm
+    ON job_stash USING GIN (name gin_trgm_ops);
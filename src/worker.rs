@@ -1,82 +1,193 @@
 use anyhow::Result;
 use cadence::StatsdClient;
-use lapin::Connection;
+use highnoon::{Request, StatusCode};
 use lru_time_cache::LruCache;
 use once_cell::sync::Lazy;
 use serde_json::Value as JsonValue;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::info;
+use std::{
+    collections::BTreeMap,
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{watch, Mutex};
+use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::{
-    amqp::amqp_connect,
+    broker::{self, Broker},
     config::Config,
     counter::Counter,
-    messages::TaskDef,
+    log_store::{self, LogArchive, LogWriter},
+    messages::{TaskDef, TaskPriority},
     metrics,
     server::api::{jwt, jwt::JwtKeys},
     util::{spawn_or_crash, spawn_retry},
+    worker::{docker::ContainerRuntime, engine::TaskEngine},
 };
 
 mod config_cache;
+pub mod control;
 mod docker;
+mod ecs;
 pub mod engine;
 pub mod env;
+pub mod executor;
 pub mod heartbeat;
 mod kube;
 mod kubejob;
+mod lambda;
+mod logs;
+mod process;
+mod reaper;
+mod registry;
+mod secrets;
+pub mod shutdown;
+mod staging;
+mod wasm;
 pub mod work;
 
+/// state for the worker's own HTTP endpoints
+#[derive(Clone)]
+struct WorkerApp(Arc<Worker>);
+
+impl highnoon::State for WorkerApp {
+    type Context = ();
+    fn new_context(&self) -> Self::Context {}
+}
+
 // TODO - move these statics
 static WORKER_ID: Lazy<Uuid> = Lazy::new(Uuid::new_v4);
 
 pub static RUNNING_TASKS: Counter = Counter::new();
 pub static TOTAL_TASKS: Counter = Counter::new();
 
+/// the parts of the config that can be changed without restarting the worker
+#[derive(Clone)]
+pub struct LiveConfig {
+    pub max_tasks: u32,
+    pub worker_tags: Vec<String>,
+    /// slots kept free for tasks of a priority or higher
+    pub reserved_slots: BTreeMap<TaskPriority, u32>,
+    pub docker_registry_username: Option<String>,
+    pub docker_registry_password: Option<String>,
+    /// set once the worker is told to drain, and kept across reloads
+    pub draining: bool,
+    /// set while the worker is in maintenance mode, and kept across reloads
+    pub maintenance: bool,
+}
+
+impl From<&Config> for LiveConfig {
+    fn from(config: &Config) -> Self {
+        LiveConfig {
+            max_tasks: config.max_tasks,
+            worker_tags: config.worker_tags.clone(),
+            reserved_slots: config.reserved_slots.clone(),
+            docker_registry_username: config.docker_registry_username.clone(),
+            docker_registry_password: config.docker_registry_password.clone(),
+            draining: false,
+            maintenance: false,
+        }
+    }
+}
+
 pub struct Worker {
-    pub amqp_conn: Connection,
+    pub broker: Arc<dyn Broker>,
     pub redis_client: redis::Client,
     //pub post_office: PostOffice,
     pub statsd: Arc<StatsdClient>,
     pub config: Config,
+    /// where the worker's HTTP server listens, with a port of 0 resolved
+    pub addr: SocketAddr,
     pub proj_config_cache: Mutex<LruCache<Uuid, JsonValue>>,
     pub task_def_cache: Mutex<LruCache<Uuid, Option<TaskDef>>>,
     pub jwt_keys: JwtKeys,
+    /// for the server's internal endpoints
+    pub internal_client: reqwest::Client,
+    /// where task logs are kept, if they are
+    pub log_writer: Option<Arc<dyn LogWriter>>,
+    /// where each task's whole output is uploaded when it finishes, if it is
+    pub log_archive: Option<LogArchive>,
+    pub live_config: watch::Sender<LiveConfig>,
+    pub slots: work::Slots,
+    work_loops: AtomicU32,
 }
 
 impl Worker {
     pub async fn new(config: Config) -> Result<Self> {
-        let amqp_conn = amqp_connect(&config).await?;
+        let broker = broker::connect(&config).await?;
         let statsd = metrics::new_client(&config)?;
         let redis_client = redis::Client::open(config.redis_url.as_ref())?;
+        // bound up front so the port is known before the first heartbeat
+        let addr = TcpListener::bind(&config.worker_bind)?.local_addr()?;
 
         let jwt_keys = jwt::load_keys(&config)?;
+        let (live_config, _) = watch::channel(LiveConfig::from(&config));
+        // built once, since it reads the TLS files
+        let internal_client = config.internal_client()?.build()?;
+        let log_writer = log_store::writer(&config, &jwt_keys, &internal_client).await?;
+        let log_archive = LogArchive::new(&config).await?;
+        let cache_ttl = Duration::from_secs(config.worker_config_cache_ttl);
 
         Ok(Worker {
-            amqp_conn,
+            broker,
             redis_client,
             statsd,
             config,
+            addr,
             proj_config_cache: Mutex::new(LruCache::with_expiry_duration_and_capacity(
-                chrono::Duration::hours(24).to_std().unwrap(),
-                100,
-            )),
-            task_def_cache: Mutex::new(LruCache::with_expiry_duration_and_capacity(
-                chrono::Duration::hours(24).to_std().unwrap(),
-                100,
+                cache_ttl, 100,
             )),
+            task_def_cache: Mutex::new(LruCache::with_expiry_duration_and_capacity(cache_ttl, 100)),
             jwt_keys,
+            internal_client,
+            log_writer,
+            log_archive,
+            live_config,
+            slots: work::Slots::default(),
+            work_loops: AtomicU32::new(0),
         })
     }
 
+    /// start enough work loops to run `max_tasks` tasks in parallel.
+    /// Loops are never stopped, when `max_tasks` is reduced the extra loops stop
+    /// taking new tasks until it's increased again.
+    pub fn spawn_work_loops(self: &Arc<Self>) {
+        let max_tasks = self.live_config.borrow().max_tasks;
+
+        while self.work_loops.load(Ordering::SeqCst) < max_tasks {
+            let i = self.work_loops.fetch_add(1, Ordering::SeqCst);
+            spawn_retry(&format!("worker-{i}"), self.clone(), work::process_work);
+        }
+    }
+
     pub async fn run_worker(self) -> Result<!> {
-        heartbeat::wait_for_server(&self.config).await;
+        heartbeat::wait_for_server(&self).await?;
 
         let this = Arc::new(self);
 
-        for i in 0..this.config.max_tasks {
-            spawn_retry(&format!("worker-{i}"), this.clone(), work::process_work);
+        this.spawn_work_loops();
+
+        if !this.config.worker_prepull_images.is_empty() {
+            let runtime = match this.config.task_engine {
+                TaskEngine::Docker => Some(ContainerRuntime::Docker),
+                TaskEngine::Podman => Some(ContainerRuntime::Podman),
+                _ => None,
+            };
+
+            match runtime {
+                Some(runtime) => {
+                    tokio::spawn(docker::prepull_images(this.clone(), runtime));
+                }
+                None => warn!("only the docker and podman engines pre-pull images"),
+            }
+        }
+
+        if matches!(this.config.task_engine, TaskEngine::Kubernetes) {
+            spawn_or_crash("reaper", this.clone(), reaper::reap_orphaned_jobs);
         }
 
         spawn_or_crash(
@@ -85,6 +196,9 @@ impl Worker {
             config_cache::process_updates,
         );
         spawn_or_crash("heartbeat", this.clone(), heartbeat::heartbeat);
+        spawn_or_crash("shutdown", this.clone(), shutdown::watch_for_shutdown);
+        spawn_or_crash("reload_signal", this.clone(), control::watch_for_reload);
+        spawn_or_crash("control", this.clone(), control::process_control);
 
         info!("worker id {}", *WORKER_ID);
 
@@ -94,15 +208,25 @@ impl Worker {
     }
 
     async fn serve(self: Arc<Self>) -> Result<()> {
-        let mut app = highnoon::App::new(());
+        let mut app = highnoon::App::new(WorkerApp(self.clone()));
         app.at("/")
             .get(|_req| async { Ok("Hello from Waterwheel Worker!") });
 
         // healthcheck to see if the worker is up
         app.at("/healthcheck").get(|_req| async { Ok("OK") });
 
-        let host = &self.config.worker_bind;
-        app.listen(host).await?;
+        // maintenance mode, for debugging the host without removing the worker from the fleet
+        app.at("/maintenance")
+            .put(|req: Request<WorkerApp>| async move {
+                control::set_maintenance(&req.state().0, true);
+                Ok(StatusCode::NO_CONTENT)
+            })
+            .delete(|req: Request<WorkerApp>| async move {
+                control::set_maintenance(&req.state().0, false);
+                Ok(StatusCode::NO_CONTENT)
+            });
+
+        app.listen(self.addr).await?;
 
         Ok(())
     }
@@ -1,26 +1,17 @@
 use crate::{
+    broker::Topic,
     messages::{ConfigUpdate, TaskDef},
-    server::api::{jwt, jwt::JwtKeys},
+    server::api::jwt,
     worker::Worker,
 };
 use anyhow::Result;
 use futures::TryStreamExt;
 use highnoon::StatusCode;
-use lapin::{
-    options::{
-        BasicAckOptions, BasicConsumeOptions, ExchangeDeclareOptions, QueueBindOptions,
-        QueueDeclareOptions,
-    },
-    types::FieldTable,
-    ExchangeKind,
-};
 use serde_json::Value as JsonValue;
 use std::{sync::Arc, time::Duration};
-use tracing::{error, trace, warn};
+use tracing::{error, info, trace, warn};
 use uuid::Uuid;
 
-const CONFIG_EXCHANGE: &str = "waterwheel.config";
-
 pub async fn get_project_config(worker: &Worker, proj_id: Uuid) -> Result<JsonValue> {
     let mut cache = worker.proj_config_cache.lock().await;
     let maybe_proj_config = cache.get(&proj_id);
@@ -28,8 +19,7 @@ pub async fn get_project_config(worker: &Worker, proj_id: Uuid) -> Result<JsonVa
     if let Some(proj_config) = maybe_proj_config {
         Ok(proj_config.clone())
     } else {
-        let proj_config =
-            fetch_project_config(&worker.jwt_keys, &worker.config.server_addr, proj_id).await?;
+        let proj_config = fetch_project_config(worker, proj_id).await?;
         cache.insert(proj_id, proj_config.clone());
         Ok(proj_config)
     }
@@ -44,33 +34,26 @@ pub async fn get_task_def(worker: &Worker, task_id: Uuid) -> Result<Option<TaskD
         Ok(def.clone())
     } else {
         trace!("task def cache miss");
-        let maybe_def =
-            fetch_task_def(&worker.jwt_keys, &worker.config.server_addr, task_id).await?;
+        let maybe_def = fetch_task_def(worker, task_id).await?;
         cache.insert(task_id, maybe_def.clone());
         Ok(maybe_def)
     }
 }
 
-async fn fetch_project_config(
-    keys: &JwtKeys,
-    server_addr: &str,
-    proj_id: Uuid,
-) -> Result<JsonValue> {
-    let token = "Bearer ".to_owned() + &jwt::generate_config_jwt(keys, proj_id)?;
+async fn fetch_project_config(worker: &Worker, proj_id: Uuid) -> Result<JsonValue> {
+    let token = "Bearer ".to_owned() + &jwt::generate_config_jwt(&worker.jwt_keys, proj_id)?;
 
-    let url = reqwest::Url::parse(server_addr)?
+    let url = reqwest::Url::parse(worker.config.internal_addr())?
         .join("int-api/projects/")?
         .join(&format!("{proj_id}/"))?
         .join("config")?;
 
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(10))
-        .build()?;
-
     trace!(?proj_id, "fetching project config from api");
 
-    let resp = client
+    let resp = worker
+        .internal_client
         .get(url.clone())
+        .timeout(Duration::from_secs(10))
         .header(reqwest::header::AUTHORIZATION, token)
         .send()
         .await?
@@ -82,25 +65,19 @@ async fn fetch_project_config(
     Ok(config)
 }
 
-async fn fetch_task_def(
-    keys: &JwtKeys,
-    server_addr: &str,
-    task_id: Uuid,
-) -> Result<Option<TaskDef>> {
-    let token = "Bearer ".to_owned() + &jwt::generate_config_jwt(keys, task_id)?;
+async fn fetch_task_def(worker: &Worker, task_id: Uuid) -> Result<Option<TaskDef>> {
+    let token = "Bearer ".to_owned() + &jwt::generate_config_jwt(&worker.jwt_keys, task_id)?;
 
-    let url = reqwest::Url::parse(server_addr)?
+    let url = reqwest::Url::parse(worker.config.internal_addr())?
         .join("int-api/tasks/")?
         .join(&format!("{task_id}"))?;
 
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(10))
-        .build()?;
-
     trace!(?task_id, "fetching task def from api");
 
-    let res = client
+    let res = worker
+        .internal_client
         .get(url.clone())
+        .timeout(Duration::from_secs(10))
         .header(reqwest::header::AUTHORIZATION, token)
         .send()
         .await;
@@ -135,64 +112,19 @@ async fn fetch_task_def(
 }
 
 pub async fn process_updates(worker: Arc<Worker>) -> Result<!> {
-    let chan = worker.amqp_conn.create_channel().await?;
-
-    // declare exchange for config updates
-    chan.exchange_declare(
-        CONFIG_EXCHANGE,
-        ExchangeKind::Fanout,
-        ExchangeDeclareOptions {
-            durable: true,
-            ..ExchangeDeclareOptions::default()
-        },
-        FieldTable::default(),
-    )
-    .await?;
-
-    // declare queue for consuming incoming messages
-    let queue = chan
-        .queue_declare(
-            "", // auto generate name on server side
-            QueueDeclareOptions {
-                durable: true,
-                exclusive: true, // implies auto delete too
-                ..QueueDeclareOptions::default()
-            },
-            FieldTable::default(),
-        )
-        .await?;
-
-    // bind queue to the exchange
-    chan.queue_bind(
-        queue.name().as_str(),
-        CONFIG_EXCHANGE,
-        "",
-        QueueBindOptions::default(),
-        FieldTable::default(),
-    )
-    .await?;
-
-    let mut consumer = chan
-        .basic_consume(
-            queue.name().as_str(),
-            "worker",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
-
-    while let Some(delivery) = consumer.try_next().await? {
-        let update: ConfigUpdate = serde_json::from_slice(&delivery.data)?;
+    let mut updates = worker.broker.subscribe(Topic::Config).await?;
+
+    while let Some(data) = updates.try_next().await? {
+        let update: ConfigUpdate = serde_json::from_slice(&data)?;
 
         trace!("received config update message: {:?}", update);
 
         match update {
             ConfigUpdate::Project(proj_id) => drop_project_config(&worker, proj_id).await,
             ConfigUpdate::TaskDef(task_id) => drop_task_def(&worker, task_id).await,
+            ConfigUpdate::Flush => flush(&worker).await,
         };
 
-        delivery.ack(BasicAckOptions::default()).await?;
-
         trace!("updated config");
     }
 
@@ -208,3 +140,9 @@ pub async fn drop_task_def(worker: &Worker, task_id: Uuid) {
     let mut cache = worker.task_def_cache.lock().await;
     cache.remove(&task_id);
 }
+
+pub async fn flush(worker: &Worker) {
+    worker.proj_config_cache.lock().await.clear();
+    worker.task_def_cache.lock().await.clear();
+    info!("flushed config cache");
+}
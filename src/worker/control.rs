@@ -0,0 +1,270 @@
+use crate::{
+    broker::Topic,
+    config, logging,
+    messages::{WorkerCommand, WorkerControl},
+    worker::{heartbeat, LiveConfig, Worker, RUNNING_TASKS, WORKER_ID},
+};
+use anyhow::Result;
+use futures::{future, Future, TryStreamExt};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing::{info, trace, warn};
+use uuid::Uuid;
+
+/// the task runs in progress on this worker, which can be killed
+static KILL_SWITCHES: Lazy<Mutex<HashMap<Uuid, watch::Sender<Option<KillReason>>>>> =
+    Lazy::new(Mutex::default);
+
+/// why a task run was killed, which decides the result it's reported with
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KillReason {
+    /// killed through the API, it's reported cancelled
+    Api,
+    /// still running when the worker's shutdown grace period ran out, it's
+    /// reported preempted so it's retried elsewhere
+    Shutdown,
+}
+
+/// Lets a task run be killed while it's in progress. It stops being killable
+/// when this is dropped.
+pub struct KillSwitch {
+    task_run_id: Uuid,
+    killed: watch::Receiver<Option<KillReason>>,
+}
+
+impl KillSwitch {
+    pub fn register(task_run_id: Uuid) -> Self {
+        let (tx, killed) = watch::channel(None);
+        KILL_SWITCHES
+            .lock()
+            .expect("kill switches lock poisoned")
+            .insert(task_run_id, tx);
+
+        KillSwitch {
+            task_run_id,
+            killed,
+        }
+    }
+
+    /// resolves once the task run has been killed
+    pub async fn killed(&self) -> KillReason {
+        let mut killed = self.killed.clone();
+        loop {
+            if let Some(reason) = *killed.borrow() {
+                return reason;
+            }
+            // the sender is only dropped with this, so it never stops waiting early
+            if killed.changed().await.is_err() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl Drop for KillSwitch {
+    fn drop(&mut self) {
+        KILL_SWITCHES
+            .lock()
+            .expect("kill switches lock poisoned")
+            .remove(&self.task_run_id);
+    }
+}
+
+/// Resolves to true if the task run is killed, or false once it's finished (or
+/// if it isn't running here). Engines spawn this to clean up after themselves,
+/// since the worker stops waiting on a killed task straight away.
+pub fn on_kill(task_run_id: Uuid) -> impl Future<Output = bool> + Send + 'static {
+    let killed = KILL_SWITCHES
+        .lock()
+        .expect("kill switches lock poisoned")
+        .get(&task_run_id)
+        .map(watch::Sender::subscribe);
+
+    async move {
+        let mut killed = match killed {
+            Some(killed) => killed,
+            None => return false,
+        };
+
+        while killed.borrow().is_none() {
+            if killed.changed().await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// flag a task run as killed, if it's running on this worker
+fn kill(task_run_id: Uuid) {
+    let switches = KILL_SWITCHES.lock().expect("kill switches lock poisoned");
+
+    match switches.get(&task_run_id) {
+        Some(tx) => {
+            info!(?task_run_id, "killing task");
+            tx.send_replace(Some(KillReason::Api));
+        }
+        None => warn!(?task_run_id, "can't kill a task that isn't running here"),
+    }
+}
+
+/// kill every task run on this worker, when it's shutting down
+pub fn kill_all() {
+    let switches = KILL_SWITCHES.lock().expect("kill switches lock poisoned");
+
+    for (task_run_id, tx) in switches.iter() {
+        warn!(?task_run_id, "stopping task for shutdown");
+        tx.send_replace(Some(KillReason::Shutdown));
+    }
+}
+
+/// reload the config whenever the worker receives SIGHUP
+#[cfg(unix)]
+pub async fn watch_for_reload(worker: Arc<Worker>) -> Result<!> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    loop {
+        sighup.recv().await;
+        info!("received SIGHUP");
+        reload(&worker);
+    }
+}
+
+/// there's no SIGHUP on Windows, workers there are only reloaded by a control message
+#[cfg(windows)]
+pub async fn watch_for_reload(_worker: Arc<Worker>) -> Result<!> {
+    future::pending().await
+}
+
+/// consume control messages sent to all workers (or this worker) from the API
+pub async fn process_control(worker: Arc<Worker>) -> Result<!> {
+    let mut controls = worker.broker.subscribe(Topic::WorkerControl).await?;
+
+    while let Some(data) = controls.try_next().await? {
+        // one bad message mustn't take down every worker
+        let control: WorkerControl = match serde_json::from_slice(&data) {
+            Ok(control) => control,
+            Err(err) => {
+                warn!("ignoring malformed control message: {}", err);
+                continue;
+            }
+        };
+
+        if control.worker_id.map_or(false, |id| id != *WORKER_ID) {
+            trace!("ignoring control message for another worker");
+            continue;
+        }
+
+        info!("received control message: {:?}", control.command);
+
+        match control.command {
+            WorkerCommand::Reload => reload(&worker),
+            WorkerCommand::Drain => drain(&worker),
+            WorkerCommand::Maintenance { enabled } => set_maintenance(&worker, enabled),
+            WorkerCommand::Kill { task_run_id } => kill(task_run_id),
+        }
+    }
+
+    unreachable!("consumer stopped consuming")
+}
+
+/// Re-read the config and apply the settings that can change without a restart.
+/// Running tasks are not affected, errors are logged and the old config is kept.
+pub fn reload(worker: &Arc<Worker>) {
+    // Pick up any changes to the .env file too. `dotenv()` never replaces a
+    // variable that's already set, which includes everything it set at startup.
+    if let Ok(vars) = dotenv::dotenv_iter() {
+        for (key, value) in vars.flatten() {
+            std::env::set_var(key, value);
+        }
+    }
+
+    let new_config = match config::load(
+        worker.config.config_file.as_deref(),
+        worker.config.profile.as_deref(),
+    ) {
+        Ok(config) => config,
+        Err(err) => {
+            warn!("failed to reload config, keeping the current config: {:#}", err);
+            return;
+        }
+    };
+
+    if let Err(err) = logging::set_filter(&new_config.log) {
+        warn!("failed to update the log filter: {:#}", err);
+    }
+
+    let mut live = LiveConfig::from(&new_config);
+    live.draining = worker.live_config.borrow().draining;
+    live.maintenance = worker.live_config.borrow().maintenance;
+    info!(
+        max_tasks = live.max_tasks,
+        worker_tags = ?live.worker_tags,
+        "reloaded config"
+    );
+    worker.live_config.send_replace(live);
+
+    // concurrency may have been increased
+    worker.spawn_work_loops();
+}
+
+/// Stop taking new tasks, and once the running ones have finished tell the
+/// server this worker is retired and exit.
+pub fn drain(worker: &Arc<Worker>) {
+    let started = worker
+        .live_config
+        .send_if_modified(|live| !std::mem::replace(&mut live.draining, true));
+
+    if !started {
+        info!("already draining");
+        return;
+    }
+
+    info!(
+        running_tasks = RUNNING_TASKS.get(),
+        "draining, no new tasks will be taken"
+    );
+    tokio::spawn(retire_when_idle(worker.clone()));
+}
+
+/// In maintenance mode the worker keeps sending heartbeats but takes no new
+/// tasks, until it's taken out of maintenance mode. Running tasks carry on.
+pub fn set_maintenance(worker: &Worker, enabled: bool) {
+    let changed = worker
+        .live_config
+        .send_if_modified(|live| std::mem::replace(&mut live.maintenance, enabled) != enabled);
+
+    match (changed, enabled) {
+        (false, _) => info!(enabled, "maintenance mode unchanged"),
+        (true, true) => info!(
+            running_tasks = RUNNING_TASKS.get(),
+            "entered maintenance mode, no new tasks will be taken"
+        ),
+        (true, false) => info!("left maintenance mode, taking tasks again"),
+    }
+}
+
+async fn retire_when_idle(worker: Arc<Worker>) {
+    // check after sleeping, so a task received just before draining started
+    // has been counted
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if RUNNING_TASKS.get() == 0 {
+            break;
+        }
+    }
+
+    if let Err(err) = heartbeat::post_retired(&worker).await {
+        warn!("failed to tell the server it's retired: {:#}", err);
+    }
+
+    info!("worker drained, shutting down");
+    std::process::exit(0);
+}
@@ -1,21 +1,59 @@
 use crate::{
-    messages::{TaskDef, TaskRequest},
-    worker::{engine::TaskEngineImpl, env, Worker},
+    messages::{ContainerOs, ErrorClass, ImagePullPolicy, TaskDef, TaskRequest},
+    task_contract::{
+        ResultFile, HELPER_DIR, HELPER_ENV, HELPER_NAME, RESULT_DIR, RESULT_FILE_ENV,
+        RESULT_FILE_NAME, WINDOWS_RESULT_DIR, WINDOWS_WORK_DIR, WORK_DIR, WORK_DIR_ENV,
+    },
+    worker::{
+        config_cache::get_project_config,
+        control,
+        engine::{InfraError, TaskEngineImpl, TaskResult},
+        env,
+        logs::{LogShipper, LogStream},
+        registry::{credentials_for_image, image_pull_policy},
+        shutdown,
+        staging::{self, WorkDir},
+        Worker,
+    },
 };
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bollard::{
+    auth::DockerCredentials,
     container::{
-        Config, CreateContainerOptions, LogsOptions, RemoveContainerOptions, StartContainerOptions,
-        WaitContainerOptions,
+        Config, CreateContainerOptions, InspectContainerOptions, LogOutput, LogsOptions,
+        RemoveContainerOptions, StartContainerOptions, StopContainerOptions, WaitContainerOptions,
     },
     image::{CreateImageOptions, ListImagesOptions},
+    models::{DeviceRequest, HostConfig, ResourcesUlimits},
 };
+use chrono::{DateTime, Utc};
 use futures::TryStreamExt;
-use redis::{streams::StreamMaxlen, AsyncCommands};
-use std::collections::HashMap;
-use tracing::trace;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{info, trace, warn, Instrument};
+
+/// Runs tasks as containers, with any runtime that serves the Docker API
+pub struct DockerEngine(pub ContainerRuntime);
+
+/// The container runtimes the docker engine can use. They differ only in where
+/// their API socket is, since Podman serves a Docker compatible API.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ContainerRuntime {
+    Docker,
+    Podman,
+}
+
+/// seconds before a request to the runtime times out, the same as bollard's default
+const REQUEST_TIMEOUT_SECS: u64 = 120;
 
-pub struct DockerEngine;
+/// seconds between SIGTERM and SIGKILL when stopping a container at its deadline
+const STOP_GRACE_SECS: i64 = 10;
+
+/// exit codes of a container killed by SIGKILL or SIGTERM
+const KILLED_EXIT_CODES: &[i64] = &[137, 143];
 
 #[async_trait::async_trait]
 impl TaskEngineImpl for DockerEngine {
@@ -24,24 +62,121 @@ impl TaskEngineImpl for DockerEngine {
         worker: &Worker,
         task_req: TaskRequest,
         task_def: TaskDef,
-    ) -> Result<bool> {
-        run_docker(worker, task_req, task_def).await
+        deadline: DateTime<Utc>,
+    ) -> Result<TaskResult> {
+        match run_docker(worker, self.0, task_req, task_def, deadline).await {
+            Err(err) if shutdown::is_shutting_down() => {
+                // the docker daemon is probably going down with the host
+                warn!("docker error during shutdown: {:#}", err);
+                Ok(TaskResult::preempted(Some(format!("{err:#}"))))
+            }
+            result => result,
+        }
     }
 }
 
-async fn run_docker(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -> Result<bool> {
-    let docker = bollard::Docker::connect_with_local_defaults()?;
+/// Connect to the runtime's API. `WATERWHEEL_DOCKER_HOST` replaces the runtime's
+/// usual socket, otherwise Docker's is found the same way the docker CLI does.
+fn connect(config: &crate::config::Config, runtime: ContainerRuntime) -> Result<bollard::Docker> {
+    let host = match (&config.docker_host, runtime) {
+        (Some(host), _) => host.clone(),
+        (None, ContainerRuntime::Docker) => {
+            return Ok(bollard::Docker::connect_with_local_defaults()?)
+        }
+        (None, ContainerRuntime::Podman) => podman_socket(),
+    };
 
-    let env = env::get_env_string(worker, &task_req, &task_def)?;
+    trace!(?runtime, %host, "connecting to container runtime");
 
-    // task_def is partially move from here down
-    let image = task_def.image.unwrap();
-    let args = task_def.args;
+    let docker = if let Some(path) = host.strip_prefix("unix://") {
+        connect_unix(path)?
+    } else if let Some(path) = host.strip_prefix("npipe://") {
+        connect_named_pipe(path)?
+    } else {
+        bollard::Docker::connect_with_http(
+            &host,
+            REQUEST_TIMEOUT_SECS,
+            bollard::API_DEFAULT_VERSION,
+        )?
+    };
 
-    // ____________________________________________________
-    // search for the image locally
+    Ok(docker)
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> Result<bollard::Docker> {
+    Ok(bollard::Docker::connect_with_unix(
+        path,
+        REQUEST_TIMEOUT_SECS,
+        bollard::API_DEFAULT_VERSION,
+    )?)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(path: &str) -> Result<bollard::Docker> {
+    anyhow::bail!("can't connect to '{path}', unix sockets aren't supported on Windows")
+}
+
+/// Docker on Windows listens on a named pipe, eg. `npipe:////./pipe/docker_engine`
+#[cfg(windows)]
+fn connect_named_pipe(path: &str) -> Result<bollard::Docker> {
+    Ok(bollard::Docker::connect_with_named_pipe(
+        path,
+        REQUEST_TIMEOUT_SECS,
+        bollard::API_DEFAULT_VERSION,
+    )?)
+}
+
+#[cfg(not(windows))]
+fn connect_named_pipe(path: &str) -> Result<bollard::Docker> {
+    anyhow::bail!("can't connect to '{path}', named pipes are only supported on Windows")
+}
+
+/// rootless Podman listens in the user's runtime directory, otherwise in /run
+fn podman_socket() -> String {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if Path::new(&dir).join("podman/podman.sock").exists() => {
+            format!("unix://{dir}/podman/podman.sock")
+        }
+        _ => "unix:///run/podman/podman.sock".to_owned(),
+    }
+}
+
+/// Errors reaching the runtime are worth retrying, errors it returns about the
+/// task (eg. an invalid image name) aren't
+fn runtime_error(err: bollard::errors::Error) -> anyhow::Error {
+    use bollard::errors::Error::*;
+
+    let transient = match &err {
+        DockerResponseServerError { status_code, .. } => *status_code >= 500,
+        RequestTimeoutError | HyperResponseError { .. } | IOError { .. } => true,
+        _ => false,
+    };
+
+    if transient {
+        anyhow::Error::new(err).context(InfraError)
+    } else {
+        err.into()
+    }
+}
+
+/// the worker's own registry login, used when the project doesn't have one
+fn worker_credentials(worker: &Worker) -> Option<DockerCredentials> {
+    // registry credentials can be changed by reloading the config
+    let live = worker.live_config.borrow();
+    live.docker_registry_username
+        .as_ref()
+        .map(|username| DockerCredentials {
+            username: Some(username.clone()),
+            password: live.docker_registry_password.clone(),
+            ..DockerCredentials::default()
+        })
+}
+
+/// whether the image is already on the worker
+async fn image_present(docker: &bollard::Docker, image: &str) -> Result<bool> {
     let mut filters = HashMap::new();
-    filters.insert("reference", vec![&*image]);
+    filters.insert("reference", vec![image]);
 
     trace!(?filters, "listing images");
 
@@ -50,53 +185,295 @@ async fn run_docker(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -
             filters,
             ..ListImagesOptions::default()
         }))
-        .await?;
+        .await
+        .map_err(runtime_error)?;
 
     trace!("got {} images", list.len());
+    Ok(!list.is_empty())
+}
+
+async fn pull_image(
+    docker: &bollard::Docker,
+    image: &str,
+    credentials: Option<DockerCredentials>,
+) -> Result<()> {
+    let mut pull = docker.create_image(
+        Some(CreateImageOptions::<&str> {
+            from_image: image,
+            ..CreateImageOptions::default()
+        }),
+        None,
+        credentials,
+    );
+
+    while let Some(data) = pull.try_next().await.map_err(runtime_error)? {
+        trace!("pulling image: {}", serde_json::to_string(&data)?);
+    }
+
+    Ok(())
+}
+
+/// Pull `WATERWHEEL_WORKER_PREPULL_IMAGES` when the worker starts, so the first
+/// tasks using them don't wait for a pull. Images already there aren't pulled
+/// again, and failures are only logged since tasks will pull them anyway.
+pub async fn prepull_images(worker: Arc<Worker>, runtime: ContainerRuntime) {
+    let docker = match connect(&worker.config, runtime) {
+        Ok(docker) => docker,
+        Err(err) => {
+            warn!("not pre-pulling images: {:#}", err);
+            return;
+        }
+    };
+
+    for image in &worker.config.worker_prepull_images {
+        let pulled = async {
+            if !image_present(&docker, image).await? {
+                pull_image(&docker, image, worker_credentials(&worker)).await?;
+            }
+            anyhow::Ok(())
+        };
+
+        match pulled.await {
+            Ok(()) => info!(?image, "pre-pulled image"),
+            Err(err) => warn!(?image, "failed to pre-pull image: {:#}", err),
+        }
+    }
+}
+
+async fn run_docker(
+    worker: &Worker,
+    runtime: ContainerRuntime,
+    task_req: TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+) -> Result<TaskResult> {
+    let docker = connect(&worker.config, runtime)?;
+
+    let mut env = env::get_env_string(worker, &task_req, &task_def, deadline).await?;
+
+    // inputs are downloaded before anything else, so a missing one fails fast
+    let work_dir = match WorkDir::stage(worker, &task_req, &task_def).await {
+        Ok(work_dir) => work_dir,
+        Err(err) => {
+            warn!("failed to stage task inputs: {:#}", err);
+            return Ok(TaskResult::failed(ErrorClass::Staging, format!("{err:#}")));
+        }
+    };
+
+    // the task's result file is in a directory shared with the container
+    let result_dir = worker
+        .config
+        .task_result_dir
+        .as_ref()
+        .map_or_else(std::env::temp_dir, PathBuf::from)
+        .join(format!("waterwheel-{}", task_req.task_run_id));
+    tokio::fs::create_dir_all(&result_dir).await?;
+    staging::share_dir(&result_dir).await?;
+
+    // the runtime runs the worker's OS unless the task is routed to a Windows worker
+    let os = task_def
+        .docker_options
+        .as_ref()
+        .and_then(|options| options.os)
+        .unwrap_or(worker.config.worker_os);
+
+    let mut binds = match os {
+        ContainerOs::Linux => {
+            env.push(format!("{RESULT_FILE_ENV}={RESULT_DIR}/{RESULT_FILE_NAME}"));
+            vec![format!("{}:{RESULT_DIR}", result_dir.display())]
+        }
+        ContainerOs::Windows => {
+            env.push(format!(
+                "{RESULT_FILE_ENV}={WINDOWS_RESULT_DIR}\\{RESULT_FILE_NAME}"
+            ));
+            vec![format!("{}:{WINDOWS_RESULT_DIR}", result_dir.display())]
+        }
+    };
+
+    // the task runs in its working directory, unless it says otherwise
+    let mut working_dir = None;
+    if let Some(work_dir) = &work_dir {
+        let mount = match os {
+            ContainerOs::Linux => WORK_DIR,
+            ContainerOs::Windows => WINDOWS_WORK_DIR,
+        };
+        binds.push(format!("{}:{mount}", work_dir.path().display()));
+        env.push(format!("{WORK_DIR_ENV}={mount}"));
+        working_dir = Some(mount.to_owned());
+    }
+
+    // the helper is a Linux binary
+    if let Some(helper) = &worker.config.task_helper_path {
+        if os == ContainerOs::Linux {
+            binds.push(format!("{helper}:{HELPER_DIR}/{HELPER_NAME}:ro"));
+            env.push(format!("{HELPER_ENV}={HELPER_DIR}/{HELPER_NAME}"));
+        }
+    }
+
+    // the project's login for the image's registry is used before the worker's
+    let project_credentials = match &task_def.image {
+        Some(image) => credentials_for_image(worker, &task_req, &task_def, image).await?,
+        None => None,
+    };
+
+    let pull_policy = image_pull_policy(worker, &task_def)
+        .await?
+        .unwrap_or(ImagePullPolicy::IfNotPresent);
+
+    let project_config = get_project_config(worker, task_def.project_id).await?;
+
+    // task_def is partially move from here down
+    let image = task_def.image.unwrap();
+    let args = task_def.args;
+    let resources = task_def.resources.unwrap_or_default();
+    let options = task_def.docker_options.unwrap_or_default();
+    binds.extend(options.volumes);
 
     // ____________________________________________________
-    // pull the image if we didn't find it
-    if list.is_empty() {
-        let mut pull = docker.create_image(
-            Some(CreateImageOptions::<&str> {
-                from_image: &image,
-                ..CreateImageOptions::default()
+    // pull the image, unless the pull policy lets us use a local copy
+    let present = match pull_policy {
+        ImagePullPolicy::Always => false,
+        _ => image_present(&docker, &image).await?,
+    };
+
+    if !present {
+        if pull_policy == ImagePullPolicy::Never {
+            return Ok(TaskResult::failed(
+                ErrorClass::ImagePull,
+                format!("image '{image}' isn't on the worker and its pull policy is never"),
+            ));
+        }
+
+        let credentials = match project_credentials {
+            Some(project) => Some(DockerCredentials {
+                username: Some(project.username),
+                password: Some(project.password),
+                serveraddress: Some(project.server),
+                ..DockerCredentials::default()
             }),
-            None,
-            None,
-        );
+            None => worker_credentials(worker),
+        };
 
-        while let Some(data) = pull.try_next().await? {
-            trace!("pulling image: {}", serde_json::to_string(&data)?);
+        if let Err(err) = pull_image(&docker, &image, credentials).await {
+            if InfraError::is(&err) {
+                return Err(err);
+            }
+            warn!(?image, "failed to pull image: {:#}", err);
+            return Ok(TaskResult::failed(
+                ErrorClass::ImagePull,
+                format!("failed to pull image '{image}': {err:#}"),
+            ));
         }
     }
 
     // ____________________________________________________
     // launch the container
-    trace!(?image, ?args, ?env, "launching container");
+    trace!(?image, ?args, ?env, ?resources, "launching container");
+
+    let ulimits = resources
+        .ulimits
+        .into_iter()
+        .map(|(name, limit)| ResourcesUlimits {
+            name: Some(name),
+            soft: Some(limit),
+            hard: Some(limit),
+        })
+        .collect::<Vec<_>>();
+
+    // the same as `docker run --gpus <n>`
+    let device_requests = resources.gpus.map(|gpus| {
+        vec![DeviceRequest {
+            count: Some(gpus.into()),
+            capabilities: Some(vec![vec!["gpu".to_owned()]]),
+            ..DeviceRequest::default()
+        }]
+    });
+
+    let mut config = Config {
+        env: Some(env),
+        cmd: Some(args),
+        image: Some(image),
+        working_dir: options.working_dir.or(working_dir),
+        user: options.user,
+        host_config: Some(HostConfig {
+            binds: Some(binds),
+            network_mode: options.network_mode,
+            nano_cpus: resources.cpu.map(|cpu| (cpu * 1e9) as i64),
+            memory: resources.memory.map(|bytes| bytes as i64),
+            ulimits: (!ulimits.is_empty()).then_some(ulimits),
+            device_requests,
+            ..HostConfig::default()
+        }),
+        ..Config::default()
+    };
+
+    if let Some(json) = project_config.get("docker_merge") {
+        let mut config_json = serde_json::to_value(&config)?;
+        trace!("merging template: {:#} with patch: {:#}", config_json, json);
+        json_patch::merge(&mut config_json, json);
+        config = serde_json::from_value(config_json).context("invalid docker_merge")?;
+    }
 
     let container = docker
-        .create_container(
-            None::<CreateContainerOptions<String>>,
-            Config {
-                env: Some(env),
-                cmd: Some(args),
-                image: Some(image),
-                ..Config::default()
-            },
-        )
-        .await?;
+        .create_container(None::<CreateContainerOptions<String>>, config)
+        .await
+        .map_err(runtime_error)?;
 
     trace!(id=?container.id, "created container");
 
     // ____________________________________________________
     // start the container
-    docker
+    if let Err(err) = docker
         .start_container(&container.id, None::<StartContainerOptions<String>>)
-        .await?;
+        .await
+    {
+        // it would be left behind otherwise, a retry creates another
+        if let Err(err) = docker
+            .remove_container(&container.id, None::<RemoveContainerOptions>)
+            .await
+        {
+            warn!(id=?container.id, "failed to remove container: {}", err);
+        }
+        return Err(runtime_error(err));
+    }
 
     trace!(id=?container.id, "started container");
 
+    // stop the container at the deadline, or remove it if the task is killed. This
+    // keeps running even if the worker gives up waiting on the task (as it does
+    // straight away for a kill) so the container isn't left behind. It logs
+    // in the task's span, like the rest of the engine
+    let stop_timer = tokio::spawn({
+        let docker = docker.clone();
+        let id = container.id.clone();
+        let killed = control::on_kill(task_req.task_run_id);
+        async move {
+            let delay = (deadline - Utc::now()).to_std().unwrap_or_default();
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {
+                    warn!(?id, "task deadline reached, stopping container");
+                    if let Err(err) = docker
+                        .stop_container(&id, Some(StopContainerOptions { t: STOP_GRACE_SECS }))
+                        .await
+                    {
+                        warn!(?id, "failed to stop container: {}", err);
+                    }
+                }
+                true = killed => {
+                    warn!(?id, "task killed, removing container");
+                    let options = RemoveContainerOptions {
+                        force: true,
+                        ..RemoveContainerOptions::default()
+                    };
+                    if let Err(err) = docker.remove_container(&id, Some(options)).await {
+                        warn!(?id, "failed to remove container: {}", err);
+                    }
+                }
+            }
+        }
+        .in_current_span()
+    });
+
     // ____________________________________________________
     // streams the logs back
     let mut logs = docker.logs(
@@ -110,26 +487,19 @@ async fn run_docker(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -
         }),
     );
 
-    let key = format!("waterwheel-logs.{}", task_req.task_run_id);
-    let mut redis = worker.redis_client.get_tokio_connection().await?;
+    let mut shipper = LogShipper::new(worker, task_req).await?;
 
-    trace!("sending docker logs to {}", key);
+    trace!(task_run_id=?task_req.task_run_id, "sending docker logs");
     while let Some(line) = logs.try_next().await? {
-        let bytes = line.into_bytes();
-        trace!("got log line ({} bytes)", bytes.len());
-        redis
-            .xadd_maxlen(
-                &key,
-                StreamMaxlen::Approx(1024),
-                "*",
-                &[("data", bytes.as_ref())],
-            )
-            .await?;
-        trace!("sent to redis");
+        let stream = match line {
+            LogOutput::StdOut { .. } => LogStream::Stdout,
+            LogOutput::StdErr { .. } => LogStream::Stderr,
+            _ => LogStream::Output,
+        };
+        shipper.send(stream, &line.into_bytes()).await?;
     }
 
-    let _: redis::Value = redis.expire(&key, worker.config.log_retention.try_into()?).await?;
-    drop(redis);
+    shipper.finish().await?;
 
     // ____________________________________________________
     // wait for it to terminate
@@ -141,6 +511,21 @@ async fn run_docker(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -
         exit = x.status_code;
     }
 
+    stop_timer.abort();
+
+    // the exit code alone doesn't say if the task was killed for its memory use
+    let oom_killed = docker
+        .inspect_container(&container.id, None::<InspectContainerOptions>)
+        .await?
+        .state
+        .and_then(|state| state.oom_killed)
+        == Some(true);
+    let error_class = if oom_killed {
+        Some(ErrorClass::OomKilled)
+    } else {
+        None
+    };
+
     // ____________________________________________________
     // remove the container
     docker
@@ -149,5 +534,42 @@ async fn run_docker(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -
 
     trace!(id=?container.id, "container removed");
 
-    Ok(exit == 0)
+    let result_file = read_result_file(&result_dir).await;
+
+    if KILLED_EXIT_CODES.contains(&exit) && shutdown::is_shutting_down() {
+        warn!(id=?container.id, "container was killed while the host is shutting down");
+        return Ok(TaskResult::preempted(Some(format!(
+            "container killed during host shutdown (exit code {exit})"
+        ))));
+    }
+
+    // outputs are only uploaded for a task that succeeded
+    if let Some(work_dir) = work_dir.filter(|_| exit == 0) {
+        if let Err(err) = work_dir.upload_outputs().await {
+            warn!("failed to upload task outputs: {:#}", err);
+            return Ok(
+                TaskResult::failed(ErrorClass::Staging, format!("{err:#}")).with_exit_code(exit)
+            );
+        }
+    }
+
+    Ok(TaskResult::from_success(exit == 0)
+        .with_exit_code(exit)
+        .with_error_class(error_class)
+        .with_result_file(result_file))
+}
+
+/// read the result file the task left behind (if any), then clean up its directory
+pub async fn read_result_file(result_dir: &std::path::Path) -> Result<Option<ResultFile>> {
+    let result = match tokio::fs::read(result_dir.join(RESULT_FILE_NAME)).await {
+        Ok(data) => ResultFile::parse(&data),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    };
+
+    if let Err(err) = tokio::fs::remove_dir_all(result_dir).await {
+        warn!(result_dir=%result_dir.display(), "failed to remove task result dir: {}", err);
+    }
+
+    result
 }
@@ -0,0 +1,517 @@
+use crate::{
+    messages::{ErrorClass, TaskDef, TaskRequest},
+    worker::{
+        config_cache::get_project_config,
+        control,
+        engine::{TaskEngineImpl, TaskResult},
+        env,
+        logs::{LogShipper, LogStream},
+        Worker,
+    },
+};
+use anyhow::{format_err, Context, Result};
+use aws_sdk_ecs::model::{
+    AssignPublicIp, AwsVpcConfiguration, Compatibility, ContainerDefinition, KeyValuePair,
+    LaunchType, LogConfiguration, LogDriver, NetworkConfiguration, NetworkMode, TaskStopCode,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{collections::HashMap, time::Duration};
+use tracing::{trace, warn};
+
+/// how often the task is checked on, and its new logs fetched
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// the smallest Fargate task, used for anything the task's resources don't say
+const DEFAULT_CPU_UNITS: u64 = 256;
+const DEFAULT_MEMORY_MIB: u64 = 512;
+
+/// Runs tasks on AWS ECS, usually Fargate. Each run registers a task definition
+/// made from the `TaskDef` (patched by `ecs_task_merge` from the project config),
+/// runs it, and streams its logs from CloudWatch until it stops.
+pub struct EcsEngine;
+
+#[async_trait::async_trait]
+impl TaskEngineImpl for EcsEngine {
+    async fn run_task(
+        &self,
+        worker: &Worker,
+        task_req: TaskRequest,
+        task_def: TaskDef,
+        deadline: DateTime<Utc>,
+    ) -> Result<TaskResult> {
+        run_ecs(worker, task_req, task_def, deadline).await
+    }
+}
+
+/// The parts of a task definition that can be set, in the JSON form used by the
+/// ECS API and `aws ecs register-task-definition`, so projects can patch it.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EcsTaskDefinition {
+    family: String,
+    cpu: String,
+    memory: String,
+    #[serde(default)]
+    requires_compatibilities: Vec<String>,
+    network_mode: Option<String>,
+    execution_role_arn: Option<String>,
+    task_role_arn: Option<String>,
+    container_definitions: Vec<EcsContainer>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EcsContainer {
+    name: String,
+    image: String,
+    #[serde(default)]
+    command: Vec<String>,
+    #[serde(default)]
+    environment: Vec<EcsKeyValue>,
+    essential: Option<bool>,
+    log_configuration: Option<EcsLogConfiguration>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EcsKeyValue {
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EcsLogConfiguration {
+    log_driver: String,
+    #[serde(default)]
+    options: HashMap<String, String>,
+}
+
+impl From<EcsContainer> for ContainerDefinition {
+    fn from(container: EcsContainer) -> Self {
+        let environment = container
+            .environment
+            .into_iter()
+            .map(|kv| {
+                KeyValuePair::builder()
+                    .name(kv.name)
+                    .value(kv.value)
+                    .build()
+            })
+            .collect();
+
+        let log_configuration = container.log_configuration.map(|log| {
+            LogConfiguration::builder()
+                .log_driver(LogDriver::from(log.log_driver.as_str()))
+                .set_options(Some(log.options))
+                .build()
+        });
+
+        ContainerDefinition::builder()
+            .name(container.name)
+            .image(container.image)
+            .set_command(Some(container.command))
+            .set_environment(Some(environment))
+            .set_essential(container.essential)
+            .set_log_configuration(log_configuration)
+            .build()
+    }
+}
+
+async fn make_task_definition(
+    worker: &Worker,
+    task_req: &TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+    region: &str,
+) -> Result<EcsTaskDefinition> {
+    let config = &worker.config;
+
+    let environment = env::get_env(worker, task_req, &task_def, deadline)
+        .await?
+        .into_iter()
+        .map(|ev| serde_json::json!({ "name": ev.name, "value": ev.value.unwrap_or_default() }))
+        .collect::<Vec<_>>();
+
+    let resources = task_def.resources.unwrap_or_default();
+    let cpu_units = resources
+        .cpu
+        .map_or(DEFAULT_CPU_UNITS, |cpu| (cpu * 1024.0).ceil() as u64);
+    let memory_mib = resources
+        .memory
+        .map_or(DEFAULT_MEMORY_MIB, |bytes| (bytes + (1 << 20) - 1) >> 20);
+
+    let mut task_json = serde_json::json!({
+        "family": format!("waterwheel-{}", task_def.task_id),
+        "cpu": cpu_units.to_string(),
+        "memory": memory_mib.to_string(),
+        "requiresCompatibilities": ["FARGATE"],
+        "networkMode": "awsvpc",
+        "executionRoleArn": config.ecs_execution_role_arn,
+        "taskRoleArn": config.ecs_task_role_arn,
+        "containerDefinitions": [
+            {
+                "name": "task",
+                "image": task_def.image.ok_or_else(|| format_err!("the task has no image"))?,
+                "command": task_def.args,
+                "environment": environment,
+                "essential": true,
+                "logConfiguration": {
+                    "logDriver": "awslogs",
+                    "options": {
+                        "awslogs-group": config.ecs_log_group,
+                        "awslogs-region": region,
+                        "awslogs-stream-prefix": "waterwheel",
+                    },
+                },
+            },
+        ],
+    });
+
+    let project_config = get_project_config(worker, task_def.project_id).await?;
+    if let Some(json) = project_config.get("ecs_task_merge") {
+        trace!("merging template: {:#} with patch: {:#}", task_json, json);
+        json_patch::merge(&mut task_json, json);
+    }
+
+    trace!("task definition json: {:#}", task_json);
+
+    serde_json::from_value(task_json).context("invalid ECS task definition")
+}
+
+pub async fn run_ecs(
+    worker: &Worker,
+    task_req: TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+) -> Result<TaskResult> {
+    let config = &worker.config;
+    let cluster = config
+        .ecs_cluster
+        .as_deref()
+        .ok_or_else(|| format_err!("the ecs engine needs WATERWHEEL_ECS_CLUSTER"))?;
+
+    let aws_config = aws_config::load_from_env().await;
+    let region = aws_config
+        .region()
+        .ok_or_else(|| format_err!("the ecs engine needs an AWS region, eg. AWS_REGION"))?
+        .to_string();
+    let ecs = aws_sdk_ecs::Client::new(&aws_config);
+    let logs = aws_sdk_cloudwatchlogs::Client::new(&aws_config);
+
+    let definition = make_task_definition(worker, &task_req, task_def, deadline, &region).await?;
+
+    // ____________________________________________________
+    // register the task definition
+    let compatibilities = definition
+        .requires_compatibilities
+        .iter()
+        .map(|compat| Compatibility::from(compat.as_str()))
+        .collect();
+    let containers = definition
+        .container_definitions
+        .into_iter()
+        .map(ContainerDefinition::from)
+        .collect::<Vec<_>>();
+
+    let log_stream_prefix = containers
+        .first()
+        .and_then(|container| container.log_configuration())
+        .and_then(|log| log.options()?.get("awslogs-stream-prefix").cloned());
+    let container_name = containers
+        .first()
+        .and_then(|container| container.name())
+        .unwrap_or_default()
+        .to_owned();
+
+    let registered = ecs
+        .register_task_definition()
+        .family(&definition.family)
+        .cpu(definition.cpu)
+        .memory(definition.memory)
+        .set_requires_compatibilities(Some(compatibilities))
+        .set_network_mode(definition.network_mode.as_deref().map(NetworkMode::from))
+        .set_execution_role_arn(definition.execution_role_arn)
+        .set_task_role_arn(definition.task_role_arn)
+        .set_container_definitions(Some(containers))
+        .send()
+        .await?;
+
+    let registered = Registered {
+        arn: registered
+            .task_definition()
+            .and_then(|def| def.task_definition_arn())
+            .ok_or_else(|| format_err!("ECS didn't return the task definition's ARN"))?
+            .to_owned(),
+        container_name,
+        log_stream_prefix,
+    };
+
+    trace!(arn=%registered.arn, "registered task definition");
+
+    let result = run_and_watch(
+        worker,
+        &task_req,
+        &ecs,
+        &logs,
+        cluster,
+        &registered,
+        deadline,
+    )
+    .await;
+
+    // each run registers a new revision, so old ones don't pile up
+    if let Err(err) = ecs
+        .deregister_task_definition()
+        .task_definition(&registered.arn)
+        .send()
+        .await
+    {
+        warn!(arn=%registered.arn, "failed to deregister task definition: {}", err);
+    }
+
+    result
+}
+
+/// a task definition that's been registered, and how to find its logs
+struct Registered {
+    arn: String,
+    container_name: String,
+    log_stream_prefix: Option<String>,
+}
+
+async fn run_and_watch(
+    worker: &Worker,
+    task_req: &TaskRequest,
+    ecs: &aws_sdk_ecs::Client,
+    logs: &aws_sdk_cloudwatchlogs::Client,
+    cluster: &str,
+    registered: &Registered,
+    deadline: DateTime<Utc>,
+) -> Result<TaskResult> {
+    let config = &worker.config;
+
+    // ____________________________________________________
+    // run the task
+    let assign_public_ip = if config.ecs_assign_public_ip {
+        AssignPublicIp::Enabled
+    } else {
+        AssignPublicIp::Disabled
+    };
+
+    let network = NetworkConfiguration::builder()
+        .awsvpc_configuration(
+            AwsVpcConfiguration::builder()
+                .set_subnets(Some(config.ecs_subnets.clone()))
+                .set_security_groups(Some(config.ecs_security_groups.clone()))
+                .assign_public_ip(assign_public_ip)
+                .build(),
+        )
+        .build();
+
+    let started = ecs
+        .run_task()
+        .cluster(cluster)
+        .task_definition(&registered.arn)
+        .launch_type(LaunchType::Fargate)
+        .network_configuration(network)
+        .started_by("waterwheel")
+        .send()
+        .await?;
+
+    let task_arn = match started.tasks().and_then(|tasks| tasks.first()) {
+        Some(task) => task.task_arn().unwrap_or_default().to_owned(),
+        None => {
+            // eg. no capacity, or a bad subnet
+            let reasons = started
+                .failures()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|failure| failure.reason())
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Ok(TaskResult::failed(
+                ErrorClass::Infra,
+                format!("ECS didn't start the task: {reasons}"),
+            ));
+        }
+    };
+
+    trace!(%task_arn, "started ECS task");
+
+    // the worker stops waiting on a killed task straight away, so this runs on its own
+    tokio::spawn({
+        let ecs = ecs.clone();
+        let cluster = cluster.to_owned();
+        let task_arn = task_arn.clone();
+        let definition_arn = registered.arn.clone();
+        let killed = control::on_kill(task_req.task_run_id);
+        async move {
+            if !killed.await {
+                return;
+            }
+
+            warn!(%task_arn, "task killed, stopping ECS task");
+            let stopped = ecs
+                .stop_task()
+                .cluster(&cluster)
+                .task(&task_arn)
+                .reason("waterwheel task killed")
+                .send()
+                .await;
+            if let Err(err) = stopped {
+                warn!(%task_arn, "failed to stop ECS task: {}", err);
+            }
+
+            let deregistered = ecs
+                .deregister_task_definition()
+                .task_definition(&definition_arn)
+                .send()
+                .await;
+            if let Err(err) = deregistered {
+                warn!(arn=%definition_arn, "failed to deregister task definition: {}", err);
+            }
+        }
+    });
+
+    // the awslogs driver names streams `<prefix>/<container>/<task id>`
+    let log_stream = registered.log_stream_prefix.as_ref().map(|prefix| {
+        let task_id = task_arn.rsplit('/').next().unwrap_or_default();
+        format!("{prefix}/{}/{task_id}", registered.container_name)
+    });
+
+    let mut shipper = LogShipper::new(worker, task_req).await?;
+    let mut log_token = None;
+    let mut stop_requested = false;
+
+    // ____________________________________________________
+    // wait for it to stop, sending its logs as they arrive
+    let stopped = loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        if let Some(stream) = &log_stream {
+            log_token =
+                ship_logs(logs, &config.ecs_log_group, stream, log_token, &mut shipper).await?;
+        }
+
+        if !stop_requested && Utc::now() > deadline {
+            warn!(%task_arn, "task deadline reached, stopping ECS task");
+            ecs.stop_task()
+                .cluster(cluster)
+                .task(&task_arn)
+                .reason("waterwheel task deadline reached")
+                .send()
+                .await?;
+            stop_requested = true;
+        }
+
+        let described = ecs
+            .describe_tasks()
+            .cluster(cluster)
+            .tasks(&task_arn)
+            .send()
+            .await?;
+
+        let task = described
+            .tasks()
+            .and_then(|tasks| tasks.first())
+            .ok_or_else(|| format_err!("ECS task {task_arn} has gone"))?;
+
+        trace!(%task_arn, status=?task.last_status(), "polled ECS task");
+
+        if task.last_status() == Some("STOPPED") {
+            break task.clone();
+        }
+    };
+
+    // the last lines can arrive after the task has stopped
+    if let Some(stream) = &log_stream {
+        ship_logs(logs, &config.ecs_log_group, stream, log_token, &mut shipper).await?;
+    }
+    shipper.finish().await?;
+
+    let container = stopped.containers().and_then(|containers| {
+        containers
+            .iter()
+            .find(|c| c.name() == Some(registered.container_name.as_str()))
+    });
+    let exit_code = container.and_then(|container| container.exit_code());
+
+    let error_details = stopped.stopped_reason().map(str::to_owned);
+
+    // ECS starts its reasons with an error name, eg. "CannotPullContainerError: ..."
+    let reasons = [
+        stopped.stopped_reason(),
+        container.and_then(|container| container.reason()),
+    ];
+    let has_reason = |name: &str| {
+        reasons
+            .iter()
+            .flatten()
+            .any(|reason| reason.starts_with(name))
+    };
+    let error_class = if has_reason("CannotPullContainerError") {
+        Some(ErrorClass::ImagePull)
+    } else if has_reason("OutOfMemoryError") {
+        Some(ErrorClass::OomKilled)
+    } else {
+        None
+    };
+
+    if stopped.stop_code() == Some(&TaskStopCode::SpotInterruption) {
+        warn!(%task_arn, "ECS task was preempted");
+        return Ok(TaskResult::preempted(error_details));
+    }
+
+    let success = exit_code == Some(0) && !stop_requested;
+
+    Ok(TaskResult {
+        success,
+        error_details: if success { None } else { error_details },
+        exit_code: exit_code.map(i64::from),
+        error_class,
+        ..TaskResult::default()
+    })
+}
+
+/// Send the log events after `token`, returning where to carry on from. The
+/// stream doesn't exist until the container starts, so that's not an error.
+async fn ship_logs(
+    logs: &aws_sdk_cloudwatchlogs::Client,
+    group: &str,
+    stream: &str,
+    mut token: Option<String>,
+    shipper: &mut LogShipper<'_>,
+) -> Result<Option<String>> {
+    loop {
+        let page = match logs
+            .get_log_events()
+            .log_group_name(group)
+            .log_stream_name(stream)
+            .start_from_head(true)
+            .set_next_token(token.clone())
+            .send()
+            .await
+        {
+            Ok(page) => page,
+            Err(err) => {
+                trace!(%stream, "no logs yet: {}", err);
+                return Ok(token);
+            }
+        };
+
+        for event in page.events().unwrap_or_default() {
+            if let Some(message) = event.message() {
+                shipper.send(LogStream::Output, message.as_bytes()).await?;
+                shipper.send(LogStream::Output, b"\n").await?;
+            }
+        }
+
+        // the same token comes back once there's nothing newer
+        let next = page.next_forward_token().map(str::to_owned);
+        if next.is_none() || next == token {
+            return Ok(next.or(token));
+        }
+        token = next;
+    }
+}
@@ -1,9 +1,19 @@
 use crate::{
-    messages::{TaskDef, TaskRequest},
-    worker::{docker::DockerEngine, kube::KubeEngine, kubejob::KubeJobEngine, Worker},
+    messages::{ErrorClass, TaskDef, TaskRequest, TokenState},
+    task_contract::{ResultFile, ResultKind},
+    worker::{
+        docker::{ContainerRuntime, DockerEngine},
+        ecs::EcsEngine,
+        kube::KubeEngine,
+        kubejob::KubeJobEngine,
+        process::ProcessEngine,
+        Worker,
+    },
 };
 use anyhow::Result;
-use std::str::FromStr;
+use chrono::{DateTime, Utc};
+use std::{str::FromStr, time::Duration};
+use tracing::warn;
 
 #[derive(Copy, Clone, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -11,12 +21,18 @@ pub enum TaskEngine {
     /// Null engine always returns success - disabled in release builds
     #[cfg(debug_assertions)]
     Null,
-    /// Use a local docker instance (TODO - allow remote docker)
+    /// Use a docker daemon, local unless `docker_host` says otherwise
     Docker,
-    /// Use a remote Kubernetes cluster (launching pods directly)
+    /// Use Podman through its Docker compatible API, eg. where there's no docker daemon
+    Podman,
+    /// Use a remote Kubernetes cluster (a job per task, collecting its logs and result)
     Kubernetes,
-    /// Use a remote Kubernetes cluster (uses jobs)
+    /// Use a remote Kubernetes cluster (fire and forget jobs)
     KubernetesJobs,
+    /// Run tasks as processes on the worker's host, with no container runtime
+    Process,
+    /// Run tasks on AWS ECS, eg. Fargate
+    Ecs,
 }
 
 impl FromStr for TaskEngine {
@@ -27,45 +43,219 @@ impl FromStr for TaskEngine {
             #[cfg(debug_assertions)]
             "null" => Ok(TaskEngine::Null),
             "docker" => Ok(TaskEngine::Docker),
+            "podman" => Ok(TaskEngine::Podman),
             "kubernetes" => Ok(TaskEngine::Kubernetes),
             "kubernetesjobs" => Ok(TaskEngine::KubernetesJobs),
+            "process" => Ok(TaskEngine::Process),
+            "ecs" => Ok(TaskEngine::Ecs),
             _ => Err(anyhow::Error::msg(
-                "invalid engine, valid options: docker, kubernetes",
+                "invalid engine, valid options: \
+                docker, podman, kubernetes, kubernetesjobs, process, ecs",
             )),
         }
     }
 }
 
 impl TaskEngine {
+    /// the name it's configured by
+    pub fn name(&self) -> &'static str {
+        match self {
+            #[cfg(debug_assertions)]
+            TaskEngine::Null => "null",
+            TaskEngine::Docker => "docker",
+            TaskEngine::Podman => "podman",
+            TaskEngine::Kubernetes => "kubernetes",
+            TaskEngine::KubernetesJobs => "kubernetesjobs",
+            TaskEngine::Process => "process",
+            TaskEngine::Ecs => "ecs",
+        }
+    }
+
     pub fn get_impl(
         &self,
     ) -> Result<std::pin::Pin<Box<dyn TaskEngineImpl + Send + Sync + 'static>>> {
         Ok(match self {
             #[cfg(debug_assertions)]
             TaskEngine::Null => Box::pin(null::NullEngine),
-            TaskEngine::Docker => Box::pin(DockerEngine),
+            TaskEngine::Docker => Box::pin(DockerEngine(ContainerRuntime::Docker)),
+            TaskEngine::Podman => Box::pin(DockerEngine(ContainerRuntime::Podman)),
             TaskEngine::Kubernetes => Box::pin(KubeEngine),
             TaskEngine::KubernetesJobs => Box::pin(KubeJobEngine),
+            TaskEngine::Process => Box::pin(ProcessEngine),
+            TaskEngine::Ecs => Box::pin(EcsEngine),
         })
     }
 }
 
+/// The result of running a task, as reported by a task engine
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TaskResult {
+    pub success: bool,
+    /// extra information about why the task failed (eg. Kubernetes events)
+    pub error_details: Option<String>,
+    /// the task was interrupted by the infrastructure (eg. spot node reclaimed)
+    pub preempted: bool,
+    /// what the task wrote to its result file, if anything
+    pub result_file: Option<ResultFile>,
+    /// the exit code of the task's process, if the engine knows it
+    pub exit_code: Option<i64>,
+    /// why the task failed, if the engine knows better than its exit code
+    pub error_class: Option<ErrorClass>,
+}
+
+impl TaskResult {
+    pub fn from_success(success: bool) -> Self {
+        TaskResult {
+            success,
+            ..TaskResult::default()
+        }
+    }
+
+    pub fn preempted(error_details: Option<String>) -> Self {
+        TaskResult {
+            success: false,
+            error_details,
+            preempted: true,
+            result_file: None,
+            exit_code: None,
+            error_class: None,
+        }
+    }
+
+    /// a failed result, from a problem the engine found before the task ran
+    pub fn failed(error_class: ErrorClass, error_details: String) -> Self {
+        TaskResult {
+            success: false,
+            error_details: Some(error_details),
+            error_class: Some(error_class),
+            ..TaskResult::default()
+        }
+    }
+
+    pub fn with_exit_code(mut self, exit_code: i64) -> Self {
+        self.exit_code = Some(exit_code);
+        self
+    }
+
+    pub fn with_error_class(mut self, error_class: Option<ErrorClass>) -> Self {
+        self.error_class = error_class;
+        self
+    }
+
+    /// Add the task's result file, or an error if it wrote an invalid one.
+    /// A task with an invalid result file is reported as an error, since its
+    /// outputs are missing even if it exited successfully.
+    pub fn with_result_file(mut self, result_file: Result<Option<ResultFile>>) -> Self {
+        match result_file {
+            Ok(result_file) => self.result_file = result_file,
+            Err(err) => {
+                self.result_file = Some(ResultFile {
+                    result: Some(ResultKind::Error),
+                    ..ResultFile::default()
+                });
+                self.error_details = Some(format!("{err:#}"));
+            }
+        }
+        self
+    }
+
+    pub fn state(&self) -> TokenState {
+        if self.preempted {
+            TokenState::Preempted
+        } else if let Some(kind) = self.result_file.as_ref().and_then(|file| file.result) {
+            kind.into()
+        } else if self.success {
+            TokenState::Success
+        } else {
+            TokenState::Failure
+        }
+    }
+
+    /// Why the task failed, if it did. Failures the engine didn't classify
+    /// are put down to the task's exit code, when it has a non-zero one.
+    pub fn error_class(&self) -> Option<ErrorClass> {
+        match self.state() {
+            TokenState::Success | TokenState::Preempted => None,
+            _ => self.error_class.or(match self.exit_code {
+                Some(code) if code != 0 => Some(ErrorClass::NonZeroExit),
+                _ => None,
+            }),
+        }
+    }
+}
+
+/// Context added to errors from the infrastructure a task runs on rather than
+/// from the task, eg. the Docker daemon being unreachable or a Kubernetes API
+/// server error. The task never got to run, so these are worth retrying.
+#[derive(Debug, thiserror::Error)]
+#[error("infrastructure error")]
+pub struct InfraError;
+
+impl InfraError {
+    pub fn is(err: &anyhow::Error) -> bool {
+        err.downcast_ref::<InfraError>().is_some()
+    }
+}
+
+/// Run a task, retrying infrastructure errors up to `worker_infra_retries`
+/// times, waiting twice as long each time. Anything else is returned as it is.
+pub async fn run_with_retries(
+    engine: &(dyn TaskEngineImpl + Send + Sync),
+    worker: &Worker,
+    task_req: TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+) -> Result<TaskResult> {
+    let mut delay = Duration::from_secs(worker.config.worker_infra_retry_delay);
+    let mut retries = 0;
+
+    loop {
+        let result = engine
+            .run_task(worker, task_req.clone(), task_def.clone(), deadline)
+            .await;
+
+        match result {
+            Err(err) if InfraError::is(&err) && retries < worker.config.worker_infra_retries => {
+                retries += 1;
+                warn!(
+                    retries,
+                    "infrastructure error, retrying in {:?}: {:#}", delay, err
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// seconds remaining until the deadline (at least 1, since 0 often means "no limit")
+pub fn seconds_until(deadline: DateTime<Utc>) -> i64 {
+    (deadline - Utc::now()).num_seconds().max(1)
+}
+
 #[async_trait::async_trait]
 pub trait TaskEngineImpl {
+    /// run the task, the engine should kill it if it's still running at the deadline
     async fn run_task(
         &self,
         worker: &Worker,
         task_req: TaskRequest,
         task_def: TaskDef,
-    ) -> Result<bool>;
+        deadline: DateTime<Utc>,
+    ) -> Result<TaskResult>;
 }
 
 #[cfg(debug_assertions)]
 mod null {
     use crate::{
         messages::{TaskDef, TaskRequest},
-        worker::{engine::TaskEngineImpl, Worker},
+        worker::{
+            engine::{TaskEngineImpl, TaskResult},
+            Worker,
+        },
     };
+    use chrono::{DateTime, Utc};
 
     pub struct NullEngine;
 
@@ -76,8 +266,9 @@ mod null {
             _worker: &Worker,
             _task_req: TaskRequest,
             _task_def: TaskDef,
-        ) -> anyhow::Result<bool> {
-            Ok(true)
+            _deadline: DateTime<Utc>,
+        ) -> anyhow::Result<TaskResult> {
+            Ok(TaskResult::from_success(true))
         }
     }
 }
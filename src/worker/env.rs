@@ -1,18 +1,21 @@
 use crate::{
     messages::{TaskDef, TaskRequest},
     server::api::jwt,
-    worker::Worker,
+    worker::{secrets::resolve_secret, Worker},
 };
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
 use k8s_openapi::api::core::v1::EnvVar;
+use serde_json::Value as JsonValue;
 
-pub fn get_env_string(
+pub async fn get_env_string(
     worker: &Worker,
     task_req: &TaskRequest,
     task_def: &TaskDef,
+    deadline: DateTime<Utc>,
 ) -> Result<Vec<String>> {
-    let env = get_env(worker, task_req, task_def)?;
+    let env = get_env(worker, task_req, task_def, deadline).await?;
 
     Ok(env
         .iter()
@@ -20,7 +23,7 @@ pub fn get_env_string(
         .collect())
 }
 
-fn envvar(name: &str, val: impl std::fmt::Display) -> EnvVar {
+pub fn envvar(name: &str, val: impl std::fmt::Display) -> EnvVar {
     EnvVar {
         name: name.to_owned(),
         value: Some(val.to_string()),
@@ -28,7 +31,12 @@ fn envvar(name: &str, val: impl std::fmt::Display) -> EnvVar {
     }
 }
 
-pub fn get_env(worker: &Worker, task_req: &TaskRequest, task_def: &TaskDef) -> Result<Vec<EnvVar>> {
+pub async fn get_env(
+    worker: &Worker,
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+    deadline: DateTime<Utc>,
+) -> Result<Vec<EnvVar>> {
     let provided_env = task_def.env.clone().unwrap_or_default();
 
     let mut env = vec![];
@@ -43,7 +51,28 @@ pub fn get_env(worker: &Worker, task_req: &TaskRequest, task_def: &TaskDef) -> R
         }
     }
 
-    let server_addr: &str = worker.config.server_addr.as_ref();
+    for secret in &task_def.secret_env {
+        let value = resolve_secret(worker, task_req, task_def, &secret.secret_ref)
+            .await
+            .map_err(|err| {
+                anyhow::Error::msg(format!(
+                    "error reading {} for env '{}': {:#}",
+                    secret.secret_ref, secret.name, err
+                ))
+            })?;
+        env.push(envvar(&secret.name, value));
+    }
+
+    // variables from the dispatch hooks replace any set by the task definition
+    for (k, v) in &task_req.env {
+        match env.iter_mut().find(|ev| &ev.name == k) {
+            Some(ev) => ev.value = Some(v.clone()),
+            None => env.push(envvar(k, v)),
+        }
+    }
+
+    // tasks only use the internal endpoints, eg. for the stash
+    let server_addr = worker.config.internal_addr();
 
     env.push(envvar(
         "WATERWHEEL_TRIGGER_DATETIME",
@@ -58,9 +87,84 @@ pub fn get_env(worker: &Worker, task_req: &TaskRequest, task_def: &TaskDef) -> R
     env.push(envvar("WATERWHEEL_PROJECT_NAME", &task_def.project_name));
     env.push(envvar("WATERWHEEL_PROJECT_ID", task_def.project_id));
     env.push(envvar("WATERWHEEL_SERVER_ADDR", server_addr));
+    // tasks can use this to checkpoint and exit cleanly before being killed
+    env.push(envvar(
+        "WATERWHEEL_DEADLINE",
+        deadline.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    ));
+
+    if let Some(failure) = &task_req.failure {
+        env.push(envvar("WATERWHEEL_FAILED_TASK_NAME", &failure.task_name));
+        env.push(envvar("WATERWHEEL_FAILED_TASK_ID", failure.task_id));
+        env.push(envvar("WATERWHEEL_FAILED_STATE", failure.state.as_ref()));
+        if let Some(details) = &failure.error_details {
+            env.push(envvar("WATERWHEEL_FAILED_ERROR_DETAILS", details));
+        }
+    }
+
+    if !task_req.upstream_outputs.is_empty() {
+        env.push(envvar(
+            "WATERWHEEL_UPSTREAM_OUTPUTS",
+            serde_json::to_string(&task_req.upstream_outputs)?,
+        ));
+
+        for (task_name, outputs) in &task_req.upstream_outputs {
+            for (name, value) in outputs {
+                env.push(envvar(
+                    &output_env_name(task_name, name),
+                    output_env_value(value),
+                ));
+            }
+        }
+    }
 
     let stash_jwt = jwt::generate_stash_jwt(&worker.jwt_keys, &task_req.task_id.to_string())?;
     env.push(envvar("WATERWHEEL_JWT", stash_jwt));
+    let refresh_jwt = jwt::generate_stash_refresh_jwt(
+        &worker.jwt_keys,
+        &task_req.task_id.to_string(),
+        deadline.into(),
+    )?;
+    env.push(envvar("WATERWHEEL_REFRESH_JWT", refresh_jwt));
 
     Ok(env)
 }
+
+/// eg. `WATERWHEEL_OUTPUT_EXTRACT_ROWS_LOADED` for the `rows_loaded` output of `extract`
+fn output_env_name(task_name: &str, output_name: &str) -> String {
+    format!("WATERWHEEL_OUTPUT_{task_name}_{output_name}")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// strings are passed as they are, anything else as JSON
+fn output_env_value(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_output_env() {
+        assert_eq!(
+            output_env_name("extract-orders", "rows loaded"),
+            "WATERWHEEL_OUTPUT_EXTRACT_ORDERS_ROWS_LOADED"
+        );
+        assert_eq!(output_env_value(&json!("2022-01-01")), "2022-01-01");
+        assert_eq!(output_env_value(&json!(1200)), "1200");
+        assert_eq!(output_env_value(&json!({"a": [1]})), r#"{"a":[1]}"#);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{
+    config::Config,
+    messages::{TaskDef, TaskRequest},
+    worker::{
+        engine::{InfraError, TaskEngineImpl, TaskResult},
+        lambda::LambdaEngine,
+        process::ProcessEngine,
+        shutdown,
+        wasm::WasmEngine,
+        Worker,
+    },
+};
+use anyhow::{format_err, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::Command,
+};
+use tracing::{debug, warn};
+
+/// the subcommand the worker runs its executors with
+pub const EXECUTOR_COMMAND: &str = "executor";
+
+/// an executor's result is tiny, anything bigger than this is garbage on stdout
+const MAX_RESULT_BYTES: u64 = 1024 * 1024;
+
+/// what the supervising worker sends to an executor on its stdin
+#[derive(Serialize, Deserialize)]
+struct ExecutorRequest {
+    task_req: TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+}
+
+/// what an executor sends back on its stdout, an error is sent as its message
+#[derive(Serialize, Deserialize)]
+enum ExecutorResult {
+    Ok(TaskResult),
+    Err(String),
+    /// an error marked as an `InfraError`, so the worker can retry it
+    InfraErr(String),
+}
+
+/// In supervisor mode each task is run by a child executor process, so a panic
+/// or runaway memory use while running one task (eg. buffering a flood of logs)
+/// only takes down that task rather than the worker and its other tasks.
+pub struct ExecutorEngine;
+
+#[async_trait::async_trait]
+impl TaskEngineImpl for ExecutorEngine {
+    async fn run_task(
+        &self,
+        worker: &Worker,
+        task_req: TaskRequest,
+        task_def: TaskDef,
+        deadline: DateTime<Utc>,
+    ) -> Result<TaskResult> {
+        let mut child = executor_command(&worker.config)?
+            .spawn()
+            .context("starting task executor")?;
+
+        debug!(pid = child.id(), "started task executor");
+
+        let request = serde_json::to_vec(&ExecutorRequest {
+            task_req,
+            task_def,
+            deadline,
+        })?;
+
+        let mut stdin = child.stdin.take().expect("executor stdin is piped");
+        stdin.write_all(&request).await?;
+        drop(stdin);
+
+        let mut output = Vec::new();
+        child
+            .stdout
+            .take()
+            .expect("executor stdout is piped")
+            .take(MAX_RESULT_BYTES)
+            .read_to_end(&mut output)
+            .await?;
+
+        let status = child.wait().await?;
+
+        if !status.success() {
+            if shutdown::is_shutting_down() {
+                warn!(%status, "task executor exited during shutdown");
+                return Ok(TaskResult::preempted(Some(format!(
+                    "task executor exited during shutdown: {status}"
+                ))));
+            }
+            return Err(format_err!("task executor crashed: {status}"));
+        }
+
+        match serde_json::from_slice(&output).context("reading the task executor's result")? {
+            ExecutorResult::Ok(result) => Ok(result),
+            ExecutorResult::Err(msg) => Err(anyhow::Error::msg(msg)),
+            ExecutorResult::InfraErr(msg) => Err(anyhow::Error::msg(msg).context(InfraError)),
+        }
+    }
+}
+
+/// Run this binary again as an executor, with the same config file and profile.
+/// The executor is killed if it's dropped (eg. the task timed out) or the worker dies.
+fn executor_command(config: &Config) -> Result<Command> {
+    let mut cmd = std::process::Command::new(std::env::current_exe()?);
+
+    if let Some(ref config_file) = config.config_file {
+        cmd.arg("--config").arg(config_file);
+    }
+    if let Some(ref profile) = config.profile {
+        cmd.arg("--profile").arg(profile);
+    }
+    cmd.arg(EXECUTOR_COMMAND)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+
+    limit_executor(&mut cmd, config.executor_memory_limit);
+
+    let mut cmd = Command::from(cmd);
+    cmd.kill_on_drop(true);
+
+    Ok(cmd)
+}
+
+/// Kill the executor if the worker dies (on Linux), and limit its memory
+#[cfg(unix)]
+fn limit_executor(cmd: &mut std::process::Command, memory_limit: Option<u64>) {
+    use std::os::unix::process::CommandExt;
+
+    // safety: only async-signal-safe calls are made between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            #[cfg(target_os = "linux")]
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            if let Some(limit) = memory_limit {
+                let rlimit = libc::rlimit {
+                    rlim_cur: limit as libc::rlim_t,
+                    rlim_max: limit as libc::rlim_t,
+                };
+                if libc::setrlimit(libc::RLIMIT_AS, &rlimit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[cfg(windows)]
+fn limit_executor(_cmd: &mut std::process::Command, memory_limit: Option<u64>) {
+    if memory_limit.is_some() {
+        warn!("executor_memory_limit isn't supported on Windows, executors aren't limited");
+    }
+}
+
+/// The entrypoint of an executor process. Read one task from stdin, run it with
+/// the configured engine and write the result to stdout. Logs go to stderr.
+pub async fn run_executor(config: Config) -> Result<()> {
+    let mut input = Vec::new();
+    tokio::io::stdin().read_to_end(&mut input).await?;
+    let request: ExecutorRequest =
+        serde_json::from_slice(&input).context("reading the task from the worker")?;
+
+    let worker = Worker::new(config).await?;
+
+    let result = if request.task_def.wasm_module.is_some() {
+        WasmEngine
+            .run_task(
+                &worker,
+                request.task_req,
+                request.task_def,
+                request.deadline,
+            )
+            .await
+    } else if request.task_def.command.is_some() {
+        ProcessEngine
+            .run_task(
+                &worker,
+                request.task_req,
+                request.task_def,
+                request.deadline,
+            )
+            .await
+    } else if request.task_def.lambda.is_some() {
+        LambdaEngine
+            .run_task(
+                &worker,
+                request.task_req,
+                request.task_def,
+                request.deadline,
+            )
+            .await
+    } else {
+        worker
+            .config
+            .task_engine
+            .get_impl()?
+            .run_task(
+                &worker,
+                request.task_req,
+                request.task_def,
+                request.deadline,
+            )
+            .await
+    };
+
+    let result = match result {
+        Ok(result) => ExecutorResult::Ok(result),
+        Err(err) if InfraError::is(&err) => ExecutorResult::InfraErr(format!("{err:#}")),
+        Err(err) => ExecutorResult::Err(format!("{err:#}")),
+    };
+
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(&serde_json::to_vec(&result)?).await?;
+    stdout.flush().await?;
+
+    Ok(())
+}
@@ -1,6 +1,11 @@
-use crate::{messages::WorkerHeartbeat, worker::Worker, GIT_VERSION};
+use crate::{
+    messages::{WorkerHeartbeat, WorkerLabels, WorkerStatus},
+    server::api::jwt,
+    worker::{work::task_queues, LiveConfig, Worker},
+    GIT_VERSION,
+};
 use anyhow::Result;
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use chrono::Utc;
 use tracing::{debug, error, trace, warn};
@@ -9,19 +14,69 @@ use super::{RUNNING_TASKS, TOTAL_TASKS, WORKER_ID};
 use crate::config::Config;
 use reqwest::{StatusCode, Url};
 
-pub async fn post_heartbeat(config: &Config, client: &reqwest::Client) -> Result<bool> {
-    let server_addr = config.server_addr.as_ref();
-    let url = Url::parse(server_addr)?.join("int-api/heartbeat")?;
+fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// where the worker can be reached, a wildcard bind address is replaced by the hostname
+fn advertised_addr(addr: SocketAddr) -> String {
+    if addr.ip().is_unspecified() {
+        format!("{}:{}", hostname(), addr.port())
+    } else {
+        addr.to_string()
+    }
+}
+
+/// the configured engine, and the ones wasm, command and lambda tasks
+/// always use, whatever it is
+fn task_engines(config: &Config) -> Vec<String> {
+    let mut engines = vec![config.task_engine.name()];
+    for engine in ["wasm", "process", "lambda"] {
+        if !engines.contains(&engine) {
+            engines.push(engine);
+        }
+    }
+    engines.into_iter().map(str::to_owned).collect()
+}
+
+fn labels(config: &Config, live: &LiveConfig) -> WorkerLabels {
+    WorkerLabels {
+        hostname: Some(hostname()),
+        zone: config.worker_zone.clone(),
+        capacity: Some(live.max_tasks),
+        queues: task_queues(config),
+        gpus: config.worker_gpus,
+        engines: task_engines(config),
+        os: config.worker_os,
+    }
+}
+
+pub async fn post_heartbeat(worker: &Worker, live: &LiveConfig) -> Result<bool> {
+    let config = &worker.config;
+    let url = Url::parse(config.internal_addr())?.join("int-api/heartbeat")?;
+    let token = jwt::generate_worker_jwt(&worker.jwt_keys, *WORKER_ID)?;
 
-    let resp = client
+    let resp = worker
+        .internal_client
         .post(url.clone())
+        .bearer_auth(token)
         .json(&WorkerHeartbeat {
             uuid: *WORKER_ID,
-            addr: "TODO".to_owned(),
+            addr: advertised_addr(worker.addr),
             last_seen_datetime: Utc::now(),
             running_tasks: RUNNING_TASKS.get(),
             total_tasks: TOTAL_TASKS.get(),
             version: GIT_VERSION.to_owned(),
+            profile: config.profile.clone(),
+            tags: live.worker_tags.clone(),
+            labels: labels(config, live),
+            status: if live.draining {
+                WorkerStatus::Draining
+            } else if live.maintenance {
+                WorkerStatus::Maintenance
+            } else {
+                WorkerStatus::Active
+            },
         })
         .send()
         .await;
@@ -52,27 +107,59 @@ pub async fn post_heartbeat(config: &Config, client: &reqwest::Client) -> Result
     }
 }
 
-pub async fn heartbeat(worker: Arc<Worker>) -> Result<!> {
-    let client = reqwest::Client::new();
+/// tell the server this worker has been drained and won't take any more tasks
+pub async fn post_retired(worker: &Worker) -> Result<()> {
+    let url = Url::parse(worker.config.internal_addr())?
+        .join(&format!("int-api/workers/{}/retire", *WORKER_ID))?;
+
+    worker
+        .internal_client
+        .post(url)
+        .bearer_auth(jwt::generate_worker_jwt(&worker.jwt_keys, *WORKER_ID)?)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
 
+/// tell the server this worker is shutting down, rather than waiting for it to miss heartbeats
+pub async fn post_gone(worker: &Worker) -> Result<()> {
+    let url = Url::parse(worker.config.internal_addr())?
+        .join(&format!("int-api/workers/{}/gone", *WORKER_ID))?;
+
+    worker
+        .internal_client
+        .post(url)
+        .bearer_auth(jwt::generate_worker_jwt(&worker.jwt_keys, *WORKER_ID)?)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+pub async fn heartbeat(worker: Arc<Worker>) -> Result<!> {
     loop {
         trace!("sending heartbeat");
-        post_heartbeat(&worker.config, &client).await?;
+        // tags and capacity can change when the config is reloaded
+        let live = worker.live_config.borrow().clone();
+        post_heartbeat(&worker, &live).await?;
 
         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
     }
 }
 
-pub async fn wait_for_server(config: &Config) {
+pub async fn wait_for_server(worker: &Worker) -> Result<()> {
     // before accepting tasks perform a synchronous heartbeat to ensure
     // the server has our worker ID recorded
-    let client = reqwest::Client::new();
+    let live = worker.live_config.borrow().clone();
 
     trace!("waiting for initial heartbeat");
     let mut retries = 5;
     loop {
         trace!("sending heartbeat");
-        if post_heartbeat(config, &client)
+        if post_heartbeat(worker, &live)
             .await
             .expect("error posting heartbeat")
         {
@@ -89,4 +176,5 @@ pub async fn wait_for_server(config: &Config) {
     }
 
     trace!("server received initial heartbeat, starting work");
+    Ok(())
 }
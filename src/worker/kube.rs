@@ -1,24 +1,64 @@
 use crate::{
-    messages::{TaskDef, TaskRequest},
-    worker::{config_cache::get_project_config, engine::TaskEngineImpl, env, Worker, WORKER_ID},
+    messages::{ErrorClass, TaskDef, TaskRequest, TaskResources},
+    task_contract::{ResultFile, HELPER_DIR, HELPER_ENV, HELPER_NAME, RESULT_FILE_ENV},
+    worker::{
+        config_cache::get_project_config,
+        control,
+        engine::{seconds_until, InfraError, TaskEngineImpl, TaskResult},
+        env,
+        logs::{LogShipper, LogStream},
+        registry::{all_credentials, image_pull_policy, DEFAULT_REGISTRY},
+        Worker, WORKER_ID,
+    },
 };
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use futures::{StreamExt, TryStreamExt};
 use itertools::Itertools;
-use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::api::{
+    batch::v1::Job,
+    core::v1::{ContainerStateTerminated, Event, Pod, PodStatus, Secret},
+};
 use kube::{
-    api::{Api, DeleteParams, LogParams, PostParams},
+    api::{Api, DeleteParams, ListParams, LogParams, Patch, PatchParams, PostParams},
     Client, Config, ResourceExt,
 };
+use kube_runtime::watcher::{self, watch_object};
 use rand::seq::SliceRandom;
-use redis::{streams::StreamMaxlen, AsyncCommands};
-use std::{convert::TryFrom, time::Duration};
+use std::{collections::HashMap, convert::TryFrom, time::Duration};
 use tracing::{trace, warn};
+use uuid::Uuid;
+
+const DELETE_JOB_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// container waiting reasons that mean the pod will never start without intervention
+const FATAL_WAITING_REASONS: &[&str] = &[
+    "ErrImagePull",
+    "ImagePullBackOff",
+    "InvalidImageName",
+    "CreateContainerConfigError",
+];
+
+/// the fatal waiting reasons that mean the task's image couldn't be pulled
+const IMAGE_PULL_REASONS: &[&str] = &["ErrImagePull", "ImagePullBackOff", "InvalidImageName"];
+
+/// Tasks write their result file to the termination message, which Kubernetes
+/// keeps in the pod status after the container exits (up to 4KB of it)
+const TERMINATION_MESSAGE_PATH: &str = "/dev/termination-log";
 
-const DELETE_POD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// pod status reasons given when the node kills a pod for reasons outside the task's control
+const PREEMPTED_REASONS: &[&str] = &["Evicted", "Preempting", "NodeLost", "Shutdown", "NodeShutdown"];
 
+/// Runs each task as a Kubernetes Job, so pods lost to evictions or node
+/// failures are retried by Kubernetes (up to `kube_backoff_limit` times)
 pub struct KubeEngine;
 
+/// a change to a task's job, or to one of its pods
+enum Watched {
+    Job(Option<Job>),
+    Pods(watcher::Event<Pod>),
+}
+
 #[async_trait::async_trait]
 impl TaskEngineImpl for KubeEngine {
     async fn run_task(
@@ -26,104 +66,350 @@ impl TaskEngineImpl for KubeEngine {
         worker: &Worker,
         task_req: TaskRequest,
         task_def: TaskDef,
-    ) -> Result<bool> {
-        run_kube(worker, task_req, task_def).await
+        deadline: DateTime<Utc>,
+    ) -> Result<TaskResult> {
+        run_kube(worker, task_req, task_def, deadline).await
+    }
+}
+
+/// Fetch the events for a Kubernetes object and format them one per line
+/// (eg. "Warning FailedScheduling: 0/3 nodes are available")
+pub async fn get_events(worker: &Worker, client: Client, name: &str) -> Result<Option<String>> {
+    let events: Api<Event> = namespaced_api(worker, client);
+
+    let list = events
+        .list(&ListParams::default().fields(&format!("involvedObject.name={name}")))
+        .await?;
+
+    let lines = list
+        .items
+        .iter()
+        .map(|event| {
+            format!(
+                "{} {}: {}",
+                event.type_.as_deref().unwrap_or("Normal"),
+                event.reason.as_deref().unwrap_or("Unknown"),
+                event.message.as_deref().unwrap_or_default(),
+            )
+        })
+        .join("\n");
+
+    Ok(if lines.is_empty() { None } else { Some(lines) })
+}
+
+/// use the namespace from the worker config (or profile), falling back to the kubeconfig's default
+pub fn namespaced_api<K>(worker: &Worker, client: Client) -> Api<K>
+where
+    K: kube::Resource,
+    K::DynamicType: Default,
+{
+    match &worker.config.kube_namespace {
+        Some(namespace) => Api::namespaced(client, namespace),
+        None => Api::default_namespaced(client),
     }
 }
 
-pub async fn run_kube(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -> Result<bool> {
+/// Delete the job (and its pods) if the task is killed. The worker stops waiting
+/// on a killed task straight away, so this runs on its own.
+pub fn delete_on_kill(jobs: Api<Job>, name: String, task_run_id: Uuid) {
+    let killed = control::on_kill(task_run_id);
+
+    tokio::spawn(async move {
+        if killed.await {
+            warn!(job_name=%name, "task killed, deleting job");
+            if let Err(err) = jobs.delete(&name, &DeleteParams::background()).await {
+                warn!(job_name=%name, "failed to delete job: {}", err);
+            }
+        }
+    });
+}
+
+/// like `get_events` but failures are logged rather than returned,
+/// since the events are only informational
+async fn try_get_events(worker: &Worker, client: Client, name: &str) -> Option<String> {
+    match get_events(worker, client, name).await {
+        Ok(events) => events,
+        Err(err) => {
+            warn!(pod_name=%name, "failed to get events for pod: {:#}", err);
+            None
+        }
+    }
+}
+
+/// check if the pod was killed by the node (eviction, spot reclaim, etc.)
+fn was_preempted(status: &PodStatus) -> bool {
+    let reason_preempted = status
+        .reason
+        .as_deref()
+        .map_or(false, |reason| PREEMPTED_REASONS.contains(&reason));
+
+    let disrupted = status
+        .conditions
+        .iter()
+        .flatten()
+        .any(|cond| cond.type_ == "DisruptionTarget" && cond.status == "True");
+
+    reason_preempted || disrupted
+}
+
+/// check if any container in the pod is stuck waiting for a reason that won't resolve itself
+fn fatal_waiting_reason(status: &PodStatus) -> Option<String> {
+    status
+        .init_container_statuses
+        .iter()
+        .chain(&status.container_statuses)
+        .flatten()
+        .filter_map(|cs| cs.state.as_ref()?.waiting.as_ref()?.reason.clone())
+        .find(|reason| FATAL_WAITING_REASONS.contains(&reason.as_str()))
+}
+
+/// whether the job succeeded, once it's finished
+fn job_finished(job: &Job) -> Option<bool> {
+    let conditions = job.status.as_ref()?.conditions.as_ref()?;
+    let is_true = |type_: &str| {
+        conditions
+            .iter()
+            .any(|cond| cond.type_ == type_ && cond.status == "True")
+    };
+
+    if is_true("Complete") {
+        Some(true)
+    } else if is_true("Failed") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Errors reaching the API server or from the server itself are worth retrying,
+/// others (eg. an invalid job) aren't
+fn api_error(err: kube::Error) -> anyhow::Error {
+    let transient = match &err {
+        kube::Error::Api(response) => response.code >= 500,
+        kube::Error::HyperError(_) | kube::Error::Service(_) => true,
+        _ => false,
+    };
+
+    if transient {
+        anyhow::Error::new(err).context(InfraError)
+    } else {
+        err.into()
+    }
+}
+
+/// how the task container terminated, if it has
+fn task_terminated(status: &PodStatus) -> Option<&ContainerStateTerminated> {
+    status
+        .container_statuses
+        .iter()
+        .flatten()
+        .find(|cs| cs.name == "task")?
+        .state
+        .as_ref()?
+        .terminated
+        .as_ref()
+}
+
+/// the result file the task container left in its termination message
+fn termination_message(status: &PodStatus) -> Option<&str> {
+    task_terminated(status)?.message.as_deref()
+}
+
+pub async fn run_kube(
+    worker: &Worker,
+    task_req: TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+) -> Result<TaskResult> {
     trace!("loading kubernetes config");
     let kube_config = Config::infer().await?;
     trace!("kubernetes namespace {}", kube_config.default_namespace);
     let client = Client::try_from(kube_config)?;
 
     trace!("connecting to kubernetes...");
-    let pods: Api<Pod> = Api::default_namespaced(client);
+    let jobs: Api<Job> = namespaced_api(worker, client.clone());
+    let pods: Api<Pod> = namespaced_api(worker, client.clone());
+
+    let job = make_job(worker, client.clone(), &task_req, task_def, deadline).await?;
+    let name = job.name_any();
 
-    let pod = make_pod(worker, &task_req, task_def).await?;
-    let name = pod.name_any();
+    // Create the job, Kubernetes creates its pods
+    trace!(job_name=%name, "creating job");
+    let _job = jobs
+        .create(&PostParams::default(), &job)
+        .await
+        .map_err(api_error)?;
+    trace!(job_name=%name, "created job");
 
-    // Create the pod
-    trace!(pod_name=%name, "creating pod");
-    let _pod = pods.create(&PostParams::default(), &pod).await?;
-    trace!(pod_name=%name, "created pod");
+    delete_on_kill(jobs.clone(), name.clone(), task_req.task_run_id);
 
-    let mut watcher = kube_runtime::watcher::watch_object(pods.clone(), &name).boxed();
+    // the job's conditions say when it's done, and its pods say why
+    let job_events = watch_object(jobs.clone(), &name).map_ok(Watched::Job);
+    let job_pods_params = ListParams::default().labels(&format!("job-name={name}"));
+    let pod_events = watcher::watcher(pods.clone(), job_pods_params.clone()).map_ok(Watched::Pods);
+    let mut events = futures::stream::select(job_events.boxed(), pod_events.boxed());
 
+    let mut job_pods = HashMap::new();
     let mut result = false;
+    let mut deleted = false;
+    let mut fatal_reason = None;
 
-    trace!(pod_name=%name, "watching pod");
+    trace!(job_name=%name, "watching job");
 
-    while let Some(maybe_pod) = watcher.try_next().await? {
-        match maybe_pod {
-            None => {
-                warn!(pod_name=%name, "pod was deleted externally");
-                anyhow::bail!("pod was deleted externally");
+    while let Some(event) = events.try_next().await? {
+        match event {
+            Watched::Job(None) => {
+                // most likely deleted by hand, its pods go with it
+                warn!(job_name=%name, "job was deleted externally");
+                deleted = true;
+                break;
             }
-            Some(pod) => {
-                let status = pod.status.as_ref().expect("status exists on pod");
-                let phase = status.phase.clone().unwrap_or_default();
-                trace!(pod_name=%pod.name_any(), "pod modified, phase is '{}'", phase);
+            Watched::Job(Some(job)) => {
+                trace!(job_name=%name, "job modified, status is '{:?}'", job.status);
 
-                if phase == "Succeeded" {
-                    result = true;
-                    break;
+                match job_finished(&job) {
+                    Some(succeeded) => {
+                        result = succeeded;
+                        break;
+                    }
+                    None => continue,
+                }
+            }
+            Watched::Pods(event) => {
+                let changed = match event {
+                    watcher::Event::Applied(pod) => vec![pod],
+                    watcher::Event::Restarted(pods) => pods,
+                    watcher::Event::Deleted(_) => continue,
+                };
+
+                let fatal = changed
+                    .iter()
+                    .filter_map(|pod| fatal_waiting_reason(pod.status.as_ref()?))
+                    .next();
+
+                for pod in changed {
+                    job_pods.insert(pod.name_any(), pod);
                 }
-                if phase == "Failed" {
+
+                // the job would keep waiting for these until its deadline
+                if let Some(reason) = fatal {
+                    warn!(job_name=%name, "pod can't start: {}", reason);
+                    fatal_reason = Some(reason);
                     break;
                 }
             }
         }
     }
 
-    let mut logs = pods
-        .log_stream(
-            &name,
-            &LogParams {
-                follow: true,
-                ..LogParams::default()
-            },
-        )
-        .await?;
-
-    let key = format!("waterwheel-logs.{}", task_req.task_run_id);
-    let mut redis = worker.redis_client.get_tokio_connection().await?;
-
-    trace!("sending kubernetes pod logs to {}", key);
-    while let Some(line) = logs.try_next().await? {
-        trace!("got log line ({} bytes)", line.len());
-        redis
-            .xadd_maxlen(
-                &key,
-                StreamMaxlen::Approx(1024),
-                "*",
-                &[("data", line.as_ref())],
-            )
-            .await?;
-        trace!("sent to redis");
+    // the job can finish before the pod watcher has seen its pods terminate, so
+    // their status is fetched again
+    if !deleted {
+        match pods.list(&job_pods_params).await {
+            Ok(list) => {
+                for pod in list.items {
+                    job_pods.insert(pod.name_any(), pod);
+                }
+            }
+            Err(err) => warn!(job_name=%name, "failed to list the job's pods: {}", err),
+        }
     }
 
-    let _: redis::Value = redis.expire(&key, worker.config.log_retention.try_into()?).await?;
-    drop(redis);
+    // the pod that ran last has the task's result, earlier ones were retried
+    let last_pod = job_pods
+        .into_values()
+        .max_by_key(|pod| pod.metadata.creation_timestamp.clone());
+    let last_pod_name = last_pod.as_ref().map(|pod| pod.name_any());
+    let last_status = last_pod.as_ref().and_then(|pod| pod.status.as_ref());
 
-    trace!(pod_name=%name, "deleting pod");
+    let preempted = deleted || (!result && last_status.map_or(false, was_preempted));
+    let terminated = last_status.and_then(task_terminated);
+    let exit_code = terminated.map(|terminated| terminated.exit_code);
+    let error_class = match (&fatal_reason, terminated) {
+        (Some(reason), _) if IMAGE_PULL_REASONS.contains(&reason.as_str()) => {
+            Some(ErrorClass::ImagePull)
+        }
+        (_, Some(terminated)) if terminated.reason.as_deref() == Some("OOMKilled") => {
+            Some(ErrorClass::OomKilled)
+        }
+        _ => None,
+    };
+    let result_file = match last_status.and_then(termination_message) {
+        Some(message) => ResultFile::parse(message.as_bytes()),
+        None => Ok(None),
+    };
+
+    let error_details = if result {
+        None
+    } else {
+        // grab the events before the job is deleted, they explain why it failed
+        let job_events = try_get_events(worker, client.clone(), &name).await;
+        let pod_events = match &last_pod_name {
+            Some(pod_name) => try_get_events(worker, client.clone(), pod_name).await,
+            None => None,
+        };
+        match (job_events, pod_events) {
+            (Some(job_events), Some(pod_events)) => Some(format!("{job_events}\n{pod_events}")),
+            (job_events, pod_events) => job_events.or(pod_events),
+        }
+    };
+
+    if let Some(pod_name) = &last_pod_name {
+        if !deleted {
+            let mut logs = pods
+                .log_stream(
+                    pod_name,
+                    &LogParams {
+                        container: Some("task".to_owned()),
+                        follow: true,
+                        ..LogParams::default()
+                    },
+                )
+                .await?;
+
+            let mut shipper = LogShipper::new(worker, task_req).await?;
+
+            trace!(%pod_name, "sending kubernetes pod logs");
+            while let Some(line) = logs.try_next().await? {
+                shipper.send(LogStream::Output, &line).await?;
+            }
 
-    match tokio::time::timeout(
-        DELETE_POD_TIMEOUT,
-        pods.delete(&name, &DeleteParams::default()),
-    )
-    .await
-    {
-        Ok(inner) => {
-            inner?;
+            shipper.finish().await?;
         }
-        Err(_) => {
-            warn!(pod_name=%name, "timeout while deleting pod");
+    }
+
+    if !deleted {
+        trace!(job_name=%name, "deleting job");
+
+        // background propagation deletes the job's pods too
+        match tokio::time::timeout(
+            DELETE_JOB_TIMEOUT,
+            jobs.delete(&name, &DeleteParams::background()),
+        )
+        .await
+        {
+            Ok(inner) => {
+                inner?;
+            }
+            Err(_) => {
+                warn!(job_name=%name, "timeout while deleting job");
+            }
         }
+        trace!(job_name=%name, "deleted job");
+    }
+
+    if preempted {
+        warn!(job_name=%name, "job was preempted");
+        return Ok(TaskResult::preempted(error_details));
     }
-    trace!(pod_name=%name, "deleted pod");
 
-    Ok(result)
+    Ok(TaskResult {
+        success: result,
+        error_details,
+        exit_code: exit_code.map(i64::from),
+        error_class,
+        ..TaskResult::default()
+    }
+    .with_result_file(result_file))
 }
 
 // TODO - make this a util, we should use this grist in a few other places too
@@ -139,25 +425,151 @@ fn make_grist() -> String {
     .join("")
 }
 
-async fn make_pod(worker: &Worker, task_req: &TaskRequest, task_def: TaskDef) -> Result<Pod> {
-    let env = env::get_env(worker, task_req, &task_def)?;
+/// The `resources` of a task's container, which requests what it's limited to.
+/// GPUs are only given as a limit, which Kubernetes also uses as the request.
+pub fn container_resources(resources: &TaskResources) -> serde_json::Value {
+    let mut quantities = serde_json::Map::new();
+    if let Some(cpu) = resources.cpu {
+        let millicores = (cpu * 1000.0).ceil() as u64;
+        quantities.insert("cpu".to_owned(), format!("{millicores}m").into());
+    }
+    if let Some(memory) = resources.memory {
+        quantities.insert("memory".to_owned(), memory.to_string().into());
+    }
+
+    let mut limits = quantities.clone();
+    if let Some(gpus) = resources.gpus {
+        limits.insert("nvidia.com/gpu".to_owned(), gpus.to_string().into());
+    }
+
+    serde_json::json!({
+        "requests": quantities,
+        "limits": limits,
+    })
+}
+
+/// Write the project's registry logins to a pull secret for its pods, returning
+/// its name. Each project has one, which is updated before every pod starts.
+pub async fn pull_secret(
+    worker: &Worker,
+    client: Client,
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+) -> Result<Option<String>> {
+    let credentials = all_credentials(worker, task_req, task_def).await?;
+    if credentials.is_empty() {
+        return Ok(None);
+    }
+
+    let auths = credentials
+        .into_iter()
+        .map(|creds| {
+            // the kubelet knows docker hub by its old address
+            let server = if creds.server == DEFAULT_REGISTRY {
+                "https://index.docker.io/v1/".to_owned()
+            } else {
+                creds.server
+            };
+            let auth = serde_json::json!({
+                "username": creds.username,
+                "password": creds.password,
+            });
+            (server, auth)
+        })
+        .collect::<serde_json::Map<_, _>>();
+
+    let name = format!("waterwheel-registry-{}", task_def.project_id);
+    let secret: Secret = serde_json::from_value(serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": {
+            "name": name,
+            "labels": {
+                "project_id": task_def.project_id,
+            },
+        },
+        "type": "kubernetes.io/dockerconfigjson",
+        "stringData": {
+            ".dockerconfigjson": serde_json::json!({ "auths": auths }).to_string(),
+        },
+    }))?;
+
+    trace!(secret_name=%name, "applying registry pull secret");
+
+    let secrets: Api<Secret> = namespaced_api(worker, client);
+    secrets
+        .patch(
+            &name,
+            &PatchParams::apply("waterwheel").force(),
+            &Patch::Apply(&secret),
+        )
+        .await?;
+
+    Ok(Some(name))
+}
+
+/// The task's own init containers, then its sidecars. Sidecars are init containers
+/// that keep running (which needs Kubernetes 1.29 or later), so they're started
+/// before the task and stopped once it exits, and the pod still succeeds.
+pub fn pod_init_containers(task_def: &TaskDef) -> Vec<serde_json::Value> {
+    let containers = match &task_def.pod_containers {
+        Some(containers) => containers,
+        None => return Vec::new(),
+    };
+
+    let sidecars = containers.sidecars.iter().cloned().map(|mut sidecar| {
+        sidecar["restartPolicy"] = "Always".into();
+        sidecar
+    });
+
+    containers
+        .init_containers
+        .iter()
+        .cloned()
+        .chain(sidecars)
+        .collect()
+}
+
+/// Make the task's job. Its pods are made the same way as the pods of earlier
+/// releases, so `kubernetes_pod_merge` still patches them.
+async fn make_job(
+    worker: &Worker,
+    client: Client,
+    task_req: &TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+) -> Result<Job> {
+    let pull_secret = pull_secret(worker, client, task_req, &task_def).await?;
+    let pull_policy = image_pull_policy(worker, &task_def).await?;
+
+    let mut env = env::get_env(worker, task_req, &task_def, deadline).await?;
+    env.push(env::envvar(RESULT_FILE_ENV, TERMINATION_MESSAGE_PATH));
+
+    let helper_image = worker.config.task_helper_image.as_ref();
+    if helper_image.is_some() {
+        env.push(env::envvar(HELPER_ENV, format!("{HELPER_DIR}/{HELPER_NAME}")));
+    }
+
+    let mut init_containers = Vec::new();
+    let task_init_containers = pod_init_containers(&task_def);
 
     let grist = make_grist();
     let name = format!("{}--{}", task_req.task_run_id, grist);
 
+    let labels = serde_json::json!({
+        "worker_id": *WORKER_ID,
+        "task_id": task_req.task_id,
+        "task_run_id": task_req.task_run_id,
+        "job_id": task_def.job_id,
+        "project_id": task_def.project_id,
+    });
+
     // Create a pod from JSON
     let mut pod_json = serde_json::json!({
         "apiVersion": "v1",
         "kind": "Pod",
         "metadata": {
-            "name": name,
-            "labels": {
-                "worker_id": *WORKER_ID,
-                "task_id": task_req.task_id,
-                "task_run_id": task_req.task_run_id,
-                "job_id": task_def.job_id,
-                "project_id": task_def.project_id,
-            },
+            "labels": labels,
         },
         "spec": {
             "containers": [
@@ -166,12 +578,46 @@ async fn make_pod(worker: &Worker, task_req: &TaskRequest, task_def: TaskDef) ->
                     "image": task_def.image.unwrap(),
                     "args": task_def.args,
                     "env": env,
+                    "terminationMessagePath": TERMINATION_MESSAGE_PATH,
                 },
             ],
             "restartPolicy": "Never",
         }
     });
 
+    if let Some(resources) = &task_def.resources {
+        pod_json["spec"]["containers"][0]["resources"] = container_resources(resources);
+    }
+
+    if let Some(name) = pull_secret {
+        pod_json["spec"]["imagePullSecrets"] = serde_json::json!([{ "name": name }]);
+    }
+
+    if let Some(policy) = pull_policy {
+        pod_json["spec"]["containers"][0]["imagePullPolicy"] = policy.kube_name().into();
+    }
+
+    // copy the helper into a volume shared with the task before it starts
+    if let Some(helper_image) = helper_image {
+        init_containers.push(serde_json::json!({
+            "name": "waterwheel-task",
+            "image": helper_image,
+            "command": [HELPER_NAME, "install", HELPER_DIR],
+            "volumeMounts": [{"name": "waterwheel-bin", "mountPath": HELPER_DIR}],
+        }));
+        pod_json["spec"]["volumes"] = serde_json::json!([
+            {"name": "waterwheel-bin", "emptyDir": {}},
+        ]);
+        pod_json["spec"]["containers"][0]["volumeMounts"] = serde_json::json!([
+            {"name": "waterwheel-bin", "mountPath": HELPER_DIR, "readOnly": true},
+        ]);
+    }
+
+    init_containers.extend(task_init_containers);
+    if !init_containers.is_empty() {
+        pod_json["spec"]["initContainers"] = init_containers.into();
+    }
+
     let config = get_project_config(worker, task_def.project_id).await?;
     let pod_merge = config.get("kubernetes_pod_merge");
 
@@ -180,8 +626,26 @@ async fn make_pod(worker: &Worker, task_req: &TaskRequest, task_def: TaskDef) ->
         json_patch::merge(&mut pod_json, json);
     }
 
-    trace!("pod json: {:#}", pod_json);
+    // the deadline is for the whole job, including any retried pods
+    let job_json = serde_json::json!({
+        "apiVersion": "batch/v1",
+        "kind": "Job",
+        "metadata": {
+            "name": name,
+            "labels": labels,
+        },
+        "spec": {
+            "backoffLimit": worker.config.kube_backoff_limit,
+            "activeDeadlineSeconds": seconds_until(deadline),
+            "template": {
+                "metadata": pod_json["metadata"],
+                "spec": pod_json["spec"],
+            },
+        },
+    });
+
+    trace!("job json: {:#}", job_json);
 
-    let pod = serde_json::from_value(pod_json)?;
-    Ok(pod)
+    let job = serde_json::from_value(job_json)?;
+    Ok(job)
 }
@@ -1,8 +1,18 @@
 use crate::{
     messages::{TaskDef, TaskRequest},
-    worker::{config_cache::get_project_config, engine::TaskEngineImpl, env, Worker, WORKER_ID},
+    worker::{
+        config_cache::get_project_config,
+        engine::{seconds_until, TaskEngineImpl, TaskResult},
+        env,
+        kube::{
+            container_resources, delete_on_kill, namespaced_api, pod_init_containers, pull_secret,
+        },
+        registry::image_pull_policy,
+        Worker, WORKER_ID,
+    },
 };
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use futures::{StreamExt, TryStreamExt};
 use k8s_openapi::api::batch::v1::Job;
 use kube::{
@@ -21,8 +31,10 @@ impl TaskEngineImpl for KubeJobEngine {
         worker: &Worker,
         task_req: TaskRequest,
         task_def: TaskDef,
-    ) -> Result<bool> {
-        run_kubejob(worker, task_req, task_def).await
+        deadline: DateTime<Utc>,
+    ) -> Result<TaskResult> {
+        let success = run_kubejob(worker, task_req, task_def, deadline).await?;
+        Ok(TaskResult::from_success(success))
     }
 }
 
@@ -30,6 +42,7 @@ pub async fn run_kubejob(
     worker: &Worker,
     task_req: TaskRequest,
     task_def: TaskDef,
+    deadline: DateTime<Utc>,
 ) -> Result<bool> {
     trace!("loading kubernetes config");
     let kube_config = Config::infer().await?;
@@ -37,14 +50,17 @@ pub async fn run_kubejob(
     let client = Client::try_from(kube_config)?;
 
     trace!("connecting to kubernetes...");
-    let jobs: Api<Job> = Api::default_namespaced(client);
+    let jobs: Api<Job> = namespaced_api(worker, client.clone());
 
-    let job = make_job(worker, task_req, task_def).await?;
+    let task_run_id = task_req.task_run_id;
+    let job = make_job(worker, client, task_req, task_def, deadline).await?;
 
     // Create the pod
     let job = jobs.create(&PostParams::default(), &job).await?;
     let name = job.name_any();
 
+    delete_on_kill(jobs.clone(), name.clone(), task_run_id);
+
     let mut watcher = kube_runtime::watcher::watch_object(jobs.clone(), &name).boxed();
 
     let mut result = false;
@@ -83,8 +99,17 @@ pub async fn run_kubejob(
 
 const ONE_HOUR: i64 = 60 * 60 * 24;
 
-async fn make_job(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -> Result<Job> {
-    let env = env::get_env(worker, &task_req, &task_def)?;
+async fn make_job(
+    worker: &Worker,
+    client: Client,
+    task_req: TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+) -> Result<Job> {
+    let pull_secret = pull_secret(worker, client, &task_req, &task_def).await?;
+    let pull_policy = image_pull_policy(worker, &task_def).await?;
+    let env = env::get_env(worker, &task_req, &task_def, deadline).await?;
+    let init_containers = pod_init_containers(&task_def);
     let name = task_req.task_run_id.to_string();
 
     let config = get_project_config(worker, task_def.project_id).await?;
@@ -111,6 +136,7 @@ async fn make_job(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) ->
         "metadata": meta,
         "spec": {
             "ttlSecondsAfterFinished": ttl,
+            "activeDeadlineSeconds": seconds_until(deadline),
             "template": {
                 "metadata": meta,
                 "spec": {
@@ -128,6 +154,25 @@ async fn make_job(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) ->
         }
     });
 
+    if let Some(resources) = &task_def.resources {
+        job_json["spec"]["template"]["spec"]["containers"][0]["resources"] =
+            container_resources(resources);
+    }
+
+    if let Some(name) = pull_secret {
+        job_json["spec"]["template"]["spec"]["imagePullSecrets"] =
+            serde_json::json!([{ "name": name }]);
+    }
+
+    if let Some(policy) = pull_policy {
+        job_json["spec"]["template"]["spec"]["containers"][0]["imagePullPolicy"] =
+            policy.kube_name().into();
+    }
+
+    if !init_containers.is_empty() {
+        job_json["spec"]["template"]["spec"]["initContainers"] = init_containers.into();
+    }
+
     if let Some(json) = job_merge {
         trace!("merging template: {:#} with patch: {:#}", job_json, json);
         json_patch::merge(&mut job_json, json);
@@ -0,0 +1,117 @@
+use crate::{
+    messages::{TaskDef, TaskRequest},
+    task_contract::ResultFile,
+    worker::{
+        engine::{TaskEngineImpl, TaskResult},
+        logs::{LogShipper, LogStream},
+        Worker,
+    },
+};
+use anyhow::{format_err, Context, Result};
+use aws_sdk_lambda::{model::InvocationType, types::Blob};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::{trace, warn};
+
+/// Invokes an AWS Lambda function and waits for its response, for small glue
+/// tasks that are already functions. The function's own logs stay in CloudWatch,
+/// the task's logs are its response.
+pub struct LambdaEngine;
+
+#[async_trait::async_trait]
+impl TaskEngineImpl for LambdaEngine {
+    async fn run_task(
+        &self,
+        worker: &Worker,
+        task_req: TaskRequest,
+        task_def: TaskDef,
+        _deadline: DateTime<Utc>,
+    ) -> Result<TaskResult> {
+        run_lambda(worker, task_req, task_def).await
+    }
+}
+
+/// what Lambda returns when a function throws or times out
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FunctionError {
+    error_type: Option<String>,
+    error_message: Option<String>,
+}
+
+async fn run_lambda(
+    worker: &Worker,
+    task_req: TaskRequest,
+    task_def: TaskDef,
+) -> Result<TaskResult> {
+    let invoke = task_def.lambda.expect("task has a lambda function");
+
+    let payload = match &invoke.payload {
+        Some(payload) => serde_json::to_vec(payload)?,
+        None => b"{}".to_vec(),
+    };
+
+    let aws_config = aws_config::load_from_env().await;
+    let lambda = aws_sdk_lambda::Client::new(&aws_config);
+
+    trace!(function=%invoke.function, qualifier=?invoke.qualifier, "invoking lambda function");
+
+    // a synchronous invoke waits for the function to finish, up to its own timeout
+    let output = lambda
+        .invoke()
+        .function_name(&invoke.function)
+        .set_qualifier(invoke.qualifier.clone())
+        .invocation_type(InvocationType::RequestResponse)
+        .payload(Blob::new(payload))
+        .send()
+        .await
+        .with_context(|| format!("invoking lambda function '{}'", invoke.function))?;
+
+    let response = output
+        .payload()
+        .map(|blob| blob.as_ref().to_vec())
+        .unwrap_or_default();
+
+    trace!(
+        function=%invoke.function,
+        version=?output.executed_version(),
+        bytes=response.len(),
+        "lambda function returned"
+    );
+
+    let mut shipper = LogShipper::new(worker, &task_req).await?;
+    shipper.send(LogStream::Output, &response).await?;
+    shipper.finish().await?;
+
+    if let Some(kind) = output.function_error() {
+        warn!(function=%invoke.function, kind, "lambda function failed");
+
+        let error_details = match serde_json::from_slice::<FunctionError>(&response) {
+            Ok(FunctionError {
+                error_type,
+                error_message,
+            }) => format!(
+                "{}: {}",
+                error_type.as_deref().unwrap_or(kind),
+                error_message.as_deref().unwrap_or("no message")
+            ),
+            Err(_) => format!("lambda function error ({kind})"),
+        };
+
+        return Ok(TaskResult {
+            success: false,
+            error_details: Some(error_details),
+            ..TaskResult::default()
+        });
+    }
+
+    // a function returning an object is treated like a result file, anything
+    // else (a string, or nothing) only goes to the logs
+    let result_file = match serde_json::from_slice::<serde_json::Value>(&response) {
+        Ok(value) if value.is_object() => ResultFile::parse(&response),
+        _ => Ok(None),
+    };
+
+    Ok(TaskResult::from_success(true)
+        .with_result_file(result_file.map_err(|err| format_err!("lambda response: {err:#}"))))
+}
@@ -0,0 +1,259 @@
+use crate::{
+    log_store::LogLine,
+    messages::TaskRequest,
+    server::api::jwt,
+    worker::{config_cache, Worker},
+};
+use anyhow::{format_err, Result};
+use chrono::Utc;
+use redis::{streams::StreamMaxlen, AsyncCommands};
+use reqwest::Url;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tokio::{fs::File, io::AsyncWriteExt};
+use tracing::{info, trace, warn};
+use uuid::Uuid;
+
+/// send lines to the log store once there are this many
+const BATCH_LINES: usize = 500;
+
+/// longer lines are split, so output without newlines doesn't sit in memory
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// or once it's been this long since the last batch, so a slow trickle of logs
+/// doesn't sit in memory
+const BATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// the target each line is logged with when `log_task_output` is set
+const TASK_OUTPUT_TARGET: &str = "waterwheel::task_output";
+
+/// which of a task's output streams some output came from
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+    /// the engine doesn't tell them apart, eg. Kubernetes pod logs
+    Output,
+}
+
+impl LogStream {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+            LogStream::Output => "output",
+        }
+    }
+}
+
+/// Sends a task's output to Redis as it's produced, so it can be tailed while
+/// the task runs, and in batches of lines to the log store, if there is one.
+/// With a log archive, the output is also kept in a file and uploaded whole
+/// once the task finishes.
+pub struct LogShipper<'a> {
+    worker: &'a Worker,
+    task_req: TaskRequest,
+    task_run_id: Uuid,
+    key: String,
+    redis: redis::aio::Connection,
+    /// the start of each stream's line that hasn't ended yet
+    partial: BTreeMap<LogStream, Vec<u8>>,
+    next_seq: i64,
+    batch: Vec<LogLine>,
+    last_batch: Instant,
+    archive_file: Option<(PathBuf, File)>,
+}
+
+impl<'a> LogShipper<'a> {
+    pub async fn new(worker: &'a Worker, task_req: &TaskRequest) -> Result<LogShipper<'a>> {
+        let archive_file = match &worker.log_archive {
+            Some(_) => create_archive_file(worker, task_req.task_run_id).await,
+            None => None,
+        };
+
+        Ok(LogShipper {
+            worker,
+            task_req: task_req.clone(),
+            task_run_id: task_req.task_run_id,
+            key: format!("waterwheel-logs.{}", task_req.task_run_id),
+            redis: worker.redis_client.get_tokio_connection().await?,
+            partial: BTreeMap::new(),
+            next_seq: 0,
+            batch: Vec::new(),
+            last_batch: Instant::now(),
+            archive_file,
+        })
+    }
+
+    /// some output, which doesn't have to be a whole line
+    pub async fn send(&mut self, stream: LogStream, data: &[u8]) -> Result<()> {
+        trace!("got log data ({} bytes)", data.len());
+        self.redis
+            .xadd_maxlen(
+                &self.key,
+                StreamMaxlen::Approx(1024),
+                "*",
+                &[("data", data)],
+            )
+            .await?;
+
+        if let Some((_, file)) = &mut self.archive_file {
+            if let Err(err) = file.write_all(data).await {
+                warn!(task_run_id=?self.task_run_id,
+                    "failed to write the log archive file: {}", err);
+                self.archive_file = None;
+            }
+        }
+
+        if self.worker.log_writer.is_none() && !self.worker.config.log_task_output {
+            return Ok(());
+        }
+
+        let mut partial = self.partial.remove(&stream).unwrap_or_default();
+        partial.extend_from_slice(data);
+        while let Some(end) = partial.iter().position(|&b| b == b'\n') {
+            let rest = partial.split_off(end + 1);
+            let line = std::mem::replace(&mut partial, rest);
+            self.push_line(stream, &line[..end]);
+        }
+        if partial.len() >= MAX_LINE_BYTES {
+            self.push_line(stream, &partial);
+        } else if !partial.is_empty() {
+            self.partial.insert(stream, partial);
+        }
+
+        if self.batch.len() >= BATCH_LINES || self.last_batch.elapsed() >= BATCH_INTERVAL {
+            self.write_batch().await;
+        }
+
+        Ok(())
+    }
+
+    /// once the task has finished, expire the logs in Redis and store the rest
+    pub async fn finish(mut self) -> Result<()> {
+        let _: redis::Value = self
+            .redis
+            .expire(&self.key, self.worker.config.log_retention.try_into()?)
+            .await?;
+
+        for (stream, line) in std::mem::take(&mut self.partial) {
+            self.push_line(stream, &line);
+        }
+        self.write_batch().await;
+
+        if let Some((path, file)) = self.archive_file.take() {
+            if let Err(err) = self.archive(&path, file).await {
+                warn!(task_run_id=?self.task_run_id, "failed to archive task logs: {:#}", err);
+            }
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                warn!(?path, "failed to remove the log archive file: {}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push_line(&mut self, stream: LogStream, line: &[u8]) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let log_line = LogLine::new(self.next_seq, line);
+        self.next_seq += 1;
+
+        if self.worker.config.log_task_output {
+            info!(target: TASK_OUTPUT_TARGET,
+                task_run_id=%self.task_run_id,
+                task_id=%self.task_req.task_id,
+                attempt=self.task_req.attempt,
+                stream=stream.as_str(),
+                timestamp=%Utc::now().to_rfc3339(),
+                seq=log_line.seq,
+                line=%log_line.line,
+                "task output");
+        }
+
+        if self.worker.log_writer.is_some() {
+            self.batch.push(log_line);
+        }
+    }
+
+    /// Losing logs isn't worth failing a task over, so errors are only logged.
+    async fn write_batch(&mut self) {
+        self.last_batch = Instant::now();
+
+        let writer = match &self.worker.log_writer {
+            Some(writer) if !self.batch.is_empty() => writer,
+            _ => return,
+        };
+
+        if let Err(err) = writer.write(self.task_run_id, &self.batch).await {
+            warn!(task_run_id=?self.task_run_id, lines=self.batch.len(),
+                "failed to store task logs: {:#}", err);
+        }
+        self.batch.clear();
+    }
+
+    /// upload the whole output to the log archive, and tell the server where it is
+    async fn archive(&self, path: &Path, mut file: File) -> Result<()> {
+        file.flush().await?;
+        drop(file);
+
+        let archive = self
+            .worker
+            .log_archive
+            .as_ref()
+            .expect("there's an archive file, so there's an archive");
+        let task_def = config_cache::get_task_def(self.worker, self.task_req.task_id)
+            .await?
+            .ok_or_else(|| format_err!("task not found"))?;
+
+        let location = archive
+            .upload(&archive.key(&task_def, &self.task_req), path)
+            .await?;
+        trace!(task_run_id=?self.task_run_id, %location, "archived task logs");
+
+        let task_run_id = self.task_run_id;
+        let url = Url::parse(self.worker.config.internal_addr())?
+            .join(&format!("int-api/task_runs/{task_run_id}/logs/archive"))?;
+        let token = jwt::generate_logs_jwt(&self.worker.jwt_keys, task_run_id)?;
+
+        self.worker
+            .internal_client
+            .put(url)
+            .bearer_auth(token)
+            .json(&serde_json::json!({ "location": location }))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for LogShipper<'_> {
+    /// a task that failed before its logs were finished doesn't leave its file behind
+    fn drop(&mut self) {
+        if let Some((path, _)) = self.archive_file.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Losing the archive isn't worth failing a task over, so it's only logged.
+async fn create_archive_file(worker: &Worker, task_run_id: Uuid) -> Option<(PathBuf, File)> {
+    let path = worker
+        .config
+        .task_result_dir
+        .as_ref()
+        .map_or_else(std::env::temp_dir, PathBuf::from)
+        .join(format!("waterwheel-{task_run_id}.log"));
+
+    match File::create(&path).await {
+        Ok(file) => Some((path, file)),
+        Err(err) => {
+            warn!(?path, "failed to create the log archive file: {}", err);
+            None
+        }
+    }
+}
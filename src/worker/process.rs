@@ -0,0 +1,172 @@
+use crate::{
+    messages::{ErrorClass, TaskDef, TaskRequest},
+    task_contract::{HELPER_ENV, RESULT_FILE_ENV, RESULT_FILE_NAME, WORK_DIR_ENV},
+    worker::{
+        docker::read_result_file,
+        engine::{TaskEngineImpl, TaskResult},
+        env,
+        logs::{LogShipper, LogStream},
+        shutdown,
+        staging::WorkDir,
+        Worker,
+    },
+};
+use anyhow::{format_err, Context, Result};
+use chrono::{DateTime, Utc};
+use std::{path::PathBuf, process::Stdio};
+use tokio::{io::AsyncReadExt, process::Command};
+use tracing::{trace, warn};
+
+/// Runs tasks as processes on the worker's own host, with no container runtime.
+/// A task with a `process` runs its command, and on a worker whose engine is
+/// `process` other tasks run their first arg as the command (their image is
+/// ignored).
+pub struct ProcessEngine;
+
+#[async_trait::async_trait]
+impl TaskEngineImpl for ProcessEngine {
+    async fn run_task(
+        &self,
+        worker: &Worker,
+        task_req: TaskRequest,
+        task_def: TaskDef,
+        deadline: DateTime<Utc>,
+    ) -> Result<TaskResult> {
+        run_process(worker, task_req, task_def, deadline).await
+    }
+}
+
+async fn run_process(
+    worker: &Worker,
+    task_req: TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+) -> Result<TaskResult> {
+    let env = env::get_env(worker, &task_req, &task_def, deadline).await?;
+
+    let work_dir = match WorkDir::stage(worker, &task_req, &task_def).await {
+        Ok(work_dir) => work_dir,
+        Err(err) => {
+            warn!("failed to stage task inputs: {:#}", err);
+            return Ok(TaskResult::failed(ErrorClass::Staging, format!("{err:#}")));
+        }
+    };
+
+    let mut args = task_def.args;
+    let program = match task_def.command {
+        Some(command) => command,
+        None if !args.is_empty() => args.remove(0),
+        None => return Err(format_err!("the task has no command to run")),
+    };
+
+    let result_dir = worker
+        .config
+        .task_result_dir
+        .as_ref()
+        .map_or_else(std::env::temp_dir, PathBuf::from)
+        .join(format!("waterwheel-{}", task_req.task_run_id));
+    tokio::fs::create_dir_all(&result_dir).await?;
+
+    let mut cmd = Command::new(&program);
+    cmd.args(&args)
+        .envs(
+            env.iter()
+                .map(|ev| (&ev.name, ev.value.as_deref().unwrap_or_default())),
+        )
+        .env(RESULT_FILE_ENV, result_dir.join(RESULT_FILE_NAME))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // the process is killed if the worker gives up on the task, eg. at its timeout
+        .kill_on_drop(true);
+
+    if let Some(helper) = &worker.config.task_helper_path {
+        cmd.env(HELPER_ENV, helper);
+    }
+
+    if let Some(work_dir) = &work_dir {
+        cmd.env(WORK_DIR_ENV, work_dir.path())
+            .current_dir(work_dir.path());
+    }
+
+    trace!(?program, ?args, "starting process");
+
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("starting '{program}'"))?;
+
+    let mut stdout = child.stdout.take().expect("process stdout is piped");
+    let mut stderr = child.stderr.take().expect("process stderr is piped");
+
+    let mut shipper = LogShipper::new(worker, &task_req).await?;
+
+    let stop_delay = (deadline - Utc::now()).to_std().unwrap_or_default();
+    let stop_timer = tokio::time::sleep(stop_delay);
+    tokio::pin!(stop_timer);
+
+    let mut stdout_buf = vec![0; 8192];
+    let mut stderr_buf = vec![0; 8192];
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+    let mut killed = false;
+
+    trace!(task_run_id=?task_req.task_run_id, "sending process output");
+    while stdout_open || stderr_open {
+        tokio::select! {
+            read = stdout.read(&mut stdout_buf), if stdout_open => match read? {
+                0 => stdout_open = false,
+                n => shipper.send(LogStream::Stdout, &stdout_buf[..n]).await?,
+            },
+            read = stderr.read(&mut stderr_buf), if stderr_open => match read? {
+                0 => stderr_open = false,
+                n => shipper.send(LogStream::Stderr, &stderr_buf[..n]).await?,
+            },
+            _ = &mut stop_timer, if !killed => {
+                warn!(?program, "task deadline reached, killing process");
+                child.start_kill()?;
+                killed = true;
+            }
+        }
+    }
+
+    shipper.finish().await?;
+
+    let status = child.wait().await?;
+    trace!(%status, "process exited");
+
+    let result_file = read_result_file(&result_dir).await;
+
+    // no exit code means it was killed by a signal
+    let exit_code = match status.code() {
+        Some(code) => code,
+        None if shutdown::is_shutting_down() => {
+            warn!(?program, "process killed while the host is shutting down");
+            return Ok(TaskResult::preempted(Some(format!(
+                "process killed during host shutdown ({status})"
+            ))));
+        }
+        None => {
+            return Ok(TaskResult {
+                success: false,
+                error_details: Some(if killed {
+                    "process killed at the task's deadline".to_owned()
+                } else {
+                    format!("process killed ({status})")
+                }),
+                ..TaskResult::default()
+            });
+        }
+    };
+
+    if let Some(work_dir) = work_dir.filter(|_| exit_code == 0) {
+        if let Err(err) = work_dir.upload_outputs().await {
+            warn!("failed to upload task outputs: {:#}", err);
+            return Ok(TaskResult::failed(ErrorClass::Staging, format!("{err:#}"))
+                .with_exit_code(exit_code.into()));
+        }
+    }
+
+    Ok(TaskResult::from_success(exit_code == 0)
+        .with_exit_code(exit_code.into())
+        .with_result_file(result_file))
+}
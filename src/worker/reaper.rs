@@ -0,0 +1,102 @@
+use crate::{
+    server::api::jwt,
+    worker::{kube::namespaced_api, Worker, WORKER_ID},
+};
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use k8s_openapi::api::batch::v1::Job;
+use kube::{
+    api::{Api, DeleteParams, ListParams},
+    Client, Config, ResourceExt,
+};
+use std::{collections::HashMap, convert::TryFrom, sync::Arc};
+use tracing::{debug, trace, warn};
+use uuid::Uuid;
+
+/// Jobs younger than this are left alone, since their task run may not have
+/// been marked as running yet
+const MIN_ORPHAN_AGE_MINS: i64 = 10;
+
+/// Delete the Kubernetes jobs left behind by workers that died while running
+/// them. Any worker can reap any job, since whether it's an orphan is decided
+/// by its task run rather than by the worker that created it.
+pub async fn reap_orphaned_jobs(worker: Arc<Worker>) -> Result<!> {
+    let client = Client::try_from(Config::infer().await?)?;
+    let jobs: Api<Job> = namespaced_api(&worker, client);
+
+    let interval = std::time::Duration::from_secs(worker.config.kube_reaper_interval);
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        if let Err(err) = reap(&worker, &jobs).await {
+            warn!("failed to reap orphaned jobs: {:#}", err);
+        }
+    }
+}
+
+async fn reap(worker: &Worker, jobs: &Api<Job>) -> Result<()> {
+    // `kubernetesjobs` jobs have no task run label, they're left to their TTL
+    let list = jobs
+        .list(&ListParams::default().labels("worker_id,task_run_id"))
+        .await?;
+
+    let cutoff = Utc::now() - Duration::minutes(MIN_ORPHAN_AGE_MINS);
+    let candidates = list
+        .items
+        .iter()
+        .filter(|job| {
+            job.creation_timestamp()
+                .map_or(false, |created| created.0 < cutoff)
+        })
+        .filter_map(|job| {
+            let task_run_id = job.labels().get("task_run_id")?.parse::<Uuid>().ok()?;
+            Some((task_run_id, job.name_any()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    trace!("checking {} jobs for orphans", candidates.len());
+
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    let orphaned = orphaned_task_runs(worker, candidates.keys().copied().collect()).await?;
+
+    for task_run_id in orphaned {
+        let name = match candidates.get(&task_run_id) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        warn!(job_name=%name, ?task_run_id, "deleting orphaned job");
+
+        // another worker may have got there first
+        if let Err(err) = jobs.delete(name, &DeleteParams::background()).await {
+            debug!(job_name=%name, "failed to delete orphaned job: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// ask the server which of these task runs are no longer running
+async fn orphaned_task_runs(worker: &Worker, task_run_ids: Vec<Uuid>) -> Result<Vec<Uuid>> {
+    let url =
+        reqwest::Url::parse(worker.config.internal_addr())?.join("int-api/task_runs/orphaned")?;
+
+    let orphaned = worker
+        .internal_client
+        .post(url)
+        .timeout(std::time::Duration::from_secs(10))
+        .bearer_auth(jwt::generate_worker_jwt(&worker.jwt_keys, *WORKER_ID)?)
+        .json(&task_run_ids)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(orphaned)
+}
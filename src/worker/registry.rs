@@ -0,0 +1,138 @@
+use crate::{
+    messages::{ImagePullPolicy, SecretRef, TaskDef, TaskRequest},
+    worker::{config_cache::get_project_config, secrets::resolve_secret, Worker},
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tracing::trace;
+
+/// the registry of images that don't name one, eg. `bash:latest`
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// A registry login from the `docker_registries` key of a project's config. The
+/// password is a secret reference, so it's kept in a secret store rather than
+/// in the config.
+#[derive(Deserialize, Debug)]
+pub struct RegistryAuth {
+    /// the registry's host, eg. `ghcr.io` or `registry.example.com:5000`
+    pub server: String,
+    pub username: String,
+    pub password: SecretRef,
+}
+
+/// a registry login with its password read
+#[derive(Debug)]
+pub struct RegistryCredentials {
+    pub server: String,
+    pub username: String,
+    pub password: String,
+}
+
+/// The registry an image is pulled from. Like docker, the first part of the
+/// name is only a registry if it looks like a host.
+pub fn image_registry(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((first, _)) if first.contains(['.', ':']) || first == "localhost" => first,
+        _ => DEFAULT_REGISTRY,
+    }
+}
+
+/// The task's image pull policy, or else the `image_pull_policy` in its
+/// project's config. `None` leaves it to the engine's default.
+pub async fn image_pull_policy(
+    worker: &Worker,
+    task_def: &TaskDef,
+) -> Result<Option<ImagePullPolicy>> {
+    if task_def.image_pull_policy.is_some() {
+        return Ok(task_def.image_pull_policy);
+    }
+
+    let config = get_project_config(worker, task_def.project_id).await?;
+
+    match config.get("image_pull_policy") {
+        Some(policy) => serde_json::from_value(policy.clone())
+            .context("invalid image_pull_policy in the project config"),
+        None => Ok(None),
+    }
+}
+
+/// the registry logins in a project's config
+async fn project_registries(worker: &Worker, task_def: &TaskDef) -> Result<Vec<RegistryAuth>> {
+    let config = get_project_config(worker, task_def.project_id).await?;
+
+    match config.get("docker_registries") {
+        Some(registries) => serde_json::from_value(registries.clone())
+            .context("invalid docker_registries in the project config"),
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn resolve(
+    worker: &Worker,
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+    auth: RegistryAuth,
+) -> Result<RegistryCredentials> {
+    trace!(server=%auth.server, username=%auth.username, "reading registry password");
+
+    let password = resolve_secret(worker, task_req, task_def, &auth.password)
+        .await
+        .with_context(|| format!("reading the password for registry '{}'", auth.server))?;
+
+    Ok(RegistryCredentials {
+        server: auth.server,
+        username: auth.username,
+        password,
+    })
+}
+
+/// the project's login for the registry of an image, if it has one
+pub async fn credentials_for_image(
+    worker: &Worker,
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+    image: &str,
+) -> Result<Option<RegistryCredentials>> {
+    let registry = image_registry(image);
+
+    let auth = project_registries(worker, task_def)
+        .await?
+        .into_iter()
+        .find(|auth| auth.server == registry);
+
+    match auth {
+        Some(auth) => Ok(Some(resolve(worker, task_req, task_def, auth).await?)),
+        None => Ok(None),
+    }
+}
+
+/// every login in the project's config, eg. for a Kubernetes pull secret
+pub async fn all_credentials(
+    worker: &Worker,
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+) -> Result<Vec<RegistryCredentials>> {
+    let mut credentials = Vec::new();
+    for auth in project_registries(worker, task_def).await? {
+        credentials.push(resolve(worker, task_req, task_def, auth).await?);
+    }
+
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_image_registry() {
+        assert_eq!(image_registry("bash:latest"), "docker.io");
+        assert_eq!(image_registry("library/bash"), "docker.io");
+        assert_eq!(image_registry("ghcr.io/org/image:v1"), "ghcr.io");
+        assert_eq!(
+            image_registry("registry.example.com:5000/image"),
+            "registry.example.com:5000"
+        );
+        assert_eq!(image_registry("localhost/image"), "localhost");
+    }
+}
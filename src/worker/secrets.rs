@@ -0,0 +1,95 @@
+use crate::{
+    messages::{SecretRef, TaskDef, TaskRequest},
+    secrets,
+    server::api::jwt,
+    worker::Worker,
+};
+use anyhow::{format_err, Result};
+use std::time::Duration;
+use tracing::trace;
+
+/// read the value of a secret referenced by a task's env
+pub async fn resolve_secret(
+    worker: &Worker,
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+    secret_ref: &SecretRef,
+) -> Result<String> {
+    trace!(task_id=?task_req.task_id, "resolving {}", secret_ref);
+
+    // checked when the job was submitted too, but the prefixes may have changed since
+    secrets::check_scope(&worker.config, &task_def.project_name, secret_ref)?;
+
+    match secret_ref {
+        SecretRef::Global { key } => {
+            let path = stash_path(task_req, task_def, "global", key)?;
+            read_stash_string(worker, task_req, &path).await
+        }
+        SecretRef::Project { key } => {
+            let path = stash_path(task_req, task_def, "project", key)?;
+            read_stash_string(worker, task_req, &path).await
+        }
+        SecretRef::Job { key } => {
+            let path = stash_path(task_req, task_def, "job", key)?;
+            read_stash_string(worker, task_req, &path).await
+        }
+        SecretRef::Vault { path, field } => {
+            secrets::read_vault(
+                worker.config.vault_addr.as_deref(),
+                worker.config.vault_token.as_deref(),
+                path,
+                field,
+            )
+            .await
+        }
+        SecretRef::Aws { secret_id, field } => secrets::read_aws(secret_id, field.as_deref()).await,
+        SecretRef::Kubernetes { name, key } => {
+            secrets::read_kube(worker.config.kube_namespace.as_deref(), name, key).await
+        }
+    }
+}
+
+/// the internal API path of a key in the `global`, `project` or `job` stash
+pub fn stash_path(
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+    stash: &str,
+    key: &str,
+) -> Result<String> {
+    match stash {
+        "global" => Ok(format!("stash/{key}")),
+        "project" => Ok(format!("projects/{}/stash/{key}", task_def.project_id)),
+        "job" => Ok(format!(
+            "jobs/{}/stash/{}/{key}",
+            task_def.job_id,
+            task_req.trigger_datetime.to_rfc3339()
+        )),
+        _ => Err(format_err!("unknown stash '{stash}'")),
+    }
+}
+
+async fn read_stash_string(worker: &Worker, task_req: &TaskRequest, path: &str) -> Result<String> {
+    let data = fetch_stash(worker, task_req, path).await?;
+    String::from_utf8(data).map_err(|_| format_err!("stash item is not valid UTF-8"))
+}
+
+/// read a stash item the same way the task itself would, using a stash JWT for the task
+pub async fn fetch_stash(worker: &Worker, task_req: &TaskRequest, path: &str) -> Result<Vec<u8>> {
+    let token = "Bearer ".to_owned()
+        + &jwt::generate_stash_jwt(&worker.jwt_keys, &task_req.task_id.to_string())?;
+
+    let url = reqwest::Url::parse(worker.config.internal_addr())?
+        .join("int-api/")?
+        .join(path)?;
+
+    let resp = worker
+        .internal_client
+        .get(url)
+        .timeout(Duration::from_secs(10))
+        .header(reqwest::header::AUTHORIZATION, token)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(resp.bytes().await?.to_vec())
+}
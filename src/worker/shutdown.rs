@@ -0,0 +1,88 @@
+use crate::worker::{control, heartbeat, Worker, RUNNING_TASKS};
+use anyhow::Result;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// how long to wait for killed tasks to report back once the grace period is over
+const REPORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// true once the worker has been told to shut down (eg. the host is a spot
+/// instance being reclaimed). Tasks killed after this point are preempted,
+/// not failed.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// wait until no tasks are running, or the timeout, returning true if they've all finished
+async fn wait_for_tasks(timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while RUNNING_TASKS.get() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    RUNNING_TASKS.get() == 0
+}
+
+#[cfg(unix)]
+async fn terminated() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::terminate())?.recv().await;
+    Ok(())
+}
+
+/// Windows has no SIGTERM, a service or container being stopped is a shutdown event
+#[cfg(windows)]
+async fn terminated() -> Result<()> {
+    let mut shutdown = tokio::signal::windows::ctrl_shutdown()?;
+
+    tokio::select! {
+        _ = shutdown.recv() => Ok(()),
+        res = tokio::signal::ctrl_c() => Ok(res?),
+    }
+}
+
+/// On SIGTERM (or a shutdown event on Windows) stop taking tasks and give the running ones the grace period to
+/// finish. Any still running after that are stopped and reported preempted, so
+/// they're retried on another worker. Then tell the server this worker is gone
+/// and exit.
+pub async fn watch_for_shutdown(worker: Arc<Worker>) -> Result<!> {
+    terminated().await?;
+
+    warn!(
+        running_tasks = RUNNING_TASKS.get(),
+        "asked to shut down, no new tasks will be taken"
+    );
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+
+    // the work loops stop consuming, and give back any task they haven't started
+    worker.live_config.send_modify(|live| live.draining = true);
+
+    let grace_period = Duration::from_secs(worker.config.worker_shutdown_grace_period);
+    if !wait_for_tasks(grace_period).await {
+        warn!(
+            running_tasks = RUNNING_TASKS.get(),
+            "shutdown grace period is over, stopping running tasks"
+        );
+        control::kill_all();
+
+        if !wait_for_tasks(REPORT_TIMEOUT).await {
+            warn!(
+                running_tasks = RUNNING_TASKS.get(),
+                "tasks didn't report back, they'll be requeued"
+            );
+        }
+    }
+
+    if let Err(err) = heartbeat::post_gone(&worker).await {
+        warn!("failed to tell the server it's gone: {:#}", err);
+    }
+
+    info!("worker shutting down");
+    std::process::exit(0);
+}
@@ -0,0 +1,251 @@
+use crate::{
+    messages::{is_valid_staged_path, StagedOutput, TaskDef, TaskRequest},
+    worker::{secrets, Worker},
+};
+use anyhow::{format_err, Context, Result};
+use aws_sdk_s3::{types::ByteStream, Client};
+use std::path::{Path, PathBuf};
+use tracing::{trace, warn};
+
+/// Let a task use a directory the worker made, since it may not run as the same user
+#[cfg(unix)]
+pub async fn share_dir(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o777)).await?;
+    Ok(())
+}
+
+/// containers on Windows can use whatever the worker can
+#[cfg(windows)]
+pub async fn share_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// A task's working directory on the worker. Its inputs are downloaded into it
+/// before it starts, and its outputs uploaded from it once it succeeds. It's
+/// removed when dropped, however the task ends.
+pub struct WorkDir {
+    path: PathBuf,
+    outputs: Vec<StagedOutput>,
+}
+
+impl WorkDir {
+    /// create the working directory and download the task's inputs into it,
+    /// if the task stages any files
+    pub async fn stage(
+        worker: &Worker,
+        task_req: &TaskRequest,
+        task_def: &TaskDef,
+    ) -> Result<Option<Self>> {
+        let staging = match &task_def.staging {
+            Some(staging) => staging,
+            None => return Ok(None),
+        };
+
+        let path = worker
+            .config
+            .task_result_dir
+            .as_ref()
+            .map_or_else(std::env::temp_dir, PathBuf::from)
+            .join(format!("waterwheel-{}-work", task_req.task_run_id));
+        tokio::fs::create_dir_all(&path).await?;
+        share_dir(&path).await?;
+
+        // made before downloading, so a failed download doesn't leave the directory behind
+        let work_dir = WorkDir {
+            path,
+            outputs: staging.outputs.clone(),
+        };
+
+        let s3 = s3_client().await;
+        for input in &staging.inputs {
+            // the server checks this too, but the path ends up on the worker's disk
+            let path = input.path();
+            if !is_valid_staged_path(path) {
+                return Err(format_err!(
+                    "input '{}' has an invalid path '{path}'",
+                    input.uri
+                ));
+            }
+            let dest = work_dir.path.join(path);
+
+            trace!(uri=%input.uri, dest=%dest.display(), "downloading input");
+            let data = download(worker, &s3, task_req, task_def, &input.uri)
+                .await
+                .with_context(|| format!("failed to download '{}'", input.uri))?;
+
+            if let Some(parent) = dest.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(&dest, data).await?;
+        }
+
+        Ok(Some(work_dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// upload the files matching each of the task's output globs
+    pub async fn upload_outputs(&self) -> Result<()> {
+        if self.outputs.is_empty() {
+            return Ok(());
+        }
+
+        let files = list_files(&self.path).await?;
+        let s3 = s3_client().await;
+
+        for output in &self.outputs {
+            let (bucket, prefix) = parse_s3_uri(&output.uri)?;
+            let prefix = match prefix {
+                "" => String::new(),
+                prefix => format!("{}/", prefix.trim_end_matches('/')),
+            };
+
+            for file in files.iter().filter(|file| glob_match(&output.glob, file)) {
+                let key = format!("{prefix}{file}");
+                trace!(%file, "uploading output to s3://{bucket}/{key}");
+
+                s3.put_object()
+                    .bucket(bucket)
+                    .key(&key)
+                    .body(ByteStream::from_path(self.path.join(file)).await?)
+                    .send()
+                    .await
+                    .with_context(|| format!("failed to upload '{file}' to s3://{bucket}/{key}"))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.path) {
+            warn!(work_dir=%self.path.display(), "failed to remove task working dir: {}", err);
+        }
+    }
+}
+
+async fn s3_client() -> Client {
+    let aws_config = aws_config::load_from_env().await;
+    Client::new(&aws_config)
+}
+
+async fn download(
+    worker: &Worker,
+    s3: &Client,
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+    uri: &str,
+) -> Result<Vec<u8>> {
+    if uri.starts_with("https://") || uri.starts_with("http://") {
+        let resp = reqwest::get(uri).await?.error_for_status()?;
+        Ok(resp.bytes().await?.to_vec())
+    } else if uri.starts_with("s3://") {
+        let (bucket, key) = parse_s3_uri(uri)?;
+        let object = s3.get_object().bucket(bucket).key(key).send().await?;
+        Ok(object.body.collect().await?.into_bytes().to_vec())
+    } else if let Some(location) = uri.strip_prefix("stash://") {
+        // fetched with the task's own access, eg. `stash://project/model.bin`
+        let (stash, key) = location
+            .split_once('/')
+            .ok_or_else(|| format_err!("stash URIs are stash://<stash>/<key>"))?;
+        let path = secrets::stash_path(task_req, task_def, stash, key)?;
+        secrets::fetch_stash(worker, task_req, &path).await
+    } else {
+        Err(format_err!("unsupported URI scheme"))
+    }
+}
+
+fn parse_s3_uri(uri: &str) -> Result<(&str, &str)> {
+    uri.strip_prefix("s3://")
+        .map(|location| location.split_once('/').unwrap_or((location, "")))
+        .filter(|(bucket, _)| !bucket.is_empty())
+        .ok_or_else(|| format_err!("invalid S3 URI '{uri}'"))
+}
+
+/// every file under the directory, by its path relative to it
+async fn list_files(root: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                let path = entry.path();
+                let relative = path.strip_prefix(root)?.to_string_lossy().into_owned();
+                files.push(relative);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// `*` and `?` match within one directory, `**` matches across any number of them
+fn glob_match(pattern: &str, path: &str) -> bool {
+    if let Some(rest) = pattern.strip_prefix("**/") {
+        return glob_match(rest, path)
+            || path
+                .match_indices('/')
+                .any(|(i, _)| glob_match(rest, &path[i + 1..]));
+    }
+    if let Some(rest) = pattern.strip_prefix("**") {
+        return (0..=path.len()).any(|i| path.is_char_boundary(i) && glob_match(rest, &path[i..]));
+    }
+
+    let mut chars = pattern.chars();
+    match chars.next() {
+        None => path.is_empty(),
+        Some('*') => {
+            let end = path.find('/').unwrap_or(path.len());
+            (0..=end).any(|i| path.is_char_boundary(i) && glob_match(chars.as_str(), &path[i..]))
+        }
+        Some('?') => {
+            let mut path_chars = path.chars();
+            matches!(path_chars.next(), Some(c) if c != '/')
+                && glob_match(chars.as_str(), path_chars.as_str())
+        }
+        Some(c) => path
+            .strip_prefix(c)
+            .map_or(false, |path| glob_match(chars.as_str(), path)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("report.csv", "report.csv"));
+        assert!(glob_match("*.csv", "report.csv"));
+        assert!(!glob_match("*.csv", "out/report.csv"));
+        assert!(glob_match("out/*.csv", "out/report.csv"));
+        assert!(glob_match("out/part-?.csv", "out/part-1.csv"));
+        assert!(!glob_match("out/part-?.csv", "out/part-10.csv"));
+        assert!(glob_match("**/*.csv", "report.csv"));
+        assert!(glob_match("**/*.csv", "out/2022/report.csv"));
+        assert!(!glob_match("**/report.csv", "out/myreport.csv"));
+        assert!(glob_match("out/**", "out/2022/report.csv"));
+        assert!(!glob_match("out/**", "other/report.csv"));
+    }
+
+    #[test]
+    fn test_parse_s3_uri() {
+        assert_eq!(
+            parse_s3_uri("s3://bucket/data/in.csv").unwrap(),
+            ("bucket", "data/in.csv")
+        );
+        assert_eq!(parse_s3_uri("s3://bucket").unwrap(), ("bucket", ""));
+        assert!(parse_s3_uri("https://bucket/key").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::{
+    messages::{TaskDef, TaskRequest},
+    worker::{
+        engine::{TaskEngineImpl, TaskResult},
+        env,
+        logs::{LogShipper, LogStream},
+        Worker,
+    },
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use tracing::{trace, warn};
+use wasi_common::pipe::WritePipe;
+use wasmtime::{Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+use wasmtime_wasi::{sync::WasiCtxBuilder, WasiCtx};
+
+/// WASM tasks are meant to be tiny, so keep their memory use well below a container's
+const MAX_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+
+/// Runs WASI modules in-process, avoiding container startup for sub-second tasks.
+/// Modules get their args and environment but no filesystem or network access.
+pub struct WasmEngine;
+
+#[async_trait::async_trait]
+impl TaskEngineImpl for WasmEngine {
+    async fn run_task(
+        &self,
+        worker: &Worker,
+        task_req: TaskRequest,
+        task_def: TaskDef,
+        deadline: DateTime<Utc>,
+    ) -> Result<TaskResult> {
+        run_wasm(worker, task_req, task_def, deadline).await
+    }
+}
+
+struct TaskState {
+    wasi: WasiCtx,
+    limits: StoreLimits,
+}
+
+async fn load_module(location: &str) -> Result<Vec<u8>> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let resp = reqwest::get(location).await?.error_for_status()?;
+        Ok(resp.bytes().await?.to_vec())
+    } else {
+        Ok(tokio::fs::read(location).await?)
+    }
+}
+
+async fn run_wasm(
+    worker: &Worker,
+    task_req: TaskRequest,
+    task_def: TaskDef,
+    deadline: DateTime<Utc>,
+) -> Result<TaskResult> {
+    let location = task_def.wasm_module.clone().expect("task has a wasm module");
+    let env: Vec<(String, String)> = env::get_env(worker, &task_req, &task_def, deadline)
+        .await?
+        .into_iter()
+        .map(|ev| (ev.name, ev.value.unwrap_or_default()))
+        .collect();
+
+    // WASI programs expect the program name as the first arg
+    let mut args = vec![task_def.task_name.clone()];
+    args.extend(task_def.args.iter().cloned());
+
+    trace!(module=%location, "loading wasm module");
+    let bytes = load_module(&location).await?;
+
+    let mut config = wasmtime::Config::new();
+    config.epoch_interruption(true);
+    let engine = Engine::new(&config)?;
+
+    // interrupt the module if it's still running at the deadline
+    let timer_engine = engine.clone();
+    let timer = tokio::spawn(async move {
+        let remaining = (deadline - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(remaining).await;
+        timer_engine.increment_epoch();
+    });
+
+    let output = WritePipe::new_in_memory();
+    let run_output = output.clone();
+
+    // wasmtime is synchronous, so run the module off the async executor
+    let result = tokio::task::spawn_blocking(move || -> Result<i32> {
+        let module = Module::new(&engine, &bytes)?;
+
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |state: &mut TaskState| &mut state.wasi)?;
+
+        let wasi = WasiCtxBuilder::new()
+            .args(&args)?
+            .envs(&env)?
+            .stdout(Box::new(run_output.clone()))
+            .stderr(Box::new(run_output))
+            .build();
+
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).build();
+        let mut store = Store::new(&engine, TaskState { wasi, limits });
+        store.limiter(|state| &mut state.limits);
+        store.set_epoch_deadline(1);
+
+        linker.module(&mut store, "", &module)?;
+        let start = linker
+            .get_default(&mut store, "")?
+            .typed::<(), (), _>(&store)?;
+
+        match start.call(&mut store, ()) {
+            Ok(()) => Ok(0),
+            Err(trap) => match trap.i32_exit_status() {
+                Some(code) => Ok(code),
+                None => Err(trap.into()),
+            },
+        }
+    })
+    .await?;
+
+    timer.abort();
+
+    let logs = output
+        .try_into_inner()
+        .map(|cursor| cursor.into_inner())
+        .unwrap_or_default();
+    send_logs(worker, &task_req, &logs).await?;
+
+    match result {
+        Ok(code) => {
+            trace!("wasm module exited with code {}", code);
+            Ok(TaskResult::from_success(code == 0).with_exit_code(code.into()))
+        }
+        Err(err) => {
+            warn!(module=%location, "wasm module trapped: {:#}", err);
+            Ok(TaskResult {
+                success: false,
+                error_details: Some(format!("{err:#}")),
+                ..TaskResult::default()
+            })
+        }
+    }
+}
+
+async fn send_logs(worker: &Worker, task_req: &TaskRequest, logs: &[u8]) -> Result<()> {
+    let mut shipper = LogShipper::new(worker, task_req).await?;
+
+    trace!(task_run_id=?task_req.task_run_id, "sending wasm module output");
+    for line in logs.split_inclusive(|&b| b == b'\n') {
+        shipper.send(LogStream::Output, line).await?;
+    }
+
+    shipper.finish().await
+}
@@ -1,96 +1,103 @@
 use super::{RUNNING_TASKS, TOTAL_TASKS, WORKER_ID};
 use crate::{
+    broker::{Broker, Consumer, Delivery, Queue, SendOptions},
+    config::Config,
     instrumented,
-    messages::{TaskProgress, TaskRequest, TokenState},
-    worker::{config_cache, Worker},
+    messages::{
+        ContainerOs, ErrorClass, TaskPriority, TaskProgress, TaskRequest, TokenState, DEFAULT_QUEUE,
+    },
+    worker::{
+        config_cache,
+        control::{KillReason, KillSwitch},
+        engine::{run_with_retries, InfraError, TaskEngineImpl},
+        executor::ExecutorEngine,
+        lambda::LambdaEngine,
+        process::ProcessEngine,
+        wasm::WasmEngine,
+        LiveConfig, Worker,
+    },
 };
 use anyhow::Result;
 use cadence::{CountedExt, Gauged};
 use chrono::{DateTime, Utc};
-use futures::{FutureExt, TryStreamExt};
-use lapin::{
-    options::{
-        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
-        ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
-    },
-    types::FieldTable,
-    BasicProperties, Channel, Consumer, ExchangeKind,
+use futures::FutureExt;
+use serde_json::Value as JsonValue;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+    sync::Arc,
+    time::Duration,
 };
-use std::{sync::Arc, time::Duration};
-use tracing::{debug, error, info, info_span, trace};
-use crate::config::Config;
-
-// TODO - queues should be configurable for task routing
-const TASK_QUEUE: &str = "waterwheel.tasks";
-
-const RESULT_EXCHANGE: &str = "waterwheel.results";
-const RESULT_QUEUE: &str = "waterwheel.results";
-
-pub async fn setup_queues(chan: &Channel, config: &Config) -> Result<()> {
-    // declare queue for consuming incoming messages
-    let mut args = FieldTable::default();
-    args.insert("x-max-priority".into(), 3.into());
-
-    let timeout_ms = config.amqp_consumer_timeout * 1000;
-    args.insert("x-consumer-timeout".into(), timeout_ms.into());
-
-    chan.queue_declare(
-        TASK_QUEUE,
-        QueueDeclareOptions {
-            durable: true,
-            ..QueueDeclareOptions::default()
-        },
-        args,
-    )
-    .await?;
-
-    // declare outgoing exchange and queue for progress reports
-    chan.exchange_declare(
-        RESULT_EXCHANGE,
-        ExchangeKind::Direct,
-        ExchangeDeclareOptions {
-            durable: true,
-            ..ExchangeDeclareOptions::default()
-        },
-        FieldTable::default(),
-    )
-    .await?;
-
-    chan.queue_declare(
-        RESULT_QUEUE,
-        QueueDeclareOptions {
-            durable: true,
-            ..QueueDeclareOptions::default()
-        },
-        FieldTable::default(),
-    )
-    .await?;
-
-    chan.queue_bind(
-        RESULT_QUEUE,
-        RESULT_EXCHANGE,
-        "",
-        QueueBindOptions::default(),
-        FieldTable::default(),
-    )
-    .await?;
+use tokio::sync::watch;
+use tracing::{debug, error, info, info_span, trace, warn};
+
+/// tasks that need a GPU and don't name a queue, which workers with GPUs take from
+pub const GPU_QUEUE: &str = "gpu";
+/// tasks that need Windows and don't name a queue, which Windows workers take from
+pub const WINDOWS_QUEUE: &str = "windows";
+
+/// how often a slot looks for a task it can take while the free slots are reserved
+const RESERVED_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// error details are cut down to this many bytes, since they can include a
+/// whole stack trace or page of events
+const MAX_ERROR_DETAILS: usize = 4096;
+
+/// the task queues this worker takes tasks from
+pub fn task_queues(config: &Config) -> Vec<String> {
+    if !config.worker_queues.is_empty() {
+        config.worker_queues.clone()
+    } else if config.worker_os == ContainerOs::Windows {
+        vec![WINDOWS_QUEUE.to_owned()]
+    } else if config.worker_gpus > 0 {
+        vec![GPU_QUEUE.to_owned()]
+    } else {
+        vec![DEFAULT_QUEUE.to_owned()]
+    }
+}
+
+/// make sure the worker's task queues exist, the server declares queues as it
+/// sends tasks to them, but a worker may start taking from one before any have
+/// been sent
+pub async fn setup_queues(broker: &dyn Broker, config: &Config) -> Result<()> {
+    for queue in task_queues(config) {
+        broker.declare(Queue::Tasks(&queue)).await?;
+    }
+
+    broker.declare(Queue::Results).await?;
 
     Ok(())
 }
 
-pub async fn create_consumer(chan: &Channel) -> Result<Consumer> {
-    chan.basic_qos(1, BasicQosOptions::default()).await?;
+/// a slot's consumer, taking from all of the worker's queues
+pub async fn create_consumer(broker: &dyn Broker, config: &Config) -> Result<Consumer> {
+    let names = task_queues(config);
+    let queues = names
+        .iter()
+        .map(|queue| Queue::Tasks(queue.as_str()))
+        .collect::<Vec<_>>();
 
-    let consumer = chan
-        .basic_consume(
-            TASK_QUEUE,
-            "worker",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+    // a slot holds one task at a time however many queues it takes from
+    broker.consume(&queues, 1).await
+}
+
+/// take a task from the first of the worker's queues that has one
+async fn get_task(broker: &dyn Broker, config: &Config) -> Result<Option<Delivery>> {
+    for queue in task_queues(config) {
+        if let Some(delivery) = broker.get(Queue::Tasks(&queue)).await? {
+            return Ok(Some(delivery));
+        }
+    }
+
+    Ok(None)
+}
 
-    Ok(consumer)
+/// wait before looking for a task again while the free slots are reserved
+async fn wait_for_slots(live_rx: &mut watch::Receiver<LiveConfig>) -> Result<()> {
+    tokio::select! {
+        _ = tokio::time::sleep(RESERVED_POLL_INTERVAL) => Ok(()),
+        changed = live_rx.changed() => Ok(changed?),
+    }
 }
 
 pub async fn process_work(worker: Arc<Worker>) -> Result<!> {
@@ -100,12 +107,86 @@ pub async fn process_work(worker: Arc<Worker>) -> Result<!> {
     let default_task_timeout = Duration::from_secs(worker.config.default_task_timeout);
     let task_heartbeat = Duration::from_secs(worker.config.task_heartbeat);
 
-    let chan = worker.amqp_conn.create_channel().await?;
-    setup_queues(&chan, &worker.config).await?;
-    let mut consumer = create_consumer(&chan).await?;
+    let broker = worker.broker.as_ref();
+    setup_queues(broker, &worker.config).await?;
+
+    let slot = worker.slots.acquire();
+    let mut live_rx = worker.live_config.subscribe();
+    let mut consumer = None;
+
+    debug!(slot = slot.id(), "worker consuming messages");
+    loop {
+        if !slot.is_enabled(&live_rx) {
+            if let Some(consumer) = consumer.take() {
+                debug!(
+                    slot = slot.id(),
+                    "slot disabled, no longer consuming messages"
+                );
+                consumer.cancel().await?;
+            }
+            live_rx.changed().await?;
+            continue;
+        }
+
+        let lowest = worker.slots.lowest_startable(&live_rx.borrow());
+
+        let delivery = if lowest == Some(TaskPriority::BackFill) {
+            if consumer.is_none() {
+                consumer = Some(create_consumer(broker, &worker.config).await?);
+            }
+            let active = consumer.as_mut().expect("consumer was just created");
+
+            tokio::select! {
+                next = active.next() => match next? {
+                    Some(delivery) => delivery,
+                    None => anyhow::bail!("consumer stopped consuming"),
+                },
+                changed = live_rx.changed() => {
+                    changed?;
+                    continue;
+                }
+            }
+        } else {
+            // The free slots are reserved for higher priorities. A consumer would
+            // hold on to the next task whatever its priority, so poll instead.
+            if let Some(consumer) = consumer.take() {
+                debug!(
+                    slot = slot.id(),
+                    "free slots are reserved, polling for tasks"
+                );
+                consumer.cancel().await?;
+            }
+
+            match get_task(broker, &worker.config).await? {
+                Some(delivery) => delivery,
+                None => {
+                    wait_for_slots(&mut live_rx).await?;
+                    continue;
+                }
+            }
+        };
+
+        if !slot.is_enabled(&live_rx) {
+            // concurrency was reduced while we were waiting, give the task back
+            delivery.requeue().await?;
+            continue;
+        }
+
+        let priority = delivery.priority.unwrap_or_default();
+
+        // counted before the task starts, so two slots can't both take the last
+        // unreserved one
+        let started = worker.slots.try_start(&live_rx.borrow(), priority);
+        let busy_slot = match started {
+            Some(busy_slot) => busy_slot,
+            None => {
+                trace!(?priority, "free slots are reserved, giving the task back");
+                delivery.requeue().await?;
+                wait_for_slots(&mut live_rx).await?;
+                continue;
+            }
+        };
 
-    debug!("worker consuming messages");
-    while let Some(delivery) = consumer.try_next().await? {
         let task_req: TaskRequest = serde_json::from_slice(&delivery.data)?;
 
         let span = info_span!("running_task",
@@ -128,7 +209,7 @@ pub async fn process_work(worker: Arc<Worker>) -> Result<!> {
                 .send();
 
             let progress = ProgressPublisher {
-                chan: &chan,
+                broker,
                 task_req: &task_req,
                 started_datetime: Utc::now(),
             };
@@ -137,47 +218,117 @@ pub async fn process_work(worker: Arc<Worker>) -> Result<!> {
 
             let maybe_task_def = config_cache::get_task_def(&worker, task_req.task_id).await?;
 
-            let result = if let Some(task_def) = maybe_task_def {
-                if task_def.paused {
-                    // job has been paused - task will get rerun by the
-                    // requeue processor when the job is unpaused
-                    TokenState::Cancelled
-                } else if task_def.image.is_none() {
-                    // task has no image, mark success immediately
-                    TokenState::Success
-                } else {
-                    let task_timeout = task_def.timeout.unwrap_or(default_task_timeout);
-
-                    let mut task = engine.run_task(&worker, task_req.clone(), task_def).boxed();
-
-                    let mut ticker = tokio::time::interval(task_heartbeat);
-                    let mut timeout = tokio::time::sleep(task_timeout).boxed();
-
-                    loop {
-                        tokio::select! {
-                            _ = &mut timeout => {
-                                error!("timeout running task");
-                                break TokenState::Timeout;
-                            }
-                            _ = ticker.tick() => {
-                                trace!("task heartbeat");
-                                progress.publish(TokenState::Running).await?;
-                            }
-                            result = &mut task => {
-                                trace!("task engine returned: {:?}", result);
-                                break TokenState::from_result(result);
+            let (result, error_details, error_class, outputs, exit_code) =
+                if let Some(task_def) = maybe_task_def {
+                    if task_def.paused {
+                        // job has been paused - task will get rerun by the
+                        // requeue processor when the job is unpaused
+                        (TokenState::Cancelled, None, None, None, None)
+                    } else if task_def.image.is_none()
+                        && task_def.wasm_module.is_none()
+                        && task_def.command.is_none()
+                        && task_def.lambda.is_none()
+                    {
+                        // task has no image, mark success immediately
+                        (TokenState::Success, None, None, None, None)
+                    } else {
+                        let task_timeout = task_def.timeout.unwrap_or(default_task_timeout);
+                        let deadline =
+                            progress.started_datetime + chrono::Duration::from_std(task_timeout)?;
+
+                        // registered before the engine starts, so it can watch for a kill too
+                        let kill_switch = KillSwitch::register(task_req.task_run_id);
+
+                        // in supervisor mode every task runs in its own executor process,
+                        // otherwise wasm modules always run in-process, commands as local
+                        // processes and lambdas are invoked, whatever the configured engine
+                        let task_engine: &(dyn TaskEngineImpl + Send + Sync) =
+                            if worker.config.worker_supervisor {
+                                &ExecutorEngine
+                            } else if task_def.wasm_module.is_some() {
+                                &WasmEngine
+                            } else if task_def.command.is_some() {
+                                &ProcessEngine
+                            } else if task_def.lambda.is_some() {
+                                &LambdaEngine
+                            } else {
+                                &*engine
+                            };
+                        let mut task = run_with_retries(
+                            task_engine,
+                            &worker,
+                            task_req.clone(),
+                            task_def,
+                            deadline,
+                        )
+                        .boxed();
+
+                        let mut ticker = tokio::time::interval(task_heartbeat);
+                        let mut timeout = tokio::time::sleep(task_timeout).boxed();
+
+                        loop {
+                            tokio::select! {
+                                _ = &mut timeout => {
+                                    error!("timeout running task");
+                                    break (TokenState::Timeout, None, None, None, None);
+                                }
+                                reason = kill_switch.killed() => {
+                                    warn!(?reason, "task killed");
+                                    let (state, details) = match reason {
+                                        KillReason::Api => {
+                                            (TokenState::Cancelled, "killed through the API")
+                                        }
+                                        KillReason::Shutdown => {
+                                            (TokenState::Preempted, "the worker shut down")
+                                        }
+                                    };
+                                    break (state, Some(details.to_owned()), None, None, None);
+                                }
+                                _ = ticker.tick() => {
+                                    trace!("task heartbeat");
+                                    progress.publish(TokenState::Running).await?;
+                                }
+                                result = &mut task => {
+                                    trace!("task engine returned: {:?}", result);
+                                    break match result {
+                                        Ok(res) => {
+                                            let outputs = res
+                                                .result_file
+                                                .as_ref()
+                                                .and_then(|file| file.outputs_json());
+                                            let error_class = res.error_class();
+                                            (
+                                                res.state(),
+                                                res.error_details,
+                                                error_class,
+                                                outputs,
+                                                res.exit_code,
+                                            )
+                                        }
+                                        Err(err) if InfraError::is(&err) => {
+                                            let details = Some(format!("{err:#}"));
+                                            let class = Some(ErrorClass::Infra);
+                                            (TokenState::InfraFailure, details, class, None, None)
+                                        }
+                                        Err(err) => {
+                                            let details = Some(format!("{err:#}"));
+                                            let state = TokenState::from_result(Err(err));
+                                            (state, details, Some(ErrorClass::Infra), None, None)
+                                        }
+                                    };
+                                }
                             }
                         }
                     }
-                }
-            } else {
-                TokenState::Error
-            };
+                } else {
+                    (TokenState::Error, None, None, None, None)
+                };
 
             let finished_datetime = Utc::now();
 
             TOTAL_TASKS.inc();
             drop(running_task_guard);
+            drop(busy_slot);
 
             statsd
                 .gauge_with_tags("tasks.running", RUNNING_TASKS.get() as u64)
@@ -193,35 +344,163 @@ pub async fn process_work(worker: Arc<Worker>) -> Result<!> {
                 started_datetime=?progress.started_datetime.to_rfc3339(),
                 "task completed");
 
-            progress.finish(finished_datetime, result).await?;
-
-            delivery.ack(BasicAckOptions::default()).await?;
+            progress
+                .finish(
+                    finished_datetime,
+                    result,
+                    error_details,
+                    error_class,
+                    outputs,
+                    exit_code,
+                )
+                .await?;
+
+            delivery.ack().await?;
             debug!("task acked");
         })?;
     }
+}
 
-    unreachable!("consumer stopped consuming")
+/// Work loops each hold a numbered slot, slots numbered at or above
+/// `max_tasks` are disabled and don't consume tasks
+#[derive(Default)]
+pub struct Slots {
+    used: std::sync::Mutex<BTreeSet<u32>>,
+    /// slots running a task, which decides whether the free ones are reserved
+    busy: std::sync::Mutex<u32>,
+}
+
+impl Slots {
+    /// the lowest priority of task that can take a free slot right now
+    pub fn lowest_startable(&self, live: &LiveConfig) -> Option<TaskPriority> {
+        let busy = *self.busy.lock().expect("slots mutex poisoned");
+        PRIORITIES
+            .into_iter()
+            .find(|priority| can_start(live.max_tasks, busy, &live.reserved_slots, *priority))
+    }
+
+    /// Count a slot as busy with a task, unless the free slots are reserved for
+    /// higher priorities. It's free again once the guard is dropped.
+    pub fn try_start(&self, live: &LiveConfig, priority: TaskPriority) -> Option<BusySlot<'_>> {
+        let mut busy = self.busy.lock().expect("slots mutex poisoned");
+        if !can_start(live.max_tasks, *busy, &live.reserved_slots, priority) {
+            return None;
+        }
+
+        *busy += 1;
+        Some(BusySlot { slots: self })
+    }
+
+    /// take the lowest free slot
+    pub fn acquire(&self) -> Slot<'_> {
+        let mut used = self.used.lock().expect("slots mutex poisoned");
+        let id = (0..)
+            .find(|id| !used.contains(id))
+            .expect("ran out of slots");
+        used.insert(id);
+        Slot { id, slots: self }
+    }
+}
+
+pub struct Slot<'a> {
+    id: u32,
+    slots: &'a Slots,
+}
+
+impl Slot<'_> {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn is_enabled(&self, live_config: &watch::Receiver<LiveConfig>) -> bool {
+        let live = live_config.borrow();
+        !live.draining && !live.maintenance && self.id < live.max_tasks
+    }
+}
+
+pub struct BusySlot<'a> {
+    slots: &'a Slots,
+}
+
+impl Drop for BusySlot<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut busy) = self.slots.busy.lock() {
+            *busy -= 1;
+        }
+    }
+}
+
+const PRIORITIES: [TaskPriority; 4] = [
+    TaskPriority::BackFill,
+    TaskPriority::Low,
+    TaskPriority::Normal,
+    TaskPriority::High,
+];
+
+/// A task can start if there are more free slots than are reserved for the
+/// priorities above it, so it leaves enough free for them.
+fn can_start(
+    max_tasks: u32,
+    busy: u32,
+    reserved: &BTreeMap<TaskPriority, u32>,
+    priority: TaskPriority,
+) -> bool {
+    let free = max_tasks.saturating_sub(busy);
+    let reserved_above: u32 = reserved
+        .range((Bound::Excluded(priority), Bound::Unbounded))
+        .map(|(_, slots)| slots)
+        .sum();
+
+    free > reserved_above
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut used) = self.slots.used.lock() {
+            used.remove(&self.id);
+        }
+    }
 }
 
 struct ProgressPublisher<'a> {
-    chan: &'a Channel,
+    broker: &'a dyn Broker,
     task_req: &'a TaskRequest,
     started_datetime: DateTime<Utc>,
 }
 
 impl ProgressPublisher<'_> {
     async fn publish(&self, result: TokenState) -> Result<()> {
-        self.do_publish(None, result).await
+        self.do_publish(None, result, None, None, None, None).await
     }
 
-    async fn finish(&self, finished_datetime: DateTime<Utc>, result: TokenState) -> Result<()> {
-        self.do_publish(Some(finished_datetime), result).await
+    async fn finish(
+        &self,
+        finished_datetime: DateTime<Utc>,
+        result: TokenState,
+        error_details: Option<String>,
+        error_class: Option<ErrorClass>,
+        outputs: Option<JsonValue>,
+        exit_code: Option<i64>,
+    ) -> Result<()> {
+        self.do_publish(
+            Some(finished_datetime),
+            result,
+            error_details,
+            error_class,
+            outputs,
+            exit_code,
+        )
+        .await
     }
 
     async fn do_publish(
         &self,
         finished_datetime: Option<DateTime<Utc>>,
         result: TokenState,
+        error_details: Option<String>,
+        error_class: Option<ErrorClass>,
+        outputs: Option<JsonValue>,
+        exit_code: Option<i64>,
     ) -> Result<()> {
         let payload = serde_json::to_vec(&TaskProgress {
             task_run_id: self.task_req.task_run_id,
@@ -229,18 +508,17 @@ impl ProgressPublisher<'_> {
             trigger_datetime: self.task_req.trigger_datetime,
             started_datetime: self.started_datetime,
             finished_datetime,
-            worker_id: *WORKER_ID,
+            worker_id: Some(*WORKER_ID),
             result,
+            error_details: error_details.map(truncate_error_details),
+            operator_override: false,
+            outputs,
+            exit_code,
+            error_class,
         })?;
 
-        self.chan
-            .basic_publish(
-                RESULT_EXCHANGE,
-                "",
-                BasicPublishOptions::default(),
-                &payload,
-                BasicProperties::default(),
-            )
+        self.broker
+            .send(Queue::Results, &payload, SendOptions::default())
             .await?;
 
         debug!(result=?result, "task result published");
@@ -248,3 +526,44 @@ impl ProgressPublisher<'_> {
         Ok(())
     }
 }
+
+/// cut error details down to `MAX_ERROR_DETAILS`, on a character boundary
+fn truncate_error_details(mut details: String) -> String {
+    if details.len() > MAX_ERROR_DETAILS {
+        let mut end = MAX_ERROR_DETAILS;
+        while !details.is_char_boundary(end) {
+            end -= 1;
+        }
+        details.truncate(end);
+        details.push_str("... (truncated)");
+    }
+    details
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_can_start() {
+        let none = BTreeMap::new();
+        assert!(can_start(4, 3, &none, TaskPriority::BackFill));
+        assert!(!can_start(4, 4, &none, TaskPriority::High));
+
+        let reserved = BTreeMap::from([(TaskPriority::High, 2), (TaskPriority::Normal, 1)]);
+        assert!(can_start(4, 0, &reserved, TaskPriority::Low));
+        assert!(!can_start(4, 1, &reserved, TaskPriority::Low));
+        assert!(can_start(4, 1, &reserved, TaskPriority::Normal));
+        assert!(!can_start(4, 2, &reserved, TaskPriority::Normal));
+        assert!(can_start(4, 3, &reserved, TaskPriority::High));
+    }
+
+    #[test]
+    fn test_truncate_error_details() {
+        assert_eq!(truncate_error_details("oops".to_owned()), "oops");
+
+        let details = truncate_error_details("é".repeat(MAX_ERROR_DETAILS));
+        assert!(details.ends_with("é... (truncated)"));
+        assert_eq!(details.len(), MAX_ERROR_DETAILS + "... (truncated)".len());
+    }
+}
@@ -1,11 +1,13 @@
+use futures::TryStreamExt;
 use highnoon::StatusCode;
-use lapin::{
-    options::{BasicGetOptions, QueueBindOptions, QueueDeclareOptions},
-    types::FieldTable,
-};
 use pretty_assertions::assert_eq;
 use serde_json::{json, Value};
-use waterwheel::server::{api::make_app, Server};
+use std::time::Duration;
+use tokio::time::timeout;
+use waterwheel::{
+    broker::Topic,
+    server::{api::make_app, Server},
+};
 
 mod common;
 
@@ -17,29 +19,9 @@ pub async fn test_project() -> highnoon::Result<()> {
 
         let tc = make_app(config).await?.test();
 
-        // (setup a queue to receive the config updates - these are a fanout broadcast so
-        // no queue is subscribed by default)
-        let amqp_chan = server.amqp_conn.create_channel().await?;
-        let queue = amqp_chan
-            .queue_declare(
-                "",
-                QueueDeclareOptions {
-                    auto_delete: true,
-                    exclusive: true,
-                    ..QueueDeclareOptions::default()
-                },
-                FieldTable::default(),
-            )
-            .await?;
-        amqp_chan
-            .queue_bind(
-                queue.name().as_str(),
-                "waterwheel.config",
-                "",
-                QueueBindOptions::default(),
-                FieldTable::default(),
-            )
-            .await?;
+        // (subscribe to the config updates - these are a broadcast so only
+        // subscribers at the time get them)
+        let mut config_updates = server.broker.subscribe(Topic::Config).await?;
 
         // CREATE A PROJECT
         let project = json!({
@@ -53,11 +35,10 @@ pub async fn test_project() -> highnoon::Result<()> {
         assert_eq!(resp.status(), StatusCode::CREATED);
 
         // CHECK FOR CONFIG UPDATE MESSAGE
-        let msg = amqp_chan
-            .basic_get(queue.name().as_str(), BasicGetOptions::default())
-            .await?
-            .expect("no message on the config update queue");
-        let data = String::from_utf8(msg.delivery.data)?;
+        let msg = timeout(Duration::from_secs(5), config_updates.try_next())
+            .await??
+            .expect("no message on the config update topic");
+        let data = String::from_utf8(msg)?;
         assert_eq!(
             data,
             r#"{"Project":"00000000-0000-0000-0000-000000000000"}"#
@@ -0,0 +1,61 @@
+use pretty_assertions::assert_eq;
+use waterwheel::{
+    broker::{self, Queue, SendOptions},
+    config::BrokerKind,
+    messages::{TaskPriority, DEFAULT_QUEUE},
+    worker::work,
+};
+
+mod common;
+
+#[tokio::main]
+#[test]
+pub async fn test_task_priority() -> highnoon::Result<()> {
+    common::with_external_services(|mut config| async move {
+        for kind in [BrokerKind::Amqp, BrokerKind::Redis] {
+            config.broker = kind;
+            let broker = broker::connect(&config).await?;
+
+            work::setup_queues(broker.as_ref(), &config).await?;
+
+            // BUILD A BACKLOG, WITH THE HIGH PRIORITY TASK QUEUED LAST
+            let published = [
+                TaskPriority::BackFill,
+                TaskPriority::BackFill,
+                TaskPriority::Low,
+                TaskPriority::BackFill,
+                TaskPriority::Normal,
+                TaskPriority::High,
+            ];
+            for priority in published {
+                let options = SendOptions {
+                    priority: Some(priority),
+                    ..SendOptions::default()
+                };
+                broker
+                    .send(
+                        Queue::Tasks(DEFAULT_QUEUE),
+                        priority.as_str().as_bytes(),
+                        options,
+                    )
+                    .await?;
+            }
+
+            // TAKE THEM IN THE ORDER A WORKER WOULD
+            let mut delivered = Vec::new();
+            while let Some(delivery) = broker.get(Queue::Tasks(DEFAULT_QUEUE)).await? {
+                delivered.push(String::from_utf8(delivery.data.clone())?);
+                delivery.ack().await?;
+            }
+
+            assert_eq!(
+                delivered,
+                ["high", "normal", "low", "backfill", "backfill", "backfill"],
+                "with the {kind:?} broker"
+            );
+        }
+
+        Ok(())
+    })
+    .await
+}
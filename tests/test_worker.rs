@@ -1,17 +1,12 @@
 use chrono::{DateTime, Utc};
-use futures::TryStreamExt;
-use lapin::{
-    options::{BasicConsumeOptions, BasicPublishOptions},
-    types::FieldTable,
-    BasicProperties,
-};
 use pretty_assertions::assert_eq;
 use serde_json::{json, Value};
 use std::{sync::Arc, time::Duration};
 use tokio::time::timeout;
 use uuid::Uuid;
 use waterwheel::{
-    messages::TaskDef,
+    broker::{Queue, SendOptions},
+    messages::{TaskDef, DEFAULT_QUEUE},
     worker::{engine::TaskEngine, heartbeat, work, Worker},
 };
 use waterwheel::server::api;
@@ -40,18 +35,14 @@ pub async fn test_worker() -> highnoon::Result<()> {
                     job_name: "testing job".to_string(),
                     project_id: NULL_UUID,
                     project_name: "testing project".to_string(),
-                    image: None,
-                    args: vec![],
-                    env: None,
-                    paused: false,
-                    timeout: None,
+                    ..TaskDef::default()
                 }),
             );
         }
 
-        let amqp_chan = worker.amqp_conn.create_channel().await?;
+        let broker = worker.broker.clone();
 
-        work::setup_queues(&amqp_chan, &config).await?;
+        work::setup_queues(broker.as_ref(), &config).await?;
 
         tokio::spawn(work::process_work(worker.clone()));
 
@@ -62,31 +53,19 @@ pub async fn test_worker() -> highnoon::Result<()> {
             "trigger_datetime": "2000-01-01T00:00:00Z",
         }))?;
 
-        amqp_chan
-            .basic_publish(
-                "",
-                "waterwheel.tasks",
-                BasicPublishOptions::default(),
+        broker
+            .send(
+                Queue::Tasks(DEFAULT_QUEUE),
                 &payload,
-                BasicProperties::default(),
+                SendOptions::default(),
             )
             .await?;
 
         //tokio::time::sleep(Duration::from_secs(5)).await;
 
         // WAIT FOR TASK STARTED
-        let mut consumer = amqp_chan
-            .basic_consume(
-                "waterwheel.results",
-                "test",
-                BasicConsumeOptions::default(),
-                FieldTable::default(),
-            )
-            .await?;
-        let delivery = consumer
-            .try_next()
-            .await?
-            .expect("no task result published");
+        let mut consumer = broker.consume(&[Queue::Results], 10).await?;
+        let delivery = consumer.next().await?.expect("no task result published");
 
         let mut data: Value = serde_json::from_slice(&delivery.data)?;
 
@@ -117,10 +96,7 @@ pub async fn test_worker() -> highnoon::Result<()> {
         );
 
         // WAIT FOR TASK SUCCESS
-        let delivery = consumer
-            .try_next()
-            .await?
-            .expect("no task result published");
+        let delivery = consumer.next().await?.expect("no task result published");
 
         let mut data: Value = serde_json::from_slice(&delivery.data)?;
 
@@ -172,11 +148,12 @@ pub async fn test_worker_missing_taskid() -> highnoon::Result<()> {
         config.task_engine = TaskEngine::Null;
 
         tokio::spawn(api::serve(config.clone()));
-        heartbeat::wait_for_server(&config).await;
 
         let worker = Arc::new(Worker::new(config.clone()).await?);
-        let amqp_chan = worker.amqp_conn.create_channel().await?;
-        work::setup_queues(&amqp_chan, &config).await?;
+        heartbeat::wait_for_server(&worker).await?;
+
+        let broker = worker.broker.clone();
+        work::setup_queues(broker.as_ref(), &config).await?;
         tokio::spawn(work::process_work(worker.clone()));
 
         // PUBLISH A TASK (no task_def in the cache!)
@@ -187,34 +164,25 @@ pub async fn test_worker_missing_taskid() -> highnoon::Result<()> {
             "priority": "normal",
         }))?;
 
-        amqp_chan
-            .basic_publish(
-                "",
-                "waterwheel.tasks",
-                BasicPublishOptions::default(),
+        broker
+            .send(
+                Queue::Tasks(DEFAULT_QUEUE),
                 &payload,
-                BasicProperties::default(),
+                SendOptions::default(),
             )
             .await?;
 
         // WAIT FOR TASK PROGRESS
-        let mut consumer = amqp_chan
-            .basic_consume(
-                "waterwheel.results",
-                "test",
-                BasicConsumeOptions::default(),
-                FieldTable::default(),
-            )
-            .await?;
+        let mut consumer = broker.consume(&[Queue::Results], 10).await?;
 
-        let delivery1 = timeout(Duration::from_secs(30), consumer.try_next())
+        let delivery1 = timeout(Duration::from_secs(30), consumer.next())
             .await??
             .expect("no task result published");
 
         let data: Value = serde_json::from_slice(&delivery1.data)?;
         assert_eq!(data["result"].as_str(), Some("running"));
 
-        let delivery2 = timeout(Duration::from_secs(30), consumer.try_next())
+        let delivery2 = timeout(Duration::from_secs(30), consumer.next())
             .await??
             .expect("no task result published");
 
@@ -0,0 +1,37 @@
+import { LiveUpdate } from '../types/LiveUpdate';
+
+const RECONNECT_MS = 5000;
+
+// Calls `onUpdate` for each update pushed from `/api/updates`, reconnecting
+// if the socket closes. Returns a function that stops watching.
+export function watchUpdates(params: Record<string, string>, onUpdate: (update: LiveUpdate) => void): () => void {
+    let url = new URL('/api/updates', window.location.href);
+    url.protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
+    for (const [key, value] of Object.entries(params)) {
+        url.searchParams.set(key, value);
+    }
+
+    let socket: WebSocket | null = null;
+    let stopped = false;
+    let reconnect: NodeJS.Timeout | null = null;
+
+    const connect = () => {
+        socket = new WebSocket(url.href);
+        socket.onmessage = (event) => onUpdate(JSON.parse(event.data));
+        socket.onclose = () => {
+            if (!stopped) {
+                reconnect = setTimeout(connect, RECONNECT_MS);
+            }
+        };
+    };
+
+    connect();
+
+    return () => {
+        stopped = true;
+        if (reconnect) {
+            clearTimeout(reconnect);
+        }
+        socket?.close();
+    };
+}
@@ -40,7 +40,7 @@ class State extends Component<StateProps> {
     } else if (state == 'failure') {
       color = 'error';
       icon = <CloseSquareOutlined />;
-    } else if (state == 'error') {
+    } else if (state == 'error' || state == 'infra_failure') {
       color = 'orange';
       icon = <WarningOutlined />;
     } else if (state == 'timeout') {
@@ -49,7 +49,13 @@ class State extends Component<StateProps> {
     } else if (state == 'cancelled') {
        color = 'default';
        icon = <StopOutlined />;
-    } else if (state == 'retry') {
+    } else if (state == 'expired') {
+       color = 'default';
+       icon = <HourglassOutlined />;
+    } else if (state == 'upstream_failed') {
+       color = 'default';
+       icon = <CloseSquareOutlined />;
+    } else if (state == 'retry' || state == 'preempted') {
        color = 'purple';
        icon = <PlusSquareOutlined />;
     } else {
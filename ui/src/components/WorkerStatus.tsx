@@ -16,7 +16,10 @@ function WorkerStatus({status}: WorkerStatusProps) {
     if (status == 'up') {
       color = 'success';
       icon = <CheckOutlined/>;
-    } else if (status == 'gone') {
+    } else if (status == 'draining' || status == 'maintenance') {
+      color = 'processing';
+      icon = <PoweroffOutlined/>;
+    } else if (status == 'gone' || status == 'retired') {
       color = 'warning';
       icon = <PoweroffOutlined/>;
     } else {
@@ -1,6 +1,6 @@
 import React, { Component, Fragment } from "react";
 import { Link } from "react-router-dom";
-import { Layout, Breadcrumb, Row, Col, Statistic } from 'antd';
+import { Layout, Breadcrumb, Row, Col, Statistic, Alert } from 'antd';
 import { geekblue, lime, red, grey, yellow } from '@ant-design/colors';
 import axios from 'axios';
 
@@ -52,6 +52,10 @@ class Home extends Component<{}, HomeState> {
               <Breadcrumb.Item><Link to="/">Home</Link></Breadcrumb.Item>
           </Breadcrumb>
           <Body>
+            {status?.read_only &&
+                <Alert type="info" showIcon style={{marginBottom: '16px'}}
+                    message="This server is read-only, changes are disabled." />
+            }
             <Row gutter={[16, 32]}>
                 <Col span={6}>
                     <Statistic title="Projects"
@@ -24,6 +24,7 @@ import { datetime, uuid } from "../../types/common";
 import { Moment } from "moment";
 import { Task } from "../../types/Task";
 import TokenRuns from "../TokenRuns";
+import { watchUpdates } from "../../components/LiveUpdates";
 
 
 const HeaderCell = styled.td`
@@ -66,11 +67,15 @@ function iconForState(task: TokenState) {
         icon = <CloseSquareOutlined style={{color: red[5]}}/>;
     } else if (state == 'timeout') {
         icon = <HourglassOutlined style={{color: orange[5]}}/>;
-    } else if (state == 'error') {
+    } else if (state == 'error' || state == 'infra_failure') {
         icon = <WarningOutlined style={{color: orange[5]}}/>;
     } else if (state == 'cancelled') {
         icon = <StopOutlined style={{color: grey[5]}} />;
-    } else if (state == 'retry') {
+    } else if (state == 'expired') {
+        icon = <HourglassOutlined style={{color: grey[5]}} />;
+    } else if (state == 'upstream_failed') {
+        icon = <CloseSquareOutlined style={{color: grey[5]}} />;
+    } else if (state == 'retry' || state == 'preempted') {
         icon = <PlusSquareOutlined  style={{color: purple[6]}} />;
     } else {
         icon = 'invalid state?';
@@ -102,8 +107,12 @@ type TaskGridState = {
     drawer_trigger_datetime: datetime | null;
 }
 
+// the websocket pushes changes, this only catches anything it missed
+const REFRESH_MS = 30000;
+
 class TaskGrid extends Component<TaskGridProps, TaskGridState> {
     interval: NodeJS.Timeout;
+    stopUpdates: () => void;
 
     constructor(props: TaskGridProps) {
         super(props);
@@ -208,12 +217,13 @@ class TaskGrid extends Component<TaskGridProps, TaskGridState> {
     componentDidMount() {
         this.fetchTokens()
 
-        // TODO - use a websocket to poll for token status changes
-        this.interval = setInterval(() => this.fetchTokens(), 1000);
+        this.stopUpdates = watchUpdates({job_id: this.props.id}, () => this.fetchTokens());
+        this.interval = setInterval(() => this.fetchTokens(), REFRESH_MS);
     }
 
     componentWillUnmount() {
         clearInterval(this.interval);
+        this.stopUpdates();
     }
 
     render() {
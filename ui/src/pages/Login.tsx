@@ -1,36 +1,28 @@
-import React, { Component, Fragment } from "react";
-import { Link } from "react-router-dom";
-import { Layout, Input, Button } from 'antd';
-import { UserOutlined, LockOutlined } from '@ant-design/icons';
-
-import axios from 'axios';
+import React, { Component } from "react";
+import { RouteComponentProps } from "react-router-dom";
+import { Layout, Button } from 'antd';
+import { LoginOutlined } from '@ant-design/icons';
 
 import Body from '../components/Body';
 
 const { Content } = Layout;
 
 
-class Login extends Component {
+class Login extends Component<RouteComponentProps> {
   render() {
+    // go back to where the login was started from, if there was somewhere
+    const redirect = new URLSearchParams(this.props.location.search).get('redirect') || '/';
+
     return (
       <Layout>
         <Content style={{padding: '50px'}}>
           <Body>
-            <form action="/login" method="post">
-              <Input
-                name="username"
-                prefix={<UserOutlined/>}
-                placeholder="Username" />
-              <Input
-                name="password"
-                prefix={<LockOutlined/>}
-                type="password"
-                placeholder="Password"
-              />
-              <Button type="primary" htmlType="submit">
-                Log in
-              </Button>
-            </form>
+            <Button
+                type="primary"
+                icon={<LoginOutlined/>}
+                href={`/auth/login?redirect=${encodeURIComponent(redirect)}`}>
+              Log in with SSO
+            </Button>
           </Body>
         </Content>
       </Layout>
@@ -39,4 +31,3 @@ class Login extends Component {
 }
 
 export default Login;
-
@@ -8,6 +8,7 @@ import Body from '../components/Body';
 import WorkerStatus from '../components/WorkerStatus';
 import { ColumnsType } from "antd/lib/table";
 import { WorkerState } from "../types/Worker";
+import { watchUpdates } from "../components/LiveUpdates";
 
 const { Content } = Layout;
 
@@ -28,14 +29,24 @@ function makeColumns(): ColumnsType<WorkerState> {
         },{
             title: 'Running Tasks',
             dataIndex: 'running_tasks',
+        },{
+            title: 'Capacity',
+            dataIndex: ['labels', 'capacity'],
         },{
             title: 'Total Tasks',
             dataIndex: 'total_tasks',
-        },/*{
-            title: 'UI Address',
+        },{
+            title: 'Address',
             dataIndex: 'addr',
-            render: text => <a href={`http://${text}`}>{text}</a>,
-        },*/{
+        },{
+            title: 'Engines',
+            dataIndex: ['labels', 'engines'],
+            render: (engines?: string[]) => engines?.join(', '),
+        },{
+            title: 'Queues',
+            dataIndex: ['labels', 'queues'],
+            render: (queues?: string[]) => queues?.join(', '),
+        },{
             title: 'Last Seen',
             dataIndex: 'last_seen_datetime',
             render: text => <Moment fromNow withTitle>{text}</Moment>
@@ -51,6 +62,7 @@ type WorkersState = {
 class Workers extends Component<{}, WorkersState> {
     columns: ColumnsType<WorkerState>;
     interval: NodeJS.Timeout;
+    stopUpdates: () => void;
 
     constructor(props: {}) {
         super(props);
@@ -84,11 +96,38 @@ class Workers extends Component<{}, WorkersState> {
 
     componentDidMount() {
         this.fetchWorkers()
-        this.interval = setInterval(() => this.fetchWorkers(), 5000);
+        this.interval = setInterval(() => this.fetchWorkers(), 30000);
+        this.stopUpdates = watchUpdates({}, (update) => {
+            if (update.type === 'worker_heartbeat') {
+                this.onHeartbeat(update);
+            }
+        });
     }
 
     componentWillUnmount() {
         clearInterval(this.interval);
+        this.stopUpdates();
+    }
+
+    onHeartbeat({worker_id, running_tasks, total_tasks, last_seen_datetime}: {
+        worker_id: string,
+        running_tasks: number,
+        total_tasks: number,
+        last_seen_datetime: string,
+    }) {
+        const { workers } = this.state;
+
+        if (!workers.some(w => w.uuid === worker_id)) {
+            // a new worker, fetch it to get the rest of its details
+            this.fetchWorkers();
+            return;
+        }
+
+        this.setState({
+            workers: workers.map(w => w.uuid === worker_id
+                ? {...w, running_tasks, total_tasks, last_seen_datetime, status: 'up'}
+                : w),
+        });
     }
 
     render() {
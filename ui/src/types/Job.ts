@@ -58,6 +58,13 @@ export type TriggerTime = {
 export type JobGraph = {
     nodes: JobGraphNode[];
     edges: JobGraphEdge[];
+    groups: JobGraphGroup[];
+};
+
+export type JobGraphGroup = {
+    name: string;
+    parent: string | null;
+    tasks: uuid[];
 };
 
 export type JobGraphNode = {
@@ -82,4 +89,8 @@ export type States = 'active'
     | 'timeout'
     | 'error'
     | 'retry'
+    | 'preempted'
+    | 'expired'
+    | 'upstream_failed'
+    | 'infra_failure'
     | 'cancelled';
@@ -0,0 +1,39 @@
+import { datetime, uuid } from "./common";
+
+export type LiveUpdate = {
+    type: 'token_state';
+    job_id: uuid;
+    task_id: uuid;
+    trigger_datetime: datetime;
+    state: string;
+} | {
+    type: 'run_finished';
+    job_id: uuid;
+    trigger_datetime: datetime;
+    state: string;
+} | {
+    type: 'worker_heartbeat';
+    worker_id: uuid;
+    running_tasks: number;
+    total_tasks: number;
+    last_seen_datetime: datetime;
+} | {
+    type: 'job_created';
+    job_id: uuid;
+    project: string;
+    name: string;
+} | {
+    type: 'trigger_fired';
+    job_id: uuid;
+    trigger_id: uuid;
+    name: string;
+    trigger_datetime: datetime;
+} | {
+    type: 'worker_joined';
+    worker_id: uuid;
+    addr: string;
+} | {
+    type: 'worker_died';
+    worker_id: uuid;
+    last_seen_datetime: datetime;
+};
@@ -2,4 +2,5 @@ export type Status = {
     num_projects: number;
     num_workers: number;
     running_tasks: number;
+    read_only?: boolean;
 };
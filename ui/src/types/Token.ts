@@ -15,6 +15,7 @@ export type TokenOverview = {
 export type TokensRow = {
     trigger_datetime: datetime;
     task_states: Record<string, TokenState>;
+    state_counts: Record<string, number>;
 };
 
 export type TokenState = {
@@ -1,5 +1,14 @@
 import { datetime, uuid } from "./common"
 
+export type WorkerLabels = {
+    hostname?: string;
+    zone?: string;
+    capacity?: number;
+    queues: string[];
+    gpus: number;
+    engines: string[];
+};
+
 export type WorkerState = {
     uuid: uuid;
     addr: string;
@@ -8,6 +17,7 @@ export type WorkerState = {
     running_tasks: number;
     total_tasks: number;
     status: string;
+    labels?: WorkerLabels;
 };
 
 export type Worker = {